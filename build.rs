@@ -0,0 +1,39 @@
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+    Embeds build info in the binary, for VERSION and INFO:
+    REDFLARE_GIT_SHA: short commit hash, with a "-dirty" suffix if there were uncommitted changes. "unknown" outside a
+    git checkout.
+    REDFLARE_BUILD_TIME: unix timestamp of the build. SOURCE_DATE_EPOCH overrides it, for reproducible builds.
+*/
+fn main() {
+    let git_sha = match git(&["rev-parse", "--short", "HEAD"]) {
+        Some(sha) => {
+            let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).map(|status| !status.is_empty());
+            if dirty == Some(true) { format!("{}-dirty", sha) } else { sha }
+        }
+        None => "unknown".to_owned(),
+    };
+    let build_time = match env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse::<u64>().ok()) {
+        Some(epoch) => epoch,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+    };
+    println!("cargo:rustc-env=REDFLARE_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=REDFLARE_BUILD_TIME={}", build_time);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = match Command::new("git").args(args).output() {
+        Ok(output) => output,
+        Err(_) => return None,
+    };
+    if !output.status.success() {
+        return None;
+    }
+    return String::from_utf8(output.stdout).ok().map(|output| output.trim().to_owned());
+}
@@ -0,0 +1,37 @@
+/*
+    Feeds arbitrary input to the RESP parser, which must return rather than panic, and must find the same frame
+    whether the input arrives at once or a byte at a time. Run with:
+
+    cargo fuzz run resp
+*/
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate redflareproxy;
+
+use redflareproxy::resp::{self, Args, FrameScanner, ParseError};
+
+fuzz_target!(|data: &[u8]| {
+    let whole = resp::frame_len(data);
+    if let Ok(len) = whole {
+        assert!(len > 0 && len <= data.len());
+    }
+
+    let mut scanner = FrameScanner::new();
+    let mut incremental = Err(ParseError::Incomplete);
+    for end in 0..data.len() + 1 {
+        incremental = scanner.scan(&data[..end]);
+        if incremental != Err(ParseError::Incomplete) {
+            break;
+        }
+    }
+    assert_eq!(whole, incremental);
+
+    if let Ok(args) = Args::parse(data) {
+        for arg in args {
+            if arg.is_err() {
+                break;
+            }
+        }
+    }
+});
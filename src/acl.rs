@@ -0,0 +1,137 @@
+use audit::glob_match;
+use redisprotocol::is_read_only;
+
+pub const NOPERM_COMMAND: &'static [u8] = b"-NOPERM this user has no permissions to run this command\r\n";
+pub const NOPERM_KEY: &'static [u8] = b"-NOPERM this user has no permissions to access one of the keys used as arguments\r\n";
+
+// The categories that rules can name with @, a subset of Redis' own that covers the commands the proxy supports.
+static CATEGORIES: [&str; 7] = ["all", "blocking", "dangerous", "keyspace", "read", "scripting", "write"];
+
+// The category as in Redis, though the proxy rejects these commands, and XREAD and XREADGROUP with BLOCK.
+static BLOCKING_COMMANDS: [&str; 6] = ["BLPOP", "BRPOP", "BZPOPMAX", "BZPOPMIN", "XREAD", "XREADGROUP"];
+
+static DANGEROUS_COMMANDS: [&str; 2] = ["RESTORE", "SORT"];
+
+static KEYSPACE_COMMANDS: [&str; 14] = [
+    "DEL", "DUMP", "EXISTS", "EXPIRE", "EXPIREAT", "PERSIST", "PEXPIRE", "PEXPIREAT", "PTTL", "RESTORE", "TOUCH", "TTL",
+    "TYPE", "UNLINK"
+];
+
+// Whether a command, as named by command_name, is in a category.
+fn in_category(category: &str, command: &str) -> bool {
+    match category {
+        "all" => true,
+        "blocking" => BLOCKING_COMMANDS.contains(&command),
+        "dangerous" => DANGEROUS_COMMANDS.contains(&command),
+        "keyspace" => KEYSPACE_COMMANDS.contains(&command),
+        "read" => is_read_only(command),
+        "scripting" => command == "EVAL" || command == "FCALL" || command == "FCALL_RO" || command == "FUNCTION",
+        "write" => !is_read_only(command),
+        _ => false,
+    }
+}
+
+enum Selector {
+    Command(String),
+    Category(String),
+}
+
+// A rule of a user's commands, e.g. +@read or -sort.
+struct Rule {
+    allow: bool,
+    selector: Selector,
+}
+
+impl Rule {
+    fn parse(rule: &str) -> Result<Rule, String> {
+        let allow = match rule.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => return Err(format!("Command rule must start with + or -: {}", rule)),
+        };
+        let name = &rule[1..];
+        let selector = if name.starts_with('@') {
+            let category = name[1..].to_ascii_lowercase();
+            if !CATEGORIES.contains(&&category[..]) {
+                return Err(format!("Unknown command category: {}. Expected one of @{}", rule, CATEGORIES.join(", @")));
+            }
+            Selector::Category(category)
+        } else if name.is_empty() {
+            return Err(format!("Command rule is missing a command: {}", rule));
+        } else {
+            Selector::Command(name.to_ascii_uppercase())
+        };
+        return Ok(Rule {
+            allow: allow,
+            selector: selector,
+        });
+    }
+
+    fn matches(&self, command: &str) -> bool {
+        match self.selector {
+            Selector::Command(ref name) => name == command,
+            Selector::Category(ref category) => in_category(category, command),
+        }
+    }
+}
+
+/*
+    What a user may do, from its commands and keys, following Redis 6 ACLs: the last of its command rules that matches
+    a command decides whether it's allowed, and a command that no rule matches isn't. Every key of a request must match
+    one of its key patterns. A user without command rules may run every command, and one without key patterns may
+    access every key, so that users only used for quotas aren't restricted.
+*/
+pub struct Permissions {
+    commands: Vec<Rule>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl Permissions {
+    pub fn parse(commands: &[String], keys: &[String]) -> Result<Permissions, String> {
+        let mut rules = Vec::new();
+        for rule in commands.iter() {
+            rules.push(try!(Rule::parse(rule)));
+        }
+        return Ok(Permissions {
+            commands: rules,
+            keys: keys.iter().map(|pattern| pattern.clone().into_bytes()).collect(),
+        });
+    }
+
+    pub fn allows_command(&self, command: &str) -> bool {
+        if self.commands.is_empty() {
+            return true;
+        }
+        return self.commands.iter().rev().find(|rule| rule.matches(command)).map_or(false, |rule| rule.allow);
+    }
+
+    pub fn allows_key(&self, key: &[u8]) -> bool {
+        return self.keys.is_empty() || self.keys.iter().any(|pattern| glob_match(pattern, key));
+    }
+}
+
+#[test]
+fn test_permissions() {
+    let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<String>>();
+    let permissions = Permissions::parse(&strings(&["+@read", "+set", "-@keyspace", "+ttl"]), &strings(&["cache:*"])).unwrap();
+    assert!(permissions.allows_command("GET"));
+    assert!(permissions.allows_command("SET"));
+    assert!(permissions.allows_command("TTL"));
+    assert!(!permissions.allows_command("EXISTS"));
+    assert!(!permissions.allows_command("DEL"));
+    assert!(!permissions.allows_command("INCR"));
+    assert!(permissions.allows_key(b"cache:user:1"));
+    assert!(!permissions.allows_key(b"session:1"));
+
+    let permissions = Permissions::parse(&strings(&["+@all", "-@dangerous"]), &[]).unwrap();
+    assert!(permissions.allows_command("INCR"));
+    assert!(!permissions.allows_command("SORT"));
+    assert!(permissions.allows_key(b"session:1"));
+
+    let permissions = Permissions::parse(&[], &[]).unwrap();
+    assert!(permissions.allows_command("RESTORE"));
+
+    assert!(Permissions::parse(&strings(&["@read"]), &[]).is_err());
+    assert!(Permissions::parse(&strings(&["+@unknown"]), &[]).is_err());
+    assert!(Permissions::parse(&strings(&["-"]), &[]).is_err());
+}
@@ -3,66 +3,150 @@ use client::Client;
 use redflareproxy::ClientTokenValue;
 use backend::write_to_stream;
 use redflareproxy::{ADMIN_LISTENER};
+use tokens::{SharedTokenSlab, TokenKind, TokenSlab, TokenSlot};
 use redflareproxy::{ClientToken};
+use redflareproxy::ProxyError;
 use config::{AdminConfig};
 use bufreader::BufReader;
+use handoff;
+use netacl::NetworkAcl;
+use proxyprotocol::{self, HeaderRead};
+use registry::{Interest, Registered, Registry};
 
 use mio::*;
 use mio::tcp::{TcpListener};
 use hashbrown::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Admin command handling logs under this target, so that it follows the admin component's log level.
+pub const LOG_TARGET: &str = "redflareproxy::admin";
+
+// Reply to an admin command.
+pub enum AdminResponse {
+    // Most replies are written as bulk strings.
+    Bulk(String),
+    Status(String),
+    Error(String),
+}
+
+impl AdminResponse {
+    pub fn to_resp(&self) -> String {
+        match *self {
+            AdminResponse::Bulk(ref reply) => format!("${}\r\n{}\r\n", reply.len(), reply),
+            AdminResponse::Status(ref reply) => format!("+{}\r\n", reply),
+            AdminResponse::Error(ref reply) => format!("-{}\r\n", reply),
+        }
+    }
+
+    pub fn into_result(self) -> Result<String, String> {
+        match self {
+            AdminResponse::Bulk(reply) | AdminResponse::Status(reply) => Ok(reply),
+            AdminResponse::Error(reply) => Err(reply),
+        }
+    }
+}
 
 pub struct AdminPort {
     pub client_sockets: HashMap<ClientTokenValue, BufferedClient>,
-    pub socket: TcpListener,
+    pub socket: Registered<TcpListener>,
     pub config: AdminConfig,
+    pub client_tokens: SharedTokenSlab,
+    // Only set when the admin's allow_clients or deny_clients are configured.
+    network_acl: Option<NetworkAcl>,
+    // Connections refused by network_acl.
+    pub denied_connections: usize,
 }
 
 impl AdminPort {
-    pub fn new(config: AdminConfig, poll : &Poll) -> AdminPort {
-        // TODO: Add configuration for tcp backlog
-
-        let addr = match config.listen.parse() {
-            Ok(addr) => addr,
-            Err(error) => {
-                panic!("Unable to parse the admin listen port from config: {}. Reason: {:?}", config.listen, error);
-            }
-        };
-
-        // Setup the server socket
-        let server_socket = match TcpListener::bind(&addr) {
-            Ok(socket) => socket,
+    pub fn new(config: AdminConfig, registry: &Registry) -> Result<AdminPort, ProxyError> {
+        let server_socket = try!(bind(&config));
+        let server_socket = match Registered::new(registry, server_socket, ADMIN_LISTENER, Interest::Readable) {
+            Ok(server_socket) => server_socket,
             Err(error) => {
-                panic!("Unable to bind to admin list port: {:?}. Reason: {:?}", addr, error);
-            }
-        };
-
-        match poll.register(&server_socket, ADMIN_LISTENER, Ready::readable(), PollOpt::edge()) {
-            Ok(_) => {}
-            Err(error) => {
-                panic!("Failed to register admin listener socket to poll. Reason: {:?}", error);
+                return Err(ProxyError::AdminPollFailure(error));
             }
         };
         debug!("Registered admin socket.");
 
-        AdminPort {
+        // The networks were validated when the config was parsed.
+        let network_acl = NetworkAcl::from_config(&config.allow_clients, &config.deny_clients).unwrap_or(None);
+        Ok(AdminPort {
             client_sockets: HashMap::new(),
             socket: server_socket,
             config: config,
+            client_tokens: TokenSlab::shared(TokenKind::AdminClient),
+            network_acl: network_acl,
+            denied_connections: 0,
+        })
+    }
+
+    /*
+        Switches the admin port to a new config. The listener is only replaced if its address changed, in which case
+        the old one is deregistered and closed once the new one is bound and registered. Connected clients keep their tokens, except
+        for those the new config no longer permits, which are closed. On error, the previous config is kept.
+    */
+    pub fn switch_config(&mut self, config: AdminConfig, registry: &Registry) -> Result<(), ProxyError> {
+        if config.listen != self.config.listen {
+            let server_socket = try!(bind(&config));
+            let server_socket = match Registered::new(registry, server_socket, ADMIN_LISTENER, Interest::Readable) {
+                Ok(server_socket) => server_socket,
+                Err(error) => {
+                    return Err(ProxyError::AdminPollFailure(error));
+                }
+            };
+            info!(target: LOG_TARGET, "Moved the admin port from {} to {}", self.config.listen, config.listen);
+            self.socket = server_socket;
         }
+        self.network_acl = NetworkAcl::from_config(&config.allow_clients, &config.deny_clients).unwrap_or(None);
+        self.config = config;
+
+        let mut denied = Vec::new();
+        for (token_value, client) in self.client_sockets.iter() {
+            if let Some(addr) = client.get_ref().peer_addr {
+                let permitted = match self.network_acl {
+                    Some(ref network_acl) => network_acl.permits(&addr.ip()),
+                    None => true,
+                };
+                if !permitted {
+                    denied.push(*token_value);
+                }
+            }
+        }
+        for token_value in denied {
+            self.close_client(Token(token_value));
+        }
+        return Ok(());
     }
 
-    pub fn accept_client_connection(&mut self, next_admin_token: usize, poll: &mut Poll) {
+    // Closes an admin client, which deregisters it and releases its token.
+    pub fn close_client(&mut self, client_token: ClientToken) {
+        if self.client_sockets.remove(&client_token.0).is_some() {
+            debug!(target: LOG_TARGET, "Closing admin client: {:?}", client_token);
+        }
+    }
+
+    pub fn accept_client_connection(&mut self, registry: &Registry) {
         loop {
             match self.socket.accept() {
-                Ok((s, _)) => {
-                    let token = Token(next_admin_token);
-                    match poll.register(&s, token, Ready::readable(), PollOpt::edge()) {
-                        Ok(_) => {}
+                Ok((s, addr)) => {
+                    // Behind a load balancer, the client's own address is only known once its PROXY protocol header is read.
+                    if !self.config.proxy_protocol && !self.permits_client(&addr) {
+                        continue;
+                    }
+                    let slot = TokenSlot::allocate(&self.client_tokens);
+                    let token = slot.token();
+                    match Registered::new(registry, s, token, Interest::Readable) {
+                        Ok(s) => {
+                            let mut client = Client::new(s, slot);
+                            client.peer_addr = Some(addr);
+                            client.proxy_header_pending = self.config.proxy_protocol;
+                            self.client_sockets.insert(token.0, BufReader::new(client));
+                        }
                         Err(error) => {
                             error!("Failed to register admin client socket to poll. Reason: {:?}", error);
                         }
                     };
-                    self.client_sockets.insert(token.0, BufReader::new(Client::new(s)));
                 }
                 Err(error) => {
                     if error.kind() == std::io::ErrorKind::WouldBlock {
@@ -75,6 +159,54 @@ impl AdminPort {
         }
     }
 
+    fn permits_client(&mut self, addr: &SocketAddr) -> bool {
+        if let Some(ref network_acl) = self.network_acl {
+            if !network_acl.permits(&addr.ip()) {
+                self.denied_connections += 1;
+                warn!(target: LOG_TARGET, "Refused admin connection from {}", addr);
+                return false;
+            }
+        }
+        return true;
+    }
+
+    /*
+        Reads the PROXY protocol header that starts each admin connection with proxy_protocol. Returns whether the
+        client's commands can be read, closing the client if its header is invalid or its address isn't permitted.
+    */
+    pub fn read_proxy_header(&mut self, client_token: ClientToken) -> bool {
+        let (read, peer_addr, buffered) = match self.client_sockets.get_mut(&client_token.0) {
+            Some(client) => {
+                if !client.get_ref().proxy_header_pending {
+                    return true;
+                }
+                let read = proxyprotocol::read_header(client);
+                if let HeaderRead::Done(Some(source)) = read {
+                    client.get_mut().peer_addr = Some(source);
+                }
+                (read, client.get_ref().peer_addr, !client.buffer().is_empty())
+            }
+            None => return false,
+        };
+        let keep = match read {
+            HeaderRead::Done(_) => peer_addr.map_or(true, |addr| self.permits_client(&addr)),
+            HeaderRead::Wait => return false,
+            HeaderRead::Closed => false,
+            HeaderRead::Invalid(err) => {
+                warn!(target: LOG_TARGET, "Closing admin client {:?} with an invalid PROXY protocol header: {}", client_token, err);
+                false
+            }
+        };
+        if !keep {
+            self.client_sockets.remove(&client_token.0);
+            return false;
+        }
+        if let Some(client) = self.client_sockets.get_mut(&client_token.0) {
+            client.get_mut().proxy_header_pending = false;
+        }
+        return buffered;
+    }
+
     pub fn write_to_client(&mut self, client_token: ClientToken, message: String) {
         match self.client_sockets.get_mut(&client_token.0) {
             Some(client) => {
@@ -92,4 +224,39 @@ impl AdminPort {
 
         self.client_sockets.remove(&client_token.0);
     }
+
+    // Closes admin clients that hung up, or that haven't sent a command within the idle_timeout.
+    pub fn sweep_clients(&mut self, now: Instant) {
+        let idle_timeout = Duration::from_millis(self.config.idle_timeout as u64);
+        let mut expired = Vec::new();
+        for (token_value, client) in self.client_sockets.iter() {
+            let client = client.get_ref();
+            if (self.config.idle_timeout > 0 && client.idle_time(now) >= idle_timeout) || client.has_hung_up() {
+                expired.push(*token_value);
+            }
+        }
+        for token_value in expired {
+            debug!(target: LOG_TARGET, "Closing admin client: {:?}", Token(token_value));
+            self.client_sockets.remove(&token_value);
+        }
+    }
+}
+
+// Binds the admin listener, unless one was handed off by a hot restart.
+fn bind(config: &AdminConfig) -> Result<TcpListener, ProxyError> {
+    // TODO: Add configuration for tcp backlog
+    let addr = match config.listen.parse() {
+        Ok(addr) => addr,
+        Err(error) => {
+            return Err(ProxyError::AdminParseFailure(config.listen.clone(), error));
+        }
+    };
+    let bound = match handoff::take_listener(&addr) {
+        Some(inherited) => inherited,
+        None => TcpListener::bind(&addr),
+    };
+    match bound {
+        Ok(socket) => return Ok(socket),
+        Err(error) => return Err(ProxyError::AdminBindSocketFailure(addr, error)),
+    }
 }
@@ -0,0 +1,141 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use humantime;
+use config::RedFlareProxyConfig;
+use slowlog::{split_args, sanitize_command};
+
+// Longest request that is written to the audit log. Longer ones are truncated.
+const MAX_REQUEST_BYTES: usize = 512;
+
+/*
+    Records who issued a sensitive command, and when, for compliance and post-incident forensics.
+    Entries are written before the request is forwarded. If the entry can't be written, the request is rejected, so
+    that no sensitive command goes unrecorded.
+*/
+pub struct AuditLog {
+    path: String,
+    // Upper-case command names, e.g. FLUSHALL.
+    commands: Vec<Vec<u8>>,
+    // DEL and UNLINK are recorded when a key matches one of these glob patterns.
+    key_patterns: Vec<Vec<u8>>,
+}
+
+impl AuditLog {
+    // Returns None when no audit_log_file is configured.
+    pub fn from_config(config: &RedFlareProxyConfig) -> Option<AuditLog> {
+        match config.audit_log_file {
+            Some(ref path) => Some(AuditLog {
+                path: path.clone(),
+                commands: config.audit_commands.iter().map(|command| command.to_ascii_uppercase().into_bytes()).collect(),
+                key_patterns: config.audit_key_patterns.iter().map(|pattern| pattern.clone().into_bytes()).collect(),
+            }),
+            None => None,
+        }
+    }
+
+    pub fn is_sensitive(&self, request: &[u8]) -> bool {
+        let args = split_args(request);
+        let command = match args.first() {
+            Some(command) => command.to_ascii_uppercase(),
+            None => return false,
+        };
+        if self.commands.contains(&command) {
+            return true;
+        }
+        if &command[..] == b"DEL" || &command[..] == b"UNLINK" {
+            return args[1..].iter().any(|key| self.key_patterns.iter().any(|pattern| glob_match(pattern, key)));
+        }
+        return false;
+    }
+
+    /*
+        Appends an entry for the request as a single line. Passwords are masked, as in the slow log. e.g.:
+        time=2019-03-01T12:00:00Z pool=pool1 client=127.0.0.1:53211 command="FLUSHALL"
+    */
+    pub fn record(&self, pool: &str, client: Option<SocketAddr>, request: &[u8]) -> Result<(), std::io::Error> {
+        let client = match client {
+            Some(addr) => addr.to_string(),
+            None => "unknown".to_owned(),
+        };
+        let entry = format!(
+            "time={} pool={} client={} command={:?}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            pool,
+            client,
+            sanitize_command(request, MAX_REQUEST_BYTES)
+        );
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        // The entry is a single write, so that concurrent writers don't interleave lines.
+        try!(file.write_all(entry.as_bytes()));
+        return Ok(());
+    }
+}
+
+// Matches a key against a glob pattern, where * matches any sequence of bytes and ? matches any single byte.
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Position of the last *, and the key position it is currently matched up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, k));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            k = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    return pattern[p..].iter().all(|&b| b == b'*');
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match(b"session:*", b"session:1"));
+    assert!(glob_match(b"session:*", b"session:"));
+    assert!(glob_match(b"*:admin", b"user:1:admin"));
+    assert!(glob_match(b"user:?", b"user:1"));
+    assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+    assert!(!glob_match(b"user:?", b"user:12"));
+    assert!(!glob_match(b"session:*", b"user:1"));
+    assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+}
+
+#[test]
+fn test_audit_log() {
+    use config::load_config;
+    use std::io::Read;
+
+    let path = std::env::temp_dir().join(format!("redflare-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = load_config("tests/conf/testconfig1.toml".to_owned(), None).unwrap();
+    assert!(AuditLog::from_config(&config).is_none());
+
+    config.audit_log_file = Some(path.to_str().unwrap().to_owned());
+    config.audit_key_patterns = vec!["session:*".to_owned()];
+    let audit_log = AuditLog::from_config(&config).unwrap();
+    assert!(audit_log.is_sensitive(b"*1\r\n$8\r\nflushall\r\n"));
+    assert!(audit_log.is_sensitive(b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$1\r\n*\r\n"));
+    assert!(audit_log.is_sensitive(b"*3\r\n$3\r\nDEL\r\n$6\r\nuser:1\r\n$9\r\nsession:1\r\n"));
+    assert!(!audit_log.is_sensitive(b"*2\r\n$3\r\nDEL\r\n$6\r\nuser:1\r\n"));
+    assert!(!audit_log.is_sensitive(b"*2\r\n$3\r\nGET\r\n$9\r\nsession:1\r\n"));
+
+    let client: SocketAddr = "127.0.0.1:53211".parse().unwrap();
+    audit_log.record("pool1", Some(client), b"*1\r\n$8\r\nFLUSHALL\r\n").unwrap();
+    audit_log.record("pool1", None, b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$11\r\nrequirepass\r\n$6\r\nsecret\r\n").unwrap();
+    let mut contents = String::new();
+    std::fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("time="), "{}", lines[0]);
+    assert!(lines[0].ends_with(" pool=pool1 client=127.0.0.1:53211 command=\"FLUSHALL\""), "{}", lines[0]);
+    assert!(lines[1].ends_with(" client=unknown command=\"CONFIG SET requirepass (redacted)\""), "{}", lines[1]);
+}
@@ -1,19 +1,20 @@
 use client::BufferedClient;
-use stats::Stats;
+use stats::{Stats, LatencyHistogram, CommandStats, ErrorStats, ConnectionStats, MemoryStats, QueueStats, command_stats, micros_since};
 use redflareproxy::ClientTokenValue;
 use redisprotocol::WriteError;
 use redflareproxy::PoolTokenValue;
 use std::net::SocketAddr;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use redflareproxy::ClientToken;
 use redflareproxy::BackendToken;
 use client::Client;
 use bufreader::BufReader;
-use redflareproxy::{NULL_TOKEN};
-use config::BackendConfig;
+use redflareproxy::{NULL_TOKEN, TimerEvent, Timers};
+use config::{BackendConfig, BackendPoolConfig};
 use mio::*;
-use mio_more::timer::{Timer, Builder};
+use log::LogLevel;
 use mio::tcp::{TcpStream};
+use iovec::IoVec;
 use std::collections::{VecDeque};
 use std::string::String;
 use std::io::{Read, Write, BufRead};
@@ -22,8 +23,31 @@ use std::time::Instant;
 use std::cell::RefCell;
 use std::rc::Rc;
 use cluster_backend::{ClusterBackend};
+use replica::Replicas;
+use redflareproxy::convert_token_to_cluster_index;
+use tokens::{self, TokenKind};
 use redisprotocol::extract_redis_command;
 use redisprotocol::RedisError;
+use redisprotocol::command_name;
+use retry::RetryPolicy;
+use concurrency::ConcurrencyLimiter;
+use trace::{RequestTrace, BackendTraces};
+use slowlog::{SlowLog, SlowRequest};
+use capture::Direction;
+use filter::{encode_command, FilterAction, SharedFilters};
+use timerwheel::TimerId;
+use mirror::MirrorKind;
+use chaos::{Fault, SharedChaos};
+use happyeyeballs::{self, ConnectRace, RaceState};
+use fastopen;
+use outagebuffer::{HeldRequest, OutageBuffer};
+use clock;
+use collapse;
+use functions;
+use registry::{Interest, Registered, Registry};
+
+// Marks a queued request that was already answered for passing its DEADLINE. The backend's response to it is discarded.
+const TIMED_OUT: ClientToken = Token(1 << tokens::KIND_BITS | TokenKind::Null as usize);
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BackendStatus {
@@ -34,6 +58,38 @@ pub enum BackendStatus {
     LOADING,
 }
 
+/*
+    How much a backend buffers before the clients sending to it are paused, from the pool's backend_queue_limit and
+    backend_output_limit. 0 disables a limit.
+*/
+#[derive(Clone, Copy)]
+pub struct QueueLimits {
+    requests: usize,
+    output_bytes: usize,
+}
+
+impl QueueLimits {
+    pub fn new(requests: usize, output_bytes: usize) -> QueueLimits {
+        QueueLimits {
+            requests: requests,
+            output_bytes: output_bytes,
+        }
+    }
+
+    pub fn from_config(config: &BackendPoolConfig) -> QueueLimits {
+        QueueLimits::new(config.backend_queue_limit, config.backend_output_limit)
+    }
+
+    fn is_full(&self, queued: usize, output_bytes: usize) -> bool {
+        return (self.requests > 0 && queued >= self.requests) || (self.output_bytes > 0 && output_bytes >= self.output_bytes);
+    }
+
+    // Paused clients are resumed once the backend is down to half of its limits, so that they aren't paused again right away.
+    fn has_room(&self, queued: usize, output_bytes: usize) -> bool {
+        return (self.requests == 0 || queued <= self.requests / 2) && (self.output_bytes == 0 || output_bytes <= self.output_bytes / 2);
+    }
+}
+
 pub enum BackendEnum {
     Single(SingleBackend),
     Cluster(ClusterBackend),
@@ -41,39 +97,115 @@ pub enum BackendEnum {
 
 pub struct Backend {
     pub weight: usize,
+    // Set for the pool's canary, which isn't sharded any keys, only sent copies of requests.
+    pub canary: bool,
     pub single: BackendEnum,
+    // Only set for a non-cluster backend with replicas, in a pool with replica_fallback or hedge_delay.
+    replicas: Replicas,
+    // Whether reads go to the replicas while the backend is down.
+    replica_fallback: bool,
+    // Only set for a non-cluster backend with a secondary, in a pool with dual_write. Kept in cluster_backends too.
+    secondary: Option<BackendToken>,
 }
 impl Backend {
     pub fn new(
         config: BackendConfig,
         token: BackendToken,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
-        poll_registry: &Rc<RefCell<Poll>>,
-        next_cluster_token_value: &mut usize,
+        registry: &Registry,
+        timers: &Timers,
+        next_cluster_index: &mut usize,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_policy: RetryPolicy,
+        queue_limits: QueueLimits,
+        concurrency_limiter: Option<ConcurrencyLimiter>,
+        big_value_threshold: usize,
+        slowlog: SlowLog,
         pool_token: PoolTokenValue,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
+        replica_fallback: bool,
+        hedge_reads: bool,
+        dual_write: bool,
     ) -> (Backend, Vec<Token>) {
         let weight = config.weight;
+        let canary = config.canary;
+        let mut replica_tokens = Vec::new();
+        if replica_fallback || hedge_reads {
+            for host in config.replicas.iter() {
+                let replica_token = tokens::token(TokenKind::ClusterServer, *next_cluster_index);
+                *next_cluster_index += 1;
+                let (replica, _) = SingleBackend::new(
+                    config.clone(),
+                    *host,
+                    replica_token,
+                    registry,
+                    timers,
+                    timeout,
+                    failure_limit,
+                    retry_policy.clone(),
+                    queue_limits,
+                    concurrency_limiter.clone(),
+                    big_value_threshold,
+                    slowlog.clone(),
+                    pool_token,
+                    num_backends,
+                    cached_backend_shards,
+                );
+                cluster_backends.push((replica, token.0));
+                replica_tokens.push(replica_token);
+            }
+        }
+        let mut secondary_token = None;
+        if let Some(host) = config.secondary.filter(|_| dual_write) {
+            let token_for_secondary = tokens::token(TokenKind::ClusterServer, *next_cluster_index);
+            *next_cluster_index += 1;
+            let (mut secondary, _) = SingleBackend::new(
+                config.clone(),
+                host,
+                token_for_secondary,
+                registry,
+                timers,
+                timeout,
+                failure_limit,
+                retry_policy.clone(),
+                queue_limits,
+                concurrency_limiter.clone(),
+                big_value_threshold,
+                slowlog.clone(),
+                pool_token,
+                num_backends,
+                cached_backend_shards,
+            );
+            secondary.mirror = Some(MirrorKind::Secondary);
+            cluster_backends.push((secondary, token.0));
+            secondary_token = Some(token_for_secondary);
+        }
         let (backend, all_backend_tokens) = match config.use_cluster {
             false => {
                 // The config should be validated to have a host when not using cluster. See load_config.
                 let host = config.host.unwrap().clone();
-                let (backend, tokens) = SingleBackend::new(
+                let (mut backend, tokens) = SingleBackend::new(
                     config,
                     host,
                     token,
-                    poll_registry,
+                    registry,
+                    timers,
                     timeout,
                     failure_limit,
-                    retry_timeout,
+                    retry_policy,
+                    queue_limits,
+                    concurrency_limiter,
+                    big_value_threshold,
+                    slowlog,
                     pool_token,
                     num_backends,
                     cached_backend_shards,
                 );
+                if canary {
+                    backend.mirror = Some(MirrorKind::Canary);
+                }
                 (BackendEnum::Single(backend), tokens)
             }
             true => {
@@ -81,11 +213,16 @@ impl Backend {
                     config,
                     token,
                     cluster_backends,
-                    poll_registry,
-                    next_cluster_token_value,
+                    registry,
+                    timers,
+                    next_cluster_index,
                     timeout,
                     failure_limit,
-                    retry_timeout,
+                    retry_policy,
+                    queue_limits,
+                    concurrency_limiter,
+                    big_value_threshold,
+                    slowlog,
                     pool_token,
                     num_backends,
                     cached_backend_shards,
@@ -96,12 +233,81 @@ impl Backend {
         (Backend {
             single: backend,
             weight: weight,
+            canary: canary,
+            replicas: Replicas::new(replica_tokens),
+            replica_fallback: replica_fallback,
+            secondary: secondary_token,
         }, all_backend_tokens)
     }
 
+    // Has the pool's chaos mode inject faults on the backend's host and its replicas. A cluster's hosts are left alone.
+    pub fn set_chaos(&mut self, chaos: &SharedChaos, cluster_backends: &mut [(SingleBackend, usize)]) {
+        if let BackendEnum::Single(ref mut backend) = self.single {
+            backend.chaos = Some(Rc::clone(chaos));
+            for replica in self.replicas.get_mut(cluster_backends) {
+                replica.chaos = Some(Rc::clone(chaos));
+            }
+        }
+    }
+
+    // Has a non-cluster backend, its replicas and its secondary connect with TCP Fast Open. See fastopen.rs.
+    pub fn enable_fastopen(&mut self, cluster_backends: &mut [(SingleBackend, usize)]) {
+        if let BackendEnum::Single(ref mut backend) = self.single {
+            backend.fastopen = true;
+            for replica in self.replicas.get_mut(cluster_backends) {
+                replica.fastopen = true;
+            }
+            if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                secondary.fastopen = true;
+            }
+        }
+    }
+
+    // Has a non-cluster backend hold its requests while it reconnects, with the pool's outage_buffer_timeout.
+    pub fn set_outage_buffer(&mut self, outage_buffer: Option<OutageBuffer>) {
+        if let BackendEnum::Single(ref mut backend) = self.single {
+            backend.outage_buffer = outage_buffer;
+        }
+    }
+
+    // Has a non-cluster backend connect to the addresses of its hostname with happy eyeballs. See happyeyeballs.rs.
+    pub fn set_addresses(&mut self, addresses: Vec<SocketAddr>) {
+        if let BackendEnum::Single(ref mut backend) = self.single {
+            backend.addresses = addresses;
+        }
+    }
+
+    // Whether the token is of a replica or the secondary of this backend, which are kept in cluster_backends.
+    fn owns_host(&self, token: BackendToken) -> bool {
+        return self.replicas.owns(token) || self.secondary == Some(token);
+    }
+
+    fn secondary_mut<'a>(&self, cluster_backends: &'a mut [(SingleBackend, usize)]) -> Option<&'a mut SingleBackend> {
+        return match self.secondary {
+            Some(token) => cluster_backends.get_mut(convert_token_to_cluster_index(token.0)).map(|(secondary, _)| secondary),
+            None => None,
+        };
+    }
+
+    fn secondary<'a>(&self, cluster_backends: &'a [(SingleBackend, usize)]) -> Option<&'a SingleBackend> {
+        return match self.secondary {
+            Some(token) => cluster_backends.get(convert_token_to_cluster_index(token.0)).map(|(secondary, _)| secondary),
+            None => None,
+        };
+    }
+
     pub fn reregister_token(&mut self, new_token: BackendToken, cluster_backends: &mut Vec<(SingleBackend, usize)>, new_num_backends: usize) -> Result<(), std::io::Error> {
         match self.single {
-            BackendEnum::Single(ref mut backend) => backend.reregister_token(new_token, new_num_backends),
+            BackendEnum::Single(ref mut backend) => {
+                self.replicas.change_owner(cluster_backends, new_token, new_num_backends);
+                if let Some(token) = self.secondary {
+                    if let Some((secondary, owner_token_value)) = cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                        secondary.num_backends = new_num_backends;
+                        *owner_token_value = new_token.0;
+                    }
+                }
+                backend.reregister_token(new_token, new_num_backends)
+            }
             BackendEnum::Cluster(ref mut backend) => backend.reregister_token(new_token, cluster_backends, new_num_backends),
         }
     }
@@ -113,6 +319,177 @@ impl Backend {
         }
     }
 
+    /*
+        Returns the latency histogram of this backend. For a cluster backend, this merges the histograms of all of its hosts,
+        and for a backend with replicas, those of its replicas. Likewise for the other stats.
+    */
+    pub fn latency(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> LatencyHistogram {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut latency = backend.latency.clone();
+                for replica in self.replicas.get(cluster_backends) {
+                    latency.merge(&replica.latency);
+                }
+                latency
+            }
+            BackendEnum::Cluster(ref backend) => backend.latency(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the per-command stats of this backend. For a cluster backend, this merges the stats of all of its hosts.
+    */
+    pub fn command_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> HashMap<&'static str, CommandStats> {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut commands = backend.commands.clone();
+                for replica in self.replicas.get(cluster_backends) {
+                    for (command, command_stats) in &replica.commands {
+                        commands.entry(*command).or_insert_with(CommandStats::new).merge(command_stats);
+                    }
+                }
+                commands
+            }
+            BackendEnum::Cluster(ref backend) => backend.command_stats(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the error counters of this backend. For a cluster backend, this merges the counters of all of its hosts.
+    */
+    pub fn error_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> ErrorStats {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut errors = backend.errors.clone();
+                for replica in self.replicas.get(cluster_backends) {
+                    errors.merge(&replica.errors);
+                }
+                errors
+            }
+            BackendEnum::Cluster(ref backend) => backend.error_stats(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the connection churn counters of this backend. For a cluster backend, this merges the counters of all of
+        its hosts.
+    */
+    pub fn connection_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> ConnectionStats {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut connections = backend.connections.clone();
+                for replica in self.replicas.get(cluster_backends) {
+                    connections.merge(&replica.connections);
+                }
+                connections
+            }
+            BackendEnum::Cluster(ref backend) => backend.connection_stats(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the buffer and queue usage of this backend. For a cluster backend, this includes all of its hosts.
+    */
+    pub fn memory_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> MemoryStats {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut memory = backend.memory_stats();
+                for replica in self.replicas.get(cluster_backends) {
+                    memory.merge(&replica.memory_stats());
+                }
+                if let Some(secondary) = self.secondary(cluster_backends) {
+                    memory.merge(&secondary.memory_stats());
+                }
+                memory
+            }
+            BackendEnum::Cluster(ref backend) => backend.memory_stats(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the request queue of each host of this backend, by host. A cluster backend has one per cluster node.
+    */
+    pub fn queue_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<(String, QueueStats)> {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut queues = vec![(backend.host.to_string(), backend.queue_stats())];
+                for replica in self.replicas.get(cluster_backends) {
+                    queues.push((replica.host.to_string(), replica.queue_stats()));
+                }
+                if let Some(secondary) = self.secondary(cluster_backends) {
+                    queues.push((secondary.host.to_string(), secondary.queue_stats()));
+                }
+                queues
+            }
+            BackendEnum::Cluster(ref backend) => backend.queue_stats(cluster_backends),
+        }
+    }
+
+    // The host and AUTH request of each host of this backend. A cluster backend has one per cluster node.
+    pub fn hosts(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<(SocketAddr, Option<Vec<u8>>)> {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut hosts = vec![backend.host_and_auth()];
+                for replica in self.replicas.get(cluster_backends) {
+                    hosts.push(replica.host_and_auth());
+                }
+                hosts
+            }
+            BackendEnum::Cluster(ref backend) => backend.hosts(cluster_backends),
+        }
+    }
+
+    pub fn reset_stats(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.reset_stats();
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.reset_stats();
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.reset_stats();
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.reset_stats(cluster_backends),
+        }
+    }
+
+    /*
+        Returns the slow requests of this backend, oldest first. For a cluster backend, this includes all of its hosts.
+    */
+    pub fn slow_requests(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<SlowRequest> {
+        match self.single {
+            BackendEnum::Single(ref backend) => {
+                let mut slow_requests: Vec<SlowRequest> = backend.slowlog.entries.iter().cloned().collect();
+                for replica in self.replicas.get(cluster_backends) {
+                    slow_requests.extend(replica.slowlog.entries.iter().cloned());
+                }
+                slow_requests.sort_by_key(|slow_request| slow_request.start_time);
+                slow_requests
+            }
+            BackendEnum::Cluster(ref backend) => backend.slow_requests(cluster_backends),
+        }
+    }
+
+    pub fn reset_slowlog(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.slowlog.reset();
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.slowlog.reset();
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.reset_slowlog(cluster_backends),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self.single {
+            BackendEnum::Single(ref backend) => format!("{}", backend.host),
+            BackendEnum::Cluster(ref backend) => backend.name(),
+        }
+    }
+
     pub fn is_available(&self) -> bool {
         match self.single {
             BackendEnum::Single(ref backend) => backend.is_available(),
@@ -122,11 +499,105 @@ impl Backend {
 
     pub fn init_connection(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
         match self.single {
-            BackendEnum::Single(ref mut backend) => backend.init_connection(),
+            BackendEnum::Single(ref mut backend) => {
+                backend.init_connection();
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.init_connection();
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.init_connection();
+                }
+            }
             BackendEnum::Cluster(ref mut backend) => backend.init_connection(cluster_backends),
         }
     }
 
+    // The sentinel master name that this backend's host is discovered by, if any.
+    pub fn sentinel_master(&self) -> Option<&String> {
+        match self.single {
+            BackendEnum::Single(ref backend) => backend.config.sentinel_master.as_ref(),
+            BackendEnum::Cluster(_) => None,
+        }
+    }
+
+    // Points a non-cluster backend at another host. See SingleBackend::change_host.
+    pub fn change_host(
+        &mut self,
+        host: SocketAddr,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if let BackendEnum::Single(ref mut backend) = self.single {
+            backend.change_host(host, clients, completed_clients, stats);
+        }
+    }
+
+    // Closes the connection of each host of this backend, e.g. when the proxy shuts down.
+    pub fn disconnect(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.disconnect();
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.disconnect();
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.disconnect();
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.disconnect(cluster_backends),
+        }
+    }
+
+    /*
+        Closes this backend for good, e.g. when a config switch removes its pool. Unlike disconnect, requests still
+        waiting on its hosts get an error, and they aren't reconnected to.
+    */
+    pub fn close(
+        &mut self,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.close(clients, completed_clients, stats);
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.close(clients, completed_clients, stats);
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.close(clients, completed_clients, stats);
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.close(clients, cluster_backends, completed_clients, stats),
+        }
+    }
+
+    // Fails each host connection of this backend that has not been established within connect_timeout.
+    pub fn expire_connects(
+        &mut self,
+        now: Instant,
+        connect_timeout: Duration,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut [(SingleBackend, usize)],
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.expire_connect(now, connect_timeout, clients, completed_clients, stats);
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.expire_connect(now, connect_timeout, clients, completed_clients, stats);
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.expire_connect(now, connect_timeout, clients, completed_clients, stats);
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.expire_connects(now, connect_timeout, clients, cluster_backends, completed_clients, stats),
+        }
+    }
+
     pub fn handle_timeout(
         &mut self,
         token: Token,
@@ -135,6 +606,12 @@ impl Backend {
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) -> bool {
+        if self.owns_host(token) {
+            return match cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                Some((replica, _)) => replica.handle_timeout(clients, completed_clients, stats),
+                None => false,
+            };
+        }
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.handle_timeout(clients, completed_clients, stats),
             BackendEnum::Cluster(ref mut backend) => {
@@ -157,6 +634,11 @@ impl Backend {
         @param cluster_backends: Access to all cluster backends, in case this backend is a ClusterBackend.
         @param request_id: Unique identifier of the request, determined by time and id. Id will always be 0 for normal
                            requests. Multikey requests are split into many requests, with each one having an id of > 0.
+        @param trace: Trace of the request, if it was sampled for tracing.
+        @param timeout: Milliseconds the request may take, if the client set it with DEADLINE. None uses the pool's
+                        timeout.
+        @return: Whether the backend is now full. If so, the client should stop being read from until the backend
+                 resumes it through completed_clients.
     */
     pub fn write_message(
         &mut self,
@@ -164,37 +646,152 @@ impl Backend {
         client_token: ClientToken,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
         request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
         stats: &mut Stats,
-    ) -> Result<(), WriteError> {
+    ) -> Result<bool, WriteError> {
         match self.single {
-            BackendEnum::Single(ref mut backend) => backend.write_message(message, client_token, request_id, stats),
+            BackendEnum::Single(ref mut backend) => {
+                // Reads go to a replica while the backend is down.
+                if self.replica_fallback && !backend.is_available() && self.replicas.serves(message) {
+                    return self.replicas.write_message(message, client_token, cluster_backends, request_id, trace, timeout, stats);
+                }
+                backend.write_message(message, client_token, request_id, trace, timeout, stats)
+            }
             BackendEnum::Cluster(ref mut backend) => {
                 backend.write_message(
                     message,
                     client_token,
                     cluster_backends,
                     request_id,
+                    trace,
+                    timeout,
                     stats,
                 )
             }
         }
     }
 
+    /*
+        Writes a read to one of the backend's replicas, with read_from_replicas. Other requests, and reads while none
+        of the replicas are available, are written to the backend itself.
+    */
+    pub fn write_to_replica(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
+        stats: &mut Stats,
+    ) -> Result<bool, WriteError> {
+        if self.replicas.serves(message) && self.replicas.any_available(cluster_backends) {
+            return self.replicas.write_message(message, client_token, cluster_backends, request_id, trace, timeout, stats);
+        }
+        return self.write_message(message, client_token, cluster_backends, request_id, trace, timeout, stats);
+    }
+
+    // Whether a read about to be written to this backend could be hedged, i.e. the backend is up and has replicas.
+    pub fn can_hedge(&self, message: &[u8]) -> bool {
+        match self.single {
+            BackendEnum::Single(ref backend) => backend.is_available() && self.replicas.serves(message),
+            BackendEnum::Cluster(_) => false,
+        }
+    }
+
+    // Schedules the hedge of a read just written to the backend, which is sent after the delay unless it's answered by then.
+    pub fn schedule_hedge(&self, client_token: ClientToken, request_id: (Instant, usize), delay: Duration) {
+        if let BackendEnum::Single(ref backend) = self.single {
+            backend.schedule_hedge(client_token, request_id, delay);
+        }
+    }
+
+    // Sends the duplicate of a read that the backend hasn't answered in time to one of its replicas.
+    pub fn write_hedge(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<bool, WriteError> {
+        return self.replicas.write_message(message, client_token, cluster_backends, request_id, None, None, stats);
+    }
+
+    /*
+        Writes a mutation that was just written to the backend to its secondary too, in a dual_write pool. Fails if the
+        backend has no secondary, or it's down. The secondary being full doesn't pause the client.
+    */
+    pub fn write_secondary(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        request_id: (Instant, usize),
+        stats: &mut Stats,
+    ) -> Result<(), WriteError> {
+        match self.secondary_mut(cluster_backends) {
+            Some(secondary) => secondary.write_message(message, client_token, request_id, None, None, stats).map(|_| ()),
+            None => Err(WriteError::BackendNotReady),
+        }
+    }
+
+    /*
+        Writes the client requests buffered by write_message. If the write fails, the backend is marked as down, and
+        its pending requests receive errors.
+    */
+    pub fn flush_output(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => {
+                backend.flush_output(clients, completed_clients, stats);
+                for replica in self.replicas.get_mut(cluster_backends) {
+                    replica.flush_output(clients, completed_clients, stats);
+                }
+                if let Some(secondary) = self.secondary_mut(cluster_backends) {
+                    secondary.flush_output(clients, completed_clients, stats);
+                }
+            }
+            BackendEnum::Cluster(ref mut backend) => backend.flush_output(clients, cluster_backends, completed_clients, stats),
+        }
+    }
+
+    // Moves the traces of completed requests into the given list. The traces of a cluster, and of replicas, are kept by their cluster backends.
+    pub fn drain_traces(&mut self, traces: &mut Vec<RequestTrace>) {
+        match self.single {
+            BackendEnum::Single(ref mut backend) => traces.append(&mut backend.traces.finished),
+            BackendEnum::Cluster(_) => {}
+        }
+    }
+
     pub fn handle_backend_response(
         &mut self,
         token: BackendToken,
         clients: &mut HashMap<usize, (BufferedClient, usize)>,
-        next_cluster_token_value: &mut usize,
+        next_cluster_index: &mut usize,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) {
+        if self.owns_host(token) {
+            if let Some((replica, _)) = cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                let mut resp_handler = |_response: &[u8]| -> () {};
+                replica.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
+            }
+            return;
+        }
         match self.single {
             BackendEnum::Single(ref mut backend) => {
                 let mut resp_handler = |_response: &[u8]| -> () {};
                 backend.handle_backend_response(clients, &mut resp_handler, completed_clients, stats);
             }
-            BackendEnum::Cluster(ref mut backend) => backend.handle_backend_response(token, clients, next_cluster_token_value, cluster_backends, completed_clients, stats),
+            BackendEnum::Cluster(ref mut backend) => backend.handle_backend_response(token, clients, next_cluster_index, cluster_backends, completed_clients, stats),
         };
     }
 
@@ -206,6 +803,12 @@ impl Backend {
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) {
+        if self.owns_host(token) {
+            if let Some((replica, _)) = cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                replica.handle_backend_failure(clients, completed_clients, stats);
+            }
+            return;
+        }
         match self.single {
             BackendEnum::Single(ref mut backend) => backend.handle_backend_failure(clients, completed_clients, stats),
             BackendEnum::Cluster(ref mut backend) => backend.handle_backend_failure(token, clients, cluster_backends, completed_clients, stats),
@@ -218,32 +821,95 @@ pub struct SingleBackend {
     status: BackendStatus,
     pub weight: usize,
     host: SocketAddr,
-    pub queue: VecDeque<(ClientToken, Instant, usize)>,
+    /*
+        Pending requests: the client, the request's timeout deadline, the multikey request id, the command name, and
+        the request's timeout. A timeout of 0 means the request has no deadline.
+    */
+    pub queue: VecDeque<(ClientToken, Instant, usize, &'static str, Duration)>,
+    // Longest the queue has been since the stats were reset.
+    max_queue_depth: usize,
+    queue_limits: QueueLimits,
+    // Adapts how many client requests may be unanswered at once. None when the pool has no max_concurrency.
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    // Clients that stopped being read from because this backend was full, resumed once it has room again.
+    paused_clients: Vec<ClientToken>,
     failure_limit: usize,
-    retry_timeout: usize,
+    retry_policy: RetryPolicy,
+    // Requests and responses larger than this many bytes are logged as warnings. 0 disables the warning.
+    big_value_threshold: usize,
+    // Requests slower than the pool's slowlog_threshold.
+    pub slowlog: SlowLog,
     failure_count: usize,
     config: BackendConfig,
     pool_token: usize,
-    poll_registry: Rc<RefCell<Poll>>,
-    socket: Option<BufReader<TcpStream>>,
-    timer: Option<Timer<Instant>>,
-    retry_timer: Option<Timer<Instant>>,
+    registry: Registry,
+    timers: Timers,
+    socket: Option<BufReader<Registered<TcpStream>>>,
+    // Client requests written since the last flush_output. Pipelined requests are sent with a single write.
+    output: Vec<u8>,
+    // Number of client requests in output.
+    output_requests: usize,
+    // Pending deadline for the earliest request in queue. It may be for a request that has since been answered.
+    request_timer: Option<TimerId>,
+    // When request_timer is due.
+    request_deadline: Instant,
+    retry_timer: Option<TimerId>,
     pub timeout: usize,
     waiting_for_auth_resp: bool,
     waiting_for_db_resp: bool,
     waiting_for_ping_resp: bool,
     pub num_backends: usize,
     cached_backend_shards: Rc<RefCell<Option<Vec<usize>>>>,
+    // Time from reading a client request to receiving its response from this backend.
+    pub latency: LatencyHistogram,
+    // Error responses and latency per command. Split multikey requests are counted under the command they were split into.
+    pub commands: HashMap<&'static str, CommandStats>,
+    pub errors: ErrorStats,
+    // Reconnects and handshake failures, and clients dropped while writing them a response.
+    pub connections: ConnectionStats,
+    // Whether a connection has been established before, so that later connections count as reconnects.
+    has_connected: bool,
+    // Whether the backend rejected the current connection's handshake, so that it is only counted once.
+    handshake_rejected: bool,
+    // When the current connection attempt started, to time out connections that are never established.
+    connect_started: Instant,
+    // Traces of sampled requests.
+    pub traces: BackendTraces,
+    // Set for the secondary of a dual_write backend and for a canary, whose responses are compared instead of written to clients.
+    pub mirror: Option<MirrorKind>,
+    // Only set when the pool has chaos configured.
+    pub chaos: Option<SharedChaos>,
+    // Pending while chaos mode holds back the output. See Fault::Latency.
+    flush_timer: Option<TimerId>,
+    // Set when chaos mode picked a disconnect, which happens on the next flush_output.
+    chaos_disconnect: bool,
+    // The addresses of the backend's hostname, in the order to connect to them. Empty for a fixed host.
+    addresses: Vec<SocketAddr>,
+    // The connection attempts to the addresses while connecting to more than one. The winner becomes the socket.
+    race: Option<ConnectRace>,
+    // Pending while the race has addresses that haven't been attempted yet.
+    attempt_timer: Option<TimerId>,
+    // Whether connections are made with TCP Fast Open, with the pool's backend_tcp_fastopen.
+    fastopen: bool,
+    // Requests held while the backend isn't ready, with the pool's outage_buffer_timeout. See outagebuffer.rs.
+    outage_buffer: Option<OutageBuffer>,
+    // Pending while the outage buffer holds requests, for when the oldest of them expires.
+    outage_timer: Option<TimerId>,
 }
 impl SingleBackend {
     pub fn new(
         config: BackendConfig,
         host: SocketAddr,
         token: BackendToken,
-        poll_registry: &Rc<RefCell<Poll>>,
+        registry: &Registry,
+        timers: &Timers,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_policy: RetryPolicy,
+        queue_limits: QueueLimits,
+        concurrency_limiter: Option<ConcurrencyLimiter>,
+        big_value_threshold: usize,
+        slowlog: SlowLog,
         pool_token: usize,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
@@ -254,47 +920,91 @@ impl SingleBackend {
             host : host,
             token : token,
             queue: VecDeque::with_capacity(4096),
+            max_queue_depth: 0,
+            queue_limits: queue_limits,
+            concurrency_limiter: concurrency_limiter,
+            paused_clients: Vec::new(),
             status: BackendStatus::DISCONNECTED,
             timeout: timeout,
-            poll_registry: Rc::clone(poll_registry),
+            registry: registry.clone(),
+            timers: Rc::clone(timers),
             failure_limit: failure_limit,
-            retry_timeout: retry_timeout,
+            retry_policy: retry_policy,
+            big_value_threshold: big_value_threshold,
+            slowlog: slowlog,
             failure_count: 0,
             weight: config.weight,
             config: config,
             pool_token: pool_token,
             socket: None,
-            timer: None,
+            output: Vec::new(),
+            output_requests: 0,
+            request_timer: None,
+            request_deadline: clock::now(),
             retry_timer: None,
             waiting_for_auth_resp: false,
             waiting_for_db_resp: false,
             waiting_for_ping_resp: false,
             num_backends: num_backends,
             cached_backend_shards: Rc::clone(cached_backend_shards),
+            latency: LatencyHistogram::new(),
+            commands: HashMap::new(),
+            errors: ErrorStats::new(),
+            connections: ConnectionStats::new(),
+            has_connected: false,
+            handshake_rejected: false,
+            connect_started: clock::now(),
+            traces: BackendTraces::new(),
+            mirror: None,
+            chaos: None,
+            flush_timer: None,
+            chaos_disconnect: false,
+            addresses: Vec::new(),
+            race: None,
+            attempt_timer: None,
+            fastopen: false,
+            outage_buffer: None,
+            outage_timer: None,
         };
         (backend, Vec::new())
     }
 
     pub fn reregister_token(&mut self, new_token: BackendToken, new_num_backends: usize) -> Result<(), std::io::Error> {
         self.num_backends = new_num_backends;
-        match self.socket {
-            Some(ref s) => {
-                self.token = new_token;
-                try!(self.poll_registry.borrow_mut().reregister(s.get_ref(), new_token, Ready::readable() | Ready::writable(), PollOpt::edge()));
+        // Set even while disconnected, so that the retry reconnects with the new token.
+        self.token = new_token;
+        if let Some(ref mut s) = self.socket {
+            try!(s.get_mut().set_token(new_token));
+        }
+        if let Some(ref mut race) = self.race {
+            try!(race.set_token(new_token));
+        }
+        // Pending deadlines move to the new token too.
+        let mut timers = self.timers.borrow_mut();
+        if let Some(id) = self.retry_timer {
+            if let Some(event) = timers.get_mut(id) {
+                *event = TimerEvent::Retry(new_token);
+            }
+        }
+        if let Some(id) = self.request_timer {
+            if let Some(event) = timers.get_mut(id) {
+                *event = TimerEvent::RequestTimeout(new_token);
+            }
+        }
+        if let Some(id) = self.flush_timer {
+            if let Some(event) = timers.get_mut(id) {
+                *event = TimerEvent::Flush(new_token);
             }
-            None => {}
         }
-        match self.retry_timer {
-            Some(ref t) => {
-                try!(self.poll_registry.borrow_mut().reregister(t, Token(new_token.0 + self.num_backends), Ready::readable(), PollOpt::edge()));
+        if let Some(id) = self.attempt_timer {
+            if let Some(event) = timers.get_mut(id) {
+                *event = TimerEvent::ConnectAttempt(new_token);
             }
-            None => {}
         }
-        match self.timer {
-            Some(ref t) => {
-                try!(self.poll_registry.borrow_mut().reregister(t, Token(new_token.0 + 2* self.num_backends), Ready::readable(), PollOpt::edge()));
+        if let Some(id) = self.outage_timer {
+            if let Some(event) = timers.get_mut(id) {
+                *event = TimerEvent::OutageBuffer(new_token);
             }
-            None => {}
         }
         return Ok(());
     }
@@ -307,11 +1017,46 @@ impl SingleBackend {
         return self.status == BackendStatus::READY;
     }
 
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut memory = MemoryStats::new();
+        if let Some(ref socket) = self.socket {
+            memory.backend_read_buffer_bytes = socket.buf.len();
+        }
+        memory.queued_requests = self.queue.len();
+        memory.request_queue_bytes = self.queue.capacity() * std::mem::size_of::<(ClientToken, Instant, usize, &'static str, Duration)>();
+        if let Some(ref outage_buffer) = self.outage_buffer {
+            memory.request_queue_bytes += outage_buffer.bytes();
+        }
+        return memory;
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        let concurrency_limit = match self.concurrency_limiter {
+            Some(ref limiter) => limiter.limit(),
+            None => 0,
+        };
+        return QueueStats::new(self.queue.len(), self.max_queue_depth, concurrency_limit);
+    }
+
+    pub fn host_and_auth(&self) -> (SocketAddr, Option<Vec<u8>>) {
+        return (self.host, auth_request(&self.config));
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.max_queue_depth = self.queue.len();
+        self.latency.reset();
+        self.commands.clear();
+        self.errors = ErrorStats::new();
+        self.connections = ConnectionStats::new();
+        self.slowlog.reset();
+    }
+
     pub fn init_connection(&mut self) {
         match self.connect() {
             Ok(a) => a,
             Err(err) => {
                 debug!("Failed to establish connection due to {:?}", err);
+                self.errors.connection_failures += 1;
                 change_state(&mut self.status, BackendStatus::DISCONNECTED);
                 *self.cached_backend_shards.borrow_mut() = None;
                 self.set_retry_timer();
@@ -325,31 +1070,143 @@ impl SingleBackend {
             return Ok(());
         }
 
+        if self.addresses.len() > 1 {
+            let mut race = ConnectRace::new(&self.addresses);
+            try!(race.start_next(&self.registry, self.token));
+            self.race = Some(race);
+            self.set_attempt_timer();
+            change_state(&mut self.status, BackendStatus::CONNECTING);
+            self.connect_started = clock::now();
+            return Ok(());
+        }
+
         // Setup the server socket
-        let socket = try!(TcpStream::connect(&self.host));
+        let socket = try!(fastopen::connect(&self.host, self.fastopen));
         debug!("New socket to {}: {:?}", self.host, socket);
 
-        try!(self.poll_registry.borrow_mut().register(&socket, self.token, Ready::readable() | Ready::writable(), PollOpt::edge()));
+        let socket = try!(Registered::new(&self.registry, socket, self.token, Interest::ReadWrite));
         debug!("Registered backend: {:?}", &self.token);
-        self.socket = Some(BufReader::new(socket));
+        self.socket = Some(BufReader::pooled(socket));
 
         change_state(&mut self.status, BackendStatus::CONNECTING);
+        self.connect_started = clock::now();
         return Ok(());
     }
 
+    /*
+        Fails a connection that is still connecting or handshaking connect_timeout after it started, e.g. to a host that
+        silently drops packets, so that it is retried instead of holding its socket and token until the OS gives up.
+    */
+    pub fn expire_connect(
+        &mut self,
+        now: Instant,
+        connect_timeout: Duration,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if self.status != BackendStatus::CONNECTING && self.status != BackendStatus::CONNECTED {
+            return;
+        }
+        if now < self.connect_started + connect_timeout {
+            return;
+        }
+        log_event!(LogLevel::Info, "backend_connect_timeout", { backend: self.host, token: self.token.0 }, "Connecting to backend {} timed out", self.host);
+        self.connections.connect_timeouts += 1;
+        self.end_race();
+        self.handle_backend_failure(clients, completed_clients, stats);
+    }
+
+    // Starts connecting to the next address of the race too, once the last attempt has had its attempt delay.
+    pub fn start_next_attempt(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        self.attempt_timer = None;
+        let started = match self.race {
+            Some(ref mut race) => race.start_next(&self.registry, self.token).is_ok(),
+            None => return,
+        };
+        if started {
+            self.set_attempt_timer();
+            return;
+        }
+        // Every address failed to start. Any earlier attempt that's still connecting decides the race.
+        self.handle_backend_response(clients, &mut |_response: &[u8]| {}, completed_clients, stats);
+    }
+
+    fn set_attempt_timer(&mut self) {
+        let mut timers = self.timers.borrow_mut();
+        if let Some(id) = self.attempt_timer.take() {
+            timers.cancel(id);
+        }
+        let has_remaining = match self.race {
+            Some(ref race) => race.has_remaining(),
+            None => false,
+        };
+        if has_remaining {
+            let deadline = clock::now() + Duration::from_millis(happyeyeballs::CONNECTION_ATTEMPT_DELAY_MS);
+            self.attempt_timer = Some(timers.insert(deadline, TimerEvent::ConnectAttempt(self.token)));
+        }
+    }
+
+    /*
+        Checks the race's attempts after an event. Returns whether one of them connected, which then becomes the
+        backend's socket, and its address the backend's host. If they all failed, the backend fails like any other
+        connection.
+    */
+    fn finish_race(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) -> bool {
+        let state = match self.race {
+            Some(ref mut race) => race.poll(&self.registry, self.token),
+            None => return true,
+        };
+        match state {
+            RaceState::Connecting => return false,
+            RaceState::Connected(socket, address) => {
+                self.end_race();
+                debug!("Connected to {} of backend {} first", address, self.host);
+                self.host = address;
+                self.socket = Some(BufReader::pooled(socket));
+                return true;
+            }
+            RaceState::Failed => {
+                self.end_race();
+                self.handle_backend_failure(clients, completed_clients, stats);
+                return false;
+            }
+        }
+    }
+
+    fn end_race(&mut self) {
+        self.race = None;
+        if let Some(id) = self.attempt_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+    }
+
     // Callback after initializing a connection.
     fn handle_connection(&mut self, stats: &mut Stats,) {
         let mut wait_for_resp = false;
+        if self.has_connected {
+            self.connections.backend_reconnects += 1;
+            log_event!(LogLevel::Info, "backend_reconnected", { backend: self.host, token: self.token.0 }, "Reconnected to backend {}", self.host);
+        } else {
+            log_event!(LogLevel::Info, "backend_connected", { backend: self.host, token: self.token.0 }, "Connected to backend {}", self.host);
+        }
+        self.has_connected = true;
+        self.handshake_rejected = false;
 
         // TODO: Cache the string pushing to config initialization.
-        if self.config.auth != String::new() {
-            let mut request = String::with_capacity(14 + self.config.auth.len());
-            request.push_str("*2\r\n$4\r\nAUTH\r\n$");
-            request.push_str(&self.config.auth.len().to_string());
-            request.push_str("\r\n");
-            request.push_str(&self.config.auth);
-            request.push_str("\r\n");
-            if self.write_to_backend_stream(NULL_TOKEN, &request.as_bytes(), (Instant::now(), 0), stats).is_err() {
+        if let Some(request) = auth_request(&self.config) {
+            if self.write_to_backend_stream(NULL_TOKEN, &request, (clock::now(), 0), None, stats).is_err() {
+                self.connections.handshake_failures += 1;
                 change_state(&mut self.status, BackendStatus::DISCONNECTED);
                 self.socket = None;
                 return;
@@ -365,7 +1222,8 @@ impl SingleBackend {
             request.push_str("\r\n");
             request.push_str(&self.config.db.to_string());
             request.push_str("\r\n");
-            if self.write_to_backend_stream(NULL_TOKEN, &request.as_bytes(), (Instant::now(), 0), stats).is_err() {
+            if self.write_to_backend_stream(NULL_TOKEN, &request.as_bytes(), (clock::now(), 0), None, stats).is_err() {
+                self.connections.handshake_failures += 1;
                 change_state(&mut self.status, BackendStatus::DISCONNECTED);
                 self.socket = None;
                 return;
@@ -375,7 +1233,8 @@ impl SingleBackend {
         }
 
         if self.timeout != 0 {
-            if self.write_to_backend_stream(NULL_TOKEN, "PING\r\n".as_bytes(), (Instant::now(), 0), stats).is_err() {
+            if self.write_to_backend_stream(NULL_TOKEN, "PING\r\n".as_bytes(), (clock::now(), 0), None, stats).is_err() {
+                self.connections.handshake_failures += 1;
                 change_state(&mut self.status, BackendStatus::DISCONNECTED);
                 self.socket = None;
                 return;
@@ -397,51 +1256,37 @@ impl SingleBackend {
         clients: &mut HashMap<usize, (BufferedClient, usize)>,
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
+    ) -> bool {
+        let mark_down = self.expire_requests(clients, completed_clients, stats);
+        self.resume_clients(completed_clients);
+        return mark_down;
+    }
+
+    // Answers the requests that have passed their deadline with a timeout error.
+    fn expire_requests(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
     ) -> bool {
         debug!("Handling ReqestTimeout for Backend {:?}", self.token);
+        self.request_timer = None;
 
         if self.status == BackendStatus::DISCONNECTED {
-            self.timer = None;
-            return false;
-        }
-        if self.queue.len() == 0 {
             return false;
         }
+        let now = clock::now();
+        self.expire_deadlines(now, clients, completed_clients, stats);
         loop {
-            let timer_poll = match self.timer {
-                Some(ref mut t) => t.poll(),
-                None => {
-                    panic!("A timeout event occurred without a backend timer being available.");
-                }
-            };
-            let target_timestamp = match timer_poll {
-                None => {
-                    // TODO: For some reason, poll says timer is activated,but timer itself doesn't think so.
-                    // This is a false poll trigger.
-                    return false;
-                }
-                Some(ts) => ts,
-            };
             let head = {
                 match self.queue.get(0) {
                     Some(h) => h.clone(),
-                    None => { return false; }
+                    None => { break; }
                 }
             };
-            let ref time = head.1;
-            if &target_timestamp < time {
-                // Should we remove the timer here?
-                // In what cases do we have a timer firing with the wrong timestamp? Could be resolved already.
-                // Are there cases we want to fire it again?
-                // TODO: We need to rethink the whole timer system. Currently, there can only be one requesttimer per backend.
-                // But we can have requests slow down, and a certain threshold will be hit. IE. 48 ms, 49 ms, 50 ms THRESHOLD, 51 ms.
-                // The last 2 requests should timeout.
-                // This means that we need to save all the timestamps of each request?
-                // Or can we only record first and last?
-                // It seems like the mio timer might be able to take multiple timeouts? This would be nifty then.
-                debug!("This is wrong timestamp? {:?} vs {:?}", target_timestamp, time);
-                //self.timer = None;
-                continue;
+            if head.4 == Duration::from_millis(0) || now < head.1 {
+                // The timer may be for a request that has since been answered.
+                break;
             }
 
             // Get rid of first queue.
@@ -450,41 +1295,114 @@ impl SingleBackend {
             debug!("queue size is now: {:?}", self.queue.len());
 
             if head.0 == NULL_TOKEN && (self.waiting_for_db_resp || self.waiting_for_auth_resp || self.waiting_for_ping_resp) {
+                self.connections.handshake_failures += 1;
                 change_state(&mut self.status, BackendStatus::DISCONNECTED);
                 *self.cached_backend_shards.borrow_mut() = None;
                 self.init_connection();
             }
 
-            if head.0 != NULL_TOKEN {
-                debug!("Trying to find client: {:?}", (head.0));
-                handle_write_to_client(
-                    clients,
-                    &(head.0).0,
-                    b"-ERR Proxy timed out\r\n",
-                    (head.1, head.2),
-                    completed_clients,
-                    stats,
-                );
+            // A request that passed its DEADLINE was already answered, and counted.
+            if head.0 != TIMED_OUT {
+                self.errors.timeouts += 1;
+            }
+            if head.0 != NULL_TOKEN && head.0 != TIMED_OUT {
+                self.fail_timed_out(head, clients, completed_clients, stats);
             }
 
-            if &target_timestamp == time {
-                if  self.status != BackendStatus::READY {
-                    // Mark it down because it never initialized properly.
+            if self.status != BackendStatus::READY {
+                // Mark it down because it never initialized properly.
+                return true;
+            }
+            if self.failure_limit > 0 {
+                self.failure_count += 1;
+                if self.failure_count >= self.failure_limit {
+                    debug!("Marking backend as failed");
                     return true;
                 }
-                if self.failure_limit > 0 {
-                    self.failure_count += 1;
-                    if self.failure_count >= self.failure_limit {
-                        debug!("Marking backend as failed");
-                        return true;
-                    }
-                }
+            }
+        }
+        // Wait for the next deadline.
+        let next_deadline = self.queue.iter()
+            .filter(|entry| entry.4 > Duration::from_millis(0))
+            .map(|entry| entry.1)
+            .min();
+        if let Some(deadline) = next_deadline {
+            self.set_request_timer(deadline);
+        }
+        return false;
+    }
 
+    /*
+        Answers the requests that passed a timeout of their own, which clients set with DEADLINE. Unlike requests that
+        pass the pool's timeout, they can be anywhere in the queue. They stay queued until the backend answers them,
+        or the pool's timeout passes, and the backend's responses to them are discarded. A request isn't answered
+        ahead of an earlier one of the same client, which the client expects first.
+    */
+    fn expire_deadlines(
+        &mut self,
+        now: Instant,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        let pool_timeout = Duration::from_millis(self.timeout as u64);
+        let mut waiting = HashSet::new();
+        let mut expired = Vec::new();
+        for entry in self.queue.iter_mut() {
+            if entry.0 == NULL_TOKEN || entry.0 == TIMED_OUT {
                 continue;
             }
-            else {
-                panic!("This shouldn't happen. Timestamp hit: {:?}. Missed previous timestamp: {:?}", target_timestamp, time);
+            if entry.4 == pool_timeout || now < entry.1 || waiting.contains(&entry.0) {
+                waiting.insert(entry.0);
+                continue;
             }
+            expired.push(*entry);
+            let start = entry.1 - entry.4;
+            entry.0 = TIMED_OUT;
+            // Without a pool timeout, it waits for its response however long that takes.
+            entry.4 = if pool_timeout == Duration::from_millis(0) { pool_timeout } else { std::cmp::max(entry.4, pool_timeout) };
+            entry.1 = start + entry.4;
+        }
+        for entry in expired {
+            self.errors.timeouts += 1;
+            self.fail_timed_out(entry, clients, completed_clients, stats);
+        }
+    }
+
+    // Answers a client request that passed its deadline with a timeout error.
+    fn fail_timed_out(
+        &mut self,
+        entry: (ClientToken, Instant, usize, &'static str, Duration),
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        let (client_token, deadline, id, command, timeout) = entry;
+        let start = deadline - timeout;
+        log_event!(
+            LogLevel::Debug,
+            "request_timeout",
+            { backend: self.host, token: client_token.0, latency_us: micros_since(start) },
+            "Request from client {:?} to backend {} timed out", client_token, self.host
+        );
+        command_stats(&mut self.commands, command).errors += 1;
+        if let Some(ref mut limiter) = self.concurrency_limiter {
+            limiter.on_timeout();
+        }
+        self.traces.fail(client_token, (deadline, id), "Proxy timed out");
+        self.slowlog.finish(client_token, (deadline, id), start, self.host, command, Some("Proxy timed out"));
+        if let Some(kind) = self.mirror {
+            record_mirror_response(kind, clients, &client_token.0, b"-ERR Proxy timed out\r\n", (start, id));
+        } else {
+            handle_write_to_client(
+                clients,
+                &client_token.0,
+                b"-ERR Proxy timed out\r\n",
+                (start, id),
+                completed_clients,
+                &mut self.connections,
+                stats,
+            );
         }
     }
 
@@ -493,59 +1411,328 @@ impl SingleBackend {
         *self.cached_backend_shards.borrow_mut() = None;
         self.failure_count = 0;
         self.socket = None;
+        self.end_race();
+        self.output.clear();
+        self.output_requests = 0;
+        self.chaos_disconnect = false;
+        if let Some(id) = self.request_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+        if let Some(id) = self.flush_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+    }
+
+    // Closes the backend for good. Its socket is deregistered as it's dropped, and it won't be retried.
+    pub fn close(
+        &mut self,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if let Some(id) = self.retry_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+        self.mark_backend_down(clients, completed_clients, stats);
+        if let Some(id) = self.outage_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+        let held = match self.outage_buffer {
+            Some(ref mut outage_buffer) => outage_buffer.take_all().into_iter().collect(),
+            None => Vec::new(),
+        };
+        self.fail_held(held, clients, completed_clients, stats);
+    }
+
+    // Marks the backend as down. Returns an error message to all pending requests.
+    // TODO: Is it still needed to have a mark_backend_down AND handle_backend_failure?
+    pub fn mark_backend_down(
+        &mut self,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        log_event!(LogLevel::Info, "backend_down", { backend: self.host, token: self.token.0 }, "Marking backend {} as down", self.host);
+        self.disconnect();
+
+        // TODO: It's possible that a client sends a request to 1 backend, then another. And the 2nd backend dies before the 1st one finishes.
+        // In that case, the client should get response for the first request first, and then the error message for the second.
+        // Actually, there is a race condition in general, even without any error, if the 2nd backend responds much quicker.
+        // How is this avoided? By only doing one request from the client at a time.
+        let mut possible_token = self.queue.pop_front();
+        loop {
+            match possible_token {
+                Some((NULL_TOKEN, _, _, _, _)) | Some((TIMED_OUT, _, _, _, _)) => {}
+                Some((client_token, instant, id, command, timeout)) => {
+                    command_stats(&mut self.commands, command).errors += 1;
+                    self.errors.backend_unavailable += 1;
+                    self.traces.fail(client_token, (instant, id), "Unavailable backend");
+                    self.slowlog.discard(client_token, (instant, id));
+                    let received = (instant - timeout, id);
+                    if let Some(kind) = self.mirror {
+                        record_mirror_response(kind, clients, &client_token.0, b"-ERR: Unavailable backend.\r\n", received);
+                    } else {
+                        handle_write_to_client(
+                            clients,
+                            &client_token.0,
+                            b"-ERR: Unavailable backend.\r\n",
+                            received,
+                            completed_clients,
+                            &mut self.connections,
+                            stats,
+                        );
+                    }
+                }
+                None => break,
+            }
+            possible_token = self.queue.pop_front();
+        }
+        self.resume_clients(completed_clients);
+    }
+
+    /*
+        Points the backend at another host, e.g. a new master after a sentinel failover. Requests still waiting on the
+        old host get an error, and the new host is connected to right away.
+    */
+    pub fn change_host(
+        &mut self,
+        host: SocketAddr,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        if host == self.host {
+            return;
+        }
+        log_event!(LogLevel::Warn, "backend_host_changed", { backend: host, token: self.token.0 }, "Moving backend {} to {}", self.host, host);
+        self.mark_backend_down(clients, completed_clients, stats);
+        if let Some(id) = self.retry_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+        self.host = host;
+        self.retry_policy.reset();
+        self.init_connection();
+    }
+
+    pub fn write_message(
+        &mut self,
+        message: &[u8],
+        client_token: Token,
+        request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
+        stats: &mut Stats,
+    ) -> Result<bool, WriteError> {
+        // TODO: get rid of this wrapper function.
+        match self.status {
+            BackendStatus::READY => {
+                if client_token != NULL_TOKEN {
+                    if let Some(ref limiter) = self.concurrency_limiter {
+                        if !limiter.allows(self.queue.len()) {
+                            self.errors.overloaded += 1;
+                            return Err(WriteError::Overloaded);
+                        }
+                    }
+                }
+                try!(self.write_to_backend_stream(client_token, message, request_id, timeout, stats));
+                if client_token != NULL_TOKEN {
+                    self.inject_request_fault();
+                }
+                if let Some(trace) = trace {
+                    // Key the trace the same way as the queue entry that was just pushed.
+                    let (_, deadline, id, _, _) = *self.queue.back().unwrap();
+                    self.traces.start(client_token, (deadline, id), trace, self.host);
+                }
+                if client_token == NULL_TOKEN || !self.queue_limits.is_full(self.queue.len(), self.output.len()) {
+                    return Ok(false);
+                }
+                if !self.paused_clients.contains(&client_token) {
+                    debug!("Backend {} is full. Pausing client {:?}", self.host, client_token);
+                    self.paused_clients.push(client_token);
+                    self.connections.client_read_pauses += 1;
+                }
+                return Ok(true);
+            }
+            _ => {
+                // Held until the backend is ready again, with outage_buffer_timeout. Mirrors only get copies.
+                if client_token != NULL_TOKEN && self.mirror.is_none() {
+                    let held = match self.outage_buffer {
+                        Some(ref mut outage_buffer) => outage_buffer.hold(message, client_token, request_id, trace, timeout, clock::now()),
+                        None => false,
+                    };
+                    if held {
+                        self.connections.held_requests += 1;
+                        if self.outage_timer.is_none() {
+                            self.set_outage_timer();
+                        }
+                        return Ok(false);
+                    }
+                }
+                debug!("No backend connection.");
+                return Err(WriteError::BackendNotReady);
+            }
+        }
+    }
+
+    fn set_outage_timer(&mut self) {
+        let mut timers = self.timers.borrow_mut();
+        if let Some(id) = self.outage_timer.take() {
+            timers.cancel(id);
+        }
+        let deadline = match self.outage_buffer {
+            Some(ref outage_buffer) => outage_buffer.next_deadline(),
+            None => None,
+        };
+        if let Some(deadline) = deadline {
+            self.outage_timer = Some(timers.insert(deadline, TimerEvent::OutageBuffer(self.token)));
+        }
+    }
+
+    // Fails the held requests that the backend wasn't ready for within outage_buffer_timeout.
+    pub fn expire_held(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        self.outage_timer = None;
+        let expired = match self.outage_buffer {
+            Some(ref mut outage_buffer) => outage_buffer.expire(clock::now()),
+            None => return,
+        };
+        if !expired.is_empty() {
+            log_event!(LogLevel::Info, "held_requests_expired", { backend: self.host, token: self.token.0 }, "Backend {} was not ready for {} held requests", self.host, expired.len());
+        }
+        self.connections.held_expired += expired.len();
+        self.fail_held(expired, clients, completed_clients, stats);
+        self.set_outage_timer();
+    }
+
+    // Writes the held requests to the backend, once it's ready again.
+    fn replay_held(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        let held = match self.outage_buffer {
+            Some(ref mut outage_buffer) if !outage_buffer.is_empty() => outage_buffer.take_all(),
+            _ => return,
+        };
+        if let Some(id) = self.outage_timer.take() {
+            self.timers.borrow_mut().cancel(id);
+        }
+        log_event!(LogLevel::Info, "held_requests_replayed", { backend: self.host, token: self.token.0 }, "Replaying {} held requests to backend {}", held.len(), self.host);
+        let mut failed = Vec::new();
+        for mut request in held {
+            let trace = request.trace.take();
+            if self.write_message(&request.message, request.client_token, request.request_id, trace, request.timeout, stats).is_err() {
+                failed.push(request);
+            }
+        }
+        self.fail_held(failed, clients, completed_clients, stats);
+        self.flush_output(clients, completed_clients, stats);
+    }
+
+    // Answers held requests that won't be written to the backend with the error they'd have got without the buffer.
+    fn fail_held(
+        &mut self,
+        requests: Vec<HeldRequest>,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        for request in requests {
+            command_stats(&mut self.commands, command_name(&request.message)).errors += 1;
+            self.errors.backend_unavailable += 1;
+            handle_write_to_client(
+                clients,
+                &request.client_token.0,
+                b"-ERROR: Not connected\r\n",
+                request.request_id,
+                completed_clients,
+                &mut self.connections,
+                stats,
+            );
+        }
     }
 
-    // Marks the backend as down. Returns an error message to all pending requests.
-    // TODO: Is it still needed to have a mark_backend_down AND handle_backend_failure?
-    pub fn mark_backend_down(
+    pub fn flush_output(
         &mut self,
-        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) {
-        self.disconnect();
-
-        // TODO: It's possible that a client sends a request to 1 backend, then another. And the 2nd backend dies before the 1st one finishes.
-        // In that case, the client should get response for the first request first, and then the error message for the second.
-        // Actually, there is a race condition in general, even without any error, if the 2nd backend responds much quicker.
-        // How is this avoided? By only doing one request from the client at a time.
-        let mut possible_token = self.queue.pop_front();
-        loop {
-            match possible_token {
-                Some((NULL_TOKEN, _, _)) => {}
-                Some((client_token, instant, id)) => {
-                    handle_write_to_client(
-                        clients,
-                        &client_token.0,
-                        b"-ERR: Unavailable backend.\r\n",
-                        (instant, id),
-                        completed_clients,
-                        stats,
-                    );
-                }
-                None => break,
-            }
-            possible_token = self.queue.pop_front();
+        if self.chaos_disconnect {
+            log_event!(LogLevel::Info, "chaos_disconnect", { backend: self.host, token: self.token.0 }, "Chaos mode is disconnecting backend {}", self.host);
+            self.handle_backend_failure(clients, completed_clients, stats);
+            return;
+        }
+        // Held back output is written by end_delay.
+        if self.flush_timer.is_some() {
+            self.resume_clients(completed_clients);
+            return;
         }
+        if let Err(err) = self.write_output(stats) {
+            log_event!(LogLevel::Info, "backend_write_failed", { backend: self.host, token: self.token.0 }, "Failed to write to backend {}: {}", self.host, err);
+            self.handle_backend_failure(clients, completed_clients, stats);
+        }
+        self.resume_clients(completed_clients);
     }
 
-    pub fn write_message(
+    // Writes the output that chaos mode held back, once its latency has passed.
+    pub fn end_delay(
         &mut self,
-        message: &[u8],
-        client_token: Token,
-        request_id: (Instant, usize),
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
-    ) -> Result<(), WriteError> {
-        // TODO: get rid of this wrapper function.
-        match self.status {
-            BackendStatus::READY => {
-                return self.write_to_backend_stream(client_token, message, request_id, stats);
-            }
-            _ => {
-                debug!("No backend connection.");
-                return Err(WriteError::BackendNotReady);
+    ) {
+        self.flush_timer = None;
+        self.flush_output(clients, completed_clients, stats);
+    }
+
+    // Picks the chaos mode fault, if any, of the client request that was just written to the output.
+    fn inject_request_fault(&mut self) {
+        let fault = match self.chaos {
+            Some(ref chaos) => chaos.borrow_mut().request_fault(&self.host),
+            None => return,
+        };
+        match fault {
+            Some(Fault::Disconnect) => self.chaos_disconnect = true,
+            Some(Fault::Latency(delay)) if self.flush_timer.is_none() => {
+                debug!("Chaos mode is delaying the output of backend {} by {:?}", self.host, delay);
+                self.flush_timer = Some(self.timers.borrow_mut().insert(clock::now() + delay, TimerEvent::Flush(self.token)));
             }
+            _ => {}
+        }
+    }
+
+    // Lets the clients paused by write_message be read from again, once the backend has room for their requests.
+    fn resume_clients(&mut self, completed_clients: &mut VecDeque<ClientTokenValue>) {
+        if self.paused_clients.is_empty() || !self.queue_limits.has_room(self.queue.len(), self.output.len()) {
+            return;
+        }
+        debug!("Backend {} has room again. Resuming {} clients", self.host, self.paused_clients.len());
+        for client_token in self.paused_clients.drain(..) {
+            completed_clients.push_back(client_token.0);
+        }
+    }
+
+    fn write_output(&mut self, stats: &mut Stats) -> Result<(), WriteError> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let result = match self.socket {
+            Some(ref mut s) => write_to_stream(s.get_mut(), &self.output),
+            None => Err(WriteError::NoSocket),
+        };
+        self.output.clear();
+        if self.output_requests > 0 {
+            stats.event_loop.requests_per_backend_write.record(self.output_requests as u64);
+            self.output_requests = 0;
         }
+        stats.shard().send_backend_bytes += try!(result);
+        return Ok(());
     }
 
     pub fn handle_backend_response(
@@ -555,6 +1742,9 @@ impl SingleBackend {
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) {
+        if self.race.is_some() && !self.finish_race(clients, completed_clients, stats) {
+            return;
+        }
         let prev_state = self.status;
         change_state(&mut self.status, BackendStatus::CONNECTED);
         if prev_state == BackendStatus::CONNECTING && self.status == BackendStatus::CONNECTED {
@@ -564,6 +1754,8 @@ impl SingleBackend {
         // This can be considered DISCONNECTED already. If that's the case, disconnect should flush all responses in the queue.
         // This does happen because when disconnecting, the socket is set to None.
 
+        // Responses are buffered per client, and written once every available response has been read.
+        let mut written_clients = Vec::new();
         // Read all responses if there are any left.
         while self.queue.len() > 0 {
             let res = route_backend_response(
@@ -577,18 +1769,45 @@ impl SingleBackend {
                 internal_resp_handler,
                 &self.cached_backend_shards,
                 completed_clients,
+                &mut self.latency,
+                &mut self.concurrency_limiter,
+                &mut self.commands,
+                &mut self.errors,
+                &mut self.connections,
+                &mut self.handshake_rejected,
+                &mut self.traces,
+                &mut self.slowlog,
+                &self.host,
+                self.big_value_threshold,
+                self.mirror,
+                &self.chaos,
+                &self.timers,
+                &mut written_clients,
                 stats,
             );
             match res {
                 Ok(true) => continue,
-                Ok(false) => { return; }
+                Ok(false) => { break; }
                 Err(err) => {
-                    error!("Received incompatible response from backend. Forcing a disconnect. Received error while parsing: {}", err);
+                    log_event!(
+                        LogLevel::Error,
+                        "backend_protocol_error",
+                        { backend: self.host, token: self.token.0 },
+                        "Received incompatible response from backend. Forcing a disconnect. Received error while parsing: {}", err
+                    );
+                    self.errors.protocol_errors += 1;
                     self.mark_backend_down(clients, completed_clients, stats);
                 }
             }
         }
-        return;
+        flush_clients(clients, &mut written_clients, &mut self.connections, stats);
+        self.resume_clients(completed_clients);
+
+        // Connection is fully established again, so the next failure starts from the base retry timeout.
+        if self.status == BackendStatus::READY {
+            self.retry_policy.reset();
+            self.replay_held(clients, completed_clients, stats);
+        }
     }
 
     pub fn handle_backend_failure(
@@ -597,42 +1816,38 @@ impl SingleBackend {
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
     ) {
+        // An error on one of the race's attempts only fails that attempt.
+        if self.race.is_some() {
+            self.handle_backend_response(clients, &mut |_response: &[u8]| {}, completed_clients, stats);
+            return;
+        }
+        self.errors.connection_failures += 1;
         self.mark_backend_down(clients, completed_clients, stats);
         self.set_retry_timer();
     }
 
     fn set_retry_timer(&mut self) {
-        if self.retry_timer.is_none() {
-        debug!("Creating timer");
-            let timer = create_timer();
-            let timer_token = Token(self.token.0 + self.num_backends);
-            match self.poll_registry.borrow_mut().register(&timer, timer_token, Ready::readable(), PollOpt::edge()) {
-                Ok(_) => {}
-                Err(err) => {
-                    // Expected to occur only when timer is registered to a different poll (which shouldn't happen).
-                    panic!("Failed to register retry timer to poll. Received error: {}", err);
-                }
-            };
-            self.retry_timer = Some(timer);
+        let retry_delay = Duration::from_millis(self.retry_policy.next_delay() as u64);
+        debug!("Retrying backend {:?} in {:?}", self.token, retry_delay);
+        let mut timers = self.timers.borrow_mut();
+        if let Some(id) = self.retry_timer.take() {
+            timers.cancel(id);
         }
+        self.retry_timer = Some(timers.insert(clock::now() + retry_delay, TimerEvent::Retry(self.token)));
+    }
 
-        let now = Instant::now();  
-        let timestamp = now + Duration::from_millis(self.retry_timeout as u64);
-        match self.retry_timer {
-            Some(ref mut timer) => {
-                match timer.set_timeout(Duration::new(0, (1000000 * self.retry_timeout) as u32), timestamp) {
-                    Ok(_) => { }
-                    Err(err) => {
-                        // Expected to occur only in cases of usize integer overflow.
-                        panic!("Failure setting timer timeout: {}.", err);
-                    }
-                }
-            }
-            None => {
-                // Never expected to occur.
-                panic!("Timer does not exist after being instantiated.");
-            }
+    fn set_request_timer(&mut self, deadline: Instant) {
+        let mut timers = self.timers.borrow_mut();
+        if let Some(id) = self.request_timer.take() {
+            timers.cancel(id);
         }
+        debug!("Setting timeout: {:?}", deadline);
+        self.request_timer = Some(timers.insert(deadline, TimerEvent::RequestTimeout(self.token)));
+        self.request_deadline = deadline;
+    }
+
+    fn schedule_hedge(&self, client_token: ClientToken, request_id: (Instant, usize), delay: Duration) {
+        self.timers.borrow_mut().insert(clock::now() + delay, TimerEvent::Hedge(client_token, request_id, self.token));
     }
 
     fn write_to_backend_stream(
@@ -640,54 +1855,67 @@ impl SingleBackend {
         client_token: ClientToken,
         message: &[u8],
         request_id: (Instant, usize),
+        timeout: Option<usize>,
         stats: &mut Stats,
     ) -> Result<(), WriteError> {
         debug!("Write to backend {:?} {}: {:?} {:?}", &self.token, self.host, std::str::from_utf8(&message), client_token);
-        let bytes_written = match self.socket {
-            Some(ref mut s) => try!(write_to_stream(s.get_mut(), message)),
-            None => return Err(WriteError::NoSocket),
-        };
-        stats.send_backend_bytes += bytes_written;
+        if self.socket.is_none() {
+            return Err(WriteError::NoSocket);
+        }
+        // Client requests are buffered until flush_output. The proxy's own requests are written immediately.
+        self.output.extend_from_slice(message);
+        if client_token == NULL_TOKEN {
+            try!(self.write_output(stats));
+        } else {
+            self.output_requests += 1;
+        }
+        let command = command_name(message);
+        if client_token != NULL_TOKEN {
+            record_value_size(
+                &mut command_stats(&mut self.commands, command).max_request_bytes,
+                message.len(),
+                "request",
+                command,
+                &self.host,
+                self.big_value_threshold,
+            );
+        }
         // TODO: Keep trying on self.socket if it's INTERRUPTED or WOULDBLOCK, otherwise DISCONNECT the backend connection.
-        let timestamp = request_id.0 + Duration::from_millis(self.timeout as u64);
-        self.queue.push_back((client_token, timestamp, request_id.1));
-        // Need to guarantee that queue is ordered. Is there any possibility
-        if self.queue.len() == 1 && self.timeout != 0 {
-            if self.timer.is_none() {
-                let timer = create_timer();
-                let timer_token = Token(self.token.0 + 2 * self.num_backends);
-                debug!("Registered timer: {:?}", timer_token);
-                match self.poll_registry.borrow_mut().register(&timer, timer_token, Ready::readable(), PollOpt::edge()) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        // Expected to occur only when timer is registered to a different poll (which shouldn't happen).
-                        panic!("Failed to register timer to poll. Received error: {}", err);
-                    }
-                };
-                self.timer = Some(timer);
-            }
-
-            match self.timer {
-                Some(ref mut timer) => {
-                    match timer.set_timeout(Duration::from_millis(self.timeout as u64), timestamp) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            // Expected to occur only in cases of usize integer overflow.
-                            panic!("Failure setting timer timeout: {}.", err);
-                        }
-                    };
-                    debug!("Setting timeout: {:?}", timestamp);
-                }
-                None => {
-                    // Never expected to occur.
-                    panic!("Timer does not exist after being instantiated.");
-                }
-            }
+        let timeout = Duration::from_millis(timeout.unwrap_or(self.timeout) as u64);
+        let timestamp = request_id.0 + timeout;
+        self.queue.push_back((client_token, timestamp, request_id.1, command, timeout));
+        if self.queue.len() > self.max_queue_depth {
+            self.max_queue_depth = self.queue.len();
+        }
+        if client_token != NULL_TOKEN {
+            self.slowlog.start(client_token, (timestamp, request_id.1), message);
+        }
+        /*
+            Only the earliest deadline is pending. Later ones are checked once it passes. A request with a timeout of
+            its own can have an earlier deadline than the ones ahead of it.
+        */
+        if timeout > Duration::from_millis(0) && (self.request_timer.is_none() || timestamp < self.request_deadline) {
+            self.set_request_timer(timestamp);
         }
         return Ok(());
     }
 }
 
+/*
+    The AUTH sent to a backend host on each new connection, if it requires one. With a username, the proxy logs in as
+    that Redis 6 ACL user rather than the default one.
+*/
+pub fn auth_request(config: &BackendConfig) -> Option<Vec<u8>> {
+    if config.auth.is_empty() {
+        return None;
+    }
+    let request = match config.username {
+        Some(ref username) => encode_command([&b"AUTH"[..], username.as_bytes(), config.auth.as_bytes()].iter().cloned()),
+        None => encode_command([&b"AUTH"[..], config.auth.as_bytes()].iter().cloned()),
+    };
+    return Some(request);
+}
+
 fn handle_internal_response(
     status: &mut BackendStatus,
     waiting_for_auth_resp: &mut bool,
@@ -696,7 +1924,23 @@ fn handle_internal_response(
     response: &[u8],
     internal_resp_handler: &mut FnMut(&[u8]),
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
+    connections: &mut ConnectionStats,
+    errors: &mut ErrorStats,
+    handshake_rejected: &mut bool,
+    host: &SocketAddr,
 ) {
+    // Once AUTH or SELECT is rejected, the rest of the handshake fails as well, so only the first error is counted.
+    if (*waiting_for_auth_resp || *waiting_for_db_resp) && response.first() == Some(&b'-') && !*handshake_rejected {
+        connections.handshake_failures += 1;
+        // AUTH is sent first, so an error while still waiting for it is the AUTH reply.
+        if *waiting_for_auth_resp {
+            log_event!(LogLevel::Error, "backend_auth_rejected", { backend: host }, "Backend rejected AUTH, check its auth and username: {:?}", std::str::from_utf8(response));
+            errors.auth_failures += 1;
+        } else {
+            log_event!(LogLevel::Error, "backend_handshake_rejected", { backend: host }, "Backend rejected the connection handshake: {:?}", std::str::from_utf8(response));
+        }
+        *handshake_rejected = true;
+    }
     // TODO: Handle the various requirements.
     if *waiting_for_auth_resp && response == b"+OK\r\n" {
         *waiting_for_auth_resp = false;
@@ -738,8 +1982,9 @@ fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool
         // happens when host has been blacked out from too many failures/timeouts.
         (BackendStatus::READY, BackendStatus::DISCONNECTED) => {}
         _ => {
-            debug!("Backend failed to change state from {:?} to {:?}", status, target_state);
-            panic!("Failure to change states"); //return false;
+            // Leave the state as is, rather than take down the proxy over one backend.
+            error!("Backend failed to change state from {:?} to {:?}", status, target_state);
+            return false;
         }
     }
     debug!("Backend changed state from {:?} to {:?}", status, target_state);
@@ -748,14 +1993,13 @@ fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool
 }
 
 /*
-    This should only be called if there is a request in the queue.
-    Will panic if the queue is empty.
+    This should only be called if there is a request in the queue. A response with an empty queue is a protocol error.
     Returns whether there may be more responses or not.
 */
 fn route_backend_response(
-    stream: &mut Option<BufReader<TcpStream>>,
+    stream: &mut Option<BufReader<Registered<TcpStream>>>,
     clients: &mut HashMap<usize, (BufferedClient, usize)>,
-    queue: &mut VecDeque<(Token, Instant, usize)>,
+    queue: &mut VecDeque<(Token, Instant, usize, &'static str, Duration)>,
     status: &mut BackendStatus,
     waiting_for_auth_resp: &mut bool,
     waiting_for_db_resp: &mut bool,
@@ -763,6 +2007,20 @@ fn route_backend_response(
     internal_resp_handler: &mut FnMut(&[u8]),
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
     completed_clients: &mut VecDeque<ClientTokenValue>,
+    latency: &mut LatencyHistogram,
+    concurrency_limiter: &mut Option<ConcurrencyLimiter>,
+    commands: &mut HashMap<&'static str, CommandStats>,
+    errors: &mut ErrorStats,
+    connections: &mut ConnectionStats,
+    handshake_rejected: &mut bool,
+    traces: &mut BackendTraces,
+    slowlog: &mut SlowLog,
+    host: &SocketAddr,
+    big_value_threshold: usize,
+    mirror: Option<MirrorKind>,
+    chaos: &Option<SharedChaos>,
+    timers: &Timers,
+    written_clients: &mut Vec<ClientTokenValue>,
     stats: &mut Stats,
 ) -> Result<bool, RedisError> {
     match stream {
@@ -794,22 +2052,15 @@ fn route_backend_response(
                     let response = match extract_redis_command(buf) {
                         Ok(r) => r,
                         Err(RedisError::IncompleteMessage) => {
-                            error!("Incomplete message. trying again.");
-                            read_attempts -= 1;
-                            if read_attempts == 0 {
-                                buf
-                            } else {
-                                continue;
-                            }
-                        }
-                        Err(RedisError::Unknown(a)) => {
-                            error!("Received unknown: {}, {:?}", RedisError::Unknown(a), std::str::from_utf8(buf));
+                            debug!("Incomplete message. trying again.");
                             read_attempts -= 1;
+                            // A partial response is never routed as a whole one, which would put the backend out of
+                            // step with its queue. If reads stop adding to it, e.g. because it's larger than the
+                            // buffer, the backend is disconnected instead.
                             if read_attempts == 0 {
-                                buf
-                            } else {
-                                continue;
+                                return Err(RedisError::IncompleteMessage);
                             }
+                            continue;
                         }
                         Err(err) => { return Err(err); }
                     };
@@ -817,12 +2068,18 @@ fn route_backend_response(
                         return Ok(false);
                     }
 
-                    let (client_token, request_id) = match queue.pop_front() {
-                        Some((client_token, instant, id)) => (client_token, (instant, id)),
-                        None => panic!("No more client token in backend queue, even though queue length was >0 just now!"),
+                    let (client_token, request_id, command, timeout) = match queue.pop_front() {
+                        Some((client_token, instant, id, command, timeout)) => (client_token, (instant, id), command, timeout),
+                        None => {
+                            // A response that no request is waiting for, so the backend can no longer be trusted.
+                            error!("Received a response from backend {} with no request waiting for it.", host);
+                            return Err(RedisError::InvalidProtocol);
+                        }
                     };
 
-                    if client_token == NULL_TOKEN {
+                    if client_token == TIMED_OUT {
+                        debug!("Discarded the response from backend {} to a request that timed out", host);
+                    } else if client_token == NULL_TOKEN {
                         handle_internal_response(
                             status,
                             waiting_for_auth_resp,
@@ -831,32 +2088,114 @@ fn route_backend_response(
                             response,
                             internal_resp_handler,
                             cached_backend_shards,
+                            connections,
+                            errors,
+                            handshake_rejected,
+                            host,
                         );
                     } else {
-                        handle_write_to_client(clients, &client_token.0, response, request_id, completed_clients, stats);
+                        // The queue holds the request's timeout deadline, so step back to when it was received.
+                        let start = request_id.0 - timeout;
+                        let fault = match *chaos {
+                            Some(ref chaos) if mirror.is_none() => chaos.borrow_mut().response_fault(host),
+                            _ => None,
+                        };
+                        if fault == Some(Fault::Drop) {
+                            debug!("Chaos mode dropped the response from backend {} for client {:?}", host, client_token);
+                            traces.fail(client_token, request_id, "Response dropped");
+                            slowlog.discard(client_token, request_id);
+                            timers.borrow_mut().insert(request_id.0, TimerEvent::DroppedResponse(client_token, (start, request_id.1)));
+                            break response.len();
+                        }
+                        // The error is counted as the backend's, like one it sent.
+                        let response: &[u8] = match fault {
+                            Some(Fault::Error(ref error)) => &error[..],
+                            _ => response,
+                        };
+                        latency.record_since(start);
+                        if let Some(ref mut limiter) = *concurrency_limiter {
+                            limiter.on_response(clock::since(start));
+                        }
+                        log_event!(
+                            LogLevel::Debug,
+                            "backend_response",
+                            { backend: host, token: client_token.0, latency_us: micros_since(start) },
+                            "Response from backend {} for client {:?}", host, client_token
+                        );
+                        let command_stats = command_stats(commands, command);
+                        command_stats.latency.record_since(start);
+                        record_value_size(&mut command_stats.max_response_bytes, response.len(), "response", command, host, big_value_threshold);
+                        if response[0] == b'-' {
+                            command_stats.errors += 1;
+                            errors.record_error_response(response);
+                        }
+                        slowlog.finish(client_token, request_id, start, *host, command, None);
+                        let trace = traces.take_response(client_token, request_id);
+                        // Clients know their requests by when they were read.
+                        if let Some(kind) = mirror {
+                            record_mirror_response(kind, clients, &client_token.0, response, (start, request_id.1));
+                        } else {
+                            buffer_client_response(clients, &client_token.0, response, (start, request_id.1), completed_clients, connections, written_clients, stats);
+                        }
+                        if let Some(trace) = trace {
+                            traces.finish(trace);
+                        }
                     }
                     break response.len()
                 }
             };
             s.consume(len);
-            stats.recv_backend_bytes += len;
+            stats.shard().recv_backend_bytes += len;
 
             return Ok(true);
         }
         // This case occurs if the backend is disconnected. If that's the case, then it should send error messges to clients.
         None => {
-            let (client_token, request_id) = match queue.pop_front() {
-                Some((client_token, instant, id)) => (client_token, (instant, id)),
-                None => panic!("No more client token in backend queue, even though queue length was >0 just now!"),
+            let (client_token, request_id, command, timeout) = match queue.pop_front() {
+                Some((client_token, instant, id, command, timeout)) => (client_token, (instant, id), command, timeout),
+                None => return Ok(false),
             };
-            if client_token != NULL_TOKEN {
-                handle_write_to_client(clients,&client_token.0, b"ERR Backend disconnected", request_id, completed_clients, stats);
+            if client_token != NULL_TOKEN && client_token != TIMED_OUT {
+                command_stats(commands, command).errors += 1;
+                errors.backend_unavailable += 1;
+                traces.fail(client_token, request_id, "Backend disconnected");
+                slowlog.discard(client_token, request_id);
+                let received = (request_id.0 - timeout, request_id.1);
+                if let Some(kind) = mirror {
+                    record_mirror_response(kind, clients, &client_token.0, b"-ERR Backend disconnected\r\n", received);
+                } else {
+                    handle_write_to_client(clients, &client_token.0, b"-ERR Backend disconnected\r\n", received, completed_clients, connections, stats);
+                }
             }
             return Ok(false);
         }
     }
 }
 
+/*
+    Tracks the largest request or response seen for a command, and warns when it exceeds the big value threshold.
+*/
+fn record_value_size(
+    max_bytes: &mut usize,
+    len: usize,
+    kind: &str,
+    command: &'static str,
+    host: &SocketAddr,
+    big_value_threshold: usize,
+) {
+    if len > *max_bytes {
+        *max_bytes = len;
+    }
+    if big_value_threshold > 0 && len > big_value_threshold {
+        log_event!(
+            LogLevel::Warn,
+            "big_value",
+            { backend: host, command: command, bytes: len },
+            "Big {} of {} bytes for {} on backend {}. Exceeds big_value_threshold of {} bytes.", kind, len, command, host, big_value_threshold
+        );
+    }
+}
+
 // This extracts the command from the stream.
 // TODO: Use a StreamingIterator: https://github.com/rust-lang/rfcs/pull/1598
 pub fn parse_redis_command<R: Read>(stream: &mut BufReader<R>) -> String {
@@ -918,13 +2257,6 @@ pub fn parse_redis_command<R: Read>(stream: &mut BufReader<R>) -> String {
     command
 }
 
-// TODO: Should we want more clarity?
-fn create_timer() -> Timer<Instant> {
-    let mut builder = Builder::default();
-    builder = builder.tick_duration(Duration::from_millis(10));
-    builder.build()
-}
-
 pub fn write_to_stream(stream: &mut TcpStream, mut message: &[u8]) -> Result<(usize), WriteError> {
     loop {
         match stream.write(&message) {
@@ -962,29 +2294,231 @@ pub fn write_to_stream(stream: &mut TcpStream, mut message: &[u8]) -> Result<(us
     }
 }
 
+// Kept under the IOV_MAX of every supported platform.
+const MAX_WRITE_PARTS: usize = 512;
+
+/*
+    Writes the parts to the stream as a single message, using vectored writes so that they don't need to be copied
+    into one buffer first. Returns the number of bytes written.
+*/
+pub fn write_parts_to_stream(stream: &mut TcpStream, parts: &[&[u8]]) -> Result<usize, WriteError> {
+    let total: usize = parts.iter().map(|part| part.len()).sum();
+    let mut written = 0;
+    while written < total {
+        // Skip past what has already been written.
+        let mut skip = written;
+        let mut bufs: Vec<&IoVec> = Vec::with_capacity(std::cmp::min(parts.len(), MAX_WRITE_PARTS));
+        for part in parts {
+            if skip >= part.len() {
+                skip -= part.len();
+                continue;
+            }
+            bufs.push(From::from(&part[skip..]));
+            skip = 0;
+            if bufs.len() == MAX_WRITE_PARTS {
+                break;
+            }
+        }
+        match stream.write_bufs(&bufs) {
+            Ok(bytes_written) => {
+                written += bytes_written;
+            }
+            Err(err) => {
+                match err.kind() {
+                    std::io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    std::io::ErrorKind::WouldBlock => {
+                        continue;
+                    }
+                    _ => {
+                        let maybe_addr = match stream.peer_addr() {
+                            Ok(addr) => Some(addr),
+                            Err(_) => None,
+                        };
+                        return Err(WriteError::WriteFailure(maybe_addr, err));
+                    }
+                }
+            }
+        }
+    }
+    return Ok(written);
+}
+
+// The response of a mirror isn't written to its client, only compared with the primary's. See mirror.rs.
+fn record_mirror_response(
+    kind: MirrorKind,
+    clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+    client_token_value: &ClientTokenValue,
+    message: &[u8],
+    request_id: (Instant, usize),
+) {
+    if let Some((client, _)) = clients.get_mut(client_token_value) {
+        client.get_mut().on_mirror_response(kind, request_id, message);
+    }
+}
+
+/*
+    Responses at least this large aren't buffered by buffer_client_response, but written straight from the backend's
+    read buffer, so that large values are forwarded without being copied.
+*/
+const MIN_UNBUFFERED_RESPONSE: usize = 4 * 1024;
+
+/*
+    Like handle_write_to_client, but a normal response is only buffered, so that the responses read from a backend in
+    one pass are written to the client together. The client is added to written_clients, for flush_clients. A large
+    response is written right away instead, after what's already buffered, with a single vectored write.
+*/
+fn buffer_client_response(
+    clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+    client_token_value: &ClientTokenValue,
+    message: &[u8],
+    request_id: (Instant, usize),
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    connections: &mut ConnectionStats,
+    written_clients: &mut Vec<ClientTokenValue>,
+    stats: &mut Stats,
+) {
+    if request_id.1 != 0 {
+        // A completed multikey response is written right away, after anything already buffered.
+        handle_write_to_client(clients, client_token_value, message, request_id, completed_clients, connections, stats);
+        return;
+    }
+    answer_collapsed(clients, client_token_value, message, request_id, completed_clients, connections, stats);
+    let res = match clients.get_mut(client_token_value) {
+        Some((client, _)) => {
+            let client = client.get_mut();
+            // The other backend of a hedged read already answered it.
+            if !client.is_first_response(request_id) {
+                return;
+            }
+            client.on_response(request_id);
+            client.on_primary_response(request_id, message);
+            stats.shard().responses += 1;
+            client.fill_cache(request_id, message);
+            let filtered = filter_response(&client.filters, *client_token_value, message);
+            let message = match filtered {
+                Some(ref response) => response,
+                None => message,
+            };
+            if let Some(ref capture) = client.capture {
+                capture.borrow_mut().record(Direction::Response, *client_token_value, message);
+            }
+            if message.len() < MIN_UNBUFFERED_RESPONSE {
+                if client.output.is_empty() {
+                    written_clients.push(*client_token_value);
+                }
+                client.output.extend_from_slice(message);
+                return;
+            }
+            let write_start = Instant::now();
+            let res = write_client_output(&mut client.stream, &mut client.output, &[message]);
+            stats.event_loop.record_client_write(write_start);
+            res
+        }
+        None => return,
+    };
+    match res {
+        Ok(bytes_written) => {
+            stats.shard().send_client_bytes += bytes_written;
+        }
+        Err(err) => {
+            debug!("Removing client: Received error: {}", err);
+            clients.remove(client_token_value);
+            connections.client_disconnects += 1;
+        }
+    }
+}
+
+// Writes the responses buffered by buffer_client_response.
+fn flush_clients(
+    clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+    written_clients: &mut Vec<ClientTokenValue>,
+    connections: &mut ConnectionStats,
+    stats: &mut Stats,
+) {
+    for client_token_value in written_clients.drain(..) {
+        let write_start = Instant::now();
+        let res = match clients.get_mut(&client_token_value) {
+            Some((client, _)) => {
+                let client = client.get_mut();
+                write_client_output(&mut client.stream, &mut client.output, &[])
+            }
+            None => continue,
+        };
+        stats.event_loop.record_client_write(write_start);
+        match res {
+            Ok(bytes_written) => {
+                stats.shard().send_client_bytes += bytes_written;
+            }
+            Err(err) => {
+                debug!("Removing client: Received error: {}", err);
+                clients.remove(&client_token_value);
+                connections.client_disconnects += 1;
+            }
+        }
+    }
+}
+
+// Writes a client's buffered responses, followed by the given parts, with a single vectored write.
+fn write_client_output(stream: &mut TcpStream, output: &mut Vec<u8>, parts: &[&[u8]]) -> Result<usize, WriteError> {
+    if output.is_empty() {
+        return write_parts_to_stream(stream, parts);
+    }
+    let result = {
+        let mut all_parts: Vec<&[u8]> = Vec::with_capacity(parts.len() + 1);
+        all_parts.push(output);
+        all_parts.extend_from_slice(parts);
+        write_parts_to_stream(stream, &all_parts)
+    };
+    output.clear();
+    return result;
+}
+
 pub fn handle_write_to_client(
     clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
     client_token_value: &ClientTokenValue,
     message: &[u8],
     request_id: (Instant, usize),
     completed_clients: &mut VecDeque<ClientTokenValue>,
+    connections: &mut ConnectionStats,
     stats: &mut Stats,
 ) {
+    answer_collapsed(clients, client_token_value, message, request_id, completed_clients, connections, stats);
     let res = match clients.get_mut(client_token_value) {
         Some((client, _)) => write_to_client(client.get_mut(), client_token_value, message, request_id, completed_clients, stats),
         None => { return; }
     };
     match res {
         Ok(bytes_written) => {
-            stats.send_client_bytes += bytes_written;
+            stats.shard().send_client_bytes += bytes_written;
         }
         Err(err) => {
             debug!("Removing client: Received error: {}", err);
             clients.remove(client_token_value);
+            connections.client_disconnects += 1;
         }
     }
 }
 
+/*
+    Answers the GETs that joined a client's GET with collapse_gets, with a copy of its response or error. They are
+    answered even if the client that sent it is gone.
+*/
+fn answer_collapsed(
+    clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+    client_token_value: &ClientTokenValue,
+    message: &[u8],
+    request_id: (Instant, usize),
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    connections: &mut ConnectionStats,
+    stats: &mut Stats,
+) {
+    for (waiter, waiter_request_id) in collapse::take_waiters(Token(*client_token_value), request_id) {
+        handle_write_to_client(clients, &waiter.0, message, waiter_request_id, completed_clients, connections, stats);
+    }
+}
+
 
 pub fn write_to_client(
     client: &mut Client,
@@ -994,31 +2528,168 @@ pub fn write_to_client(
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
 ) -> std::result::Result<usize, WriteError> {
-    if request_id.1 == 0 {
+    if !client.is_first_response(request_id) {
+        return Ok(0);
+    }
+    client.on_response(request_id);
+    // Errors of the proxy's own are sent as the pool's error_responses.
+    let custom_error = match client.error_responses {
+        Some(ref error_responses) => error_responses.replace(message).map(|response| response.to_vec()),
+        None => None,
+    };
+    let message = match custom_error {
+        Some(ref response) => &response[..],
+        None => message,
+    };
+    client.on_primary_response(request_id, message);
+    // A multi key request is answered once all of its keys are.
+    let answered = request_id.1 == 0 || client.pending_count == 1;
+    if let Some(ref mut limits) = client.limits {
+        if answered && limits.on_response() {
+            completed_clients.push_back(*client_token_value);
+        }
+    }
+    if let Some(ref identity) = client.identity {
+        identity.quota.borrow_mut().charge_bytes(message.len());
+    }
+    let write_start = Instant::now();
+    client.mark_active();
+    client.fill_cache(request_id, message);
+    let result = if request_id.1 == 0 {
         // Id of 0 means that request is a normal request.
-        stats.responses += 1;
-        write_to_stream(&mut client.stream, message)
+        stats.shard().responses += 1;
+        let filtered = filter_response(&client.filters, *client_token_value, message);
+        let message = match filtered {
+            Some(ref response) => response,
+            None => message,
+        };
+        if let Some(ref capture) = client.capture {
+            capture.borrow_mut().record(Direction::Response, *client_token_value, message);
+        }
+        write_client_output(&mut client.stream, &mut client.output, &[message])
     } else {
-        // Id > 0 means that the request is a multikey request.
+        // Id > 0 means that the request is a multikey request. The backend's read buffer is reused for its next
+        // response, so each key's response is kept as a copy until all of them are in.
         client.pending_response[request_id.1 - 1] = message.to_vec();
         client.pending_count -= 1;
         if client.pending_count == 0 {
-            // Assemble the full response.
-            let mut full_message = Vec::new();
-            full_message.extend_from_slice(b"*");
-            full_message.extend_from_slice(client.pending_response.len().to_string().as_bytes());
-            full_message.extend_from_slice(b"\r\n");
-            for i in client.pending_response.iter() {
-                full_message.extend_from_slice(&i);
+            // The full response is the array header followed by each response, written without joining them.
+            let header = format!("*{}\r\n", client.pending_response.len());
+            // The responses to a broadcast request are merged into one instead.
+            let merged = client.pending_broadcast.take().map(|broadcast| functions::merge(broadcast, &client.pending_response));
+            let mut parts: Vec<&[u8]> = Vec::with_capacity(client.pending_response.len() + 1);
+            match merged {
+                Some(ref response) => parts.push(response),
+                None => {
+                    parts.push(header.as_bytes());
+                    for response in client.pending_response.iter() {
+                        parts.push(response);
+                    }
+                }
             }
 
             // Add client to completed_clients, to force an event to trigger for the client. It will normally not
             // fire because the poll is edge-triggered, not level-triggered.
             completed_clients.push_back(*client_token_value);
-            stats.responses += 1;
-            write_to_stream(&mut client.stream, &full_message)
+            stats.shard().responses += 1;
+            // Filters see the joined response, which is then written in one part.
+            let filtered = if client.filters.is_some() {
+                let response = parts.concat();
+                Some(filter_response(&client.filters, *client_token_value, &response).unwrap_or(response))
+            } else {
+                None
+            };
+            if let Some(ref response) = filtered {
+                parts = vec![response];
+            }
+            if let Some(ref capture) = client.capture {
+                capture.borrow_mut().record(Direction::Response, *client_token_value, &parts.concat());
+            }
+            write_client_output(&mut client.stream, &mut client.output, &parts)
         } else {
             Ok(0)
         }
-    }
+    };
+    stats.event_loop.record_client_write(write_start);
+    return result;
+}
+
+// Runs a response through the client's filters. Returns the response to send instead, if they changed it.
+fn filter_response(filters: &Option<SharedFilters>, client_token_value: ClientTokenValue, response: &[u8]) -> Option<Vec<u8>> {
+    let filters = match *filters {
+        Some(ref filters) => filters,
+        None => return None,
+    };
+    return match filters.borrow_mut().on_response(client_token_value, response) {
+        FilterAction::Continue => None,
+        FilterAction::Rewrite(response) | FilterAction::Reply(response) => Some(response),
+    };
+}
+
+#[test]
+fn test_write_parts_to_stream() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::from_stream(std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    let value = vec![b'x'; 100000];
+    let mut parts: Vec<&[u8]> = vec![b"*2\r\n", b"", b"$100000\r\n", &value, b"\r\n"];
+    // More parts than a single vectored write takes.
+    parts.extend(vec![&b"+"[..]; MAX_WRITE_PARTS]);
+    let expected = parts.concat();
+    let reader = std::thread::spawn(move || {
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        received
+    });
+    assert_eq!(write_parts_to_stream(&mut stream, &parts).unwrap(), expected.len());
+    drop(stream);
+    assert_eq!(reader.join().unwrap(), expected);
+}
+
+#[test]
+fn test_write_client_output() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut stream = TcpStream::from_stream(std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+
+    // Buffered responses are written ahead of the next response, and the buffer is emptied.
+    let mut output = b"+OK\r\n$-1\r\n".to_vec();
+    assert_eq!(write_client_output(&mut stream, &mut output, &[b"*1\r\n", b":1\r\n"]).unwrap(), 18);
+    assert!(output.is_empty());
+    assert_eq!(write_client_output(&mut stream, &mut output, &[b"+PONG\r\n"]).unwrap(), 7);
+    drop(stream);
+    let mut received = Vec::new();
+    peer.read_to_end(&mut received).unwrap();
+    assert_eq!(&received[..], &b"+OK\r\n$-1\r\n*1\r\n:1\r\n+PONG\r\n"[..]);
+}
+
+#[test]
+fn test_queue_limits() {
+    let limits = QueueLimits::new(4, 100);
+    assert!(!limits.is_full(3, 99));
+    assert!(limits.is_full(4, 0));
+    assert!(limits.is_full(0, 100));
+    // Paused clients are only resumed once the backend is down to half of both limits.
+    assert!(!limits.has_room(3, 0));
+    assert!(!limits.has_room(2, 51));
+    assert!(limits.has_room(2, 50));
+
+    let unlimited = QueueLimits::new(0, 0);
+    assert!(!unlimited.is_full(100000, 100000000));
+    assert!(unlimited.has_room(100000, 100000000));
+}
+
+#[test]
+fn test_auth_request() {
+    use toml;
+    let config = |server: &str| -> BackendConfig { toml::Value::try_into(toml::from_str(server).unwrap()).unwrap() };
+    assert_eq!(auth_request(&config("weight = 1")), None);
+    assert_eq!(auth_request(&config("weight = 1\nauth = \"password1\"")), Some(b"*2\r\n$4\r\nAUTH\r\n$9\r\npassword1\r\n".to_vec()));
+    assert_eq!(
+        auth_request(&config("weight = 1\nauth = \"password1\"\nusername = \"proxy\"")),
+        Some(b"*3\r\n$4\r\nAUTH\r\n$5\r\nproxy\r\n$9\r\npassword1\r\n".to_vec())
+    );
 }
@@ -0,0 +1,182 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use hashbrown::HashMap;
+
+// How long each backend gets to answer. The admin port waits for each backend in turn.
+const INFO_TIMEOUT_MS: u64 = 1000;
+
+/*
+    Key fields of a backend's INFO reply.
+*/
+#[derive(Debug, PartialEq)]
+pub struct BackendInfo {
+    pub role: String,
+    pub used_memory: String,
+    pub connected_clients: String,
+    // For a replica, seconds since it last heard from its master. For a master, the highest lag of its replicas.
+    // "-" if it has no replication link.
+    pub repl_lag: String,
+}
+
+impl BackendInfo {
+    pub fn from_info(info: &str) -> BackendInfo {
+        let fields = parse_info(info);
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_else(|| "-".to_owned());
+        let role = field("role");
+        let repl_lag = if role == "slave" {
+            field("master_last_io_seconds_ago")
+        } else {
+            // e.g. slave0:ip=10.0.0.2,port=6379,state=online,offset=1234,lag=1
+            let lags = fields.iter()
+                .filter(|&(name, _)| name.starts_with("slave") && name[5..].parse::<usize>().is_ok())
+                .filter_map(|(_, value)| value.split(',').find(|part| part.starts_with("lag=")).and_then(|lag| lag[4..].parse::<u64>().ok()));
+            match lags.max() {
+                Some(lag) => lag.to_string(),
+                None => "-".to_owned(),
+            }
+        };
+        BackendInfo {
+            role: role,
+            used_memory: field("used_memory_human"),
+            connected_clients: field("connected_clients"),
+            repl_lag: repl_lag,
+        }
+    }
+}
+
+// Parses the "name:value" lines of an INFO reply. Section headers and blank lines are skipped.
+pub fn parse_info(info: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    for line in info.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            fields.insert(name.to_owned(), value.to_owned());
+        }
+    }
+    return fields;
+}
+
+/*
+    Sends INFO to a backend on a new connection, after its AUTH request if it requires one. Returns the INFO text, or
+    a description of what went wrong.
+*/
+pub fn fetch_info(host: SocketAddr, auth_request: Option<&[u8]>) -> Result<String, String> {
+    let timeout = Duration::from_millis(INFO_TIMEOUT_MS);
+    let stream = try!(TcpStream::connect_timeout(&host, timeout).map_err(|err| format!("connect failed: {}", err)));
+    try!(stream.set_read_timeout(Some(timeout)).map_err(|err| err.to_string()));
+    try!(stream.set_write_timeout(Some(timeout)).map_err(|err| err.to_string()));
+    let mut reader = BufReader::new(try!(stream.try_clone().map_err(|err| err.to_string())));
+    let mut writer = stream;
+    if let Some(request) = auth_request {
+        try!(writer.write_all(request).map_err(|err| format!("write failed: {}", err)));
+        let line = try!(read_line(&mut reader));
+        if !line.starts_with('+') {
+            return Err(format!("AUTH failed: {}", line));
+        }
+    }
+    try!(writer.write_all(b"*1\r\n$4\r\nINFO\r\n").map_err(|err| format!("write failed: {}", err)));
+    let line = try!(read_line(&mut reader));
+    if !line.starts_with('$') {
+        return Err(format!("INFO failed: {}", line));
+    }
+    let len = try!(line[1..].parse::<usize>().map_err(|_| format!("invalid reply: {}", line)));
+    let mut info = vec![0; len];
+    try!(reader.read_exact(&mut info).map_err(|err| format!("read failed: {}", err)));
+    return Ok(String::from_utf8_lossy(&info).into_owned());
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err("connection closed".to_owned()),
+        Ok(_) => Ok(line.trim_end().to_owned()),
+        Err(err) => Err(format!("read failed: {}", err)),
+    }
+}
+
+/*
+    Formats one row per backend, with aligned columns. e.g.:
+    host            role    used_memory  connected_clients  repl_lag
+    127.0.0.1:6380  master  1.02M        12                 0
+    127.0.0.1:6381  error: connect failed: Connection refused (os error 111)
+*/
+pub fn format_table(rows: &[(String, Result<BackendInfo, String>)]) -> String {
+    let header = ["host", "role", "used_memory", "connected_clients", "repl_lag"];
+    let mut table: Vec<Vec<String>> = vec![header.iter().map(|name| name.to_string()).collect()];
+    for (host, info) in rows.iter() {
+        table.push(match info {
+            Ok(info) => vec![
+                host.clone(),
+                info.role.clone(),
+                info.used_memory.clone(),
+                info.connected_clients.clone(),
+                info.repl_lag.clone(),
+            ],
+            Err(err) => vec![host.clone(), format!("error: {}", err)],
+        });
+    }
+    let mut widths = vec![0; header.len()];
+    for row in table.iter().filter(|row| row.len() == header.len()) {
+        for (index, cell) in row.iter().enumerate() {
+            widths[index] = std::cmp::max(widths[index], cell.len());
+        }
+    }
+    let lines: Vec<String> = table.iter().map(|row| {
+        let last = row.len() - 1;
+        let cells: Vec<String> = row.iter().enumerate().map(|(index, cell)| {
+            if index == last { cell.clone() } else { format!("{:width$}", cell, width = widths[index]) }
+        }).collect();
+        cells.join("  ")
+    }).collect();
+    return lines.join("\n");
+}
+
+#[test]
+fn test_backend_info() {
+    let master = "# Server\r\nredis_version:5.0.0\r\n\r\n# Clients\r\nconnected_clients:12\r\n\r\n# Memory\r\n\
+        used_memory:1069520\r\nused_memory_human:1.02M\r\n\r\n# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+        slave0:ip=10.0.0.2,port=6379,state=online,offset=1234,lag=1\r\n\
+        slave1:ip=10.0.0.3,port=6379,state=online,offset=1200,lag=3\r\n";
+    assert_eq!(BackendInfo::from_info(master), BackendInfo {
+        role: "master".to_owned(),
+        used_memory: "1.02M".to_owned(),
+        connected_clients: "12".to_owned(),
+        repl_lag: "3".to_owned(),
+    });
+    let replica = "role:slave\r\nmaster_host:10.0.0.1\r\nmaster_last_io_seconds_ago:2\r\nconnected_clients:1\r\n";
+    let replica = BackendInfo::from_info(replica);
+    assert_eq!(replica.repl_lag, "2");
+    assert_eq!(replica.used_memory, "-");
+
+    let table = format_table(&[
+        ("127.0.0.1:6380".to_owned(), Ok(BackendInfo::from_info(master))),
+        ("127.0.0.1:6381".to_owned(), Err("connect failed".to_owned())),
+    ]);
+    assert_eq!(table, "host            role    used_memory  connected_clients  repl_lag\n\
+        127.0.0.1:6380  master  1.02M        12                 3\n\
+        127.0.0.1:6381  error: connect failed");
+}
+
+#[test]
+fn test_fetch_info() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        let len = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"*2\r\n$4\r\nAUTH\r\n$9\r\npassword1\r\n");
+        stream.write_all(b"+OK\r\n").unwrap();
+        let len = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"*1\r\n$4\r\nINFO\r\n");
+        stream.write_all(b"$34\r\nrole:master\r\nconnected_clients:3\r\n\r\n").unwrap();
+    });
+    let info = fetch_info(host, Some(b"*2\r\n$4\r\nAUTH\r\n$9\r\npassword1\r\n")).unwrap();
+    assert_eq!(BackendInfo::from_info(&info).connected_clients, "3");
+}
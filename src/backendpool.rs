@@ -1,5 +1,30 @@
 use client::BufferedClient;
-use stats::Stats;
+use stats::{Stats, PoolStats, command_stats};
+use hotkeys::HotKeys;
+use cache::{ReadCache, SharedCache};
+use mirror::{Mirrors, MirrorStats, PercentSampler, SharedMirrorStats};
+use sentinel::SentinelWatcher;
+use discovery::DiscoveryWatcher;
+use standby::Failover;
+use ratelimit::{Admission, ClientLimits, KeyRateLimits, RATE_LIMITED};
+use readyourwrites::RecentWrites;
+use quota::{Users, QUOTA_EXCEEDED};
+use acl::{NOPERM_COMMAND, NOPERM_KEY};
+use namespace;
+use netacl::NetworkAcl;
+use quarantine::Quarantine;
+use maintenance::Maintenance;
+use proxyprotocol::{self, HeaderRead};
+use slowlog::split_args;
+use audit::AuditLog;
+use capture::{Direction, SharedCapture};
+use chaos::{Chaos, SharedChaos};
+use errorresponses::ErrorResponses;
+use clock;
+use collapse;
+use functions::{self, Broadcast};
+use filter::{FilterAction, SharedFilters};
+use trace::{RequestTrace, TraceSampler};
 use std::collections::VecDeque;
 use backend::{write_to_client};
 use bufreader::BufReader;
@@ -7,19 +32,28 @@ use redflareproxy::PoolTokenValue;
 use redflareproxy::ClientTokenValue;
 use backend::SingleBackend;
 use redflareproxy::ClientToken;
-use client::{Client};
+use client::{Client, Hedge};
 use redflareproxy::ProxyError;
-use redisprotocol::extract_redis_command;
+use redisprotocol::extract_redis_command_within;
+use resp::{Limit, Limits};
+use worker;
+use handoff;
+use fastopen;
+use memory;
+use tokens::{SharedTokenSlab, TokenSlot};
+use registry::{Interest, Registered, Registry};
 use hash::hash;
 use redflareproxy::BackendToken;
 use redflareproxy::PoolToken;
-use config::{Distribution, BackendPoolConfig};
+use redflareproxy::{TimerEvent, Timers};
+use config::{Distribution, BackendPoolConfig, MaintenanceMode, QuotaEnforcement, StalePolicy};
 use backend::{Backend};
-use redisprotocol::{extract_key, RedisError, KeyPos};
+use redisprotocol::{extract_key, command_name, is_read_only, RedisError, KeyPos, WriteError};
 use mio::*;
-use mio::tcp::{TcpListener};
+use log::LogLevel;
+use mio::tcp::{TcpListener, TcpStream};
 use std::string::String;
-use std::io::{BufRead};
+use std::io::{BufRead, Write};
 use hashbrown::HashMap;
 use conhash::*;
 use conhash::Node;
@@ -27,6 +61,8 @@ use rand::thread_rng;
 use rand::Rng;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::net::SocketAddr;
+use std::time::SystemTime;
 
 #[derive(Clone)]
 struct IndexNode {
@@ -51,46 +87,171 @@ pub struct BackendPool {
     pub first_backend_index: usize,
     pub num_backends: usize,
 
-    pub listen_socket: Option<TcpListener>,
+    pub listen_socket: Option<Registered<TcpListener>>,
+
+    pub stats: PoolStats,
+
+    // Only set when hotkey_sample_rate is configured.
+    pub hotkeys: Option<HotKeys>,
+
+    // Only set when cache_max_bytes is configured.
+    pub cache: Option<SharedCache>,
+
+    // Only set when a backend is discovered through sentinel.
+    pub sentinel: Option<SentinelWatcher>,
+
+    // Only set when discovery is configured.
+    pub discovery: Option<DiscoveryWatcher>,
+
+    // Only set when dual_write is configured.
+    pub dual_write_stats: Option<SharedMirrorStats>,
+
+    // Position of the canary among the pool's backends, if it has one, and the stats of the requests mirrored to it.
+    canary_index: Option<usize>,
+    pub canary_stats: Option<SharedMirrorStats>,
+    canary_sampler: PercentSampler,
+
+    // Only set when a standby pool is configured.
+    pub failover: Option<Failover>,
+
+    // The worker's timers, to read clients again once they're within their limits.
+    pub timers: Option<Timers>,
+
+    // Only set when key_rate_limits are configured.
+    key_limits: Option<KeyRateLimits>,
+
+    // Only set when the pool has users, which its clients must authenticate as.
+    pub users: Option<Users>,
+
+    // Only set when allow_clients or deny_clients are configured.
+    network_acl: Option<NetworkAcl>,
+
+    // Only set when quarantine_threshold is configured.
+    quarantine: Option<Quarantine>,
+
+    // Only set when maintenance windows are configured.
+    maintenance: Option<Maintenance>,
+
+    // Shared by all pools. Only set when audit_log_file is configured.
+    pub audit_log: Option<Rc<AuditLog>>,
+
+    // Set while a CAPTURE is running on the pool.
+    pub capture: Option<SharedCapture>,
+
+    // Only set when chaos is configured. Shared with the backend hosts it injects faults on.
+    pub chaos: Option<SharedChaos>,
+
+    // Only set when filters have been added for the pool.
+    pub filters: Option<SharedFilters>,
+
+    // Only set when error_responses is configured. Shared with the pool's clients, which send them.
+    pub error_responses: Option<Rc<ErrorResponses>>,
+
+    trace_sampler: TraceSampler,
 }
 
 impl BackendPool {
     pub fn new(pool_name: String, pool_token: PoolToken, config: BackendPoolConfig, enable_advanced_commands: bool, first_backend_index: usize) -> BackendPool {
         debug!("PoolToken: {:?} for pool: {:?}", pool_token, pool_name);
+        let hotkeys = if config.hotkey_sample_rate > 0 {
+            Some(HotKeys::new(config.hotkey_sample_rate, config.hotkey_top_k))
+        } else {
+            None
+        };
+        let cache = ReadCache::from_config(&config);
+        let sentinel = SentinelWatcher::from_config(&config);
+        let discovery = DiscoveryWatcher::from_config(&pool_name, &config);
+        let dual_write_stats = if config.dual_write {
+            Some(MirrorStats::shared())
+        } else {
+            None
+        };
+        let canary_index = config.servers.iter().position(|backend_config| backend_config.canary);
+        let canary_stats = canary_index.map(|_| MirrorStats::shared());
+        let canary_sampler = PercentSampler::new(config.canary_percent);
+        let failover = Failover::from_config(&config);
+        let key_limits = KeyRateLimits::from_config(&config);
+        let users = Users::from_config(&config);
+        let chaos = Chaos::from_config(&config);
+        // The responses were validated when the config was parsed.
+        let error_responses = config.error_responses.as_ref().and_then(|error_responses| ErrorResponses::from_config(error_responses).ok()).map(Rc::new);
+        // The networks were validated when the config was parsed.
+        let network_acl = NetworkAcl::from_config(&config.allow_clients, &config.deny_clients).unwrap_or(None);
+        let quarantine = Quarantine::from_config(&config);
+        // The windows were validated when the config was parsed.
+        let mut maintenance = Maintenance::from_config(&config.maintenance).unwrap_or(None);
+        if let Some(ref mut maintenance) = maintenance {
+            if maintenance.update(SystemTime::now()) {
+                log_event!(LogLevel::Info, "maintenance_started", { pool: pool_name }, "Pool {} is starting out under maintenance", pool_name);
+            }
+        }
         BackendPool {
             name: pool_name,
             token: pool_token,
             num_backends: config.servers.len(),
+            trace_sampler: TraceSampler::new(config.trace_sample_rate),
             config: config,
             enable_advanced_commands: enable_advanced_commands,
             first_backend_index: first_backend_index,
             listen_socket: None,
             cached_backend_shards: Rc::new(RefCell::new(None)),
+            stats: PoolStats::new(),
+            hotkeys: hotkeys,
+            cache: cache,
+            sentinel: sentinel,
+            discovery: discovery,
+            dual_write_stats: dual_write_stats,
+            canary_index: canary_index,
+            canary_stats: canary_stats,
+            canary_sampler: canary_sampler,
+            failover: failover,
+            timers: None,
+            key_limits: key_limits,
+            users: users,
+            network_acl: network_acl,
+            quarantine: quarantine,
+            maintenance: maintenance,
+            audit_log: None,
+            capture: None,
+            chaos: chaos,
+            filters: None,
+            error_responses: error_responses,
         }
     }
 
     /*
         Attempts to establish the pool by binding to the listening socket, and registering to the event poll.
-        If this process fails, an error is returned.
+        If this process fails, an error is returned. With reuse_port, every worker binds its own listener to the address.
     */
-    pub fn connect(&mut self, poll_registry: &mut Poll) -> Result<(), ProxyError> {
-        // Setup the server socket
+    pub fn connect(&mut self, registry: &Registry, reuse_port: bool) -> Result<(), ProxyError> {
+        // Setup the server socket, unless one was handed off by a hot restart.
         let addr = self.config.listen;
-        let server_socket = match TcpListener::bind(&addr) {
+        let bound = if let Some(inherited) = handoff::take_listener(&addr) {
+            inherited
+        } else if reuse_port {
+            worker::bind_reuse_port(&addr)
+        } else {
+            TcpListener::bind(&addr)
+        };
+        let server_socket = match bound {
             Ok(soc) => soc,
             Err(err) => {
                 return Err(ProxyError::PoolBindSocketFailure(addr, err));
             }
         };
+        if self.config.tcp_fastopen {
+            if let Err(err) = fastopen::enable_listener(&server_socket) {
+                log_event!(LogLevel::Warn, "tcp_fastopen_unavailable", { pool: self.name }, "Unable to enable TCP Fast Open for pool {}: {}", self.name, err);
+            }
+        }
 
         debug!("Setup backend listener: {:?}", self.token);
-        match poll_registry.register(&server_socket, self.token, Ready::readable(), PollOpt::edge()) {
-            Ok(_) => {}
+        match Registered::new(registry, server_socket, self.token, Interest::Readable) {
+            Ok(server_socket) => self.listen_socket = Some(server_socket),
             Err(err) => {
                 return Err(ProxyError::PoolPollFailure(err));
             }
         };
-        self.listen_socket = Some(server_socket);
 
         if self.config.warm_sockets {
             // There's an issue where new TcpStreams take 10-15 ms longer to be accepted. It appears to be due to some kind
@@ -108,41 +269,314 @@ impl BackendPool {
 
     pub fn accept_client_connection(
         &mut self,
-        poll: &Rc<RefCell<Poll>>,
-        next_client_token_value: &mut ClientTokenValue,
+        registry: &Registry,
+        client_tokens: &SharedTokenSlab,
         clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
         stats: &mut Stats,
     ) {
-        match self.listen_socket {
-            Some(ref mut listener) => {
-                loop {
-                    let mut stream = match listener.accept() {
-                        Ok(s) => s.0,
-                        Err(e) => {
-                            if e.kind() == std::io::ErrorKind::WouldBlock {
-                                return;
-                            }
-                            panic!("Failed for some reason {:?}", e);
-                        }
-                    };
-                    let client_token = Token(*next_client_token_value);
-                    *next_client_token_value += 1;
-                    match poll.borrow_mut().register(&stream, client_token, Ready::readable(), PollOpt::edge()) {
-                        Ok(_) => {
-                            clients.insert(client_token.0, (BufReader::new(Client::new(stream)), self.token.0));
-                            stats.accepted_clients += 1;
-                            debug!("Backend Connection accepted: client {:?}", client_token);
-                        }
-                        Err(err) => {
-                            error!("Failed to register client token to poll: {:?}", err);
-                        }
-                    };
+        loop {
+            let accepted = match self.listen_socket {
+                Some(ref mut listener) => listener.accept(),
+                None => {
+                    error!("Listen socket is no more when accepting!");
+                    return
+                }
+            };
+            let (stream, addr) = match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::WouldBlock {
+                        return;
+                    }
+                    // e.g. the process is out of file descriptors. Remaining connections are accepted on the
+                    // next event, once other clients have disconnected.
+                    error!("Unable to accept client connection for pool {}. Received error: {}", self.name, e);
+                    return;
                 }
+            };
+            self.add_client(stream, addr, registry, client_tokens, clients, stats);
+        }
+    }
+
+    // Takes on a client connection, accepted by the pool's listener or handed over by the transparent listener.
+    pub fn add_client(
+        &mut self,
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        registry: &Registry,
+        client_tokens: &SharedTokenSlab,
+        clients: &mut HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
+        stats: &mut Stats,
+    ) {
+        // Behind a load balancer, the client's own address is only known once its PROXY protocol header is read.
+        if !self.config.proxy_protocol && !permits_client(self, &addr) {
+            // Closed once dropped, without a reply, like a firewall would.
+            return;
+        }
+        if let Some(response) = maintenance_response(self, MaintenanceMode::Drain) {
+            // The connection is closed once dropped.
+            let _ = stream.write(response);
+            debug!("Refused client connection for pool {}: draining for maintenance", self.name);
+            return;
+        }
+        if memory::is_exhausted() {
+            // The connection is closed once dropped.
+            let response: &[u8] = b"-ERROR: Proxy memory limit reached\r\n";
+            let _ = match self.error_responses {
+                Some(ref error_responses) => stream.write(error_responses.replace(response).unwrap_or(response)),
+                None => stream.write(response),
+            };
+            self.stats.connections.shed_connections += 1;
+            debug!("Refused client connection for pool {}: memory limit reached", self.name);
+            return;
+        }
+        // Dropping the slot on failure releases the token again.
+        let slot = TokenSlot::allocate(client_tokens);
+        let client_token = slot.token();
+        match Registered::new(registry, stream, client_token, Interest::Readable) {
+            Ok(stream) => {
+                let mut client = Client::new(stream, slot);
+                if let Some(ref capture) = self.capture {
+                    if capture.borrow_mut().sample_client() {
+                        client.capture = Some(capture.clone());
+                    }
+                }
+                client.filters = self.filters.clone();
+                client.error_responses = self.error_responses.clone();
+                client.cache = self.cache.clone();
+                client.dual_writes = self.dual_write_stats.as_ref().map(Mirrors::new);
+                client.canary = self.canary_stats.as_ref().map(Mirrors::new);
+                client.limits = ClientLimits::from_config(&self.config);
+                client.recent_writes = RecentWrites::from_config(&self.config);
+                if self.config.collapse_gets {
+                    client.awaiting = Some(Default::default());
+                }
+                client.peer_addr = Some(addr);
+                client.proxy_header_pending = self.config.proxy_protocol;
+                clients.insert(client_token.0, (BufReader::pooled(client), self.token.0));
+                stats.shard().accepted_clients += 1;
+                self.stats.accepted_clients += 1;
+                log_event!(LogLevel::Debug, "client_connected", { pool: self.name, token: client_token.0 }, "Backend Connection accepted: client {:?}", client_token);
             }
-            None => {
-                error!("Listen socket is no more when accepting!");
-                return
+            Err(err) => {
+                error!("Failed to register client token to poll: {:?}", err);
             }
+        };
+    }
+
+    // Enters or leaves maintenance as the pool's maintenance windows start and end. Called once a second.
+    pub fn update_maintenance(&mut self, now: SystemTime) {
+        let maintenance = match self.maintenance {
+            Some(ref mut maintenance) => maintenance,
+            None => return,
+        };
+        if !maintenance.update(now) {
+            return;
+        }
+        match maintenance.active() {
+            Some(window) => log_event!(LogLevel::Info, "maintenance_started", { pool: self.name, mode: format!("{:?}", window.mode) }, "Pool {} is under maintenance ({:?})", self.name, window.mode),
+            None => log_event!(LogLevel::Info, "maintenance_ended", { pool: self.name }, "Pool {} is no longer under maintenance", self.name),
+        }
+    }
+}
+
+/*
+    Answers a GET whose backend is down with the key's expired cache entry, with cache_stale_ttl. The request's cache
+    fill is dropped, so that the stale response isn't cached again as a fresh one.
+*/
+fn stale_response(backend_pool: &BackendPool, client: &mut Client, command: &str, key: &[u8], request_id: (std::time::Instant, usize)) -> Option<Vec<u8>> {
+    if command != "GET" || backend_pool.config.cache_stale_ttl == 0 {
+        return None;
+    }
+    let response = match key_cache(backend_pool, key) {
+        Some(cache) => match cache.borrow_mut().get_stale(key, request_id.0) {
+            Some(response) => response.to_vec(),
+            None => return None,
+        },
+        None => return None,
+    };
+    client.cache_fills.remove(&request_id);
+    if backend_pool.config.cache_stale_policy == StalePolicy::Log {
+        log_event!(LogLevel::Info, "cache_stale_served", { pool: backend_pool.name }, "Served a stale response for {:?} of pool {}, whose backend is down", String::from_utf8_lossy(key), backend_pool.name);
+    }
+    return Some(response);
+}
+
+// The response of the pool's active maintenance window, if it's in the given mode.
+fn maintenance_response(backend_pool: &BackendPool, mode: MaintenanceMode) -> Option<&[u8]> {
+    match backend_pool.maintenance.as_ref().and_then(|maintenance| maintenance.active()) {
+        Some(window) if window.mode == mode => return Some(&window.response),
+        _ => return None,
+    }
+}
+
+// Whether a read of the key goes to a replica: with read_from_replicas, unless the client wrote the key recently.
+fn reads_from_replica(config: &BackendPoolConfig, client: &Client, key: &[u8], now: std::time::Instant) -> bool {
+    if !config.read_from_replicas {
+        return false;
+    }
+    return !client.recent_writes.as_ref().map_or(false, |recent_writes| recent_writes.is_recent(key, now));
+}
+
+/*
+    Whether a GET joined one of the same key that's waiting for its backend, with collapse_gets. Reads that go to a
+    replica, or that have a DEADLINE of their own, are sent as usual, and don't lead others either. So are the GETs of
+    a client still waiting for earlier responses, which the joined GET's response could otherwise be written before.
+*/
+fn collapses_get(
+    backend_pool: &BackendPool,
+    client: &mut Client,
+    client_token: ClientToken,
+    key: &[u8],
+    request_id: (std::time::Instant, usize),
+    request_timeout: Option<usize>,
+) -> bool {
+    if !backend_pool.config.collapse_gets || request_timeout.is_some() || !client.is_answered() {
+        return false;
+    }
+    if reads_from_replica(&backend_pool.config, client, key, request_id.0) {
+        return false;
+    }
+    if !collapse::join(backend_pool.token.0, key, client_token, request_id) {
+        return false;
+    }
+    client.await_response(request_id);
+    return true;
+}
+
+/*
+    Sends a request to every backend of the pool, e.g. FUNCTION LOAD, which is answered once they all have, with their
+    responses merged. Returns whether a backend is full, or Err if the client couldn't be written to.
+*/
+fn broadcast_request(
+    backend_pool: &mut BackendPool,
+    backends: &mut [Backend],
+    client: &mut Client,
+    client_token: ClientToken,
+    request: &[u8],
+    broadcast: Broadcast,
+    instant: std::time::Instant,
+    request_timeout: Option<usize>,
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    completed_clients: &mut VecDeque<ClientTokenValue>,
+    stats: &mut Stats,
+) -> Result<bool, ()> {
+    // The canary only gets copies of requests. See write_canary.
+    let count = backends.iter().filter(|backend| !backend.canary).count();
+    if count == 0 {
+        backend_pool.stats.errors.backend_unavailable += 1;
+        return match write_to_client(client, &client_token.0, b"-ERROR: No backend\r\n", (instant, 0), completed_clients, stats) {
+            Ok(_) => Ok(false),
+            Err(_) => Err(()),
+        };
+    }
+    client.pending_response = vec![Vec::new(); count];
+    client.pending_count = count;
+    client.pending_broadcast = Some(broadcast);
+    let mut backend_full = false;
+    for (index, backend) in backends.iter_mut().filter(|backend| !backend.canary).enumerate() {
+        let id = index + 1;
+        match backend.write_message(request, client_token, cluster_backends, (instant, id), None, request_timeout, stats) {
+            Ok(full) => {
+                backend_full |= full;
+                client.await_response((instant, id));
+            }
+            Err(err) => {
+                log_event!(LogLevel::Debug, "backend_write_failed", { pool: backend_pool.name, token: client_token.0 }, "Backend could not be written to when broadcasting. Received error: {}", err);
+                let resp: &[u8] = match err {
+                    WriteError::Overloaded => b"-ERROR: Backend overloaded\r\n",
+                    _ => {
+                        backend_pool.stats.errors.backend_unavailable += 1;
+                        b"-ERROR: Not connected\r\n"
+                    }
+                };
+                if write_to_client(client, &client_token.0, resp, (instant, id), completed_clients, stats).is_err() {
+                    return Err(());
+                }
+            }
+        }
+    }
+    return Ok(backend_full);
+}
+
+fn record_write(client: &mut Client, key: &[u8], now: std::time::Instant) {
+    if let Some(ref mut recent_writes) = client.recent_writes {
+        recent_writes.record(key, now);
+    }
+}
+
+// Whether a client may connect from the given address, under the pool's allow_clients and deny_clients, and as long as it isn't quarantined.
+fn permits_client(backend_pool: &mut BackendPool, addr: &SocketAddr) -> bool {
+    if let Some(ref network_acl) = backend_pool.network_acl {
+        if !network_acl.permits(&addr.ip()) {
+            backend_pool.stats.connections.denied_connections += 1;
+            log_event!(LogLevel::Debug, "client_denied", { pool: backend_pool.name, addr: addr }, "Refused client connection for pool {} from {}", backend_pool.name, addr);
+            return false;
+        }
+    }
+    if let Some(ref mut quarantine) = backend_pool.quarantine {
+        if quarantine.contains(&addr.ip(), clock::now()) {
+            backend_pool.stats.connections.quarantine_refused += 1;
+            log_event!(LogLevel::Debug, "client_quarantine_refused", { pool: backend_pool.name, addr: addr }, "Refused client connection for pool {} from quarantined {}", backend_pool.name, addr);
+            return false;
+        }
+    }
+    return true;
+}
+
+/*
+    Counts an invalid request against the client. Returns true once it has sent quarantine_threshold of them, after
+    quarantining its address, in which case it should be closed.
+*/
+fn quarantine_client(backend_pool: &mut BackendPool, client: &mut Client, client_token: ClientToken) -> bool {
+    let quarantine = match backend_pool.quarantine {
+        Some(ref mut quarantine) => quarantine,
+        None => return false,
+    };
+    client.protocol_errors += 1;
+    if client.protocol_errors < quarantine.threshold {
+        return false;
+    }
+    backend_pool.stats.connections.quarantined_clients += 1;
+    match client.peer_addr {
+        Some(addr) if quarantine.add(&addr.ip(), clock::now()) => {
+            log_event!(LogLevel::Info, "client_quarantined", { pool: backend_pool.name, token: client_token.0, addr: addr }, "Closed client {:?} of pool {} after {} invalid requests, and quarantined {}", client_token, backend_pool.name, client.protocol_errors, addr);
+        }
+        _ => {
+            log_event!(LogLevel::Info, "client_quarantined", { pool: backend_pool.name, token: client_token.0 }, "Closed client {:?} of pool {} after {} invalid requests", client_token, backend_pool.name, client.protocol_errors);
+        }
+    }
+    return true;
+}
+
+/*
+    Reads the PROXY protocol header that starts each client connection of a pool with proxy_protocol, and takes the
+    client's address from it. Returns false if the client should be closed, and None once the header is read and the
+    client's requests can be.
+*/
+fn read_proxy_header(backend_pool: &mut BackendPool, client: &mut BufferedClient, client_token: ClientToken) -> Option<bool> {
+    match proxyprotocol::read_header(client) {
+        HeaderRead::Done(source) => {
+            client.inner.proxy_header_pending = false;
+            if source.is_some() {
+                client.inner.peer_addr = source;
+            }
+            if let Some(addr) = client.inner.peer_addr {
+                if !permits_client(backend_pool, &addr) {
+                    return Some(false);
+                }
+            }
+            if client.buffer().is_empty() {
+                return Some(true);
+            }
+            return None;
+        }
+        HeaderRead::Wait => return Some(true),
+        HeaderRead::Closed => return Some(false),
+        HeaderRead::Invalid(err) => {
+            log_event!(LogLevel::Debug, "client_protocol_error", { pool: backend_pool.name, token: client_token.0 }, "Invalid PROXY protocol header: {}", err);
+            backend_pool.stats.errors.protocol_errors += 1;
+            return Some(false);
         }
     }
 }
@@ -160,6 +594,11 @@ pub fn shard<'a>(
         let mut consistent_hash = conhash::ConsistentHash::new();
         let mut i = 0;
         for backend in backends.iter() {
+            // The canary only gets copies of requests. See write_canary.
+            if backend.canary {
+                i += 1;
+                continue;
+            }
             // 40 is pulled to match twemproxy's ketama.
             consistent_hash.add(&IndexNode{index: i}, backend.weight * 40);
             //consistent_hash.add(&TokenNode {token: i.clone()}, backend.weight);
@@ -188,7 +627,7 @@ pub fn shard<'a>(
         // Get total size:
         let mut total_weight = 0;
         for ref mut backend in backends.iter_mut() {
-            if !config.auto_eject_hosts || backend.is_available() {
+            if !backend.canary && (!config.auto_eject_hosts || backend.is_available()) {
                 total_weight += backend.weight;
             }
         }
@@ -199,7 +638,7 @@ pub fn shard<'a>(
         let mut index = 0;
         let mut backend_index = 0;
         for ref mut backend in backends.iter_mut() {
-            if !config.auto_eject_hosts || backend.is_available() {
+            if !backend.canary && (!config.auto_eject_hosts || backend.is_available()) {
                 for _i in index..index+backend.weight {
                     mapping.push(backend_index);
                 }
@@ -211,31 +650,31 @@ pub fn shard<'a>(
     }
 
 
-    let total_weight = match cached_backend_shards {
-        Some(mapping) => mapping.len(),
-        None => { panic!("No cached backend mapping"); }
+    let mapping = match cached_backend_shards {
+        Some(ref mapping) => mapping,
+        None => return Err(RedisError::NoBackend),
     };
+    // Every backend may have been ejected.
+    let total_weight = mapping.len();
+    if total_weight == 0 {
+        return Err(RedisError::NoBackend);
+    }
 
-    let shard: Result<usize, ProxyError> = match config.distribution {
-        Distribution::Modula => Ok(hash(&config.hash_function, &tag) % total_weight), // Should be using key, not command.
-        Distribution::Random => Ok(thread_rng().gen_range(0, total_weight - 1)),
-        _ => panic!("Impossible to hit this with ketama!"),
+    let shard_no = match config.distribution {
+        Distribution::Modula => hash(&config.hash_function, &tag) % total_weight, // Should be using key, not command.
+        Distribution::Random => thread_rng().gen_range(0, total_weight),
+        Distribution::Ketama => return Err(RedisError::NoBackend),
+    };
+    debug!("Sharding command tag to be {}", shard_no);
+    let backend_index = match mapping.get(shard_no) {
+        Some(backend_index) => *backend_index,
+        None => return Err(RedisError::NoBackend),
+    };
+    debug!("Now got index: {:?}", backend_index);
+    return match backends.get_mut(backend_index) {
+        Some(backend) => Ok(backend),
+        None => Err(RedisError::NoBackend),
     };
-    match shard {
-        Ok(shard_no) => {
-            debug!("Sharding command tag to be {}", shard_no);
-            {
-                let backend_index = match cached_backend_shards {
-                    Some(mapping) => mapping.get(shard_no).unwrap(),
-                    None => { panic!("No cached backend mapping when getting backend"); }
-                };
-                debug!("Now got index: {:?}", backend_index);
-                return Ok(backends.get_mut(*backend_index).unwrap());
-            }
-        }
-        Err(error) => debug!("Received {:?} while sharding!", error),
-    }
-    Err(RedisError::NoBackend)
 }
 
 #[cfg(test)]
@@ -257,7 +696,7 @@ fn get_tag<'a>(key: &'a [u8], tags: &String) -> &'a [u8] {
     let ref mut chars = tags.chars();
     let a = match chars.next() {
         Some(a) => a,
-        None => { panic!("Shouldn't be possible to hit, get_tag"); }
+        None => return key,
     };
     let b = match chars.next() {
         Some(b) => b,
@@ -274,7 +713,7 @@ fn get_tag<'a>(key: &'a [u8], tags: &String) -> &'a [u8] {
         else if parsing_tag && cha == b as u8 {
             return match key.get(beginning..index) {
                 Some(res) => res,
-                None => { panic!("Shouldn't be possible to hit, get_tag exceeded bounds"); }
+                None => key,
             };
         }
         index += 1;
@@ -309,6 +748,243 @@ fn mark_backend_down(
     //}
 }
 
+/*
+    Records the request to the audit log, if it is a sensitive command. Returns false if it couldn't be recorded, in
+    which case the request shouldn't be forwarded.
+*/
+fn audit_request(backend_pool: &BackendPool, client: &BufferedClient, request: &[u8]) -> bool {
+    let audit_log = match backend_pool.audit_log {
+        Some(ref audit_log) => audit_log,
+        None => return true,
+    };
+    if !audit_log.is_sensitive(request) {
+        return true;
+    }
+    match audit_log.record(&backend_pool.name, client.get_ref().peer_addr, request) {
+        Ok(_) => true,
+        Err(err) => {
+            error!("Failed to write to the audit log: {}", err);
+            false
+        }
+    }
+}
+
+/*
+    Writes a mutation that was just written to its backend to the backend's secondary too, in a dual_write pool. The
+    client compares the secondary's response with the primary's once both arrive.
+*/
+fn write_secondary(
+    backend: &mut Backend,
+    dual_writes: &mut Option<Mirrors>,
+    client_token: ClientToken,
+    request: &[u8],
+    request_id: (std::time::Instant, usize),
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    stats: &mut Stats,
+) {
+    let dual_writes = match *dual_writes {
+        Some(ref mut dual_writes) => dual_writes,
+        None => return,
+    };
+    if is_read_only(command_name(request)) {
+        return;
+    }
+    match backend.write_secondary(request, client_token, cluster_backends, request_id, stats) {
+        Ok(_) => dual_writes.start(request_id),
+        Err(err) => {
+            debug!("Skipped the dual write of a request from {:?}. Received error: {}", client_token, err);
+            dual_writes.skip();
+        }
+    }
+}
+
+/*
+    Sends a copy of a request that was just written to its backend to the pool's canary, which was sampled for it. The
+    client compares the canary's response with the real one once both arrive.
+*/
+fn write_canary(
+    backend_pool: &BackendPool,
+    backends: &mut [Backend],
+    canary_mirrors: &mut Option<Mirrors>,
+    client_token: ClientToken,
+    request: &[u8],
+    request_id: (std::time::Instant, usize),
+    cluster_backends: &mut Vec<(SingleBackend, usize)>,
+    stats: &mut Stats,
+) {
+    let canary_mirrors = match *canary_mirrors {
+        Some(ref mut canary_mirrors) => canary_mirrors,
+        None => return,
+    };
+    let canary = match backend_pool.canary_index.and_then(|index| backends.get_mut(index)) {
+        Some(canary) => canary,
+        None => return,
+    };
+    match canary.write_message(request, client_token, cluster_backends, request_id, None, None, stats) {
+        Ok(_) => canary_mirrors.start(request_id),
+        Err(err) => {
+            debug!("Skipped the canary copy of a request from {:?}. Received error: {}", client_token, err);
+            canary_mirrors.skip();
+        }
+    }
+}
+
+// The pool's read cache, if GETs of the key are cached.
+fn key_cache<'a>(backend_pool: &'a BackendPool, key: &[u8]) -> Option<&'a SharedCache> {
+    let cache = match backend_pool.cache {
+        Some(ref cache) => cache,
+        None => return None,
+    };
+    if cache.borrow().hot_keys_only {
+        let hot = match backend_pool.hotkeys {
+            Some(ref hotkeys) => hotkeys.is_hot(key),
+            None => false,
+        };
+        if !hot {
+            return None;
+        }
+    }
+    return Some(cache);
+}
+
+// Looks the key up in the pool's read cache, if GETs of the key are cached.
+fn cached_response(backend_pool: &BackendPool, key: &[u8], now: std::time::Instant) -> Option<Vec<u8>> {
+    return match key_cache(backend_pool, key) {
+        Some(cache) => cache.borrow_mut().get(key, now).map(|response| response.to_vec()),
+        None => None,
+    };
+}
+
+/*
+    Decides whether a client's request is read now, from the quotas of its user and its own limits. A user is only
+    charged for the requests that are admitted.
+*/
+fn admit_request(backend_pool: &BackendPool, client: &mut Client, request_len: usize, now: std::time::Instant) -> Admission {
+    if let Some(ref identity) = client.identity {
+        let mut quota = identity.quota.borrow_mut();
+        match quota.exceeded(now) {
+            Some(wake_at) => {
+                quota.stats.exceeded += 1;
+                match backend_pool.config.quota_enforcement {
+                    QuotaEnforcement::Log => {
+                        if !quota.over {
+                            log_event!(LogLevel::Warn, "quota_exceeded", { pool: backend_pool.name, user: identity.user }, "User {} of pool {} is over its quota", identity.user, backend_pool.name);
+                        }
+                    }
+                    QuotaEnforcement::Throttle => return Admission::Wait(Some(wake_at)),
+                    QuotaEnforcement::Reject => return Admission::Reject(QUOTA_EXCEEDED),
+                }
+                quota.over = true;
+            }
+            None => quota.over = false,
+        }
+    }
+    let admission = match client.limits {
+        Some(ref mut limits) => limits.admit(now),
+        None => Admission::Admit,
+    };
+    if admission == Admission::Admit {
+        if let Some(ref identity) = client.identity {
+            identity.quota.borrow_mut().charge_request(request_len);
+        }
+    }
+    return admission;
+}
+
+fn is_auth(request: &[u8]) -> bool {
+    return match split_args(request).first() {
+        Some(command) => command.eq_ignore_ascii_case(b"AUTH"),
+        None => false,
+    };
+}
+
+// Answers a client's AUTH, as one of the pool's users. A failed AUTH leaves the client as it was.
+fn authenticate(backend_pool: &BackendPool, client: &mut Client, request: &[u8]) -> Vec<u8> {
+    let users = match backend_pool.users {
+        Some(ref users) => users,
+        None => return b"-ERR Client sent AUTH, but no password is set\r\n".to_vec(),
+    };
+    let args = split_args(request);
+    match users.authenticate(&args[1..]) {
+        Ok(identity) => {
+            log_event!(LogLevel::Debug, "client_authenticated", { pool: backend_pool.name, user: identity.user }, "Client authenticated as {}", identity.user);
+            client.identity = Some(identity);
+            return b"+OK\r\n".to_vec();
+        }
+        Err(response) => return response.to_vec(),
+    }
+}
+
+fn is_deadline(request: &[u8]) -> bool {
+    return match split_args(request).first() {
+        Some(command) => command.eq_ignore_ascii_case(b"DEADLINE"),
+        None => false,
+    };
+}
+
+// Answers a client's DEADLINE, which sets how many milliseconds its next request may take, with client_deadlines.
+fn set_deadline(client: &mut Client, request: &[u8]) -> Vec<u8> {
+    let args = split_args(request);
+    let timeout = match args.get(1).and_then(|arg| std::str::from_utf8(arg).ok()).and_then(|arg| arg.parse::<usize>().ok()) {
+        Some(timeout) if args.len() == 2 && timeout > 0 => timeout,
+        _ => return b"-ERR DEADLINE must be a positive number of milliseconds\r\n".to_vec(),
+    };
+    client.request_timeout = Some(timeout);
+    return b"+OK\r\n".to_vec();
+}
+
+// The NOPERM error for a request that the client's user may not send, if any. Requests that may be sent are left as they are.
+fn permission_error(backend_pool: &BackendPool, client: &Client, command: &str, request: &[u8], key_pos: &Result<KeyPos, RedisError>) -> Option<&'static [u8]> {
+    let identity = match client.identity {
+        Some(ref identity) => identity,
+        None => return None,
+    };
+    let permissions = &identity.permissions;
+    // Keys are matched as the user sent them, before they were moved into its namespace.
+    let allows_key = |key: &[u8]| match identity.key_prefix {
+        Some(ref key_prefix) if key.starts_with(key_prefix) => permissions.allows_key(&key[key_prefix.len()..]),
+        _ => permissions.allows_key(key),
+    };
+    let isolation_error = match identity.key_prefix {
+        Some(_) => namespace::isolation_error(command, request),
+        None => None,
+    };
+    let error = if !permissions.allows_command(command) {
+        NOPERM_COMMAND
+    } else if let Some(error) = isolation_error {
+        error
+    } else {
+        let allowed = match *key_pos {
+            Ok(KeyPos::Single(key)) => allows_key(key),
+            Ok(KeyPos::Multi(ref keys)) => keys.iter().all(|key| allows_key(*key)),
+            Ok(KeyPos::MultiSet(ref pairs)) => pairs.iter().all(|&(key, _)| allows_key(key)),
+            Err(_) => true,
+        };
+        if allowed {
+            return None;
+        }
+        NOPERM_KEY
+    };
+    identity.quota.borrow_mut().stats.denied += 1;
+    log_event!(LogLevel::Debug, "permission_denied", { pool: backend_pool.name, user: identity.user, command: command }, "User {} of pool {} was denied {}", identity.user, backend_pool.name, command);
+    return Some(error);
+}
+
+/*
+    Whether the keys of a request are within the pool's key_rate_limits. A multi key request is rejected as a whole if
+    any of its keys is over, though the keys before it have used up their tokens.
+*/
+fn within_key_rate_limits(backend_pool: &mut BackendPool, command: &str, keys: &[&[u8]], now: std::time::Instant) -> bool {
+    let allowed = match backend_pool.key_limits {
+        Some(ref mut key_limits) => keys.iter().all(|key| key_limits.allow(command, key, now)),
+        None => true,
+    };
+    if !allowed {
+        backend_pool.stats.errors.rate_limited += 1;
+    }
+    return allowed;
+}
+
 pub fn handle_client_readable(
     backend_pool: &mut BackendPool,
     client: &mut BufferedClient,
@@ -319,12 +995,29 @@ pub fn handle_client_readable(
     stats: &mut Stats,
 ) -> bool {
     debug!("Handling client: {:?}", &client_token);
+    if client.inner.proxy_header_pending {
+        if let Some(keep) = read_proxy_header(backend_pool, client, client_token) {
+            return keep;
+        }
+    }
+    // Set once a backend this client sent to is full. The client's remaining requests are left unread until resumed.
+    let mut backend_full = false;
+    // Requests read so far, to hand over to the other clients after client_burst of them.
+    let mut handled = 0;
 
     // 1. Pull command from client.
     let buf_len = loop {
         let mut id = 0;
-        let instant = std::time::Instant::now();
-        let (buf_len, err_resp, more_buf) = {
+        let instant = clock::now();
+        // Response from a filter or the read cache, sent instead of routing the request.
+        let mut local_reply: Option<Vec<u8>> = None;
+        // Set when the client is over its limits, which leaves the request unread until it's within them.
+        let mut waiting = false;
+        // Set when the request is over the pool's size limits. The rest of it can't be told apart from the next request.
+        let mut too_large = false;
+        // Set when the request is invalid, which counts toward the client's quarantine_threshold.
+        let mut invalid = false;
+        let (buf_len, err_resp, more_buf, incomplete) = {
             let buf = if client.fill_buf().is_ok() {
                     &client.buf[client.pos..client.cap]
                 }
@@ -333,78 +1026,347 @@ pub fn handle_client_readable(
             };
             if buf.len() == 0 {
                 // mark client as closed.
-                (0, None, false) // Nothing. Mark it as closed. Mark as nothing?
+                (0, None, false, false) // Nothing. Mark it as closed. Mark as nothing?
             }
             else {
                 debug!("Read from client:\n{:?}", std::str::from_utf8(buf));
                 let mut err_resp: Option<&[u8]> = None;
-                let (client_request, consumed_len): (&[u8], usize) = match extract_redis_command(buf) {
+                let mut incomplete = false;
+                let limits = Limits::new(backend_pool.config.max_request_size, backend_pool.config.max_multibulk_length, backend_pool.config.max_bulk_length);
+                let (client_request, consumed_len): (&[u8], usize) = match extract_redis_command_within(buf, limits) {
                     Ok(r) => (r, r.len()),
+                    // Only part of the request has been read so far. A request larger than the whole buffer is invalid.
+                    Err(RedisError::IncompleteMessage) if client.pos > 0 || client.cap < client.buf.len() => {
+                        incomplete = true;
+                        (b"", 0)
+                    }
+                    Err(RedisError::TooLarge(limit)) => {
+                        log_event!(LogLevel::Debug, "client_request_too_large", { pool: backend_pool.name, token: client_token.0 }, "Request over the pool's {:?} limit", limit);
+                        backend_pool.stats.errors.protocol_errors += 1;
+                        err_resp = Some(match limit {
+                            Limit::FrameLen => b"-ERROR: Request larger than max_request_size\r\n",
+                            Limit::ArrayLen => b"-ERROR: Request has more arguments than max_multibulk_length\r\n",
+                            Limit::BulkLen => b"-ERROR: Argument larger than max_bulk_length\r\n",
+                        });
+                        too_large = true;
+                        invalid = true;
+                        (b"", buf.len())
+                    }
                     Err(err) => {
-                        debug!("Invalid redis protocol: {:?}", err);
+                        log_event!(LogLevel::Debug, "client_protocol_error", { pool: backend_pool.name, token: client_token.0 }, "Invalid redis protocol: {:?}", err);
+                        backend_pool.stats.errors.protocol_errors += 1;
                         err_resp = Some(b"-ERROR: Invalid redis protocol\r\n");
+                        invalid = true;
                         (b"", buf.len())
                     }
                 };
                 debug!("Extracted from client:\n{:?}", std::str::from_utf8(&client_request));
-                if client_request.len() > 0 {
-                    stats.requests += 1;
+                let admission = if client_request.len() > 0 {
+                    admit_request(backend_pool, &mut client.inner, client_request.len(), instant)
+                } else {
+                    Admission::Admit
+                };
+                if let Admission::Wait(wake_at) = admission {
+                    if let (Some(wake_at), Some(ref timers)) = (wake_at, backend_pool.timers.as_ref()) {
+                        timers.borrow_mut().insert(wake_at, TimerEvent::ReadClient(client_token));
+                    }
+                    waiting = true;
+                } else if client_request.len() > 0 {
+                    if let Some(ref mut limits) = client.inner.limits {
+                        limits.on_request();
+                    }
+                    if let Some(ref capture) = client.inner.capture {
+                        capture.borrow_mut().record(Direction::Request, client_token.0, client_request);
+                    }
+                    // Set when a filter rewrote the request, which is routed instead of the one read.
+                    let rewritten;
+                    let client_request: &[u8] = match client.inner.filters {
+                        Some(ref filters) => match filters.borrow_mut().on_request(client_token.0, client_request) {
+                            FilterAction::Continue => client_request,
+                            FilterAction::Rewrite(request) => {
+                                rewritten = request;
+                                &rewritten
+                            }
+                            FilterAction::Reply(response) => {
+                                local_reply = Some(response);
+                                client_request
+                            }
+                        },
+                        None => client_request,
+                    };
+                    // Set when the client's user has a namespace, which the request's keys are moved into.
+                    let namespaced;
+                    let client_request: &[u8] = match client.inner.identity.as_ref().and_then(|identity| identity.key_prefix.clone()) {
+                        Some(ref key_prefix) if local_reply.is_none() => match namespace::prefix_keys(client_request, key_prefix) {
+                            Some(request) => {
+                                namespaced = request;
+                                &namespaced
+                            }
+                            None => client_request,
+                        },
+                        _ => client_request,
+                    };
+                    stats.shard().requests += 1;
+                    backend_pool.stats.requests += 1;
+                    let command = command_name(&client_request);
+                    command_stats(&mut backend_pool.stats.commands, command).requests += 1;
+                    // Set when the client's user may not send the request.
+                    let mut denied = None;
+                    // The timeout the client set for this request with DEADLINE, if any.
+                    let request_timeout = client.inner.request_timeout.take();
                     match extract_key(&client_request) {
+                        _ if admission != Admission::Admit => {
+                            backend_pool.stats.errors.rate_limited += 1;
+                            local_reply = None;
+                            if let Admission::Reject(response) = admission {
+                                err_resp = Some(response);
+                            }
+                        }
+                        _ if maintenance_response(backend_pool, MaintenanceMode::Deny).is_some() => {
+                            local_reply = maintenance_response(backend_pool, MaintenanceMode::Deny).map(|response| response.to_vec());
+                        }
+                        _ if backend_pool.users.is_some() && is_auth(client_request) => {
+                            local_reply = Some(authenticate(backend_pool, &mut client.inner, client_request));
+                        }
+                        _ if backend_pool.users.is_some() && client.inner.identity.is_none() => {
+                            local_reply = None;
+                            err_resp = Some(b"-NOAUTH Authentication required.\r\n");
+                        }
+                        _ if backend_pool.config.client_deadlines && is_deadline(client_request) => {
+                            local_reply = Some(set_deadline(&mut client.inner, client_request));
+                        }
+                        ref key_pos if {
+                            denied = permission_error(backend_pool, &client.inner, command, client_request, key_pos);
+                            denied.is_some()
+                        } => {
+                            local_reply = None;
+                            err_resp = denied;
+                        }
+                        _ if local_reply.is_some() => {}
+                        _ if memory::is_exhausted() => {
+                            backend_pool.stats.errors.memory_shed += 1;
+                            err_resp = Some(b"-ERROR: Proxy memory limit reached\r\n");
+                        }
+                        // Sensitive commands are only forwarded once they are recorded.
+                        _ if !audit_request(backend_pool, client, client_request) => {
+                            err_resp = Some(b"-ERROR: Audit log unavailable\r\n");
+                        }
+                        // FUNCTION has no key, and is sent to every backend, so that FCALL finds its functions on any of them.
+                        Err(RedisError::UnsupportedCommand) if command == "FUNCTION" => {
+                            match functions::broadcast(client_request) {
+                                Some(broadcast) => match broadcast_request(
+                                    backend_pool,
+                                    backends,
+                                    &mut client.inner,
+                                    client_token,
+                                    client_request,
+                                    broadcast,
+                                    instant,
+                                    request_timeout,
+                                    cluster_backends,
+                                    completed_clients,
+                                    stats
+                                ) {
+                                    Ok(full) => backend_full |= full,
+                                    Err(()) => return false,
+                                },
+                                None => err_resp = Some(b"-ERROR: Unsupported command\r\n"),
+                            }
+                        }
+                        Ok(KeyPos::Single(key)) if !within_key_rate_limits(backend_pool, command, &[key], instant) => {
+                            err_resp = Some(RATE_LIMITED);
+                        }
+                        Ok(KeyPos::Single(key)) if command == "GET" && {
+                            local_reply = cached_response(backend_pool, key, instant);
+                            local_reply.is_some()
+                        } => {
+                            if let Some(ref mut hotkeys) = backend_pool.hotkeys {
+                                hotkeys.record(key);
+                            }
+                        }
+                        // Answered along with the GET it joined.
+                        Ok(KeyPos::Single(key)) if command == "GET" && collapses_get(backend_pool, &mut client.inner, client_token, key, (instant, id), request_timeout) => {
+                            backend_pool.stats.collapsed_requests += 1;
+                            if let Some(ref mut hotkeys) = backend_pool.hotkeys {
+                                hotkeys.record(key);
+                            }
+                        }
                         Ok(KeyPos::Single(key)) => {
-                            let backend = shard(
+                            if let Some(ref mut hotkeys) = backend_pool.hotkeys {
+                                hotkeys.record(key);
+                            }
+                            if command != "GET" {
+                                if let Some(ref cache) = backend_pool.cache {
+                                    cache.borrow_mut().invalidate(key);
+                                }
+                            } else if let Some(cache) = key_cache(backend_pool, key) {
+                                let generation = cache.borrow().generation();
+                                client.inner.cache_fills.insert((instant, id), (key.to_vec(), generation));
+                            }
+                            let mut trace = if backend_pool.trace_sampler.sample() {
+                                Some(RequestTrace::new(&backend_pool.name, command, instant))
+                            } else {
+                                None
+                            };
+                            let sharded = shard(
                                 &mut backend_pool.cached_backend_shards.borrow_mut(),
                                 &mut backend_pool.config,
                                 backends,
                                 key
-                            ).unwrap();
-                            match backend.write_message(
-                                &client_request,
-                                client_token,
-                                cluster_backends,
-                                (instant, id),
-                                stats
-                            ) {
-                                Ok(_) => {}
+                            );
+                            let mut mirror_to_canary = false;
+                            match sharded {
+                                Ok(backend) => {
+                                    if let Some(ref mut trace) = trace {
+                                        trace.shard_selected = Some(clock::now());
+                                    }
+                                    let to_replica = reads_from_replica(&backend_pool.config, &client.inner, key, instant);
+                                    let hedged = !to_replica && backend_pool.config.hedge_delay > 0 && backend.can_hedge(&client_request);
+                                    let written = if to_replica {
+                                        backend.write_to_replica(&client_request, client_token, cluster_backends, (instant, id), trace, request_timeout, stats)
+                                    } else {
+                                        backend.write_message(&client_request, client_token, cluster_backends, (instant, id), trace, request_timeout, stats)
+                                    };
+                                    match written {
+                                        Ok(full) => {
+                                            backend_full |= full;
+                                            client.inner.await_response((instant, id));
+                                            if !is_read_only(command) {
+                                                record_write(&mut client.inner, key, instant);
+                                            } else if command == "GET" && backend_pool.config.collapse_gets && !to_replica && request_timeout.is_none() {
+                                                collapse::lead(backend_pool.token.0, key, client_token, (instant, id));
+                                            }
+                                            write_secondary(backend, &mut client.inner.dual_writes, client_token, &client_request, (instant, id), cluster_backends, stats);
+                                            mirror_to_canary = backend_pool.canary_index.is_some() && backend_pool.canary_sampler.sample();
+                                            if hedged {
+                                                client.inner.hedges.insert((instant, id), Hedge::Scheduled(client_request.to_vec()));
+                                                backend.schedule_hedge(client_token, (instant, id), std::time::Duration::from_millis(backend_pool.config.hedge_delay as u64));
+                                            }
+                                        }
+                                        Err(err) => {
+                                            log_event!(LogLevel::Debug, "backend_write_failed", { pool: backend_pool.name, token: client_token.0 }, "Backend could not be written to. Received error: {}", err);
+                                            match err {
+                                                // Counted by the backend host, which knows its limit.
+                                                WriteError::Overloaded => err_resp = Some(b"-ERROR: Backend overloaded\r\n"),
+                                                _ => {
+                                                    backend_pool.stats.errors.backend_unavailable += 1;
+                                                    local_reply = stale_response(backend_pool, &mut client.inner, command, key, (instant, id));
+                                                    if local_reply.is_none() {
+                                                        err_resp = Some(b"-ERROR: Not connected\r\n");
+                                                    }
+                                                }
+                                            };
+                                        }
+                                    };
+                                }
                                 Err(err) => {
-                                    debug!("Backend could not be written to. Received error: {}", err);
-                                    err_resp = Some(b"-ERROR: Not connected\r\n");
+                                    log_event!(LogLevel::Debug, "shard_failed", { pool: backend_pool.name, token: client_token.0 }, "No backend available for the request. Received error: {}", err);
+                                    backend_pool.stats.errors.backend_unavailable += 1;
+                                    local_reply = stale_response(backend_pool, &mut client.inner, command, key, (instant, id));
+                                    if local_reply.is_none() {
+                                        err_resp = Some(b"-ERROR: No backend\r\n");
+                                    }
                                 }
                             };
+                            if mirror_to_canary {
+                                write_canary(backend_pool, backends, &mut client.inner.canary, client_token, &client_request, (instant, id), cluster_backends, stats);
+                            }
                         }
                         Ok(KeyPos::Multi(vec)) => {
                             if !backend_pool.enable_advanced_commands {
                                 err_resp = Some(b"-ProxyError: Advanced commands are currently disabled. They can be enabled by setting 'enable_advanced_commands' to true in the proxy config\r\n");
+                            } else if !within_key_rate_limits(backend_pool, command, &vec, instant) {
+                                err_resp = Some(RATE_LIMITED);
                             } else {
                                 client.inner.pending_response = Vec::new();
                                 client.inner.pending_count = vec.len();
+                                // Reused for each key's GET.
+                                let mut split_msg: Vec<u8> = Vec::new();
                                 for key in vec.iter() {
                                     id += 1;
+                                    if let Some(ref mut hotkeys) = backend_pool.hotkeys {
+                                        hotkeys.record(key);
+                                    }
                                     client.inner.pending_response.push(Vec::new());
+                                    if let Some(response) = cached_response(backend_pool, key, instant) {
+                                        if write_to_client(
+                                            &mut client.inner,
+                                            &client_token.0,
+                                            &response,
+                                            (instant, id),
+                                            completed_clients,
+                                            stats
+                                        ).is_err() {
+                                            return false;
+                                        };
+                                        continue;
+                                    }
+                                    if let Some(cache) = key_cache(backend_pool, key) {
+                                        let generation = cache.borrow().generation();
+                                        client.inner.cache_fills.insert((instant, id), (key.to_vec(), generation));
+                                    }
 
-                                    let backend = shard(
+                                    let sharded = shard(
                                         &mut backend_pool.cached_backend_shards.borrow_mut(),
                                         &mut backend_pool.config,
                                         backends,
                                         key
-                                    ).unwrap();
-                                    let mut split_msg : Vec<u8> = Vec::with_capacity(25 + key.len());
+                                    );
+                                    let backend = match sharded {
+                                        Ok(backend) => backend,
+                                        Err(err) => {
+                                            log_event!(LogLevel::Debug, "shard_failed", { pool: backend_pool.name, token: client_token.0 }, "No backend available when splitting. Received error: {}", err);
+                                            backend_pool.stats.errors.backend_unavailable += 1;
+                                            let stale = stale_response(backend_pool, &mut client.inner, "GET", key, (instant, id));
+                                            let resp: &[u8] = match stale {
+                                                Some(ref response) => response,
+                                                None => b"-ERROR: No backend\r\n",
+                                            };
+                                            if write_to_client(
+                                                &mut client.inner,
+                                                &client_token.0,
+                                                resp,
+                                                (instant, id),
+                                                completed_clients,
+                                                stats
+                                            ).is_err() {
+                                                return false;
+                                            };
+                                            continue;
+                                        }
+                                    };
+                                    split_msg.clear();
                                     split_msg.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$");
                                     split_msg.extend_from_slice(&key.len().to_string().as_bytes());
                                     split_msg.extend_from_slice(b"\r\n");
                                     split_msg.extend_from_slice(key);
                                     split_msg.extend_from_slice(b"\r\n");
 
-                                    match backend.write_message(
-                                        &split_msg,
-                                        client_token,
-                                        cluster_backends,
-                                        (instant, id),
-                                        stats
-                                    ) {
-                                        Ok(_) => {}
+                                    let written = if reads_from_replica(&backend_pool.config, &client.inner, key, instant) {
+                                        backend.write_to_replica(&split_msg, client_token, cluster_backends, (instant, id), None, request_timeout, stats)
+                                    } else {
+                                        backend.write_message(&split_msg, client_token, cluster_backends, (instant, id), None, request_timeout, stats)
+                                    };
+                                    match written {
+                                        Ok(full) => {
+                                            backend_full |= full;
+                                            client.inner.await_response((instant, id));
+                                        }
                                         Err(err) => {
-                                            debug!("Backend could not be written to when splitting. Received error: {}", err);
-                                            let resp = b"-ERROR: Not connected\r\n";
+                                            log_event!(LogLevel::Debug, "backend_write_failed", { pool: backend_pool.name, token: client_token.0 }, "Backend could not be written to when splitting. Received error: {}", err);
+                                            // Set when the key's backend is down, but its stale response may be served.
+                                            let stale;
+                                            let resp: &[u8] = match err {
+                                                WriteError::Overloaded => b"-ERROR: Backend overloaded\r\n",
+                                                _ => {
+                                                    backend_pool.stats.errors.backend_unavailable += 1;
+                                                    stale = stale_response(backend_pool, &mut client.inner, "GET", key, (instant, id));
+                                                    match stale {
+                                                        Some(ref response) => response,
+                                                        None => b"-ERROR: Not connected\r\n",
+                                                    }
+                                                }
+                                            };
                                             if write_to_client(
                                                 &mut client.inner,
                                                 &client_token.0,
@@ -421,22 +1383,51 @@ pub fn handle_client_readable(
                             }
                         }
                         Ok(KeyPos::MultiSet(vec)) => {
+                            let keys: Vec<&[u8]> = vec.iter().map(|&(key, _)| key).collect();
                             if !backend_pool.enable_advanced_commands {
                                 err_resp = Some(b"-ProxyError: Advanced commands are currently disabled. They can be enabled by setting 'enable_advanced_commands' to true in the proxy config\r\n");
+                            } else if !within_key_rate_limits(backend_pool, command, &keys, instant) {
+                                err_resp = Some(RATE_LIMITED);
                             } else {
                                 client.inner.pending_response = Vec::new();
                                 client.inner.pending_count = vec.len();
+                                // Reused for each key's SET.
+                                let mut split_msg: Vec<u8> = Vec::new();
                                 for (key, args) in vec.iter() {
                                     id += 1;
+                                    if let Some(ref mut hotkeys) = backend_pool.hotkeys {
+                                        hotkeys.record(key);
+                                    }
+                                    if let Some(ref cache) = backend_pool.cache {
+                                        cache.borrow_mut().invalidate(key);
+                                    }
                                     client.inner.pending_response.push(Vec::new());
 
-                                    let backend = shard(
+                                    let sharded = shard(
                                         &mut backend_pool.cached_backend_shards.borrow_mut(),
                                         &mut backend_pool.config,
                                         backends,
                                         key
-                                    ).unwrap();
-                                    let mut split_msg : Vec<u8> = Vec::with_capacity(35 + key.len() + args.len());
+                                    );
+                                    let backend = match sharded {
+                                        Ok(backend) => backend,
+                                        Err(err) => {
+                                            log_event!(LogLevel::Debug, "shard_failed", { pool: backend_pool.name, token: client_token.0 }, "No backend available when splitting. Received error: {}", err);
+                                            backend_pool.stats.errors.backend_unavailable += 1;
+                                            if write_to_client(
+                                                &mut client.inner,
+                                                &client_token.0,
+                                                b"-ERROR: No backend\r\n",
+                                                (instant, id),
+                                                completed_clients,
+                                                stats
+                                            ).is_err() {
+                                                return false;
+                                            };
+                                            continue;
+                                        }
+                                    };
+                                    split_msg.clear();
                                     split_msg.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$");
                                     split_msg.extend_from_slice(&key.len().to_string().as_bytes());
                                     split_msg.extend_from_slice(b"\r\n");
@@ -452,16 +1443,29 @@ pub fn handle_client_readable(
                                         client_token,
                                         cluster_backends,
                                         (instant, id),
+                                        None,
+                                        request_timeout,
                                         stats
                                     ) {
-                                        Ok(_) => {}
+                                        Ok(full) => {
+                                            backend_full |= full;
+                                            client.inner.await_response((instant, id));
+                                            record_write(&mut client.inner, key, instant);
+                                            write_secondary(backend, &mut client.inner.dual_writes, client_token, &split_msg, (instant, id), cluster_backends, stats);
+                                        }
                                         Err(err) => {
-                                            debug!("Backend could not be written to when splitting. Received error: {}", err);
-                                            let resp = b"-ERROR: Not connected\r\n".to_vec();
+                                            log_event!(LogLevel::Debug, "backend_write_failed", { pool: backend_pool.name, token: client_token.0 }, "Backend could not be written to when splitting. Received error: {}", err);
+                                            let resp: &[u8] = match err {
+                                                WriteError::Overloaded => b"-ERROR: Backend overloaded\r\n",
+                                                _ => {
+                                                    backend_pool.stats.errors.backend_unavailable += 1;
+                                                    b"-ERROR: Not connected\r\n"
+                                                }
+                                            };
                                             if write_to_client(
                                                 &mut client.inner,
                                                 &client_token.0,
-                                                &resp,
+                                                resp,
                                                 (instant, id),
                                                 completed_clients,
                                                 stats
@@ -476,6 +1480,12 @@ pub fn handle_client_readable(
                         Err(RedisError::NoBackend) => {
                             err_resp = Some(b"-ERROR: No backend\r\n");
                         }
+                        Err(RedisError::InvalidProtocol) => {
+                            log_event!(LogLevel::Debug, "client_protocol_error", { pool: backend_pool.name, token: client_token.0 }, "Invalid redis request: {:?}", std::str::from_utf8(client_request));
+                            backend_pool.stats.errors.protocol_errors += 1;
+                            err_resp = Some(b"-ERROR: Invalid redis protocol\r\n");
+                            invalid = true;
+                        }
                         Err(RedisError::UnsupportedCommand) => {
                             err_resp = Some(b"-ERROR: Unsupported command\r\n");
                         }
@@ -491,18 +1501,45 @@ pub fn handle_client_readable(
                         Err(RedisError::WrongArgsMset) => {
                             err_resp = Some(b"-wrong number of arguments for MSET\r\n");
                         }
+                        Err(RedisError::InvalidStreams) => {
+                            err_resp = Some(b"-ERROR: XREAD and XREADGROUP must read 1 stream\r\n");
+                        }
+                        Err(RedisError::BlockingCommand) => {
+                            err_resp = Some(b"-ERROR: Blocking commands are not supported\r\n");
+                        }
                         Err(_reason) => {
                             debug!("Failed to shard: reason: {:?}", _reason);
                             err_resp = Some(b"-ERROR: Unknown proxy error\r\n");
                         }
                     };
+                    if err_resp.is_some() {
+                        command_stats(&mut backend_pool.stats.commands, command).errors += 1;
+                    }
                 }
-                let more_buf = buf.len() > client_request.len() && client.inner.pending_count == 0;
-                (consumed_len, err_resp, more_buf)
+                let more_buf = !incomplete && buf.len() > client_request.len() && client.inner.pending_count == 0;
+                (consumed_len, err_resp, more_buf, incomplete)
             }
         };
+        if waiting {
+            debug!("Client {:?} is over its limits. Reading the rest later", client_token);
+            break client.cap - client.pos;
+        }
+        if incomplete {
+            // Move the partial request to the front of the buffer, and read the rest of it after it.
+            let buffered = client.reset_buf().len();
+            match client.append_buf() {
+                Ok(buf) if buf.len() > buffered => continue,
+                // The client hung up partway through a request.
+                Ok(_) => break 0,
+                // The next readable event picks up where this left off.
+                Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => break buffered,
+                Err(_) => break 0,
+            }
+        }
         client.consume(buf_len);
-        stats.recv_client_bytes += buf_len;
+        handled += 1;
+        stats.shard().recv_client_bytes += buf_len;
+        backend_pool.stats.recv_client_bytes += buf_len;
 
 
         match err_resp {
@@ -524,8 +1561,32 @@ pub fn handle_client_readable(
                 };
             }
         }
+        if invalid && quarantine_client(backend_pool, client.get_mut(), client_token) {
+            return false;
+        }
+        if too_large {
+            return false;
+        }
+        if let Some(resp) = local_reply {
+            if write_to_client(
+                client.get_mut(),
+                &client_token.0,
+                &resp,
+                (instant, id),
+                completed_clients,
+                stats
+            ).is_err() {
+                return false;
+            };
+        }
         debug!("All done handling client! {:?}", buf_len);
-        if more_buf {
+        if more_buf && !backend_full {
+            if backend_pool.config.client_burst > 0 && handled >= backend_pool.config.client_burst {
+                // Queued behind the clients already waiting, which takes it round-robin with them.
+                debug!("Client {:?} used up its burst. Reading the rest later", client_token);
+                completed_clients.push_back(client_token.0);
+                break buf_len;
+            }
             continue;
         } else {
             break buf_len;
@@ -535,5 +1596,17 @@ pub fn handle_client_readable(
     if buf_len == 0 {
         return false;
     }
+    client.get_mut().mark_active();
+    // A read that filled the whole buffer may have left more of the pipeline in the socket, which no new
+    // edge-triggered event will announce.
+    if !backend_full && client.pos == client.cap && client.cap == client.buf.len() {
+        completed_clients.push_back(client_token.0);
+    }
+    if backend_full {
+        if let Err(err) = client.get_mut().pause() {
+            error!("Failed to pause client {:?}: {:?}", client_token, err);
+            return false;
+        }
+    }
     return true;
 }
\ No newline at end of file
@@ -0,0 +1,312 @@
+use rand;
+use stats::{duration_micros, LatencyHistogram};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Keys are picked at random from bench:0 to bench:9999.
+const KEYSPACE: usize = 10000;
+const IO_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchConfig {
+    pub requests: usize,
+    // Connections to the pool, each with one request in flight at a time.
+    pub clients: usize,
+    // The rest of the requests are GETs.
+    pub set_percent: usize,
+    pub value_size: usize,
+}
+
+impl BenchConfig {
+    // Parses the optional arguments of BENCH START: [requests] [clients] [set_percent] [value_size].
+    pub fn parse(args: &[&str]) -> Result<BenchConfig, String> {
+        let names = ["requests", "clients", "set_percent", "value_size"];
+        let mut values = [100000, 50, 10, 64];
+        for (index, arg) in args.iter().enumerate().take(values.len()) {
+            values[index] = match arg.parse::<usize>() {
+                Ok(value) => value,
+                Err(_) => return Err(format!("Invalid {}: {}", names[index], arg)),
+            };
+        }
+        let mut config = BenchConfig {
+            requests: values[0],
+            clients: values[1],
+            set_percent: values[2],
+            value_size: values[3],
+        };
+        if config.requests == 0 || config.clients == 0 {
+            return Err("requests and clients must be at least 1".to_owned());
+        }
+        if config.set_percent > 100 {
+            return Err(format!("Invalid set_percent: {}", config.set_percent));
+        }
+        config.clients = std::cmp::min(config.clients, config.requests);
+        return Ok(config);
+    }
+}
+
+// What the client threads share with the admin port.
+struct Progress {
+    completed: AtomicUsize,
+    errors: AtomicUsize,
+    stopped: AtomicBool,
+    // The first connection failure, which ends that client's share of the requests.
+    failure: Mutex<Option<String>>,
+    // Set once every client is done.
+    finished: Mutex<Option<(LatencyHistogram, Duration)>>,
+}
+
+/*
+    A load generator for BENCH, which sends GET and SET traffic to a pool's port from threads of the proxy process,
+    and reports the throughput and latency percentiles seen by the clients. It's a quick capacity check of the proxy
+    and its backends together, without installing a benchmark tool next to it. The traffic goes through the event loop
+    like any other client's, so it's counted in the pool's stats.
+*/
+pub struct Bench {
+    pub pool: String,
+    pub config: BenchConfig,
+    started: Instant,
+    progress: Arc<Progress>,
+}
+
+impl Bench {
+    pub fn start(pool: &str, addr: SocketAddr, config: BenchConfig) -> Result<Bench, String> {
+        let progress = Arc::new(Progress {
+            completed: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            finished: Mutex::new(None),
+        });
+        let started = Instant::now();
+        let thread_config = config.clone();
+        let thread_progress = progress.clone();
+        let spawned = thread::Builder::new().name(format!("bench-{}", pool)).spawn(move || {
+            let mut clients = Vec::with_capacity(thread_config.clients);
+            for index in 0..thread_config.clients {
+                // The first clients send the remainder.
+                let mut requests = thread_config.requests / thread_config.clients;
+                if index < thread_config.requests % thread_config.clients {
+                    requests += 1;
+                }
+                let config = thread_config.clone();
+                let progress = thread_progress.clone();
+                clients.push(thread::spawn(move || run_client(addr, &config, requests, &progress)));
+            }
+            let mut latency = LatencyHistogram::new();
+            for client in clients {
+                if let Ok(client_latency) = client.join() {
+                    latency.merge(&client_latency);
+                }
+            }
+            *thread_progress.finished.lock().unwrap() = Some((latency, started.elapsed()));
+        });
+        if let Err(err) = spawned {
+            return Err(format!("Failed to start benchmark: {}", err));
+        }
+        return Ok(Bench {
+            pool: pool.to_owned(),
+            config: config,
+            started: started,
+            progress: progress,
+        });
+    }
+
+    pub fn is_running(&self) -> bool {
+        return self.progress.finished.lock().unwrap().is_none();
+    }
+
+    // Clients stop after the request they're waiting on.
+    pub fn stop(&self) {
+        self.progress.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /*
+        e.g. pool1 done requests=100000/100000 errors=0 elapsed_ms=2012 ops_per_sec=49701 p50_us=870 p95_us=1950
+        p99_us=3100 p999_us=6400 max_us=12000
+    */
+    pub fn format(&self) -> String {
+        let completed = self.progress.completed.load(Ordering::SeqCst);
+        let errors = self.progress.errors.load(Ordering::SeqCst);
+        let finished = self.progress.finished.lock().unwrap();
+        let state = match *finished {
+            None if self.progress.stopped.load(Ordering::SeqCst) => "stopping",
+            None => "running",
+            Some(_) if self.progress.stopped.load(Ordering::SeqCst) => "stopped",
+            Some(_) => "done",
+        };
+        let elapsed = match *finished {
+            Some((_, elapsed)) => elapsed,
+            None => self.started.elapsed(),
+        };
+        let elapsed_ms = duration_micros(elapsed) / 1000;
+        let ops_per_sec = if elapsed_ms == 0 { 0 } else { completed as u64 * 1000 / elapsed_ms };
+        let mut output = format!(
+            "{} {} requests={}/{} errors={} elapsed_ms={} ops_per_sec={}",
+            self.pool, state, completed, self.config.requests, errors, elapsed_ms, ops_per_sec
+        );
+        if let Some((ref latency, _)) = *finished {
+            output.push_str(&format!(
+                " p50_us={} p95_us={} p99_us={} p999_us={} max_us={}",
+                latency.percentile(50.0), latency.percentile(95.0), latency.percentile(99.0), latency.percentile(99.9), latency.percentile(100.0)
+            ));
+        }
+        if let Some(ref failure) = *self.progress.failure.lock().unwrap() {
+            output.push_str(&format!("\nClient failed: {}", failure));
+        }
+        return output;
+    }
+}
+
+impl Drop for Bench {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Sends a client's share of the requests, one at a time, and returns their latencies.
+fn run_client(addr: SocketAddr, config: &BenchConfig, requests: usize, progress: &Progress) -> LatencyHistogram {
+    let mut latency = LatencyHistogram::new();
+    if let Err(err) = send_requests(addr, config, requests, progress, &mut latency) {
+        let mut failure = progress.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(err.to_string());
+        }
+    }
+    return latency;
+}
+
+fn send_requests(addr: SocketAddr, config: &BenchConfig, requests: usize, progress: &Progress, latency: &mut LatencyHistogram) -> Result<(), io::Error> {
+    let stream = try!(TcpStream::connect_timeout(&addr, Duration::from_millis(IO_TIMEOUT_MS)));
+    try!(stream.set_nodelay(true));
+    try!(stream.set_read_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS))));
+    try!(stream.set_write_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS))));
+    let mut writer = try!(stream.try_clone());
+    let mut reader = BufReader::new(stream);
+    let value = vec![b'x'; config.value_size];
+    for _ in 0..requests {
+        if progress.stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let key = format!("bench:{}", rand::random::<usize>() % KEYSPACE);
+        let request = if rand::random::<usize>() % 100 < config.set_percent {
+            encode_command(&[b"SET", key.as_bytes(), &value])
+        } else {
+            encode_command(&[b"GET", key.as_bytes()])
+        };
+        let start = Instant::now();
+        try!(writer.write_all(&request));
+        let is_error = try!(read_reply(&mut reader));
+        latency.record(duration_micros(start.elapsed()));
+        progress.completed.fetch_add(1, Ordering::SeqCst);
+        if is_error {
+            progress.errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    return Ok(());
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    return command;
+}
+
+// Reads a whole reply, and returns whether it's an error reply.
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<bool, io::Error> {
+    let mut line = Vec::new();
+    try!(reader.read_until(b'\n', &mut line));
+    if line.len() < 3 || !line.ends_with(b"\r\n") {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by the proxy"));
+    }
+    let length = || -> Result<i64, io::Error> {
+        return String::from_utf8_lossy(&line[1..line.len() - 2]).parse::<i64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid reply length"));
+    };
+    match line[0] {
+        b'+' | b':' => return Ok(false),
+        b'-' => return Ok(true),
+        b'$' => {
+            let length = try!(length());
+            if length >= 0 {
+                let mut bulk = vec![0; length as usize + 2];
+                try!(reader.read_exact(&mut bulk));
+            }
+            return Ok(false);
+        }
+        b'*' => {
+            let mut is_error = false;
+            for _ in 0..std::cmp::max(try!(length()), 0) {
+                is_error |= try!(read_reply(reader));
+            }
+            return Ok(is_error);
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid reply")),
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    assert_eq!(BenchConfig::parse(&[]).unwrap(), BenchConfig { requests: 100000, clients: 50, set_percent: 10, value_size: 64 });
+    assert_eq!(BenchConfig::parse(&["20", "100", "50"]).unwrap(), BenchConfig { requests: 20, clients: 20, set_percent: 50, value_size: 64 });
+    assert_eq!(BenchConfig::parse(&["many"]), Err("Invalid requests: many".to_owned()));
+    assert!(BenchConfig::parse(&["100", "0"]).is_err());
+    assert!(BenchConfig::parse(&["100", "10", "101"]).is_err());
+}
+
+#[test]
+fn test_read_reply() {
+    let mut replies: &[u8] = b"+OK\r\n$-1\r\n$3\r\nabc\r\n-ERR no\r\n*2\r\n:1\r\n$1\r\na\r\n";
+    assert_eq!(read_reply(&mut replies).unwrap(), false);
+    assert_eq!(read_reply(&mut replies).unwrap(), false);
+    assert_eq!(read_reply(&mut replies).unwrap(), false);
+    assert_eq!(read_reply(&mut replies).unwrap(), true);
+    assert_eq!(read_reply(&mut replies).unwrap(), false);
+    assert!(read_reply(&mut replies).is_err());
+}
+
+#[test]
+fn test_bench() {
+    use std::net::TcpListener;
+
+    // A pool stand-in that answers every request with +OK.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = Vec::new();
+                while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                    // The last line of a GET.
+                    if line.starts_with(b"bench:") {
+                        let _ = writer.write_all(b"+OK\r\n");
+                    }
+                    line.clear();
+                }
+            });
+        }
+    });
+
+    let config = BenchConfig::parse(&["200", "4", "0"]).unwrap();
+    let bench = Bench::start("pool1", addr, config).unwrap();
+    for _ in 0..500 {
+        if !bench.is_running() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let report = bench.format();
+    assert!(report.starts_with("pool1 done requests=200/200 errors=0 "), "{}", report);
+    assert!(report.contains(" p99_us="), "{}", report);
+}
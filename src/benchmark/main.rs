@@ -1,5 +1,4 @@
 extern crate mio;
-extern crate mio_more;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
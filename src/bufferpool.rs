@@ -0,0 +1,111 @@
+use std::cell::RefCell;
+use std::fmt;
+
+/*
+    Free read buffers, reused across connections so that accepting clients and reconnecting backends doesn't allocate.
+    A pooled BufReader acquires its buffer when created, and returns it when dropped. Each event loop thread has its own
+    pool, so no locking is needed.
+*/
+pub struct BufferPool {
+    free: Vec<Box<[u8]>>,
+    buffer_size: usize,
+    // Returned buffers beyond this many free ones are deallocated.
+    max_free: usize,
+    // Buffers allocated because none were free.
+    allocated: usize,
+    // Buffers handed out from the free list.
+    reused: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferPoolStats {
+    pub buffer_size: usize,
+    pub free: usize,
+    pub allocated: usize,
+    pub reused: usize,
+}
+
+thread_local! {
+    static BUFFER_POOL: RefCell<BufferPool> = RefCell::new(BufferPool::new(16384, 1024));
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_free: usize) -> BufferPool {
+        BufferPool {
+            free: Vec::new(),
+            buffer_size: buffer_size,
+            max_free: max_free,
+            allocated: 0,
+            reused: 0,
+        }
+    }
+
+    pub fn acquire(&mut self) -> Box<[u8]> {
+        match self.free.pop() {
+            Some(buf) => {
+                self.reused += 1;
+                return buf;
+            }
+            None => {
+                self.allocated += 1;
+                return vec![0; self.buffer_size].into_boxed_slice();
+            }
+        }
+    }
+
+    // Buffers of a different size, e.g. from before the pool was reconfigured, are deallocated.
+    pub fn release(&mut self, buf: Box<[u8]>) {
+        if buf.len() == self.buffer_size && self.free.len() < self.max_free {
+            self.free.push(buf);
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            buffer_size: self.buffer_size,
+            free: self.free.len(),
+            allocated: self.allocated,
+            reused: self.reused,
+        }
+    }
+}
+
+// Replaces the current thread's pool, e.g. with the configured buffer_size and buffer_pool_size.
+pub fn configure(buffer_size: usize, max_free: usize) {
+    BUFFER_POOL.with(|pool| *pool.borrow_mut() = BufferPool::new(buffer_size, max_free));
+}
+
+pub fn acquire() -> Box<[u8]> {
+    return BUFFER_POOL.with(|pool| pool.borrow_mut().acquire());
+}
+
+pub fn release(buf: Box<[u8]>) {
+    // The pool may already be destroyed if this runs while the thread exits.
+    let _ = BUFFER_POOL.try_with(|pool| pool.borrow_mut().release(buf));
+}
+
+pub fn stats() -> BufferPoolStats {
+    return BUFFER_POOL.with(|pool| pool.borrow().stats());
+}
+
+impl fmt::Display for BufferPoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "buffer_size={} free={} allocated={} reused={}", self.buffer_size, self.free, self.allocated, self.reused)
+    }
+}
+
+#[test]
+fn test_buffer_pool() {
+    let mut pool = BufferPool::new(8, 1);
+    let first = pool.acquire();
+    let second = pool.acquire();
+    assert_eq!(first.len(), 8);
+    pool.release(first);
+    // Past max_free, returned buffers are deallocated.
+    pool.release(second);
+    pool.release(vec![0; 4].into_boxed_slice());
+    assert_eq!(pool.stats(), BufferPoolStats { buffer_size: 8, free: 1, allocated: 2, reused: 0 });
+
+    pool.acquire();
+    assert_eq!(pool.stats().to_string(), "buffer_size=8 free=0 allocated=2 reused=1");
+}
@@ -17,6 +17,7 @@ use std::error;
 use std::fmt;
 use std::io::{self, Error, ErrorKind, SeekFrom};
 use memchr;
+use bufferpool;
 
 const DEFAULT_BUF_SIZE: usize = 16384;
 
@@ -60,6 +61,8 @@ pub struct BufReader<R> {
     pub buf: Box<[u8]>,
     pub pos: usize,
     pub cap: usize,
+    // Whether buf came from the buffer pool, and should be returned to it on drop.
+    pooled: bool,
 }
 
 impl<R: Read> BufReader<R> {
@@ -110,10 +113,23 @@ impl<R: Read> BufReader<R> {
                 buf: buffer.into_boxed_slice(),
                 pos: 0,
                 cap: 0,
+                pooled: false,
             }
         }
     }
 
+    /// Creates a new `BufReader` with a buffer from the current thread's buffer pool, which is returned to the pool
+    /// when the `BufReader` is dropped.
+    pub fn pooled(inner: R) -> BufReader<R> {
+        BufReader {
+            inner,
+            buf: bufferpool::acquire(),
+            pos: 0,
+            cap: 0,
+            pooled: true,
+        }
+    }
+
     /// Gets a reference to the underlying reader.
     ///
     /// It is inadvisable to directly read from the underlying reader.
@@ -182,26 +198,6 @@ impl<R: Read> BufReader<R> {
         &self.buf[self.pos..self.cap]
     }
 
-    /// Unwraps this `BufReader`, returning the underlying reader.
-    ///
-    /// Note that any leftover data in the internal buffer is lost.
-    ///
-    /// # Examples
-    ///
-    /// ```no_run
-    /// use std::io::BufReader;
-    /// use std::fs::File;
-    ///
-    /// fn main() -> std::io::Result<()> {
-    ///     let f1 = File::open("log.txt")?;
-    ///     let reader = BufReader::new(f1);
-    ///
-    ///     let f2 = reader.into_inner();
-    ///     Ok(())
-    /// }
-    /// ```
-    pub fn into_inner(self) -> R { self.inner }
-
     pub fn append_buf(&mut self) -> std::io::Result<&[u8]> {
         self.cap = self.inner.read(&mut self.buf[self.cap..])? + self.cap;
         Ok(&self.buf[self.pos..self.cap])
@@ -294,6 +290,14 @@ impl<R: Read> BufRead for BufReader<R> {
     }
 }
 
+impl<R> Drop for BufReader<R> {
+    fn drop(&mut self) {
+        if self.pooled {
+            bufferpool::release(std::mem::replace(&mut self.buf, Box::new([])));
+        }
+    }
+}
+
 //#[stable(feature = "rust1", since = "1.0.0")]
 impl<R> fmt::Debug for BufReader<R> where R: fmt::Debug {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
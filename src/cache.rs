@@ -0,0 +1,356 @@
+use config::BackendPoolConfig;
+use hashbrown::HashMap;
+use invalidation::{Invalidation, InvalidationListener};
+use stats::metric_labels;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Size limit of a cache that only holds hot keys, whose number is limited by hotkey_top_k.
+const HOT_KEY_CACHE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+// Shared between a pool, which serves hits, and its clients, which fill it with the responses to misses.
+pub type SharedCache = Rc<RefCell<ReadCache>>;
+
+struct Entry {
+    response: Vec<u8>,
+    expires: Instant,
+    // Position in the LRU order.
+    last_used: u64,
+}
+
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    // Responses added to the cache.
+    pub fills: usize,
+    // Entries removed to stay under cache_max_bytes.
+    pub evictions: usize,
+    pub expirations: usize,
+    // Entries removed because a request other than a GET used their key, or a backend reported that it changed.
+    pub invalidations: usize,
+    // Times the whole cache was invalidated by a backend.
+    pub flushes: usize,
+    // Expired responses served because the key's backend was down, with cache_stale_ttl.
+    pub stale_hits: usize,
+}
+
+impl CacheStats {
+    fn new() -> CacheStats {
+        CacheStats {
+            hits: 0,
+            misses: 0,
+            fills: 0,
+            evictions: 0,
+            expirations: 0,
+            invalidations: 0,
+            flushes: 0,
+            stale_hits: 0,
+        }
+    }
+}
+
+/*
+    A near-cache of a pool's GET responses, for read-heavy workloads. GETs, and the GETs an MGET is split into, are
+    answered from the cache while their entry is younger than cache_ttl, and their responses are cached on a miss.
+    Other requests through the pool with the same key, such as a SET or DEL, remove the entry. Writes that don't go
+    through the pool are seen once the entry expires, or right away with cache_invalidation. Only bulk string responses are cached: errors and nil aren't.
+
+    A response is only cached if no key was invalidated while its GET was in flight, since the GET may have been
+    answered before a write to its key that was sent after it.
+
+    The least recently used entries are evicted to keep the keys and responses within cache_max_bytes.
+
+    With cache_stale_ttl, expired entries are kept for that much longer. They aren't served as hits, but a GET whose
+    backend is down is answered with one instead of an error.
+
+    With hotkey_cache_ttl instead of cache_max_bytes, only the keys that hot key detection currently has in its top
+    list are cached, for a short time, to spread a hot key's reads away from its shard.
+*/
+pub struct ReadCache {
+    entries: HashMap<Vec<u8>, Entry>,
+    // Keys by when they were last used, least recent first.
+    lru: BTreeMap<u64, Vec<u8>>,
+    next_use: u64,
+    // Bumped by every invalidation, so that responses to GETs sent before it aren't cached.
+    generation: u64,
+    ttl: Duration,
+    // How long past ttl expired entries are kept for, to be served while their backend is down.
+    stale_ttl: Duration,
+    max_bytes: usize,
+    // Set when only hot keys are cached.
+    pub hot_keys_only: bool,
+    // Only set when cache_invalidation is configured.
+    listener: Option<InvalidationListener>,
+    // Bytes of the cached keys and responses.
+    pub bytes: usize,
+    pub stats: CacheStats,
+}
+
+impl ReadCache {
+    // Returns None when neither cache_max_bytes nor hotkey_cache_ttl is set.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<SharedCache> {
+        let mut cache = if config.cache_max_bytes > 0 {
+            ReadCache::new(config.cache_max_bytes, Duration::from_millis(config.cache_ttl as u64))
+        } else if config.hotkey_cache_ttl > 0 && config.hotkey_sample_rate > 0 {
+            let mut cache = ReadCache::new(HOT_KEY_CACHE_MAX_BYTES, Duration::from_millis(config.hotkey_cache_ttl as u64));
+            cache.hot_keys_only = true;
+            cache
+        } else {
+            return None;
+        };
+        cache.stale_ttl = Duration::from_millis(config.cache_stale_ttl as u64);
+        cache.listener = InvalidationListener::from_config(config);
+        return Some(Rc::new(RefCell::new(cache)));
+    }
+
+    pub fn new(max_bytes: usize, ttl: Duration) -> ReadCache {
+        ReadCache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_use: 0,
+            generation: 0,
+            ttl: ttl,
+            stale_ttl: Duration::from_millis(0),
+            max_bytes: max_bytes,
+            hot_keys_only: false,
+            listener: None,
+            bytes: 0,
+            stats: CacheStats::new(),
+        }
+    }
+
+    // Returns the cached response for the key, unless it has expired. Counts the hit or miss.
+    pub fn get(&mut self, key: &[u8], now: Instant) -> Option<&[u8]> {
+        self.apply_invalidations();
+        let (expired, stale) = match self.entries.get(key) {
+            Some(entry) => (entry.expires <= now, now < entry.expires + self.stale_ttl),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        if expired {
+            // Kept in case the key's backend is down.
+            if !stale {
+                self.remove(key);
+                self.stats.expirations += 1;
+            }
+            self.stats.misses += 1;
+            return None;
+        }
+        self.stats.hits += 1;
+        let last_used = self.next_use;
+        self.next_use += 1;
+        let entry = self.entries.get_mut(key).unwrap();
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(last_used, key.to_vec());
+        entry.last_used = last_used;
+        return Some(&entry.response);
+    }
+
+    /*
+        Returns the cached response for the key even if it has expired, as long as that was less than stale_ttl ago,
+        for a GET whose backend is down. Counts the stale hit.
+    */
+    pub fn get_stale(&mut self, key: &[u8], now: Instant) -> Option<&[u8]> {
+        self.apply_invalidations();
+        match self.entries.get(key) {
+            Some(entry) if now < entry.expires + self.stale_ttl => {}
+            _ => return None,
+        }
+        self.stats.stale_hits += 1;
+        return self.entries.get(key).map(|entry| &entry.response[..]);
+    }
+
+    // The generation to pass to insert, for a GET about to be sent.
+    pub fn generation(&self) -> u64 {
+        return self.generation;
+    }
+
+    // Caches the response to a GET of the key, if it's a bulk string and no key was invalidated since the generation.
+    pub fn insert(&mut self, key: Vec<u8>, response: &[u8], generation: u64, now: Instant) {
+        self.apply_invalidations();
+        if generation != self.generation || !response.starts_with(b"$") || response.starts_with(b"$-") {
+            return;
+        }
+        let size = key.len() + response.len();
+        if size > self.max_bytes {
+            return;
+        }
+        self.remove(&key);
+        while self.bytes + size > self.max_bytes {
+            let oldest = match self.lru.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        let last_used = self.next_use;
+        self.next_use += 1;
+        self.lru.insert(last_used, key.clone());
+        self.entries.insert(key, Entry {
+            response: response.to_vec(),
+            expires: now + self.ttl,
+            last_used: last_used,
+        });
+        self.bytes += size;
+        self.stats.fills += 1;
+    }
+
+    pub fn invalidate(&mut self, key: &[u8]) {
+        self.generation += 1;
+        if self.remove(key) {
+            self.stats.invalidations += 1;
+        }
+    }
+
+    // Removes the keys that the backends reported as changed.
+    fn apply_invalidations(&mut self) {
+        let pending = match self.listener {
+            Some(ref listener) => listener.pending(),
+            None => return,
+        };
+        for invalidation in pending {
+            match invalidation {
+                Invalidation::Key(key) => self.invalidate(&key),
+                Invalidation::Flush => self.flush(),
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes = 0;
+        self.stats.flushes += 1;
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::new();
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.lru.remove(&entry.last_used);
+                self.bytes -= key.len() + entry.response.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // e.g. entries=120 bytes=5230 max_bytes=1048576 hits=300 misses=120 fills=120 evictions=0 expirations=4 invalidations=2 flushes=0 stale_hits=0
+    pub fn format(&self) -> String {
+        return format!(
+            "entries={} bytes={} max_bytes={} hits={} misses={} fills={} evictions={} expirations={} invalidations={} flushes={} stale_hits={}",
+            self.len(),
+            self.bytes,
+            self.max_bytes,
+            self.stats.hits,
+            self.stats.misses,
+            self.stats.fills,
+            self.stats.evictions,
+            self.stats.expirations,
+            self.stats.invalidations,
+            self.stats.flushes,
+            self.stats.stale_hits
+        );
+    }
+
+    // Formats the cache's stats as one metric per line, e.g. cache_read_cache_hits{pool="pool1"} 300
+    pub fn format_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("read_cache_entries", self.len()),
+            ("read_cache_bytes", self.bytes),
+            ("read_cache_hits", self.stats.hits),
+            ("read_cache_misses", self.stats.misses),
+            ("read_cache_fills", self.stats.fills),
+            ("read_cache_evictions", self.stats.evictions),
+            ("read_cache_expirations", self.stats.expirations),
+            ("read_cache_invalidations", self.stats.invalidations),
+            ("read_cache_flushes", self.stats.flushes),
+            ("read_cache_stale_hits", self.stats.stale_hits),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        return output;
+    }
+}
+
+#[test]
+fn test_read_cache() {
+    let now = Instant::now();
+    let mut cache = ReadCache::new(40, Duration::from_millis(100));
+    assert_eq!(cache.get(b"a", now), None);
+    cache.insert(b"a".to_vec(), b"$3\r\nfoo\r\n", 0, now);
+    // Errors and nil aren't cached.
+    cache.insert(b"b".to_vec(), b"$-1\r\n", 0, now);
+    cache.insert(b"c".to_vec(), b"-ERR\r\n", 0, now);
+    assert_eq!(cache.get(b"a", now), Some(&b"$3\r\nfoo\r\n"[..]));
+    assert_eq!(cache.get(b"b", now), None);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.bytes, 10);
+
+    // Expires after the ttl.
+    assert_eq!(cache.get(b"a", now + Duration::from_millis(100)), None);
+    assert_eq!(cache.len(), 0);
+    assert_eq!(cache.bytes, 0);
+
+    // Evicts the least recently used to stay within max_bytes.
+    cache.insert(b"a".to_vec(), b"$3\r\nfoo\r\n", 0, now);
+    cache.insert(b"b".to_vec(), b"$3\r\nbar\r\n", 0, now);
+    cache.insert(b"c".to_vec(), b"$3\r\nbaz\r\n", 0, now);
+    assert!(cache.get(b"a", now).is_some());
+    cache.insert(b"d".to_vec(), b"$3\r\nqux\r\n", 0, now);
+    cache.insert(b"e".to_vec(), b"$3\r\nquz\r\n", 0, now);
+    assert!(cache.get(b"a", now).is_some());
+    assert_eq!(cache.get(b"b", now), None);
+    assert_eq!(cache.len(), 4);
+    assert_eq!(cache.stats.evictions, 1);
+    // Too large to cache at all.
+    cache.insert(b"f".to_vec(), &[b'$'; 40], 0, now);
+    assert_eq!(cache.len(), 4);
+
+    let generation = cache.generation();
+    cache.invalidate(b"a");
+    assert_eq!(cache.get(b"a", now), None);
+    assert_eq!(cache.stats.invalidations, 1);
+    // Not cached, since its GET was sent before the invalidation.
+    cache.insert(b"a".to_vec(), b"$3\r\nfoo\r\n", generation, now);
+    assert_eq!(cache.len(), 3);
+    assert_eq!(cache.format(), "entries=3 bytes=30 max_bytes=40 hits=3 misses=5 fills=6 evictions=1 expirations=1 invalidations=1 flushes=0 stale_hits=0");
+}
+
+#[test]
+fn test_stale_responses() {
+    let now = Instant::now();
+    let mut cache = ReadCache::new(1024, Duration::from_millis(100));
+    cache.stale_ttl = Duration::from_millis(200);
+    cache.insert(b"a".to_vec(), b"$3\r\nfoo\r\n", 0, now);
+    // Expired entries are misses, but are kept to be served stale.
+    assert_eq!(cache.get(b"a", now + Duration::from_millis(150)), None);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.get_stale(b"a", now + Duration::from_millis(299)), Some(&b"$3\r\nfoo\r\n"[..]));
+    assert_eq!(cache.get_stale(b"a", now + Duration::from_millis(300)), None);
+    assert_eq!(cache.get(b"a", now + Duration::from_millis(300)), None);
+    assert_eq!(cache.len(), 0);
+
+    // Invalidated entries aren't served stale.
+    cache.insert(b"b".to_vec(), b"$3\r\nbar\r\n", cache.generation(), now);
+    cache.invalidate(b"b");
+    assert_eq!(cache.get_stale(b"b", now), None);
+    assert_eq!(cache.stats.stale_hits, 1);
+    assert_eq!(cache.stats.expirations, 1);
+}
@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime};
+use clock;
+use humantime;
+use redflareproxy::ClientTokenValue;
+use stats::micros_since;
+
+// Shared between a pool and the clients it samples.
+pub type SharedCapture = Rc<RefCell<Capture>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    // From a client to the proxy.
+    Request,
+    // From the proxy to a client.
+    Response,
+}
+
+impl Direction {
+    fn marker(&self) -> u8 {
+        match *self {
+            Direction::Request => b'>',
+            Direction::Response => b'<',
+        }
+    }
+}
+
+/*
+    Records the raw requests and responses of a pool's clients to a file, so that protocol bugs can be reproduced
+    offline. One in every sample_rate client connections is captured, so that each captured connection has all of its
+    frames. The capture stops by itself once the file reaches max_bytes.
+
+    The file starts with a "# redflare capture" line, followed by one record per frame:
+    <direction> <offset_us> <client> <length>\r\n<data>\r\n
+    where direction is '>' for requests and '<' for responses, and offset_us is the time since the capture started.
+*/
+pub struct Capture {
+    pub path: String,
+    writer: BufWriter<File>,
+    start: Instant,
+    sample_rate: usize,
+    seen_clients: usize,
+    max_bytes: u64,
+    pub bytes: u64,
+    pub frames: u64,
+    pub active: bool,
+}
+
+impl Capture {
+    /*
+        Starts a capture into a new file in dir, named after the pool and start time, e.g.
+        /tmp/pool1-20190301T120000Z.capture
+    */
+    pub fn start(dir: &str, pool: &str, sample_rate: usize, max_bytes: u64) -> Result<Capture, std::io::Error> {
+        let now = SystemTime::now();
+        let timestamp: String = humantime::format_rfc3339_seconds(now).to_string().chars().filter(|c| *c != '-' && *c != ':').collect();
+        let path = format!("{}/{}-{}.capture", dir.trim_end_matches('/'), pool, timestamp);
+        let file = try!(File::create(&path));
+        let mut writer = BufWriter::new(file);
+        let header = format!(
+            "# redflare capture pool={} start={} sample_rate={}\n",
+            pool,
+            humantime::format_rfc3339_seconds(now),
+            sample_rate
+        );
+        try!(writer.write_all(header.as_bytes()));
+        Ok(Capture {
+            path: path,
+            writer: writer,
+            start: clock::now(),
+            sample_rate: std::cmp::max(sample_rate, 1),
+            seen_clients: 0,
+            max_bytes: max_bytes,
+            bytes: header.len() as u64,
+            frames: 0,
+            active: true,
+        })
+    }
+
+    // Whether a newly seen client connection should be captured.
+    pub fn sample_client(&mut self) -> bool {
+        if !self.active {
+            return false;
+        }
+        self.seen_clients += 1;
+        return (self.seen_clients - 1) % self.sample_rate == 0;
+    }
+
+    pub fn record(&mut self, direction: Direction, client: ClientTokenValue, data: &[u8]) {
+        if !self.active {
+            return;
+        }
+        let header = format!("{} {} {} {}\r\n", direction.marker() as char, micros_since(self.start), client, data.len());
+        let len = (header.len() + data.len() + 2) as u64;
+        if self.bytes + len > self.max_bytes {
+            info!("Capture {} reached its size limit of {} bytes. Stopping.", self.path, self.max_bytes);
+            self.stop();
+            return;
+        }
+        let result = self.writer.write_all(header.as_bytes())
+            .and_then(|_| self.writer.write_all(data))
+            .and_then(|_| self.writer.write_all(b"\r\n"));
+        if let Err(err) = result {
+            error!("Failed to write to capture {}: {}. Stopping.", self.path, err);
+            self.stop();
+            return;
+        }
+        self.bytes += len;
+        self.frames += 1;
+    }
+
+    // Flushes the file. Clients still holding the capture stop recording to it.
+    pub fn stop(&mut self) {
+        self.active = false;
+        if let Err(err) = self.writer.flush() {
+            error!("Failed to flush capture {}: {}", self.path, err);
+        }
+    }
+
+    // e.g. path=/tmp/pool1-20190301T120000Z.capture active=true frames=120 bytes=5230
+    pub fn format(&self) -> String {
+        return format!("path={} active={} frames={} bytes={}", self.path, self.active, self.frames, self.bytes);
+    }
+}
+
+#[test]
+fn test_capture() {
+    use std::io::Read;
+
+    let dir = std::env::temp_dir().join(format!("redflare-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut capture = Capture::start(dir.to_str().unwrap(), "pool1", 2, 200).unwrap();
+    assert!(capture.path.ends_with(".capture"));
+    assert!(capture.path.contains("/pool1-"));
+
+    // One in every 2 clients is sampled.
+    assert!(capture.sample_client());
+    assert!(!capture.sample_client());
+    assert!(capture.sample_client());
+
+    capture.record(Direction::Request, 20, b"*2\r\n$3\r\nGET\r\n$2\r\nk1\r\n");
+    capture.record(Direction::Response, 20, b"$-1\r\n");
+    // Past the size limit, the capture stops.
+    capture.record(Direction::Request, 20, &[b'x'; 200]);
+    assert!(!capture.active);
+    assert_eq!(capture.frames, 2);
+    assert!(!capture.sample_client());
+
+    let mut contents = Vec::new();
+    File::open(&capture.path).unwrap().read_to_end(&mut contents).unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    let contents = String::from_utf8(contents).unwrap();
+    let lines: Vec<&str> = contents.split("\r\n").collect();
+    assert!(lines[0].starts_with("# redflare capture pool=pool1 start="), "{}", lines[0]);
+    // Each frame is "<direction> <offset_us> <client> <length>", followed by the data.
+    assert!(lines[0].contains(" sample_rate=2\n> ") && lines[0].ends_with(" 20 21"), "{}", lines[0]);
+    assert_eq!(&lines[1..6], &["*2", "$3", "GET", "$2", "k1"]);
+    assert!(lines[7].starts_with("< ") && lines[7].ends_with(" 20 5"), "{}", lines[7]);
+    assert_eq!(lines[8], "$-1");
+}
@@ -0,0 +1,141 @@
+use config::{BackendPoolConfig, ChaosConfig};
+use rand;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+// Shared by a pool and the backend hosts it injects faults on, so that CHAOS starts and stops them all at once.
+pub type SharedChaos = Rc<RefCell<Chaos>>;
+
+#[derive(Debug, PartialEq)]
+pub enum Fault {
+    // The request, and those written to the backend after it, are held back this long.
+    Latency(Duration),
+    // The backend connection is closed once the request is written, failing the requests waiting on it.
+    Disconnect,
+    // The response is thrown away, and the request times out.
+    Drop,
+    // The response is replaced with this error.
+    Error(Vec<u8>),
+}
+
+/*
+    A pool's chaos mode, which injects artificial latency, disconnects, dropped responses and error replies into the
+    traffic of its backends, so that applications can be tested against a failing proxy or backend. Faults are picked
+    at random for each request, by the percents in the pool's chaos config. Only the hosts of non-cluster backends, and
+    their replicas, are targeted.
+*/
+pub struct Chaos {
+    config: ChaosConfig,
+    // Whether faults are being injected, from the config's enabled and the CHAOS admin command.
+    pub active: bool,
+    // The error response, encoded.
+    error: Vec<u8>,
+    // Faults injected since the stats were reset.
+    delays: usize,
+    disconnects: usize,
+    drops: usize,
+    errors: usize,
+}
+
+impl Chaos {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<SharedChaos> {
+        return config.chaos.as_ref().map(|config| Rc::new(RefCell::new(Chaos::new(config.clone()))));
+    }
+
+    pub fn new(config: ChaosConfig) -> Chaos {
+        Chaos {
+            active: config.enabled,
+            error: format!("-{}\r\n", config.error).into_bytes(),
+            config: config,
+            delays: 0,
+            disconnects: 0,
+            drops: 0,
+            errors: 0,
+        }
+    }
+
+    fn targets(&self, host: &SocketAddr) -> bool {
+        return self.active && (self.config.backends.is_empty() || self.config.backends.contains(host));
+    }
+
+    // The fault for a client request just written to the host, if it gets one.
+    pub fn request_fault(&mut self, host: &SocketAddr) -> Option<Fault> {
+        if !self.targets(host) {
+            return None;
+        }
+        if roll(self.config.disconnect_percent) {
+            self.disconnects += 1;
+            return Some(Fault::Disconnect);
+        }
+        if self.config.latency > 0 && roll(self.config.latency_percent) {
+            self.delays += 1;
+            return Some(Fault::Latency(Duration::from_millis(self.config.latency as u64)));
+        }
+        return None;
+    }
+
+    // The fault for a response the host just sent to a client, if it gets one.
+    pub fn response_fault(&mut self, host: &SocketAddr) -> Option<Fault> {
+        if !self.targets(host) {
+            return None;
+        }
+        if roll(self.config.drop_percent) {
+            self.drops += 1;
+            return Some(Fault::Drop);
+        }
+        if roll(self.config.error_percent) {
+            self.errors += 1;
+            return Some(Fault::Error(self.error.clone()));
+        }
+        return None;
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.delays = 0;
+        self.disconnects = 0;
+        self.drops = 0;
+        self.errors = 0;
+    }
+
+    // e.g. active=true delays=12 disconnects=1 drops=3 errors=5
+    pub fn format(&self) -> String {
+        return format!(
+            "active={} delays={} disconnects={} drops={} errors={}",
+            self.active, self.delays, self.disconnects, self.drops, self.errors
+        );
+    }
+}
+
+fn roll(percent: usize) -> bool {
+    return percent > 0 && rand::random::<usize>() % 100 < percent;
+}
+
+#[test]
+fn test_faults() {
+    let config = ChaosConfig {
+        enabled: true,
+        backends: vec!["127.0.0.1:6380".parse().unwrap()],
+        latency: 20,
+        latency_percent: 100,
+        disconnect_percent: 0,
+        drop_percent: 0,
+        error_percent: 100,
+        error: "ERR Injected fault".to_owned(),
+    };
+    let mut chaos = Chaos::new(config);
+    let targeted = "127.0.0.1:6380".parse().unwrap();
+    let other = "127.0.0.1:6381".parse().unwrap();
+    assert_eq!(chaos.request_fault(&targeted), Some(Fault::Latency(Duration::from_millis(20))));
+    assert_eq!(chaos.response_fault(&targeted), Some(Fault::Error(b"-ERR Injected fault\r\n".to_vec())));
+    assert_eq!(chaos.request_fault(&other), None);
+    assert_eq!(chaos.response_fault(&other), None);
+    assert_eq!(chaos.format(), "active=true delays=1 disconnects=0 drops=0 errors=1");
+
+    chaos.active = false;
+    assert_eq!(chaos.request_fault(&targeted), None);
+    assert_eq!(chaos.response_fault(&targeted), None);
+    chaos.reset_stats();
+    assert_eq!(chaos.format(), "active=false delays=0 disconnects=0 drops=0 errors=0");
+}
@@ -1,23 +1,251 @@
 use std::io::Read;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use mio::net::TcpStream;
 use bufreader::BufReader;
+use cache::SharedCache;
+use mirror::{MirrorKind, Mirrors};
+use capture::SharedCapture;
+use clock;
+use std::collections::HashMap;
+use filter::SharedFilters;
+use errorresponses::ErrorResponses;
+use ratelimit::ClientLimits;
+use quota::Identity;
+use readyourwrites::RecentWrites;
+use functions::Broadcast;
+use mio::Token;
+use registry::{Interest, Registered};
+use tokens::TokenSlot;
+use std::rc::Rc;
+
+// A read that may be sent to a second backend, if the first doesn't answer within the pool's hedge_delay.
+pub enum Hedge {
+    // Waiting for the delay to pass. Holds the request, to send again.
+    Scheduled(Vec<u8>),
+    // Sent to both backends, neither of which has answered yet.
+    Sent,
+    // One backend answered. The response of the other is dropped.
+    Answered,
+}
 
 pub struct Client {
-    pub stream: TcpStream,
+    pub stream: Registered<TcpStream>,
     // Used to house response for a multikey request.
     pub pending_response: Vec<Vec<u8>>,
     // Remaining number of responses needed for multikey request. 0 means that no multikey request is inflight.
     pub pending_count: usize,
+    // Set while a request broadcast to every backend is in flight, whose responses are merged instead of joined.
+    pub pending_broadcast: Option<Broadcast>,
+    // Responses read from a backend in one pass, written to the client together once the pass is done.
+    pub output: Vec<u8>,
+    // Set while the client's traffic is being captured.
+    pub capture: Option<SharedCapture>,
+    // The filters of the client's pool, if it has any.
+    pub filters: Option<SharedFilters>,
+    // The error_responses of the client's pool, if it has them.
+    pub error_responses: Option<Rc<ErrorResponses>>,
+    // The read cache of the client's pool, if it has one.
+    pub cache: Option<SharedCache>,
+    // Keys and cache generations of the client's GETs that missed the cache, by request id, to cache their responses.
+    pub cache_fills: HashMap<(Instant, usize), (Vec<u8>, u64)>,
+    // The client's reads that may be hedged, by request id.
+    pub hedges: HashMap<(Instant, usize), Hedge>,
+    // The client's mutations also written to their backend's secondary, in a dual_write pool.
+    pub dual_writes: Option<Mirrors>,
+    // The client's requests also written to the pool's canary.
+    pub canary: Option<Mirrors>,
+    // Set when the pool caps the requests of each client.
+    pub limits: Option<ClientLimits>,
+    // Set once the client has authenticated as one of the pool's users.
+    pub identity: Option<Identity>,
+    // Keys the client wrote recently, whose reads go to the backend rather than a replica, with read_your_writes.
+    pub recent_writes: Option<RecentWrites>,
+    /*
+        Requests waiting for a backend's response, or for the GET they joined, by request id. Tracked in a pool with
+        collapse_gets, where a GET only joins another if the client has none, so that its responses stay in order.
+    */
+    pub awaiting: Option<HashMap<(Instant, usize), usize>>,
+    // Milliseconds the client's next request may take, set with DEADLINE in a pool with client_deadlines.
+    pub request_timeout: Option<usize>,
+    // Invalid requests the client has sent, toward the pool's quarantine_threshold.
+    pub protocol_errors: usize,
+    // Where the client connected from, as passed on by a load balancer in a PROXY protocol header if there is one.
+    pub peer_addr: Option<SocketAddr>,
+    // Set until the PROXY protocol header has been read, on a listener with proxy_protocol.
+    pub proxy_header_pending: bool,
+    // The client's token, released once the client is dropped.
+    slot: TokenSlot,
+    // Set while the client isn't read from, because a backend it sent requests to is full.
+    paused: bool,
+    // Last time a request was read from the client, or a response written to it.
+    last_active: Instant,
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    pub fn new(stream: Registered<TcpStream>, slot: TokenSlot) -> Client {
         Client {
             stream: stream,
             pending_response: Vec::new(),
             pending_count: 0,
+            pending_broadcast: None,
+            output: Vec::new(),
+            capture: None,
+            filters: None,
+            error_responses: None,
+            cache: None,
+            cache_fills: HashMap::new(),
+            hedges: HashMap::new(),
+            dual_writes: None,
+            canary: None,
+            limits: None,
+            identity: None,
+            recent_writes: None,
+            awaiting: None,
+            request_timeout: None,
+            protocol_errors: 0,
+            peer_addr: None,
+            proxy_header_pending: false,
+            slot: slot,
+            paused: false,
+            last_active: clock::now(),
+        }
+    }
+
+    // Caches the response to a request, if it's a GET that missed the cache.
+    pub fn fill_cache(&mut self, request_id: (Instant, usize), response: &[u8]) {
+        if self.cache_fills.is_empty() {
+            return;
+        }
+        if let Some((key, generation)) = self.cache_fills.remove(&request_id) {
+            if let Some(ref cache) = self.cache {
+                cache.borrow_mut().insert(key, response, generation, clock::now());
+            }
+        }
+    }
+
+    // Records a request that's waiting for its response, when the client's requests are tracked.
+    pub fn await_response(&mut self, request_id: (Instant, usize)) {
+        if let Some(ref mut awaiting) = self.awaiting {
+            *awaiting.entry(request_id).or_insert(0) += 1;
         }
     }
+
+    pub fn on_response(&mut self, request_id: (Instant, usize)) {
+        let awaiting = match self.awaiting {
+            Some(ref mut awaiting) if !awaiting.is_empty() => awaiting,
+            _ => return,
+        };
+        let answered = match awaiting.get_mut(&request_id) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if answered {
+            awaiting.remove(&request_id);
+        }
+    }
+
+    // Whether all of the client's requests have been answered. False if they aren't tracked.
+    pub fn is_answered(&self) -> bool {
+        return self.awaiting.as_ref().map_or(false, |awaiting| awaiting.is_empty());
+    }
+
+    // Compares the response of a request's own backend with its mirrors' responses, if it was mirrored.
+    pub fn on_primary_response(&mut self, request_id: (Instant, usize), response: &[u8]) {
+        if let Some(ref mut dual_writes) = self.dual_writes {
+            dual_writes.on_primary_response(request_id, response);
+        }
+        if let Some(ref mut canary) = self.canary {
+            canary.on_primary_response(request_id, response);
+        }
+    }
+
+    pub fn on_mirror_response(&mut self, kind: MirrorKind, request_id: (Instant, usize), response: &[u8]) {
+        let mirrors = match kind {
+            MirrorKind::Secondary => &mut self.dual_writes,
+            MirrorKind::Canary => &mut self.canary,
+        };
+        if let Some(ref mut mirrors) = *mirrors {
+            mirrors.on_mirror_response(request_id, response);
+        }
+    }
+
+    // Whether a response is the first one to a request, and should be written to the client.
+    pub fn is_first_response(&mut self, request_id: (Instant, usize)) -> bool {
+        if self.hedges.is_empty() {
+            return true;
+        }
+        match self.hedges.remove(&request_id) {
+            Some(Hedge::Sent) => {
+                self.hedges.insert(request_id, Hedge::Answered);
+                true
+            }
+            Some(Hedge::Answered) => false,
+            Some(Hedge::Scheduled(_)) | None => true,
+        }
+    }
+
+    // Takes the request of a hedge whose delay passed, unless it has been answered already.
+    pub fn start_hedge(&mut self, request_id: (Instant, usize)) -> Option<Vec<u8>> {
+        match self.hedges.remove(&request_id) {
+            Some(Hedge::Scheduled(request)) => {
+                self.hedges.insert(request_id, Hedge::Sent);
+                Some(request)
+            }
+            Some(hedge) => {
+                self.hedges.insert(request_id, hedge);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        return self.slot.token();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.paused;
+    }
+
+    /*
+        Stops polling the client for reads, so that a full backend slows it down through TCP instead of the proxy
+        buffering its requests. Requests that were already read stay buffered until it is resumed.
+    */
+    pub fn pause(&mut self) -> Result<(), std::io::Error> {
+        self.paused = true;
+        return self.stream.set_interest(Interest::Paused);
+    }
+
+    pub fn resume(&mut self) -> Result<(), std::io::Error> {
+        self.paused = false;
+        return self.stream.set_interest(Interest::Readable);
+    }
+
+    pub fn mark_active(&mut self) {
+        self.last_active = clock::now();
+    }
+
+    pub fn idle_time(&self, now: Instant) -> Duration {
+        if now > self.last_active {
+            return now - self.last_active;
+        }
+        return Duration::from_millis(0);
+    }
+
+    // Whether the client closed its end, without the proxy having read the close yet, e.g. while it was paused.
+    pub fn has_hung_up(&self) -> bool {
+        let mut buf = [0; 1];
+        return match self.stream.peek(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => false,
+            Err(_) => true,
+        };
+    }
 }
 
 impl Read for Client {
@@ -27,3 +255,53 @@ impl Read for Client {
 }
 
 pub type BufferedClient = BufReader<Client>;
+
+#[cfg(test)]
+fn test_client(listener: &std::net::TcpListener) -> Client {
+    use registry::Registry;
+    use tokens::{TokenKind, TokenSlab};
+    let stream = TcpStream::from_stream(listener.accept().unwrap().0).unwrap();
+    let slot = TokenSlot::allocate(&TokenSlab::shared(TokenKind::PoolClient));
+    let stream = Registered::new(&Registry::new().unwrap(), stream, slot.token(), Interest::Readable).unwrap();
+    return Client::new(stream, slot);
+}
+
+#[test]
+fn test_client_idle_and_hang_up() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut client = test_client(&listener);
+
+    let later = Instant::now() + Duration::from_millis(500);
+    assert!(client.idle_time(later) >= Duration::from_millis(500));
+    client.mark_active();
+    assert_eq!(client.idle_time(client.last_active), Duration::from_millis(0));
+    assert!(!client.has_hung_up());
+
+    drop(peer);
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(client.has_hung_up());
+}
+
+#[test]
+fn test_client_hedges() {
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let mut client = test_client(&listener);
+    let now = Instant::now();
+
+    // Answered before the delay passed, so it's never sent again.
+    client.hedges.insert((now, 0), Hedge::Scheduled(b"GET".to_vec()));
+    assert!(client.is_first_response((now, 0)));
+    assert_eq!(client.start_hedge((now, 0)), None);
+
+    // Sent twice, so only the first response is written.
+    client.hedges.insert((now, 1), Hedge::Scheduled(b"GET".to_vec()));
+    assert_eq!(client.start_hedge((now, 1)), Some(b"GET".to_vec()));
+    assert_eq!(client.start_hedge((now, 1)), None);
+    assert!(client.is_first_response((now, 1)));
+    assert!(!client.is_first_response((now, 1)));
+    assert!(client.hedges.is_empty());
+}
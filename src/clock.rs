@@ -0,0 +1,84 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/*
+    Where the event loop reads the time from: its deadlines, timeouts, retry backoff, rate limits and latencies. Each
+    worker runs its event loop on its own thread, so the clock is set per thread, and the system clock is used until
+    one is set. Tests set a MockClock to move time forward deterministically instead of sleeping. See harness.rs.
+*/
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+}
+
+// A clock that only moves when it's advanced. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Rc::new(Cell::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        return self.now.get();
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Option<Rc<dyn Clock>>> = RefCell::new(None);
+}
+
+// Sets the clock of the current thread. None goes back to the system clock.
+pub fn set(clock: Option<Rc<dyn Clock>>) {
+    CLOCK.with(|current| *current.borrow_mut() = clock);
+}
+
+pub fn now() -> Instant {
+    return CLOCK.with(|current| match *current.borrow() {
+        Some(ref clock) => clock.now(),
+        None => Instant::now(),
+    });
+}
+
+// Time passed since an earlier instant, or 0 if it's in the future.
+pub fn since(earlier: Instant) -> Duration {
+    let now = now();
+    if earlier >= now {
+        return Duration::from_millis(0);
+    }
+    return now - earlier;
+}
+
+#[test]
+fn test_mock_clock() {
+    let clock = MockClock::new();
+    set(Some(Rc::new(clock.clone())));
+    let start = now();
+    assert_eq!(now(), start);
+    clock.advance(Duration::from_millis(250));
+    assert_eq!(now(), start + Duration::from_millis(250));
+    assert_eq!(since(start), Duration::from_millis(250));
+    assert_eq!(since(start + Duration::from_secs(1)), Duration::from_millis(0));
+
+    set(None);
+    assert!(now() < start + Duration::from_millis(250));
+}
@@ -1,24 +1,32 @@
 use client::BufferedClient;
-use stats::Stats;
+use stats::{Stats, LatencyHistogram, CommandStats, ErrorStats, ConnectionStats, MemoryStats, QueueStats};
 use redflareproxy::ClientTokenValue;
 use redisprotocol::RedisError;
-use redisprotocol::handle_slotsmap;
+use redisprotocol::{handle_slotsmap, CLUSTER_SLOTS};
 use redisprotocol::WriteError;
 use std::net::SocketAddr;
 use redflareproxy::PoolTokenValue;
 use redflareproxy::convert_token_to_cluster_index;
-use redflareproxy::{BackendToken, ClientToken, NULL_TOKEN};
-use backend::{BackendStatus, SingleBackend};
+use tokens;
+use tokens::TokenKind;
+use redflareproxy::{BackendToken, ClientToken, NULL_TOKEN, Timers};
+use backend::{BackendStatus, QueueLimits, SingleBackend};
+use clock;
+use concurrency::ConcurrencyLimiter;
 use config::BackendConfig;
 use std::collections::{VecDeque};
 use hashbrown::HashMap;
 use crc16::*;
-use mio::{Token, Poll};
-use std::time::Instant;
+use mio::Token;
+use std::time::{Duration, Instant};
 use std::cell::{RefCell};
 use std::rc::Rc;
 use std;
+use registry::Registry;
 use redisprotocol::{extract_key, KeyPos};
+use retry::RetryPolicy;
+use trace::RequestTrace;
+use slowlog::{SlowLog, SlowRequest};
 
 pub type Host = String;
 
@@ -28,13 +36,18 @@ pub struct ClusterBackend {
     status: BackendStatus,
     config: BackendConfig,
     token: BackendToken,
-    queue: VecDeque<(ClientToken, Instant, usize)>,
+    queue: VecDeque<(ClientToken, Instant, usize, &'static str, Duration)>,
     pool_token: PoolTokenValue,
     // Following are stored for future backend connections that can be established.
     timeout: usize,
     failure_limit: usize,
-    retry_timeout: usize,
-    poll_registry: Rc<RefCell<Poll>>,
+    retry_policy: RetryPolicy,
+    queue_limits: QueueLimits,
+    concurrency_limiter: Option<ConcurrencyLimiter>,
+    big_value_threshold: usize,
+    slowlog: SlowLog,
+    registry: Registry,
+    timers: Timers,
     num_backends: usize,
     waiting_for_slotsmap_resp: bool,
     cached_backend_shards: Rc<RefCell<Option<Vec<usize>>>>,
@@ -44,18 +57,23 @@ impl ClusterBackend {
         config: BackendConfig,
         token: BackendToken,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
-        poll_registry: &Rc<RefCell<Poll>>,
-        next_cluster_token_value: &mut usize,
+        registry: &Registry,
+        timers: &Timers,
+        next_cluster_index: &mut usize,
         timeout: usize,
         failure_limit: usize,
-        retry_timeout: usize,
+        retry_policy: RetryPolicy,
+        queue_limits: QueueLimits,
+        concurrency_limiter: Option<ConcurrencyLimiter>,
+        big_value_threshold: usize,
+        slowlog: SlowLog,
         pool_token: usize,
         num_backends: usize,
         cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
     ) -> (ClusterBackend, Vec<BackendToken>) {
         let mut cluster = ClusterBackend {
             hostnames: HashMap::new(),
-            slots: Vec::with_capacity(16384),
+            slots: Vec::with_capacity(CLUSTER_SLOTS),
             config: config,
             status: BackendStatus::DISCONNECTED,
             token: token,
@@ -63,8 +81,13 @@ impl ClusterBackend {
             pool_token: pool_token,
             timeout: timeout,
             failure_limit: failure_limit,
-            retry_timeout: retry_timeout,
-            poll_registry: Rc::clone(poll_registry),
+            retry_policy: retry_policy,
+            queue_limits: queue_limits,
+            concurrency_limiter: concurrency_limiter,
+            big_value_threshold: big_value_threshold,
+            slowlog: slowlog,
+            registry: registry.clone(),
+            timers: Rc::clone(timers),
             num_backends: num_backends,
             waiting_for_slotsmap_resp: false,
             cached_backend_shards: Rc::clone(cached_backend_shards),
@@ -76,16 +99,21 @@ impl ClusterBackend {
         let mut all_backend_tokens = Vec::with_capacity(cluster.config.cluster_hosts.len());
 
         for host in &cluster.config.cluster_hosts {
-            let backend_token = Token(*next_cluster_token_value);
-            *next_cluster_token_value += 1;
+            let backend_token = tokens::token(TokenKind::ClusterServer, *next_cluster_index);
+            *next_cluster_index += 1;
             let (single, _) = SingleBackend::new(
                 cluster.config.clone(),
                 host.clone(),
                 backend_token,
-                poll_registry,
+                registry,
+                timers,
                 timeout,
                 failure_limit,
-                retry_timeout,
+                cluster.retry_policy.clone(),
+                queue_limits,
+                cluster.concurrency_limiter.clone(),
+                big_value_threshold,
+                cluster.slowlog.clone(),
                 pool_token,
                 num_backends,
                 &cluster.cached_backend_shards,
@@ -106,11 +134,12 @@ impl ClusterBackend {
         for backend_token in self.hostnames.values() {
             let client_index = convert_token_to_cluster_index(backend_token.0);
             match cluster_backends.get_mut(client_index) {
-                Some((backend, _)) => {
+                Some((backend, owner_token_value)) => {
                     backend.num_backends = new_num_backends;
+                    *owner_token_value = new_token.0;
                 }
                 None => {
-                    panic!("ClusterBackend is referencing a Backend that does not exist! Occurred during reregistering token.");
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred during reregistering token.");
                 }
             };
         }
@@ -125,6 +154,207 @@ impl ClusterBackend {
         return self.status == BackendStatus::READY;
     }
 
+    pub fn disconnect(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        self.status = BackendStatus::DISCONNECTED;
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get_mut(client_index) {
+                Some((backend, _)) => backend.disconnect(),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when disconnecting.");
+                }
+            };
+        }
+    }
+
+    // Closes the connection of each host for good, e.g. when a config switch removes the pool.
+    pub fn close(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        self.status = BackendStatus::DISCONNECTED;
+        for backend_token in self.hostnames.values() {
+            match cluster_backends.get_mut(convert_token_to_cluster_index(backend_token.0)) {
+                Some((backend, _)) => backend.close(clients, completed_clients, stats),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when closing.");
+                }
+            };
+        }
+    }
+
+    pub fn expire_connects(
+        &mut self,
+        now: Instant,
+        connect_timeout: Duration,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut [(SingleBackend, usize)],
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get_mut(client_index) {
+                Some((backend, _)) => backend.expire_connect(now, connect_timeout, clients, completed_clients, stats),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when expiring connects.");
+                }
+            };
+        }
+    }
+
+    pub fn name(&self) -> String {
+        return self.config.cluster_name.clone().unwrap_or_default();
+    }
+
+    pub fn latency(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> LatencyHistogram {
+        let mut latency = LatencyHistogram::new();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => latency.merge(&backend.latency),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting latency.");
+                }
+            };
+        }
+        return latency;
+    }
+
+    pub fn command_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> HashMap<&'static str, CommandStats> {
+        let mut commands: HashMap<&'static str, CommandStats> = HashMap::new();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => {
+                    for (command, command_stats) in &backend.commands {
+                        commands.entry(*command).or_insert_with(CommandStats::new).merge(command_stats);
+                    }
+                }
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting command stats.");
+                }
+            };
+        }
+        return commands;
+    }
+
+    pub fn connection_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> ConnectionStats {
+        let mut connections = ConnectionStats::new();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => connections.merge(&backend.connections),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting connection stats.");
+                }
+            };
+        }
+        return connections;
+    }
+
+    pub fn error_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> ErrorStats {
+        let mut errors = ErrorStats::new();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => errors.merge(&backend.errors),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting error stats.");
+                }
+            };
+        }
+        return errors;
+    }
+
+    pub fn memory_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> MemoryStats {
+        let mut memory = MemoryStats::new();
+        memory.queued_requests = self.queue.len();
+        memory.request_queue_bytes = self.queue.capacity() * std::mem::size_of::<(ClientToken, Instant, usize, &'static str, Duration)>();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => memory.merge(&backend.memory_stats()),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting memory stats.");
+                }
+            };
+        }
+        return memory;
+    }
+
+    pub fn queue_stats(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<(String, QueueStats)> {
+        let mut queues = Vec::with_capacity(self.hostnames.len());
+        for (host, backend_token) in self.hostnames.iter() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => queues.push((host.clone(), backend.queue_stats())),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting queue stats.");
+                }
+            };
+        }
+        queues.sort_by(|a, b| a.0.cmp(&b.0));
+        return queues;
+    }
+
+    pub fn hosts(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<(SocketAddr, Option<Vec<u8>>)> {
+        let mut hosts = Vec::with_capacity(self.hostnames.len());
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => hosts.push(backend.host_and_auth()),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when listing hosts.");
+                }
+            };
+        }
+        hosts.sort_by(|a, b| a.0.cmp(&b.0));
+        return hosts;
+    }
+
+    pub fn slow_requests(&self, cluster_backends: &Vec<(SingleBackend, usize)>) -> Vec<SlowRequest> {
+        let mut slow_requests = Vec::new();
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get(client_index) {
+                Some((backend, _)) => slow_requests.extend(backend.slowlog.entries.iter().cloned()),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when collecting slow requests.");
+                }
+            };
+        }
+        slow_requests.sort_by_key(|slow_request| slow_request.start_time);
+        return slow_requests;
+    }
+
+    pub fn reset_slowlog(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get_mut(client_index) {
+                Some((backend, _)) => backend.slowlog.reset(),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when resetting the slow log.");
+                }
+            };
+        }
+    }
+
+    pub fn reset_stats(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
+        for backend_token in self.hostnames.values() {
+            let client_index = convert_token_to_cluster_index(backend_token.0);
+            match cluster_backends.get_mut(client_index) {
+                Some((backend, _)) => backend.reset_stats(),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when resetting stats.");
+                }
+            };
+        }
+    }
+
     pub fn init_connection(&mut self, cluster_backends: &mut Vec<(SingleBackend, usize)>) {
         for backend_token in self.hostnames.values() {
             let client_index = convert_token_to_cluster_index(backend_token.0);
@@ -133,7 +363,7 @@ impl ClusterBackend {
                     backend.init_connection();
                 }
                 None => {
-                    panic!("ClusterBackend is referencing a Backend that does not exist! Occurred when initializing connections.");
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when initializing connections.");
                 }
             };
             // TODO: Should backend connection fail on the first connection? Perhaps a config option should determine
@@ -147,7 +377,7 @@ impl ClusterBackend {
         &mut self,
         backend_token: BackendToken,
         clients: &mut HashMap<usize, (BufferedClient, usize)>,
-        next_cluster_token_value: &mut usize,
+        next_cluster_index: &mut usize,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
         completed_clients: &mut VecDeque<ClientTokenValue>,
         stats: &mut Stats,
@@ -159,12 +389,12 @@ impl ClusterBackend {
         // Accumulate all potential new cluster backends.
         {
             let mut resp_handler = |response: &[u8]| -> () {
-                handle_unhandled_response(self, response, next_cluster_token_value, &mut additional_cluster_backends, &mut failed_slotsmap);
+                handle_unhandled_response(self, response, next_cluster_index, &mut additional_cluster_backends, &mut failed_slotsmap);
             };
             match cluster_backends.get_mut(cluster_index) {
                 Some((backend, _)) => backend.handle_backend_response(clients, &mut resp_handler, completed_clients, stats),
                 None => {
-                    panic!("ClusterBackend is referencing a Backend that does not exist! Occurred when handling backend response.");
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when handling backend response.");
                 }
             };
         }
@@ -184,9 +414,9 @@ impl ClusterBackend {
                 // Resend slotsmap request if previous request failed.
                 for (_, b_token) in self.hostnames.iter() {
                     let cluster_index = convert_token_to_cluster_index(b_token.0);
-                    let available = {
-                        let cluster_backend = &cluster_backends.get(cluster_index).unwrap().0;
-                        cluster_backend.is_available()
+                    let available = match cluster_backends.get(cluster_index) {
+                        Some((cluster_backend, _)) => cluster_backend.is_available(),
+                        None => false,
                     };
                     if available {
                         if initialize_slotmap(&mut self.queue, *b_token, cluster_backends, stats).is_ok() {
@@ -220,7 +450,12 @@ impl ClusterBackend {
         stats: &mut Stats,
     ) {
         let cluster_index = convert_token_to_cluster_index(backend_token.0);
-        cluster_backends.get_mut(cluster_index).unwrap().0.handle_backend_failure(clients, completed_clients, stats);
+        match cluster_backends.get_mut(cluster_index) {
+            Some((backend, _)) => backend.handle_backend_failure(clients, completed_clients, stats),
+            None => {
+                error!("ClusterBackend is referencing a Backend that does not exist! Occurred when handling backend failure.");
+            }
+        };
     }
 
     // callback when a timeout has occurred.
@@ -233,7 +468,14 @@ impl ClusterBackend {
         stats: &mut Stats,
     ) -> bool {
         let cluster_index = convert_token_to_cluster_index(backend_token.0);
-        cluster_backends.get_mut(cluster_index).unwrap().0.handle_timeout(clients, completed_clients, stats);
+        match cluster_backends.get_mut(cluster_index) {
+            Some((backend, _)) => {
+                backend.handle_timeout(clients, completed_clients, stats);
+            }
+            None => {
+                error!("ClusterBackend is referencing a Backend that does not exist! Occurred when handling timeout.");
+            }
+        };
         if self.queue.len() == 0 {
             return false;
         }
@@ -257,7 +499,7 @@ impl ClusterBackend {
             }
             token => {
                 if token != backend_token {
-                    panic!("ClusterBackend: handle_timeout: Tokens don't match! {:?} and {:?}", token, backend_token);
+                    error!("ClusterBackend: handle_timeout: Tokens don't match! {:?} and {:?}", token, backend_token);
                 }
             }
 
@@ -267,16 +509,23 @@ impl ClusterBackend {
         false
     }
 
-    fn get_shard(&self, message: &[u8])-> BackendToken {
-        let key = extract_key(&message).unwrap();
-        let key = match key {
-            KeyPos::Single(k) => k,
-            _ => panic!("TODO: unsupported Multi and other keypos"),
+    // Returns None if the message has no single key, or if its slot isn't assigned to a known node yet.
+    fn get_shard(&self, message: &[u8]) -> Option<BackendToken> {
+        let key = match extract_key(message) {
+            Ok(KeyPos::Single(k)) => k,
+            // TODO: unsupported Multi and other keypos
+            _ => {
+                debug!("Unable to shard a cluster request without a single key: {:?}", std::str::from_utf8(message));
+                return None;
+            }
         };
         let hash_no = State::<XMODEM>::calculate(key);
-        let shard_no = hash_no % 16384;
-        let hostname = self.slots.get(shard_no as usize).unwrap();
-        return self.hostnames.get(hostname).unwrap().clone();
+        let shard_no = hash_no as usize % CLUSTER_SLOTS;
+        let hostname = match self.slots.get(shard_no) {
+            Some(hostname) => hostname,
+            None => return None,
+        };
+        return self.hostnames.get(hostname).cloned();
     }
 
     pub fn write_message(
@@ -285,28 +534,67 @@ impl ClusterBackend {
         client_token: ClientToken,
         cluster_backends: &mut Vec<(SingleBackend, usize)>,
         request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
         stats: &mut Stats,
-    ) -> Result<(), WriteError> {
+    ) -> Result<bool, WriteError> {
         // get the predicted backend to write to.
-        let backend_token = self.get_shard(message);
+        let backend_token = match self.get_shard(message) {
+            Some(backend_token) => backend_token,
+            None => return Err(WriteError::BackendNotReady),
+        };
         debug!("Cluster Writing to {:?}. Source: {:?}", backend_token, client_token);
         let cluster_index = convert_token_to_cluster_index(backend_token.0);
-        try!(cluster_backends.get_mut(cluster_index).unwrap().0.write_message(message, client_token, request_id, stats));
-        self.queue.push_back(cluster_backends.get(cluster_index).unwrap().0.queue.back().unwrap().clone());
-        return Ok(());
+        let host = match cluster_backends.get_mut(cluster_index) {
+            Some((host, _)) => host,
+            None => {
+                error!("ClusterBackend is referencing a Backend that does not exist! Occurred when writing a request.");
+                return Err(WriteError::BackendNotReady);
+            }
+        };
+        let full = try!(host.write_message(message, client_token, request_id, trace, timeout, stats));
+        if let Some(entry) = host.queue.back() {
+            self.queue.push_back(entry.clone());
+        }
+        return Ok(full);
+    }
+
+    pub fn flush_output(
+        &mut self,
+        clients: &mut HashMap<usize, (BufferedClient, usize)>,
+        cluster_backends: &mut Vec<(SingleBackend, usize)>,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+        stats: &mut Stats,
+    ) {
+        for backend_token in self.hostnames.values() {
+            match cluster_backends.get_mut(convert_token_to_cluster_index(backend_token.0)) {
+                Some((backend, _)) => backend.flush_output(clients, completed_clients, stats),
+                None => {
+                    error!("ClusterBackend is referencing a Backend that does not exist! Occurred when flushing output.");
+                }
+            };
+        }
     }
 }
 
 fn initialize_slotmap(
-    queue: &mut VecDeque<(ClientToken, Instant, usize)>,
+    queue: &mut VecDeque<(ClientToken, Instant, usize, &'static str, Duration)>,
     backend_token: BackendToken,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     stats: &mut Stats,
 ) -> Result<(), WriteError> {
     let cluster_index = convert_token_to_cluster_index(backend_token.0);
-    let ref mut host = cluster_backends.get_mut(cluster_index).unwrap().0;
-    try!(host.write_message(b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nSLOTS\r\n", NULL_TOKEN, (Instant::now(), 0), stats));
-    queue.push_back(host.queue.back().unwrap().clone());
+    let host = match cluster_backends.get_mut(cluster_index) {
+        Some((host, _)) => host,
+        None => {
+            error!("ClusterBackend is referencing a Backend that does not exist! Occurred when requesting the slots map.");
+            return Err(WriteError::BackendNotReady);
+        }
+    };
+    try!(host.write_message(b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nSLOTS\r\n", NULL_TOKEN, (clock::now(), 0), None, None, stats));
+    if let Some(entry) = host.queue.back() {
+        queue.push_back(entry.clone());
+    }
     return Ok(());
 }
 
@@ -330,8 +618,9 @@ fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool
         (BackendStatus::LOADING, BackendStatus::DISCONNECTED) => {}
         (BackendStatus::READY, BackendStatus::DISCONNECTED) => {} // happens when host has been blacked out from too many failures/timeouts.
         _ => {
-            debug!("ClusterBackend failed to change state from {:?} to {:?}", status, target_state);
-            panic!("Failure to change states");
+            // Leave the state as is, rather than take down the proxy over one backend.
+            error!("ClusterBackend failed to change state from {:?} to {:?}", status, target_state);
+            return false;
         }
     }
     debug!("ClusterBackend changed state from {:?} to {:?}", status, target_state);
@@ -342,7 +631,7 @@ fn change_state(status: &mut BackendStatus, target_state: BackendStatus) -> bool
 fn handle_unhandled_response(
     cluster: &mut ClusterBackend,
     response: &[u8],
-    next_cluster_token_value: &mut usize,
+    next_cluster_index: &mut usize,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     failed_slotsmap: &mut bool,
 ) {
@@ -368,15 +657,20 @@ fn handle_unhandled_response(
                     &mut cluster.hostnames,
                     cluster.token,
                     &cluster.config,
-                    &cluster.poll_registry,
+                    &cluster.registry,
+                    &cluster.timers,
                     cluster.timeout,
                     cluster.failure_limit,
-                    cluster.retry_timeout,
+                    &cluster.retry_policy,
+                    cluster.queue_limits,
+                    &cluster.concurrency_limiter,
+                    cluster.big_value_threshold,
+                    &cluster.slowlog,
                     cluster.pool_token,
                     cluster.num_backends,
                     &cluster.cached_backend_shards,
                     addr,
-                    next_cluster_token_value,
+                    next_cluster_index,
                     cluster_backends
                 );
             }
@@ -405,27 +699,37 @@ fn initialize_host(
     hostnames: &mut HashMap<Host, BackendToken>,
     self_token: Token,
     config: &BackendConfig,
-    poll_registry: &Rc<RefCell<Poll>>,
+    registry: &Registry,
+    timers: &Timers,
     timeout: usize,
     failure_limit: usize,
-    retry_timeout: usize,
+    retry_policy: &RetryPolicy,
+    queue_limits: QueueLimits,
+    concurrency_limiter: &Option<ConcurrencyLimiter>,
+    big_value_threshold: usize,
+    slowlog: &SlowLog,
     pool_token: PoolTokenValue,
     num_backends: usize,
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
     host: SocketAddr,
-    next_cluster_token_value: &mut usize,
+    next_cluster_index: &mut usize,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
 ) {
-    let backend_token = Token(*next_cluster_token_value);
-    *next_cluster_token_value += 1;
+    let backend_token = tokens::token(TokenKind::ClusterServer, *next_cluster_index);
+    *next_cluster_index += 1;
         let (single, _) = SingleBackend::new(
             config.clone(),
             host,
             backend_token,
-            poll_registry,
+            registry,
+            timers,
             timeout,
             failure_limit,
-            retry_timeout,
+            retry_policy.clone(),
+            queue_limits,
+            concurrency_limiter.clone(),
+            big_value_threshold,
+            slowlog.clone(),
             pool_token,
             num_backends,
             cached_backend_shards,
@@ -0,0 +1,104 @@
+use redflareproxy::ClientToken;
+
+use hashbrown::HashMap;
+use std::cell::RefCell;
+use std::time::Instant;
+
+/*
+    Request collapsing, for pools with collapse_gets. While a GET of a key is waiting for its backend, GETs of the same
+    key from other clients join it instead of being sent too, and are answered with a copy of its response. A hot key
+    that many clients read at once, e.g. right after it expired, then costs its backend a single GET.
+
+    Only GETs sent to the key's own backend lead or join one, so a client's later requests to that backend are still
+    answered after its GET. A client only joins one once all of its earlier requests are answered, since the GET it
+    joined may be answered before them. Each event loop thread has its own requests in flight, so no locking is needed.
+*/
+pub struct InFlight {
+    // The GET in flight for each key of a pool, by the client that sent it and its request id.
+    leaders: HashMap<(usize, Vec<u8>), (ClientToken, (Instant, usize))>,
+    // The GETs that joined each one in flight, with the pool and key it's for.
+    waiters: HashMap<(ClientToken, (Instant, usize)), ((usize, Vec<u8>), Vec<(ClientToken, (Instant, usize))>)>,
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<InFlight> = RefCell::new(InFlight::new());
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        return InFlight {
+            leaders: HashMap::new(),
+            waiters: HashMap::new(),
+        };
+    }
+
+    // Records a GET that was just sent to its backend, which others of the key can join until it's answered.
+    pub fn lead(&mut self, pool: usize, key: &[u8], client_token: ClientToken, request_id: (Instant, usize)) {
+        let pool_key = (pool, key.to_vec());
+        if self.leaders.contains_key(&pool_key) {
+            return;
+        }
+        self.leaders.insert(pool_key.clone(), (client_token, request_id));
+        self.waiters.insert((client_token, request_id), (pool_key, Vec::new()));
+    }
+
+    // Joins a GET of the key that's in flight, if there is one. Returns false if the GET has to be sent itself.
+    pub fn join(&mut self, pool: usize, key: &[u8], client_token: ClientToken, request_id: (Instant, usize)) -> bool {
+        let leader = match self.leaders.get(&(pool, key.to_vec())) {
+            Some(leader) => *leader,
+            None => return false,
+        };
+        match self.waiters.get_mut(&leader) {
+            Some(&mut (_, ref mut waiters)) => waiters.push((client_token, request_id)),
+            None => return false,
+        }
+        return true;
+    }
+
+    // Removes a GET once it's answered, returning the ones that joined it to be answered the same way.
+    pub fn take_waiters(&mut self, client_token: ClientToken, request_id: (Instant, usize)) -> Vec<(ClientToken, (Instant, usize))> {
+        if self.waiters.is_empty() {
+            return Vec::new();
+        }
+        return match self.waiters.remove(&(client_token, request_id)) {
+            Some((pool_key, waiters)) => {
+                self.leaders.remove(&pool_key);
+                waiters
+            }
+            None => Vec::new(),
+        };
+    }
+}
+
+pub fn lead(pool: usize, key: &[u8], client_token: ClientToken, request_id: (Instant, usize)) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().lead(pool, key, client_token, request_id));
+}
+
+pub fn join(pool: usize, key: &[u8], client_token: ClientToken, request_id: (Instant, usize)) -> bool {
+    return IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().join(pool, key, client_token, request_id));
+}
+
+pub fn take_waiters(client_token: ClientToken, request_id: (Instant, usize)) -> Vec<(ClientToken, (Instant, usize))> {
+    return IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().take_waiters(client_token, request_id));
+}
+
+#[test]
+fn test_in_flight() {
+    use mio::Token;
+
+    let start = Instant::now();
+    let mut in_flight = InFlight::new();
+    assert!(!in_flight.join(0, b"a", Token(1), (start, 0)));
+    in_flight.lead(0, b"a", Token(1), (start, 0));
+    assert!(in_flight.join(0, b"a", Token(2), (start, 0)));
+    assert!(in_flight.join(0, b"a", Token(3), (start, 0)));
+    // Keys of other pools, and other keys, are sent themselves.
+    assert!(!in_flight.join(1, b"a", Token(4), (start, 0)));
+    assert!(!in_flight.join(0, b"b", Token(4), (start, 0)));
+
+    assert!(in_flight.take_waiters(Token(2), (start, 0)).is_empty());
+    assert_eq!(in_flight.take_waiters(Token(1), (start, 0)), vec![(Token(2), (start, 0)), (Token(3), (start, 0))]);
+    // Once answered, the next GET of the key is sent again.
+    assert!(!in_flight.join(0, b"a", Token(2), (start, 0)));
+    assert!(in_flight.take_waiters(Token(1), (start, 0)).is_empty());
+}
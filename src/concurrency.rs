@@ -0,0 +1,128 @@
+use config::BackendPoolConfig;
+use std::time::Duration;
+
+// A response this many times slower than the backend's typical latency is taken as a sign of overload.
+const LATENCY_TOLERANCE: f64 = 2.0;
+// Responses faster than this are never taken as overload, since jitter easily doubles very small latencies.
+const MIN_OVERLOAD_LATENCY_US: f64 = 1000.0;
+// Weight of each response in the typical latency. Small, so that it follows lasting changes but not a sudden overload.
+const BASELINE_WEIGHT: f64 = 0.01;
+// The limit is multiplied by this on overload.
+const BACKOFF: f64 = 0.9;
+
+/*
+    Infers how many requests a backend host can have in flight before it slows down, with additive increase and
+    multiplicative decrease (AIMD). Each response within LATENCY_TOLERANCE of the typical latency raises the limit by
+    1/limit, i.e. by 1 for every limit's worth of responses, up to max_concurrency. A slower response or a timeout
+    lowers it by BACKOFF, at most once per limit's worth of responses, so that the many slow responses of one overload
+    only count once. Requests past the limit are rejected right away, instead of piling onto a struggling backend, e.g.
+    while clients retry during an incident.
+*/
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    limit: f64,
+    max: f64,
+    // Moving average of response latencies, in microseconds. 0 until the first response.
+    baseline_us: f64,
+    // Responses since the limit was last lowered.
+    since_backoff: usize,
+}
+
+impl ConcurrencyLimiter {
+    // Starts at max, so that nothing is rejected until the backend shows signs of overload.
+    pub fn new(max: usize) -> ConcurrencyLimiter {
+        let max = if max < 1 { 1.0 } else { max as f64 };
+        ConcurrencyLimiter {
+            limit: max,
+            max: max,
+            baseline_us: 0.0,
+            since_backoff: 0,
+        }
+    }
+
+    // Limiting is disabled when the pool's max_concurrency is 0.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<ConcurrencyLimiter> {
+        if config.max_concurrency == 0 {
+            return None;
+        }
+        return Some(ConcurrencyLimiter::new(config.max_concurrency));
+    }
+
+    pub fn limit(&self) -> usize {
+        return self.limit as usize;
+    }
+
+    // Whether another request may be sent while in_flight requests are waiting for a response.
+    pub fn allows(&self, in_flight: usize) -> bool {
+        return in_flight < self.limit();
+    }
+
+    pub fn on_response(&mut self, latency: Duration) {
+        let latency_us = latency.as_secs() as f64 * 1_000_000.0 + latency.subsec_micros() as f64;
+        self.since_backoff += 1;
+        if self.baseline_us == 0.0 {
+            self.baseline_us = latency_us;
+        }
+        let overloaded = latency_us > MIN_OVERLOAD_LATENCY_US && latency_us > self.baseline_us * LATENCY_TOLERANCE;
+        self.baseline_us += (latency_us - self.baseline_us) * BASELINE_WEIGHT;
+        if overloaded {
+            self.backoff();
+        } else {
+            self.limit = (self.limit + 1.0 / self.limit).min(self.max);
+        }
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.since_backoff += 1;
+        self.backoff();
+    }
+
+    fn backoff(&mut self) {
+        if (self.since_backoff as f64) < self.limit {
+            return;
+        }
+        self.limit = (self.limit * BACKOFF).max(1.0);
+        self.since_backoff = 0;
+    }
+}
+
+#[test]
+fn test_concurrency_limiter() {
+    let fast = Duration::from_millis(2);
+    let slow = Duration::from_millis(10);
+    let mut limiter = ConcurrencyLimiter::new(10);
+    assert_eq!(limiter.limit(), 10);
+    assert!(limiter.allows(9));
+    assert!(!limiter.allows(10));
+
+    for _ in 0..10 {
+        limiter.on_response(fast);
+    }
+    // Slow responses lower the limit once per limit's worth of responses.
+    limiter.on_response(slow);
+    assert_eq!(limiter.limit(), 9);
+    limiter.on_response(slow);
+    assert_eq!(limiter.limit(), 9);
+    for _ in 0..20 {
+        limiter.on_timeout();
+    }
+    assert_eq!(limiter.limit(), 7);
+
+    // Fast responses raise it again, by 1 per limit's worth of responses, up to the max.
+    for _ in 0..8 {
+        limiter.on_response(fast);
+    }
+    assert_eq!(limiter.limit(), 8);
+    for _ in 0..100 {
+        limiter.on_response(fast);
+    }
+    assert_eq!(limiter.limit(), 10);
+
+    // Small latencies aren't taken as overload, however much they grow.
+    let mut limiter = ConcurrencyLimiter::new(10);
+    limiter.on_response(Duration::from_micros(50));
+    for _ in 0..20 {
+        limiter.on_response(Duration::from_micros(500));
+    }
+    assert_eq!(limiter.limit(), 10);
+}
@@ -2,17 +2,48 @@ use std::net::SocketAddr;
 use std::collections::BTreeMap;
 use toml;
 use std::fs::File;
-use std::io::{Read};
+use std::io::{Read, Write};
 use hash::HashFunction;
+use logging;
+use filter;
+use netacl::NetworkAcl;
+use maintenance::Maintenance;
+use acl::Permissions;
+use namespace;
+use happyeyeballs;
+use errorresponses::ErrorResponses;
+use stats;
 use redflareproxy::ProxyError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub enum Distribution {
     Modula,
     Ketama,
     Random,
 }
 
+// toml can't (de)serialize enum variants, so these are written and read as plain strings.
+impl Deserialize for Distribution {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<Distribution, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Modula" => Ok(Distribution::Modula),
+            "Ketama" => Ok(Distribution::Ketama),
+            "Random" => Ok(Distribution::Random),
+            other => Err(serde::de::Error::custom(format!("Unknown distribution: {}. Expected one of Modula, Ketama, Random", other))),
+        }
+    }
+}
+impl Serialize for Distribution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            Distribution::Modula => "Modula",
+            Distribution::Ketama => "Ketama",
+            Distribution::Random => "Random",
+        })
+    }
+}
+
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
 pub struct RedFlareProxyConfig {
     pub admin: AdminConfig,
@@ -20,11 +51,524 @@ pub struct RedFlareProxyConfig {
 
     #[serde(default)]
     pub enable_advanced_commands: bool,
+
+    // Process options. These only take effect at startup.
+    #[serde(default)]
+    pub daemonize: bool,
+    #[serde(default)]
+    pub pid_file: Option<String>,
+    #[serde(default)]
+    pub stdout_file: Option<String>,
+    #[serde(default)]
+    pub stderr_file: Option<String>,
+
+    // Appends a JSON stats snapshot to this file every stats_snapshot_interval seconds. Disabled when unset.
+    #[serde(default)]
+    pub stats_snapshot_file: Option<String>,
+    #[serde(default = "default_stats_snapshot_interval")]
+    pub stats_snapshot_interval: usize,
+
+    // Sampled request traces are exported to this OTLP/HTTP collector (host:port). Disabled when unset.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default = "default_otlp_service_name")]
+    pub otlp_service_name: String,
+
+    // Records sensitive commands to this file, with the client that issued them. Disabled when unset.
+    #[serde(default)]
+    pub audit_log_file: Option<String>,
+    // Commands that are recorded to the audit log.
+    #[serde(default = "default_audit_commands")]
+    pub audit_commands: Vec<String>,
+    // DEL and UNLINK are recorded when a key matches one of these glob patterns, e.g. "session:*".
+    #[serde(default)]
+    pub audit_key_patterns: Vec<String>,
+
+    // Directory that CAPTURE writes its files to.
+    #[serde(default = "default_capture_dir")]
+    pub capture_dir: String,
+    // Default size limit of a CAPTURE file, in bytes.
+    #[serde(default = "default_capture_max_bytes")]
+    pub capture_max_bytes: usize,
+
+    // Size of the read buffer of each client and backend connection. Buffers are reused across connections.
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    // Maximum number of free buffers kept for reuse by each worker. Buffers returned beyond this are deallocated.
+    #[serde(default = "default_buffer_pool_size")]
+    pub buffer_pool_size: usize,
+    // Bytes of connection buffers and request queues allowed across every worker. Once reached, new client
+    // connections and requests are rejected until usage drops. Leave headroom below the OOM killer's limit, since
+    // usage is measured periodically. 0 disables the limit.
+    #[serde(default)]
+    pub memory_limit: usize,
+
+    // Number of event loop threads. Each worker accepts its own share of client connections on the pool ports, and
+    // has its own backend connections. The totals in STATS cover every worker. Admin commands that would only cover
+    // worker 0, which serves the admin port, e.g. POOLSTATS, LOGLEVEL and SWITCHCONFIG, aren't supported with more
+    // than one worker.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    // CPUs to pin the workers to, e.g. [2, 3, 4, 5] to keep them off the CPUs handling interrupts. Worker i runs on
+    // worker_cpus[i % len]. Each worker's buffers are then allocated on its CPU's NUMA node. Linux only. Not pinned
+    // when empty.
+    #[serde(default)]
+    pub worker_cpus: Vec<usize>,
+
+    // Milliseconds to wait on SIGTERM, SIGINT or SHUTDOWN for in-flight requests to be answered, before exiting anyway.
+    // The pool ports stop accepting connections as soon as shutdown begins.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: usize,
+
+    // Event loop tuning.
+    // Events each worker takes from the OS per poll. Only takes effect at startup.
+    #[serde(default = "default_event_capacity")]
+    pub event_capacity: usize,
+    // Block waits for events, timers or shutdown. Spin never waits, for the lowest latency at the cost of a busy core
+    // per worker.
+    #[serde(default = "default_poll_strategy")]
+    pub poll_strategy: PollStrategy,
+    // Longest a blocked worker waits, in milliseconds, so that it still checks for shutdown and restarts if a wake up is
+    // missed. 0 waits until the next event or timer.
+    #[serde(default = "default_max_poll_timeout")]
+    pub max_poll_timeout: usize,
+    // Events handled per iteration. The rest are handled in the next iterations, after timers and the clients left
+    // to read, so that a burst of events doesn't delay them. 0 handles every event right away.
+    #[serde(default)]
+    pub max_events_per_iteration: usize,
+    // Writes the requests buffered for each backend once per iteration, after every ready client has been read, rather
+    // than after each client. Pipelined requests from many clients then share a write to their backend.
+    #[serde(default)]
+    pub batch_dispatch: bool,
+    // Milliseconds an iteration of a worker's event loop may take before the watchdog logs the stall. 0 disables the
+    // watchdog.
+    #[serde(default = "default_watchdog_threshold")]
+    pub watchdog_threshold: usize,
+    // Milliseconds an iteration of a worker's event loop may take before the worker stops accepting clients on the pool
+    // ports, leaving new connections in the listen backlog until it catches up. 0 disables it. See overload.rs.
+    #[serde(default)]
+    pub overload_latency: usize,
+    // Requests in flight to a worker's backends at which it stops accepting clients on the pool ports. 0 disables it.
+    #[serde(default)]
+    pub overload_queue_depth: usize,
+
+    // Log levels for individual components (admin, backend, cluster, protocol), overriding --log_level. e.g.:
+    // [log_levels]
+    // cluster = "DEBUG"
+    #[serde(default)]
+    pub log_levels: BTreeMap<String, String>,
+
+    // Accepts connections that iptables redirects to the proxy, for the pools of their original destinations.
+    #[serde(default)]
+    pub transparent: Option<TransparentConfig>,
+}
+
+/*
+    The listener of transparent proxy mode, which connections to redis are redirected to by iptables instead of
+    being made to the proxy, e.g.:
+    [transparent]
+    listen = "0.0.0.0:15379"
+    mode = "Redirect"
+    Only takes effect at startup. See transparent.rs.
+*/
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
+pub struct TransparentConfig {
+    pub listen: SocketAddr,
+
+    #[serde(default = "default_transparent_mode")]
+    pub mode: TransparentMode,
+}
+
+fn default_transparent_mode() -> TransparentMode {
+    return TransparentMode::Redirect;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransparentMode {
+    // Connections redirected with a REDIRECT or DNAT rule. Their original destination is read with SO_ORIGINAL_DST.
+    Redirect,
+    // Connections diverted with a TPROXY rule, which keep their original destination as their local address.
+    Tproxy,
+}
+
+impl Deserialize for TransparentMode {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<TransparentMode, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Redirect" => Ok(TransparentMode::Redirect),
+            "Tproxy" => Ok(TransparentMode::Tproxy),
+            other => Err(serde::de::Error::custom(format!("Unknown transparent mode: {}. Expected one of Redirect, Tproxy", other))),
+        }
+    }
+}
+impl Serialize for TransparentMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            TransparentMode::Redirect => "Redirect",
+            TransparentMode::Tproxy => "Tproxy",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum PollStrategy {
+    Block,
+    Spin,
+}
+
+impl Deserialize for PollStrategy {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<PollStrategy, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Block" => Ok(PollStrategy::Block),
+            "Spin" => Ok(PollStrategy::Spin),
+            other => Err(serde::de::Error::custom(format!("Unknown poll_strategy: {}. Expected one of Block, Spin", other))),
+        }
+    }
+}
+impl Serialize for PollStrategy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            PollStrategy::Block => "Block",
+            PollStrategy::Spin => "Spin",
+        })
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub enum RetryStrategy {
+    Fixed,
+    Exponential,
+    DecorrelatedJitter,
 }
 
+impl Deserialize for RetryStrategy {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<RetryStrategy, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Fixed" => Ok(RetryStrategy::Fixed),
+            "Exponential" => Ok(RetryStrategy::Exponential),
+            "DecorrelatedJitter" => Ok(RetryStrategy::DecorrelatedJitter),
+            other => Err(serde::de::Error::custom(format!("Unknown retry_strategy: {}. Expected one of Fixed, Exponential, DecorrelatedJitter", other))),
+        }
+    }
+}
+impl Serialize for RetryStrategy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            RetryStrategy::Fixed => "Fixed",
+            RetryStrategy::Exponential => "Exponential",
+            RetryStrategy::DecorrelatedJitter => "DecorrelatedJitter",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum CacheInvalidation {
+    // Cached responses are only refreshed once they expire.
+    Off,
+    // CLIENT TRACKING in broadcasting mode. Requires redis 6.
+    Tracking,
+    // Keyspace notifications, which the backends must have enabled with notify-keyspace-events.
+    Keyspace,
+}
+
+impl Deserialize for CacheInvalidation {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<CacheInvalidation, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Off" => Ok(CacheInvalidation::Off),
+            "Tracking" => Ok(CacheInvalidation::Tracking),
+            "Keyspace" => Ok(CacheInvalidation::Keyspace),
+            other => Err(serde::de::Error::custom(format!("Unknown cache_invalidation: {}. Expected one of Off, Tracking, Keyspace", other))),
+        }
+    }
+}
+impl Serialize for CacheInvalidation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            CacheInvalidation::Off => "Off",
+            CacheInvalidation::Tracking => "Tracking",
+            CacheInvalidation::Keyspace => "Keyspace",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StalePolicy {
+    // Stale responses are only counted, as stale_hits in the cache stats.
+    Count,
+    // Each stale response is logged with its key too.
+    Log,
+}
+
+impl Deserialize for StalePolicy {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<StalePolicy, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Count" => Ok(StalePolicy::Count),
+            "Log" => Ok(StalePolicy::Log),
+            other => Err(serde::de::Error::custom(format!("Unknown cache_stale_policy: {}. Expected one of Count, Log", other))),
+        }
+    }
+}
+impl Serialize for StalePolicy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            StalePolicy::Count => "Count",
+            StalePolicy::Log => "Log",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Failback {
+    // Clients go back to the primary once all of its backends have been available for failback_delay.
+    Auto,
+    // Clients stay on the standby until FAILOVER <pool> PRIMARY.
+    Manual,
+}
+
+impl Deserialize for Failback {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<Failback, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Auto" => Ok(Failback::Auto),
+            "Manual" => Ok(Failback::Manual),
+            other => Err(serde::de::Error::custom(format!("Unknown failback: {}. Expected one of Auto, Manual", other))),
+        }
+    }
+}
+impl Serialize for Failback {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            Failback::Auto => "Auto",
+            Failback::Manual => "Manual",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum DiscoverySource {
+    // The passing instances of a service, from the health API of a Consul agent.
+    Consul,
+    // The values of the keys under a prefix, from the v3 JSON gateway of etcd.
+    Etcd,
+    // The targets of a DNS SRV record, e.g. of a headless Kubernetes service.
+    Srv,
+    // The ready endpoints of a Kubernetes service, from its EndpointSlices, through kubectl proxy.
+    Kubernetes,
+}
+
+impl Deserialize for DiscoverySource {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<DiscoverySource, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Consul" => Ok(DiscoverySource::Consul),
+            "Etcd" => Ok(DiscoverySource::Etcd),
+            "Srv" => Ok(DiscoverySource::Srv),
+            "Kubernetes" => Ok(DiscoverySource::Kubernetes),
+            other => Err(serde::de::Error::custom(format!("Unknown discovery source: {}. Expected one of Consul, Etcd, Srv, Kubernetes", other))),
+        }
+    }
+}
+impl Serialize for DiscoverySource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            DiscoverySource::Consul => "Consul",
+            DiscoverySource::Etcd => "Etcd",
+            DiscoverySource::Srv => "Srv",
+            DiscoverySource::Kubernetes => "Kubernetes",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum LimitAction {
+    // Requests over the limit are answered with an error.
+    Reject,
+    // Requests over the limit are left unread until they're within it, which slows the client down through TCP.
+    Delay,
+}
+
+impl Deserialize for LimitAction {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<LimitAction, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Reject" => Ok(LimitAction::Reject),
+            "Delay" => Ok(LimitAction::Delay),
+            other => Err(serde::de::Error::custom(format!("Unknown client_limit_action: {}. Expected one of Reject, Delay", other))),
+        }
+    }
+}
+impl Serialize for LimitAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            LimitAction::Reject => "Reject",
+            LimitAction::Delay => "Delay",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum QuotaEnforcement {
+    // Requests over a user's quota are only counted, and logged once until the user is within it again.
+    Log,
+    // The user's clients aren't read from until the user is within its quota again.
+    Throttle,
+    // Requests over the quota are answered with an error.
+    Reject,
+}
+
+impl Deserialize for QuotaEnforcement {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<QuotaEnforcement, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Log" => Ok(QuotaEnforcement::Log),
+            "Throttle" => Ok(QuotaEnforcement::Throttle),
+            "Reject" => Ok(QuotaEnforcement::Reject),
+            other => Err(serde::de::Error::custom(format!("Unknown quota_enforcement: {}. Expected one of Log, Throttle, Reject", other))),
+        }
+    }
+}
+impl Serialize for QuotaEnforcement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            QuotaEnforcement::Log => "Log",
+            QuotaEnforcement::Throttle => "Throttle",
+            QuotaEnforcement::Reject => "Reject",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub enum MaintenanceMode {
+    // New client connections are refused with the window's message. Clients already connected are still served.
+    Drain,
+    // Every request is answered with the window's message.
+    Deny,
+}
+
+impl Deserialize for MaintenanceMode {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<MaintenanceMode, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Drain" => Ok(MaintenanceMode::Drain),
+            "Deny" => Ok(MaintenanceMode::Deny),
+            other => Err(serde::de::Error::custom(format!("Unknown maintenance mode: {}. Expected one of Drain, Deny", other))),
+        }
+    }
+}
+impl Serialize for MaintenanceMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            MaintenanceMode::Drain => "Drain",
+            MaintenanceMode::Deny => "Deny",
+        })
+    }
+}
+
+fn default_stats_snapshot_interval() -> usize {
+    return 60;
+}
+fn default_otlp_service_name() -> String {
+    return "redflare".to_owned();
+}
+fn default_capture_dir() -> String {
+    return "/tmp".to_owned();
+}
+fn default_capture_max_bytes() -> usize {
+    return 100 * 1024 * 1024;
+}
+fn default_buffer_size() -> usize {
+    return 16384;
+}
+fn default_buffer_pool_size() -> usize {
+    return 1024;
+}
+fn default_workers() -> usize {
+    return 1;
+}
+fn default_shutdown_timeout() -> usize {
+    return 5000;
+}
+fn default_event_capacity() -> usize {
+    return 1024;
+}
+fn default_poll_strategy() -> PollStrategy {
+    return PollStrategy::Block;
+}
+fn default_max_poll_timeout() -> usize {
+    return 1000;
+}
+fn default_watchdog_threshold() -> usize {
+    return 1000;
+}
+fn default_audit_commands() -> Vec<String> {
+    return vec!["FLUSHALL".to_owned(), "FLUSHDB".to_owned(), "CONFIG".to_owned()];
+}
 fn default_retry_timeout() -> usize {
     return 1000;
 }
+fn default_retry_strategy() -> RetryStrategy {
+    return RetryStrategy::Fixed;
+}
+fn default_max_retry_timeout() -> usize {
+    return 30000;
+}
+fn default_hotkey_top_k() -> usize {
+    return 10;
+}
+fn default_cache_ttl() -> usize {
+    return 1000;
+}
+fn default_cache_invalidation() -> CacheInvalidation {
+    return CacheInvalidation::Off;
+}
+fn default_cache_stale_policy() -> StalePolicy {
+    return StalePolicy::Count;
+}
+fn default_big_value_threshold() -> usize {
+    return 1048576;
+}
+fn default_slowlog_max_len() -> usize {
+    return 128;
+}
+fn default_backend_queue_limit() -> usize {
+    return 4096;
+}
+fn default_backend_output_limit() -> usize {
+    return 1048576;
+}
+fn default_client_burst() -> usize {
+    return 128;
+}
+fn default_connect_timeout() -> usize {
+    return 3000;
+}
+fn default_outage_buffer_size() -> usize {
+    return 1024;
+}
+fn default_quarantine_duration() -> usize {
+    return 60000;
+}
+fn default_maintenance_mode() -> MaintenanceMode {
+    return MaintenanceMode::Deny;
+}
+fn default_maintenance_message() -> String {
+    return "Pool is under maintenance".to_owned();
+}
+fn default_failback() -> Failback {
+    return Failback::Auto;
+}
+fn default_failback_delay() -> usize {
+    return 10000;
+}
+fn default_discovery_interval() -> usize {
+    return 5000;
+}
+fn default_chaos_error() -> String {
+    return "ERR Injected fault".to_owned();
+}
+fn default_limit_action() -> LimitAction {
+    return LimitAction::Reject;
+}
+fn default_quota_enforcement() -> QuotaEnforcement {
+    return QuotaEnforcement::Reject;
+}
+fn default_admin_idle_timeout() -> usize {
+    return 300000;
+}
 fn default_distribution() -> Distribution {
     return Distribution::Modula;
 }
@@ -41,15 +585,36 @@ pub struct BackendPoolConfig {
 
     pub servers: Vec<BackendConfig>,
 
+    /*
+        Keeps the servers in sync with a discovery service. The servers are served until the first lookup, and the
+        first of them is the template of every discovered one. See discovery.rs.
+    */
+    #[serde(default)]
+    pub discovery: Option<DiscoveryConfig>,
+
     #[serde(default)]
     pub timeout: usize,
 
+    /*
+        Lets clients set the timeout of their next request with DEADLINE <milliseconds>, instead of the pool's timeout.
+        Latency-sensitive clients can fail fast, and batch jobs can wait longer.
+    */
+    #[serde(default)]
+    pub client_deadlines: bool,
+
     #[serde(default)]
     pub failure_limit: usize,
 
     #[serde(default = "default_retry_timeout")]
     pub retry_timeout: usize,
 
+    // How the retry timeout grows after consecutive failures. Capped by max_retry_timeout.
+    #[serde(default = "default_retry_strategy")]
+    pub retry_strategy: RetryStrategy,
+
+    #[serde(default = "default_max_retry_timeout")]
+    pub max_retry_timeout: usize,
+
     #[serde(default)]
     pub auto_eject_hosts: bool,
 
@@ -64,12 +629,363 @@ pub struct BackendPoolConfig {
 
     #[serde(default = "default_warm_sockets")]
     pub warm_sockets: bool,
+
+    // Prepended to every metric name reported for this pool.
+    #[serde(default)]
+    pub metric_prefix: String,
+
+    // Extra labels (e.g. team, service) attached to every metric reported for this pool. Their names can't be ones the
+    // proxy sets itself, e.g. pool or backend.
+    #[serde(default)]
+    pub metric_labels: BTreeMap<String, String>,
+
+    // Hot key detection samples one in every hotkey_sample_rate keys. 0 disables it.
+    #[serde(default)]
+    pub hotkey_sample_rate: usize,
+
+    #[serde(default = "default_hotkey_top_k")]
+    pub hotkey_top_k: usize,
+
+    /*
+        Milliseconds to cache the GET responses of the keys hot key detection currently has in its top list, so that a
+        single hot key doesn't overload its shard. Only applies when cache_max_bytes is 0, since otherwise every GET is
+        cached for cache_ttl. Requires hotkey_sample_rate. 0 disables it.
+    */
+    #[serde(default)]
+    pub hotkey_cache_ttl: usize,
+
+    // Requests and responses larger than this many bytes are logged as warnings. 0 disables the warning.
+    #[serde(default = "default_big_value_threshold")]
+    pub big_value_threshold: usize,
+
+    // Traces one in every trace_sample_rate single key requests. 0 disables tracing.
+    #[serde(default)]
+    pub trace_sample_rate: usize,
+
+    // Requests that take longer than this many microseconds are kept in the slow log, shown by SLOWLOG. 0 disables it.
+    #[serde(default)]
+    pub slowlog_threshold: usize,
+
+    // How many slow requests are kept per backend.
+    #[serde(default = "default_slowlog_max_len")]
+    pub slowlog_max_len: usize,
+
+    // Keeps up to this many bytes of each slow request, with passwords masked. 0 keeps just the command name.
+    #[serde(default)]
+    pub slowlog_payload_bytes: usize,
+
+    /*
+        Once a backend has this many unanswered requests, or this many bytes of requests waiting to be written, clients
+        sending to it are no longer read from until it catches up, so that they are slowed down by TCP instead of
+        being buffered by the proxy. 0 disables the limit.
+    */
+    #[serde(default = "default_backend_queue_limit")]
+    pub backend_queue_limit: usize,
+    #[serde(default = "default_backend_output_limit")]
+    pub backend_output_limit: usize,
+
+    /*
+        Upper bound on unanswered requests per backend host. Within it, the limit adapts to the backend's latency: it
+        drops while responses slow down and grows back while they're fast. Requests past the limit are answered with an
+        error right away. 0 disables the limit.
+    */
+    #[serde(default)]
+    pub max_concurrency: usize,
+
+    /*
+        Requests read from a client in a row before moving on to the pool's other clients, so that a client pipelining
+        thousands of requests doesn't starve the others sharing its backends. The rest of its pipeline is read on a
+        later turn. 0 reads each client until it has nothing left.
+    */
+    #[serde(default = "default_client_burst")]
+    pub client_burst: usize,
+
+    /*
+        Requests per second that each client connection may send, with bursts of up to a second's worth. 0 disables
+        the limit. Applies to the connections accepted after a config switch.
+    */
+    #[serde(default)]
+    pub client_rate_limit: usize,
+
+    // Requests that each client connection may have waiting for a response. 0 disables the limit.
+    #[serde(default)]
+    pub client_max_inflight: usize,
+
+    /*
+        Limits on the size of the pool's requests, which are rejected with an error as soon as their headers show
+        they're over, before the rest of them is buffered, and their client is disconnected. 0 is no limit, past the
+        proxy's buffer_size for a whole request.
+    */
+    // Bytes of a whole request.
+    #[serde(default)]
+    pub max_request_size: usize,
+    // Arguments of a request, including the command.
+    #[serde(default)]
+    pub max_multibulk_length: usize,
+    // Bytes of each argument.
+    #[serde(default)]
+    pub max_bulk_length: usize,
+
+    // What happens to a client's requests over client_rate_limit or client_max_inflight. See ratelimit.rs.
+    #[serde(default = "default_limit_action")]
+    pub client_limit_action: LimitAction,
+
+    /*
+        Throttles the requests to keys matching a pattern, across all of the pool's clients, e.g. writes to counter:*.
+        Requests over the limit are answered with an error. e.g.:
+        key_rate_limits = [ { pattern = "counter:*", rate = 100, writes_only = true } ]
+    */
+    #[serde(default)]
+    pub key_rate_limits: Vec<KeyRateLimitConfig>,
+
+    /*
+        Identities that the pool's clients must AUTH as before sending anything else, each with its own quotas and
+        permissions. The proxy answers AUTH itself. See quota.rs and acl.rs. e.g.:
+        [pools.pool1.users.search]
+        password = "secret"
+        request_quota = 1000
+        commands = ["+@read"]
+        keys = ["search:*"]
+        namespace = "search"
+    */
+    #[serde(default)]
+    pub users: BTreeMap<String, UserConfig>,
+
+    // What happens to the requests of a user over one of its quotas.
+    #[serde(default = "default_quota_enforcement")]
+    pub quota_enforcement: QuotaEnforcement,
+
+    /*
+        Networks in CIDR notation that clients may connect from. Empty allows every address not in deny_clients.
+        Refused connections are closed as soon as they're accepted. See netacl.rs. e.g.:
+        allow_clients = ["10.0.0.0/8", "fd00::/8"]
+        deny_clients = ["10.0.99.0/24"]
+    */
+    #[serde(default)]
+    pub allow_clients: Vec<String>,
+
+    // Networks in CIDR notation that clients may not connect from, even if they're in allow_clients.
+    #[serde(default)]
+    pub deny_clients: Vec<String>,
+
+    /*
+        Expects every client connection to start with a PROXY protocol header, version 1 or 2, e.g. behind an L4 load
+        balancer. The client address it carries is used instead of the load balancer's, including for allow_clients
+        and deny_clients. Connections without a valid header are closed.
+    */
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    /*
+        Invalid requests a client may send before it's closed and its address is quarantined, e.g. a port scanner or a
+        peer speaking another protocol. New connections from a quarantined address are refused like those from
+        deny_clients. 0 never quarantines. See quarantine.rs.
+    */
+    #[serde(default)]
+    pub quarantine_threshold: usize,
+
+    // Milliseconds an address stays quarantined for.
+    #[serde(default = "default_quarantine_duration")]
+    pub quarantine_duration: usize,
+
+    /*
+        Recurring windows during which the pool is under maintenance, in UTC. e.g.:
+        [[pools.pool1.maintenance]]
+        days = ["Sat", "Sun"]
+        start = "02:00"
+        end = "04:00"
+        mode = "Drain"
+        message = "Cache maintenance until 04:00 UTC"
+        See maintenance.rs.
+    */
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindowConfig>,
+
+    /*
+        Original destinations, e.g. "10.0.0.5:6379", whose connections are handed to this pool when iptables redirects
+        them to the proxy's transparent listener.
+    */
+    #[serde(default)]
+    pub original_destinations: Vec<SocketAddr>,
+
+    // Milliseconds a client may go without sending a request or receiving a response before it's closed. 0 never closes.
+    #[serde(default)]
+    pub client_idle_timeout: usize,
+
+    // Milliseconds to wait for a backend connection to be established, before retrying it.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: usize,
+
+    // Lets clients of the pool send their first request with their SYN, with TCP Fast Open. See fastopen.rs.
+    #[serde(default)]
+    pub tcp_fastopen: bool,
+
+    /*
+        Sends the first request of each backend connection with its SYN, with TCP Fast Open, e.g. AUTH after a
+        reconnect. Neither a redis cluster's hosts nor the racing connections to a backend hostname's addresses use it.
+        See fastopen.rs.
+    */
+    #[serde(default)]
+    pub backend_tcp_fastopen: bool,
+
+    /*
+        Rules that rewrite the pool's requests before they're routed, in order, e.g.:
+        rewrite_rules = [
+            { command = "HMSET", rename = "HSET" },
+            { command = "SET", default_ttl = 3600 },
+            { command = "SCAN", max_count = 1000 },
+        ]
+        See rewrite.rs.
+    */
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRuleConfig>,
+
+    // Lua script that filters the pool's requests and responses. See luafilter.rs. Requires the lua feature.
+    #[serde(default)]
+    pub lua_filter: Option<String>,
+
+    // Sandboxed WASM module that filters the pool's requests. See wasmfilter.rs for its ABI. Requires the wasm feature.
+    #[serde(default)]
+    pub wasm_filter: Option<String>,
+
+    // File of the keys that string values are encrypted with. See encryption.rs. Requires the encryption feature.
+    #[serde(default)]
+    pub encryption_keys: Option<String>,
+
+    // Id of the key in encryption_keys that new values are encrypted with. Defaults to the file's last key.
+    #[serde(default)]
+    pub encryption_key_id: Option<String>,
+
+    // Glob patterns of the keys whose values are encrypted. Empty encrypts the values of every key.
+    #[serde(default)]
+    pub encrypted_keys: Vec<String>,
+
+    // Caches GET responses in the proxy, using up to this many bytes. See cache.rs. 0 disables the cache.
+    #[serde(default)]
+    pub cache_max_bytes: usize,
+
+    // Milliseconds a cached response is served for, before the key is read from the backend again.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: usize,
+
+    // Evicts cached keys as soon as the backends report that they changed. See invalidation.rs.
+    #[serde(default = "default_cache_invalidation")]
+    pub cache_invalidation: CacheInvalidation,
+
+    /*
+        Milliseconds an expired cached response is kept for past cache_ttl, to answer GETs of its key while the key's
+        backend is down instead of failing them. For workloads that prefer stale values to errors. 0 disables it.
+    */
+    #[serde(default)]
+    pub cache_stale_ttl: usize,
+
+    // How responses served stale are flagged.
+    #[serde(default = "default_cache_stale_policy")]
+    pub cache_stale_policy: StalePolicy,
+
+    /*
+        While a backend is down, its read commands are sent to its replicas instead, and only writes fail. Backends
+        ejected by auto_eject_hosts aren't sent anything. See replica.rs.
+    */
+    #[serde(default)]
+    pub replica_fallback: bool,
+
+    /*
+        Read/write splitting: read commands go to one of the backend's replicas while it's up too, and only writes go to
+        the backend itself. Reads go to the backend while none of its replicas are available.
+    */
+    #[serde(default)]
+    pub read_from_replicas: bool,
+
+    /*
+        Milliseconds after a client writes a key during which its reads of the key go to the backend instead of a
+        replica, so that it reads its own write even if the replicas lag behind. Requires read_from_replicas. 0
+        disables it. See readyourwrites.rs.
+    */
+    #[serde(default)]
+    pub read_your_writes: usize,
+
+    /*
+        Milliseconds that requests to a backend which is reconnecting, e.g. through a redis restart, are held for
+        instead of failing right away. They're written once it's ready again, and fail as before if it isn't within
+        that time. The time held counts toward the timeout, which it must be less than. 0 disables it. See
+        outagebuffer.rs.
+    */
+    #[serde(default)]
+    pub outage_buffer_timeout: usize,
+
+    // Requests held per backend with outage_buffer_timeout. Requests past it fail right away.
+    #[serde(default = "default_outage_buffer_size")]
+    pub outage_buffer_size: usize,
+
+    /*
+        Milliseconds after which a read that a backend hasn't answered yet is also sent to one of its replicas, e.g. the
+        backends' p95 latency. Whichever answers first is returned, and the other response is dropped. 0 disables it.
+    */
+    #[serde(default)]
+    pub hedge_delay: usize,
+
+    /*
+        Collapses concurrent GETs of a key from different clients into one backend request, whose response answers all
+        of them, e.g. for hot keys that many clients read right after they expire. See collapse.rs.
+    */
+    #[serde(default)]
+    pub collapse_gets: bool,
+
+    /*
+        Writes every mutation to each backend's secondary as well, e.g. while migrating to another datacenter. Clients
+        only get the primary's response, and the secondary's errors and differing responses are counted. See
+        mirror.rs.
+    */
+    #[serde(default)]
+    pub dual_write: bool,
+
+    // Percent of the pool's single key requests that are also sent to its canary server. See BackendConfig::canary.
+    #[serde(default)]
+    pub canary_percent: usize,
+
+    /*
+        Another pool that the pool's clients are sent to while none of the pool's own backends are available, e.g. one
+        in another datacenter. The standby keeps its connections open while idle. See standby.rs.
+    */
+    #[serde(default)]
+    pub standby: Option<String>,
+
+    // When clients go back from the standby to the pool.
+    #[serde(default = "default_failback")]
+    pub failback: Failback,
+
+    // Milliseconds all of the pool's backends must have been available again before an Auto failback.
+    #[serde(default = "default_failback_delay")]
+    pub failback_delay: usize,
+
+    // Faults injected into the traffic of the pool's backends, to test how applications handle them. See chaos.rs.
+    #[serde(default)]
+    pub chaos: Option<ChaosConfig>,
+
+    /*
+        Replaces the errors that the proxy answers the pool's requests with itself, e.g.:
+        [pools.pool1.error_responses]
+        codes = true
+        timeout = "TIMEOUT Request timed out, safe to retry"
+        See errorresponses.rs.
+    */
+    #[serde(default)]
+    pub error_responses: Option<ErrorResponsesConfig>,
 }
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
 pub struct BackendConfig {
     #[serde(default)]
     pub host: Option<SocketAddr>,
 
+    /*
+        Name and port of the backend, e.g. redis.internal:6379, instead of a fixed host. It's resolved once the pool
+        starts, and when it has both IPv6 and IPv4 addresses, they're connected to with happy eyeballs. See
+        happyeyeballs.rs.
+    */
+    #[serde(default)]
+    pub hostname: Option<String>,
+
     // How to handle RedisCluster list of hosts?
 
     pub weight: usize,
@@ -79,6 +995,10 @@ pub struct BackendConfig {
     #[serde(default)]
     pub auth: String,
 
+    // Redis 6 ACL user that the proxy logs in to the backend as, with auth as its password. Requires auth.
+    #[serde(default)]
+    pub username: Option<String>,
+
     // Used for redis cluster.
     #[serde(default)]
     pub use_cluster: bool,
@@ -88,14 +1008,249 @@ pub struct BackendConfig {
 
     #[serde(default)]
     pub cluster_hosts: Vec<SocketAddr>,
+
+    // Used to find the backend through redis sentinel, instead of a fixed host.
+    #[serde(default)]
+    pub sentinel_master: Option<String>,
+
+    #[serde(default)]
+    pub sentinel_hosts: Vec<SocketAddr>,
+
+    // Replicas of the backend, which answer its reads while it's down with replica_fallback, and its hedged reads.
+    #[serde(default)]
+    pub replicas: Vec<SocketAddr>,
+
+    // Host that the backend's mutations are also written to, with dual_write.
+    #[serde(default)]
+    pub secondary: Option<SocketAddr>,
+
+    /*
+        Marks the pool's canary, e.g. a host running a new Redis version. It isn't sharded any keys. Instead, it gets a
+        copy of canary_percent of the requests, and its responses are compared with the real ones rather than returned.
+    */
+    #[serde(default)]
+    pub canary: bool,
+}
+
+// A recurring maintenance window of a pool.
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct MaintenanceWindowConfig {
+    // Days the window starts on, as Mon, Tue, ... Sun. Empty is every day.
+    #[serde(default)]
+    pub days: Vec<String>,
+
+    // Times of day in UTC, as HH:MM. A window that ends at or before its start ends on the next day.
+    pub start: String,
+    pub end: String,
+
+    #[serde(default = "default_maintenance_mode")]
+    pub mode: MaintenanceMode,
+
+    // Sent to clients as -ERROR: <message>.
+    #[serde(default = "default_maintenance_message")]
+    pub message: String,
+}
+
+/*
+    A user of a pool. Clients authenticate as it with AUTH <user> <password>, or with AUTH <password> for the user
+    named default. Quotas are shared by all of the user's connections to a worker.
+*/
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct UserConfig {
+    pub password: String,
+
+    // Requests per second. 0 is unlimited.
+    #[serde(default)]
+    pub request_quota: usize,
+
+    // Bytes per second of requests and responses. 0 is unlimited.
+    #[serde(default)]
+    pub bandwidth_quota: usize,
+
+    /*
+        Rules of the commands the user may run, in the style of Redis 6 ACLs, e.g. ["+@read", "+set", "-@dangerous"].
+        The last rule that matches a command decides. Empty allows every command. See acl.rs for the categories.
+    */
+    #[serde(default)]
+    pub commands: Vec<String>,
+
+    // Glob patterns of the keys the user may access, e.g. ["cache:*"]. Empty allows every key.
+    #[serde(default)]
+    pub keys: Vec<String>,
+
+    /*
+        Keeps the user's keys apart from the other users', by prefixing them with the namespace and a ':', e.g.
+        namespace = "team1" sends GET a as GET team1:a. Empty sees every key. See namespace.rs.
+    */
+    #[serde(default)]
+    pub namespace: String,
+}
+
+// Where a pool's backends are discovered. See discovery.rs.
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct DiscoveryConfig {
+    pub source: DiscoverySource,
+
+    /*
+        host:port of the Consul agent, the etcd gateway, the nameserver, or kubectl proxy. Srv defaults to the system's
+        nameserver.
+    */
+    #[serde(default)]
+    pub endpoint: String,
+
+    // The Consul service, the etcd key prefix, the SRV record, or the Kubernetes namespace/service of the backends.
+    pub name: String,
+
+    // Milliseconds between lookups.
+    #[serde(default = "default_discovery_interval")]
+    pub interval: usize,
+}
+
+/*
+    The faults of a pool's chaos mode. Each percent is of the requests to the targeted backends, and 0 disables that
+    fault.
+*/
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct ChaosConfig {
+    // Whether faults are injected from startup. Otherwise they are started with the CHAOS admin command.
+    #[serde(default)]
+    pub enabled: bool,
+
+    // The backend hosts, or replicas, to inject faults on. Empty targets every host of the pool.
+    #[serde(default)]
+    pub backends: Vec<SocketAddr>,
+
+    // Milliseconds that a delayed request waits before it's written to the backend.
+    #[serde(default)]
+    pub latency: usize,
+
+    #[serde(default)]
+    pub latency_percent: usize,
+
+    // Percent of requests after which the backend connection is closed, as if the backend had gone away.
+    #[serde(default)]
+    pub disconnect_percent: usize,
+
+    // Percent of responses thrown away. Their requests time out instead.
+    #[serde(default)]
+    pub drop_percent: usize,
+
+    // Percent of responses replaced with the error.
+    #[serde(default)]
+    pub error_percent: usize,
+
+    #[serde(default = "default_chaos_error")]
+    pub error: String,
+}
+
+/*
+    The text of the errors that the proxy answers a pool's requests with itself, without the leading '-', e.g.
+    "TIMEOUT Request timed out". The first word is the code that clients see, as with redis' own errors.
+*/
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct ErrorResponsesConfig {
+    // Starts the errors that aren't set below with a code of their own, e.g. -PROXY_TIMEOUT Proxy timed out.
+    #[serde(default)]
+    pub codes: bool,
+
+    // A request that received no response within the pool's timeout.
+    #[serde(default)]
+    pub timeout: Option<String>,
+
+    // A request whose backend isn't connected, or disconnected before it responded.
+    #[serde(default)]
+    pub backend_unavailable: Option<String>,
+
+    // A request that no backend could be picked for.
+    #[serde(default)]
+    pub no_backend: Option<String>,
+
+    // A request to a backend whose queue or concurrency limit is full.
+    #[serde(default)]
+    pub overloaded: Option<String>,
+
+    // A request or connection refused at the proxy's memory_limit.
+    #[serde(default)]
+    pub memory_limit: Option<String>,
+
+    // A request over client_rate_limit, client_max_inflight or key_rate_limits.
+    #[serde(default)]
+    pub rate_limited: Option<String>,
+
+    // A request of a user over one of its quotas.
+    #[serde(default)]
+    pub quota_exceeded: Option<String>,
+}
+
+// A rule of rewrite_rules. Each of its rewrites that is set applies to the requests of its command.
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct RewriteRuleConfig {
+    // The command, e.g. SET. Matches the name that the earlier rules left the request with.
+    pub command: String,
+
+    // Sends the request as this command instead, with the same arguments.
+    #[serde(default)]
+    pub rename: Option<String>,
+
+    // Seconds that a SET without an expiry, or KEEPTTL, expires in. 0 leaves it as it is.
+    #[serde(default)]
+    pub default_ttl: usize,
+
+    // Caps the COUNT of a SCAN, HSCAN, SSCAN, ZSCAN, XRANGE or XREVRANGE, and adds it to one without. 0 leaves it as it is.
+    #[serde(default)]
+    pub max_count: usize,
+}
+
+// A rule of key_rate_limits. The first rule that matches a request applies to it.
+#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+pub struct KeyRateLimitConfig {
+    // Glob pattern of the keys, where * matches any sequence of bytes and ? matches any single byte.
+    pub pattern: String,
+
+    // Requests per second. Each worker enforces the limit on its own.
+    pub rate: usize,
+
+    // Requests allowed at once after a quiet period. 0 allows a second's worth.
+    #[serde(default)]
+    pub burst: usize,
+
+    // Commands that the rule applies to, e.g. INCR. Empty applies to every command.
+    #[serde(default)]
+    pub commands: Vec<String>,
+
+    // Only applies to commands that modify their key.
+    #[serde(default)]
+    pub writes_only: bool,
 }
 
 #[derive(Deserialize, Clone, Serialize, Eq, PartialEq)]
 pub struct AdminConfig {
     pub listen: String,
+
+    // Milliseconds an admin client may stay connected without sending a command. 0 never closes.
+    #[serde(default = "default_admin_idle_timeout")]
+    pub idle_timeout: usize,
+
+    // Networks that admin clients may and may not connect from, like a pool's allow_clients and deny_clients.
+    #[serde(default)]
+    pub allow_clients: Vec<String>,
+
+    #[serde(default)]
+    pub deny_clients: Vec<String>,
+
+    // Expects admin connections to start with a PROXY protocol header, like a pool's proxy_protocol.
+    #[serde(default)]
+    pub proxy_protocol: bool,
 }
 
-pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, ProxyError> {
+/*
+    Loads the config at the given path. If a profile is given, the [profile.<name>] table is merged over the rest of the
+    file before parsing, so a profile only needs to list the values it overrides. e.g.:
+
+    [profile.canary.pools.pool1]
+    timeout = 50
+*/
+pub fn load_config(full_config_path: String, profile: Option<&str>) -> Result<RedFlareProxyConfig, ProxyError> {
     // TOOD: trim config_path
     let config_path = full_config_path.trim();
     let mut file = match File::open(&config_path) {
@@ -112,19 +1267,71 @@ pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, Prox
         }
     };
     debug!("Config contents: {}", file_contents);
-    let config: RedFlareProxyConfig = match toml::from_str(&file_contents) {
+    let mut config_value: toml::Value = match toml::from_str(&file_contents) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), err));
+        }
+    };
+    try!(apply_profile(&mut config_value, profile, config_path));
+    return parse_config_value(config_value, config_path);
+}
+
+// Converts a parsed config file to a config, filling in the defaults, and validates it. config_path names it in errors.
+fn parse_config_value(config_value: toml::Value, config_path: &str) -> Result<RedFlareProxyConfig, ProxyError> {
+    let config: RedFlareProxyConfig = match config_value.try_into() {
         Ok(config) => config,
         Err(err) => {
             return Err(ProxyError::ParseConfigFailure(config_path.to_string(), err));
         }
     };
 
+    if let Err(err) = NetworkAcl::from_config(&config.admin.allow_clients, &config.admin.deny_clients) {
+        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in admin. {}", err, config_path))));
+    }
+
+    let mut original_destinations: Vec<&SocketAddr> = Vec::new();
+    for (ref pool_name, ref pool_config) in &config.pools {
+        if !pool_config.original_destinations.is_empty() && config.transparent.is_none() {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Pool {} has 'original_destinations' without a [transparent] listener. {}", pool_name, config_path))));
+        }
+        for destination in &pool_config.original_destinations {
+            if original_destinations.contains(&destination) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Original destination {} of pool {} belongs to another pool too. {}", destination, pool_name, config_path))));
+            }
+            original_destinations.push(destination);
+        }
+    }
+
     // Verify that cluster-associated configs should only be used when use_cluster is true, and verify that host is there when use_cluster is false.
     for (ref pool_name, ref pool_config) in &config.pools {
         for ref backend_config in &pool_config.servers {
+            if backend_config.username.is_some() && backend_config.auth.is_empty() {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Backend 'username' requires an 'auth' in pool {}. {}", pool_name, config_path))));
+            }
             if !backend_config.use_cluster {
-                if backend_config.host.is_none() {
-                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-cluster backend requires a 'host' in pool {}. {}", pool_name, config_path))));
+                if backend_config.sentinel_master.is_some() {
+                    if backend_config.host.is_some() || backend_config.hostname.is_some() {
+                        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend cannot have a 'host' in pool {}. {}", pool_name, config_path))));
+                    }
+                    if backend_config.sentinel_hosts.is_empty() {
+                        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Sentinel backend requires 'sentinel_hosts' in pool {}. {}", pool_name, config_path))));
+                    }
+                } else {
+                    if backend_config.host.is_none() && backend_config.hostname.is_none() {
+                        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-cluster backend requires a 'host' in pool {}. {}", pool_name, config_path))));
+                    }
+                    if backend_config.host.is_some() && backend_config.hostname.is_some() {
+                        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Backend cannot have both a 'host' and a 'hostname' in pool {}. {}", pool_name, config_path))));
+                    }
+                    if let Some(ref hostname) = backend_config.hostname {
+                        if let Err(err) = happyeyeballs::validate_hostname(hostname) {
+                            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in pool {}. {}", err, pool_name, config_path))));
+                        }
+                    }
+                    if !backend_config.sentinel_hosts.is_empty() {
+                        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Backend cannot have 'sentinel_hosts' without a 'sentinel_master' in pool {}. {}", pool_name, config_path))));
+                    }
                 }
                 if backend_config.cluster_hosts.len() > 0 {
                     return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-cluster backend cannot have any 'cluster_hosts' in pool {}. {}", pool_name, config_path))));
@@ -133,7 +1340,7 @@ pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, Prox
                     return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Non-cluster backend cannot have a 'cluster_name' in pool {}. {}", pool_name, config_path))));
                 }
             } else {
-                if backend_config.host.is_some() {
+                if backend_config.host.is_some() || backend_config.hostname.is_some() {
                     return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend cannot have a 'host' in pool {}. {}", pool_name, config_path))));
                 }
                 if backend_config.cluster_hosts.len() == 0 {
@@ -142,10 +1349,437 @@ pub fn load_config(full_config_path: String) -> Result<RedFlareProxyConfig, Prox
                 if backend_config.cluster_name.is_none() {
                     return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend requires a 'cluster_name' in pool {}. {}", pool_name, config_path))));
                 }
+                if backend_config.sentinel_master.is_some() || !backend_config.sentinel_hosts.is_empty() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend cannot use sentinel in pool {}. {}", pool_name, config_path))));
+                }
+                if !backend_config.replicas.is_empty() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend cannot have 'replicas' in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.canary {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend cannot be a 'canary' in pool {}. {}", pool_name, config_path))));
+                }
+                if backend_config.secondary.is_some() {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Cluster backend cannot have a 'secondary' in pool {}. {}", pool_name, config_path))));
+                }
 
             }
         }
     }
+    if config.buffer_size == 0 {
+        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'buffer_size' must be at least 1. {}", config_path))));
+    }
+    if config.workers == 0 {
+        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'workers' must be at least 1. {}", config_path))));
+    }
+    for (pool_name, pool_config) in &config.pools {
+        if pool_config.cache_stale_ttl > 0 && pool_config.cache_max_bytes == 0 && pool_config.hotkey_cache_ttl == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'cache_stale_ttl' requires 'cache_max_bytes' or 'hotkey_cache_ttl' in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.hotkey_cache_ttl > 0 && pool_config.hotkey_sample_rate == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'hotkey_cache_ttl' requires 'hotkey_sample_rate' in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.outage_buffer_timeout > 0 && pool_config.timeout > 0 && pool_config.outage_buffer_timeout >= pool_config.timeout {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'outage_buffer_timeout' must be less than 'timeout' in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.outage_buffer_timeout > 0 && pool_config.outage_buffer_size == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'outage_buffer_size' must be at least 1 in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.quarantine_threshold > 0 && pool_config.quarantine_duration == 0 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'quarantine_duration' must be at least 1 in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.read_your_writes > 0 && !pool_config.read_from_replicas {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'read_your_writes' requires 'read_from_replicas' in pool {}. {}", pool_name, config_path))));
+        }
+        if pool_config.canary_percent > 100 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'canary_percent' must be at most 100 in pool {}. {}", pool_name, config_path))));
+        }
+        for label_name in pool_config.metric_labels.keys() {
+            if let Err(err) = stats::validate_label_name(label_name) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in pool {}. {}", err, pool_name, config_path))));
+            }
+        }
+        if pool_config.servers.iter().filter(|backend_config| backend_config.canary).count() > 1 {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Only one server can be a 'canary' in pool {}. {}", pool_name, config_path))));
+        }
+        for (user_name, user_config) in pool_config.users.iter() {
+            if let Err(err) = Permissions::parse(&user_config.commands, &user_config.keys) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} for user {} in pool {}. {}", err, user_name, pool_name, config_path))));
+            }
+            if let Err(err) = namespace::validate(&user_config.namespace) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} for user {} in pool {}. {}", err, user_name, pool_name, config_path))));
+            }
+        }
+        if let Err(err) = NetworkAcl::from_config(&pool_config.allow_clients, &pool_config.deny_clients) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in pool {}. {}", err, pool_name, config_path))));
+        }
+        if let Err(err) = Maintenance::from_config(&pool_config.maintenance) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in pool {}. {}", err, pool_name, config_path))));
+        }
+        if let Some(ref discovery) = pool_config.discovery {
+            // Discovered backends are switched to the same way as SWITCHCONFIG, which only a single worker supports.
+            if config.workers > 1 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'discovery' is not supported with multiple workers in pool {}. {}", pool_name, config_path))));
+            }
+            if discovery.endpoint.is_empty() && discovery.source != DiscoverySource::Srv {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Discovery requires an 'endpoint' in pool {}. {}", pool_name, config_path))));
+            }
+            if discovery.source == DiscoverySource::Kubernetes && discovery.name.splitn(2, '/').filter(|part| !part.is_empty()).count() != 2 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Kubernetes discovery 'name' must be namespace/service in pool {}. {}", pool_name, config_path))));
+            }
+            if discovery.interval == 0 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Discovery 'interval' must be at least 1 in pool {}. {}", pool_name, config_path))));
+            }
+            match pool_config.servers.first() {
+                Some(template) if template.host.is_some() && !template.canary && template.replicas.is_empty() && template.secondary.is_none() => {}
+                _ => {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'discovery' requires the first server to have a 'host', and no 'replicas', 'secondary' or 'canary', since it's the template of the discovered backends in pool {}. {}", pool_name, config_path))));
+                }
+            }
+        }
+        if let Some(ref chaos) = pool_config.chaos {
+            if [chaos.latency_percent, chaos.disconnect_percent, chaos.drop_percent, chaos.error_percent].iter().any(|&percent| percent > 100) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'chaos' percents must be at most 100 in pool {}. {}", pool_name, config_path))));
+            }
+            // A dropped response is answered once its request times out, so there must be a timeout.
+            if chaos.drop_percent > 0 && pool_config.timeout == 0 {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'chaos' drop_percent requires a 'timeout' in pool {}. {}", pool_name, config_path))));
+            }
+            if chaos.error.is_empty() || chaos.error.contains('\r') || chaos.error.contains('\n') {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'chaos' error must be a single line in pool {}. {}", pool_name, config_path))));
+            }
+        }
+        if let Some(ref error_responses) = pool_config.error_responses {
+            if let Err(err) = ErrorResponses::from_config(error_responses) {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("{} in pool {}. {}", err, pool_name, config_path))));
+            }
+        }
+        for rule in pool_config.key_rate_limits.iter() {
+            if rule.rate == 0 || rule.pattern.is_empty() {
+                return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'key_rate_limits' require a 'pattern' and a 'rate' of at least 1 in pool {}. {}", pool_name, config_path))));
+            }
+        }
+        if let Some(ref standby) = pool_config.standby {
+            match config.pools.get(standby) {
+                None => {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Unknown 'standby' pool {} in pool {}. {}", standby, pool_name, config_path))));
+                }
+                Some(_) if standby == pool_name => {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Pool {} cannot be its own 'standby'. {}", pool_name, config_path))));
+                }
+                Some(standby_config) if standby_config.standby.is_some() => {
+                    return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("The 'standby' pool {} of pool {} cannot have a standby itself. {}", standby, pool_name, config_path))));
+                }
+                Some(_) => {}
+            }
+        }
+        if let Err(err) = filter::from_config(pool_config) {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Invalid filter in pool {}: {}. {}", pool_name, err, config_path))));
+        }
+    }
+    if config.event_capacity == 0 {
+        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("'event_capacity' must be at least 1. {}", config_path))));
+    }
+    if let Err(entry) = logging::parse_component_levels(&config.log_levels) {
+        return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("Invalid log level: {}. Components are admin, backend, cluster and protocol, and levels are DEBUG, INFO, WARNING and ERROR. {}", entry, config_path))));
+    }
     
     Ok(config)
-}
\ No newline at end of file
+}
+/*
+    Removes the profile tables from the parsed config, and merges the selected profile's values over the base config.
+*/
+fn apply_profile(config_value: &mut toml::Value, profile: Option<&str>, config_path: &str) -> Result<(), ProxyError> {
+    let root = match config_value.as_table_mut() {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+    let mut profiles = root.remove("profile");
+    let profile_name = match profile {
+        Some(profile_name) => profile_name,
+        None => return Ok(()),
+    };
+    let overrides = match profiles.as_mut().and_then(|p| p.as_table_mut()).and_then(|p| p.remove(profile_name)) {
+        Some(toml::Value::Table(overrides)) => overrides,
+        _ => {
+            return Err(ProxyError::ParseConfigFailure(config_path.to_string(), serde::de::Error::custom(format!("No profile named '{}' in {}", profile_name, config_path))));
+        }
+    };
+    merge_table(root, overrides);
+    Ok(())
+}
+
+// Recursively merges overrides into base. Tables are merged key by key; any other value replaces the base value.
+fn merge_table(base: &mut toml::value::Table, overrides: toml::value::Table) {
+    for (key, value) in overrides {
+        match value {
+            toml::Value::Table(override_table) => {
+                if let Some(&mut toml::Value::Table(ref mut base_table)) = base.get_mut(&key) {
+                    merge_table(base_table, override_table);
+                    continue;
+                }
+                base.insert(key, toml::Value::Table(override_table));
+            }
+            other => {
+                base.insert(key, other);
+            }
+        }
+    }
+}
+
+pub fn serialize_config(config: &RedFlareProxyConfig) -> Result<String, ProxyError> {
+    // Convert to a toml::Value first, so that plain values are emitted before tables regardless of field order.
+    match toml::Value::try_from(config).and_then(|value| toml::to_string(&value)) {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(ProxyError::SerializeConfigFailure(err)),
+    }
+}
+
+/*
+    Serializes the config and writes it to the given path, replacing the file's contents.
+    The file is written to a temporary path first and then renamed, so a failed write does not clobber the existing config.
+*/
+pub fn write_config(config: &RedFlareProxyConfig, config_path: &str) -> Result<(), ProxyError> {
+    if defines_profiles(config_path) {
+        return Err(ProxyError::ProfilesNotRewritable(config_path.to_string()));
+    }
+    let file_contents = try!(serialize_config(config));
+    let temp_path = format!("{}.tmp", config_path);
+    let write_result = File::create(&temp_path)
+        .and_then(|mut file| {
+            try!(file.write_all(file_contents.as_bytes()));
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&temp_path, config_path));
+    match write_result {
+        Ok(_) => {}
+        Err(err) => {
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(ProxyError::ConfigWriteFailure(config_path.to_string(), err));
+        }
+    };
+    Ok(())
+}
+
+/*
+    Whether the file at config_path defines [profile.*] tables. A loaded config has its profile already merged in, so
+    writing it over such a file would flatten the selected profile into the base config and drop the others.
+*/
+fn defines_profiles(config_path: &str) -> bool {
+    let mut file_contents = String::new();
+    match File::open(config_path).and_then(|mut file| file.read_to_string(&mut file_contents)) {
+        Ok(_) => {}
+        Err(_) => return false,
+    };
+    match toml::from_str::<toml::Value>(&file_contents) {
+        Ok(value) => return value.as_table().map_or(false, |root| root.contains_key("profile")),
+        Err(_) => return false,
+    }
+}
+
+/*
+    Builds a config in code, e.g. for a proxy embedded in a test harness. Options left unset get the same defaults as
+    in a config file, and the config is validated like a loaded one. Options without a setter can be changed on the
+    built config, whose fields are public. e.g.:
+
+    let mut config = try!(ConfigBuilder::new("127.0.0.1:1530")
+        .pool("pool1", PoolBuilder::new("127.0.0.1:1531").server("127.0.0.1:6379", 1).timeout(100))
+        .build());
+    config.shutdown_timeout = 1000;
+*/
+pub struct ConfigBuilder {
+    root: toml::value::Table,
+    pools: toml::value::Table,
+}
+
+impl ConfigBuilder {
+    pub fn new(admin_listen: &str) -> ConfigBuilder {
+        let mut admin = toml::value::Table::new();
+        admin.insert("listen".to_owned(), toml::Value::String(admin_listen.to_owned()));
+        let mut root = toml::value::Table::new();
+        root.insert("admin".to_owned(), toml::Value::Table(admin));
+        ConfigBuilder {
+            root: root,
+            pools: toml::value::Table::new(),
+        }
+    }
+
+    pub fn pool(mut self, name: &str, pool: PoolBuilder) -> ConfigBuilder {
+        self.pools.insert(name.to_owned(), toml::Value::Table(pool.build()));
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> ConfigBuilder {
+        self.root.insert("workers".to_owned(), toml::Value::Integer(workers as i64));
+        self
+    }
+
+    pub fn enable_advanced_commands(mut self, enabled: bool) -> ConfigBuilder {
+        self.root.insert("enable_advanced_commands".to_owned(), toml::Value::Boolean(enabled));
+        self
+    }
+
+    pub fn build(mut self) -> Result<RedFlareProxyConfig, ProxyError> {
+        self.root.insert("pools".to_owned(), toml::Value::Table(self.pools));
+        return parse_config_value(toml::Value::Table(self.root), "<builder>");
+    }
+}
+
+// A pool for ConfigBuilder::pool.
+pub struct PoolBuilder {
+    pool: toml::value::Table,
+    servers: Vec<toml::Value>,
+}
+
+impl PoolBuilder {
+    pub fn new(listen: &str) -> PoolBuilder {
+        let mut pool = toml::value::Table::new();
+        pool.insert("listen".to_owned(), toml::Value::String(listen.to_owned()));
+        PoolBuilder {
+            pool: pool,
+            servers: Vec::new(),
+        }
+    }
+
+    pub fn server(mut self, host: &str, weight: usize) -> PoolBuilder {
+        let mut server = toml::value::Table::new();
+        server.insert("host".to_owned(), toml::Value::String(host.to_owned()));
+        server.insert("weight".to_owned(), toml::Value::Integer(weight as i64));
+        self.servers.push(toml::Value::Table(server));
+        self
+    }
+
+    // Adds a redis cluster, discovered from the given hosts.
+    pub fn cluster(mut self, name: &str, hosts: &[&str], weight: usize) -> PoolBuilder {
+        let mut server = toml::value::Table::new();
+        server.insert("use_cluster".to_owned(), toml::Value::Boolean(true));
+        server.insert("cluster_name".to_owned(), toml::Value::String(name.to_owned()));
+        server.insert("cluster_hosts".to_owned(), toml::Value::Array(hosts.iter().map(|host| toml::Value::String(host.to_string())).collect()));
+        server.insert("weight".to_owned(), toml::Value::Integer(weight as i64));
+        self.servers.push(toml::Value::Table(server));
+        self
+    }
+
+    // Adds a backend at the address of the named master, discovered from the given sentinels.
+    pub fn sentinel(mut self, master_name: &str, hosts: &[&str], weight: usize) -> PoolBuilder {
+        let mut server = toml::value::Table::new();
+        server.insert("sentinel_master".to_owned(), toml::Value::String(master_name.to_owned()));
+        server.insert("sentinel_hosts".to_owned(), toml::Value::Array(hosts.iter().map(|host| toml::Value::String(host.to_string())).collect()));
+        server.insert("weight".to_owned(), toml::Value::Integer(weight as i64));
+        self.servers.push(toml::Value::Table(server));
+        self
+    }
+
+    // Milliseconds before a request to a backend times out. 0 never times out.
+    pub fn timeout(mut self, timeout: usize) -> PoolBuilder {
+        self.pool.insert("timeout".to_owned(), toml::Value::Integer(timeout as i64));
+        self
+    }
+
+    fn build(mut self) -> toml::value::Table {
+        self.pool.insert("servers".to_owned(), toml::Value::Array(self.servers));
+        return self.pool;
+    }
+}
+
+#[test]
+fn test_config_builder() {
+    let config = ConfigBuilder::new("127.0.0.1:1530")
+        .pool("pool1", PoolBuilder::new("127.0.0.1:1531").server("127.0.0.1:6380", 1).timeout(100))
+        .pool("cluster", PoolBuilder::new("127.0.0.1:1532").cluster("c1", &["127.0.0.1:7000", "127.0.0.1:7001"], 1))
+        .build()
+        .unwrap();
+    let loaded = load_config("tests/conf/testconfig1.toml".to_owned(), None).unwrap();
+    // Unset options get the same defaults as in a config file.
+    assert_eq!(config.buffer_size, loaded.buffer_size);
+    assert!(config.admin.listen == "127.0.0.1:1530");
+    let pool = config.pools.get("pool1").unwrap();
+    assert_eq!(pool.timeout, 100);
+    assert!(pool.servers[0].host == Some("127.0.0.1:6380".parse().unwrap()));
+    assert_eq!(pool.client_burst, loaded.pools.values().next().unwrap().client_burst);
+    assert_eq!(config.pools.get("cluster").unwrap().servers[0].cluster_hosts.len(), 2);
+
+    // Validated like a loaded config.
+    assert!(ConfigBuilder::new("127.0.0.1:1530").pool("pool1", PoolBuilder::new("not an address")).build().is_err());
+    assert!(ConfigBuilder::new("127.0.0.1:1530").workers(0).build().is_err());
+
+    let sentinel = ConfigBuilder::new("127.0.0.1:1530").pool("pool1", PoolBuilder::new("127.0.0.1:1531").sentinel("mymaster", &["127.0.0.1:26379"], 1)).build().unwrap();
+    assert_eq!(sentinel.pools.get("pool1").unwrap().servers[0].sentinel_master, Some("mymaster".to_owned()));
+    // A sentinel backend needs sentinels to ask, and no fixed host.
+    assert!(ConfigBuilder::new("127.0.0.1:1530").pool("pool1", PoolBuilder::new("127.0.0.1:1531").sentinel("mymaster", &[], 1)).build().is_err());
+    let mut with_host = PoolBuilder::new("127.0.0.1:1531").sentinel("mymaster", &["127.0.0.1:26379"], 1);
+    if let toml::Value::Table(ref mut server) = with_host.servers[0] {
+        server.insert("host".to_owned(), toml::Value::String("127.0.0.1:6380".to_owned()));
+    }
+    assert!(ConfigBuilder::new("127.0.0.1:1530").pool("pool1", with_host).build().is_err());
+}
+
+#[test]
+fn test_metric_label_names() {
+    let mut file_contents = String::new();
+    File::open("tests/conf/poolmetrics1.toml").unwrap().read_to_string(&mut file_contents).unwrap();
+    let with_label = |name: &str| {
+        let mut config_value: toml::Value = toml::from_str(&file_contents).unwrap();
+        if let Some(labels) = config_value.as_table_mut()
+            .and_then(|root| root.get_mut("pools")).and_then(|pools| pools.as_table_mut())
+            .and_then(|pools| pools.get_mut("pool1")).and_then(|pool| pool.as_table_mut())
+            .and_then(|pool| pool.get_mut("metric_labels")).and_then(|labels| labels.as_table_mut()) {
+            labels.insert(name.to_owned(), toml::Value::String("value".to_owned()));
+        }
+        return parse_config_value(config_value, "tests/conf/poolmetrics1.toml");
+    };
+    assert!(with_label("region").is_ok());
+    // Prometheus would reject the metrics, or see two values of one label.
+    assert!(with_label("team-name").is_err());
+    assert!(with_label("pool").is_err());
+    assert!(with_label("backend").is_err());
+}
+
+#[test]
+fn test_write_config_roundtrip() {
+    let path = std::env::temp_dir().join("redflare_test_write_config.toml");
+    let path = path.to_str().unwrap();
+    for config_path in ["tests/conf/poolmetrics1.toml", "tests/conf/retrybackoff1.toml", "tests/conf/cluster1.toml", "tests/conf/eventloop1.toml", "tests/conf/batchdispatch1.toml", "tests/conf/idle1.toml", "tests/conf/readcache2.toml", "tests/conf/sentinel1.toml", "tests/conf/replica1.toml", "tests/conf/hedge1.toml", "tests/conf/dualwrite1.toml", "tests/conf/canary1.toml", "tests/conf/standby1.toml", "tests/conf/ratelimit1.toml", "tests/conf/keyratelimit1.toml", "tests/conf/quota1.toml", "tests/conf/netacl1.toml", "tests/conf/acl1.toml", "tests/conf/namespace1.toml", "tests/conf/rewrite1.toml", "tests/conf/sizelimits1.toml", "tests/conf/errorresponses1.toml", "tests/conf/hostname1.toml", "tests/conf/fastopen1.toml", "tests/conf/transparent1.toml", "tests/conf/outagebuffer1.toml", "tests/conf/readyourwrites1.toml", "tests/conf/quarantine1.toml", "tests/conf/overload1.toml", "tests/conf/maintenance1.toml", "tests/conf/servestale1.toml", "tests/conf/deadline1.toml", "tests/conf/collapse1.toml", "tests/conf/auth3.toml", "tests/conf/proxyprotocol1.toml", "tests/conf/discovery1.toml", "tests/conf/chaos1.toml"].iter() {
+        let config = load_config(config_path.to_string(), None).unwrap();
+        write_config(&config, path).unwrap();
+        let rewritten = load_config(path.to_owned(), None).unwrap();
+        assert!(config == rewritten);
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_load_config_profile() {
+    let base = load_config("tests/conf/profiles1.toml".to_owned(), None).unwrap();
+    assert_eq!(base.pools.get("pool1").unwrap().timeout, 0);
+    assert_eq!(base.pools.get("pool1").unwrap().servers.len(), 1);
+
+    let canary = load_config("tests/conf/profiles1.toml".to_owned(), Some("canary")).unwrap();
+    let canary_pool = canary.pools.get("pool1").unwrap();
+    assert_eq!(canary_pool.timeout, 50);
+    assert_eq!(format!("{}", canary_pool.listen), "127.0.0.1:1541");
+    assert!(canary_pool.servers == base.pools.get("pool1").unwrap().servers);
+    assert_eq!(canary.admin.listen, "127.0.0.1:1540");
+
+    let replica = load_config("tests/conf/profiles1.toml".to_owned(), Some("replica")).unwrap();
+    assert_eq!(format!("{}", replica.pools.get("pool1").unwrap().servers[0].host.unwrap()), "127.0.0.1:6381");
+
+    assert!(load_config("tests/conf/profiles1.toml".to_owned(), Some("missing")).is_err());
+}
+
+#[test]
+fn test_write_config_profiles() {
+    let path = std::env::temp_dir().join("redflare_test_write_config_profiles.toml");
+    let path = path.to_str().unwrap();
+    std::fs::copy("tests/conf/profiles1.toml", path).unwrap();
+    let config = load_config(path.to_owned(), Some("canary")).unwrap();
+    match write_config(&config, path) {
+        Err(ProxyError::ProfilesNotRewritable(_)) => {}
+        _ => panic!("Expected the config with profiles not to be rewritten"),
+    }
+    let mut file_contents = String::new();
+    File::open(path).unwrap().read_to_string(&mut file_contents).unwrap();
+    let mut original_contents = String::new();
+    File::open("tests/conf/profiles1.toml").unwrap().read_to_string(&mut original_contents).unwrap();
+    assert_eq!(file_contents, original_contents);
+    let _ = std::fs::remove_file(path);
+}
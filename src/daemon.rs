@@ -0,0 +1,103 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use daemonize::{Daemonize, DaemonizeError};
+use config::RedFlareProxyConfig;
+use redflareproxy::ProxyError;
+use handoff;
+use libc;
+
+/*
+    Applies the process-level options of the config: forking into the background, writing the pid file, and
+    redirecting stdout/stderr to files. This should be called before the event poll is created.
+*/
+pub fn daemonize(config: &RedFlareProxyConfig) -> Result<(), ProxyError> {
+    if config.daemonize {
+        // Keep the working directory, so that relative config and log paths keep working after forking.
+        let working_directory = match std::env::current_dir() {
+            Ok(dir) => dir,
+            Err(err) => {
+                debug!("Unable to read current directory: {}", err);
+                return Err(ProxyError::DaemonizeFailure(DaemonizeError::ChangeDirectory));
+            }
+        };
+        let mut daemon = Daemonize::new().working_directory(working_directory);
+        // On a hot restart, the old process still holds the lock on the pid file, so it is written without one.
+        let restart = handoff::is_restart();
+        if let Some(ref pid_file) = config.pid_file {
+            if !restart {
+                daemon = daemon.pid_file(pid_file);
+            }
+        }
+        match daemon.start() {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(ProxyError::DaemonizeFailure(err));
+            }
+        };
+        if let Some(ref pid_file) = config.pid_file {
+            if restart {
+                try!(write_pid_file(pid_file));
+            }
+        }
+    } else if let Some(ref pid_file) = config.pid_file {
+        try!(write_pid_file(pid_file));
+    }
+
+    if let Some(ref stdout_file) = config.stdout_file {
+        try!(redirect_stream(stdout_file, libc::STDOUT_FILENO));
+    }
+    if let Some(ref stderr_file) = config.stderr_file {
+        try!(redirect_stream(stderr_file, libc::STDERR_FILENO));
+    }
+    Ok(())
+}
+
+/*
+    Removes the pid file, if one was configured. Called when the proxy shuts down cleanly. The file is kept if it has
+    another pid, i.e. when a hot restart's new process has already replaced it.
+*/
+pub fn remove_pid_file(config: &RedFlareProxyConfig) {
+    if let Some(ref pid_file) = config.pid_file {
+        match std::fs::read_to_string(pid_file) {
+            Ok(ref contents) if contents.trim() != std::process::id().to_string() => {
+                debug!("Keeping pid file {}, which belongs to process {}", pid_file, contents.trim());
+                return;
+            }
+            _ => {}
+        }
+        match std::fs::remove_file(pid_file) {
+            Ok(_) => {}
+            Err(err) => {
+                error!("Unable to remove pid file: {}. Received error: {}", pid_file, err);
+            }
+        }
+    }
+}
+
+fn write_pid_file(pid_file: &str) -> Result<(), ProxyError> {
+    let mut file = match File::create(pid_file) {
+        Ok(f) => f,
+        Err(err) => {
+            return Err(ProxyError::PidFileFailure(pid_file.to_owned(), err));
+        }
+    };
+    match write!(file, "{}", std::process::id()) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ProxyError::PidFileFailure(pid_file.to_owned(), err)),
+    }
+}
+
+fn redirect_stream(path: &str, target_fd: RawFd) -> Result<(), ProxyError> {
+    let file = match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => f,
+        Err(err) => {
+            return Err(ProxyError::StdioRedirectFailure(path.to_owned(), err));
+        }
+    };
+    // The duplicated descriptor stays open after the file is dropped.
+    if unsafe { libc::dup2(file.as_raw_fd(), target_fd) } == -1 {
+        return Err(ProxyError::StdioRedirectFailure(path.to_owned(), std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
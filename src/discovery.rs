@@ -0,0 +1,405 @@
+use config::{BackendConfig, BackendPoolConfig, DiscoveryConfig, DiscoverySource};
+use dns;
+use json::{self, Json};
+use log::LogLevel;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+// A backend that was discovered, with its weight if the source has one.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Target {
+    pub host: SocketAddr,
+    pub weight: Option<usize>,
+}
+
+/*
+    Keeps a pool's backends in sync with a discovery service, from its discovery config: a Consul service, whose
+    passing instances are the backends, an etcd prefix, whose keys each have a backend's host:port as their value,
+    optionally followed by a space and its weight, a DNS SRV record, whose targets with the lowest priority are the
+    backends, weighted by their SRV weights, or a Kubernetes service, e.g. one in front of a Redis StatefulSet, whose
+    ready endpoints are the backends.
+
+    A thread looks the backends up every interval, and hands each list it finds over to the event loop through a
+    channel. The event loop switches the pool over to them when they change, the same way as SWITCHCONFIG does. Lookups
+    that fail, or find no backends, are logged and leave the pool as it is.
+*/
+pub struct DiscoveryWatcher {
+    receiver: Receiver<Vec<Target>>,
+    // Stops the thread once the watcher is dropped.
+    stopped: Arc<AtomicBool>,
+}
+
+impl DiscoveryWatcher {
+    // Returns None when the pool has no discovery.
+    pub fn from_config(pool_name: &str, config: &BackendPoolConfig) -> Option<DiscoveryWatcher> {
+        let discovery = match config.discovery {
+            Some(ref discovery) => discovery.clone(),
+            None => return None,
+        };
+        let (sender, receiver) = channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let pool_name = pool_name.to_owned();
+        thread::spawn(move || {
+            while !thread_stopped.load(Ordering::SeqCst) {
+                match discover(&discovery) {
+                    Ok(ref targets) if targets.is_empty() => {
+                        log_event!(LogLevel::Warn, "discovery_empty", { pool: pool_name, name: discovery.name }, "Discovered no backends for pool {} from {}. Keeping the current ones", pool_name, discovery.name);
+                    }
+                    Ok(targets) => {
+                        if sender.send(targets).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        log_event!(LogLevel::Warn, "discovery_failed", { pool: pool_name, name: discovery.name }, "Unable to discover the backends of pool {} from {}: {}", pool_name, discovery.name, err);
+                    }
+                }
+                thread::sleep(Duration::from_millis(discovery.interval as u64));
+            }
+        });
+        return Some(DiscoveryWatcher {
+            receiver: receiver,
+            stopped: stopped,
+        });
+    }
+
+    // The latest backends discovered since the last call, without waiting.
+    pub fn latest(&self) -> Option<Vec<Target>> {
+        return self.receiver.try_iter().last();
+    }
+}
+
+impl Drop for DiscoveryWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/*
+    The servers of a pool with the discovered backends. Every setting but the host, and the weight if the source has
+    one, comes from the pool's first server.
+*/
+pub fn discovered_servers(servers: &[BackendConfig], targets: &[Target]) -> Vec<BackendConfig> {
+    let template = match servers.first() {
+        Some(template) => template,
+        None => return Vec::new(),
+    };
+    return targets.iter().map(|target| {
+        let mut server = template.clone();
+        server.host = Some(target.host);
+        server.weight = target.weight.unwrap_or(template.weight);
+        server
+    }).collect();
+}
+
+// Sorted, so that the same backends are always in the same order, and sharded the same way.
+fn discover(config: &DiscoveryConfig) -> Result<Vec<Target>, String> {
+    let mut targets = match config.source {
+        DiscoverySource::Consul => try!(discover_consul(config)),
+        DiscoverySource::Etcd => try!(discover_etcd(config)),
+        DiscoverySource::Srv => try!(discover_srv(config)),
+        DiscoverySource::Kubernetes => try!(discover_kubernetes(config)),
+    };
+    targets.sort();
+    targets.dedup_by(|a, b| a.host == b.host);
+    return Ok(targets);
+}
+
+fn discover_consul(config: &DiscoveryConfig) -> Result<Vec<Target>, String> {
+    let body = try!(http_request(&config.endpoint, "GET", &format!("/v1/health/service/{}?passing", config.name), ""));
+    return parse_consul(&body);
+}
+
+// e.g. [{"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "", "Port": 6379, "Weights": {"Passing": 1}}}]
+fn parse_consul(body: &str) -> Result<Vec<Target>, String> {
+    let document = try!(json::parse(body));
+    let entries = match document.as_array() {
+        Some(entries) => entries,
+        None => return Err("expected a list of service instances".to_owned()),
+    };
+    let mut targets = Vec::new();
+    for entry in entries {
+        let service = entry.get("Service");
+        // Instances without an address of their own are at their node's.
+        let address = match service.and_then(|service| service.get("Address")).and_then(Json::as_str) {
+            Some(address) if !address.is_empty() => address,
+            _ => match entry.get("Node").and_then(|node| node.get("Address")).and_then(Json::as_str) {
+                Some(address) => address,
+                None => return Err("service instance without an address".to_owned()),
+            },
+        };
+        let port = match service.and_then(|service| service.get("Port")).and_then(Json::as_u64) {
+            Some(port) if port <= 65535 => port as u16,
+            _ => return Err(format!("service instance at {} without a valid port", address)),
+        };
+        let weight = service.and_then(|service| service.get("Weights")).and_then(|weights| weights.get("Passing")).and_then(Json::as_u64);
+        targets.push(Target {
+            host: try!(resolve(&format!("{}:{}", address, port))),
+            weight: weight.map(|weight| weight as usize),
+        });
+    }
+    return Ok(targets);
+}
+
+// Reads the keys under the prefix through etcd's v3 JSON gateway, which base64 encodes keys and values.
+fn discover_etcd(config: &DiscoveryConfig) -> Result<Vec<Target>, String> {
+    let prefix = config.name.as_bytes();
+    let request = format!("{{\"key\":\"{}\",\"range_end\":\"{}\"}}", base64_encode(prefix), base64_encode(&prefix_end(prefix)));
+    let body = try!(http_request(&config.endpoint, "POST", "/v3/kv/range", &request));
+    return parse_etcd(&body);
+}
+
+// e.g. {"header": {...}, "kvs": [{"key": "L3JlZGlzL2E=", "value": "MTAuMC4wLjE6NjM3OQ=="}], "count": "1"}
+fn parse_etcd(body: &str) -> Result<Vec<Target>, String> {
+    let document = try!(json::parse(body));
+    let mut targets = Vec::new();
+    // kvs is left out when no key matched.
+    for kv in document.get("kvs").and_then(Json::as_array).unwrap_or(&[]) {
+        let value = match kv.get("value").and_then(Json::as_str).and_then(base64_decode).and_then(|value| String::from_utf8(value).ok()) {
+            Some(value) => value,
+            None => return Err("invalid value of a key".to_owned()),
+        };
+        let mut parts = value.split_whitespace();
+        let host = try!(resolve(parts.next().unwrap_or("")));
+        let weight = match parts.next() {
+            None => None,
+            Some(weight) => match weight.parse::<usize>() {
+                Ok(weight) => Some(weight),
+                Err(_) => return Err(format!("invalid weight in {}", value)),
+            },
+        };
+        targets.push(Target {
+            host: host,
+            weight: weight,
+        });
+    }
+    return Ok(targets);
+}
+
+// Only the targets with the lowest priority are used. The others are meant as fallbacks for when those are down.
+fn discover_srv(config: &DiscoveryConfig) -> Result<Vec<Target>, String> {
+    let nameserver = if config.endpoint.is_empty() {
+        try!(dns::system_nameserver())
+    } else {
+        try!(resolve(&config.endpoint))
+    };
+    let records = try!(dns::resolve_srv(nameserver, &config.name));
+    let priority = records.iter().map(|record| record.priority).min();
+    return Ok(records.into_iter().filter(|record| Some(record.priority) == priority).map(|record| Target {
+        host: record.host,
+        // A weight of 0 only means that the target should rarely be picked, so it still gets a share of the keys.
+        weight: Some(std::cmp::max(record.weight as usize, 1)),
+    }).collect());
+}
+
+/*
+    Lists the EndpointSlices of the namespace/service. The proxy doesn't speak TLS, so the API server is reached through
+    kubectl proxy, e.g. in a sidecar, which also authenticates with the pod's service account. The slices are polled
+    every interval rather than watched.
+*/
+fn discover_kubernetes(config: &DiscoveryConfig) -> Result<Vec<Target>, String> {
+    let mut parts = config.name.splitn(2, '/');
+    let (namespace, service) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = format!("/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}", namespace, service);
+    let body = try!(http_request(&config.endpoint, "GET", &path, ""));
+    return parse_endpoint_slices(&body);
+}
+
+/*
+    e.g. {"kind": "EndpointSliceList", "items": [{"addressType": "IPv4", "ports": [{"name": "redis", "port": 6379}],
+    "endpoints": [{"addresses": ["10.1.0.5"], "conditions": {"ready": true}}]}]}
+
+    The port named redis is used, or else the slice's first one. Endpoints are ready unless they say otherwise.
+*/
+fn parse_endpoint_slices(body: &str) -> Result<Vec<Target>, String> {
+    let document = try!(json::parse(body));
+    let slices = match document.get("items").and_then(Json::as_array) {
+        Some(slices) => slices,
+        None => return Err("expected a list of EndpointSlices".to_owned()),
+    };
+    let mut targets = Vec::new();
+    for slice in slices {
+        let ports = slice.get("ports").and_then(Json::as_array).unwrap_or(&[]);
+        let port = ports.iter().find(|port| port.get("name").and_then(Json::as_str) == Some("redis")).or(ports.first());
+        let port = match port.and_then(|port| port.get("port")).and_then(Json::as_u64) {
+            Some(port) if port <= 65535 => port as u16,
+            // A slice without ports has no endpoints either, e.g. while the service has no pods.
+            _ => continue,
+        };
+        for endpoint in slice.get("endpoints").and_then(Json::as_array).unwrap_or(&[]) {
+            if endpoint.get("conditions").and_then(|conditions| conditions.get("ready")) == Some(&Json::Bool(false)) {
+                continue;
+            }
+            for address in endpoint.get("addresses").and_then(Json::as_array).unwrap_or(&[]).iter().filter_map(Json::as_str) {
+                let ip = match address.parse::<IpAddr>() {
+                    Ok(ip) => ip,
+                    Err(_) => return Err(format!("invalid endpoint address {}", address)),
+                };
+                targets.push(Target {
+                    host: SocketAddr::new(ip, port),
+                    weight: None,
+                });
+            }
+        }
+    }
+    return Ok(targets);
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, String> {
+    return match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(host)) => Ok(host),
+        Ok(None) => Err(format!("{} did not resolve to an address", address)),
+        Err(err) => Err(format!("unable to resolve {}: {}", address, err)),
+    };
+}
+
+/*
+    Sends an HTTP/1.0 request to the host:port endpoint and returns the body of a 2xx response. HTTP/1.0 keeps the
+    response from being chunked, and the connection is closed after it.
+*/
+pub fn http_request(endpoint: &str, method: &str, path: &str, body: &str) -> Result<String, String> {
+    let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+    let addr = try!(resolve(endpoint));
+    let mut stream = try!(TcpStream::connect_timeout(&addr, timeout).map_err(|err| format!("unable to connect to {}: {}", endpoint, err)));
+    let request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, endpoint, body.len(), body
+    );
+    let mut response = String::new();
+    let result = stream.set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .and_then(|_| stream.write_all(request.as_bytes()))
+        .and_then(|_| stream.read_to_string(&mut response));
+    if let Err(err) = result {
+        return Err(format!("request to {} failed: {}", endpoint, err));
+    }
+    let status = response.split(' ').nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        return Err(format!("{} responded with {}", endpoint, response.lines().next().unwrap_or("")));
+    }
+    return match response.find("\r\n\r\n") {
+        Some(end) => Ok(response[end + 4..].to_owned()),
+        None => Err(format!("incomplete response from {}", endpoint)),
+    };
+}
+
+// The end of the range of keys that start with the prefix: the prefix with its last byte that isn't 0xff incremented.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key.
+    return vec![0];
+}
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    return output;
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches('=').as_bytes();
+    let mut output = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = match BASE64_ALPHABET.iter().position(|&a| a == c) {
+                Some(value) => value as u32,
+                None => return None,
+            };
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            output.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    return Some(output);
+}
+
+#[test]
+fn test_parse_consul() {
+    let body = r#"[
+        {"Node": {"Node": "a", "Address": "127.0.0.1"}, "Service": {"Address": "", "Port": 6380, "Weights": {"Passing": 2, "Warning": 1}}},
+        {"Node": {"Node": "b", "Address": "127.0.0.2"}, "Service": {"Address": "127.0.0.3", "Port": 6381}}
+    ]"#;
+    let targets = parse_consul(body).unwrap();
+    assert_eq!(targets, vec![
+        Target { host: "127.0.0.1:6380".parse().unwrap(), weight: Some(2) },
+        Target { host: "127.0.0.3:6381".parse().unwrap(), weight: None },
+    ]);
+    assert_eq!(parse_consul("[]").unwrap(), Vec::new());
+    assert!(parse_consul("{}").is_err());
+    assert!(parse_consul(r#"[{"Service": {"Address": "127.0.0.1", "Port": 70000}}]"#).is_err());
+}
+
+#[test]
+fn test_parse_etcd() {
+    let body = format!(
+        r#"{{"header": {{"revision": "5"}}, "kvs": [{{"key": "{}", "value": "{}"}}, {{"key": "{}", "value": "{}"}}], "count": "2"}}"#,
+        base64_encode(b"/redis/a"), base64_encode(b"127.0.0.1:6380"), base64_encode(b"/redis/b"), base64_encode(b"127.0.0.1:6381 3")
+    );
+    let targets = parse_etcd(&body).unwrap();
+    assert_eq!(targets, vec![
+        Target { host: "127.0.0.1:6380".parse().unwrap(), weight: None },
+        Target { host: "127.0.0.1:6381".parse().unwrap(), weight: Some(3) },
+    ]);
+    assert_eq!(parse_etcd(r#"{"header": {"revision": "5"}}"#).unwrap(), Vec::new());
+    assert!(parse_etcd(&format!(r#"{{"kvs": [{{"value": "{}"}}]}}"#, base64_encode(b"127.0.0.1:6380 x"))).is_err());
+}
+
+#[test]
+fn test_parse_endpoint_slices() {
+    let body = r#"{"kind": "EndpointSliceList", "items": [
+        {"addressType": "IPv4", "ports": [{"name": "metrics", "port": 9121}, {"name": "redis", "port": 6379}], "endpoints": [
+            {"addresses": ["10.1.0.5"], "conditions": {"ready": true}},
+            {"addresses": ["10.1.0.6"], "conditions": {"ready": false}},
+            {"addresses": ["10.1.0.7"]}
+        ]},
+        {"addressType": "IPv6", "ports": [{"port": 6380}], "endpoints": [{"addresses": ["fd00::5"], "conditions": {}}]},
+        {"addressType": "IPv4", "ports": null, "endpoints": null}
+    ]}"#;
+    let hosts: Vec<String> = parse_endpoint_slices(body).unwrap().iter().map(|target| target.host.to_string()).collect();
+    assert_eq!(hosts, vec!["10.1.0.5:6379", "10.1.0.7:6379", "[fd00::5]:6380"]);
+    assert_eq!(parse_endpoint_slices(r#"{"kind": "EndpointSliceList", "items": []}"#).unwrap(), Vec::new());
+    assert!(parse_endpoint_slices(r#"{"kind": "Status", "code": 403}"#).is_err());
+}
+
+#[test]
+fn test_base64() {
+    for &(decoded, encoded) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"/redis/\xff", "L3JlZGlzL/8=")].iter() {
+        assert_eq!(base64_encode(decoded), encoded);
+        assert_eq!(base64_decode(encoded), Some(decoded.to_vec()));
+    }
+    assert_eq!(base64_decode("Z"), None);
+    assert_eq!(base64_decode("Zm9v!"), None);
+    assert_eq!(prefix_end(b"/redis/"), b"/redis0".to_vec());
+    assert_eq!(prefix_end(b"a\xff"), b"b".to_vec());
+    assert_eq!(prefix_end(b""), vec![0]);
+}
@@ -0,0 +1,313 @@
+use rand;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const QUERY_TIMEOUT_MS: u64 = 5000;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+// A target of an SRV record, resolved to an address.
+#[derive(Debug, PartialEq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub host: SocketAddr,
+}
+
+/*
+    Looks up the SRV record of a name, e.g. _redis._tcp.redis.default.svc.cluster.local, by asking the nameserver
+    directly, since the system resolver only resolves addresses. Targets are resolved from the addresses that the
+    nameserver sent along with the record, as Kubernetes' does for headless services, or else through the system
+    resolver. A truncated response is asked for again over TCP.
+*/
+pub fn resolve_srv(nameserver: SocketAddr, name: &str) -> Result<Vec<SrvTarget>, String> {
+    let id = rand::random::<u16>();
+    let query = try!(encode_query(id, name, TYPE_SRV));
+    let response = try!(query_udp(nameserver, &query));
+    let response = if response.len() > 2 && response[2] & 0x02 != 0 {
+        try!(query_tcp(nameserver, &query))
+    } else {
+        response
+    };
+    let mut targets = Vec::new();
+    for record in try!(parse_srv_records(id, &response)) {
+        let ip = match record.address {
+            Some(ip) => ip,
+            None => match (&record.target[..], record.port).to_socket_addrs().map(|mut hosts| hosts.next()) {
+                Ok(Some(host)) => host.ip(),
+                _ => return Err(format!("unable to resolve SRV target {}", record.target)),
+            },
+        };
+        targets.push(SrvTarget {
+            priority: record.priority,
+            weight: record.weight,
+            host: SocketAddr::new(ip, record.port),
+        });
+    }
+    return Ok(targets);
+}
+
+// The first nameserver of /etc/resolv.conf.
+pub fn system_nameserver() -> Result<SocketAddr, String> {
+    let mut contents = String::new();
+    if let Err(err) = File::open("/etc/resolv.conf").and_then(|mut file| file.read_to_string(&mut contents)) {
+        return Err(format!("Unable to read /etc/resolv.conf: {}", err));
+    }
+    return parse_resolv_conf(&contents);
+}
+
+fn parse_resolv_conf(contents: &str) -> Result<SocketAddr, String> {
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            continue;
+        }
+        if let Some(Ok(ip)) = fields.next().map(|ip| ip.parse::<IpAddr>()) {
+            return Ok(SocketAddr::new(ip, 53));
+        }
+    }
+    return Err("No nameserver in /etc/resolv.conf".to_owned());
+}
+
+fn encode_query(id: u16, name: &str, kind: u16) -> Result<Vec<u8>, String> {
+    // The id, recursion desired, and one question.
+    let mut query = vec![(id >> 8) as u8, id as u8, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name {}", name));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&[(kind >> 8) as u8, kind as u8, (CLASS_IN >> 8) as u8, CLASS_IN as u8]);
+    return Ok(query);
+}
+
+fn query_udp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let local: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0),
+    };
+    let mut response = vec![0; 65535];
+    let result = UdpSocket::bind(local).and_then(|socket| {
+        try!(socket.set_read_timeout(Some(Duration::from_millis(QUERY_TIMEOUT_MS))));
+        try!(socket.send_to(query, nameserver));
+        socket.recv(&mut response)
+    });
+    return match result {
+        Ok(len) => {
+            response.truncate(len);
+            Ok(response)
+        }
+        Err(err) => Err(format!("query to {} failed: {}", nameserver, err)),
+    };
+}
+
+// Over TCP, each message is preceded by its length.
+fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> Result<Vec<u8>, String> {
+    let timeout = Duration::from_millis(QUERY_TIMEOUT_MS);
+    let mut response = Vec::new();
+    let result = TcpStream::connect_timeout(&nameserver, timeout).and_then(|mut stream| {
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.write_all(&[(query.len() >> 8) as u8, query.len() as u8]));
+        try!(stream.write_all(query));
+        stream.read_to_end(&mut response)
+    });
+    if let Err(err) = result {
+        return Err(format!("query to {} failed: {}", nameserver, err));
+    }
+    if response.len() < 2 {
+        return Err(format!("empty response from {}", nameserver));
+    }
+    return Ok(response.split_off(2));
+}
+
+struct Record {
+    name: String,
+    kind: u16,
+    // Position of the record's data in the message.
+    data: (usize, usize),
+}
+
+struct Message {
+    // The answer, authority and additional records, in order.
+    records: Vec<Record>,
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Result<u16, String> {
+    return match message.get(offset..offset + 2) {
+        Some(bytes) => Ok((bytes[0] as u16) << 8 | bytes[1] as u16),
+        None => Err("truncated response".to_owned()),
+    };
+}
+
+// Reads a possibly compressed name. Returns it, and the offset after it.
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize), String> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Every pointer must go back, so that a malicious message can't loop.
+    let mut limit = offset;
+    loop {
+        let len = match message.get(offset) {
+            Some(&len) => len as usize,
+            None => return Err("truncated name".to_owned()),
+        };
+        if len == 0 {
+            offset += 1;
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = (try!(read_u16(message, offset)) & 0x3fff) as usize;
+            if pointer >= limit {
+                return Err("invalid name pointer".to_owned());
+            }
+            end = end.or(Some(offset + 2));
+            limit = pointer;
+            offset = pointer;
+            continue;
+        }
+        match message.get(offset + 1..offset + 1 + len) {
+            Some(label) => labels.push(String::from_utf8_lossy(label).into_owned()),
+            None => return Err("truncated name".to_owned()),
+        }
+        offset += 1 + len;
+    }
+    return Ok((labels.join("."), end.unwrap_or(offset)));
+}
+
+fn parse_response(id: u16, response: &[u8]) -> Result<Message, String> {
+    if response.len() < 12 {
+        return Err("truncated response".to_owned());
+    }
+    if try!(read_u16(response, 0)) != id {
+        return Err("response to another query".to_owned());
+    }
+    match response[3] & 0x0f {
+        0 => {}
+        3 => return Err("no such name".to_owned()),
+        rcode => return Err(format!("nameserver failed with rcode {}", rcode)),
+    }
+    let questions = try!(read_u16(response, 4));
+    let records = try!(read_u16(response, 6)) as usize + try!(read_u16(response, 8)) as usize + try!(read_u16(response, 10)) as usize;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = try!(read_name(response, offset)).1 + 4;
+    }
+    let mut message = Message {
+        records: Vec::with_capacity(records),
+    };
+    for _ in 0..records {
+        let (name, after_name) = try!(read_name(response, offset));
+        let kind = try!(read_u16(response, after_name));
+        let data_len = try!(read_u16(response, after_name + 8)) as usize;
+        let data_start = after_name + 10;
+        if data_start + data_len > response.len() {
+            return Err("truncated response".to_owned());
+        }
+        message.records.push(Record {
+            name: name,
+            kind: kind,
+            data: (data_start, data_start + data_len),
+        });
+        offset = data_start + data_len;
+    }
+    return Ok(message);
+}
+
+// An SRV record of a response, with the address of its target if the nameserver sent one along.
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+    address: Option<IpAddr>,
+}
+
+fn parse_srv_records(id: u16, response: &[u8]) -> Result<Vec<SrvRecord>, String> {
+    let message = try!(parse_response(id, response));
+    let mut srv_records = Vec::new();
+    for record in message.records.iter().filter(|record| record.kind == TYPE_SRV) {
+        let (start, end) = record.data;
+        if end - start < 7 {
+            return Err("truncated SRV record".to_owned());
+        }
+        let (target, _) = try!(read_name(response, start + 6));
+        let address = message.records.iter()
+            .filter(|address| address.name.eq_ignore_ascii_case(&target))
+            .filter_map(|address| parse_address(response, address))
+            .next();
+        srv_records.push(SrvRecord {
+            priority: try!(read_u16(response, start)),
+            weight: try!(read_u16(response, start + 2)),
+            port: try!(read_u16(response, start + 4)),
+            target: target,
+            address: address,
+        });
+    }
+    return Ok(srv_records);
+}
+
+fn parse_address(response: &[u8], record: &Record) -> Option<IpAddr> {
+    let data = &response[record.data.0..record.data.1];
+    match (record.kind, data.len()) {
+        (TYPE_A, 4) => return Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        (TYPE_AAAA, 16) => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(data);
+            return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+        }
+        _ => return None,
+    }
+}
+
+#[test]
+fn test_parse_srv_records() {
+    let query = encode_query(0x1234, "_redis._tcp.redis.local.", TYPE_SRV).unwrap();
+    let mut response = query.clone();
+    // A response, with 2 answers and 1 additional record.
+    response[2] = 0x81;
+    response[3] = 0x80;
+    response[7] = 2;
+    response[11] = 1;
+    // Both answers point back at the question's name, at offset 12.
+    for &(priority, weight, port, target) in [(10, 60, 6380, &b"\x06redis0\x05local\x00"[..]), (20, 0, 6381, &b"\x06redis1\xc0\x1e"[..])].iter() {
+        response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 30, 0, 6 + target.len() as u8]);
+        response.extend_from_slice(&[0, priority, 0, weight, (port >> 8) as u8, port as u8]);
+        response.extend_from_slice(target);
+    }
+    // The address of redis0.local, whose name is in the first answer.
+    let redis0 = query.len() + 18;
+    response.extend_from_slice(&[0xc0, redis0 as u8, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 10, 0, 0, 5]);
+
+    let records = parse_srv_records(0x1234, &response).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!((records[0].priority, records[0].weight, records[0].port), (10, 60, 6380));
+    assert_eq!(records[0].target, "redis0.local");
+    assert_eq!(records[0].address, Some("10.0.0.5".parse().unwrap()));
+    assert_eq!((records[1].priority, records[1].weight, records[1].port), (20, 0, 6381));
+    assert_eq!(records[1].target, "redis1.local");
+    assert_eq!(records[1].address, None);
+
+    assert!(parse_srv_records(0x4321, &response).is_err());
+    assert!(parse_srv_records(0x1234, &response[..response.len() - 3]).is_err());
+    let mut nxdomain = query.clone();
+    nxdomain[3] = 0x83;
+    assert!(parse_srv_records(0x1234, &nxdomain).is_err());
+    // A name that points at itself.
+    let mut looping = query.clone();
+    looping[7] = 1;
+    looping.extend_from_slice(&[0xc0, query.len() as u8]);
+    assert!(parse_srv_records(0x1234, &looping).is_err());
+}
+
+#[test]
+fn test_parse_resolv_conf() {
+    assert_eq!(parse_resolv_conf("# generated\nsearch local\nnameserver 10.96.0.10\nnameserver 8.8.8.8\n"), Ok("10.96.0.10:53".parse().unwrap()));
+    assert_eq!(parse_resolv_conf("nameserver fd00::53"), Ok("[fd00::53]:53".parse().unwrap()));
+    assert!(parse_resolv_conf("search local").is_err());
+}
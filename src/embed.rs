@@ -0,0 +1,243 @@
+use config::RedFlareProxyConfig;
+use filter::Filter;
+use redflareproxy::{ProxyError, RedFlareProxy, CONTROL};
+use registry::{Interest, Registry};
+
+use mio::{Ready, Registration, SetReadiness};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+// A request to the event loop of an embedded proxy.
+pub enum ControlRequest {
+    // An admin command, with its arguments on separate lines, and where to send its reply.
+    Command(String, Sender<Result<String, String>>),
+    // Stops accepting clients, and stops the proxy once in-flight requests are answered or the shutdown_timeout passes.
+    Stop,
+}
+
+/*
+    The event loop's end of an embedded proxy's control channel. Sending a request wakes the loop up, and requests are
+    handled at the start of the next iteration.
+*/
+pub struct ControlReceiver {
+    requests: Receiver<ControlRequest>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+impl ControlReceiver {
+    pub fn register(&self, registry: &Registry) -> Result<(), std::io::Error> {
+        return registry.register_static(&self.registration, CONTROL, Interest::Readable);
+    }
+
+    // Returns the requests sent since the last call.
+    pub fn take_requests(&self) -> Vec<ControlRequest> {
+        // Cleared before reading, so that a request sent in between wakes the loop up again.
+        let _ = self.readiness.set_readiness(Ready::empty());
+        return self.requests.try_iter().collect();
+    }
+}
+
+/*
+    A proxy running on a thread of its own, for embedding the proxy in a test harness or a custom daemon instead of
+    running the redflareproxy binary. Admin commands can be sent through the handle as well as to the admin port.
+    Dropping the handle stops the proxy and waits for it. e.g.:
+
+    let handle = try!(ProxyHandle::start(config));
+    let stats = handle.command(&["STATS"]);
+    handle.stop();
+    try!(handle.join());
+
+    The process-wide settings of the binary are left to the embedder: no signal handlers are installed, and logging
+    goes through whatever logger the embedder has set up. The buffer pool and the memory_limit are shared by every
+    proxy in the process.
+*/
+pub struct ProxyHandle {
+    requests: Sender<ControlRequest>,
+    readiness: SetReadiness,
+    thread: Option<JoinHandle<Result<(), ProxyError>>>,
+}
+
+impl ProxyHandle {
+    /*
+        Starts a proxy for the config, and returns once its ports are bound, or with the reason it failed to start.
+        Embedded proxies run a single worker.
+    */
+    pub fn start(config: RedFlareProxyConfig) -> Result<ProxyHandle, ProxyError> {
+        return ProxyHandle::start_with_filters(config, Vec::new());
+    }
+
+    /*
+        Starts a proxy with filters added to its pools, as (pool name, filter) pairs in the order they run. See
+        filter::Filter. The filters are moved to the proxy's thread.
+    */
+    pub fn start_with_filters(config: RedFlareProxyConfig, filters: Vec<(String, Box<dyn Filter + Send>)>) -> Result<ProxyHandle, ProxyError> {
+        if config.workers > 1 {
+            return Err(ProxyError::EmbeddedWorkers(config.workers));
+        }
+        let (registration, readiness) = Registration::new2();
+        let (sender, receiver) = channel();
+        let control = ControlReceiver {
+            requests: receiver,
+            registration: registration,
+            readiness: readiness.clone(),
+        };
+        let (started_sender, started) = channel();
+        let spawned = thread::Builder::new().name("redflareproxy".to_owned()).spawn(move || {
+            let mut redflareproxy = try!(RedFlareProxy::new(config, String::new(), None, None, None));
+            try!(redflareproxy.set_control(control));
+            for (pool_name, filter) in filters {
+                try!(redflareproxy.add_filter(&pool_name, filter));
+            }
+            let _ = started_sender.send(());
+            return redflareproxy.run();
+        });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(err) => return Err(ProxyError::EmbeddedThreadFailure(err)),
+        };
+        if started.recv().is_err() {
+            // The thread exited without starting the proxy. Its result has the reason.
+            return match thread.join() {
+                Ok(Err(err)) => Err(err),
+                _ => Err(ProxyError::EmbeddedProxyPanicked),
+            };
+        }
+        Ok(ProxyHandle {
+            requests: sender,
+            readiness: readiness,
+            thread: Some(thread),
+        })
+    }
+
+    /*
+        Runs an admin command, e.g. &["STATS"] or &["LOADCONFIG", path], and waits for its reply. Error replies, and
+        commands sent after the proxy stopped, return Err.
+    */
+    pub fn command(&self, args: &[&str]) -> Result<String, String> {
+        let (reply_sender, reply) = channel();
+        if !self.send(ControlRequest::Command(args.join("\n"), reply_sender)) {
+            return Err("Proxy has stopped".to_owned());
+        }
+        match reply.recv() {
+            Ok(result) => result,
+            Err(_) => Err("Proxy has stopped".to_owned()),
+        }
+    }
+
+    // Begins a graceful shutdown, without waiting for it. See join.
+    pub fn stop(&self) {
+        self.send(ControlRequest::Stop);
+    }
+
+    // Waits for the proxy to stop, and returns the error it stopped with, if any.
+    pub fn join(mut self) -> Result<(), ProxyError> {
+        return self.wait();
+    }
+
+    fn send(&self, request: ControlRequest) -> bool {
+        if self.requests.send(request).is_err() {
+            return false;
+        }
+        let _ = self.readiness.set_readiness(Ready::readable());
+        return true;
+    }
+
+    fn wait(&mut self) -> Result<(), ProxyError> {
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(ProxyError::EmbeddedProxyPanicked),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ProxyHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.stop();
+            if let Err(err) = self.wait() {
+                error!("Embedded proxy stopped with an error: {}", err);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_embedded_proxy() {
+    use config::{ConfigBuilder, PoolBuilder};
+    let config = ConfigBuilder::new("127.0.0.1:0")
+        .pool("pool1", PoolBuilder::new("127.0.0.1:0").server("127.0.0.1:1", 1))
+        .build()
+        .unwrap();
+    let handle = ProxyHandle::start(config.clone()).unwrap();
+    assert_eq!(handle.command(&["PING"]), Ok("PONG".to_owned()));
+    assert!(handle.command(&["STATS"]).unwrap().starts_with("Stats:\n"));
+    assert!(handle.command(&["SWITCHCONFIG"]).is_err());
+    handle.stop();
+    handle.join().unwrap();
+
+    // Errors starting the proxy are returned by start.
+    let mut config = config;
+    config.admin.listen = "not an address".to_owned();
+    match ProxyHandle::start(config.clone()) {
+        Err(ProxyError::AdminParseFailure(..)) => {}
+        _ => panic!("Expected the admin address to fail to parse"),
+    }
+    config.workers = 2;
+    assert!(ProxyHandle::start(config).is_err());
+}
+
+#[test]
+fn test_embedded_proxy_filters() {
+    use config::{ConfigBuilder, PoolBuilder};
+    use filter::{FilterAction, FilterContext};
+    use std::io::{Read, Write};
+
+    // Answers GET a itself, and replaces the proxy's error responses.
+    struct Policy;
+    impl Filter for Policy {
+        fn on_request(&mut self, _context: &mut FilterContext, request: &[u8]) -> FilterAction {
+            if request == b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n" {
+                return FilterAction::Reply(b"$8\r\nfiltered\r\n".to_vec());
+            }
+            return FilterAction::Continue;
+        }
+
+        fn on_response(&mut self, _context: &mut FilterContext, response: &[u8]) -> FilterAction {
+            if response.starts_with(b"-") {
+                return FilterAction::Rewrite(b"-ERROR: Unavailable\r\n".to_vec());
+            }
+            return FilterAction::Continue;
+        }
+    }
+
+    let listen = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let config = ConfigBuilder::new("127.0.0.1:0")
+        .pool("pool1", PoolBuilder::new(&listen).server("127.0.0.1:1", 1))
+        .build()
+        .unwrap();
+    match ProxyHandle::start_with_filters(config.clone(), vec![("pool2".to_owned(), Box::new(Policy) as Box<dyn Filter + Send>)]) {
+        Err(ProxyError::FilterUnknownPool(..)) => {}
+        _ => panic!("Expected the filter's pool to be unknown"),
+    }
+    let handle = ProxyHandle::start_with_filters(config, vec![("pool1".to_owned(), Box::new(Policy) as Box<dyn Filter + Send>)]).unwrap();
+
+    let mut client = std::net::TcpStream::connect(&*listen).unwrap();
+    let read = |client: &mut std::net::TcpStream, len: usize| {
+        let mut response = vec![0; len];
+        client.read_exact(&mut response).unwrap();
+        response
+    };
+    client.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n").unwrap();
+    assert_eq!(read(&mut client, 14), b"$8\r\nfiltered\r\n".to_vec());
+    // The backend isn't reachable.
+    client.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n").unwrap();
+    assert_eq!(read(&mut client, 21), b"-ERROR: Unavailable\r\n".to_vec());
+    drop(handle);
+}
@@ -0,0 +1,309 @@
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::Aes256Gcm;
+use audit::glob_match;
+use filter::{encode_command, Filter, FilterAction, FilterContext};
+use log::LogLevel;
+use rand::{OsRng, Rng};
+use redisprotocol::command_name;
+use slowlog::split_args;
+use std::fs::File;
+use std::io::Read;
+
+// Starts every encrypted value, so that values written before encryption was enabled are still passed on as they are.
+const MAGIC: &'static [u8] = b"\x00RFE1";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+const UNSUPPORTED: &'static [u8] = b"-ERROR: Command is not supported on encrypted values\r\n";
+const ENCRYPTION_FAILED: &'static [u8] = b"-ERROR: Unable to encrypt value\r\n";
+const DECRYPTION_FAILED: &'static [u8] = b"-ERROR: Unable to decrypt value\r\n";
+
+// Commands that read or change part of a string value, which can't be done on its ciphertext.
+static UNSUPPORTED_COMMANDS: [&str; 14] = [
+    "APPEND", "BITCOUNT", "BITFIELD", "BITPOS", "DECR", "DECRBY", "GETBIT", "GETRANGE", "INCR", "INCRBY", "INCRBYFLOAT",
+    "SETBIT", "SETRANGE", "STRLEN"
+];
+
+/*
+    Encrypts the string values of a pool with AES-256-GCM, so that they're encrypted at rest in redis without changing
+    the applications, from its encryption_keys file. Each line of the file is a key id and a hex encoded 256 bit key,
+    e.g. as written out by a KMS agent, and # starts a comment:
+
+    2024-01 8f3c...
+    2024-02 51d0...
+
+    New values are encrypted with encryption_key_id, or the last key of the file. Each encrypted value is the magic
+    bytes, the length and id of its key, a random nonce, then the ciphertext, so that values written with older keys
+    can be read until they're rewritten, and keys are rotated by adding a line and reloading the config.
+
+    The values of SET, SETNX, SETEX, PSETEX, GETSET and MSET are encrypted, for the keys that match encrypted_keys, or
+    every key if it's empty. Encrypted bulk strings are decrypted in any response, and other values are passed on as
+    they are. Commands that work on part of a value, e.g. INCR or APPEND, are refused for those keys. Hashes, lists,
+    sets and sorted sets, and the values that scripts see, are not encrypted.
+
+    Runs after the pool's lua_filter and wasm_filter, so they see plaintext requests, but encrypted responses.
+*/
+pub struct Encryption {
+    // Key ids and their ciphers, in the order of the file.
+    keys: Vec<(Vec<u8>, Aes256Gcm)>,
+    // The key new values are encrypted with.
+    active: usize,
+    patterns: Vec<Vec<u8>>,
+    rng: OsRng,
+}
+
+impl Encryption {
+    pub fn load(path: &str, key_id: Option<&str>, patterns: &[String]) -> Result<Encryption, String> {
+        let mut contents = String::new();
+        if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            return Err(format!("Unable to read {}: {}", path, err));
+        }
+        return Encryption::from_keys(&contents, key_id, patterns).map_err(|err| format!("{} in {}", err, path));
+    }
+
+    fn from_keys(contents: &str, key_id: Option<&str>, patterns: &[String]) -> Result<Encryption, String> {
+        let mut keys = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 || parts[0].len() > 255 {
+                return Err(format!("Invalid key line '{}', expected a key id and a hex key", parts[0]));
+            }
+            let key = match parse_hex(parts[1]) {
+                Some(ref key) if key.len() == KEY_LEN => Aes256Gcm::new(GenericArray::from_slice(&key[..])),
+                _ => return Err(format!("Key {} is not {} hex encoded bytes", parts[0], KEY_LEN)),
+            };
+            if keys.iter().any(|&(ref id, _)| id == parts[0].as_bytes()) {
+                return Err(format!("Duplicate key id {}", parts[0]));
+            }
+            keys.push((parts[0].as_bytes().to_vec(), key));
+        }
+        if keys.is_empty() {
+            return Err("No keys".to_owned());
+        }
+        let active = match key_id {
+            None => keys.len() - 1,
+            Some(key_id) => match keys.iter().position(|&(ref id, _)| id == key_id.as_bytes()) {
+                Some(active) => active,
+                None => return Err(format!("Unknown encryption_key_id {}", key_id)),
+            },
+        };
+        let rng = match OsRng::new() {
+            Ok(rng) => rng,
+            Err(err) => return Err(format!("Unable to open the system's random number generator: {}", err)),
+        };
+        return Ok(Encryption {
+            keys: keys,
+            active: active,
+            patterns: patterns.iter().map(|pattern| pattern.clone().into_bytes()).collect(),
+            rng: rng,
+        });
+    }
+
+    fn encrypts(&self, key: &[u8]) -> bool {
+        return self.patterns.is_empty() || self.patterns.iter().any(|pattern| glob_match(pattern, key));
+    }
+
+    fn encrypt(&mut self, value: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill_bytes(&mut nonce);
+        let (ref id, ref cipher) = self.keys[self.active];
+        let ciphertext = match cipher.encrypt(GenericArray::from_slice(&nonce), value) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return None,
+        };
+        let mut encrypted = Vec::with_capacity(MAGIC.len() + 1 + id.len() + NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.push(id.len() as u8);
+        encrypted.extend_from_slice(id);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        return Some(encrypted);
+    }
+
+    fn decrypt(&self, value: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let id_len = match value.get(MAGIC.len()) {
+            Some(&id_len) => id_len as usize,
+            None => return Err("truncated value"),
+        };
+        let id_start = MAGIC.len() + 1;
+        let nonce_start = id_start + id_len;
+        if value.len() < nonce_start + NONCE_LEN {
+            return Err("truncated value");
+        }
+        let cipher = match self.keys.iter().find(|&&(ref id, _)| &id[..] == &value[id_start..nonce_start]) {
+            Some(&(_, ref cipher)) => cipher,
+            None => return Err("unknown key id"),
+        };
+        let nonce = GenericArray::from_slice(&value[nonce_start..nonce_start + NONCE_LEN]);
+        return cipher.decrypt(nonce, &value[nonce_start + NONCE_LEN..]).map_err(|_| "wrong key or tampered value");
+    }
+
+    // The indexes of the arguments of a request that are values to encrypt.
+    fn values_to_encrypt(&self, command: &str, args: &[&[u8]]) -> Vec<usize> {
+        let values: Vec<usize> = match command {
+            "SET" | "SETNX" | "GETSET" if args.len() >= 3 => vec![2],
+            "SETEX" | "PSETEX" if args.len() >= 4 => vec![3],
+            "MSET" => (2..args.len()).step_by(2).collect(),
+            _ => Vec::new(),
+        };
+        return values.into_iter().filter(|&index| self.encrypts(args[if command == "MSET" { index - 1 } else { 1 }])).collect();
+    }
+
+    // Copies the redis message at index to output, with its encrypted bulk strings decrypted.
+    fn decrypt_message(&self, message: &[u8], index: &mut usize, output: &mut Vec<u8>) -> Result<(), &'static str> {
+        let end = match message[*index..].windows(2).position(|window| window == b"\r\n") {
+            Some(position) => *index + position,
+            None => return Err("malformed response"),
+        };
+        let header = &message[*index..end + 2];
+        let len = || std::str::from_utf8(&header[1..header.len() - 2]).ok().and_then(|len| len.parse::<isize>().ok()).ok_or("malformed response");
+        *index = end + 2;
+        match header[0] {
+            b'$' => {
+                let len = try!(len());
+                if len < 0 {
+                    output.extend_from_slice(header);
+                    return Ok(());
+                }
+                let value = match message.get(*index..*index + len as usize) {
+                    Some(value) => value,
+                    None => return Err("malformed response"),
+                };
+                *index += len as usize + 2;
+                if !value.starts_with(MAGIC) {
+                    output.extend_from_slice(header);
+                    output.extend_from_slice(value);
+                } else {
+                    let plaintext = try!(self.decrypt(value));
+                    output.extend_from_slice(format!("${}\r\n", plaintext.len()).as_bytes());
+                    output.extend_from_slice(&plaintext);
+                }
+                output.extend_from_slice(b"\r\n");
+            }
+            b'*' => {
+                output.extend_from_slice(header);
+                for _ in 0..try!(len()) {
+                    try!(self.decrypt_message(message, index, output));
+                }
+            }
+            _ => output.extend_from_slice(header),
+        }
+        return Ok(());
+    }
+}
+
+impl Filter for Encryption {
+    fn on_request(&mut self, context: &mut FilterContext, request: &[u8]) -> FilterAction {
+        let command = command_name(request);
+        let args = split_args(request);
+        if UNSUPPORTED_COMMANDS.contains(&command) && args.len() >= 2 && self.encrypts(args[1]) {
+            return FilterAction::Reply(UNSUPPORTED.to_vec());
+        }
+        let values = self.values_to_encrypt(command, &args);
+        if values.is_empty() {
+            return FilterAction::Continue;
+        }
+        let mut encrypted: Vec<Vec<u8>> = Vec::with_capacity(args.len());
+        for (index, arg) in args.iter().enumerate() {
+            if !values.contains(&index) {
+                encrypted.push(arg.to_vec());
+                continue;
+            }
+            match self.encrypt(arg) {
+                Some(value) => encrypted.push(value),
+                None => {
+                    log_event!(LogLevel::Warn, "encryption_failed", { pool: context.pool }, "Unable to encrypt a value of a {} in pool {}", command, context.pool);
+                    return FilterAction::Reply(ENCRYPTION_FAILED.to_vec());
+                }
+            }
+        }
+        return FilterAction::Rewrite(encode_command(encrypted.iter().map(|arg| &arg[..])));
+    }
+
+    fn on_response(&mut self, context: &mut FilterContext, response: &[u8]) -> FilterAction {
+        if !response.windows(MAGIC.len()).any(|window| window == MAGIC) {
+            return FilterAction::Continue;
+        }
+        let mut output = Vec::with_capacity(response.len());
+        let mut index = 0;
+        while index < response.len() {
+            if let Err(err) = self.decrypt_message(response, &mut index, &mut output) {
+                log_event!(LogLevel::Warn, "decryption_failed", { pool: context.pool }, "Unable to decrypt a value in pool {}: {}", context.pool, err);
+                return FilterAction::Reply(DECRYPTION_FAILED.to_vec());
+            }
+        }
+        return FilterAction::Rewrite(output);
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    return (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect();
+}
+
+#[cfg(test)]
+const TEST_KEYS: &'static str = "# rotated monthly\n\
+    2024-01 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n\
+    2024-02 202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f # current\n";
+
+#[test]
+fn test_load_keys() {
+    let encryption = Encryption::from_keys(TEST_KEYS, None, &[]).unwrap();
+    assert_eq!(encryption.keys.len(), 2);
+    assert_eq!(encryption.keys[encryption.active].0, b"2024-02".to_vec());
+    assert_eq!(Encryption::from_keys(TEST_KEYS, Some("2024-01"), &[]).unwrap().active, 0);
+    assert!(Encryption::from_keys(TEST_KEYS, Some("2023-12"), &[]).is_err());
+    assert!(Encryption::from_keys("", None, &[]).is_err());
+    assert!(Encryption::from_keys("a 0011", None, &[]).is_err());
+    assert!(Encryption::from_keys("a zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f", None, &[]).is_err());
+}
+
+#[test]
+fn test_encrypt_values() {
+    let mut encryption = Encryption::from_keys(TEST_KEYS, None, &["secret:*".to_owned()]).unwrap();
+    let mut context = FilterContext::new("pool1", 1);
+    let request = encode_command([&b"SET"[..], b"secret:a", b"value", b"EX", b"10"].iter().cloned());
+    let encrypted = match encryption.on_request(&mut context, &request) {
+        FilterAction::Rewrite(encrypted) => encrypted,
+        action => panic!("Expected a rewritten request, got {:?}", action),
+    };
+    let args = split_args(&encrypted);
+    assert_eq!((args[1], args[3], args[4]), (&b"secret:a"[..], &b"EX"[..], &b"10"[..]));
+    assert!(args[2].starts_with(b"\x00RFE1\x072024-02"));
+
+    // Only the values of matching keys are encrypted.
+    let request = encode_command([&b"MSET"[..], b"other", b"a", b"secret:b", b"b"].iter().cloned());
+    let encrypted = match encryption.on_request(&mut context, &request) {
+        FilterAction::Rewrite(encrypted) => encrypted,
+        action => panic!("Expected a rewritten request, got {:?}", action),
+    };
+    let mset_args = split_args(&encrypted);
+    assert_eq!(mset_args[2], b"a");
+    assert!(mset_args[4].starts_with(MAGIC));
+    assert_eq!(encryption.on_request(&mut context, b"*2\r\n$3\r\nGET\r\n$8\r\nsecret:a\r\n"), FilterAction::Continue);
+    assert_eq!(encryption.on_request(&mut context, b"*2\r\n$4\r\nINCR\r\n$8\r\nsecret:a\r\n"), FilterAction::Reply(UNSUPPORTED.to_vec()));
+    assert_eq!(encryption.on_request(&mut context, b"*2\r\n$4\r\nINCR\r\n$7\r\ncounter\r\n"), FilterAction::Continue);
+
+    // Decrypts bulk strings anywhere in a response, and passes on plaintext ones.
+    let mut response = format!("*3\r\n${}\r\n", args[2].len()).into_bytes();
+    response.extend_from_slice(args[2]);
+    response.extend_from_slice(b"\r\n$-1\r\n$5\r\nplain\r\n");
+    assert_eq!(encryption.on_response(&mut context, &response), FilterAction::Rewrite(b"*3\r\n$5\r\nvalue\r\n$-1\r\n$5\r\nplain\r\n".to_vec()));
+    assert_eq!(encryption.on_response(&mut context, b"$5\r\nplain\r\n"), FilterAction::Continue);
+
+    // Values written with an older key are still read after it's rotated, until the key is removed.
+    let rotated = Encryption::from_keys(&format!("{}2024-03 {}\n", TEST_KEYS, "ff".repeat(32)), None, &[]).unwrap();
+    assert_eq!(rotated.decrypt(args[2]), Ok(b"value".to_vec()));
+    let removed = Encryption::from_keys("2024-03 ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", None, &[]).unwrap();
+    assert!(removed.decrypt(args[2]).is_err());
+    let mut tampered = args[2].to_vec();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(encryption.decrypt(&tampered).is_err());
+}
@@ -0,0 +1,98 @@
+use config::ErrorResponsesConfig;
+
+/*
+    The errors that the proxy answers requests with itself: their name in error_responses, their code, their text, and
+    the responses the proxy sends for them by default.
+*/
+static PROXY_ERRORS: [(&str, &str, &str, &[&[u8]]); 7] = [
+    ("timeout", "PROXY_TIMEOUT", "Proxy timed out", &[b"-ERR Proxy timed out\r\n"]),
+    (
+        "backend_unavailable",
+        "PROXY_UNAVAILABLE",
+        "Backend unavailable",
+        &[b"-ERROR: Not connected\r\n", b"-ERR: Unavailable backend.\r\n", b"-ERR Backend disconnected\r\n"]
+    ),
+    ("no_backend", "PROXY_NO_BACKEND", "No backend", &[b"-ERROR: No backend\r\n"]),
+    ("overloaded", "PROXY_OVERLOADED", "Backend overloaded", &[b"-ERROR: Backend overloaded\r\n"]),
+    ("memory_limit", "PROXY_MEMORY_LIMIT", "Proxy memory limit reached", &[b"-ERROR: Proxy memory limit reached\r\n"]),
+    ("rate_limited", "PROXY_RATE_LIMITED", "Rate limit exceeded", &[b"-ERR rate limit exceeded\r\n"]),
+    ("quota_exceeded", "PROXY_QUOTA_EXCEEDED", "Quota exceeded", &[b"-ERR quota exceeded\r\n"]),
+];
+
+/*
+    The error_responses of a pool, which replace the text of the errors that the proxy answers requests with itself,
+    e.g. so that clients can tell a timeout of the proxy's from an error of redis', and retry it. With codes, each error
+    starts with a code of its own instead of ERR, e.g. -PROXY_TIMEOUT Proxy timed out.
+
+    The errors are replaced as they're written to the client, whichever part of the proxy answered with them, so the
+    rest of the proxy only ever sees the default ones.
+*/
+pub struct ErrorResponses {
+    // The default responses, and what's sent instead.
+    replacements: Vec<(&'static [u8], Vec<u8>)>,
+}
+
+impl ErrorResponses {
+    pub fn from_config(config: &ErrorResponsesConfig) -> Result<ErrorResponses, String> {
+        let custom = [
+            &config.timeout, &config.backend_unavailable, &config.no_backend, &config.overloaded, &config.memory_limit,
+            &config.rate_limited, &config.quota_exceeded
+        ];
+        let mut replacements = Vec::new();
+        for (&(name, code, text, defaults), custom) in PROXY_ERRORS.iter().zip(custom.iter()) {
+            let response = match **custom {
+                Some(ref response) => {
+                    if response.is_empty() || response.contains(|c: char| c == '\r' || c == '\n') {
+                        return Err(format!("Invalid error response for {}: '{}'. Expected a single line", name, response));
+                    }
+                    format!("-{}\r\n", response)
+                }
+                None if config.codes => format!("-{} {}\r\n", code, text),
+                None => continue,
+            };
+            for default in defaults.iter() {
+                replacements.push((*default, response.clone().into_bytes()));
+            }
+        }
+        return Ok(ErrorResponses {
+            replacements: replacements,
+        });
+    }
+
+    // What's sent instead of a response, if it's one of the proxy's errors that the pool replaced.
+    pub fn replace(&self, response: &[u8]) -> Option<&[u8]> {
+        if response.first() != Some(&b'-') {
+            return None;
+        }
+        return self.replacements.iter().find(|&&(default, _)| default == response).map(|&(_, ref replacement)| &replacement[..]);
+    }
+}
+
+#[test]
+fn test_error_responses() {
+    let mut config = ErrorResponsesConfig {
+        codes: false,
+        timeout: Some("TIMEOUT Request timed out".to_owned()),
+        backend_unavailable: None,
+        no_backend: None,
+        overloaded: None,
+        memory_limit: None,
+        rate_limited: None,
+        quota_exceeded: None,
+    };
+    let responses = ErrorResponses::from_config(&config).unwrap();
+    assert_eq!(responses.replace(b"-ERR Proxy timed out\r\n"), Some(&b"-TIMEOUT Request timed out\r\n"[..]));
+    assert_eq!(responses.replace(b"-ERROR: No backend\r\n"), None);
+    assert_eq!(responses.replace(b"+OK\r\n"), None);
+
+    config.codes = true;
+    let responses = ErrorResponses::from_config(&config).unwrap();
+    assert_eq!(responses.replace(b"-ERR Proxy timed out\r\n"), Some(&b"-TIMEOUT Request timed out\r\n"[..]));
+    assert_eq!(responses.replace(b"-ERR: Unavailable backend.\r\n"), Some(&b"-PROXY_UNAVAILABLE Backend unavailable\r\n"[..]));
+    assert_eq!(responses.replace(b"-ERR Backend disconnected\r\n"), Some(&b"-PROXY_UNAVAILABLE Backend unavailable\r\n"[..]));
+    // Errors from redis are left as they are.
+    assert_eq!(responses.replace(b"-ERR unknown command 'FOO'\r\n"), None);
+
+    config.overloaded = Some("BUSY\r\n+OK".to_owned());
+    assert!(ErrorResponses::from_config(&config).is_err());
+}
@@ -0,0 +1,262 @@
+use config::BackendPoolConfig;
+use log::LogLevel;
+use logging;
+#[cfg(feature = "lua")]
+use luafilter::LuaFilter;
+#[cfg(feature = "wasm")]
+use wasmfilter::WasmFilter;
+#[cfg(feature = "encryption")]
+use encryption::Encryption;
+use redflareproxy::ClientTokenValue;
+use rewrite::RewriteRules;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Shared between a pool and its clients, which run the response filters.
+pub type SharedFilters = Rc<RefCell<FilterChain>>;
+
+// What a filter does with a request or response.
+#[derive(Debug, PartialEq)]
+pub enum FilterAction {
+    // Passes it on unchanged, to the next filter and then to the backend or client.
+    Continue,
+    // Passes on this instead, to the next filter. Must be a whole redis message.
+    Rewrite(Vec<u8>),
+    /*
+        Skips the remaining filters. For a request, this is sent to the client as the response, and the request isn't
+        routed to a backend. For a response, this is sent to the client instead.
+    */
+    Reply(Vec<u8>),
+}
+
+// The request or response being filtered.
+pub struct FilterContext<'a> {
+    pub pool: &'a str,
+    pub client: ClientTokenValue,
+    annotations: Vec<(String, String)>,
+}
+
+impl<'a> FilterContext<'a> {
+    pub fn new(pool: &'a str, client: ClientTokenValue) -> FilterContext<'a> {
+        FilterContext {
+            pool: pool,
+            client: client,
+            annotations: Vec::new(),
+        }
+    }
+
+    // Adds a field to the filter_annotation event logged once the filters are done, e.g. ("tenant", "a").
+    pub fn annotate(&mut self, key: &str, value: &str) {
+        self.annotations.push((key.to_owned(), value.to_owned()));
+    }
+}
+
+/*
+    A policy applied to the traffic of a pool, e.g. rejecting commands, renaming keys for a tenant or redacting
+    responses, without changing the routing. Filters are registered per pool by embedders, with
+    RedFlareProxy::add_filter or ProxyHandle::start_with_filters, and run in the order they were added.
+
+    Requests are filtered after they're parsed, and before they're routed. Multikey requests are filtered before they
+    are split, and their responses once they're joined. Error responses from the proxy are filtered as well.
+*/
+pub trait Filter {
+    fn on_request(&mut self, _context: &mut FilterContext, _request: &[u8]) -> FilterAction {
+        return FilterAction::Continue;
+    }
+
+    fn on_response(&mut self, _context: &mut FilterContext, _response: &[u8]) -> FilterAction {
+        return FilterAction::Continue;
+    }
+}
+
+// The filters of a pool.
+pub struct FilterChain {
+    pool: String,
+    // The filters from the pool's config, which run first. Replaced when the config is switched.
+    configured: Vec<Box<dyn Filter>>,
+    // Filters added by the embedder.
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+    pub fn new(pool: &str) -> FilterChain {
+        FilterChain {
+            pool: pool.to_owned(),
+            configured: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, filter: Box<dyn Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn set_configured(&mut self, filters: Vec<Box<dyn Filter>>) {
+        self.configured = filters;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.configured.is_empty() && self.filters.is_empty();
+    }
+
+    /*
+        Runs the request through the filters. Returns Continue if none of them changed it, Rewrite with the final
+        request if any rewrote it, or the Reply that stopped it.
+    */
+    pub fn on_request(&mut self, client: ClientTokenValue, request: &[u8]) -> FilterAction {
+        return self.run(client, request, "request", |filter, context, message| filter.on_request(context, message));
+    }
+
+    // Runs the response through the filters. Returns the same as on_request.
+    pub fn on_response(&mut self, client: ClientTokenValue, response: &[u8]) -> FilterAction {
+        return self.run(client, response, "response", |filter, context, message| filter.on_response(context, message));
+    }
+
+    fn run<F>(&mut self, client: ClientTokenValue, message: &[u8], direction: &str, mut apply: F) -> FilterAction
+        where F: FnMut(&mut Box<dyn Filter>, &mut FilterContext, &[u8]) -> FilterAction
+    {
+        let mut context = FilterContext::new(&self.pool, client);
+        let mut rewritten: Option<Vec<u8>> = None;
+        let mut result = FilterAction::Continue;
+        for filter in self.configured.iter_mut().chain(self.filters.iter_mut()) {
+            let action = match rewritten {
+                Some(ref message) => apply(filter, &mut context, message),
+                None => apply(filter, &mut context, message),
+            };
+            match action {
+                FilterAction::Continue => {}
+                FilterAction::Rewrite(message) => rewritten = Some(message),
+                FilterAction::Reply(message) => {
+                    result = FilterAction::Reply(message);
+                    break;
+                }
+            }
+        }
+        if !context.annotations.is_empty() && log_enabled!(LogLevel::Info) {
+            let mut fields: Vec<(&str, String)> = vec![("pool", context.pool.to_owned()), ("client", client.to_string())];
+            for (key, value) in context.annotations.iter() {
+                fields.push((key, value.clone()));
+            }
+            let annotations: Vec<String> = context.annotations.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+            logging::with_fields("filter_annotation", &fields, || {
+                info!("Filtered {} of client {} in pool {}: {}", direction, client, context.pool, annotations.join(" "))
+            });
+        }
+        return match (result, rewritten) {
+            (FilterAction::Continue, Some(message)) => FilterAction::Rewrite(message),
+            (result, _) => result,
+        };
+    }
+}
+
+/*
+    Loads the filters configured for a pool: its rewrite_rules, its lua_filter script, its wasm_filter plugin, then the
+    encryption of its values with its encryption_keys. Returns the reason if one couldn't be loaded.
+*/
+pub fn from_config(pool_config: &BackendPoolConfig) -> Result<Vec<Box<dyn Filter>>, String> {
+    let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+    if !pool_config.rewrite_rules.is_empty() {
+        filters.push(Box::new(try!(RewriteRules::from_config(&pool_config.rewrite_rules))));
+    }
+    if let Some(ref path) = pool_config.lua_filter {
+        filters.push(try!(load_lua(path)));
+    }
+    if let Some(ref path) = pool_config.wasm_filter {
+        filters.push(try!(load_wasm(path)));
+    }
+    match pool_config.encryption_keys {
+        Some(ref path) => filters.push(try!(load_encryption(path, pool_config))),
+        None if pool_config.encryption_key_id.is_some() || !pool_config.encrypted_keys.is_empty() => {
+            return Err("encryption_key_id and encrypted_keys require encryption_keys".to_owned());
+        }
+        None => {}
+    }
+    return Ok(filters);
+}
+
+#[cfg(feature = "lua")]
+fn load_lua(path: &str) -> Result<Box<dyn Filter>, String> {
+    return LuaFilter::load(path).map(|filter| Box::new(filter) as Box<dyn Filter>);
+}
+
+#[cfg(not(feature = "lua"))]
+fn load_lua(_path: &str) -> Result<Box<dyn Filter>, String> {
+    return Err("redflareproxy was built without the lua feature".to_owned());
+}
+
+#[cfg(feature = "wasm")]
+fn load_wasm(path: &str) -> Result<Box<dyn Filter>, String> {
+    return WasmFilter::load(path).map(|filter| Box::new(filter) as Box<dyn Filter>);
+}
+
+#[cfg(not(feature = "wasm"))]
+fn load_wasm(_path: &str) -> Result<Box<dyn Filter>, String> {
+    return Err("redflareproxy was built without the wasm feature".to_owned());
+}
+
+#[cfg(feature = "encryption")]
+fn load_encryption(path: &str, pool_config: &BackendPoolConfig) -> Result<Box<dyn Filter>, String> {
+    let key_id = pool_config.encryption_key_id.as_ref().map(|key_id| &key_id[..]);
+    return Encryption::load(path, key_id, &pool_config.encrypted_keys).map(|filter| Box::new(filter) as Box<dyn Filter>);
+}
+
+#[cfg(not(feature = "encryption"))]
+fn load_encryption(_path: &str, _pool_config: &BackendPoolConfig) -> Result<Box<dyn Filter>, String> {
+    return Err("redflareproxy was built without the encryption feature".to_owned());
+}
+
+// Encodes the arguments as a multibulk request, e.g. for filters that rewrite requests from their arguments.
+pub fn encode_command<'a, I: ExactSizeIterator<Item = &'a [u8]>>(args: I) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    return command;
+}
+
+#[test]
+fn test_filter_chain() {
+    // Renames a key, rejects FLUSHALL, and hides the responses to one client.
+    struct Prefix;
+    impl Filter for Prefix {
+        fn on_request(&mut self, _context: &mut FilterContext, request: &[u8]) -> FilterAction {
+            if request == b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n" {
+                return FilterAction::Rewrite(b"*2\r\n$3\r\nGET\r\n$3\r\nt:a\r\n".to_vec());
+            }
+            return FilterAction::Continue;
+        }
+    }
+    struct Deny {
+        denied: usize,
+    }
+    impl Filter for Deny {
+        fn on_request(&mut self, context: &mut FilterContext, request: &[u8]) -> FilterAction {
+            if request.ends_with(b"FLUSHALL\r\n") {
+                self.denied += 1;
+                context.annotate("denied", "FLUSHALL");
+                return FilterAction::Reply(b"-ERROR: Denied\r\n".to_vec());
+            }
+            return FilterAction::Continue;
+        }
+
+        fn on_response(&mut self, context: &mut FilterContext, _response: &[u8]) -> FilterAction {
+            if context.client == 7 {
+                return FilterAction::Rewrite(b"$-1\r\n".to_vec());
+            }
+            return FilterAction::Continue;
+        }
+    }
+
+    let mut chain = FilterChain::new("pool1");
+    chain.add(Box::new(Prefix));
+    chain.add(Box::new(Deny { denied: 0 }));
+
+    assert_eq!(chain.on_request(1, b"*1\r\n$4\r\nPING\r\n"), FilterAction::Continue);
+    assert_eq!(chain.on_request(1, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"), FilterAction::Rewrite(b"*2\r\n$3\r\nGET\r\n$3\r\nt:a\r\n".to_vec()));
+    assert_eq!(chain.on_request(1, b"*1\r\n$8\r\nFLUSHALL\r\n"), FilterAction::Reply(b"-ERROR: Denied\r\n".to_vec()));
+
+    assert_eq!(chain.on_response(1, b"$1\r\nx\r\n"), FilterAction::Continue);
+    assert_eq!(chain.on_response(7, b"$1\r\nx\r\n"), FilterAction::Rewrite(b"$-1\r\n".to_vec()));
+}
@@ -0,0 +1,126 @@
+use resp::{self, Args};
+
+use hashbrown::HashSet;
+
+/*
+    Redis 7 functions, for sharded pools. FCALL can only find a library's functions on the backends it was loaded on,
+    so FUNCTION LOAD and DELETE are sent to every backend of the pool, and FUNCTION LIST lists the libraries of all of
+    them. FCALL and FCALL_RO themselves are sent to the backend of their key, like EVAL.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Broadcast {
+    // Answered with the first backend's response, or the first error of any backend.
+    Agreed,
+    // Answered with the libraries of every backend, each listed once.
+    Libraries,
+}
+
+// How a FUNCTION request is sent to the pool's backends, or None if its subcommand isn't supported.
+pub fn broadcast(request: &[u8]) -> Option<Broadcast> {
+    let mut args = match Args::parse(request) {
+        Ok(args) => args,
+        Err(_) => return None,
+    };
+    let subcommand = match args.nth(1) {
+        Some(Ok(subcommand)) => subcommand.to_ascii_uppercase(),
+        _ => return None,
+    };
+    match &subcommand[..] {
+        b"LOAD" | b"DELETE" => return Some(Broadcast::Agreed),
+        b"LIST" => return Some(Broadcast::Libraries),
+        _ => return None,
+    }
+}
+
+// Combines the responses of every backend to a broadcast request into the client's response.
+pub fn merge(broadcast: Broadcast, responses: &[Vec<u8>]) -> Vec<u8> {
+    if let Some(error) = responses.iter().find(|response| response.first() == Some(&b'-')) {
+        return error.clone();
+    }
+    match broadcast {
+        Broadcast::Agreed => return responses.first().cloned().unwrap_or_default(),
+        Broadcast::Libraries => {
+            let mut names = HashSet::new();
+            let mut libraries = Vec::new();
+            for response in responses {
+                let entries = match elements(response) {
+                    Some(entries) => entries,
+                    None => return response.clone(),
+                };
+                // Backends that all loaded a library list it the same way, so the first one's entry is kept.
+                for library in entries {
+                    if names.insert(library_name(library).unwrap_or(library)) {
+                        libraries.push(library);
+                    }
+                }
+            }
+            let mut merged = format!("*{}\r\n", libraries.len()).into_bytes();
+            for library in libraries {
+                merged.extend_from_slice(library);
+            }
+            return merged;
+        }
+    }
+}
+
+// The elements of an array response, or None if it isn't one.
+fn elements(response: &[u8]) -> Option<Vec<&[u8]>> {
+    if response.first() != Some(&b'*') {
+        return None;
+    }
+    let header_len = match response.iter().position(|byte| *byte == b'\n') {
+        Some(position) => position + 1,
+        None => return None,
+    };
+    let len: usize = match std::str::from_utf8(&response[1..header_len]).ok().and_then(|len| len.trim().parse().ok()) {
+        Some(len) => len,
+        None => return None,
+    };
+    let mut elements = Vec::with_capacity(len);
+    let mut index = header_len;
+    for _ in 0..len {
+        let element_len = match resp::frame_len(&response[index..]) {
+            Ok(element_len) => element_len,
+            Err(_) => return None,
+        };
+        elements.push(&response[index..index + element_len]);
+        index += element_len;
+    }
+    return Some(elements);
+}
+
+// The library_name of an entry of FUNCTION LIST, which lists its fields as name and value pairs.
+fn library_name(library: &[u8]) -> Option<&[u8]> {
+    let fields = match elements(library) {
+        Some(fields) => fields,
+        None => return None,
+    };
+    return fields.chunks(2)
+        .find(|pair| pair.len() == 2 && pair[0] == &b"$12\r\nlibrary_name\r\n"[..])
+        .map(|pair| pair[1]);
+}
+
+#[test]
+fn test_broadcast() {
+    assert_eq!(broadcast(b"*3\r\n$8\r\nFUNCTION\r\n$4\r\nload\r\n$4\r\ncode\r\n"), Some(Broadcast::Agreed));
+    assert_eq!(broadcast(b"*3\r\n$8\r\nFUNCTION\r\n$6\r\nDELETE\r\n$3\r\nlib\r\n"), Some(Broadcast::Agreed));
+    assert_eq!(broadcast(b"*2\r\n$8\r\nFUNCTION\r\n$4\r\nLIST\r\n"), Some(Broadcast::Libraries));
+    assert_eq!(broadcast(b"*2\r\n$8\r\nFUNCTION\r\n$5\r\nFLUSH\r\n"), None);
+    assert_eq!(broadcast(b"*1\r\n$8\r\nFUNCTION\r\n"), None);
+}
+
+#[test]
+fn test_merge() {
+    let responses = vec![b"$3\r\nlib\r\n".to_vec(), b"-ERR Library 'lib' already exists\r\n".to_vec()];
+    assert_eq!(merge(Broadcast::Agreed, &responses), b"-ERR Library 'lib' already exists\r\n".to_vec());
+    assert_eq!(merge(Broadcast::Agreed, &responses[..1]), b"$3\r\nlib\r\n".to_vec());
+
+    let first: &[u8] = b"*4\r\n$12\r\nlibrary_name\r\n$1\r\na\r\n$9\r\nfunctions\r\n*0\r\n";
+    let second: &[u8] = b"*4\r\n$12\r\nlibrary_name\r\n$1\r\nb\r\n$9\r\nfunctions\r\n*0\r\n";
+    let responses = vec![
+        [&b"*1\r\n"[..], first].concat(),
+        [&b"*2\r\n"[..], second, first].concat(),
+        b"*0\r\n".to_vec(),
+    ];
+    assert_eq!(merge(Broadcast::Libraries, &responses), [&b"*2\r\n"[..], first, second].concat());
+}
@@ -0,0 +1,166 @@
+use libc;
+use mio::tcp::TcpListener;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::{Child, Command};
+
+// Listener fds passed by a hot restarting proxy, comma separated, along with its pid.
+const LISTEN_FDS_ENV: &str = "REDFLARE_LISTEN_FDS";
+const PARENT_PID_ENV: &str = "REDFLARE_PARENT_PID";
+// systemd socket activation passes LISTEN_FDS listeners, starting from fd 3, to the process LISTEN_PID.
+const SYSTEMD_LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+const SYSTEMD_LISTEN_PID_ENV: &str = "LISTEN_PID";
+const SYSTEMD_FIRST_FD: RawFd = 3;
+
+/*
+    Listeners inherited from the process that started this one, which are claimed by address instead of binding a
+    new socket. They are only kept on the main thread, so only worker 0 and the admin port claim them. The other workers
+    bind their own listeners with SO_REUSEPORT, which requires the inherited listener to have been bound with it too.
+*/
+struct Inherited {
+    listeners: Vec<(SocketAddr, std::net::TcpListener)>,
+    // Set when started by a hot restart. The parent drains and exits once told that this process took over.
+    parent_pid: Option<libc::pid_t>,
+}
+
+thread_local! {
+    static INHERITED: RefCell<Inherited> = RefCell::new(Inherited { listeners: Vec::new(), parent_pid: None });
+}
+
+/*
+    Collects the listeners passed by a hot restart or by systemd socket activation. This should be called before
+    daemonizing, since systemd passes listeners to a specific pid. The environment variables are cleared, so that they
+    aren't passed on to later restarts.
+*/
+pub fn inherit_listeners() {
+    let mut fds = Vec::new();
+    let mut parent_pid = None;
+    if let Ok(value) = std::env::var(LISTEN_FDS_ENV) {
+        for fd in value.split(',').filter(|fd| !fd.is_empty()) {
+            match fd.parse::<RawFd>() {
+                Ok(fd) => fds.push(fd),
+                Err(_) => error!("Ignoring unrecognized inherited listener fd: {}", fd),
+            }
+        }
+        parent_pid = std::env::var(PARENT_PID_ENV).ok().and_then(|pid| pid.parse().ok());
+    } else if std::env::var(SYSTEMD_LISTEN_PID_ENV).ok().and_then(|pid| pid.parse().ok()) == Some(std::process::id()) {
+        let count = std::env::var(SYSTEMD_LISTEN_FDS_ENV).ok().and_then(|count| count.parse().ok()).unwrap_or(0);
+        for fd in SYSTEMD_FIRST_FD..SYSTEMD_FIRST_FD + count {
+            fds.push(fd);
+        }
+    }
+    for name in &[LISTEN_FDS_ENV, PARENT_PID_ENV, SYSTEMD_LISTEN_FDS_ENV, SYSTEMD_LISTEN_PID_ENV] {
+        std::env::remove_var(name);
+    }
+
+    let mut listeners = Vec::with_capacity(fds.len());
+    for fd in fds {
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => {
+                set_cloexec(fd, true);
+                info!("Inherited listener for {}", addr);
+                listeners.push((addr, listener));
+            }
+            Err(err) => {
+                error!("Ignoring inherited fd {}, which isn't a listening socket: {}", fd, err);
+                // Not ours to close.
+                std::mem::forget(listener);
+            }
+        }
+    }
+    INHERITED.with(|inherited| {
+        *inherited.borrow_mut() = Inherited { listeners: listeners, parent_pid: parent_pid };
+    });
+}
+
+// Whether this process was started by a hot restart of another proxy.
+pub fn is_restart() -> bool {
+    return INHERITED.with(|inherited| inherited.borrow().parent_pid.is_some());
+}
+
+// Returns the inherited listener for addr, if any, instead of binding a new one.
+pub fn take_listener(addr: &SocketAddr) -> Option<Result<TcpListener, std::io::Error>> {
+    let listener = INHERITED.with(|inherited| {
+        let mut inherited = inherited.borrow_mut();
+        match inherited.listeners.iter().position(|&(ref listener_addr, _)| listener_addr == addr) {
+            Some(index) => Some(inherited.listeners.remove(index).1),
+            None => None,
+        }
+    });
+    return listener.map(|listener| {
+        debug!("Using inherited listener for {}", addr);
+        TcpListener::from_std(listener)
+    });
+}
+
+/*
+    Closes inherited listeners that the config no longer has a pool for, and tells the process that started this one
+    to drain and exit. This should be called once the proxy is listening.
+*/
+pub fn finish_takeover() {
+    let (listeners, parent_pid) = INHERITED.with(|inherited| {
+        let mut inherited = inherited.borrow_mut();
+        (std::mem::replace(&mut inherited.listeners, Vec::new()), inherited.parent_pid.take())
+    });
+    for (addr, _) in listeners {
+        warn!("Closing inherited listener for {}, which isn't in the config", addr);
+    }
+    if let Some(pid) = parent_pid {
+        info!("Took over listeners from process {}. Asking it to shut down", pid);
+        if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+            error!("Unable to signal process {}: {}", pid, std::io::Error::last_os_error());
+        }
+    }
+}
+
+/*
+    Starts a new proxy process with the same arguments, passing it the given listeners. The executable is looked up
+    again from argv[0], so that a replaced binary is picked up. This process keeps serving until the new one calls
+    finish_takeover.
+*/
+pub fn spawn_restart(listener_fds: &[RawFd]) -> Result<Child, std::io::Error> {
+    let mut args = std::env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Unable to find the executable to restart")),
+    };
+    let fds: Vec<String> = listener_fds.iter().map(|fd| fd.to_string()).collect();
+    for fd in listener_fds {
+        set_cloexec(*fd, false);
+    }
+    let child = Command::new(program)
+        .args(args)
+        .env(LISTEN_FDS_ENV, fds.join(","))
+        .env(PARENT_PID_ENV, std::process::id().to_string())
+        .spawn();
+    for fd in listener_fds {
+        set_cloexec(*fd, true);
+    }
+    return child;
+}
+
+fn set_cloexec(fd: RawFd, enabled: bool) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 {
+            return;
+        }
+        let flags = if enabled { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+        libc::fcntl(fd, libc::F_SETFD, flags);
+    }
+}
+
+#[test]
+fn test_take_listener() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    INHERITED.with(|inherited| inherited.borrow_mut().listeners.push((addr, listener)));
+
+    assert!(take_listener(&"127.0.0.1:1".parse().unwrap()).is_none());
+    let taken = take_listener(&addr).unwrap().unwrap();
+    assert_eq!(taken.local_addr().unwrap(), addr);
+    // Each listener is only handed out once.
+    assert!(take_listener(&addr).is_none());
+}
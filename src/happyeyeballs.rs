@@ -0,0 +1,217 @@
+use mio::tcp::TcpStream;
+use mio::Token;
+use registry::{Interest, Registered, Registry};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+// How long a connection attempt has before the next address is tried alongside it. See RFC 8305 section 5.
+pub const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+// Checks that a backend's hostname has a port, e.g. redis.internal:6379, without resolving it.
+pub fn validate_hostname(hostname: &str) -> Result<(), String> {
+    let mut parts = hostname.rsplitn(2, ':');
+    let port = parts.next().unwrap_or("");
+    match parts.next() {
+        Some(name) if !name.is_empty() && port.parse::<u16>().is_ok() => return Ok(()),
+        _ => return Err(format!("Invalid hostname: '{}'. Expected a name and port, e.g. redis.internal:6379", hostname)),
+    }
+}
+
+// The addresses of a backend's hostname, in the order to connect to them. Resolved with the system resolver.
+pub fn resolve(hostname: &str) -> Result<Vec<SocketAddr>, String> {
+    let addresses: Vec<SocketAddr> = match hostname.to_socket_addrs() {
+        Ok(addresses) => addresses.collect(),
+        Err(err) => return Err(err.to_string()),
+    };
+    if addresses.is_empty() {
+        return Err("No addresses found".to_owned());
+    }
+    return Ok(interleave_families(&addresses));
+}
+
+/*
+    Orders the addresses of a backend's hostname for connecting, alternating between IPv6 and IPv4 starting with IPv6,
+    as in RFC 8305 section 4. A network where one of the families is broken then only costs one attempt delay.
+*/
+pub fn interleave_families(addresses: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut unique: Vec<SocketAddr> = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        if !unique.contains(address) {
+            unique.push(*address);
+        }
+    }
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = unique.into_iter().partition(|address| address.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::with_capacity(addresses.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (first, second) => {
+                ordered.extend(first);
+                ordered.extend(second);
+            }
+        }
+    }
+}
+
+pub enum RaceState {
+    Connecting,
+    Connected(Registered<TcpStream>, SocketAddr),
+    // Every address was attempted, and none of them connected.
+    Failed,
+}
+
+/*
+    Happy eyeballs: connects to the addresses of a hostname one after the other, without waiting for an attempt to fail
+    before starting the next, and keeps whichever connects first. The next attempt starts once the last one has had
+    CONNECTION_ATTEMPT_DELAY_MS, or right away when an attempt fails.
+
+    Every attempt is registered with the backend's token, so any of them wakes the backend, which then checks them all.
+*/
+pub struct ConnectRace {
+    // Addresses that haven't been attempted yet, in order.
+    remaining: VecDeque<SocketAddr>,
+    attempts: Vec<(Registered<TcpStream>, SocketAddr)>,
+}
+
+impl ConnectRace {
+    pub fn new(addresses: &[SocketAddr]) -> ConnectRace {
+        return ConnectRace {
+            remaining: addresses.iter().cloned().collect(),
+            attempts: Vec::new(),
+        };
+    }
+
+    pub fn has_remaining(&self) -> bool {
+        return !self.remaining.is_empty();
+    }
+
+    /*
+        Starts an attempt to the next address, skipping any that fail right away, e.g. an IPv6 address without an IPv6
+        route. Returns the error of the last address if none could be started.
+    */
+    pub fn start_next(&mut self, registry: &Registry, token: Token) -> Result<(), Error> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "No addresses left to connect to");
+        while let Some(address) = self.remaining.pop_front() {
+            let attempt = TcpStream::connect(&address).and_then(|socket| Registered::new(registry, socket, token, Interest::ReadWrite));
+            match attempt {
+                Ok(socket) => {
+                    debug!("Attempting to connect to {}", address);
+                    self.attempts.push((socket, address));
+                    return Ok(());
+                }
+                Err(err) => {
+                    debug!("Failed to start connecting to {}: {:?}", address, err);
+                    last_error = err;
+                }
+            }
+        }
+        return Err(last_error);
+    }
+
+    // Checks the attempts after an event on the token. Attempts that failed are replaced by the next address.
+    pub fn poll(&mut self, registry: &Registry, token: Token) -> RaceState {
+        let mut index = 0;
+        while index < self.attempts.len() {
+            let connected = {
+                let socket = &self.attempts[index].0;
+                match socket.take_error() {
+                    Ok(None) => match socket.peer_addr() {
+                        Ok(_) => Some(true),
+                        Err(ref err) if err.kind() == ErrorKind::NotConnected => None,
+                        Err(_) => Some(false),
+                    },
+                    _ => Some(false),
+                }
+            };
+            match connected {
+                Some(true) => {
+                    // The other attempts are closed as they're dropped.
+                    let (socket, address) = self.attempts.swap_remove(index);
+                    self.attempts.clear();
+                    self.remaining.clear();
+                    return RaceState::Connected(socket, address);
+                }
+                Some(false) => {
+                    debug!("Failed to connect to {}", self.attempts[index].1);
+                    self.attempts.swap_remove(index);
+                    let _ = self.start_next(registry, token);
+                }
+                None => index += 1,
+            }
+        }
+        if self.attempts.is_empty() {
+            return RaceState::Failed;
+        }
+        return RaceState::Connecting;
+    }
+
+    pub fn set_token(&mut self, token: Token) -> Result<(), Error> {
+        for &mut (ref mut socket, _) in self.attempts.iter_mut() {
+            try!(socket.set_token(token));
+        }
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_interleave_families() {
+    let addresses: Vec<SocketAddr> = ["10.0.0.1:6379", "10.0.0.2:6379", "[::1]:6379", "10.0.0.1:6379", "10.0.0.3:6379", "[::2]:6379"]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+    let ordered: Vec<String> = interleave_families(&addresses).iter().map(|address| address.to_string()).collect();
+    assert_eq!(ordered, vec!["[::1]:6379", "10.0.0.1:6379", "[::2]:6379", "10.0.0.2:6379", "10.0.0.3:6379"]);
+    assert!(interleave_families(&[]).is_empty());
+
+    assert!(validate_hostname("redis.internal:6379").is_ok());
+    assert!(validate_hostname("redis.internal").is_err());
+    assert!(validate_hostname(":6379").is_err());
+    assert_eq!(resolve("127.0.0.1:6379"), Ok(vec!["127.0.0.1:6379".parse().unwrap()]));
+}
+
+#[test]
+fn test_connect_race() {
+    use mio::Events;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    // A port that nothing listens on, so that its attempt is refused.
+    let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listening = listener.local_addr().unwrap();
+
+    let registry = Registry::new().unwrap();
+    let mut events = Events::with_capacity(16);
+    let token = Token(5);
+    let mut race = ConnectRace::new(&[refused, listening]);
+    race.start_next(&registry, token).unwrap();
+    assert!(race.has_remaining());
+    let mut winner = None;
+    for _ in 0..50 {
+        registry.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+        match race.poll(&registry, token) {
+            RaceState::Connecting => continue,
+            RaceState::Connected(_, address) => {
+                winner = Some(address);
+                break;
+            }
+            RaceState::Failed => break,
+        }
+    }
+    assert_eq!(winner, Some(listening));
+
+    let mut race = ConnectRace::new(&[refused]);
+    race.start_next(&registry, token).unwrap();
+    let mut failed = false;
+    for _ in 0..50 {
+        registry.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+        if let RaceState::Failed = race.poll(&registry, token) {
+            failed = true;
+            break;
+        }
+    }
+    assert!(failed);
+}
@@ -0,0 +1,138 @@
+use clock::{self, MockClock};
+use config::RedFlareProxyConfig;
+use redflareproxy::{EventLoopState, ProxyError, RedFlareProxy};
+
+use std::rc::Rc;
+use std::time::Duration;
+
+/*
+    Runs a proxy on the current thread, one event loop iteration at a time, on a mock clock, so that tests can check
+    timeouts, retry backoff and blackouts without sleeping. Time only passes when the test advances it, e.g.:
+
+    let mut harness = try!(Harness::new(config));
+    // Connect a client, and send it a request.
+    try!(harness.step());
+    // The request times out.
+    try!(harness.advance(Duration::from_millis(100)));
+
+    Each step polls without waiting, so sockets still need a step after the data they're waiting on is sent. The
+    mock clock is the clock of the thread until the harness is dropped.
+*/
+pub struct Harness {
+    pub proxy: RedFlareProxy,
+    pub clock: MockClock,
+    state: EventLoopState,
+}
+
+impl Harness {
+    pub fn new(config: RedFlareProxyConfig) -> Result<Harness, ProxyError> {
+        if config.workers > 1 {
+            return Err(ProxyError::EmbeddedWorkers(config.workers));
+        }
+        // Before the proxy is created, so that its timers start at the mock time.
+        let clock = MockClock::new();
+        clock::set(Some(Rc::new(clock.clone())));
+        let state = EventLoopState::new(config.event_capacity);
+        let proxy = match RedFlareProxy::new(config, String::new(), None, None, None) {
+            Ok(proxy) => proxy,
+            Err(err) => {
+                clock::set(None);
+                return Err(err);
+            }
+        };
+        Ok(Harness {
+            proxy: proxy,
+            clock: clock,
+            state: state,
+        })
+    }
+
+    // Runs one iteration of the event loop. Returns false once the proxy has shut down.
+    pub fn step(&mut self) -> Result<bool, ProxyError> {
+        return self.proxy.run_iteration(&mut self.state, Some(Duration::from_millis(0)));
+    }
+
+    // Runs a number of iterations, for events that take a few round trips, like connecting.
+    pub fn steps(&mut self, count: usize) -> Result<bool, ProxyError> {
+        for _ in 0..count {
+            if !try!(self.step()) {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    // Moves the clock forward, and runs an iteration, which fires the timers that are due.
+    pub fn advance(&mut self, duration: Duration) -> Result<bool, ProxyError> {
+        self.clock.advance(duration);
+        return self.step();
+    }
+
+    // Runs an admin command, e.g. &["STATS"]. See embed::ProxyHandle::command.
+    pub fn command(&mut self, args: &[&str]) -> Result<String, String> {
+        return self.proxy.command(args);
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        clock::set(None);
+    }
+}
+
+#[test]
+fn test_request_timeout() {
+    use config::{ConfigBuilder, PoolBuilder};
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // A backend that answers the proxy's PING, and then never answers.
+    let backend_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    backend_listener.set_nonblocking(true).unwrap();
+    let listen = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let config = ConfigBuilder::new("127.0.0.1:0")
+        .pool("pool1", PoolBuilder::new(&listen).server(&backend_listener.local_addr().unwrap().to_string(), 1).timeout(100))
+        .build()
+        .unwrap();
+    let mut harness = Harness::new(config).unwrap();
+
+    let mut backend = None;
+    for _ in 0..100 {
+        harness.step().unwrap();
+        if let Ok((stream, _)) = backend_listener.accept() {
+            backend = Some(stream);
+            break;
+        }
+    }
+    let mut backend = backend.expect("The proxy didn't connect to the backend");
+    backend.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    harness.steps(5).unwrap();
+    let mut ping = [0; 6];
+    backend.read_exact(&mut ping).unwrap();
+    assert_eq!(&ping, b"PING\r\n");
+    backend.write_all(b"+PONG\r\n").unwrap();
+    harness.steps(5).unwrap();
+
+    let request = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n";
+    let mut client = TcpStream::connect(&*listen).unwrap();
+    client.write_all(request).unwrap();
+    harness.steps(5).unwrap();
+    let mut forwarded = vec![0; request.len()];
+    backend.read_exact(&mut forwarded).unwrap();
+    assert_eq!(&forwarded[..], &request[..]);
+
+    // No time has passed on the mock clock, however long the steps took.
+    client.set_nonblocking(true).unwrap();
+    let mut response = [0; 22];
+    harness.advance(Duration::from_millis(99)).unwrap();
+    assert_eq!(client.read(&mut response).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    harness.advance(Duration::from_millis(2)).unwrap();
+    client.set_nonblocking(false).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], b"-ERR Proxy timed out\r\n");
+}
@@ -3,13 +3,14 @@ use std::default::Default;
 use crc::{crc16, crc32};
 use fasthash::*;
 use hashers::jenkins::spooky_hash;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 // Reading: https://probablydance.com/2017/02/26/i-wrote-the-fastest-hashtable/
 // Benchmarks: https://github.com/rurban/smhasher/
 // Hsieh = not implemented. See http://www.azillionmonkeys.com/qed/hash.html
 
 
-#[derive(Deserialize, Clone, Serialize, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub enum HashFunction {
     Crc16,
     Crc32,
@@ -18,6 +19,31 @@ pub enum HashFunction {
     Jenkins,
 }
 
+// toml can't (de)serialize enum variants, so this is written and read as a plain string.
+impl Deserialize for HashFunction {
+    fn deserialize<D: Deserializer>(deserializer: D) -> Result<HashFunction, D::Error> {
+        match try!(String::deserialize(deserializer)).as_str() {
+            "Crc16" => Ok(HashFunction::Crc16),
+            "Crc32" => Ok(HashFunction::Crc32),
+            "Fnv1a64" => Ok(HashFunction::Fnv1a64),
+            "Murmur" => Ok(HashFunction::Murmur),
+            "Jenkins" => Ok(HashFunction::Jenkins),
+            other => Err(serde::de::Error::custom(format!("Unknown hash_function: {}. Expected one of Crc16, Crc32, Fnv1a64, Murmur, Jenkins", other))),
+        }
+    }
+}
+impl Serialize for HashFunction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match *self {
+            HashFunction::Crc16 => "Crc16",
+            HashFunction::Crc32 => "Crc32",
+            HashFunction::Fnv1a64 => "Fnv1a64",
+            HashFunction::Murmur => "Murmur",
+            HashFunction::Jenkins => "Jenkins",
+        })
+    }
+}
+
 pub fn hash(hash_function: &HashFunction, key: &[u8]) -> usize {
     match hash_function {
         HashFunction::Crc16 => {
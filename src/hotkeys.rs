@@ -0,0 +1,126 @@
+use hash::{hash, HashFunction};
+
+// Dimensions of the count-min sketch. Estimates overcount by at most ~0.1% of sampled requests, with high probability.
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+
+/*
+    Tracks the most frequently accessed keys of a pool, to help diagnose shard hotspots.
+    One in every sample_rate keys is recorded into a count-min sketch, and the keys with the highest estimated counts
+    are kept in a small top-k list. Memory usage is fixed regardless of the number of distinct keys.
+*/
+pub struct HotKeys {
+    sample_rate: usize,
+    top_k: usize,
+    seen: usize,
+    sketch: Vec<u64>,
+    // Unordered list of (key, estimated sampled count). Kept small, so it is scanned linearly.
+    top: Vec<(Vec<u8>, u64)>,
+}
+
+impl HotKeys {
+    pub fn new(sample_rate: usize, top_k: usize) -> HotKeys {
+        HotKeys {
+            sample_rate: sample_rate,
+            top_k: top_k,
+            seen: 0,
+            sketch: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+            top: Vec::with_capacity(top_k + 1),
+        }
+    }
+
+    pub fn record(&mut self, key: &[u8]) {
+        self.seen += 1;
+        if self.seen % self.sample_rate != 0 {
+            return;
+        }
+
+        // Derive each row's index from two hashes, rather than hashing once per row.
+        let hash1 = hash(&HashFunction::Fnv1a64, key);
+        let hash2 = hash(&HashFunction::Murmur, key) | 1;
+        let mut estimate = u64::max_value();
+        for row in 0..SKETCH_DEPTH {
+            let column = hash1.wrapping_add(row.wrapping_mul(hash2)) % SKETCH_WIDTH;
+            let count = &mut self.sketch[row * SKETCH_WIDTH + column];
+            *count += 1;
+            estimate = std::cmp::min(estimate, *count);
+        }
+
+        for entry in self.top.iter_mut() {
+            if entry.0 == key {
+                entry.1 = estimate;
+                return;
+            }
+        }
+        if self.top.len() < self.top_k {
+            self.top.push((key.to_vec(), estimate));
+            return;
+        }
+        let mut min_index = 0;
+        for index in 1..self.top.len() {
+            if self.top[index].1 < self.top[min_index].1 {
+                min_index = index;
+            }
+        }
+        if self.top_k > 0 && estimate > self.top[min_index].1 {
+            self.top[min_index] = (key.to_vec(), estimate);
+        }
+    }
+
+    // Whether the key is currently one of the top keys.
+    pub fn is_hot(&self, key: &[u8]) -> bool {
+        return self.top.iter().any(|entry| entry.0 == key);
+    }
+
+    // Returns the hottest keys with their estimated request counts, hottest first.
+    pub fn top_keys(&self) -> Vec<(Vec<u8>, u64)> {
+        let mut top: Vec<(Vec<u8>, u64)> = self.top.iter()
+            .map(|&(ref key, count)| (key.clone(), count * self.sample_rate as u64))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        return top;
+    }
+
+    pub fn reset(&mut self) {
+        self.seen = 0;
+        for count in self.sketch.iter_mut() {
+            *count = 0;
+        }
+        self.top.clear();
+    }
+}
+
+#[test]
+fn test_hot_keys() {
+    let mut hotkeys = HotKeys::new(1, 3);
+    for i in 0..1000 {
+        hotkeys.record(format!("cold{}", i).as_bytes());
+        if i % 2 == 0 {
+            hotkeys.record(b"hot1");
+        }
+        if i % 4 == 0 {
+            hotkeys.record(b"hot2");
+        }
+        if i % 8 == 0 {
+            hotkeys.record(b"hot3");
+        }
+    }
+    let top = hotkeys.top_keys();
+    assert_eq!(top.len(), 3);
+    assert_eq!(top[0].0, b"hot1".to_vec());
+    assert_eq!(top[1].0, b"hot2".to_vec());
+    assert_eq!(top[2].0, b"hot3".to_vec());
+    assert!(top[0].1 >= 500);
+    assert!(hotkeys.is_hot(b"hot3"));
+    assert!(!hotkeys.is_hot(b"cold1"));
+
+    hotkeys.reset();
+    assert_eq!(hotkeys.top_keys().len(), 0);
+
+    // With sampling, counts are scaled back up by the sample rate.
+    let mut sampled = HotKeys::new(10, 3);
+    for _ in 0..100 {
+        sampled.record(b"hot");
+    }
+    assert_eq!(sampled.top_keys(), vec![(b"hot".to_vec(), 100)]);
+}
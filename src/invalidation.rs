@@ -0,0 +1,211 @@
+use config::{BackendPoolConfig, CacheInvalidation};
+use log::LogLevel;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use subscription::{Connection, Reply, Subscriptions};
+#[cfg(test)]
+use subscription::read_reply;
+
+const TRACKING_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+#[derive(Debug, PartialEq)]
+pub enum Invalidation {
+    Key(Vec<u8>),
+    // Every key may have changed, e.g. after a FLUSHALL, or while a subscription was down.
+    Flush,
+}
+
+/*
+    Listens for changes to the keys of a pool's backends, so that its read cache can evict them right away instead of
+    serving them until they expire. Each backend host gets a subscription on its own connection, with either:
+
+    Tracking: CLIENT TRACKING ON BCAST, redirected to the connection itself, followed by SUBSCRIBE __redis__:invalidate.
+    Redis then publishes every key that changes, and nil when the whole database is flushed.
+    Keyspace: PSUBSCRIBE __keyspace@<db>__:*. The backend must have keyspace events enabled, e.g.
+    notify-keyspace-events K$gx, and doesn't report flushes.
+
+    What changed is handed over to the event loop through a channel, which the cache drains before each lookup.
+    Since changes can be missed while a subscription is down, the whole cache is invalidated once it's re-established.
+    For redis cluster backends, only the configured cluster_hosts are subscribed to, and backends discovered through
+    sentinel aren't subscribed to.
+*/
+pub struct InvalidationListener {
+    receiver: Receiver<Invalidation>,
+    // Stops the threads once the listener is dropped.
+    _subscriptions: Subscriptions,
+}
+
+impl InvalidationListener {
+    // Returns None when the pool's cache_invalidation is Off.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<InvalidationListener> {
+        if config.cache_invalidation == CacheInvalidation::Off {
+            return None;
+        }
+        let (sender, receiver) = channel();
+        let mut subscriptions = Subscriptions::new();
+        for server in config.servers.iter() {
+            for &host in server.host.iter().chain(server.cluster_hosts.iter()) {
+                let sender = sender.clone();
+                let auth = server.auth.clone();
+                let username = server.username.clone();
+                let db = server.db;
+                let mode = config.cache_invalidation;
+                subscriptions.spawn(host, move |connection| {
+                    let result = listen(connection, host, username.as_ref().map(|u| u.as_str()), &auth, db, mode, &sender);
+                    // Changes may be missed until the subscription is back up.
+                    if result.is_err() && sender.send(Invalidation::Flush).is_err() {
+                        return Ok(());
+                    }
+                    result
+                });
+            }
+        }
+        return Some(InvalidationListener {
+            receiver: receiver,
+            _subscriptions: subscriptions,
+        });
+    }
+
+    // Takes what changed since the last call, without waiting.
+    pub fn pending(&self) -> Vec<Invalidation> {
+        return self.receiver.try_iter().collect();
+    }
+}
+
+// Subscribes, then passes on what changed until the connection fails. Returns Ok once the receiving end is gone.
+fn listen(connection: &mut Connection, host: SocketAddr, username: Option<&str>, auth: &str, db: usize, mode: CacheInvalidation, sender: &Sender<Invalidation>) -> Result<(), String> {
+    // Logs in like the pool's own connections to the backend, see backend::auth_request.
+    if !auth.is_empty() {
+        match username {
+            Some(username) => try!(connection.command(&[b"AUTH", username.as_bytes(), auth.as_bytes()])),
+            None => try!(connection.command(&[b"AUTH", auth.as_bytes()])),
+        };
+    }
+    let keyspace_prefix = format!("__keyspace@{}__:", db).into_bytes();
+    match mode {
+        CacheInvalidation::Tracking => {
+            let id = match try!(connection.command(&[b"CLIENT", b"ID"])) {
+                Reply::Integer(id) => id.to_string(),
+                reply => return Err(format!("CLIENT ID failed: {:?}", reply)),
+            };
+            try!(connection.command(&[b"CLIENT", b"TRACKING", b"ON", b"REDIRECT", id.as_bytes(), b"BCAST"]));
+            try!(connection.command(&[b"SUBSCRIBE", TRACKING_CHANNEL]));
+        }
+        CacheInvalidation::Keyspace => {
+            let mut pattern = keyspace_prefix.clone();
+            pattern.push(b'*');
+            try!(connection.command(&[b"PSUBSCRIBE", &pattern]));
+        }
+        CacheInvalidation::Off => return Ok(()),
+    }
+    log_event!(LogLevel::Info, "cache_invalidation_subscribed", { backend: host }, "Subscribed to cache invalidations from {}", host);
+    // Anything cached before now may have changed while the subscription was down.
+    if sender.send(Invalidation::Flush).is_err() {
+        return Ok(());
+    }
+    loop {
+        let reply = try!(connection.read_reply());
+        for invalidation in parse_message(reply, &keyspace_prefix) {
+            if sender.send(invalidation).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/*
+    Returns what a pubsub message says has changed. e.g.:
+    ["message", "__redis__:invalidate", ["a", "b"]], or nil instead of the keys for a flush
+    ["pmessage", "__keyspace@0__:*", "__keyspace@0__:a", "set"]
+*/
+fn parse_message(reply: Reply, keyspace_prefix: &[u8]) -> Vec<Invalidation> {
+    let mut items = match reply {
+        Reply::Array(Some(items)) => items.into_iter(),
+        _ => return Vec::new(),
+    };
+    return match (items.next(), items.next(), items.next()) {
+        (Some(Reply::Bulk(Some(ref kind))), Some(Reply::Bulk(Some(ref channel))), Some(keys)) if kind == b"message" && channel == TRACKING_CHANNEL => {
+            match keys {
+                Reply::Array(Some(keys)) => keys.into_iter().filter_map(|key| match key {
+                    Reply::Bulk(Some(key)) => Some(Invalidation::Key(key)),
+                    _ => None,
+                }).collect(),
+                Reply::Bulk(Some(key)) => vec![Invalidation::Key(key)],
+                _ => vec![Invalidation::Flush],
+            }
+        }
+        (Some(Reply::Bulk(Some(ref kind))), Some(_), Some(Reply::Bulk(Some(ref channel)))) if kind == b"pmessage" && channel.starts_with(keyspace_prefix) => {
+            vec![Invalidation::Key(channel[keyspace_prefix.len()..].to_vec())]
+        }
+        _ => Vec::new(),
+    };
+}
+
+#[test]
+fn test_parse_message() {
+    use std::io::Cursor;
+    let parse = |message: &[u8]| parse_message(read_reply(&mut Cursor::new(message)).unwrap(), b"__keyspace@0__:");
+    assert_eq!(
+        parse(b"*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n"),
+        vec![Invalidation::Key(b"a".to_vec()), Invalidation::Key(b"b".to_vec())]
+    );
+    assert_eq!(parse(b"*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*-1\r\n"), vec![Invalidation::Flush]);
+    assert_eq!(
+        parse(b"*4\r\n$8\r\npmessage\r\n$16\r\n__keyspace@0__:*\r\n$16\r\n__keyspace@0__:a\r\n$3\r\nset\r\n"),
+        vec![Invalidation::Key(b"a".to_vec())]
+    );
+    // Confirmations of the subscription itself.
+    assert_eq!(parse(b"*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n"), vec![]);
+    assert_eq!(parse(b"+OK\r\n"), vec![]);
+}
+
+#[test]
+fn test_invalidation_listener() {
+    use config::{ConfigBuilder, PoolBuilder};
+    use std::io::{BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap();
+    let backend = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut expect = |request: &[u8], reply: &[u8]| {
+            let mut received = vec![0; request.len()];
+            reader.read_exact(&mut received).unwrap();
+            assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(request));
+            writer.write_all(reply).unwrap();
+        };
+        expect(b"*3\r\n$4\r\nAUTH\r\n$5\r\nproxy\r\n$9\r\npassword1\r\n", b"+OK\r\n");
+        expect(b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n", b":7\r\n");
+        expect(b"*6\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n$8\r\nREDIRECT\r\n$1\r\n7\r\n$5\r\nBCAST\r\n", b"+OK\r\n");
+        expect(
+            b"*2\r\n$9\r\nSUBSCRIBE\r\n$20\r\n__redis__:invalidate\r\n",
+            b"*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n\
+            *3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\na\r\n",
+        );
+        // Waits for the listener to be dropped.
+        let mut rest = Vec::new();
+        let _ = reader.read_to_end(&mut rest);
+    });
+
+    let config = ConfigBuilder::new("127.0.0.1:0")
+        .pool("pool1", PoolBuilder::new("127.0.0.1:0").server(&host.to_string(), 1))
+        .build()
+        .unwrap();
+    let mut pool_config = config.pools["pool1"].clone();
+    pool_config.cache_invalidation = CacheInvalidation::Tracking;
+    pool_config.servers[0].auth = "password1".to_owned();
+    pool_config.servers[0].username = Some("proxy".to_owned());
+    let listener = InvalidationListener::from_config(&pool_config).unwrap();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        received.extend(listener.pending());
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(received, vec![Invalidation::Flush, Invalidation::Key(b"a".to_vec())]);
+    drop(listener);
+    backend.join().unwrap();
+}
@@ -0,0 +1,256 @@
+/*
+    A parsed JSON document, for reading the responses of the services that backends are discovered through. JSON is
+    written with the helpers in stats.rs.
+*/
+#[derive(Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    // Fields in the order of the document.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    // The value of a field of an object.
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|&&(ref field, _)| field == name).map(|&(_, ref value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref value) => Some(&value[..]),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match *self {
+            Json::Array(ref values) => Some(&values[..]),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Json::Number(value) if value >= 0.0 && value.fract() == 0.0 => Some(value as u64),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Json, String> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        index: 0,
+    };
+    let value = try!(parser.parse_value());
+    parser.skip_whitespace();
+    if parser.index < parser.input.len() {
+        return Err(format!("unexpected data at offset {}", parser.index));
+    }
+    return Ok(value);
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.index < self.input.len() && (self.input[self.index] as char).is_whitespace() {
+            self.index += 1;
+        }
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, String> {
+        return Err(format!("expected {} at offset {}", expected, self.index));
+    }
+
+    fn consume(&mut self, literal: &[u8]) -> bool {
+        if self.input[self.index..].starts_with(literal) {
+            self.index += literal.len();
+            return true;
+        }
+        return false;
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.input.get(self.index) {
+            Some(&b'{') => return self.parse_object(),
+            Some(&b'[') => return self.parse_array(),
+            Some(&b'"') => return self.parse_string().map(Json::String),
+            Some(&b'-') | Some(&b'0'..=b'9') => return self.parse_number(),
+            _ => {}
+        }
+        if self.consume(b"null") {
+            return Ok(Json::Null);
+        }
+        if self.consume(b"true") {
+            return Ok(Json::Bool(true));
+        }
+        if self.consume(b"false") {
+            return Ok(Json::Bool(false));
+        }
+        return self.error("a value");
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        self.index += 1;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.consume(b"}") {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.input.get(self.index) != Some(&b'"') {
+                return self.error("a field name");
+            }
+            let name = try!(self.parse_string());
+            self.skip_whitespace();
+            if !self.consume(b":") {
+                return self.error(":");
+            }
+            let value = try!(self.parse_value());
+            fields.push((name, value));
+            self.skip_whitespace();
+            if self.consume(b"}") {
+                return Ok(Json::Object(fields));
+            }
+            if !self.consume(b",") {
+                return self.error(", or }");
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, String> {
+        self.index += 1;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.consume(b"]") {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(try!(self.parse_value()));
+            self.skip_whitespace();
+            if self.consume(b"]") {
+                return Ok(Json::Array(values));
+            }
+            if !self.consume(b",") {
+                return self.error(", or ]");
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.index += 1;
+        let mut output: Vec<u8> = Vec::new();
+        loop {
+            let byte = match self.input.get(self.index) {
+                Some(&byte) => byte,
+                None => return self.error("the end of the string"),
+            };
+            self.index += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.input.get(self.index) {
+                        Some(&escaped) => escaped,
+                        None => return self.error("an escape"),
+                    };
+                    self.index += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => try!(self.parse_unicode_escape()),
+                        _ => return self.error("an escape"),
+                    };
+                    let mut encoded = [0; 4];
+                    output.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+                }
+                byte => output.push(byte),
+            }
+        }
+        return String::from_utf8(output).or_else(|_| self.error("a UTF-8 string"));
+    }
+
+    // The XXXX of \uXXXX, with the low half of a surrogate pair after it.
+    fn parse_unicode_escape(&mut self) -> Result<char, String> {
+        let high = try!(self.parse_hex4());
+        let code = if high >= 0xd800 && high < 0xdc00 {
+            if !self.consume(b"\\u") {
+                return self.error("a low surrogate");
+            }
+            let low = try!(self.parse_hex4());
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+        return match std::char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("a unicode character"),
+        };
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, String> {
+        let digits = self.input.get(self.index..self.index + 4).and_then(|digits| std::str::from_utf8(digits).ok());
+        match digits.and_then(|digits| u32::from_str_radix(digits, 16).ok()) {
+            Some(code) => {
+                self.index += 4;
+                return Ok(code);
+            }
+            None => return self.error("4 hex digits"),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, String> {
+        let start = self.index;
+        while self.index < self.input.len() && b"+-0123456789.eE".contains(&self.input[self.index]) {
+            self.index += 1;
+        }
+        let number = std::str::from_utf8(&self.input[start..self.index]).ok().and_then(|number| number.parse::<f64>().ok());
+        match number {
+            Some(number) => return Ok(Json::Number(number)),
+            None => {
+                self.index = start;
+                return self.error("a number");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let document = parse(r#" {"Service": {"Address": "10.0.0.1", "Port": 6379, "Tags": ["a\"b", "é😀"]},
+        "Checks": [], "Passing": true, "Meta": null, "Weight": -1.5e1} "#).unwrap();
+    let service = document.get("Service").unwrap();
+    assert_eq!(service.get("Address").and_then(Json::as_str), Some("10.0.0.1"));
+    assert_eq!(service.get("Port").and_then(Json::as_u64), Some(6379));
+    let tags: Vec<&str> = service.get("Tags").and_then(Json::as_array).unwrap().iter().filter_map(Json::as_str).collect();
+    assert_eq!(tags, vec!["a\"b", "\u{e9}\u{1f600}"]);
+    assert_eq!(document.get("Checks"), Some(&Json::Array(Vec::new())));
+    assert_eq!(document.get("Passing"), Some(&Json::Bool(true)));
+    assert_eq!(document.get("Meta"), Some(&Json::Null));
+    assert_eq!(document.get("Weight"), Some(&Json::Number(-15.0)));
+    assert_eq!(document.get("Missing"), None);
+
+    assert!(parse("").is_err());
+    assert!(parse("{\"a\": 1,}").is_err());
+    assert!(parse("[1, 2").is_err());
+    assert!(parse("\"abc").is_err());
+    assert!(parse("{} x").is_err());
+}
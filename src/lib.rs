@@ -0,0 +1,170 @@
+/*
+    The proxy as a library, for embedding it in test harnesses and custom daemons. See embed::ProxyHandle and
+    config::ConfigBuilder. The redflareproxy binary is a command line front end to it.
+*/
+extern crate mio;
+extern crate toml;
+#[macro_use]
+extern crate log;
+extern crate log4rs;
+extern crate log_mdc;
+extern crate humantime;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate daemonize;
+extern crate conhash;
+extern crate rand;
+extern crate crc16;
+extern crate bufstream;
+extern crate fxhash;
+extern crate crc;
+extern crate fasthash;
+extern crate hashers;
+extern crate hashbrown;
+extern crate memchr;
+extern crate libc;
+extern crate iovec;
+#[cfg(feature = "lua")]
+extern crate rlua;
+#[cfg(feature = "wasm")]
+extern crate wasmi;
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+#[cfg(test)]
+use log::LogLevelFilter;
+#[cfg(test)]
+use log4rs::append::console::ConsoleAppender;
+#[cfg(test)]
+use log4rs::config::{Appender, Config, Root};
+
+pub use config::{ConfigBuilder, PoolBuilder, RedFlareProxyConfig};
+pub use embed::ProxyHandle;
+pub use filter::{Filter, FilterAction, FilterContext};
+pub use redflareproxy::{ProxyError, RedFlareProxy};
+
+#[macro_use]
+pub mod logging;
+mod admin;
+pub mod redflareproxy;
+pub mod config;
+mod backend;
+mod cluster_backend;
+mod backendpool;
+mod redisprotocol;
+pub mod resp;
+mod hash;
+mod client;
+pub mod stats;
+pub mod daemon;
+mod retry;
+mod hotkeys;
+mod cache;
+mod invalidation;
+mod subscription;
+mod sentinel;
+mod discovery;
+mod dns;
+mod happyeyeballs;
+mod fastopen;
+mod json;
+mod replica;
+mod readyourwrites;
+mod outagebuffer;
+mod mirror;
+mod standby;
+mod ratelimit;
+mod quota;
+mod netacl;
+mod quarantine;
+mod maintenance;
+mod acl;
+mod namespace;
+mod proxyprotocol;
+mod transparent;
+mod snapshot;
+mod trace;
+pub mod syslog;
+mod slowlog;
+mod audit;
+mod capture;
+mod chaos;
+mod errorresponses;
+mod bench;
+mod backendinfo;
+pub mod version;
+pub mod worker;
+pub mod embed;
+pub mod clock;
+pub mod harness;
+pub mod filter;
+mod rewrite;
+#[cfg(feature = "lua")]
+mod luafilter;
+#[cfg(feature = "wasm")]
+mod wasmfilter;
+#[cfg(feature = "encryption")]
+mod encryption;
+
+mod bufreader;
+mod bufferpool;
+mod timerwheel;
+pub mod signals;
+pub mod handoff;
+pub mod systemd;
+mod tokens;
+mod memory;
+mod concurrency;
+mod watchdog;
+mod overload;
+mod registry;
+mod platform;
+mod collapse;
+mod functions;
+
+#[cfg(test)]
+pub fn init_logging() {
+    let stdout = ConsoleAppender::builder().build();
+    let config = 
+            Config::builder()
+                .appender(Appender::builder().build("stdout", Box::new(stdout)))
+                .build(Root::builder().appender("stdout").build(LogLevelFilter::Debug))
+                .unwrap();
+
+    match log4rs::init_config(config) {
+        Ok(_) => {},
+        Err(logger_error) => {
+            println!("Logging error: {:?}", logger_error);
+            return;
+        }
+    };
+}
+#[cfg(test)]
+pub fn init_logging_info() {
+    let stdout = ConsoleAppender::builder().build();
+    let config = 
+            Config::builder()
+                .appender(Appender::builder().build("stdout", Box::new(stdout)))
+                .build(Root::builder().appender("stdout").build(LogLevelFilter::Info))
+                .unwrap();
+
+    match log4rs::init_config(config) {
+        Ok(_) => {},
+        Err(logger_error) => {
+            println!("Logging error: {:?}", logger_error);
+            return;
+        }
+    };
+}
+
+impl std::convert::From<log::SetLoggerError> for ProxyError {
+    fn from(error: log::SetLoggerError) -> Self {
+        return ProxyError::SetLoggerError(error);
+    }
+}
+
+impl std::convert::From<log4rs::config::Errors> for ProxyError {
+    fn from(error: log4rs::config::Errors) -> Self {
+        return ProxyError::InvalidParams(error);
+    }
+}
@@ -0,0 +1,332 @@
+use log::{LogLevelFilter, LogRecord};
+use log4rs;
+use log4rs::Handle;
+use log4rs::append::Append;
+use log4rs::append::rolling_file::LogFile;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::Encode;
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+use log_mdc;
+use redflareproxy::ProxyError;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Components whose log level can be set separately from the global level, and the module each one covers.
+pub const COMPONENTS: [(&str, &str); 4] = [
+    ("admin", ::admin::LOG_TARGET),
+    ("backend", "redflareproxy::backend"),
+    ("cluster", "redflareproxy::cluster_backend"),
+    ("protocol", "redflareproxy::redisprotocol"),
+];
+
+/*
+    Logs an event, with structured fields attached. e.g.:
+    log_event!(LogLevel::Info, "client_connected", { pool: pool_name, token: token.0 }, "Accepted client {:?}", token);
+    The fields are only written out in the JSON log format, where they appear under "mdc" alongside an "event" field.
+    The text log format shows just the message, so it should still make sense on its own.
+*/
+macro_rules! log_event {
+    ($level:expr, $event:expr, { $($key:ident: $value:expr),* }, $($arg:tt)+) => {{
+        let level = $level;
+        if log_enabled!(level) {
+            ::logging::with_fields($event, &[$((stringify!($key), $value.to_string())),*], || log!(level, $($arg)+));
+        }
+    }};
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, for log pipelines.
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.to_uppercase().trim() {
+            "TEXT" => Some(LogFormat::Text),
+            "JSON" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn encoder(&self) -> Box<Encode> {
+        match *self {
+            LogFormat::Text => Box::new(PatternEncoder::new("{d} - {m}{n}")),
+            LogFormat::Json => Box::new(JsonEncoder::new()),
+        }
+    }
+
+    // Encoder for sinks that add their own timestamp, such as syslog.
+    pub fn message_encoder(&self) -> Box<Encode> {
+        match *self {
+            LogFormat::Text => Box::new(PatternEncoder::new("{m}")),
+            LogFormat::Json => Box::new(JsonEncoder::new()),
+        }
+    }
+}
+
+pub fn parse_level(level: &str) -> Option<LogLevelFilter> {
+    match level.to_uppercase().trim() {
+        "DEBUG" => Some(LogLevelFilter::Debug),
+        "INFO" => Some(LogLevelFilter::Info),
+        "WARN" | "WARNING" => Some(LogLevelFilter::Warn),
+        "ERROR" => Some(LogLevelFilter::Error),
+        _ => None,
+    }
+}
+
+/*
+    Parses per-component log levels, e.g. { cluster = "DEBUG" }. Returns the offending entry if a component or level
+    isn't recognized.
+*/
+pub fn parse_component_levels(levels: &BTreeMap<String, String>) -> Result<BTreeMap<String, LogLevelFilter>, String> {
+    let mut parsed = BTreeMap::new();
+    for (component, level) in levels {
+        if !COMPONENTS.iter().any(|&(name, _)| name == component) {
+            return Err(format!("{} = {}", component, level));
+        }
+        match parse_level(level) {
+            Some(level) => parsed.insert(component.clone(), level),
+            None => return Err(format!("{} = {}", component, level)),
+        };
+    }
+    return Ok(parsed);
+}
+
+// Lets the same appender be used by successive log configs.
+#[derive(Debug)]
+struct SharedAppender(Arc<Append>);
+
+impl Append for SharedAppender {
+    fn append(&self, record: &LogRecord) -> Result<(), Box<Error + Sync + Send>> {
+        return self.0.append(record);
+    }
+}
+
+/*
+    Holds the installed logger, so that component log levels can be changed while running. The appenders are kept and
+    shared with each new config, so changing a level doesn't reopen log files or syslog sockets.
+*/
+pub struct Logging {
+    handle: Handle,
+    appenders: Vec<(String, Arc<Append>)>,
+    level: LogLevelFilter,
+    component_levels: BTreeMap<String, LogLevelFilter>,
+}
+
+impl Logging {
+    pub fn init(appenders: Vec<(String, Box<Append>)>, level: LogLevelFilter) -> Result<Logging, ProxyError> {
+        let appenders: Vec<(String, Arc<Append>)> = appenders.into_iter()
+            .map(|(name, appender)| (name, Arc::from(appender)))
+            .collect();
+        let config = try!(build_config(&appenders, level, &BTreeMap::new()));
+        let handle = try!(log4rs::init_config(config));
+        Ok(Logging {
+            handle: handle,
+            appenders: appenders,
+            level: level,
+            component_levels: BTreeMap::new(),
+        })
+    }
+
+    // Replaces the component log levels. Components without a level log at the global level.
+    pub fn set_component_levels(&mut self, component_levels: BTreeMap<String, LogLevelFilter>) -> Result<(), ProxyError> {
+        let config = try!(build_config(&self.appenders, self.level, &component_levels));
+        self.handle.set_config(config);
+        self.component_levels = component_levels;
+        return Ok(());
+    }
+
+    // One "component: LEVEL" line for the global level, followed by each component.
+    pub fn format_levels(&self) -> String {
+        let mut output = format!("default: {}", self.level);
+        for &(component, _) in COMPONENTS.iter() {
+            let level = self.component_levels.get(component).unwrap_or(&self.level);
+            output.push_str(&format!("\n{}: {}", component, level));
+        }
+        return output;
+    }
+}
+
+fn build_config(
+    appenders: &[(String, Arc<Append>)],
+    level: LogLevelFilter,
+    component_levels: &BTreeMap<String, LogLevelFilter>,
+) -> Result<Config, log4rs::config::Errors> {
+    let mut config = Config::builder();
+    let mut root = Root::builder();
+    for (name, appender) in appenders {
+        config = config.appender(Appender::builder().build(name.as_str(), Box::new(SharedAppender(appender.clone()))));
+        root = root.appender(name.as_str());
+    }
+    for &(component, module) in COMPONENTS.iter() {
+        if let Some(&component_level) = component_levels.get(component) {
+            config = config.logger(Logger::builder().build(module, component_level));
+        }
+    }
+    return config.build(root.build(level));
+}
+
+/*
+    Runs the log statement with the event's fields set, so that the JSON encoder picks them up. Logging happens on the
+    calling thread, so the fields are removed again afterwards.
+*/
+pub fn with_fields<F: FnOnce()>(event: &str, fields: &[(&str, String)], log: F) {
+    log_mdc::insert("event", event);
+    for &(key, ref value) in fields {
+        log_mdc::insert(key, value.as_str());
+    }
+    log();
+    log_mdc::clear();
+}
+
+/*
+    Parses a size in bytes, with an optional K, M or G suffix. e.g. "100M".
+*/
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let (digits, multiplier) = if size.ends_with('K') {
+        (&size[..size.len() - 1], 1024)
+    } else if size.ends_with('M') {
+        (&size[..size.len() - 1], 1024 * 1024)
+    } else if size.ends_with('G') {
+        (&size[..size.len() - 1], 1024 * 1024 * 1024)
+    } else {
+        (&size[..], 1)
+    };
+    return digits.trim().parse::<u64>().ok().map(|value| value * multiplier);
+}
+
+/*
+    Rotates the log file once it grows past max_size bytes, or once interval has passed since the last rotation. Either
+    limit can be left unset. The limits are only checked when a line is logged, so an idle proxy rotates on its next line.
+*/
+#[derive(Debug)]
+pub struct RotationTrigger {
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    next_rotation: Mutex<Instant>,
+}
+
+impl RotationTrigger {
+    pub fn new(max_size: Option<u64>, interval: Option<Duration>) -> RotationTrigger {
+        let now = Instant::now();
+        RotationTrigger {
+            max_size: max_size,
+            interval: interval,
+            next_rotation: Mutex::new(interval.map(|interval| now + interval).unwrap_or(now)),
+        }
+    }
+
+    fn should_rotate(&self, len: u64, now: Instant) -> bool {
+        let mut next_rotation = match self.next_rotation.lock() {
+            Ok(next_rotation) => next_rotation,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let size_exceeded = self.max_size.map(|max_size| len > max_size).unwrap_or(false);
+        let interval_passed = self.interval.is_some() && now >= *next_rotation;
+        if !size_exceeded && !interval_passed {
+            return false;
+        }
+        // Any rotation starts a new interval.
+        if let Some(interval) = self.interval {
+            *next_rotation = now + interval;
+        }
+        return true;
+    }
+}
+
+impl Trigger for RotationTrigger {
+    fn trigger(&self, file: &LogFile) -> Result<bool, Box<Error + Sync + Send>> {
+        return Ok(self.should_rotate(file.len(), Instant::now()));
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Some(512));
+    assert_eq!(parse_size("10k"), Some(10240));
+    assert_eq!(parse_size("100M"), Some(100 * 1024 * 1024));
+    assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+    assert_eq!(parse_size("M"), None);
+    assert_eq!(parse_size("ten"), None);
+}
+
+#[test]
+fn test_rotation_trigger() {
+    let by_size = RotationTrigger::new(Some(1000), None);
+    let now = Instant::now();
+    assert!(!by_size.should_rotate(1000, now));
+    assert!(by_size.should_rotate(1001, now));
+    assert!(!by_size.should_rotate(0, now + Duration::from_secs(86400)));
+
+    let by_time = RotationTrigger::new(None, Some(Duration::from_secs(60)));
+    let now = Instant::now();
+    assert!(!by_time.should_rotate(1 << 40, now));
+    assert!(by_time.should_rotate(0, now + Duration::from_secs(61)));
+    // The next rotation is an interval after the last one.
+    assert!(!by_time.should_rotate(0, now + Duration::from_secs(120)));
+    assert!(by_time.should_rotate(0, now + Duration::from_secs(122)));
+
+    let both = RotationTrigger::new(Some(1000), Some(Duration::from_secs(60)));
+    let now = Instant::now();
+    assert!(both.should_rotate(1001, now + Duration::from_secs(30)));
+    // Rotating by size restarts the interval.
+    assert!(!both.should_rotate(0, now + Duration::from_secs(61)));
+    assert!(both.should_rotate(0, now + Duration::from_secs(91)));
+}
+
+#[test]
+fn test_parse_component_levels() {
+    let mut levels = BTreeMap::new();
+    levels.insert("cluster".to_owned(), "debug".to_owned());
+    levels.insert("protocol".to_owned(), "WARNING".to_owned());
+    let parsed = parse_component_levels(&levels).unwrap();
+    assert_eq!(parsed.get("cluster"), Some(&LogLevelFilter::Debug));
+    assert_eq!(parsed.get("protocol"), Some(&LogLevelFilter::Warn));
+
+    levels.insert("backend".to_owned(), "LOUD".to_owned());
+    assert_eq!(parse_component_levels(&levels), Err("backend = LOUD".to_owned()));
+    levels.remove("backend");
+    levels.insert("pool".to_owned(), "DEBUG".to_owned());
+    assert_eq!(parse_component_levels(&levels), Err("pool = DEBUG".to_owned()));
+}
+
+#[test]
+fn test_build_config() {
+    let mut component_levels = BTreeMap::new();
+    component_levels.insert("cluster".to_owned(), LogLevelFilter::Debug);
+    let appenders: Vec<(String, Arc<Append>)> = vec![
+        ("stdout".to_owned(), Arc::new(log4rs::append::console::ConsoleAppender::builder().build())),
+    ];
+    let config = build_config(&appenders, LogLevelFilter::Info, &component_levels).unwrap();
+    assert_eq!(config.root().level(), LogLevelFilter::Info);
+    assert_eq!(config.root().appenders(), &["stdout".to_owned()]);
+    assert_eq!(config.loggers().len(), 1);
+    assert_eq!(config.loggers()[0].name(), "redflareproxy::cluster_backend");
+    assert_eq!(config.loggers()[0].level(), LogLevelFilter::Debug);
+}
+
+#[test]
+fn test_log_format() {
+    assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+    assert_eq!(LogFormat::parse("TEXT"), Some(LogFormat::Text));
+    assert_eq!(LogFormat::parse("xml"), None);
+}
+
+#[test]
+fn test_with_fields() {
+    let mut seen = Vec::new();
+    with_fields("client_connected", &[("pool", "pool1".to_owned()), ("token", 20.to_string())], || {
+        seen.push(log_mdc::get("event", |v| v.map(|v| v.to_owned())));
+        seen.push(log_mdc::get("pool", |v| v.map(|v| v.to_owned())));
+        seen.push(log_mdc::get("token", |v| v.map(|v| v.to_owned())));
+    });
+    assert_eq!(seen, vec![Some("client_connected".to_owned()), Some("pool1".to_owned()), Some("20".to_owned())]);
+    assert_eq!(log_mdc::get("pool", |v| v.map(|v| v.to_owned())), None);
+}
@@ -0,0 +1,186 @@
+use filter::{encode_command, Filter, FilterAction, FilterContext};
+use log::LogLevel;
+use rlua::{self, Context, Lua, Table, Value};
+use slowlog::split_args;
+use std::fs::File;
+use std::io::Read;
+
+// Sent instead of a response when the script fails on the request.
+const SCRIPT_FAILED: &[u8] = b"-ERROR: Filter script failed\r\n";
+
+/*
+    A filter written in Lua, loaded from a pool's lua_filter script, so that rejection rules, key rewrites and routing
+    tweaks can be changed without rebuilding the proxy. A rewritten key is sharded like any other. The script defines
+    either or both of:
+
+    function on_request(command, context)
+    function on_response(response, context)
+
+    command is a list of the request's arguments, e.g. {"GET", "a"}, and response is the raw redis response. context
+    has the pool and client, and context.annotate(key, value), which adds a field to the filter_annotation log event.
+
+    on_request returns nil to pass the request on, a list of arguments to pass on instead, {error = "..."} to answer it
+    with an error, or {reply = "..."} to answer it with a raw redis response. on_response returns nil, or a raw redis
+    response to send instead. If the script fails, the request is answered with an error, and a response is left as it
+    is.
+*/
+pub struct LuaFilter {
+    path: String,
+    lua: Lua,
+}
+
+impl LuaFilter {
+    pub fn load(path: &str) -> Result<LuaFilter, String> {
+        let mut source = Vec::new();
+        if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut source)) {
+            return Err(format!("Unable to read {}: {}", path, err));
+        }
+        let lua = Lua::new();
+        let defined = lua.context(|ctx| -> rlua::Result<bool> {
+            try!(try!(ctx.load(&source).set_name(path)).exec());
+            let globals = ctx.globals();
+            let on_request: Value = try!(globals.get("on_request"));
+            let on_response: Value = try!(globals.get("on_response"));
+            Ok(is_function(&on_request) || is_function(&on_response))
+        });
+        return match defined {
+            Ok(true) => Ok(LuaFilter {
+                path: path.to_owned(),
+                lua: lua,
+            }),
+            Ok(false) => Err(format!("{} defines neither on_request nor on_response", path)),
+            Err(err) => Err(format!("Unable to load {}: {}", path, err)),
+        };
+    }
+
+    fn filter_request(&self, context: &mut FilterContext, request: &[u8]) -> rlua::Result<FilterAction> {
+        return self.lua.context(|ctx| {
+            let args: Vec<rlua::String> = try!(split_args(request).into_iter().map(|arg| ctx.create_string(arg)).collect());
+            let command = try!(ctx.create_sequence_from(args));
+            let table = match try!(call_hook(ctx, "on_request", Value::Table(command), context)) {
+                Value::Nil => return Ok(FilterAction::Continue),
+                Value::Table(table) => table,
+                _ => return Err(rlua::Error::RuntimeError("on_request must return nil or a table".to_owned())),
+            };
+            if let Some(error) = try!(table.get::<_, Option<rlua::String>>("error")) {
+                let mut reply = b"-".to_vec();
+                reply.extend_from_slice(error.as_bytes());
+                reply.extend_from_slice(b"\r\n");
+                return Ok(FilterAction::Reply(reply));
+            }
+            if let Some(reply) = try!(table.get::<_, Option<rlua::String>>("reply")) {
+                return Ok(FilterAction::Reply(reply.as_bytes().to_vec()));
+            }
+            let args = try!(table.sequence_values::<rlua::String>().collect::<rlua::Result<Vec<_>>>());
+            if args.is_empty() {
+                return Err(rlua::Error::RuntimeError("on_request returned an empty command".to_owned()));
+            }
+            return Ok(FilterAction::Rewrite(encode_command(args.iter().map(|arg| arg.as_bytes()))));
+        });
+    }
+
+    fn filter_response(&self, context: &mut FilterContext, response: &[u8]) -> rlua::Result<FilterAction> {
+        return self.lua.context(|ctx| {
+            let response = try!(ctx.create_string(response));
+            return match try!(call_hook(ctx, "on_response", Value::String(response), context)) {
+                Value::Nil => Ok(FilterAction::Continue),
+                Value::String(response) => Ok(FilterAction::Rewrite(response.as_bytes().to_vec())),
+                _ => Err(rlua::Error::RuntimeError("on_response must return nil or a string".to_owned())),
+            };
+        });
+    }
+
+    fn log_failure(&self, hook: &str, context: &FilterContext, err: rlua::Error) {
+        log_event!(LogLevel::Warn, "lua_filter_failed", { pool: context.pool, script: self.path }, "{} of {} failed for pool {}: {}", hook, self.path, context.pool, err);
+    }
+}
+
+impl Filter for LuaFilter {
+    fn on_request(&mut self, context: &mut FilterContext, request: &[u8]) -> FilterAction {
+        return match self.filter_request(context, request) {
+            Ok(action) => action,
+            Err(err) => {
+                self.log_failure("on_request", context, err);
+                FilterAction::Reply(SCRIPT_FAILED.to_vec())
+            }
+        };
+    }
+
+    fn on_response(&mut self, context: &mut FilterContext, response: &[u8]) -> FilterAction {
+        return match self.filter_response(context, response) {
+            Ok(action) => action,
+            Err(err) => {
+                self.log_failure("on_response", context, err);
+                FilterAction::Continue
+            }
+        };
+    }
+}
+
+// Calls the script's hook with the message and a context table. Returns nil if the script doesn't define the hook.
+fn call_hook<'lua>(ctx: Context<'lua>, hook: &str, message: Value<'lua>, context: &mut FilterContext) -> rlua::Result<Value<'lua>> {
+    let function = match try!(ctx.globals().get::<_, Value>(hook)) {
+        Value::Function(function) => function,
+        _ => return Ok(Value::Nil),
+    };
+    let pool = context.pool;
+    let client = context.client;
+    return ctx.scope(|scope| {
+        let table: Table = try!(ctx.create_table());
+        try!(table.set("pool", pool));
+        try!(table.set("client", client));
+        try!(table.set("annotate", try!(scope.create_function_mut(|_, (key, value): (String, String)| {
+            context.annotate(&key, &value);
+            Ok(())
+        }))));
+        return function.call::<_, Value>((message, table));
+    });
+}
+
+fn is_function(value: &Value) -> bool {
+    return matches!(*value, Value::Function(_));
+}
+
+#[test]
+fn test_lua_filter() {
+    use std::io::Write;
+    let path = format!("{}/redflare-test-filter-{}.lua", std::env::temp_dir().display(), std::process::id());
+    File::create(&path).unwrap().write_all(br#"
+        function on_request(command, context)
+            if command[1] == "FLUSHALL" then
+                context.annotate("denied", command[1])
+                return {error = "ERROR: Denied"}
+            end
+            if command[1] == "GET" then
+                command[2] = context.pool .. ":" .. command[2]
+                return command
+            end
+            if command[1] == "PING" then
+                return {reply = "+PONG\r\n"}
+            end
+            if command[1] == "BROKEN" then
+                return 1
+            end
+        end
+
+        function on_response(response, context)
+            if response == "$-1\r\n" then
+                return "$0\r\n\r\n"
+            end
+        end
+    "#).unwrap();
+    let mut filter = LuaFilter::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut context = FilterContext::new("pool1", 1);
+
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$8\r\nFLUSHALL\r\n"), FilterAction::Reply(b"-ERROR: Denied\r\n".to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"), FilterAction::Rewrite(b"*2\r\n$3\r\nGET\r\n$7\r\npool1:a\r\n".to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$4\r\nPING\r\n"), FilterAction::Reply(b"+PONG\r\n".to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n"), FilterAction::Continue);
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$6\r\nBROKEN\r\n"), FilterAction::Reply(SCRIPT_FAILED.to_vec()));
+
+    assert_eq!(filter.on_response(&mut context, b"$-1\r\n"), FilterAction::Rewrite(b"$0\r\n\r\n".to_vec()));
+    assert_eq!(filter.on_response(&mut context, b"+OK\r\n"), FilterAction::Continue);
+
+    assert!(LuaFilter::load("/nonexistent.lua").is_err());
+}
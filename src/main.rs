@@ -1,81 +1,17 @@
-extern crate mio;
-extern crate mio_more;
-extern crate toml;
+extern crate redflareproxy;
 #[macro_use]
 extern crate log;
 extern crate log4rs;
-extern crate env_logger;
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
+extern crate humantime;
 extern crate clap;
 use redflareproxy::ProxyError;
+use redflareproxy::{clock, config, daemon, handoff, logging, signals, stats, syslog, systemd, version, worker};
 use clap::{Arg, App};
-extern crate daemonize;
-extern crate conhash;
-extern crate rand;
-extern crate crc16;
-extern crate bufstream;
-extern crate fxhash;
-extern crate crc;
-extern crate fasthash;
-extern crate hashers;
-extern crate hashbrown;
-extern crate memchr;
-use log::LogLevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
-use log4rs::encode::pattern::PatternEncoder;
-use log4rs::config::{Appender, Config, Root};
-
-mod admin;
-mod redflareproxy;
-mod config;
-mod backend;
-mod cluster_backend;
-mod backendpool;
-mod redisprotocol;
-mod hash;
-mod client;
-mod stats;
-
-mod bufreader;
-
-#[cfg(test)]
-pub fn init_logging() {
-    let stdout = ConsoleAppender::builder().build();
-    let config = 
-            Config::builder()
-                .appender(Appender::builder().build("stdout", Box::new(stdout)))
-                .build(Root::builder().appender("stdout").build(LogLevelFilter::Debug))
-                .unwrap();
-
-    match log4rs::init_config(config) {
-        Ok(_) => {},
-        Err(logger_error) => {
-            println!("Logging error: {:?}", logger_error);
-            return;
-        }
-    };
-}
-#[cfg(test)]
-pub fn init_logging_info() {
-    let stdout = ConsoleAppender::builder().build();
-    let config = 
-            Config::builder()
-                .appender(Appender::builder().build("stdout", Box::new(stdout)))
-                .build(Root::builder().appender("stdout").build(LogLevelFilter::Info))
-                .unwrap();
-
-    match log4rs::init_config(config) {
-        Ok(_) => {},
-        Err(logger_error) => {
-            println!("Logging error: {:?}", logger_error);
-            return;
-        }
-    };
-}
-
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
 
 /*
 Entrypoint for redflareproxy.
@@ -83,7 +19,7 @@ Entrypoint for redflareproxy.
 fn main() -> Result<(), ProxyError> {
     // Take in args.
     let matches = App::new("RedFlareProxy")
-                    .version("0.1")
+                    .version(version::VERSION)
                     .author("Kevin X. <xiaok10003@gmail.com>")
                     .about("Fast, light-weight redis proxy")
                     .arg(Arg::with_name("config")
@@ -93,6 +29,11 @@ fn main() -> Result<(), ProxyError> {
                         .default_value("conf/config.toml")
                         .help("Sets a custom config file")
                         .takes_value(true))
+                    .arg(Arg::with_name("profile")
+                        .long("profile")
+                        .value_name("PROFILE")
+                        .takes_value(true)
+                        .help("Selects a [profile.<name>] section of the config file to apply over the base config"))
                     .arg(Arg::with_name("log_file")
                             .short("o")
                             .long("log_file")
@@ -105,65 +46,231 @@ fn main() -> Result<(), ProxyError> {
                         .value_name("LOG_LEVEL")
                         .default_value("INFO")
                         .help("Sets the level of verbosity: DEBUG/INFO/WARNING/ERROR"))
+                    .arg(Arg::with_name("log_format")
+                        .long("log_format")
+                        .value_name("LOG_FORMAT")
+                        .default_value("TEXT")
+                        .help("Sets the log format: TEXT/JSON. JSON writes one object per line, with structured fields"))
+                    .arg(Arg::with_name("log_max_size")
+                        .long("log_max_size")
+                        .value_name("SIZE")
+                        .takes_value(true)
+                        .help("Rotates the log file once it exceeds this size, e.g. 100M. Accepts K/M/G suffixes"))
+                    .arg(Arg::with_name("log_rotate_interval")
+                        .long("log_rotate_interval")
+                        .value_name("INTERVAL")
+                        .takes_value(true)
+                        .help("Rotates the log file after this much time, e.g. 1day or 12h"))
+                    .arg(Arg::with_name("log_retention")
+                        .long("log_retention")
+                        .value_name("COUNT")
+                        .default_value("5")
+                        .help("Sets how many rotated log files to keep, as LOG_FILE.1 (newest) to LOG_FILE.COUNT"))
+                    .arg(Arg::with_name("syslog")
+                        .long("syslog")
+                        .value_name("ADDRESS")
+                        .takes_value(true)
+                        .help("Also sends logs to syslog: 'local' for the local syslog daemon, or host:port of a remote server (UDP)"))
+                    .arg(Arg::with_name("syslog_facility")
+                        .long("syslog_facility")
+                        .value_name("FACILITY")
+                        .default_value("daemon")
+                        .help("Sets the syslog facility, e.g. daemon, user, local0-local7"))
+                    .arg(Arg::with_name("syslog_severity")
+                        .long("syslog_severity")
+                        .value_name("MAPPING")
+                        .takes_value(true)
+                        .help("Overrides the syslog severity of log levels, e.g. ERROR=crit,INFO=notice"))
+                    .arg(Arg::with_name("daemonize")
+                        .short("d")
+                        .long("daemonize")
+                        .help("Runs the proxy in the background"))
+                    .arg(Arg::with_name("pid_file")
+                        .short("p")
+                        .long("pid_file")
+                        .value_name("PID_FILE")
+                        .takes_value(true)
+                        .help("Sets the file to write the process id to"))
+                    .arg(Arg::with_name("stdout_file")
+                        .long("stdout_file")
+                        .value_name("STDOUT_FILE")
+                        .takes_value(true)
+                        .help("Redirects stdout to the given file"))
+                    .arg(Arg::with_name("stderr_file")
+                        .long("stderr_file")
+                        .value_name("STDERR_FILE")
+                        .takes_value(true)
+                        .help("Redirects stderr to the given file"))
                     .get_matches();
 
     // initialize logging
-    let log_level = match matches.value_of("log_level").unwrap().to_uppercase().as_str().trim() {
-        "DEBUG" => LogLevelFilter::Debug,
-        "INFO" => LogLevelFilter::Info,
-        "WARNING" => LogLevelFilter::Warn,
-        "ERROR" => LogLevelFilter::Error,
-        level => {
-            return Err(ProxyError::InvalidLogLevel(level.to_string()));
+    let log_level = matches.value_of("log_level").unwrap();
+    let log_level = match logging::parse_level(log_level) {
+        Some(level) => level,
+        None => {
+            return Err(ProxyError::InvalidLogLevel(log_level.to_string()));
         }
     };
 
-    let stdout = ConsoleAppender::builder().build();
+    let log_format = matches.value_of("log_format").unwrap();
+    let log_format = match logging::LogFormat::parse(log_format) {
+        Some(format) => format,
+        None => {
+            return Err(ProxyError::InvalidLogFormat(log_format.to_string()));
+        }
+    };
+
+    let stdout = ConsoleAppender::builder().encoder(log_format.encoder()).build();
+
+    let mut appenders: Vec<(String, Box<log4rs::append::Append>)> = vec![("stdout".to_owned(), Box::new(stdout))];
+
+    let log_max_size = match matches.value_of("log_max_size") {
+        Some(size) => match logging::parse_size(size) {
+            Some(size) => Some(size),
+            None => {
+                return Err(ProxyError::InvalidLogRotation(format!("unrecognized size: {}", size)));
+            }
+        },
+        None => None,
+    };
+    let log_rotate_interval = match matches.value_of("log_rotate_interval") {
+        Some(interval) => match humantime::parse_duration(interval) {
+            Ok(ref interval) if *interval == std::time::Duration::from_secs(0) => {
+                return Err(ProxyError::InvalidLogRotation("interval must be greater than 0".to_owned()));
+            }
+            Ok(interval) => Some(interval),
+            Err(err) => {
+                return Err(ProxyError::InvalidLogRotation(format!("unrecognized interval: {}. {}", interval, err)));
+            }
+        },
+        None => None,
+    };
+    let rotate_logs = log_max_size.is_some() || log_rotate_interval.is_some();
 
-    let log_file = matches.value_of("log_file");
-    let config = match log_file {
-        Some(file_path) => {
+    if let Some(file_path) = matches.value_of("log_file") {
+        if rotate_logs {
+            let retention = matches.value_of("log_retention").unwrap();
+            let retention = match retention.parse::<u32>() {
+                Ok(retention) => retention,
+                Err(_) => {
+                    return Err(ProxyError::InvalidLogRotation(format!("unrecognized retention count: {}", retention)));
+                }
+            };
+            let roller = match FixedWindowRoller::builder().base(1).build(&format!("{}.{{}}", file_path), retention) {
+                Ok(roller) => roller,
+                Err(err) => {
+                    return Err(ProxyError::InvalidLogRotation(err.to_string()));
+                }
+            };
+            let trigger = logging::RotationTrigger::new(log_max_size, log_rotate_interval);
+            let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+            let requests = match RollingFileAppender::builder()
+                .encoder(log_format.encoder()).build(file_path, Box::new(policy)) {
+                Ok(a) => a,
+                Err(err) => {
+                    return Err(ProxyError::LogFileFailure(file_path.to_string(), err));
+                }
+            };
+            appenders.push(("logfile".to_owned(), Box::new(requests)));
+        } else {
             let requests: log4rs::append::file::FileAppender = match FileAppender::builder()
-                .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}"))).build(file_path) {
+                .encoder(log_format.encoder()).build(file_path) {
                 Ok(a) => a,
                 Err(err) => {
                     return Err(ProxyError::LogFileFailure(file_path.to_string(), err));
                 }
             };
-
-            try!(Config::builder()
-                .appender(Appender::builder().build("stdout", Box::new(stdout)))
-                .appender(Appender::builder().build("logfile", Box::new(requests)))
-                .build(Root::builder().appender("stdout").appender("logfile").build(log_level)))
+            appenders.push(("logfile".to_owned(), Box::new(requests)));
         }
-        None => {
-            try!(Config::builder()
-                .appender(Appender::builder().build("stdout", Box::new(stdout)))
-                .build(Root::builder().appender("stdout").build(log_level)))
+    } else if rotate_logs {
+        return Err(ProxyError::InvalidLogRotation("--log_max_size and --log_rotate_interval require --log_file".to_owned()));
+    }
+
+    if let Some(address) = matches.value_of("syslog") {
+        let facility = matches.value_of("syslog_facility").unwrap();
+        let facility = match syslog::parse_facility(facility) {
+            Some(facility) => facility,
+            None => {
+                return Err(ProxyError::InvalidSyslogFacility(facility.to_string()));
+            }
+        };
+        let severities = match syslog::SeverityMap::parse(matches.value_of("syslog_severity")) {
+            Ok(severities) => severities,
+            Err(entry) => {
+                return Err(ProxyError::InvalidSyslogSeverity(entry));
+            }
+        };
+        let appender = match syslog::SyslogAppender::new(address, facility, severities, log_format.message_encoder()) {
+            Ok(a) => a,
+            Err(err) => {
+                return Err(ProxyError::SyslogFailure(address.to_string(), err));
+            }
+        };
+        appenders.push(("syslog".to_owned(), Box::new(appender)));
+    }
+
+    let mut logging = try!(logging::Logging::init(appenders, log_level));
+
+    let config_path = matches.value_of("config").unwrap();
+    let profile = matches.value_of("profile");
+    let mut config = try!(config::load_config(config_path.to_owned(), profile));
+    let component_levels = match logging::parse_component_levels(&config.log_levels) {
+        Ok(component_levels) => component_levels,
+        Err(entry) => {
+            return Err(ProxyError::InvalidLogLevel(entry));
         }
     };
+    try!(logging.set_component_levels(component_levels));
 
-    try!(log4rs::init_config(config));
+    // Command-line process options take precedence over the config file.
+    if matches.is_present("daemonize") {
+        config.daemonize = true;
+    }
+    if let Some(pid_file) = matches.value_of("pid_file") {
+        config.pid_file = Some(pid_file.to_owned());
+    }
+    if let Some(stdout_file) = matches.value_of("stdout_file") {
+        config.stdout_file = Some(stdout_file.to_owned());
+    }
+    if let Some(stderr_file) = matches.value_of("stderr_file") {
+        config.stderr_file = Some(stderr_file.to_owned());
+    }
+    handoff::inherit_listeners();
+    try!(daemon::daemonize(&config));
+    if let Err(err) = signals::install() {
+        return Err(ProxyError::SignalHandlerFailure(err));
+    }
 
-    let config_path = matches.value_of("config").unwrap();
-    
     // Start proxy.
     debug!("Starting up");
 
-    let mut redflareproxy = try!(redflareproxy::RedFlareProxy::new(config_path.to_owned()));
-    try!(redflareproxy.run());
+    let profile = profile.map(|p| p.to_owned());
+    // With more than one worker, this thread runs worker 0, which also serves the admin port.
+    let mut workers = Vec::new();
+    let worker = if config.workers > 1 {
+        let shards = stats::WorkerShards::new(config.workers);
+        workers = worker::spawn_workers(&config, config_path, profile.clone(), &shards);
+        Some(worker::WorkerContext { id: 0, shards: shards })
+    } else {
+        None
+    };
+    let mut redflareproxy = try!(redflareproxy::RedFlareProxy::new(config.clone(), config_path.to_owned(), profile, Some(logging), worker));
+    handoff::finish_takeover();
+    if let Some(notifier) = systemd::Notifier::from_env(&config, clock::now()) {
+        redflareproxy.set_systemd(notifier);
+    }
+    let result = redflareproxy.run();
+    // Worker 0 stops on shutdown, and the other workers drain their own in-flight requests before stopping too.
+    if result.is_err() {
+        signals::request_shutdown();
+    }
+    for handle in workers {
+        if handle.join().is_err() {
+            error!("A worker panicked while shutting down.");
+        }
+    }
+    daemon::remove_pid_file(&config);
+    try!(result);
     debug!("Finished.");
     return Ok(());
 }
-
-impl std::convert::From<log::SetLoggerError> for redflareproxy::ProxyError {
-    fn from(error: log::SetLoggerError) -> Self {
-        return ProxyError::SetLoggerError(error);
-    }
-}
-
-impl std::convert::From<log4rs::config::Errors> for redflareproxy::ProxyError {
-    fn from(error: log4rs::config::Errors) -> Self {
-        return ProxyError::InvalidParams(error);
-    }
-}
\ No newline at end of file
@@ -0,0 +1,140 @@
+use config::{MaintenanceMode, MaintenanceWindowConfig};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+pub struct Window {
+    // Indexed from Sunday, like DAYS.
+    days: [bool; 7],
+    // Minutes from midnight UTC.
+    start: u64,
+    end: u64,
+    pub mode: MaintenanceMode,
+    // What clients are sent, e.g. -ERROR: Pool is under maintenance
+    pub response: Vec<u8>,
+}
+
+impl Window {
+    fn parse(config: &MaintenanceWindowConfig) -> Result<Window, String> {
+        let mut days = [config.days.is_empty(); 7];
+        for day in config.days.iter() {
+            match DAYS.iter().position(|name| name.eq_ignore_ascii_case(day)) {
+                Some(index) => days[index] = true,
+                None => return Err(format!("Invalid maintenance day: {}. Expected one of {}", day, DAYS.join(", "))),
+            }
+        }
+        return Ok(Window {
+            days: days,
+            start: try!(parse_time(&config.start)),
+            end: try!(parse_time(&config.end)),
+            mode: config.mode,
+            response: format!("-ERROR: {}\r\n", config.message).into_bytes(),
+        });
+    }
+
+    // Whether the window covers the given minute of the given day, counted from Sunday.
+    fn contains(&self, day: usize, minute: u64) -> bool {
+        if self.start < self.end {
+            return self.days[day] && self.start <= minute && minute < self.end;
+        }
+        // Past midnight, the window belongs to the day it started on.
+        let previous_day = (day + 6) % 7;
+        return (self.days[day] && minute >= self.start) || (self.days[previous_day] && minute < self.end);
+    }
+}
+
+// Minutes from midnight, of a time of day as HH:MM.
+fn parse_time(time: &str) -> Result<u64, String> {
+    let mut parts = time.splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<u64>().ok());
+    let minutes = parts.next().and_then(|minutes| minutes.parse::<u64>().ok());
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => return Ok(hours * 60 + minutes),
+        _ => return Err(format!("Invalid maintenance time: {}. Expected HH:MM", time)),
+    }
+}
+
+/*
+    Scheduled maintenance windows of a pool, so that recurring maintenance of its backends doesn't need anyone to
+    run admin commands. While a Drain window is active, new clients are refused with its message, and while a Deny
+    window is active, every request is answered with it. Whether a window is active is checked once a second, by the
+    sweep timer. When windows overlap, the first one configured applies.
+*/
+pub struct Maintenance {
+    windows: Vec<Window>,
+    // Index of the active window.
+    active: Option<usize>,
+}
+
+impl Maintenance {
+    // None when the pool has no maintenance windows.
+    pub fn from_config(config: &[MaintenanceWindowConfig]) -> Result<Option<Maintenance>, String> {
+        if config.is_empty() {
+            return Ok(None);
+        }
+        let mut windows = Vec::with_capacity(config.len());
+        for window in config.iter() {
+            windows.push(try!(Window::parse(window)));
+        }
+        return Ok(Some(Maintenance {
+            windows: windows,
+            active: None,
+        }));
+    }
+
+    pub fn active(&self) -> Option<&Window> {
+        return self.active.map(|index| &self.windows[index]);
+    }
+
+    // Updates which window is active at the given time. Returns true if that changed.
+    pub fn update(&mut self, now: SystemTime) -> bool {
+        let minutes = match now.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() / 60,
+            Err(_) => return false,
+        };
+        // The epoch was a Thursday.
+        let day = ((minutes / MINUTES_PER_DAY + 4) % 7) as usize;
+        let minute = minutes % MINUTES_PER_DAY;
+        let active = self.windows.iter().position(|window| window.contains(day, minute));
+        if active == self.active {
+            return false;
+        }
+        self.active = active;
+        return true;
+    }
+}
+
+#[test]
+fn test_maintenance_windows() {
+    use std::time::Duration;
+
+    let window = |days: &[&str], start: &str, end: &str| MaintenanceWindowConfig {
+        days: days.iter().map(|day| day.to_string()).collect(),
+        start: start.to_owned(),
+        end: end.to_owned(),
+        mode: MaintenanceMode::Deny,
+        message: "Back soon".to_owned(),
+    };
+    let mut maintenance = Maintenance::from_config(&[window(&["Sun"], "23:00", "01:30"), window(&[], "12:00", "12:10")]).unwrap().unwrap();
+    // Sunday 2024-01-07 00:00 UTC.
+    let sunday = UNIX_EPOCH + Duration::from_secs(1704585600);
+    let at = |hours: u64, minutes: u64| sunday + Duration::from_secs(hours * 3600 + minutes * 60);
+
+    assert!(!maintenance.update(at(0, 30)));
+    assert!(maintenance.active().is_none());
+    assert!(maintenance.update(at(23, 0)));
+    assert_eq!(maintenance.active().unwrap().response, b"-ERROR: Back soon\r\n".to_vec());
+    // Into Monday.
+    assert!(!maintenance.update(at(25, 29)));
+    assert!(maintenance.update(at(25, 30)));
+    assert!(maintenance.active().is_none());
+    // Every day.
+    assert!(maintenance.update(at(36, 5)));
+    assert!(maintenance.update(at(36, 10)));
+
+    assert!(Maintenance::from_config(&[window(&["Someday"], "01:00", "02:00")]).is_err());
+    assert!(Maintenance::from_config(&[window(&[], "24:00", "02:00")]).is_err());
+    assert!(Maintenance::from_config(&[window(&[], "1", "02:00")]).is_err());
+}
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Bytes of buffered data allowed across every worker, from memory_limit. 0 disables the budget.
+static LIMIT: AtomicUsize = AtomicUsize::new(0);
+// Sum of the latest usage reported by each worker.
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+// How often each worker measures its usage. Usage may overshoot the limit by what is buffered in between.
+pub const CHECK_INTERVAL_MS: u64 = 100;
+
+pub fn set_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::SeqCst);
+}
+
+pub fn limit() -> usize {
+    return LIMIT.load(Ordering::SeqCst);
+}
+
+pub fn used() -> usize {
+    return USED.load(Ordering::SeqCst);
+}
+
+/*
+    Whether the proxy is at its memory budget. New client connections and requests are then rejected, until enough
+    buffered data is released, instead of letting the proxy grow until the OOM killer steps in.
+*/
+pub fn is_exhausted() -> bool {
+    let limit = limit();
+    return limit > 0 && used() >= limit;
+}
+
+/*
+    A worker's share of the memory budget. Each measurement replaces the worker's previous one in the total, and the
+    share is removed once the worker exits.
+*/
+pub struct MemoryUsage {
+    reported: usize,
+    next_check: Instant,
+}
+
+impl MemoryUsage {
+    pub fn new(now: Instant) -> MemoryUsage {
+        MemoryUsage {
+            reported: 0,
+            next_check: now,
+        }
+    }
+
+    pub fn is_check_due(&self, now: Instant) -> bool {
+        return now >= self.next_check;
+    }
+
+    pub fn time_until_check(&self, now: Instant) -> Duration {
+        if self.next_check > now {
+            return self.next_check - now;
+        }
+        return Duration::from_millis(0);
+    }
+
+    pub fn report(&mut self, bytes: usize, now: Instant) {
+        if bytes > self.reported {
+            USED.fetch_add(bytes - self.reported, Ordering::SeqCst);
+        } else {
+            USED.fetch_sub(self.reported - bytes, Ordering::SeqCst);
+        }
+        self.reported = bytes;
+        self.next_check = now + Duration::from_millis(CHECK_INTERVAL_MS);
+    }
+}
+
+impl Drop for MemoryUsage {
+    fn drop(&mut self) {
+        USED.fetch_sub(self.reported, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_memory_budget() {
+    let now = Instant::now();
+    let interval = Duration::from_millis(CHECK_INTERVAL_MS);
+    let mut first = MemoryUsage::new(now);
+    let mut second = MemoryUsage::new(now);
+    assert!(first.is_check_due(now));
+    first.report(600, now);
+    second.report(500, now);
+    assert!(!first.is_check_due(now));
+    assert_eq!(first.time_until_check(now), interval);
+    assert_eq!(used(), 1100);
+
+    // Unlimited until a limit is set.
+    assert!(!is_exhausted());
+    set_limit(1000);
+    assert!(is_exhausted());
+    // A new measurement replaces the worker's previous one.
+    first.report(400, now + interval);
+    assert_eq!(used(), 900);
+    assert!(!is_exhausted());
+    drop(second);
+    assert_eq!(used(), 400);
+    set_limit(0);
+}
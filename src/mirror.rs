@@ -0,0 +1,206 @@
+use config::BackendPoolConfig;
+use hashbrown::HashMap;
+use stats::metric_labels;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+// Shared between a pool, which reports them, and its clients, which compare the responses of each mirrored request.
+pub type SharedMirrorStats = Rc<RefCell<MirrorStats>>;
+
+/*
+    Which copy of a client's requests a backend receives, on top of the backend the request was sharded to: the
+    secondary of a dual_write backend, or the pool's canary. Its responses are compared instead of written to clients.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MirrorKind {
+    Secondary,
+    Canary,
+}
+
+pub struct MirrorStats {
+    // Requests written to the mirror as well as their backend.
+    pub requests: usize,
+    // Requests only written to their backend, because the mirror was down.
+    pub skipped: usize,
+    // Error responses from the mirror, including the proxy's own, e.g. for a timeout.
+    pub errors: usize,
+    // Requests whose mirror response differs from their backend's.
+    pub divergences: usize,
+}
+
+impl MirrorStats {
+    pub fn shared() -> SharedMirrorStats {
+        Rc::new(RefCell::new(MirrorStats {
+            requests: 0,
+            skipped: 0,
+            errors: 0,
+            divergences: 0,
+        }))
+    }
+
+    pub fn reset(&mut self) {
+        self.requests = 0;
+        self.skipped = 0;
+        self.errors = 0;
+        self.divergences = 0;
+    }
+
+    // Formats the counters as one metric per line, each named after the mirror, e.g. canary_divergences.
+    pub fn format_metrics(&self, mirror_name: &str, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("requests", self.requests),
+            ("skipped", self.skipped),
+            ("errors", self.errors),
+            ("divergences", self.divergences),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}_{}{{{}}} {}\n", config.metric_prefix, mirror_name, name, labels, value));
+        }
+        return output;
+    }
+}
+
+// The first of a mirrored request's two responses, kept until the other arrives.
+enum Answer {
+    Neither,
+    Primary(Vec<u8>),
+    Mirror(Vec<u8>),
+}
+
+/*
+    A client's requests that were also written to a mirror: the mutations of a dual_write pool, which go to the
+    secondary of their backend, e.g. a backend in the datacenter being migrated to, and the requests sampled for the
+    pool's canary. Only the response of the request's own backend is written to the client. The mirror's is compared
+    with it once both have arrived, and errors and differences are counted.
+
+    Every request that is started gets exactly one response from each backend, since a backend that times out or goes
+    down answers its pending requests with errors.
+*/
+pub struct Mirrors {
+    stats: SharedMirrorStats,
+    pending: HashMap<(Instant, usize), Answer>,
+}
+
+impl Mirrors {
+    pub fn new(stats: &SharedMirrorStats) -> Mirrors {
+        Mirrors {
+            stats: Rc::clone(stats),
+            pending: HashMap::new(),
+        }
+    }
+
+    // Tracks a request just written to both backends, by request id.
+    pub fn start(&mut self, request_id: (Instant, usize)) {
+        self.stats.borrow_mut().requests += 1;
+        self.pending.insert(request_id, Answer::Neither);
+    }
+
+    pub fn skip(&self) {
+        self.stats.borrow_mut().skipped += 1;
+    }
+
+    pub fn on_primary_response(&mut self, request_id: (Instant, usize), response: &[u8]) {
+        if self.pending.is_empty() {
+            return;
+        }
+        match self.pending.remove(&request_id) {
+            Some(Answer::Neither) => {
+                self.pending.insert(request_id, Answer::Primary(response.to_vec()));
+            }
+            Some(Answer::Mirror(mirrored)) => self.compare(response, &mirrored),
+            Some(answer) => {
+                self.pending.insert(request_id, answer);
+            }
+            None => {}
+        }
+    }
+
+    pub fn on_mirror_response(&mut self, request_id: (Instant, usize), response: &[u8]) {
+        match self.pending.remove(&request_id) {
+            Some(Answer::Neither) => {
+                self.pending.insert(request_id, Answer::Mirror(response.to_vec()));
+            }
+            Some(Answer::Primary(primary)) => self.compare(&primary, response),
+            Some(answer) => {
+                self.pending.insert(request_id, answer);
+            }
+            None => {}
+        }
+    }
+
+    fn compare(&self, primary: &[u8], mirrored: &[u8]) {
+        let mut stats = self.stats.borrow_mut();
+        if mirrored.first() == Some(&b'-') {
+            stats.errors += 1;
+        }
+        if primary != mirrored {
+            stats.divergences += 1;
+            debug!("Mirrored response diverged. Primary: {:?} Mirror: {:?}", std::str::from_utf8(primary), std::str::from_utf8(mirrored));
+        }
+    }
+}
+
+/*
+    Picks the requests mirrored to a pool's canary: percent out of every 100, spread evenly rather than at random, so
+    that the canary's share of the load is exact.
+*/
+pub struct PercentSampler {
+    percent: usize,
+    credit: usize,
+}
+
+impl PercentSampler {
+    pub fn new(percent: usize) -> PercentSampler {
+        PercentSampler {
+            percent: percent,
+            credit: 0,
+        }
+    }
+
+    pub fn sample(&mut self) -> bool {
+        self.credit += self.percent;
+        if self.credit < 100 {
+            return false;
+        }
+        self.credit -= 100;
+        return true;
+    }
+}
+
+#[test]
+fn test_mirrors() {
+    let stats = MirrorStats::shared();
+    let mut mirrors = Mirrors::new(&stats);
+    let now = Instant::now();
+
+    // Responses that weren't mirrored, e.g. to reads of a dual_write pool, are ignored.
+    mirrors.on_primary_response((now, 0), b"$1\r\na\r\n");
+    assert_eq!(stats.borrow().divergences, 0);
+
+    // Either response may arrive first.
+    mirrors.start((now, 1));
+    mirrors.start((now, 2));
+    mirrors.on_primary_response((now, 1), b"+OK\r\n");
+    mirrors.on_mirror_response((now, 2), b"-ERR Proxy timed out\r\n");
+    assert_eq!(stats.borrow().divergences, 0);
+    mirrors.on_mirror_response((now, 1), b"+OK\r\n");
+    mirrors.on_primary_response((now, 2), b"+OK\r\n");
+    assert_eq!(stats.borrow().requests, 2);
+    assert_eq!(stats.borrow().errors, 1);
+    assert_eq!(stats.borrow().divergences, 1);
+    assert!(mirrors.pending.is_empty());
+}
+
+#[test]
+fn test_percent_sampler() {
+    let mut sampler = PercentSampler::new(25);
+    let sampled = (0..100).filter(|_| sampler.sample()).count();
+    assert_eq!(sampled, 25);
+    let mut sampler = PercentSampler::new(0);
+    assert!(!(0..100).any(|_| sampler.sample()));
+    let mut sampler = PercentSampler::new(100);
+    assert!((0..100).all(|_| sampler.sample()));
+}
@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate clap;
+use clap::{Arg, App};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+mod server;
+
+/*
+    An in-memory stand-in for a redis server, for testing the proxy without redis-server. See README.md.
+*/
+fn main() {
+    let matches = App::new("RedFlareProxy Mock Backend")
+                    .version("0.1")
+                    .author("Kevin X. <xiaok10003@gmail.com>")
+                    .about("Serves GET, SET and DEL from memory, with scriptable replies and failures")
+                    .arg(Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value("6379")
+                        .help("Port to listen on, on 127.0.0.1"))
+                    .arg(Arg::with_name("requirepass")
+                        .long("requirepass")
+                        .value_name("PASSWORD")
+                        .takes_value(true)
+                        .help("Password that clients must AUTH with"))
+                    .get_matches();
+
+    let _ = env_logger::init();
+
+    let port = match matches.value_of("port").unwrap().parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => exit_with_error(&format!("Invalid port: {}", matches.value_of("port").unwrap())),
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => exit_with_error(&format!("Failed to listen on port {}: {}", port, err)),
+    };
+    let state = server::State::new(matches.value_of("requirepass").map(|password| password.to_owned()));
+    info!("Listening on port {}", port);
+    server::serve(listener, Arc::new(Mutex::new(state)));
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
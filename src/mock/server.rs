@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How every command other than MOCK is answered, set with MOCK FAIL.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    None,
+    // Replies with an error.
+    Error,
+    // Never replies, like a backend that stopped responding.
+    Hang,
+    // Closes the connection, like a backend that went away.
+    Close,
+}
+
+impl Failure {
+    fn parse(mode: &str) -> Option<Failure> {
+        match mode {
+            "NONE" => Some(Failure::None),
+            "ERROR" => Some(Failure::Error),
+            "HANG" => Some(Failure::Hang),
+            "CLOSE" => Some(Failure::Close),
+            _ => None,
+        }
+    }
+}
+
+// The data and the scripted behavior of the server, shared by its connections.
+pub struct State {
+    data: HashMap<Vec<u8>, Vec<u8>>,
+    // Replies sent instead of running the command, keyed by the command's name in upper case.
+    replies: HashMap<String, Vec<u8>>,
+    failure: Failure,
+    // Added before every reply.
+    delay: Duration,
+    // Set with --requirepass. Clients must AUTH before anything else.
+    password: Option<String>,
+}
+
+impl State {
+    pub fn new(password: Option<String>) -> State {
+        State {
+            data: HashMap::new(),
+            replies: HashMap::new(),
+            failure: Failure::None,
+            delay: Duration::from_millis(0),
+            password: password,
+        }
+    }
+}
+
+pub type SharedState = Arc<Mutex<State>>;
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Send(Vec<u8>),
+    // Nothing is sent back.
+    Hang,
+    // The connection is closed without a reply.
+    Close,
+    // The whole server exits, like SHUTDOWN on a redis server.
+    Shutdown,
+}
+
+// Serves each connection on its own thread, until SHUTDOWN.
+pub fn serve(listener: TcpListener, state: SharedState) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                thread::spawn(move || handle_connection(stream, state));
+            }
+            Err(err) => error!("Failed to accept a connection: {}", err),
+        }
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: SharedState) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let mut authenticated = state.lock().unwrap().password.is_none();
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return,
+            Err(err) => {
+                let _ = writer.write_all(format!("-ERR Protocol error: {}\r\n", err).as_bytes());
+                return;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let (reply, delay) = {
+            let mut state = state.lock().unwrap();
+            let delay = state.delay;
+            (execute(&mut state, &args, &mut authenticated), delay)
+        };
+        match reply {
+            Reply::Send(response) => {
+                if delay > Duration::from_millis(0) {
+                    thread::sleep(delay);
+                }
+                if writer.write_all(&response).is_err() {
+                    return;
+                }
+            }
+            Reply::Hang => {}
+            Reply::Close => return,
+            Reply::Shutdown => {
+                info!("Shutting down");
+                std::process::exit(0);
+            }
+        }
+    }
+}
+
+/*
+    Reads the arguments of the next command, either a multibulk request or an inline one, e.g. the PING that the proxy
+    sends. Returns None once the client hangs up.
+*/
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>, String> {
+    let line = match try!(read_line(reader)) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with(b"*") {
+        let args = line.split(|&byte| byte == b' ').filter(|arg| !arg.is_empty()).map(|arg| arg.to_vec()).collect();
+        return Ok(Some(args));
+    }
+    let count = try!(parse_length(&line[1..]));
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let header = match try!(read_line(reader)) {
+            Some(ref header) if header.starts_with(b"$") => try!(parse_length(&header[1..])),
+            Some(_) => return Err("expected '$'".to_owned()),
+            None => return Ok(None),
+        };
+        let mut arg = vec![0; header + 2];
+        if reader.read_exact(&mut arg).is_err() {
+            return Ok(None);
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err("expected CRLF after a bulk string".to_owned());
+        }
+        arg.truncate(header);
+        args.push(arg);
+    }
+    return Ok(Some(args));
+}
+
+// A line without its CRLF.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line) {
+        Ok(0) | Err(_) => return Ok(None),
+        Ok(_) => {}
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err("expected CRLF".to_owned());
+    }
+    line.truncate(line.len() - 2);
+    return Ok(Some(line));
+}
+
+fn parse_length(digits: &[u8]) -> Result<usize, String> {
+    return std::str::from_utf8(digits).ok().and_then(|digits| digits.parse().ok()).ok_or_else(|| "invalid length".to_owned());
+}
+
+/*
+    Runs a command against the state. MOCK commands script the server, and are always run, even while it's failing:
+        MOCK REPLY <command> <reply>    Answers the command with the raw reply, e.g. "-ERR boom\r\n", instead.
+        MOCK UNREPLY <command>          Runs the command again.
+        MOCK DELAY <ms>                 Waits this long before every reply.
+        MOCK FAIL NONE|ERROR|HANG|CLOSE Fails every other command. See Failure.
+        MOCK RESET                      Undoes all of the above, and empties the data.
+*/
+pub fn execute(state: &mut State, args: &[Vec<u8>], authenticated: &mut bool) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let args = &args[1..];
+    if name == "MOCK" {
+        return Reply::Send(script(state, args));
+    }
+    match state.failure {
+        Failure::None => {}
+        Failure::Error => return Reply::Send(b"-ERR Mock failure\r\n".to_vec()),
+        Failure::Hang => return Reply::Hang,
+        Failure::Close => return Reply::Close,
+    }
+    if !*authenticated && name != "AUTH" {
+        return Reply::Send(b"-NOAUTH Authentication required.\r\n".to_vec());
+    }
+    if let Some(reply) = state.replies.get(&name) {
+        return Reply::Send(reply.clone());
+    }
+    let response = match (&name[..], args.len()) {
+        ("PING", 0) => b"+PONG\r\n".to_vec(),
+        ("PING", 1) | ("ECHO", 1) => bulk(Some(&args[0][..])),
+        ("AUTH", 1) | ("AUTH", 2) => match state.password {
+            Some(ref password) if args[args.len() - 1] == password.as_bytes() => {
+                *authenticated = true;
+                b"+OK\r\n".to_vec()
+            }
+            Some(_) => b"-WRONGPASS invalid username-password pair\r\n".to_vec(),
+            None => b"-ERR Client sent AUTH, but no password is set\r\n".to_vec(),
+        },
+        ("SELECT", 1) => b"+OK\r\n".to_vec(),
+        ("GET", 1) => bulk(state.data.get(&args[0]).map(|value| &value[..])),
+        // Options such as EX are accepted, but keys never expire.
+        ("SET", count) if count >= 2 => {
+            state.data.insert(args[0].clone(), args[1].clone());
+            b"+OK\r\n".to_vec()
+        }
+        ("DEL", count) if count >= 1 => integer(args.iter().filter(|key| state.data.remove(*key).is_some()).count()),
+        ("EXISTS", count) if count >= 1 => integer(args.iter().filter(|key| state.data.contains_key(*key)).count()),
+        ("DBSIZE", 0) => integer(state.data.len()),
+        ("FLUSHALL", _) | ("FLUSHDB", _) => {
+            state.data.clear();
+            b"+OK\r\n".to_vec()
+        }
+        ("INFO", _) => bulk(Some(&b"# Server\r\nredis_version:0.0.0\r\nredis_mode:standalone\r\n"[..])),
+        ("QUIT", 0) => return Reply::Close,
+        ("SHUTDOWN", _) => return Reply::Shutdown,
+        ("PING", _) | ("ECHO", _) | ("AUTH", _) | ("SELECT", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("DBSIZE", _) | ("QUIT", _) => {
+            format!("-ERR wrong number of arguments for '{}' command\r\n", name.to_lowercase()).into_bytes()
+        }
+        _ => format!("-ERR unknown command '{}'\r\n", name.to_lowercase()).into_bytes(),
+    };
+    return Reply::Send(response);
+}
+
+fn script(state: &mut State, args: &[Vec<u8>]) -> Vec<u8> {
+    let subcommand = args.first().map(|arg| String::from_utf8_lossy(arg).to_uppercase());
+    let argument = |index: usize| args.get(index).map(|arg| String::from_utf8_lossy(arg).into_owned());
+    match (subcommand.as_ref().map(|subcommand| &subcommand[..]), args.len()) {
+        (Some("REPLY"), 3) => {
+            state.replies.insert(argument(1).unwrap().to_uppercase(), args[2].clone());
+        }
+        (Some("UNREPLY"), 2) => {
+            state.replies.remove(&argument(1).unwrap().to_uppercase());
+        }
+        (Some("DELAY"), 2) => match argument(1).unwrap().parse::<u64>() {
+            Ok(ms) => state.delay = Duration::from_millis(ms),
+            Err(_) => return b"-ERR invalid delay\r\n".to_vec(),
+        },
+        (Some("FAIL"), 2) => match Failure::parse(&argument(1).unwrap().to_uppercase()) {
+            Some(failure) => state.failure = failure,
+            None => return b"-ERR unknown failure mode, expected one of NONE, ERROR, HANG, CLOSE\r\n".to_vec(),
+        },
+        (Some("RESET"), 1) => {
+            let password = state.password.take();
+            *state = State::new(password);
+        }
+        _ => return b"-ERR unknown MOCK subcommand or wrong number of arguments\r\n".to_vec(),
+    }
+    return b"+OK\r\n".to_vec();
+}
+
+fn bulk(value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => {
+            let mut response = format!("${}\r\n", value.len()).into_bytes();
+            response.extend_from_slice(value);
+            response.extend_from_slice(b"\r\n");
+            return response;
+        }
+        None => return b"$-1\r\n".to_vec(),
+    }
+}
+
+fn integer(value: usize) -> Vec<u8> {
+    return format!(":{}\r\n", value).into_bytes();
+}
+
+#[test]
+fn test_read_command() {
+    let mut input = &b"*2\r\n$3\r\nGET\r\n$1\r\na\r\nPING\r\n*1\r\n$3\r\nGE"[..];
+    assert_eq!(read_command(&mut input), Ok(Some(vec![b"GET".to_vec(), b"a".to_vec()])));
+    assert_eq!(read_command(&mut input), Ok(Some(vec![b"PING".to_vec()])));
+    assert_eq!(read_command(&mut input), Ok(None));
+    assert!(read_command(&mut &b"*1\r\n:3\r\n"[..]).is_err());
+}
+
+#[test]
+fn test_execute() {
+    let command = |args: &[&str]| -> Vec<Vec<u8>> { args.iter().map(|arg| arg.as_bytes().to_vec()).collect() };
+    let mut state = State::new(Some("secret".to_owned()));
+    let mut authenticated = false;
+    let mut run = |state: &mut State, args: &[&str]| execute(state, &command(args), &mut authenticated);
+    assert_eq!(run(&mut state, &["GET", "a"]), Reply::Send(b"-NOAUTH Authentication required.\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["AUTH", "secret"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["SET", "a", "1"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["get", "a"]), Reply::Send(b"$1\r\n1\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["DEL", "a", "b"]), Reply::Send(b":1\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["GET", "a"]), Reply::Send(b"$-1\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["GET"]), Reply::Send(b"-ERR wrong number of arguments for 'get' command\r\n".to_vec()));
+
+    assert_eq!(run(&mut state, &["MOCK", "REPLY", "GET", "-ERR boom\r\n"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["GET", "a"]), Reply::Send(b"-ERR boom\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["MOCK", "FAIL", "HANG"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["PING"]), Reply::Hang);
+    assert_eq!(run(&mut state, &["MOCK", "FAIL", "CLOSE"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["PING"]), Reply::Close);
+    assert_eq!(run(&mut state, &["MOCK", "RESET"]), Reply::Send(b"+OK\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["PING"]), Reply::Send(b"+PONG\r\n".to_vec()));
+    assert_eq!(run(&mut state, &["MOCK", "FAIL", "SOMETIMES"]), Reply::Send(b"-ERR unknown failure mode, expected one of NONE, ERROR, HANG, CLOSE\r\n".to_vec()));
+}
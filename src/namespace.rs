@@ -0,0 +1,162 @@
+use acl::{NOPERM_COMMAND, NOPERM_KEY};
+use redisprotocol::{command_name, OTHER_COMMAND};
+use resp::Args;
+
+/*
+    Key isolation between the users of a pool, so that teams can share its backends. The keys of a request from a
+    user with a namespace are moved into it, e.g. GET a from a user with namespace = "team1" is sent as GET team1:a.
+    Users without a namespace see every key, e.g. for an operator.
+
+    Namespaces can't contain ':', so one can't be the prefix of another's keys, nor '{' or '}', so that the hash tags of
+    the keys still decide their slots. Requests that could reach keys outside of the namespace, through a script or
+    SORT's BY and GET patterns, are denied instead, as are those whose replies name their keys, e.g. XREAD, which would
+    show the user the namespace's prefix. Commands that list keys, e.g. SCAN and KEYS, aren't supported by the proxy.
+*/
+
+// The prefix of the keys of a user's namespace, e.g. team1:
+pub fn key_prefix(namespace: &str) -> Vec<u8> {
+    let mut prefix = namespace.as_bytes().to_vec();
+    prefix.push(b':');
+    return prefix;
+}
+
+pub fn validate(namespace: &str) -> Result<(), String> {
+    if namespace.contains(|c: char| c == ':' || c == '{' || c == '}') {
+        return Err(format!("Namespace can't contain ':', '{{' or '}}': {}", namespace));
+    }
+    return Ok(());
+}
+
+/*
+    The request with its keys moved into the namespace of the prefix. None if it has no keys, or isn't a command the
+    proxy supports, which is then answered like any other request.
+*/
+pub fn prefix_keys(request: &[u8], prefix: &[u8]) -> Option<Vec<u8>> {
+    let command = command_name(request);
+    if command == OTHER_COMMAND {
+        return None;
+    }
+    let args: Vec<&[u8]> = match Args::parse(request) {
+        Ok(args) => match args.collect::<Result<Vec<&[u8]>, _>>() {
+            Ok(args) => args,
+            Err(_) => return None,
+        },
+        Err(_) => return None,
+    };
+    let keys = key_indexes(command, &args);
+    if keys.is_empty() {
+        return None;
+    }
+    let mut rewritten = format!("*{}\r\n", args.len()).into_bytes();
+    for (index, arg) in args.iter().enumerate() {
+        let arg_prefix: &[u8] = if keys.contains(&index) { prefix } else { b"" };
+        rewritten.extend_from_slice(format!("${}\r\n", arg_prefix.len() + arg.len()).as_bytes());
+        rewritten.extend_from_slice(arg_prefix);
+        rewritten.extend_from_slice(arg);
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    return Some(rewritten);
+}
+
+// The NOPERM error for a request of a namespaced user that could reach keys outside of its namespace, if any.
+pub fn isolation_error(command: &str, request: &[u8]) -> Option<&'static [u8]> {
+    match command {
+        // Scripts and functions can call any key, not only the ones they declare. Functions are shared by all users.
+        "EVAL" | "FCALL" | "FCALL_RO" | "FUNCTION" => return Some(NOPERM_COMMAND),
+        // Their replies name the keys, with the prefix. The pops are also rejected as blocking commands.
+        "BLPOP" | "BRPOP" | "BZPOPMAX" | "BZPOPMIN" | "XREAD" | "XREADGROUP" => return Some(NOPERM_COMMAND),
+        "SORT" => {
+            let args: Vec<&[u8]> = match Args::parse(request) {
+                Ok(args) => args.filter_map(|arg| arg.ok()).collect(),
+                Err(_) => return None,
+            };
+            let has_pattern = args.iter().skip(2).any(|arg| arg.eq_ignore_ascii_case(b"BY") || arg.eq_ignore_ascii_case(b"GET"));
+            if has_pattern {
+                return Some(NOPERM_KEY);
+            }
+            return None;
+        }
+        _ => return None,
+    }
+}
+
+// The indexes of the arguments that are keys, of a command as named by command_name.
+fn key_indexes(command: &str, args: &[&[u8]]) -> Vec<usize> {
+    match command {
+        "DEL" | "EXISTS" | "MGET" | "PFCOUNT" | "TOUCH" | "UNLINK" => return (1..args.len()).collect(),
+        "MSET" => return (1..args.len()).filter(|index| index % 2 == 1).collect(),
+        "XGROUP" if args.len() > 2 => return vec![2],
+        "XGROUP" => return Vec::new(),
+        // Denied by isolation_error.
+        "EVAL" | "FCALL" | "FCALL_RO" | "BLPOP" | "BRPOP" | "BZPOPMAX" | "BZPOPMIN" | "XREAD" | "XREADGROUP" => {
+            return Vec::new();
+        }
+        // The options after key longitude latitude radius unit, or key member radius unit, may STORE to another key.
+        "GEORADIUS" => return store_indexes(args, 6),
+        "GEORADIUSBYMEMBER" => return store_indexes(args, 5),
+        "SORT" => return store_indexes(args, 2),
+        _ if args.len() > 1 => return vec![1],
+        _ => return Vec::new(),
+    }
+}
+
+// The key, and the destinations of any STORE or STOREDIST in the options from the given index.
+fn store_indexes(args: &[&[u8]], options_start: usize) -> Vec<usize> {
+    let mut keys = vec![1];
+    for index in options_start..args.len().saturating_sub(1) {
+        if args[index].eq_ignore_ascii_case(b"STORE") || args[index].eq_ignore_ascii_case(b"STOREDIST") {
+            keys.push(index + 1);
+        }
+    }
+    return keys;
+}
+
+#[test]
+fn test_prefix_keys() {
+    let prefix = key_prefix("team1");
+    let prefixed = |request: &[u8]| prefix_keys(request, &prefix).map(|request| String::from_utf8(request).unwrap());
+    assert_eq!(prefixed(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"), Some("*2\r\n$3\r\nGET\r\n$7\r\nteam1:a\r\n".to_owned()));
+    assert_eq!(prefixed(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n"), Some("*3\r\n$3\r\nSET\r\n$7\r\nteam1:a\r\n$1\r\nb\r\n".to_owned()));
+    assert_eq!(prefixed(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n"), Some("*3\r\n$4\r\nMGET\r\n$7\r\nteam1:a\r\n$7\r\nteam1:b\r\n".to_owned()));
+    assert_eq!(
+        prefixed(b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"),
+        Some("*5\r\n$4\r\nMSET\r\n$7\r\nteam1:a\r\n$1\r\n1\r\n$7\r\nteam1:b\r\n$1\r\n2\r\n".to_owned())
+    );
+    assert_eq!(
+        prefixed(b"*5\r\n$6\r\nXGROUP\r\n$6\r\nCREATE\r\n$1\r\na\r\n$1\r\ng\r\n$1\r\n0\r\n"),
+        Some("*5\r\n$6\r\nXGROUP\r\n$6\r\nCREATE\r\n$7\r\nteam1:a\r\n$1\r\ng\r\n$1\r\n0\r\n".to_owned())
+    );
+    assert_eq!(
+        prefixed(b"*4\r\n$4\r\nSORT\r\n$1\r\na\r\n$5\r\nstore\r\n$1\r\nb\r\n"),
+        Some("*4\r\n$4\r\nSORT\r\n$7\r\nteam1:a\r\n$5\r\nstore\r\n$7\r\nteam1:b\r\n".to_owned())
+    );
+    assert_eq!(prefixed(b"*1\r\n$4\r\nPING\r\n"), None);
+    assert_eq!(prefixed(b"*2\r\n$3\r\nGET\r\n$10\r\na"), None);
+}
+
+#[test]
+fn test_isolation_error() {
+    assert!(validate("team1").is_ok());
+    assert!(validate("team:1").is_err());
+    assert!(validate("{team1}").is_err());
+    let eval = b"*4\r\n$4\r\nEVAL\r\n$8\r\nreturn 1\r\n$1\r\n1\r\n$1\r\na\r\n";
+    assert_eq!(isolation_error(command_name(eval), eval), Some(NOPERM_COMMAND));
+    let load = b"*3\r\n$8\r\nFUNCTION\r\n$4\r\nLOAD\r\n$4\r\ncode\r\n";
+    assert_eq!(isolation_error(command_name(load), load), Some(NOPERM_COMMAND));
+    let sort = b"*4\r\n$4\r\nSORT\r\n$1\r\na\r\n$2\r\nby\r\n$1\r\n*\r\n";
+    assert_eq!(isolation_error(command_name(sort), sort), Some(NOPERM_KEY));
+    let sort = b"*3\r\n$4\r\nSORT\r\n$1\r\na\r\n$5\r\nALPHA\r\n";
+    assert_eq!(isolation_error(command_name(sort), sort), None);
+    let get = b"*2\r\n$3\r\nGET\r\n$3\r\nGET\r\n";
+    assert_eq!(isolation_error(command_name(get), get), None);
+    // Replies that name their keys would show the prefix.
+    let xread = b"*4\r\n$5\r\nXREAD\r\n$7\r\nSTREAMS\r\n$1\r\na\r\n$1\r\n0\r\n";
+    assert_eq!(isolation_error(command_name(xread), xread), Some(NOPERM_COMMAND));
+    let xreadgroup = b"*7\r\n$10\r\nXREADGROUP\r\n$5\r\nGROUP\r\n$1\r\ng\r\n$1\r\nc\r\n$7\r\nSTREAMS\r\n\
+        $1\r\na\r\n$1\r\n>\r\n";
+    assert_eq!(isolation_error(command_name(xreadgroup), xreadgroup), Some(NOPERM_COMMAND));
+    let blpop = b"*3\r\n$5\r\nBLPOP\r\n$1\r\na\r\n$1\r\n0\r\n";
+    assert_eq!(isolation_error(command_name(blpop), blpop), Some(NOPERM_COMMAND));
+    let xrange = b"*4\r\n$6\r\nXRANGE\r\n$1\r\na\r\n$1\r\n-\r\n$1\r\n+\r\n";
+    assert_eq!(isolation_error(command_name(xrange), xrange), None);
+}
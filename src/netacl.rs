@@ -0,0 +1,133 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// A network in CIDR notation, e.g. 10.0.0.0/8 or fd00::/8. A plain address is a network of just that address.
+#[derive(Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Cidr, String> {
+        let mut parts = cidr.trim().splitn(2, '/');
+        let address: IpAddr = match parts.next().unwrap_or("").parse() {
+            Ok(address) => address,
+            Err(_) => return Err(format!("Invalid network: {}", cidr)),
+        };
+        let max_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match parts.next() {
+            None => max_len,
+            Some(prefix_len) => match prefix_len.parse::<u32>() {
+                Ok(prefix_len) if prefix_len <= max_len => prefix_len,
+                _ => return Err(format!("Invalid prefix length in network: {}", cidr)),
+            },
+        };
+        return Ok(Cidr {
+            network: address,
+            prefix_len: prefix_len,
+        });
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (&self.network, &normalize(address)) {
+            (&IpAddr::V4(network), &IpAddr::V4(address)) => {
+                prefix_matches(u32::from(network) as u128, u32::from(address) as u128, 32, self.prefix_len)
+            }
+            (&IpAddr::V6(network), &IpAddr::V6(address)) => {
+                prefix_matches(ipv6_bits(&network), ipv6_bits(&address), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, bits: u32, prefix_len: u32) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    return (network >> shift) == (address >> shift);
+}
+
+fn ipv6_bits(address: &Ipv6Addr) -> u128 {
+    return address.octets().iter().fold(0, |bits, &octet| (bits << 8) | octet as u128);
+}
+
+// Clients connecting over IPv4 to a listener on an IPv6 address show up as ::ffff:a.b.c.d, and match IPv4 networks.
+pub fn normalize(address: &IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = *address {
+        let segments = v6.segments();
+        if segments[..5].iter().all(|&segment| segment == 0) && segments[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new(
+                (segments[6] >> 8) as u8, segments[6] as u8, (segments[7] >> 8) as u8, segments[7] as u8));
+        }
+    }
+    return *address;
+}
+
+/*
+    Which client addresses may connect to a listener, from its allow_clients and deny_clients. A client is refused if
+    it is in any denied network, or if there are allowed networks and it is in none of them. This is a backstop for the
+    network's own firewall rules, checked at accept time, before the client can send anything.
+*/
+pub struct NetworkAcl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl NetworkAcl {
+    // None if both lists are empty, so that every client is accepted without a check.
+    pub fn from_config(allow: &[String], deny: &[String]) -> Result<Option<NetworkAcl>, String> {
+        if allow.is_empty() && deny.is_empty() {
+            return Ok(None);
+        }
+        let parse = |networks: &[String]| networks.iter().map(|network| Cidr::parse(network)).collect::<Result<Vec<Cidr>, String>>();
+        return Ok(Some(NetworkAcl {
+            allow: try!(parse(allow)),
+            deny: try!(parse(deny)),
+        }));
+    }
+
+    pub fn permits(&self, address: &IpAddr) -> bool {
+        if self.deny.iter().any(|network| network.contains(address)) {
+            return false;
+        }
+        return self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address));
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let contains = |cidr: &str, address: &str| Cidr::parse(cidr).unwrap().contains(&address.parse().unwrap());
+    assert!(contains("10.0.0.0/8", "10.1.2.3"));
+    assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+    assert!(contains("192.168.1.7", "192.168.1.7"));
+    assert!(!contains("192.168.1.7", "192.168.1.8"));
+    assert!(contains("0.0.0.0/0", "8.8.8.8"));
+    assert!(contains("fd00::/8", "fd12::1"));
+    assert!(!contains("fd00::/8", "fe80::1"));
+    assert!(contains("127.0.0.0/8", "::ffff:127.0.0.1"));
+    assert!(!contains("::/0", "127.0.0.1"));
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("10.0.0/8").is_err());
+    assert!(Cidr::parse("localhost").is_err());
+}
+
+#[test]
+fn test_network_acl() {
+    let networks = |networks: &[&str]| networks.iter().map(|network| network.to_string()).collect::<Vec<String>>();
+    assert!(NetworkAcl::from_config(&[], &[]).unwrap().is_none());
+    assert!(NetworkAcl::from_config(&networks(&["10.0.0.0/8", "bad"]), &[]).is_err());
+
+    let acl = NetworkAcl::from_config(&networks(&["10.0.0.0/8"]), &networks(&["10.0.5.0/24"])).unwrap().unwrap();
+    assert!(acl.permits(&"10.0.4.1".parse().unwrap()));
+    assert!(!acl.permits(&"10.0.5.1".parse().unwrap()));
+    assert!(!acl.permits(&"172.16.0.1".parse().unwrap()));
+
+    let acl = NetworkAcl::from_config(&[], &networks(&["172.16.0.0/12"])).unwrap().unwrap();
+    assert!(acl.permits(&"10.0.5.1".parse().unwrap()));
+    assert!(!acl.permits(&"172.20.0.1".parse().unwrap()));
+}
@@ -0,0 +1,130 @@
+use config::BackendPoolConfig;
+use redflareproxy::ClientToken;
+use trace::RequestTrace;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/*
+    Buffer-and-replay, for brief backend outages such as a redis restart. While a backend isn't ready, the requests
+    written to it are held here instead of failing right away, and written once it's ready again, in the order they
+    arrived. A request that is still held outage_buffer_timeout after it arrived fails as it would have without the
+    buffer, as do requests past outage_buffer_size.
+
+    Requests keep the id they were read with, and the timeout their client set with DEADLINE, so the time held counts
+    toward their timeout.
+*/
+
+pub struct HeldRequest {
+    pub message: Vec<u8>,
+    pub client_token: ClientToken,
+    pub request_id: (Instant, usize),
+    pub trace: Option<RequestTrace>,
+    pub timeout: Option<usize>,
+    deadline: Instant,
+}
+
+pub struct OutageBuffer {
+    timeout: Duration,
+    size: usize,
+    requests: VecDeque<HeldRequest>,
+}
+
+impl OutageBuffer {
+    // None unless the pool has an outage_buffer_timeout.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<OutageBuffer> {
+        if config.outage_buffer_timeout == 0 {
+            return None;
+        }
+        return Some(OutageBuffer::new(Duration::from_millis(config.outage_buffer_timeout as u64), config.outage_buffer_size));
+    }
+
+    pub fn new(timeout: Duration, size: usize) -> OutageBuffer {
+        return OutageBuffer {
+            timeout: timeout,
+            size: size,
+            requests: VecDeque::new(),
+        };
+    }
+
+    /*
+        Holds a request until the backend is ready. Returns false if the buffer is full, in which case the request
+        isn't held.
+    */
+    pub fn hold(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
+        now: Instant,
+    ) -> bool {
+        if self.requests.len() >= self.size {
+            return false;
+        }
+        self.requests.push_back(HeldRequest {
+            message: message.to_vec(),
+            client_token: client_token,
+            request_id: request_id,
+            trace: trace,
+            timeout: timeout,
+            deadline: now + self.timeout,
+        });
+        return true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.requests.is_empty();
+    }
+
+    // Bytes of the requests held.
+    pub fn bytes(&self) -> usize {
+        return self.requests.iter().map(|request| request.message.len()).sum();
+    }
+
+    // When the oldest request held expires.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        return self.requests.front().map(|request| request.deadline);
+    }
+
+    // Removes the requests that have been held for the whole outage_buffer_timeout.
+    pub fn expire(&mut self, now: Instant) -> Vec<HeldRequest> {
+        let mut expired = Vec::new();
+        while self.requests.front().map_or(false, |request| request.deadline <= now) {
+            expired.extend(self.requests.pop_front());
+        }
+        return expired;
+    }
+
+    // Removes every request, in the order they arrived, e.g. to write them once the backend is ready.
+    pub fn take_all(&mut self) -> VecDeque<HeldRequest> {
+        return self.requests.split_off(0);
+    }
+}
+
+#[test]
+fn test_outage_buffer() {
+    use mio::Token;
+
+    let start = Instant::now();
+    let mut buffer = OutageBuffer::new(Duration::from_millis(500), 2);
+    assert!(buffer.hold(b"GET a\r\n", Token(1), (start, 0), None, None, start));
+    assert!(buffer.hold(b"GET b\r\n", Token(2), (start, 0), None, Some(50), start + Duration::from_millis(100)));
+    // Full.
+    assert!(!buffer.hold(b"GET c\r\n", Token(3), (start, 0), None, None, start + Duration::from_millis(100)));
+    assert_eq!(buffer.bytes(), 14);
+    assert_eq!(buffer.next_deadline(), Some(start + Duration::from_millis(500)));
+
+    assert!(buffer.expire(start + Duration::from_millis(499)).is_empty());
+    let expired = buffer.expire(start + Duration::from_millis(500));
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].client_token, Token(1));
+    assert_eq!(buffer.next_deadline(), Some(start + Duration::from_millis(600)));
+
+    assert!(buffer.hold(b"GET d\r\n", Token(4), (start, 0), None, None, start + Duration::from_millis(550)));
+    let held: Vec<(ClientToken, Option<usize>)> = buffer.take_all().iter().map(|request| (request.client_token, request.timeout)).collect();
+    assert_eq!(held, vec![(Token(2), Some(50)), (Token(4), None)]);
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.next_deadline(), None);
+}
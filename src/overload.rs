@@ -0,0 +1,106 @@
+use config::RedFlareProxyConfig;
+
+use std::time::{Duration, Instant};
+
+// How often the pressure on the event loop is checked. Accepting resumes after a whole interval below the thresholds.
+const CHECK_INTERVAL_MS: u64 = 50;
+
+/*
+    Accept throttling, which favours the clients a worker already has while it's overloaded. Once an iteration of the
+    event loop takes longer than overload_latency, or overload_queue_depth requests are in flight to its backends, the
+    worker stops accepting clients on the pool ports. New connections wait in the kernel's listen backlog, and are
+    accepted once the worker is below both thresholds again. The admin port is always accepted on.
+*/
+pub struct AcceptThrottle {
+    latency: Option<Duration>,
+    pub queue_depth: usize,
+    // Slowest iteration since the last check.
+    slowest: Duration,
+    next_check: Instant,
+    paused: bool,
+}
+
+impl AcceptThrottle {
+    // None unless overload_latency or overload_queue_depth is configured.
+    pub fn from_config(config: &RedFlareProxyConfig, now: Instant) -> Option<AcceptThrottle> {
+        if config.overload_latency == 0 && config.overload_queue_depth == 0 {
+            return None;
+        }
+        let latency = if config.overload_latency > 0 {
+            Some(Duration::from_millis(config.overload_latency as u64))
+        } else {
+            None
+        };
+        return Some(AcceptThrottle::new(latency, config.overload_queue_depth, now));
+    }
+
+    pub fn new(latency: Option<Duration>, queue_depth: usize, now: Instant) -> AcceptThrottle {
+        return AcceptThrottle {
+            latency: latency,
+            queue_depth: queue_depth,
+            slowest: Duration::from_secs(0),
+            next_check: now + Duration::from_millis(CHECK_INTERVAL_MS),
+            paused: false,
+        };
+    }
+
+    // Called at the end of each iteration of the event loop, with how long it took.
+    pub fn record_iteration(&mut self, time: Duration) {
+        if time > self.slowest {
+            self.slowest = time;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.paused;
+    }
+
+    pub fn is_check_due(&self, now: Instant) -> bool {
+        return now >= self.next_check;
+    }
+
+    // How long a paused worker may wait for events before checking whether to resume.
+    pub fn time_until_check(&self, now: Instant) -> Duration {
+        if self.next_check > now {
+            return self.next_check - now;
+        }
+        return Duration::from_millis(0);
+    }
+
+    /*
+        Compares the iterations since the last check, and the requests in flight now, with the thresholds. Returns
+        Some(true) when accepting should pause, Some(false) when it should resume, and None when it's unchanged.
+    */
+    pub fn check(&mut self, in_flight: usize, now: Instant) -> Option<bool> {
+        let slow = self.latency.map_or(false, |latency| self.slowest > latency);
+        let queued = self.queue_depth > 0 && in_flight >= self.queue_depth;
+        self.slowest = Duration::from_secs(0);
+        self.next_check = now + Duration::from_millis(CHECK_INTERVAL_MS);
+        let overloaded = slow || queued;
+        if overloaded == self.paused {
+            return None;
+        }
+        self.paused = overloaded;
+        return Some(overloaded);
+    }
+}
+
+#[test]
+fn test_accept_throttle() {
+    let start = Instant::now();
+    let mut throttle = AcceptThrottle::new(Some(Duration::from_millis(100)), 10, start);
+    assert!(!throttle.is_check_due(start));
+    let first_check = start + Duration::from_millis(CHECK_INTERVAL_MS);
+    assert!(throttle.is_check_due(first_check));
+
+    throttle.record_iteration(Duration::from_millis(150));
+    throttle.record_iteration(Duration::from_millis(5));
+    assert_eq!(throttle.check(0, first_check), Some(true));
+    assert!(throttle.is_paused());
+    // Still paused while the queue is deep, even though the loop is fast again.
+    let second_check = first_check + Duration::from_millis(CHECK_INTERVAL_MS);
+    assert_eq!(throttle.time_until_check(first_check), Duration::from_millis(CHECK_INTERVAL_MS));
+    assert_eq!(throttle.check(10, second_check), None);
+    assert_eq!(throttle.check(9, second_check + Duration::from_millis(CHECK_INTERVAL_MS)), Some(false));
+    assert!(!throttle.is_paused());
+}
@@ -0,0 +1,95 @@
+use libc;
+use mio::Ready;
+#[cfg(unix)]
+use mio::unix::UnixReady;
+use std::io::Error;
+use std::os::unix::io::RawFd;
+
+/*
+    What differs between the platforms the event loop runs on, so that the rest of the proxy doesn't assume Linux.
+
+    Readiness: epoll (Linux) and kqueue (macOS, the BSDs) report errors and hang-ups along with readiness, as
+    UnixReady. Elsewhere they only show up as the result of the next read or write, so these are false, and the
+    connection fails on its next read or write instead.
+
+    Sockets: Linux creates them close-on-exec atomically with SOCK_CLOEXEC, other platforms set FD_CLOEXEC after.
+
+    Features that rely on a Linux-only API, e.g. TCP Fast Open, worker_cpus and transparent mode, fail with an error
+    on other platforms, and the rest of the proxy runs without them. Windows isn't supported yet, since signals, hot
+    restart and the listener handoff rely on unix file descriptors.
+*/
+
+// Whether the event is for a socket that failed, e.g. a backend connection that was refused.
+#[cfg(unix)]
+pub fn is_error(readiness: Ready) -> bool {
+    return UnixReady::from(readiness).is_error();
+}
+
+#[cfg(not(unix))]
+pub fn is_error(_readiness: Ready) -> bool {
+    return false;
+}
+
+// Whether the peer hung up. There may still be data left to read.
+#[cfg(unix)]
+pub fn is_hup(readiness: Ready) -> bool {
+    return UnixReady::from(readiness).is_hup();
+}
+
+#[cfg(not(unix))]
+pub fn is_hup(_readiness: Ready) -> bool {
+    return false;
+}
+
+// Whether a connection is closed, with nothing left to read or write, so it can be treated as failed right away.
+pub fn is_closed(readiness: Ready) -> bool {
+    return is_error(readiness) || (is_hup(readiness) && !readiness.is_readable() && !readiness.is_writable());
+}
+
+/*
+    Creates a TCP socket that's closed on exec, so that only the listeners handed off by a hot restart are passed to
+    the new process.
+*/
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn tcp_socket(family: libc::c_int) -> Result<RawFd, Error> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    return Ok(fd);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn tcp_socket(family: libc::c_int) -> Result<RawFd, Error> {
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        let err = Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    return Ok(fd);
+}
+
+#[test]
+fn test_readiness() {
+    assert!(!is_closed(Ready::readable()));
+    assert!(!is_error(Ready::readable() | Ready::writable()));
+    #[cfg(unix)]
+    {
+        assert!(is_closed(Ready::from(UnixReady::error())));
+        // Data that arrived before the hang-up is read first.
+        assert!(!is_closed(Ready::readable() | Ready::from(UnixReady::hup())));
+        assert!(is_closed(Ready::from(UnixReady::hup())));
+    }
+}
+
+#[test]
+fn test_tcp_socket_cloexec() {
+    let fd = tcp_socket(libc::AF_INET).unwrap();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    unsafe { libc::close(fd) };
+    assert!(flags & libc::FD_CLOEXEC != 0);
+}
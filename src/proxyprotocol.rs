@@ -0,0 +1,173 @@
+use client::BufferedClient;
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &'static [u8] = b"PROXY ";
+const V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// The longest v1 header, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    // Only part of the header has been read so far.
+    Incomplete,
+    // The length of the header, and the address of the client that the load balancer accepted, if it passed one on.
+    Complete(usize, Option<SocketAddr>),
+}
+
+/*
+    Parses the HAProxy PROXY protocol header, version 1 or 2, that a load balancer sends ahead of the client's own
+    traffic. See https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+*/
+pub fn parse_header(buf: &[u8]) -> Result<Header, &'static str> {
+    if buf.starts_with(V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+    if buf.starts_with(V1_PREFIX) {
+        return parse_v1(buf);
+    }
+    if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        return Ok(Header::Incomplete);
+    }
+    return Err("missing PROXY protocol header");
+}
+
+// e.g. PROXY TCP4 10.0.0.1 10.0.0.2 51234 6379\r\n
+fn parse_v1(buf: &[u8]) -> Result<Header, &'static str> {
+    let end = match buf.iter().take(V1_MAX_LEN).position(|&byte| byte == b'\n') {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(Header::Incomplete),
+        None => return Err("PROXY protocol v1 header is too long"),
+    };
+    if buf[end - 1] != b'\r' {
+        return Err("invalid PROXY protocol v1 header");
+    }
+    let line = match std::str::from_utf8(&buf[..end - 1]) {
+        Ok(line) => line,
+        Err(_) => return Err("invalid PROXY protocol v1 header"),
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[1] {
+        // The load balancer couldn't tell where the connection came from.
+        "UNKNOWN" => return Ok(Header::Complete(end + 1, None)),
+        "TCP4" | "TCP6" if parts.len() == 6 => {
+            match (parts[2].parse::<IpAddr>(), parts[4].parse::<u16>()) {
+                (Ok(ip), Ok(port)) => return Ok(Header::Complete(end + 1, Some(SocketAddr::new(ip, port)))),
+                _ => return Err("invalid source address in PROXY protocol v1 header"),
+            }
+        }
+        _ => return Err("invalid PROXY protocol v1 header"),
+    }
+}
+
+// A 16 byte binary header, followed by the addresses.
+fn parse_v2(buf: &[u8]) -> Result<Header, &'static str> {
+    if buf.len() < 16 {
+        return Ok(Header::Incomplete);
+    }
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err("unsupported PROXY protocol version");
+    }
+    let len = 16 + ((buf[14] as usize) << 8 | buf[15] as usize);
+    if buf.len() < len {
+        return Ok(Header::Incomplete);
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. a health check from the load balancer itself, which keeps the connection's own address.
+        0 => return Ok(Header::Complete(len, None)),
+        1 => {}
+        _ => return Err("unsupported PROXY protocol v2 command"),
+    }
+    let addresses = &buf[16..len];
+    let port = |bytes: &[u8]| (bytes[0] as u16) << 8 | bytes[1] as u16;
+    let source = match buf[13] >> 4 {
+        // AF_UNSPEC and AF_UNIX have no address to pass on.
+        0 | 3 => None,
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port(&addresses[8..10])))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(&addresses[32..34])))
+        }
+        _ => return Err("invalid addresses in PROXY protocol v2 header"),
+    };
+    return Ok(Header::Complete(len, source));
+}
+
+pub enum HeaderRead {
+    // The header was read and consumed, with the client's address if the load balancer passed one on.
+    Done(Option<SocketAddr>),
+    // Only part of the header has arrived. The rest is read on the next readable event.
+    Wait,
+    // The client hung up before sending all of the header.
+    Closed,
+    Invalid(&'static str),
+}
+
+/*
+    Reads the PROXY protocol header at the start of a client connection, on a listener with proxy_protocol. The
+    client's own requests after it are left buffered.
+*/
+pub fn read_header(client: &mut BufferedClient) -> HeaderRead {
+    match client.fill_buf() {
+        Ok(buf) if buf.is_empty() => return HeaderRead::Closed,
+        Ok(_) => {}
+        Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return HeaderRead::Wait,
+        Err(_) => return HeaderRead::Closed,
+    }
+    loop {
+        match parse_header(client.buffer()) {
+            Ok(Header::Complete(len, source)) => {
+                client.consume(len);
+                return HeaderRead::Done(source);
+            }
+            Ok(Header::Incomplete) => {}
+            Err(err) => return HeaderRead::Invalid(err),
+        }
+        let buffered = client.reset_buf().len();
+        match client.append_buf() {
+            Ok(buf) if buf.len() > buffered => continue,
+            Ok(_) if buffered == client.buf.len() => return HeaderRead::Invalid("PROXY protocol header is larger than the read buffer"),
+            Ok(_) => return HeaderRead::Closed,
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return HeaderRead::Wait,
+            Err(_) => return HeaderRead::Closed,
+        }
+    }
+}
+
+#[test]
+fn test_parse_v1() {
+    let addr = |addr: &str| Some(addr.parse::<SocketAddr>().unwrap());
+    let header = b"PROXY TCP4 10.0.0.1 10.0.0.2 51234 6379\r\n*1\r\n$4\r\nPING\r\n";
+    assert_eq!(parse_header(header), Ok(Header::Complete(41, addr("10.0.0.1:51234"))));
+    assert_eq!(parse_header(b"PROXY TCP6 fd00::1 fd00::2 51234 6379\r\n"), Ok(Header::Complete(39, addr("[fd00::1]:51234"))));
+    assert_eq!(parse_header(b"PROXY UNKNOWN\r\n"), Ok(Header::Complete(15, None)));
+    assert_eq!(parse_header(b"PROX"), Ok(Header::Incomplete));
+    assert_eq!(parse_header(b"PROXY TCP4 10.0.0.1"), Ok(Header::Incomplete));
+    assert!(parse_header(b"PROXY TCP4 10.0.0.1 10.0.0.2 51234\r\n").is_err());
+    assert!(parse_header(b"PROXY TCP4 10.0.0.1 10.0.0.2 51234 6379\n").is_err());
+    assert!(parse_header(b"*1\r\n$4\r\nPING\r\n").is_err());
+    assert!(parse_header(&[b'x'; 200][..]).is_err());
+}
+
+#[test]
+fn test_parse_v2() {
+    let mut header = V2_SIGNATURE.to_vec();
+    // PROXY over TCP4, with 12 bytes of addresses.
+    header.extend_from_slice(&[0x21, 0x11, 0, 12, 10, 0, 0, 1, 10, 0, 0, 2, 0xc8, 0x22, 0x18, 0xeb]);
+    assert_eq!(parse_header(&header[..20]), Ok(Header::Incomplete));
+    header.extend_from_slice(b"*1\r\n");
+    assert_eq!(parse_header(&header), Ok(Header::Complete(28, Some("10.0.0.1:51234".parse().unwrap()))));
+
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+    assert_eq!(parse_header(&local), Ok(Header::Complete(16, None)));
+
+    let mut unsupported = V2_SIGNATURE.to_vec();
+    unsupported.extend_from_slice(&[0x11, 0x11, 0, 0]);
+    assert!(parse_header(&unsupported).is_err());
+}
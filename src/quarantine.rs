@@ -0,0 +1,84 @@
+use config::BackendPoolConfig;
+use netacl;
+
+use hashbrown::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Addresses quarantined at once. Past this, further ones aren't until others expire.
+const MAX_QUARANTINED: usize = 4096;
+
+/*
+    Quarantine for clients that keep sending invalid requests, e.g. port scanners or peers speaking another protocol.
+    A client is closed once it has sent quarantine_threshold of them, and new connections from its address are refused
+    for quarantine_duration, so it doesn't keep taking up the event loop.
+*/
+pub struct Quarantine {
+    pub threshold: usize,
+    duration: Duration,
+    // Quarantined addresses, with when their quarantine ends.
+    addrs: HashMap<IpAddr, Instant>,
+}
+
+impl Quarantine {
+    // None unless the pool has a quarantine_threshold.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<Quarantine> {
+        if config.quarantine_threshold == 0 {
+            return None;
+        }
+        return Some(Quarantine::new(config.quarantine_threshold, Duration::from_millis(config.quarantine_duration as u64)));
+    }
+
+    pub fn new(threshold: usize, duration: Duration) -> Quarantine {
+        return Quarantine {
+            threshold: threshold,
+            duration: duration,
+            addrs: HashMap::new(),
+        };
+    }
+
+    // Quarantines the address. Returns false if too many already are.
+    pub fn add(&mut self, addr: &IpAddr, now: Instant) -> bool {
+        let addr = netacl::normalize(addr);
+        if self.addrs.len() >= MAX_QUARANTINED && !self.addrs.contains_key(&addr) {
+            self.addrs.retain(|_, until| *until > now);
+            if self.addrs.len() >= MAX_QUARANTINED {
+                return false;
+            }
+        }
+        self.addrs.insert(addr, now + self.duration);
+        return true;
+    }
+
+    pub fn contains(&mut self, addr: &IpAddr, now: Instant) -> bool {
+        let addr = netacl::normalize(addr);
+        match self.addrs.get(&addr) {
+            Some(until) if now < *until => return true,
+            Some(_) => {}
+            None => return false,
+        }
+        self.addrs.remove(&addr);
+        return false;
+    }
+}
+
+#[test]
+fn test_quarantine() {
+    let start = Instant::now();
+    let mut quarantine = Quarantine::new(3, Duration::from_millis(100));
+    let addr: IpAddr = "10.0.0.1".parse().unwrap();
+    assert!(quarantine.add(&addr, start));
+    assert!(quarantine.contains(&addr, start + Duration::from_millis(99)));
+    // Connections over IPv6 from an IPv4 client are the same address.
+    assert!(quarantine.contains(&"::ffff:10.0.0.1".parse().unwrap(), start));
+    assert!(!quarantine.contains(&"10.0.0.2".parse().unwrap(), start));
+    assert!(!quarantine.contains(&addr, start + Duration::from_millis(100)));
+
+    for index in 0..MAX_QUARANTINED {
+        let addr = IpAddr::V4((index as u32).into());
+        assert!(quarantine.add(&addr, start));
+    }
+    assert!(!quarantine.add(&addr, start + Duration::from_millis(50)));
+    // Expired addresses make room again.
+    assert!(quarantine.add(&addr, start + Duration::from_millis(100)));
+}
@@ -0,0 +1,226 @@
+use acl::Permissions;
+use clock;
+use config::BackendPoolConfig;
+use namespace;
+use ratelimit::TokenBucket;
+use stats::{escape_label_value, metric_labels};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Shared by the connections of a user, which are charged for each request and response.
+pub type SharedQuota = Rc<RefCell<Quota>>;
+
+pub const QUOTA_EXCEEDED: &'static [u8] = b"-ERR quota exceeded\r\n";
+
+pub struct QuotaStats {
+    pub requests: usize,
+    // Bytes of requests and responses.
+    pub bytes: usize,
+    // Requests that found the user over one of its quotas.
+    pub exceeded: usize,
+    // Requests refused because the user may not run the command or access one of its keys.
+    pub denied: usize,
+}
+
+/*
+    The request and bandwidth quotas of a user. The bytes of a response are only known once it arrives, so bandwidth
+    is charged after the fact, and a user that went over is held back until it has paid it off.
+*/
+pub struct Quota {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    // Set while the user is over a quota, so that Log enforcement logs each overrun once.
+    pub over: bool,
+    pub stats: QuotaStats,
+}
+
+impl Quota {
+    fn new(request_quota: usize, bandwidth_quota: usize, now: Instant) -> Quota {
+        let bucket = |rate: usize| if rate > 0 { Some(TokenBucket::new(rate, rate, now)) } else { None };
+        Quota {
+            requests: bucket(request_quota),
+            bytes: bucket(bandwidth_quota),
+            over: false,
+            stats: QuotaStats {
+                requests: 0,
+                bytes: 0,
+                exceeded: 0,
+                denied: 0,
+            },
+        }
+    }
+
+    // When the user is over one of its quotas, when it's within all of them again.
+    pub fn exceeded(&mut self, now: Instant) -> Option<Instant> {
+        let mut wait: Option<Duration> = None;
+        for bucket in self.requests.iter_mut().chain(self.bytes.iter_mut()) {
+            if !bucket.has(1, now) {
+                let bucket_wait = bucket.wait_time(now);
+                wait = Some(wait.map_or(bucket_wait, |wait| std::cmp::max(wait, bucket_wait)));
+            }
+        }
+        return wait.map(|wait| now + wait);
+    }
+
+    pub fn charge_request(&mut self, bytes: usize) {
+        self.stats.requests += 1;
+        if let Some(ref mut requests) = self.requests {
+            requests.charge(1);
+        }
+        self.charge_bytes(bytes);
+    }
+
+    pub fn charge_bytes(&mut self, bytes: usize) {
+        self.stats.bytes += bytes;
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.charge(bytes);
+        }
+    }
+}
+
+// Who a client authenticated as.
+pub struct Identity {
+    pub user: String,
+    pub quota: SharedQuota,
+    pub permissions: Rc<Permissions>,
+    // The prefix of the user's keys, e.g. team1:, if it has a namespace.
+    pub key_prefix: Option<Rc<Vec<u8>>>,
+}
+
+struct User {
+    password: Vec<u8>,
+    quota: SharedQuota,
+    permissions: Rc<Permissions>,
+    key_prefix: Option<Rc<Vec<u8>>>,
+}
+
+// The users of a pool, which its clients must authenticate as.
+pub struct Users {
+    users: BTreeMap<String, User>,
+}
+
+impl Users {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<Users> {
+        if config.users.is_empty() {
+            return None;
+        }
+        let now = clock::now();
+        let mut users = BTreeMap::new();
+        for (name, user_config) in config.users.iter() {
+            users.insert(name.clone(), User {
+                password: user_config.password.clone().into_bytes(),
+                quota: Rc::new(RefCell::new(Quota::new(user_config.request_quota, user_config.bandwidth_quota, now))),
+                permissions: Rc::new(Permissions::parse(&user_config.commands, &user_config.keys).expect("User permissions are validated when the config is parsed")),
+                key_prefix: if user_config.namespace.is_empty() {
+                    None
+                } else {
+                    Some(Rc::new(namespace::key_prefix(&user_config.namespace)))
+                },
+            });
+        }
+        return Some(Users {
+            users: users,
+        });
+    }
+
+    // Checks the arguments of an AUTH, after the command name, like Redis 6 does.
+    pub fn authenticate(&self, args: &[&[u8]]) -> Result<Identity, &'static [u8]> {
+        let (name, password): (&[u8], &[u8]) = match args.len() {
+            1 => (&b"default"[..], args[0]),
+            2 => (args[0], args[1]),
+            _ => return Err(b"-ERR wrong number of arguments for 'auth' command\r\n"),
+        };
+        match self.users.iter().find(|&(user_name, _)| user_name.as_bytes() == name) {
+            Some((name, user)) if constant_time_eq(&user.password, password) => Ok(Identity {
+                user: name.clone(),
+                quota: Rc::clone(&user.quota),
+                permissions: Rc::clone(&user.permissions),
+                key_prefix: user.key_prefix.clone(),
+            }),
+            _ => Err(b"-WRONGPASS invalid username-password pair\r\n"),
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        for user in self.users.values() {
+            let mut quota = user.quota.borrow_mut();
+            quota.stats.requests = 0;
+            quota.stats.bytes = 0;
+            quota.stats.exceeded = 0;
+            quota.stats.denied = 0;
+        }
+    }
+
+    // Formats the counters of each user as one metric per line, e.g. user_requests{pool="pool1",user="search"} 10
+    pub fn format_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let mut output = String::new();
+        for (name, user) in self.users.iter() {
+            let quota = user.quota.borrow();
+            let metrics = [
+                ("user_requests", quota.stats.requests),
+                ("user_bytes", quota.stats.bytes),
+                ("user_quota_exceeded", quota.stats.exceeded),
+                ("user_denied", quota.stats.denied),
+            ];
+            for &(metric, value) in metrics.iter() {
+                output.push_str(&format!("{}{}{{{},user=\"{}\"}} {}\n", config.metric_prefix, metric, labels, escape_label_value(name), value));
+            }
+        }
+        return output;
+    }
+}
+
+// Compares passwords in time that only depends on their lengths, so that timing doesn't reveal how much of one matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0;
+}
+
+#[test]
+fn test_authenticate() {
+    let now = Instant::now();
+    let mut users = BTreeMap::new();
+    for &(name, password) in [("default", "pass1"), ("search", "pass2")].iter() {
+        users.insert(name.to_owned(), User {
+            password: password.as_bytes().to_vec(),
+            quota: Rc::new(RefCell::new(Quota::new(0, 0, now))),
+            permissions: Rc::new(Permissions::parse(&[], &[]).unwrap()),
+            key_prefix: None,
+        });
+    }
+    let users = Users {
+        users: users,
+    };
+    let authenticate = |args: &[&str]| {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        users.authenticate(&args).map(|identity| identity.user)
+    };
+    assert_eq!(authenticate(&["pass1"]), Ok("default".to_owned()));
+    assert_eq!(authenticate(&["search", "pass2"]), Ok("search".to_owned()));
+    assert!(authenticate(&["search", "pass1"]).is_err());
+    assert!(authenticate(&["other", "pass1"]).is_err());
+    assert!(authenticate(&[]).is_err());
+}
+
+#[test]
+fn test_quota() {
+    let now = Instant::now();
+    let mut quota = Quota::new(2, 100, now);
+    assert_eq!(quota.exceeded(now), None);
+    quota.charge_request(10);
+    quota.charge_request(10);
+    assert_eq!(quota.exceeded(now), Some(now + Duration::from_millis(500)));
+
+    // A large response puts the user in debt until it's paid off.
+    let later = now + Duration::from_secs(10);
+    assert_eq!(quota.exceeded(later), None);
+    quota.charge_request(10);
+    quota.charge_bytes(290);
+    assert_eq!(quota.exceeded(later), Some(later + Duration::from_millis(2010)));
+    assert_eq!((quota.stats.requests, quota.stats.bytes), (3, 320));
+}
@@ -0,0 +1,307 @@
+use audit::glob_match;
+use config::{BackendPoolConfig, KeyRateLimitConfig, LimitAction};
+use clock;
+use redisprotocol::is_read_only;
+use std::time::{Duration, Instant};
+
+pub const RATE_LIMITED: &'static [u8] = b"-ERR rate limit exceeded\r\n";
+
+// Tokens are counted in billionths, so that refills are exact whatever the time between them.
+const TOKEN: i64 = 1_000_000_000;
+
+/*
+    Allows rate requests per second on average, and bursts of up to burst requests. Tokens are added continuously
+    rather than once a second, so that a client that used up its burst is let through again as soon as one is earned.
+    Costs only known afterwards, e.g. the bytes of a response, can be charged past 0, which is paid back before any
+    more tokens are available.
+*/
+pub struct TokenBucket {
+    rate: i64,
+    burst: i64,
+    tokens: i64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Starts full.
+    pub fn new(rate: usize, burst: usize, now: Instant) -> TokenBucket {
+        let burst = if burst < 1 { TOKEN } else { (burst as i64).saturating_mul(TOKEN) };
+        TokenBucket {
+            rate: rate as i64,
+            burst: burst,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let elapsed_nanos = (elapsed.as_secs() as i64).saturating_mul(TOKEN).saturating_add(elapsed.subsec_nanos() as i64);
+        self.tokens = self.tokens.saturating_add(elapsed_nanos.saturating_mul(self.rate)).min(self.burst);
+        self.last_refill = now;
+    }
+
+    // Whether amount tokens are available.
+    pub fn has(&mut self, amount: usize, now: Instant) -> bool {
+        self.refill(now);
+        return self.tokens >= (amount as i64).saturating_mul(TOKEN);
+    }
+
+    pub fn charge(&mut self, amount: usize) {
+        self.tokens = self.tokens.saturating_sub((amount as i64).saturating_mul(TOKEN));
+    }
+
+    // Takes a token, if one is available.
+    pub fn take(&mut self, now: Instant) -> bool {
+        if !self.has(1, now) {
+            return false;
+        }
+        self.charge(1);
+        return true;
+    }
+
+    // How long until the next token is available.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= TOKEN || self.rate == 0 {
+            return Duration::from_millis(0);
+        }
+        let nanos = (TOKEN - self.tokens + self.rate - 1) / self.rate;
+        return Duration::from_nanos(nanos as u64);
+    }
+}
+
+// Whether a client's request may be read now.
+#[derive(Debug, PartialEq)]
+pub enum Admission {
+    Admit,
+    // Answered with the given error instead of being routed.
+    Reject(&'static [u8]),
+    /*
+        Left unread until the client is read again, at the given time for the rate limit, or once a response is
+        written for the in-flight limit. None if a wake up is already pending.
+    */
+    Wait(Option<Instant>),
+}
+
+/*
+    Caps the requests of a single client connection, from the pool's client_rate_limit and client_max_inflight, so that
+    one runaway application instance can't take all of the backends' capacity. Requests over a cap are either answered
+    with an error or left unread, which slows the client down through TCP, depending on client_limit_action.
+*/
+pub struct ClientLimits {
+    bucket: Option<TokenBucket>,
+    max_inflight: usize,
+    action: LimitAction,
+    // Requests that were read and not answered yet. A multi key request counts once.
+    in_flight: usize,
+    // Set while the client waits for a response to get back under max_inflight.
+    waiting_for_response: bool,
+    // When the client is read again, while it waits for a token.
+    wake_at: Option<Instant>,
+}
+
+impl ClientLimits {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<ClientLimits> {
+        if config.client_rate_limit == 0 && config.client_max_inflight == 0 {
+            return None;
+        }
+        let bucket = if config.client_rate_limit > 0 {
+            Some(TokenBucket::new(config.client_rate_limit, config.client_rate_limit, clock::now()))
+        } else {
+            None
+        };
+        return Some(ClientLimits::new(bucket, config.client_max_inflight, config.client_limit_action));
+    }
+
+    fn new(bucket: Option<TokenBucket>, max_inflight: usize, action: LimitAction) -> ClientLimits {
+        ClientLimits {
+            bucket: bucket,
+            max_inflight: max_inflight,
+            action: action,
+            in_flight: 0,
+            waiting_for_response: false,
+            wake_at: None,
+        }
+    }
+
+    // Decides what to do with the client's next request.
+    pub fn admit(&mut self, now: Instant) -> Admission {
+        if self.max_inflight > 0 && self.in_flight >= self.max_inflight {
+            if self.action == LimitAction::Delay {
+                self.waiting_for_response = true;
+                return Admission::Wait(None);
+            }
+            return Admission::Reject(RATE_LIMITED);
+        }
+        if let Some(ref mut bucket) = self.bucket {
+            if !bucket.take(now) {
+                if self.action == LimitAction::Reject {
+                    return Admission::Reject(RATE_LIMITED);
+                }
+                if let Some(wake_at) = self.wake_at {
+                    if wake_at > now {
+                        return Admission::Wait(None);
+                    }
+                }
+                let wake_at = now + bucket.wait_time(now);
+                self.wake_at = Some(wake_at);
+                return Admission::Wait(Some(wake_at));
+            }
+        }
+        self.wake_at = None;
+        return Admission::Admit;
+    }
+
+    // Counts a request that was read, whether it was admitted or rejected, since both get a response.
+    pub fn on_request(&mut self) {
+        self.in_flight += 1;
+    }
+
+    // Counts a response written to the client. Returns whether the client should be read again, having waited for it.
+    pub fn on_response(&mut self) -> bool {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if self.waiting_for_response && self.in_flight < self.max_inflight {
+            self.waiting_for_response = false;
+            return true;
+        }
+        return false;
+    }
+}
+
+struct KeyRateLimit {
+    pattern: Vec<u8>,
+    // Upper-case command names. Empty matches every command.
+    commands: Vec<String>,
+    writes_only: bool,
+    bucket: TokenBucket,
+}
+
+impl KeyRateLimit {
+    fn new(config: &KeyRateLimitConfig, now: Instant) -> KeyRateLimit {
+        let burst = if config.burst > 0 { config.burst } else { config.rate };
+        KeyRateLimit {
+            pattern: config.pattern.clone().into_bytes(),
+            commands: config.commands.iter().map(|command| command.to_ascii_uppercase()).collect(),
+            writes_only: config.writes_only,
+            bucket: TokenBucket::new(config.rate, burst, now),
+        }
+    }
+
+    fn matches(&self, command: &str, key: &[u8]) -> bool {
+        if self.writes_only && is_read_only(command) {
+            return false;
+        }
+        if !self.commands.is_empty() && !self.commands.iter().any(|name| name == command) {
+            return false;
+        }
+        return glob_match(&self.pattern, key);
+    }
+}
+
+/*
+    The pool's key_rate_limits, which contain abusive access patterns, e.g. a job hammering the same few counters, at
+    the proxy rather than on the shard that owns the keys. Each rule has its own token bucket, shared by every client
+    of the pool.
+*/
+pub struct KeyRateLimits {
+    rules: Vec<KeyRateLimit>,
+}
+
+impl KeyRateLimits {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<KeyRateLimits> {
+        if config.key_rate_limits.is_empty() {
+            return None;
+        }
+        let now = clock::now();
+        return Some(KeyRateLimits {
+            rules: config.key_rate_limits.iter().map(|rule| KeyRateLimit::new(rule, now)).collect(),
+        });
+    }
+
+    // Whether a request to a key is within the first rule that matches it. Requests that match no rule always are.
+    pub fn allow(&mut self, command: &str, key: &[u8], now: Instant) -> bool {
+        match self.rules.iter_mut().find(|rule| rule.matches(command, key)) {
+            Some(rule) => rule.bucket.take(now),
+            None => true,
+        }
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10, 2, now);
+    assert!(bucket.take(now));
+    assert!(bucket.take(now));
+    assert!(!bucket.take(now));
+    assert_eq!(bucket.wait_time(now), Duration::from_millis(100));
+    assert!(!bucket.take(now + Duration::from_millis(50)));
+    assert!(bucket.take(now + Duration::from_millis(100)));
+    // Refills up to the burst only.
+    let later = now + Duration::from_secs(10);
+    assert!(bucket.take(later));
+    assert!(bucket.take(later));
+    assert!(!bucket.take(later));
+}
+
+#[test]
+fn test_client_limits() {
+    let now = Instant::now();
+    let mut limits = ClientLimits::new(None, 2, LimitAction::Reject);
+    for _ in 0..2 {
+        assert_eq!(limits.admit(now), Admission::Admit);
+        limits.on_request();
+    }
+    assert_eq!(limits.admit(now), Admission::Reject(RATE_LIMITED));
+    limits.on_request();
+    assert!(!limits.on_response());
+    assert!(!limits.on_response());
+    assert!(!limits.on_response());
+    assert_eq!(limits.admit(now), Admission::Admit);
+
+    let mut limits = ClientLimits::new(None, 1, LimitAction::Delay);
+    assert_eq!(limits.admit(now), Admission::Admit);
+    limits.on_request();
+    assert_eq!(limits.admit(now), Admission::Wait(None));
+    assert!(limits.on_response());
+    assert_eq!(limits.admit(now), Admission::Admit);
+
+    // A client waiting for a token is woken up once, when the token is earned.
+    let mut limits = ClientLimits::new(Some(TokenBucket::new(10, 1, now)), 0, LimitAction::Delay);
+    assert_eq!(limits.admit(now), Admission::Admit);
+    let wake_at = now + Duration::from_millis(100);
+    assert_eq!(limits.admit(now), Admission::Wait(Some(wake_at)));
+    assert_eq!(limits.admit(now + Duration::from_millis(10)), Admission::Wait(None));
+    assert_eq!(limits.admit(wake_at), Admission::Admit);
+}
+
+#[test]
+fn test_key_rate_limits() {
+    let now = Instant::now();
+    let rule = |pattern: &str, rate: usize, commands: Vec<String>, writes_only: bool| KeyRateLimit::new(&KeyRateLimitConfig {
+        pattern: pattern.to_owned(),
+        rate: rate,
+        burst: 0,
+        commands: commands,
+        writes_only: writes_only,
+    }, now);
+    let mut limits = KeyRateLimits {
+        rules: vec![
+            rule("counter:*", 1, Vec::new(), true),
+            rule("user:?", 2, vec!["hget".to_owned()], false),
+        ],
+    };
+    assert!(limits.allow("INCR", b"counter:1", now));
+    assert!(!limits.allow("INCR", b"counter:2", now));
+    assert!(limits.allow("GET", b"counter:1", now));
+    assert!(limits.allow("INCR", b"other", now));
+    assert!(limits.allow("HGET", b"user:1", now));
+    assert!(limits.allow("HGET", b"user:2", now));
+    assert!(!limits.allow("HGET", b"user:3", now));
+    assert!(limits.allow("HSET", b"user:3", now));
+    assert!(limits.allow("INCR", b"counter:1", now + Duration::from_secs(1)));
+}
@@ -0,0 +1,82 @@
+use config::BackendPoolConfig;
+
+use hashbrown::HashMap;
+use std::time::{Duration, Instant};
+
+// Keys a client's recent writes are tracked for. Past this, all of its reads go to the primary until they expire.
+const MAX_TRACKED_KEYS: usize = 1024;
+
+/*
+    Read-your-writes consistency for pools with read_from_replicas. Replicas lag behind their primary, so a client
+    that reads a key from one right after writing it may get the old value. Each client tracks the keys it wrote in
+    the last read_your_writes milliseconds, and its reads of those keys go to the primary instead.
+*/
+pub struct RecentWrites {
+    window: Duration,
+    // Keys written, with when their window ends.
+    keys: HashMap<Vec<u8>, Instant>,
+    // Set when the client wrote more keys than are tracked. Every read goes to the primary until then.
+    all_until: Option<Instant>,
+}
+
+impl RecentWrites {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<RecentWrites> {
+        if !config.read_from_replicas || config.read_your_writes == 0 {
+            return None;
+        }
+        return Some(RecentWrites::new(Duration::from_millis(config.read_your_writes as u64)));
+    }
+
+    pub fn new(window: Duration) -> RecentWrites {
+        return RecentWrites {
+            window: window,
+            keys: HashMap::new(),
+            all_until: None,
+        };
+    }
+
+    pub fn record(&mut self, key: &[u8], now: Instant) {
+        let until = now + self.window;
+        if self.keys.len() >= MAX_TRACKED_KEYS && !self.keys.contains_key(key) {
+            self.keys.retain(|_, until| *until > now);
+            if self.keys.len() >= MAX_TRACKED_KEYS {
+                self.all_until = Some(until);
+                return;
+            }
+        }
+        self.keys.insert(key.to_vec(), until);
+    }
+
+    // Whether a read of the key has to go to the primary, to see the client's own write.
+    pub fn is_recent(&self, key: &[u8], now: Instant) -> bool {
+        if self.all_until.map_or(false, |until| now < until) {
+            return true;
+        }
+        return self.keys.get(key).map_or(false, |until| now < *until);
+    }
+}
+
+#[test]
+fn test_recent_writes() {
+    let start = Instant::now();
+    let mut recent_writes = RecentWrites::new(Duration::from_millis(100));
+    recent_writes.record(b"a", start);
+    assert!(recent_writes.is_recent(b"a", start + Duration::from_millis(99)));
+    assert!(!recent_writes.is_recent(b"a", start + Duration::from_millis(100)));
+    assert!(!recent_writes.is_recent(b"b", start));
+
+    // Writing the key again restarts its window.
+    recent_writes.record(b"a", start + Duration::from_millis(50));
+    assert!(recent_writes.is_recent(b"a", start + Duration::from_millis(120)));
+
+    // Past the tracked keys, every read goes to the primary for the window.
+    for index in 0..MAX_TRACKED_KEYS + 1 {
+        recent_writes.record(index.to_string().as_bytes(), start + Duration::from_millis(60));
+    }
+    assert!(recent_writes.is_recent(b"untracked", start + Duration::from_millis(150)));
+    assert!(!recent_writes.is_recent(b"untracked", start + Duration::from_millis(160)));
+    // Expired keys make room again.
+    recent_writes.record(b"c", start + Duration::from_millis(200));
+    assert!(recent_writes.is_recent(b"c", start + Duration::from_millis(250)));
+    assert!(!recent_writes.is_recent(b"untracked", start + Duration::from_millis(250)));
+}
@@ -1,5 +1,6 @@
 use client::BufferedClient;
-use std::collections::VecDeque;
+use std::collections::{VecDeque, BTreeMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fmt;
 use std::error;
 use std::net::SocketAddr;
@@ -7,17 +8,55 @@ use backend::SingleBackend;
 use backendpool::handle_timeout;
 use backendpool::handle_client_readable;
 use config::BackendConfig;
-use backend::Backend;
+use backend::{Backend, BackendEnum, QueueLimits, handle_write_to_client};
+use concurrency::ConcurrencyLimiter;
 use admin;
-use config::{RedFlareProxyConfig, BackendPoolConfig, load_config};
+use admin::AdminResponse;
+use bench::{Bench, BenchConfig};
+use config::{RedFlareProxyConfig, BackendPoolConfig, PollStrategy, load_config, write_config, serialize_config};
 use backendpool;
 use backendpool::BackendPool;
 use mio::*;
-use mio::unix::{UnixReady};
+use log::LogLevel;
 use std::mem;
 use std::cell::{RefCell};
 use std::rc::Rc;
-use stats::Stats;
+use snapshot::StatsSnapshotter;
+use trace::{RequestTrace, TraceExporter};
+use logging::{self, Logging};
+use stats::{json_object, Stats, LatencyHistogram, CommandStats, ConnectionStats, ErrorStats, MemoryStats, merge_command_stats, max_value_sizes, format_value_size_metrics};
+use retry::RetryPolicy;
+use slowlog::SlowLog;
+use audit::AuditLog;
+use capture::Capture;
+use clock;
+use filter::{self, Filter, FilterChain, SharedFilters};
+use backendinfo::{self, BackendInfo};
+use sentinel;
+use happyeyeballs;
+use outagebuffer::OutageBuffer;
+use transparent::TransparentListener;
+use discovery;
+use standby::FailoverMode;
+use version;
+use bufferpool;
+use memory;
+use memory::MemoryUsage;
+use watchdog::{Phase, Watchdog};
+use overload::AcceptThrottle;
+use registry::Registry;
+use embed::{ControlReceiver, ControlRequest};
+use worker;
+use worker::WorkerContext;
+use timerwheel::TimerWheel;
+use signals;
+use handoff;
+use platform;
+use systemd::Notifier;
+use tokens;
+use tokens::{SharedTokenSlab, TokenKind, TokenSlab};
+use std::os::unix::io::AsRawFd;
+use std::process::Child;
 
 use hashbrown::HashMap;
 
@@ -25,24 +64,20 @@ use hashbrown::HashMap;
 use backend::parse_redis_command;
 use toml;
 
-// Reserved Token space.
-pub const NULL_TOKEN: Token = Token(0);
-pub const ADMIN_LISTENER: Token = Token(1);
+// Tokens with a single instance per worker. Every other token is made with tokens::token. See TokenKind.
+pub const NULL_TOKEN: Token = Token(TokenKind::Null as usize);
+pub const ADMIN_LISTENER: Token = Token(TokenKind::AdminListener as usize);
+pub const SHUTDOWN_SIGNAL: Token = Token(TokenKind::ShutdownSignal as usize);
+// Wakes up the event loop of an embedded proxy for its control requests. See embed::ControlReceiver.
+pub const CONTROL: Token = Token(1 << tokens::KIND_BITS | TokenKind::ShutdownSignal as usize);
 
-// Pool Listeners
-pub const FIRST_SOCKET_INDEX: usize = 10;
+// Number of recent request traces kept for DEBUG TIMING.
+const RECENT_TRACES: usize = 100;
+// Milliseconds between sweeps for idle clients and stale backend connects.
+const SWEEP_INTERVAL_MS: u64 = 1000;
 
-// Backend conns
-// from FIRST_SOCKET_INDEX + backendpools.len
-
-// Backend conns timeouts
-
-// backend conns retry?
-
-// Client conns.
-
-pub const FIRST_CLUSTER_BACKEND_INDEX: usize = 1000000000;
-// Cluster clients... start from reverse to end?
+// Admin commands that act on the pools and backends of the worker serving the admin port alone.
+const WORKER_LOCAL_COMMANDS: [&str; 7] = ["CACHE", "CAPTURE", "DEBUG", "HOTKEYS", "LOGLEVEL", "POOLSTATS", "SLOWLOG"];
 
 pub type BackendToken = Token;
 pub type PoolToken = Token;
@@ -54,34 +89,66 @@ pub type PoolIndex = usize;
 pub type PoolTokenValue = usize;
 pub type BackendIndex = usize;
 pub type BackendTokenValue = usize;
-pub type TimeoutTokenValue = usize;
-pub type RequestTimeoutTokenValue = usize;
 pub type ClusterTokenValue = usize;
 
-#[derive(Clone, Copy, Debug)]
-enum SubType {
-    Timeout,
-    RequestTimeout,
-    PoolServer,
-    PoolListener,
-    PoolClient,
-    ClusterServer,
-    AdminListener,
-    AdminClient,
+// Deadlines of backends, keyed by the backend's token. Cluster backends use their cluster token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerEvent {
+    // Reconnect to a failed backend.
+    Retry(BackendToken),
+    // The oldest request in the backend's queue may have timed out.
+    RequestTimeout(BackendToken),
+    // Close idle and dead connections of the worker. See sweep_connections.
+    Sweep,
+    /*
+        Send a client's read, with its request id, to a replica of the backend it was sent to, unless it has been
+        answered already. See hedge_delay.
+    */
+    Hedge(ClientToken, (Instant, usize), BackendToken),
+    // Read a client again, once it has earned a token under client_rate_limit.
+    ReadClient(ClientToken),
+    // Write the requests that chaos mode held back from the backend. See chaos.rs.
+    Flush(BackendToken),
+    // Answer a client's request, received at the given time, whose response chaos mode dropped, as timed out.
+    DroppedResponse(ClientToken, (Instant, usize)),
+    // Start connecting to the next address of the backend's hostname too. See happyeyeballs.rs.
+    ConnectAttempt(BackendToken),
+    // The oldest request that the backend holds while it reconnects may have expired. See outagebuffer.rs.
+    OutageBuffer(BackendToken),
 }
 
+// Every pending deadline of a worker's event loop, shared with its backends like the Poll.
+pub type Timers = Rc<RefCell<TimerWheel<TimerEvent>>>;
+
 #[derive(Debug)]
 pub enum ProxyError {
     InvalidLogLevel(String),
+    InvalidLogFormat(String),
     InvalidParams(log4rs::config::Errors),
     LogFileFailure(String, std::io::Error),
+    InvalidLogRotation(String),
+    InvalidSyslogFacility(String),
+    InvalidSyslogSeverity(String),
+    SyslogFailure(String, std::io::Error),
     SetLoggerError(log::SetLoggerError),
 
     ConfigFileFailure(String, std::io::Error),
     ConfigFileFormatFailure(String, std::io::Error), // probably because not UTF8
     ParseConfigFailure(String, toml::de::Error),
+    SerializeConfigFailure(toml::ser::Error),
+    ConfigWriteFailure(String, std::io::Error),
+    ProfilesNotRewritable(String),
+
+    DaemonizeFailure(daemonize::DaemonizeError),
+    PidFileFailure(String, std::io::Error),
+    StdioRedirectFailure(String, std::io::Error),
+    SignalHandlerFailure(std::io::Error),
+    CpuAffinityFailure(usize, std::io::Error),
 
     InitPollFailure(std::io::Error),
+    AdminParseFailure(String, std::net::AddrParseError),
+    AdminBindSocketFailure(SocketAddr, std::io::Error),
+    AdminPollFailure(std::io::Error),
     PoolBindSocketFailure(SocketAddr, std::io::Error),
     PoolPollFailure(std::io::Error),
 
@@ -89,24 +156,55 @@ pub enum ProxyError {
     SameConfig,
 
     PollFailure(std::io::Error),
+
+    EmbeddedWorkers(usize),
+    EmbeddedThreadFailure(std::io::Error),
+    EmbeddedProxyPanicked,
+    FilterUnknownPool(String),
+    FilterLoadFailure(String, String),
+    SentinelDiscoveryFailure(String, String),
+    HostnameResolutionFailure(String, String),
 }
 
 impl fmt::Display for ProxyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ProxyError::InvalidLogLevel(ref l) => write!(f, "Unrecognized log level: {}. Please use {{DEBUG|INFO|WARNING|ERROR}}.", l),
+            ProxyError::InvalidLogFormat(ref l) => write!(f, "Unrecognized log format: {}. Please use {{TEXT|JSON}}.", l),
             ProxyError::InvalidParams(ref e) => write!(f, "Invalid arguments: {}", e),
             ProxyError::LogFileFailure(ref file, ref e) => write!(f, "Unable to log to file: {}. Received error: {}", file, e),
+            ProxyError::InvalidLogRotation(ref s) => write!(f, "Invalid log rotation: {}", s),
+            ProxyError::InvalidSyslogFacility(ref s) => write!(f, "Unrecognized syslog facility: {}. Please use one of kern, user, mail, daemon, auth, syslog, lpr, news, uucp, cron, authpriv, ftp or local0-local7.", s),
+            ProxyError::InvalidSyslogSeverity(ref s) => write!(f, "Invalid syslog severity mapping: {}. Please use LEVEL=severity, e.g. ERROR=crit.", s),
+            ProxyError::SyslogFailure(ref address, ref e) => write!(f, "Unable to connect to syslog: {}. Received error: {}", address, e),
             ProxyError::SetLoggerError(ref e) => write!(f, "Failed to initialize logger. Received error: {}.", e),
             ProxyError::ConfigFileFailure(ref c, ref e) => write!(f, "Unable to open config file: {}. Received error: {}", c, e),
             ProxyError::ConfigFileFormatFailure(ref c, ref e) => write!(f, "Unable to parse config file: {}. Perhaps it's not UTF8 encoded. Received error: {}", c, e),
             ProxyError::ParseConfigFailure(ref c, ref e) => write!(f, "Unable to parse config file: {} into appropriate types. Received error: {}", c, e),
+            ProxyError::SerializeConfigFailure(ref e) => write!(f, "Unable to serialize config. Received error: {}", e),
+            ProxyError::ConfigWriteFailure(ref c, ref e) => write!(f, "Unable to write config file: {}. Received error: {}", c, e),
+            ProxyError::ProfilesNotRewritable(ref c) => write!(f, "Unable to rewrite config file: {}. It defines profiles, which would be lost. Please edit it by hand.", c),
+            ProxyError::DaemonizeFailure(ref e) => write!(f, "Unable to daemonize. Received error: {}", e),
+            ProxyError::PidFileFailure(ref file, ref e) => write!(f, "Unable to write pid file: {}. Received error: {}", file, e),
+            ProxyError::StdioRedirectFailure(ref file, ref e) => write!(f, "Unable to redirect output to file: {}. Received error: {}", file, e),
+            ProxyError::SignalHandlerFailure(ref e) => write!(f, "Unable to install signal handlers. Received error: {}", e),
+            ProxyError::CpuAffinityFailure(cpu, ref e) => write!(f, "Unable to pin worker to CPU {}. Received error: {}", cpu, e),
             ProxyError::InitPollFailure(ref e) => write!(f, "Unable to initialize event poll. Received error: {}", e),
+            ProxyError::AdminParseFailure(ref listen, ref e) => write!(f, "Unable to parse the admin listen address: {}. Received error: {}", listen, e),
+            ProxyError::AdminBindSocketFailure(ref addr, ref e) => write!(f, "Unable to bind to admin listening socket: {}. Received error: {}", addr, e),
+            ProxyError::AdminPollFailure(ref e) => write!(f, "Unable to register admin port to event poll. Received error: {}", e),
             ProxyError::PoolBindSocketFailure(ref addr, ref e) => write!(f, "Unable to bind to pool listening socket: {}. Received error: {}", addr, e),
             ProxyError::PoolPollFailure(ref e) => write!(f, "Unable to register backend pool to event poll. Received error: {}", e),
             ProxyError::UnavailableConfig => write!(f, "No staged config. Please load a config first."),
             ProxyError::SameConfig => write!(f, "The loaded and staged configs are identical."),
             ProxyError::PollFailure(ref e) => write!(f, "Unable to poll the event poll. Received error: {}", e),
+            ProxyError::EmbeddedWorkers(workers) => write!(f, "Embedded proxies run a single worker, but {} workers are configured.", workers),
+            ProxyError::EmbeddedThreadFailure(ref e) => write!(f, "Unable to start the embedded proxy's thread. Received error: {}", e),
+            ProxyError::EmbeddedProxyPanicked => write!(f, "The embedded proxy's thread panicked."),
+            ProxyError::FilterUnknownPool(ref pool) => write!(f, "Unable to add a filter to unknown pool: {}", pool),
+            ProxyError::FilterLoadFailure(ref pool, ref e) => write!(f, "Unable to load the filters of pool {}. Received error: {}", pool, e),
+            ProxyError::SentinelDiscoveryFailure(ref master, ref e) => write!(f, "Unable to discover master {} through sentinel. Received error: {}", master, e),
+            ProxyError::HostnameResolutionFailure(ref hostname, ref e) => write!(f, "Unable to resolve backend hostname {}. Received error: {}", hostname, e),
         }
     }
 }
@@ -115,30 +213,83 @@ impl error::Error for ProxyError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             ProxyError::InvalidLogLevel(_) => None,
+            ProxyError::InvalidLogFormat(_) => None,
             ProxyError::InvalidParams(ref e) => Some(e),
             ProxyError::LogFileFailure(_, ref e) => Some(e),
+            ProxyError::InvalidLogRotation(_) => None,
+            ProxyError::InvalidSyslogFacility(_) => None,
+            ProxyError::InvalidSyslogSeverity(_) => None,
+            ProxyError::SyslogFailure(_, ref e) => Some(e),
             ProxyError::SetLoggerError(ref e) => Some(e),
             ProxyError::ConfigFileFailure(_, ref e) => Some(e),
             ProxyError::ConfigFileFormatFailure(_, ref e) => Some(e),
             ProxyError::ParseConfigFailure(_, ref e) => Some(e),
+            ProxyError::SerializeConfigFailure(ref e) => Some(e),
+            ProxyError::ConfigWriteFailure(_, ref e) => Some(e),
+            ProxyError::ProfilesNotRewritable(_) => None,
+            ProxyError::DaemonizeFailure(ref e) => Some(e),
+            ProxyError::PidFileFailure(_, ref e) => Some(e),
+            ProxyError::StdioRedirectFailure(_, ref e) => Some(e),
+            ProxyError::SignalHandlerFailure(ref e) => Some(e),
+            ProxyError::CpuAffinityFailure(_, ref e) => Some(e),
             ProxyError::InitPollFailure(ref e) => Some(e),
+            ProxyError::AdminParseFailure(_, ref e) => Some(e),
+            ProxyError::AdminBindSocketFailure(_, ref e) => Some(e),
+            ProxyError::AdminPollFailure(ref e) => Some(e),
             ProxyError::PoolBindSocketFailure(_, ref e) => Some(e),
             ProxyError::PoolPollFailure(ref e) => Some(e),
             ProxyError::UnavailableConfig => None,
             ProxyError::SameConfig => None,
             ProxyError::PollFailure(ref e) => Some(e),
+            ProxyError::EmbeddedWorkers(_) => None,
+            ProxyError::EmbeddedThreadFailure(ref e) => Some(e),
+            ProxyError::EmbeddedProxyPanicked => None,
+            ProxyError::FilterUnknownPool(_) => None,
+            ProxyError::FilterLoadFailure(_, _) => None,
+            ProxyError::SentinelDiscoveryFailure(_, _) => None,
+            ProxyError::HostnameResolutionFailure(_, _) => None,
+        }
+    }
+}
+
+// What the event loop carries from one iteration to the next. See RedFlareProxy::run_iteration.
+pub struct EventLoopState {
+    events: Events,
+    // Events polled but not handled yet, when more arrive at once than max_events_per_iteration.
+    pending_events: VecDeque<Event>,
+    /*
+        A running collection of clients that should be checked if they have any pending requests. This is basically
+        a way to manually trigger a readable event for a client. This is used when a multikey command is completed.
+    */
+    completed_clients: VecDeque<ClientTokenValue>,
+    new_completed_clients: VecDeque<ClientTokenValue>,
+    expired_timers: Vec<TimerEvent>,
+}
+
+impl EventLoopState {
+    pub fn new(event_capacity: usize) -> EventLoopState {
+        EventLoopState {
+            events: Events::with_capacity(event_capacity),
+            pending_events: VecDeque::new(),
+            completed_clients: VecDeque::with_capacity(1024),
+            new_completed_clients: VecDeque::with_capacity(1024),
+            expired_timers: Vec::new(),
         }
     }
 }
 
 // High-level struct that contains everything for a redflareproxy instance.
 pub struct RedFlareProxy {
-    // This may just get integrated back into RedFlareProxy.
-    admin: admin::AdminPort,
+    // This may just get integrated back into RedFlareProxy. Only worker 0 has an admin port.
+    admin: Option<admin::AdminPort>,
 
     // Configs
     config: RedFlareProxyConfig,
     staged_config: Option<RedFlareProxyConfig>,
+    // Path the config was loaded from at startup, which REWRITECONFIG writes to.
+    config_path: String,
+    // Profile selected at startup. It is also applied to configs loaded with LOADCONFIG.
+    profile: Option<String>,
 
     // Child structs.
     backendpools: Vec<BackendPool>,
@@ -149,22 +300,76 @@ pub struct RedFlareProxy {
     clients: HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)>,
 
     stats: Stats,
+    // Writes periodic stats snapshots, if stats_snapshot_file is configured.
+    snapshotter: Option<StatsSnapshotter>,
+    // Exports sampled request traces, if otlp_endpoint is configured.
+    trace_exporter: Option<TraceExporter>,
+    // The most recently completed request traces, oldest first.
+    recent_traces: VecDeque<RequestTrace>,
+    // The installed logger, for changing component log levels. None if the logger wasn't set up by redflareproxy.
+    logging: Option<Logging>,
 
-    // Registry...
-    poll: Rc<RefCell<Poll>>,
-    next_client_token_value: ClientTokenValue,
-    running: bool,
+    // The worker's Poll, which every socket is registered with. See registry.rs.
+    registry: Registry,
+    // Request timeouts and reconnects of every backend.
+    timers: Timers,
+    // Tokens of client connections, reused once a client disconnects.
+    client_tokens: SharedTokenSlab,
+    // Set when clients were read from with batch_dispatch, so their requests are waiting to be written to backends.
+    dispatch_pending: bool,
+    // This worker's share of the memory_limit budget.
+    memory: MemoryUsage,
+    // Set once shutdown begins. The event loop runs until in-flight requests are answered, or until this passes.
+    shutdown_deadline: Option<Instant>,
+    // The new process started by a hot restart, until it takes over or exits.
+    restart_child: Option<Child>,
+    // For the uptime reported by INFO.
+    start_time: Instant,
+    worker_id: usize,
+    // Reports iterations of the event loop that stall, unless the watchdog_threshold is 0.
+    watchdog: Option<Watchdog>,
+    // Stops accepting clients on the pool ports while the worker is overloaded, with overload_latency or
+    // overload_queue_depth.
+    accept_throttle: Option<AcceptThrottle>,
+    // Requests from the handle of an embedded proxy. None when run by the redflareproxy binary.
+    control: Option<ControlReceiver>,
+    // Readiness and watchdog notifications, when run as a systemd service. See systemd.rs.
+    systemd: Option<Notifier>,
+    // Filters of each pool, by pool name. Those added by the embedder are kept across config switches.
+    filters: BTreeMap<String, SharedFilters>,
+    // The running or last benchmark started with BENCH.
+    bench: Option<Bench>,
+    // Accepts the connections redirected to the proxy, with [transparent]. See transparent.rs.
+    transparent: Option<TransparentListener>,
 }
 impl RedFlareProxy {
-    pub fn new(config_path: String) -> Result<RedFlareProxy, ProxyError> {
-        let config = try!(load_config(config_path));
-        let poll = match Poll::new() {
-            Ok(poll) => Rc::new(RefCell::new(poll)),
+    /*
+        Creates the proxy and binds its ports. worker is None when the event loop runs on a single thread. Otherwise,
+        only worker 0 binds the admin port and writes stats snapshots.
+    */
+    pub fn new(config: RedFlareProxyConfig, config_path: String, profile: Option<String>, logging: Option<Logging>, worker: Option<WorkerContext>) -> Result<RedFlareProxy, ProxyError> {
+        // Before anything else is allocated, so that the worker's memory is local to its CPU.
+        try!(worker::pin_to_cpu(&config, worker.as_ref().map_or(0, |worker| worker.id)));
+        let registry = match Registry::new() {
+            Ok(registry) => registry,
             Err(err) => {
                 return Err(ProxyError::InitPollFailure(err));
             }
         };
-        let admin = admin::AdminPort::new(config.admin.clone(), &poll.borrow());
+        if let Err(err) = signals::register(&registry, SHUTDOWN_SIGNAL) {
+            return Err(ProxyError::InitPollFailure(err));
+        }
+        let (worker_id, stats) = match worker {
+            Some(worker) => (worker.id, Stats::for_worker(worker.id, worker.shards)),
+            None => (0, Stats::new()),
+        };
+        bufferpool::configure(config.buffer_size, config.buffer_pool_size);
+        memory::set_limit(config.memory_limit);
+        let admin = if worker_id == 0 {
+            Some(try!(admin::AdminPort::new(config.admin.clone(), &registry)))
+        } else {
+            None
+        };
 
         let num_pools = config.pools.len();
 
@@ -181,15 +386,40 @@ impl RedFlareProxy {
             clients: HashMap::with_capacity(4096),
             config: config,
             staged_config: None,
-            poll: poll,
-            next_client_token_value: FIRST_SOCKET_INDEX + num_pools + 3*num_backends,
-            stats: Stats::new(),
-            running: true,
+            config_path: config_path,
+            profile: profile,
+            registry: registry,
+            timers: Rc::new(RefCell::new(TimerWheel::new(Duration::from_millis(1), clock::now()))),
+            client_tokens: TokenSlab::shared(TokenKind::PoolClient),
+            dispatch_pending: false,
+            memory: MemoryUsage::new(clock::now()),
+            stats: stats,
+            snapshotter: None,
+            trace_exporter: None,
+            recent_traces: VecDeque::with_capacity(RECENT_TRACES),
+            logging: logging,
+            shutdown_deadline: None,
+            restart_child: None,
+            start_time: Instant::now(),
+            worker_id: worker_id,
+            watchdog: None,
+            accept_throttle: None,
+            control: None,
+            systemd: None,
+            filters: BTreeMap::new(),
+            bench: None,
+            transparent: None,
         };
+        if worker_id == 0 {
+            redflareproxy.snapshotter = StatsSnapshotter::from_config(&redflareproxy.config, clock::now());
+        }
+        redflareproxy.trace_exporter = TraceExporter::from_config(&redflareproxy.config);
+        redflareproxy.watchdog = Watchdog::from_config(&redflareproxy.config, worker_id);
+        redflareproxy.accept_throttle = AcceptThrottle::from_config(&redflareproxy.config, clock::now());
         // Populate backend pools.
         let pools_config = redflareproxy.config.pools.clone();
-        let mut next_backend_token_value = FIRST_SOCKET_INDEX + num_pools;
-        let mut pool_token_value = FIRST_SOCKET_INDEX;
+        let mut next_backend_index = 0;
+        let mut pool_index = 0;
         for (pool_name, pool_config) in pools_config {
             try!(init_backend_pool(
                 &mut redflareproxy.backendpools,
@@ -198,13 +428,28 @@ impl RedFlareProxy {
                 &pool_config,
                 redflareproxy.config.enable_advanced_commands,
                 &mut redflareproxy.cluster_backends,
-                &mut next_backend_token_value,
-                pool_token_value,
-                &mut redflareproxy.poll,
+                &mut next_backend_index,
+                pool_index,
+                &redflareproxy.registry,
+                &redflareproxy.timers,
                 num_backends,
+                redflareproxy.config.workers > 1,
             ));
-            pool_token_value += 1;
+            pool_index += 1;
+        }
+        if let Some(transparent_config) = redflareproxy.config.transparent.clone() {
+            let token = tokens::token(TokenKind::TransparentListener, 0);
+            let transparent = match TransparentListener::bind(&transparent_config, redflareproxy.config.workers > 1, &redflareproxy.registry, token) {
+                Ok(transparent) => transparent,
+                Err(err) => return Err(ProxyError::PoolBindSocketFailure(transparent_config.listen, err)),
+            };
+            redflareproxy.transparent = Some(transparent);
         }
+        redflareproxy.set_audit_log();
+        redflareproxy.resolve_standby_pools();
+        let configured_filters = try!(load_configured_filters(&redflareproxy.config));
+        redflareproxy.set_configured_filters(configured_filters);
+        redflareproxy.timers.borrow_mut().insert(clock::now() + Duration::from_millis(SWEEP_INTERVAL_MS), TimerEvent::Sweep);
         debug!("Initialized redflareproxy");
 
         Ok(redflareproxy)
@@ -225,30 +470,49 @@ impl RedFlareProxy {
                 None => {}
             }
         }
+        // Loaded before anything is switched, so that a script that fails to load keeps the current config.
+        let configured_filters = match self.staged_config {
+            Some(ref staged_config) => try!(load_configured_filters(staged_config)),
+            None => BTreeMap::new(),
+        };
         let staged_config = mem::replace(&mut self.staged_config, None);
-        self.config = staged_config.unwrap();
+        let previous_log_levels = mem::replace(&mut self.config, staged_config.unwrap()).log_levels;
+        self.snapshotter = StatsSnapshotter::from_config(&self.config, clock::now());
+        self.trace_exporter = TraceExporter::from_config(&self.config);
+        self.watchdog = Watchdog::from_config(&self.config, self.worker_id);
+        let accepts_paused = self.accepts_paused();
+        self.accept_throttle = AcceptThrottle::from_config(&self.config, clock::now());
+        // Buffers of the previous size are deallocated as their connections close.
+        bufferpool::configure(self.config.buffer_size, self.config.buffer_pool_size);
+        memory::set_limit(self.config.memory_limit);
+        if self.config.log_levels != previous_log_levels {
+            self.apply_log_levels();
+        }
 
-        // Replace admin.
-        if self.config.admin != self.admin.config {
-            let admin = admin::AdminPort::new(self.config.admin.clone(), &self.poll.borrow());
-            self.admin = admin; // TODO: what to do with old admin?
+        // Switch the admin port, keeping its clients.
+        if let Some(ref mut admin) = self.admin {
+            if self.config.admin != admin.config {
+                if let Err(err) = admin.switch_config(self.config.admin.clone(), &self.registry) {
+                    error!(target: admin::LOG_TARGET, "Keeping the previous admin port. {}", err);
+                }
+            }
         }
 
+        // Clients keep their tokens, and move to the pool that now has their listen address.
         let mut existing_clients: HashMap<SocketAddr, Vec<BufferedClient>> = HashMap::new();
+        let mut orphaned_clients = Vec::new();
         for (_client_token_value, (client, pool_token_value)) in self.clients.drain() {
-            // check listen socket of pool_token_value.
-            let pool_index = pool_token_value - FIRST_SOCKET_INDEX;
-            let listen_socket = self.backendpools.get_mut(pool_index).unwrap().config.listen.clone();
-            if existing_clients.contains_key(&listen_socket) {
-                existing_clients.get_mut(&listen_socket).unwrap().push(client);
-            } else {
-                let mut clients = Vec::new();
-                clients.push(client);
-                existing_clients.insert(listen_socket, clients);
-            }
+            let listen_socket = match self.backendpools.get(convert_token_to_pool_index(pool_token_value)) {
+                Some(pool) => pool.config.listen,
+                None => {
+                    orphaned_clients.push(client);
+                    continue;
+                }
+            };
+            existing_clients.entry(listen_socket).or_insert_with(Vec::new).push(client);
         }
 
-            let (new_backends, new_clients) = {
+            let (new_backends, new_clients, expired_backends) = {
                     let mut expired_pools = Vec::new();
                     let mut remaining_pools = HashMap::new();
                     let mut expired_backends: Vec<Backend> = Vec::new();
@@ -265,23 +529,23 @@ impl RedFlareProxy {
                                         }
                                     }
                                 }
+                                let num_backends = pool.num_backends;
                                 if !should_keep {
-                                    let num_backends = pool.num_backends;
                                     expired_pools.push(pool);
-                                    for _ in 0..num_backends {
-                                        expired_backends.push(backends_iter.next().unwrap());
-                                    }
+                                    expired_backends.extend(backends_iter.by_ref().take(num_backends));
                                 } else {
-                                    let num_backends = pool.num_backends;
                                     let first_backend_index = pool.first_backend_index;
                                     remaining_pools.insert(pool.config.clone(), pool);
                                     for i in 0..num_backends {
-                                        remaining_backends.insert(first_backend_index + i, backends_iter.next().unwrap());
+                                        if let Some(backend) = backends_iter.next() {
+                                            remaining_backends.insert(first_backend_index + i, backend);
+                                        }
                                     }
                                 }
                     }
-                    for _pool in expired_pools {
-                        // dont need to clean up anything, i believe.
+                    // Closed before the new pools are set up, so that a new pool can bind the same address.
+                    for pool in expired_pools {
+                        log_event!(LogLevel::Info, "pool_removed", { pool: pool.name }, "Removed pool {}", pool.name);
                     }
 
                     // now, try to remake.
@@ -293,44 +557,43 @@ impl RedFlareProxy {
                 }
                 let mut new_backends = Vec::with_capacity(num_backends);
                 let mut new_clients: HashMap<ClientTokenValue, (BufferedClient, PoolTokenValue)> = HashMap::new();
-                let mut new_cluster_backends: Vec<(SingleBackend, BackendTokenValue)> = Vec::new();
-                // TODO: Implement cluster switching.
 
                 let pools_config = self.config.pools.clone();
-                let mut pool_token_value = FIRST_SOCKET_INDEX;
-                let mut next_backend_token_value = FIRST_SOCKET_INDEX + num_pools;
-                let mut next_client_token_value = FIRST_SOCKET_INDEX + num_pools + 3*num_backends;
+                let mut pool_index = 0;
+                let mut next_backend_index = 0;
                 for (pool_name, pool_config) in pools_config {
+                    let pool_token = tokens::token(TokenKind::PoolListener, pool_index);
                     // check if pool_config exists in remaining_pools. if it does, reregister it to the correct token.
                     match remaining_pools.remove(&pool_config) {
                         Some(mut pool) => {
                             // regregister pool token.
-                            pool.token = Token(pool_token_value);
-                            match pool.listen_socket {
-                                Some(ref s) => {
-                                    let _ = self.poll.borrow_mut().reregister(s, Token(pool_token_value), Ready::readable(), PollOpt::edge()).unwrap();
+                            pool.token = pool_token;
+                            if let Some(ref mut s) = pool.listen_socket {
+                                if let Err(err) = s.set_token(pool_token) {
+                                    error!("Unable to reregister the listener of pool {}. Received error: {}", pool_name, err);
                                 }
-                                None => {}
                             }
                             // rename to the right name.
                             pool.name = pool_name.clone();
 
-                            // move the existing backends.
+                            // move the existing backends, and reregister them to their new index.
                             let num_backends = pool.num_backends;
                             let first_backend_index = pool.first_backend_index;
-                            for i in (first_backend_index..first_backend_index+num_backends).rev() {
-                                let mut backend = remaining_backends.remove(&i).unwrap();
+                            pool.first_backend_index = next_backend_index;
+                            for i in first_backend_index..first_backend_index+num_backends {
+                                let mut backend = match remaining_backends.remove(&i) {
+                                    Some(backend) => backend,
+                                    None => continue,
+                                };
                                 // TODO: Also change number of backends.
-                                let _ = backend.reregister_token(Token(i), &mut new_cluster_backends, num_backends);
+                                let _ = backend.reregister_token(tokens::token(TokenKind::PoolServer, next_backend_index), &mut self.cluster_backends, num_backends);
 
                                 // also, rename pool token.
-                                backend.change_pool_token(pool_token_value);
+                                backend.change_pool_token(pool_token.0);
                                 new_backends.push(backend);
+                                next_backend_index += 1;
                             }
 
-                            pool.first_backend_index = next_backend_token_value;
-                            next_backend_token_value += num_backends;
-
                             new_backendpools.push(pool);
                         }
                         None => {
@@ -340,74 +603,685 @@ impl RedFlareProxy {
                                 &pool_name,
                                 &pool_config,
                                 self.config.enable_advanced_commands,
-                                &mut new_cluster_backends,
-                                &mut next_backend_token_value,
-                                pool_token_value,
-                                &mut self.poll,
+                                &mut self.cluster_backends,
+                                &mut next_backend_index,
+                                pool_index,
+                                &self.registry,
+                                &self.timers,
                                 num_backends,
+                                self.config.workers > 1,
                             ));
                         }
                     }
-                    match existing_clients.remove(&pool_config.listen) {
-                        Some(mut clients) => {
-                            for mut client in clients.drain(0..) {
-                                let _ = self.poll.borrow_mut().reregister(&client.get_ref().stream, Token(next_client_token_value), Ready::readable() | Ready::writable(), PollOpt::edge());
-                                new_clients.insert(next_client_token_value, (client, pool_token_value));
-                                next_client_token_value += 1;
-                            }
+                    if let Some(clients) = existing_clients.remove(&pool_config.listen) {
+                        // Fills go to the cache of the client's new pool.
+                        let cache = new_backendpools.last().and_then(|pool| pool.cache.clone());
+                        for mut client in clients {
+                            client.get_mut().cache = cache.clone();
+                            let client_token = client.get_ref().token();
+                            new_clients.insert(client_token.0, (client, pool_token.0));
                         }
-                        None => {}
                     }
 
-                    pool_token_value += 1;
+                    pool_index += 1;
                 }
 
             self.backendpools = new_backendpools;
-            (new_backends, new_clients)
+            orphaned_clients.extend(existing_clients.drain().flat_map(|(_, clients)| clients));
+            (new_backends, new_clients, expired_backends)
             };
 
 
             self.backends = new_backends;
 
             self.clients = new_clients;
+            self.close_replaced(expired_backends, orphaned_clients);
+            self.set_audit_log();
+            self.resolve_standby_pools();
+            self.set_configured_filters(configured_filters);
+            // The new throttle starts out accepting. Connections that waited meanwhile are only announced by the
+            // listener's next event.
+            if accepts_paused {
+                self.accept_backlog();
+            }
         Ok(())
     }
 
+    /*
+        Tears down what a config switch replaced: the backends of removed pools, and the clients of pools whose listen
+        address is gone. Their sockets are deregistered before they're closed, and their tokens released, so that
+        repeated switches don't leak them. The slots of removed cluster hosts stay in cluster_backends, closed, since
+        the tokens of the other hosts are their indexes.
+
+        Requests still waiting on a removed backend get an error, as their clients may have moved to a new pool.
+    */
+    fn close_replaced(&mut self, expired_backends: Vec<Backend>, orphaned_clients: Vec<BufferedClient>) {
+        let mut completed_clients = VecDeque::new();
+        for mut backend in expired_backends {
+            backend.close(&mut self.clients, &mut self.cluster_backends, &mut completed_clients, &mut self.stats);
+        }
+        // Clients that an error was written to are read from again on the next pass of the event loop.
+        let now = clock::now();
+        for client_token_value in completed_clients {
+            self.timers.borrow_mut().insert(now, TimerEvent::ReadClient(Token(client_token_value)));
+        }
+        // Dropping the clients deregisters and closes them.
+        if !orphaned_clients.is_empty() {
+            log_event!(LogLevel::Info, "clients_closed", { clients: orphaned_clients.len() }, "Closing {} clients whose pool was removed", orphaned_clients.len());
+        }
+    }
+
+    // Sets up the audit log from the current config, and shares it with every pool.
+    fn set_audit_log(&mut self) {
+        let audit_log = AuditLog::from_config(&self.config).map(Rc::new);
+        for pool in self.backendpools.iter_mut() {
+            pool.audit_log = audit_log.clone();
+        }
+    }
+
+    // Points each pool with a standby at the standby's position among the current pools.
+    fn resolve_standby_pools(&mut self) {
+        let names: HashMap<String, PoolIndex> = self.backendpools.iter().enumerate().map(|(index, pool)| (pool.name.clone(), index)).collect();
+        for pool in self.backendpools.iter_mut() {
+            let standby_index = match pool.config.standby {
+                Some(ref standby) => names.get(standby).cloned(),
+                None => None,
+            };
+            if let Some(ref mut failover) = pool.failover {
+                failover.standby_index = standby_index;
+            }
+        }
+    }
+
+    /*
+        Adds a filter to the requests and responses of a pool, after the filters already added to it. It applies to
+        the pool's connected clients as well as new ones, and to the pool of the same name after a config switch.
+    */
+    pub fn add_filter(&mut self, pool_name: &str, filter: Box<dyn Filter>) -> Result<(), ProxyError> {
+        if !self.config.pools.contains_key(pool_name) {
+            return Err(ProxyError::FilterUnknownPool(pool_name.to_owned()));
+        }
+        self.filters.entry(pool_name.to_owned())
+            .or_insert_with(|| Rc::new(RefCell::new(FilterChain::new(pool_name))))
+            .borrow_mut()
+            .add(filter);
+        self.set_filters();
+        info!("Added a filter to {}", pool_name);
+        return Ok(());
+    }
+
+    // Replaces the filters from the previous config with the ones loaded from the current one.
+    fn set_configured_filters(&mut self, mut configured_filters: BTreeMap<String, Vec<Box<dyn Filter>>>) {
+        for pool_name in self.config.pools.keys() {
+            let filters = configured_filters.remove(pool_name).unwrap_or_default();
+            if filters.is_empty() && !self.filters.contains_key(pool_name) {
+                continue;
+            }
+            self.filters.entry(pool_name.clone())
+                .or_insert_with(|| Rc::new(RefCell::new(FilterChain::new(pool_name))))
+                .borrow_mut()
+                .set_configured(filters);
+        }
+        self.set_filters();
+    }
+
+    // Shares each pool's filters with it and its clients. Pools without any filters skip filtering altogether.
+    fn set_filters(&mut self) {
+        for pool in self.backendpools.iter_mut() {
+            pool.filters = match self.filters.get(&pool.name) {
+                Some(filters) if !filters.borrow().is_empty() => Some(filters.clone()),
+                _ => None,
+            };
+            for (client, client_pool_token_value) in self.clients.values_mut() {
+                if *client_pool_token_value == pool.token.0 {
+                    client.get_mut().filters = pool.filters.clone();
+                }
+            }
+        }
+    }
+
     pub fn run(&mut self) -> Result<(), ProxyError> {
-        let mut events = Events::with_capacity(1024);
-        /*
-            A running collection of clients that should be checked if they have any pending requests. This is basically
-            a way to manually trigger a readable event for a client. This is used when a multikey command is completed.
-        */
-        let mut completed_clients = VecDeque::with_capacity(1024);
-        let mut new_completed_clients = VecDeque::with_capacity(1024);
-        while self.running {
-            match self.poll.borrow_mut().poll(&mut events, None) {
-                Ok(_poll_size) => {}
-                Err(error) => {
-                    return Err(ProxyError::PollFailure(error));
+        let mut state = EventLoopState::new(self.config.event_capacity);
+        while try!(self.run_iteration(&mut state, None)) {}
+        self.close_connections();
+        return Ok(());
+    }
+
+    /*
+        Polls once, waiting at most max_wait, and handles the events, expired timers and resumed clients. Returns false
+        once the proxy is done shutting down, and should be closed. The harness calls this to step the event loop.
+    */
+    pub fn run_iteration(&mut self, state: &mut EventLoopState, max_wait: Option<Duration>) -> Result<bool, ProxyError> {
+        if self.admin.is_some() && signals::take_restart_request() {
+            self.hot_restart();
+        }
+        self.check_restart_child();
+        self.handle_control_requests();
+        // Between iterations, so that no events are pending for the connections that a switch replaces.
+        if state.pending_events.is_empty() && self.shutdown_deadline.is_none() {
+            self.follow_discovery();
+        }
+        if self.shutdown_deadline.is_none() && signals::shutdown_requested() {
+            self.begin_shutdown();
+        }
+        if let Some(deadline) = self.shutdown_deadline {
+            let in_flight = self.in_flight_requests();
+            if in_flight == 0 {
+                return Ok(false);
+            }
+            if clock::now() >= deadline {
+                warn!("Shutdown timed out with {} requests still in flight", in_flight);
+                return Ok(false);
+            }
+        }
+        // Wake up in time for the next timer, stats snapshot or shutdown deadline, even if there are no events.
+        // Clients left to handle, e.g. ones resumed by a backend that had room again, shouldn't wait for an event.
+        // While at the memory budget, usage is measured again even if idle, so that shedding stops once it drops.
+        let now = clock::now();
+        let busy = !state.completed_clients.is_empty() || !state.pending_events.is_empty() || self.config.poll_strategy == PollStrategy::Spin;
+        let poll_timeout = [
+            if busy { Some(Duration::from_millis(0)) } else { None },
+            max_wait,
+            if self.config.max_poll_timeout > 0 { Some(Duration::from_millis(self.config.max_poll_timeout as u64)) } else { None },
+            if memory::is_exhausted() { Some(self.memory.time_until_check(now)) } else { None },
+            self.timers.borrow().time_until_next(now),
+            self.snapshotter.as_ref().map(|snapshotter| snapshotter.time_until_due(now)),
+            self.shutdown_deadline.map(|deadline| if deadline > now { deadline - now } else { Duration::from_millis(0) }),
+            // While accepting is paused, so that it resumes even if no events arrive.
+            self.accept_throttle.as_ref().and_then(|throttle| if throttle.is_paused() { Some(throttle.time_until_check(now)) } else { None }),
+            self.systemd.as_ref().and_then(|systemd| systemd.time_until_due(now)),
+        ].iter().filter_map(|timeout| *timeout).min();
+        let poll_size = match self.registry.poll(&mut state.events, poll_timeout) {
+            Ok(poll_size) => poll_size,
+            Err(error) => {
+                return Err(ProxyError::PollFailure(error));
+            }
+        };
+        let iteration_start = clock::now();
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.begin_iteration();
+        }
+        self.enter_phase(Phase::Events);
+        // Before any client requests are sent to a master that has been failed over.
+        self.follow_failovers(&mut state.completed_clients);
+        state.pending_events.extend(state.events.iter());
+        let handled_events = match self.config.max_events_per_iteration {
+            0 => state.pending_events.len(),
+            max => std::cmp::min(max, state.pending_events.len()),
+        };
+        for event in state.pending_events.drain(..handled_events) {
+            if let Some(ref watchdog) = self.watchdog {
+                watchdog.handling(event.token());
+            }
+            self.handle_event(&event, &mut state.completed_clients);
+        }
+        // Tokens of the connections closed while handling these events can be reused from now on, unless events
+        // for them are still pending.
+        if state.pending_events.is_empty() {
+            self.client_tokens.borrow_mut().reclaim();
+            if let Some(ref admin) = self.admin {
+                admin.client_tokens.borrow_mut().reclaim();
+            }
+        }
+        self.enter_phase(Phase::Timers);
+        self.timers.borrow_mut().advance(clock::now(), &mut state.expired_timers);
+        for timer_event in state.expired_timers.drain(..) {
+            self.handle_timer_event(timer_event, &mut state.completed_clients);
+        }
+        self.enter_phase(Phase::Clients);
+        for completed_ctv in state.completed_clients.drain(0..) {
+            if let Some(ref watchdog) = self.watchdog {
+                watchdog.handling(Token(completed_ctv));
+            }
+            handle_client(
+                &mut self.backendpools,
+                &mut self.backends,
+                &mut self.cluster_backends,
+                &mut self.clients,
+                &mut Token(completed_ctv),
+                &mut state.new_completed_clients,
+                &mut self.stats,
+                false,
+                !self.config.batch_dispatch,
+            );
+            self.dispatch_pending |= self.config.batch_dispatch;
+        }
+        std::mem::swap(&mut state.completed_clients, &mut state.new_completed_clients);
+        self.dispatch_requests(&mut state.completed_clients);
+
+        self.enter_phase(Phase::Housekeeping);
+        self.sample_rates();
+        self.measure_memory();
+        self.write_stats_snapshot();
+        self.collect_traces();
+        self.stats.event_loop.record_iteration(iteration_start, poll_size);
+        if let Some(ref mut throttle) = self.accept_throttle {
+            throttle.record_iteration(iteration_start.elapsed());
+        }
+        self.throttle_accepts();
+        self.notify_systemd();
+        if let Some(ref watchdog) = self.watchdog {
+            self.stats.event_loop.stalls += watchdog.take_stalls();
+            watchdog.end_iteration();
+        }
+        self.stats.publish();
+        return Ok(true);
+    }
+
+    // Takes requests from the handle of an embedded proxy. The proxy is then stopped through the handle.
+    pub fn set_control(&mut self, control: ControlReceiver) -> Result<(), ProxyError> {
+        if let Err(err) = control.register(&self.registry) {
+            return Err(ProxyError::InitPollFailure(err));
+        }
+        self.control = Some(control);
+        return Ok(());
+    }
+
+    /*
+        Writes the requests buffered for each backend by the clients read from in this iteration, with batch_dispatch.
+        Each backend gets them in the order they were read, in a single write.
+    */
+    fn dispatch_requests(&mut self, completed_clients: &mut VecDeque<ClientTokenValue>) {
+        if !self.dispatch_pending {
+            return;
+        }
+        self.dispatch_pending = false;
+        for backend in self.backends.iter_mut() {
+            backend.flush_output(&mut self.clients, &mut self.cluster_backends, completed_clients, &mut self.stats);
+        }
+    }
+
+    // Notifies systemd once the proxy is ready, and keeps its watchdog from firing while the event loop runs.
+    pub fn set_systemd(&mut self, notifier: Notifier) {
+        self.systemd = Some(notifier);
+    }
+
+    fn notify_systemd(&mut self) {
+        let now = clock::now();
+        let connected = match self.systemd {
+            Some(ref systemd) if !systemd.is_ready() => self.backends.iter().all(|backend| backend.is_available()),
+            Some(_) => true,
+            None => return,
+        };
+        if let Some(ref mut systemd) = self.systemd {
+            systemd.check_ready(connected, now);
+            systemd.keepalive(now);
+        }
+    }
+
+    // Runs an admin command on the proxy's own thread, for the harness. See embed::ProxyHandle::command.
+    pub fn command(&mut self, args: &[&str]) -> Result<String, String> {
+        return self.handle_admin_command(&args.join("\n")).into_result();
+    }
+
+    fn handle_control_requests(&mut self) {
+        let requests = match self.control {
+            Some(ref control) => control.take_requests(),
+            None => return,
+        };
+        for request in requests {
+            match request {
+                ControlRequest::Command(command, reply) => {
+                    let _ = reply.send(self.handle_admin_command(&command).into_result());
+                }
+                ControlRequest::Stop => self.stop(),
+            }
+        }
+    }
+
+    // Shuts down this proxy alone. signals::request_shutdown shuts down every worker of the process.
+    fn stop(&mut self) {
+        if self.shutdown_deadline.is_none() {
+            self.begin_shutdown();
+        }
+    }
+
+    /*
+        Stops accepting clients on the pool ports, and gives in-flight requests until shutdown_timeout to be answered.
+        Clients that are already connected are still served meanwhile.
+    */
+    fn begin_shutdown(&mut self) {
+        log_event!(
+            LogLevel::Info,
+            "shutdown",
+            { in_flight: self.in_flight_requests(), timeout_ms: self.config.shutdown_timeout },
+            "Shutting down. Waiting up to {}ms for in-flight requests", self.config.shutdown_timeout
+        );
+        for pool in &mut self.backendpools {
+            // Closing the listener also removes it from the poll.
+            pool.listen_socket = None;
+        }
+        self.transparent = None;
+        // After a hot restart, the new process is the one systemd supervises from then on.
+        match self.systemd {
+            Some(ref systemd) if self.restart_child.is_none() => systemd.stopping(),
+            _ => {}
+        }
+        self.shutdown_deadline = Some(clock::now() + Duration::from_millis(self.config.shutdown_timeout as u64));
+    }
+
+    /*
+        Starts a new proxy process, handing it the admin and pool listeners of this worker. Once the new process is
+        listening, it sends this one SIGTERM, and this one drains its in-flight requests and exits. Clients that are
+        already connected stay on this process until then.
+    */
+    fn hot_restart(&mut self) {
+        if self.shutdown_deadline.is_some() || self.restart_child.is_some() {
+            warn!("Ignoring hot restart request, since the proxy is already shutting down or restarting.");
+            return;
+        }
+        let mut listener_fds = vec![self.admin().socket.as_raw_fd()];
+        for pool in &self.backendpools {
+            if let Some(ref listener) = pool.listen_socket {
+                listener_fds.push(listener.as_raw_fd());
+            }
+        }
+        if let Some(ref transparent) = self.transparent {
+            listener_fds.push(transparent.socket.as_raw_fd());
+        }
+        match handoff::spawn_restart(&listener_fds) {
+            Ok(child) => {
+                log_event!(LogLevel::Info, "hot_restart", { pid: child.id() }, "Started process {} for hot restart", child.id());
+                self.restart_child = Some(child);
+            }
+            Err(err) => error!("Unable to start a new process for hot restart: {}", err),
+        }
+    }
+
+    // Reaps the process started by a hot restart if it exited, e.g. because it failed to start.
+    fn check_restart_child(&mut self) {
+        let exited = match self.restart_child {
+            Some(ref mut child) => match child.try_wait() {
+                Ok(Some(status)) => {
+                    // A daemonized process exits successfully once it has forked into the background.
+                    if !status.success() {
+                        error!("Hot restart failed. Process {} exited with {}", child.id(), status);
+                    }
+                    true
+                }
+                Ok(None) => false,
+                Err(err) => {
+                    error!("Unable to check on hot restart process {}: {}", child.id(), err);
+                    true
+                }
+            },
+            None => false,
+        };
+        if exited {
+            self.restart_child = None;
+        }
+    }
+
+    // Requests sent to backends that have yet to be answered, including the proxy's own requests.
+    fn in_flight_requests(&self) -> usize {
+        let mut in_flight = 0;
+        for backend in &self.backends {
+            for (_, queue) in backend.queue_stats(&self.cluster_backends) {
+                in_flight += queue.depth;
+            }
+        }
+        return in_flight;
+    }
+
+    fn accepts_paused(&self) -> bool {
+        return self.accept_throttle.as_ref().map_or(false, |throttle| throttle.is_paused());
+    }
+
+    // Pauses or resumes accepting clients on the pool ports, depending on the pressure on the event loop.
+    fn throttle_accepts(&mut self) {
+        let now = clock::now();
+        let in_flight = match self.accept_throttle {
+            Some(ref throttle) if throttle.is_check_due(now) => {
+                if throttle.queue_depth > 0 { self.in_flight_requests() } else { 0 }
+            }
+            _ => return,
+        };
+        match self.accept_throttle.as_mut().and_then(|throttle| throttle.check(in_flight, now)) {
+            Some(true) => {
+                self.stats.event_loop.accept_pauses += 1;
+                log_event!(LogLevel::Warn, "accept_paused", { in_flight: in_flight }, "Overloaded with {} requests in flight. Pausing accepting clients", in_flight);
+            }
+            Some(false) => {
+                log_event!(LogLevel::Info, "accept_resumed", { in_flight: in_flight }, "No longer overloaded. Resuming accepting clients");
+                self.accept_backlog();
+            }
+            None => {}
+        }
+    }
+
+    // Accepts the connections left in the listen backlogs while accepting was paused, which no new event announces.
+    fn accept_backlog(&mut self) {
+        for pool in self.backendpools.iter_mut() {
+            if pool.listen_socket.is_some() {
+                pool.accept_client_connection(&self.registry, &self.client_tokens, &mut self.clients, &mut self.stats);
+            }
+        }
+        self.accept_transparent_clients();
+    }
+
+    // Closes client and backend connections once shutdown is done. Responses have already been written.
+    fn close_connections(&mut self) {
+        let num_clients = self.clients.len();
+        self.clients.clear();
+        for backend in &mut self.backends {
+            backend.disconnect(&mut self.cluster_backends);
+        }
+        log_event!(LogLevel::Info, "shutdown_complete", { clients: num_clients }, "Closed {} client connections and all backend connections", num_clients);
+    }
+
+    fn enter_phase(&self, phase: Phase) {
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.enter(phase);
+        }
+    }
+
+    /*
+        Handles a backend deadline that has passed. Accumulates any clients that should be manually triggered.
+    */
+    fn handle_timer_event(
+        &mut self,
+        timer_event: TimerEvent,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+    ) {
+        match timer_event {
+            TimerEvent::Retry(token) => {
+                debug!("RetryTimeout {:?}", token);
+                if tokens::kind(token) == TokenKind::ClusterServer {
+                    match self.cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                        Some((backend, _)) => backend.init_connection(),
+                        None => error!("Retry timer for a cluster backend that doesn't exist: {:?}", token),
+                    }
+                    return;
+                }
+                match self.backends.get_mut(convert_token_to_backend_index(token.0)) {
+                    Some(backend) => backend.init_connection(&mut self.cluster_backends),
+                    None => error!("Retry timer for a backend that doesn't exist: {:?}", token),
+                }
+            }
+            TimerEvent::RequestTimeout(token) => {
+                debug!("RequestTimeout {:?}", token);
+                // Cluster backends time out through the backend of the pool that owns them.
+                let backend_token_value = if tokens::kind(token) == TokenKind::ClusterServer {
+                    match self.cluster_backends.get(convert_token_to_cluster_index(token.0)) {
+                        Some(&(_, backend_token_value)) => backend_token_value,
+                        None => {
+                            error!("Request timer for a cluster backend that doesn't exist: {:?}", token);
+                            return;
+                        }
+                    }
+                } else {
+                    token.0
+                };
+                match self.backends.get_mut(convert_token_to_backend_index(backend_token_value)) {
+                    Some(backend) => {
+                        handle_timeout(
+                            backend,
+                            token,
+                            &mut self.clients,
+                            &mut self.cluster_backends,
+                            completed_clients,
+                            &mut self.stats
+                        );
+                    }
+                    None => error!("Request timer for a backend that doesn't exist: {:?}", token),
+                }
+            }
+            TimerEvent::Sweep => {
+                self.sweep_connections(completed_clients);
+                let now = SystemTime::now();
+                for pool in self.backendpools.iter_mut() {
+                    pool.update_maintenance(now);
+                }
+                self.timers.borrow_mut().insert(clock::now() + Duration::from_millis(SWEEP_INTERVAL_MS), TimerEvent::Sweep);
+            }
+            TimerEvent::Hedge(client_token, request_id, token) => {
+                self.send_hedge(client_token, request_id, token, completed_clients);
+            }
+            TimerEvent::ReadClient(client_token) => {
+                completed_clients.push_back(client_token.0);
+            }
+            TimerEvent::Flush(token) => {
+                match single_backend_mut(&mut self.backends, &mut self.cluster_backends, token) {
+                    Some(backend) => backend.end_delay(&mut self.clients, completed_clients, &mut self.stats),
+                    None => error!("Flush timer for a backend that doesn't exist: {:?}", token),
+                }
+            }
+            TimerEvent::ConnectAttempt(token) => {
+                match single_backend_mut(&mut self.backends, &mut self.cluster_backends, token) {
+                    Some(backend) => backend.start_next_attempt(&mut self.clients, completed_clients, &mut self.stats),
+                    None => error!("Connect attempt timer for a backend that doesn't exist: {:?}", token),
+                }
+            }
+            TimerEvent::OutageBuffer(token) => {
+                match single_backend_mut(&mut self.backends, &mut self.cluster_backends, token) {
+                    Some(backend) => backend.expire_held(&mut self.clients, completed_clients, &mut self.stats),
+                    None => error!("Outage buffer timer for a backend that doesn't exist: {:?}", token),
+                }
+            }
+            TimerEvent::DroppedResponse(client_token, request_id) => {
+                let pool_token_value = match self.clients.get(&client_token.0) {
+                    Some(&(_, pool_token_value)) => pool_token_value,
+                    None => return,
+                };
+                if let Some(pool) = self.backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                    pool.stats.errors.timeouts += 1;
+                    handle_write_to_client(
+                        &mut self.clients,
+                        &client_token.0,
+                        b"-ERR Proxy timed out\r\n",
+                        request_id,
+                        completed_clients,
+                        &mut pool.stats.connections,
+                        &mut self.stats,
+                    );
+                }
+            }
+        }
+    }
+
+    /*
+        Sends a read that its backend hasn't answered within hedge_delay to one of the backend's replicas too. If none
+        of them can take it, the read just waits for the backend.
+    */
+    fn send_hedge(
+        &mut self,
+        client_token: ClientToken,
+        request_id: (Instant, usize),
+        token: BackendToken,
+        completed_clients: &mut VecDeque<ClientTokenValue>,
+    ) {
+        let (request, pool_token_value) = match self.clients.get_mut(&client_token.0) {
+            Some((client, pool_token_value)) => match client.get_mut().start_hedge(request_id) {
+                Some(request) => (request, *pool_token_value),
+                None => return,
+            },
+            None => return,
+        };
+        let backend = match self.backends.get_mut(convert_token_to_backend_index(token.0)) {
+            Some(backend) => backend,
+            None => return,
+        };
+        match backend.write_hedge(&request, client_token, &mut self.cluster_backends, request_id, &mut self.stats) {
+            Ok(_) => {
+                debug!("Hedged a read from {:?} to a replica", client_token);
+                if let Some(pool) = self.backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                    pool.stats.hedged_requests += 1;
+                }
+                backend.flush_output(&mut self.clients, &mut self.cluster_backends, completed_clients, &mut self.stats);
+            }
+            Err(err) => {
+                debug!("Could not hedge a read from {:?}. Received error: {}", client_token, err);
+                if let Some((client, _)) = self.clients.get_mut(&client_token.0) {
+                    client.get_mut().hedges.remove(&request_id);
                 }
+            }
+        }
+    }
+
+    /*
+        Closes connections that no event will come for: clients without traffic for their pool's client_idle_timeout,
+        paused clients that hung up, admin clients idle for the admin idle_timeout, and backend connections that weren't
+        established within their pool's connect_timeout. Their tokens are reclaimed like those of any closed connection.
+    */
+    fn sweep_connections(&mut self, completed_clients: &mut VecDeque<ClientTokenValue>) {
+        let now = clock::now();
+        let mut idle_clients = Vec::new();
+        let mut hung_up_clients = Vec::new();
+        for (token_value, &(ref client, pool_token_value)) in self.clients.iter() {
+            let client = client.get_ref();
+            let idle_time = client.idle_time(now);
+            // Clients with a request in flight are closed by its timeout instead.
+            if client.pending_count > 0 || idle_time < Duration::from_millis(SWEEP_INTERVAL_MS) {
+                continue;
+            }
+            let idle_timeout = match self.backendpools.get(convert_token_to_pool_index(pool_token_value)) {
+                Some(pool) => pool.config.client_idle_timeout,
+                None => continue,
             };
-            for event in events.iter() {
-                self.handle_event(&event, &mut completed_clients);
+            if idle_timeout > 0 && idle_time >= Duration::from_millis(idle_timeout as u64) {
+                idle_clients.push(*token_value);
+            } else if client.is_paused() && client.has_hung_up() {
+                // Other clients are removed when their hang up is read, but paused ones aren't read.
+                hung_up_clients.push(*token_value);
             }
-            for completed_ctv in completed_clients.drain(0..) {
-                handle_client(
-                    &mut self.backendpools,
-                    &mut self.backends,
-                    &mut self.cluster_backends,
+        }
+        for token_value in idle_clients {
+            if let Some((_, pool_token_value)) = self.clients.remove(&token_value) {
+                debug!("Closing idle client: {:?}", Token(token_value));
+                if let Some(pool) = self.backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                    pool.stats.connections.idle_closed += 1;
+                }
+            }
+        }
+        for token_value in hung_up_clients {
+            if let Some((_, pool_token_value)) = self.clients.remove(&token_value) {
+                debug!("Removing paused client that hung up: {:?}", Token(token_value));
+                if let Some(pool) = self.backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                    pool.stats.connections.client_disconnects += 1;
+                }
+            }
+        }
+
+        if let Some(ref mut admin) = self.admin {
+            admin.sweep_clients(now);
+        }
+
+        for pool_index in 0..self.backendpools.len() {
+            let connect_timeout = self.backendpools[pool_index].config.connect_timeout;
+            if connect_timeout == 0 {
+                continue;
+            }
+            for backend in pool_backends_mut(&self.backendpools, &mut self.backends, pool_index).iter_mut() {
+                backend.expire_connects(
+                    now,
+                    Duration::from_millis(connect_timeout as u64),
                     &mut self.clients,
-                    &mut Token(completed_ctv),
-                    &mut new_completed_clients,
+                    &mut self.cluster_backends,
+                    completed_clients,
                     &mut self.stats,
-                    false,
                 );
             }
-            let temp = completed_clients;
-            completed_clients = new_completed_clients;
-            new_completed_clients = temp;
         }
-        return Ok(());
     }
 
     /*
@@ -420,12 +1294,11 @@ impl RedFlareProxy {
     ) {
         let mut token = event.token();
         debug!("Event: {:?} {:?}", token, event.readiness());
-        if event.readiness().contains(UnixReady::error()) {
-            info!("Received unix error");
-            let subscriber = self.identify_token(token);
-            match subscriber {
-                SubType::PoolServer => {
-                    let token_id = convert_token_to_backend_index(token.0, self.backendpools.len());
+        if platform::is_closed(event.readiness()) {
+            info!("Received socket error or hang-up");
+            match tokens::kind(token) {
+                TokenKind::PoolServer => {
+                    let token_id = convert_token_to_backend_index(token.0);
                     let backend = match self.backends.get_mut(token_id) {
                         Some(backend) => backend,
                         None => {
@@ -442,18 +1315,21 @@ impl RedFlareProxy {
                     );
                     return;
                 }
-                SubType::PoolClient => {
-                    info!("Removed client because of error: {:?}", token);
-                    self.clients.remove(&token.0);
+                TokenKind::PoolClient => {
+                    if let Some((_, pool_token_value)) = self.clients.remove(&token.0) {
+                        if let Some(pool) = self.backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                            pool.stats.connections.client_disconnects += 1;
+                            log_event!(LogLevel::Info, "client_disconnected", { pool: pool.name, token: token.0 }, "Removed client because of error: {:?}", token);
+                        }
+                    }
                 }
                 other => {
                     error!("Received other error: {:?} {:?}", other, token);
                 }
             }
         }
-        let subscriber = self.identify_token(token);
-        match subscriber {
-            SubType::PoolClient => {
+        match tokens::kind(token) {
+            TokenKind::PoolClient => {
                 debug!("PoolClient {:?}", token);
                 handle_client(
                     &mut self.backendpools,
@@ -464,65 +1340,36 @@ impl RedFlareProxy {
                     completed_clients,
                     &mut self.stats,
                     true,
+                    !self.config.batch_dispatch,
                 );
+                self.dispatch_pending |= self.config.batch_dispatch;
             }
-            SubType::Timeout => {
-                debug!("RetryTimeout {:?}", token);
-                let num_pools = self.backendpools.len();
-                let num_backends = self.backends.len();
-                let token_id = convert_token_to_timeout_index(token.0, num_pools, num_backends);
-
-                match self.backends.get_mut(token_id) {
-                    Some(backend) => {
-                        backend.init_connection(&mut self.cluster_backends);
-                    }
-                    None => error!("HashMap says it has token but it really doesn't! {:?}",token),
-                }
-            }
-            SubType::RequestTimeout => {
-                debug!("RequestTimeout {:?})", token);
-                let num_pools = self.backendpools.len();
-                let num_backends = self.backends.len();
-                let token_id = convert_token_to_requesttimeout_index(token.0, num_pools, num_backends);
-                let backend_token = Token(token.0 - 2 * num_backends);
-                match self.backends.get_mut(token_id) {
-                    Some(backend) => {
-                        handle_timeout(
-                            backend,
-                            backend_token,
-                            &mut self.clients,
-                            &mut self.cluster_backends,
-                            completed_clients,
-                            &mut self.stats
-                        );
-                    }
-                    None => error!("HashMap says it has token but it really doesn't! {:?}", token),
-                }
+            TokenKind::PoolListener if self.accepts_paused() => {
+                debug!("PoolListener {:?} left in the backlog while overloaded", token);
             }
-            SubType::PoolListener => {
+            TokenKind::PoolListener => {
                 debug!("PoolListener {:?}", token);
                 let token_id = convert_token_to_pool_index(token.0);
                 match self.backendpools.get_mut(token_id) {
                     Some(pool) => pool.accept_client_connection(
-                                    &self.poll,
-                                    &mut self.next_client_token_value,
+                                    &self.registry,
+                                    &self.client_tokens,
                                     &mut self.clients,
                                     &mut self.stats,
                                   ),
                     None => error!("HashMap says it has token but it really doesn't!"),
                 }
             }
-            SubType::PoolServer => {
+            TokenKind::PoolServer => {
                 debug!("PoolServer {:?}", token);
-                let num_pools = self.backendpools.len();
-                let backend_index = convert_token_to_backend_index(token.0, num_pools);
-                let mut next_cluster_token_value = FIRST_CLUSTER_BACKEND_INDEX + self.cluster_backends.len();
+                let backend_index = convert_token_to_backend_index(token.0);
+                let mut next_cluster_index = self.cluster_backends.len();
                 match self.backends.get_mut(backend_index) {
                     Some(b) => {
                         b.handle_backend_response(
                             token,
                             &mut self.clients,
-                            &mut next_cluster_token_value,
+                            &mut next_cluster_index,
                             &mut self.cluster_backends,
                             completed_clients,
                             &mut self.stats,
@@ -531,81 +1378,210 @@ impl RedFlareProxy {
                     None => error!("HashMap says it has token but it really doesn't!"),
                 }
             }
-            SubType::ClusterServer => {
+            TokenKind::ClusterServer => {
                 debug!("ClusterServer {:?}", token);
-                let num_pools = self.backendpools.len();
                 let cluster_index = convert_token_to_cluster_index(token.0);
-                let pool_token_value = self.cluster_backends.get(cluster_index).unwrap().1;
-                let backend_index = convert_token_to_backend_index(pool_token_value, num_pools);
-                let mut next_cluster_token_value = FIRST_CLUSTER_BACKEND_INDEX + self.cluster_backends.len();
-                self.backends.get_mut(backend_index).unwrap().handle_backend_response(
-                    token,
-                    &mut self.clients,
-                    &mut next_cluster_token_value,
-                    &mut self.cluster_backends,
-                    completed_clients,
-                    &mut self.stats,
-                );
+                let pool_token_value = match self.cluster_backends.get(cluster_index) {
+                    Some(&(_, pool_token_value)) => pool_token_value,
+                    None => {
+                        error!("Received an event for a nonexistent cluster backend: {:?}", token);
+                        return;
+                    }
+                };
+                let backend_index = convert_token_to_backend_index(pool_token_value);
+                let mut next_cluster_index = self.cluster_backends.len();
+                match self.backends.get_mut(backend_index) {
+                    Some(b) => {
+                        b.handle_backend_response(
+                            token,
+                            &mut self.clients,
+                            &mut next_cluster_index,
+                            &mut self.cluster_backends,
+                            completed_clients,
+                            &mut self.stats,
+                        )
+                    }
+                    None => error!("HashMap says it has token but it really doesn't!"),
+                }
             }
-            SubType::AdminClient => {
+            TokenKind::TransparentListener if self.accepts_paused() => {
+                debug!("TransparentListener {:?} left in the backlog while overloaded", token);
+            }
+            TokenKind::TransparentListener => {
+                debug!("TransparentListener {:?}", token);
+                self.accept_transparent_clients();
+            }
+            TokenKind::AdminClient => {
                 debug!("AdminClient {:?}", token);
                 self.handle_client_socket(token);
             }
-            SubType::AdminListener => {
+            TokenKind::ShutdownSignal => {
+                // The event loop checks for a requested shutdown and control requests on each iteration.
+                debug!("ShutdownSignal {:?}", token);
+            }
+            TokenKind::AdminListener => {
                 debug!("AdminListener {:?}", token);
-                self.admin.accept_client_connection(2, &mut self.poll.borrow_mut());
+                let registry = self.registry.clone();
+                self.admin().accept_client_connection(&registry);
+            }
+            TokenKind::Null => {
+                error!("Received an event for the null token");
             }
         }
         return;
     }
 
+    /*
+        Sets a component's log level from the admin port, e.g. LOGLEVEL cluster DEBUG. DEFAULT removes the override, so the
+        component logs at the global level again. The change is kept in the config, so REWRITECONFIG persists it.
+    */
+    fn set_log_level(&mut self, component: &str, level: &str) -> String {
+        let component = component.trim().to_lowercase();
+        if level.trim().to_uppercase() == "DEFAULT" {
+            self.config.log_levels.remove(&component);
+        } else {
+            let mut log_levels = self.config.log_levels.clone();
+            log_levels.insert(component.clone(), level.trim().to_uppercase());
+            if let Err(entry) = logging::parse_component_levels(&log_levels) {
+                return format!("Invalid log level: {}", entry);
+            }
+            self.config.log_levels = log_levels;
+        }
+        self.apply_log_levels();
+        return "OK".to_owned();
+    }
+
+    fn apply_log_levels(&mut self) {
+        let component_levels = match logging::parse_component_levels(&self.config.log_levels) {
+            Ok(component_levels) => component_levels,
+            Err(entry) => {
+                error!("Invalid log level: {}", entry);
+                return;
+            }
+        };
+        if let Some(ref mut logging) = self.logging {
+            if let Err(err) = logging.set_component_levels(component_levels) {
+                error!("Failed to change log levels: {}", err);
+            }
+        }
+    }
+
     pub fn get_current_config(&self) -> RedFlareProxyConfig {
         self.config.clone()
     }
     
+    // Only worker 0 binds the admin port, so admin events only occur on it.
+    /*
+        Accepts the connections redirected to the transparent listener, and hands each to the pool with its original
+        destination. Connections to a destination that no pool has are closed.
+    */
+    fn accept_transparent_clients(&mut self) {
+        loop {
+            let accepted = match self.transparent {
+                Some(ref transparent) => transparent.accept(),
+                None => return,
+            };
+            let (stream, addr, destination) = match accepted {
+                Ok(Some(accepted)) => accepted,
+                Ok(None) => return,
+                Err(err) => {
+                    error!("Unable to accept transparent connection. Received error: {}", err);
+                    return;
+                }
+            };
+            match self.backendpools.iter_mut().find(|pool| pool.config.original_destinations.contains(&destination)) {
+                Some(pool) => pool.add_client(stream, addr, &self.registry, &self.client_tokens, &mut self.clients, &mut self.stats),
+                None => log_event!(
+                    LogLevel::Info,
+                    "transparent_unrouted",
+                    { addr: addr, destination: destination },
+                    "Closed connection from {} to {}, which no pool has in its original_destinations", addr, destination
+                ),
+            }
+        }
+    }
+
+    fn admin(&mut self) -> &mut admin::AdminPort {
+        return self.admin.as_mut().expect("Received an admin event on a worker without an admin port");
+    }
+
     pub fn get_staged_config(&self) -> Option<RedFlareProxyConfig> {
         self.staged_config.clone()
     }
 
     fn handle_client_socket(&mut self, token: ClientToken) {
-        let mut switching_config = false;
+        if !self.admin().read_proxy_header(token) {
+            return;
+        }
         let request = {
-            let client = match self.admin.client_sockets.get_mut(&token.0) {
+            let client = match self.admin().client_sockets.get_mut(&token.0) {
                 Some(c) => c,
                 None => {
-                    error!("AdminClient {:?} triggered an event, but it is no longer stored.", token);
+                    error!(target: admin::LOG_TARGET, "AdminClient {:?} triggered an event, but it is no longer stored.", token);
                     return;
                 }
             };
-            parse_redis_command(client)
+            let request = parse_redis_command(client);
+            if !request.is_empty() {
+                client.get_mut().mark_active();
+            }
+            request
         };
-        debug!("RECEIVED COMMAND: {}", request);
+        debug!(target: admin::LOG_TARGET, "RECEIVED COMMAND: {}", request);
+        if request.lines().next().is_none() {
+            error!(target: admin::LOG_TARGET, "AdminClient socket has nothing, when something was expected.");
+            return;
+        }
+        let response = self.handle_admin_command(&request).to_resp();
+        debug!(target: admin::LOG_TARGET, "RESPONSE: {}", &response);
+        self.admin().write_to_client(token, response);
+    }
+
+    /*
+        Runs an admin command, given as its arguments on separate lines, for an admin client or for the control channel
+        of an embedded proxy.
+    */
+    fn handle_admin_command(&mut self, request: &str) -> AdminResponse {
+        let mut switching_config = false;
         let mut lines = request.lines();
         let current_line = lines.next();
         let res = match current_line {
             None => {
-                error!("AdminClient socket has nothing, when something was expected.");
-                return;
+                return AdminResponse::Error("Missing command".to_owned());
             }
             Some("INFO") => {
-                "DERP".to_owned()
+                version::format_info(self.start_time)
+            }
+            Some("VERSION") => {
+                version::format_version()
             }
             Some("PING") => {
                 "PONG".to_owned()
             }
             Some("LOADCONFIG") => {
-                let next_line = lines.next();
-                if next_line.is_none() {
-                    "Missing filepath argument!".to_owned()
-                } else {
-                    let argument = next_line.unwrap();
-                    let config = load_config(argument.to_owned()).unwrap();
-                    self.staged_config = Some(config);
-                    argument.to_owned()
+                match lines.next() {
+                    None => "Missing filepath argument!".to_owned(),
+                    Some(argument) => {
+                        match load_config(argument.to_owned(), self.profile.as_ref().map(|p| p.as_str())) {
+                            Ok(config) => {
+                                self.staged_config = Some(config);
+                                argument.to_owned()
+                            }
+                            Err(err) => {
+                                error!(target: admin::LOG_TARGET, "Failed to load config: {}", err);
+                                format!("{}", err)
+                            }
+                        }
+                    }
                 }
             }
             Some("SHUTDOWN") => {
-                self.running = false;
+                // An embedded proxy stops on its own, instead of every proxy in the process.
+                if self.control.is_some() {
+                    self.stop();
+                } else {
+                    signals::request_shutdown();
+                }
                "OK".to_owned()
             }
             Some("STAGEDCONFIG") => {
@@ -613,11 +1589,34 @@ impl RedFlareProxy {
                 if staged_config.is_none() {
                     "No config staged.".to_owned()
                 } else {
-                    toml::to_string(&staged_config).unwrap()
+                    match serialize_config(&staged_config.unwrap()) {
+                        Ok(contents) => contents,
+                        Err(err) => format!("{}", err),
+                    }
                 }
             }
             Some("CONFIGINFO") => {
-                toml::to_string(&self.get_current_config()).unwrap()
+                match serialize_config(&self.get_current_config()) {
+                    Ok(contents) => contents,
+                    Err(err) => format!("{}", err),
+                }
+            }
+            Some("REWRITECONFIG") => {
+                // Only the proxy's own config file is written, so that admin access can't overwrite any other file.
+                if lines.next().is_some() {
+                    "ERROR: REWRITECONFIG takes no arguments. It writes to the config file the proxy was started with.".to_owned()
+                } else {
+                    match write_config(&self.config, &self.config_path) {
+                        Ok(_) => "OK".to_owned(),
+                        Err(err) => {
+                            error!(target: admin::LOG_TARGET, "Failed to rewrite config: {}", err);
+                            format!("{}", err)
+                        }
+                    }
+                }
+            }
+            Some("SWITCHCONFIG") if self.config.workers > 1 => {
+                "ERROR: SWITCHCONFIG is not supported with multiple workers. Restart the proxy instead.".to_owned()
             }
             Some("SWITCHCONFIG") => {
                 // TODO: Need to lose reference to the stream, OR
@@ -627,91 +1626,881 @@ impl RedFlareProxy {
                 "OK".to_owned()
             }
             Some("STATS") => {
-                format!("{}", self.stats)
+                self.sample_rates();
+                format!(
+                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+                    self.stats,
+                    self.format_latency(),
+                    self.format_errors(),
+                    self.format_backend_errors(),
+                    self.format_connections(),
+                    self.format_big_values(),
+                    self.format_command_stats(),
+                    self.stats.format_rates(clock::now()),
+                    self.format_memory(),
+                    self.format_queues(),
+                    self.stats.event_loop
+                )
+            }
+            // These only see, or change, worker 0's pools and backends, so they'd give a partial view of each pool.
+            Some(command) if self.config.workers > 1 && WORKER_LOCAL_COMMANDS.contains(&command) => {
+                format!("ERROR: {} is not supported with multiple workers, since it only covers worker 0.", command)
+            }
+            Some("POOLSTATS") => {
+                self.sample_rates();
+                let mut output = String::new();
+                let memory = self.pool_memory();
+                for pool_index in 0..self.backendpools.len() {
+                    let pool = &self.backendpools[pool_index];
+                    output.push_str(&pool.stats.format_metrics(
+                        &pool.name,
+                        &pool.config,
+                        &self.pool_latency(pool_index),
+                        &self.pool_command_stats(pool_index),
+                    ));
+                    output.push_str(&self.pool_errors(pool_index).format_pool_metrics(&pool.name, &pool.config));
+                    output.push_str(&self.pool_connections(pool_index).format_metrics(&pool.name, &pool.config));
+                    output.push_str(&memory[pool_index].format_metrics(&pool.name, &pool.config));
+                    if let Some(ref cache) = pool.cache {
+                        output.push_str(&cache.borrow().format_metrics(&pool.name, &pool.config));
+                    }
+                    if let Some(ref dual_write_stats) = pool.dual_write_stats {
+                        output.push_str(&dual_write_stats.borrow().format_metrics("dual_write", &pool.name, &pool.config));
+                    }
+                    if let Some(ref canary_stats) = pool.canary_stats {
+                        output.push_str(&canary_stats.borrow().format_metrics("canary", &pool.name, &pool.config));
+                    }
+                    if let Some(ref failover) = pool.failover {
+                        output.push_str(&failover.format_metrics(&pool.name, &pool.config));
+                    }
+                    if let Some(ref users) = pool.users {
+                        output.push_str(&users.format_metrics(&pool.name, &pool.config));
+                    }
+                    for backend in self.pool_backends(pool_index) {
+                        output.push_str(&backend.error_stats(&self.cluster_backends).format_metrics(
+                            &pool.name,
+                            &backend.name(),
+                            &pool.config,
+                            &backend.latency(&self.cluster_backends),
+                        ));
+                        output.push_str(&format_value_size_metrics(
+                            &pool.name,
+                            &backend.name(),
+                            &pool.config,
+                            &backend.command_stats(&self.cluster_backends),
+                        ));
+                        for (host, queue) in backend.queue_stats(&self.cluster_backends) {
+                            output.push_str(&queue.format_metrics(&pool.name, &host, &pool.config));
+                        }
+                    }
+                }
+                output
+            }
+            Some("HOTKEYS") => {
+                self.format_hot_keys()
+            }
+            Some("CACHE") => {
+                self.format_caches()
+            }
+            Some("DEBUG") => {
+                match lines.next() {
+                    Some("TIMING") => {
+                        self.collect_traces();
+                        self.format_timings()
+                    }
+                    _ => "Unknown DEBUG subcommand".to_owned(),
+                }
+            }
+            Some("SLOWLOG") => {
+                match lines.next() {
+                    None => self.format_slow_requests(),
+                    Some("RESET") => {
+                        for backend in self.backends.iter_mut() {
+                            backend.reset_slowlog(&mut self.cluster_backends);
+                        }
+                        "OK".to_owned()
+                    }
+                    _ => "Unknown SLOWLOG subcommand".to_owned(),
+                }
+            }
+            Some("BENCH") => {
+                match lines.next() {
+                    None => self.format_bench(),
+                    Some("START") => match lines.next() {
+                        Some(pool_name) => {
+                            let args: Vec<&str> = lines.collect();
+                            self.start_bench(pool_name, &args)
+                        }
+                        None => "Missing pool argument!".to_owned(),
+                    },
+                    Some("STOP") => self.stop_bench(),
+                    _ => "Unknown BENCH subcommand".to_owned(),
+                }
+            }
+            Some("BACKENDINFO") => {
+                match lines.next() {
+                    Some(pool_name) => self.format_backend_info(pool_name),
+                    None => "Missing pool argument!".to_owned(),
+                }
+            }
+            Some("CAPTURE") => {
+                match lines.next() {
+                    None => self.format_captures(),
+                    Some("START") => match lines.next() {
+                        Some(pool_name) => self.start_capture(pool_name, lines.next(), lines.next()),
+                        None => "Missing pool argument!".to_owned(),
+                    },
+                    Some("STOP") => match lines.next() {
+                        Some(pool_name) => self.stop_capture(pool_name),
+                        None => "Missing pool argument!".to_owned(),
+                    },
+                    _ => "Unknown CAPTURE subcommand".to_owned(),
+                }
+            }
+            Some("CHAOS") if self.config.workers > 1 => {
+                "ERROR: CHAOS is not supported with multiple workers.".to_owned()
+            }
+            Some("CHAOS") => {
+                match lines.next() {
+                    None => self.format_chaos(),
+                    Some("START") => match lines.next() {
+                        Some(pool_name) => self.set_chaos_active(pool_name, true),
+                        None => "Missing pool argument!".to_owned(),
+                    },
+                    Some("STOP") => match lines.next() {
+                        Some(pool_name) => self.set_chaos_active(pool_name, false),
+                        None => "Missing pool argument!".to_owned(),
+                    },
+                    _ => "Unknown CHAOS subcommand".to_owned(),
+                }
+            }
+            Some("FAILOVER") if self.config.workers > 1 => {
+                "ERROR: FAILOVER is not supported with multiple workers. Failovers stay automatic.".to_owned()
+            }
+            Some("FAILOVER") => {
+                match (lines.next(), lines.next()) {
+                    (None, _) => self.format_failovers(),
+                    (Some(pool_name), Some(mode)) => self.set_failover_mode(pool_name, mode),
+                    (Some(_), None) => "Missing mode argument!".to_owned(),
+                }
+            }
+            Some("LOGLEVEL") => {
+                match (lines.next(), lines.next()) {
+                    (None, _) => match self.logging {
+                        Some(ref logging) => logging.format_levels(),
+                        None => "Logging is not configured".to_owned(),
+                    },
+                    (Some(component), Some(level)) => self.set_log_level(component, level),
+                    (Some(_), None) => "Missing level argument!".to_owned(),
+                }
             }
             Some("RESETSTATS") => {
                 self.stats.reset();
+                for pool in self.backendpools.iter_mut() {
+                    pool.stats.reset();
+                }
+                for backend in self.backends.iter_mut() {
+                    backend.reset_stats(&mut self.cluster_backends);
+                }
+                for pool in self.backendpools.iter_mut() {
+                    if let Some(ref mut hotkeys) = pool.hotkeys {
+                        hotkeys.reset();
+                    }
+                    if let Some(ref cache) = pool.cache {
+                        cache.borrow_mut().reset_stats();
+                    }
+                    if let Some(ref dual_write_stats) = pool.dual_write_stats {
+                        dual_write_stats.borrow_mut().reset();
+                    }
+                    if let Some(ref canary_stats) = pool.canary_stats {
+                        canary_stats.borrow_mut().reset();
+                    }
+                    if let Some(ref mut failover) = pool.failover {
+                        failover.reset_stats();
+                    }
+                    if let Some(ref users) = pool.users {
+                        users.reset_stats();
+                    }
+                    if let Some(ref chaos) = pool.chaos {
+                        chaos.borrow_mut().reset_stats();
+                    }
+                }
+                if let Some(ref mut admin) = self.admin {
+                    admin.denied_connections = 0;
+                }
+                self.collect_traces();
+                self.recent_traces.clear();
                 "OK".to_owned()
             }
             Some(unknown_command) => {
-                debug!("Unknown command: {}", unknown_command);
+                debug!(target: admin::LOG_TARGET, "Unknown command: {}", unknown_command);
                 "Unknown command".to_owned()
             }
         };
         if !switching_config {
-            let mut response = String::new();
-            response.push_str("$");
-            response.push_str(&res.len().to_string());
-            response.push_str("\r\n");
-            response.push_str(res.as_str());
-            response.push_str("\r\n");
-            debug!("RESPONSE: {}", &response);
-            self.admin.write_to_client(token, response);
-        }
-        if switching_config {
-            let result = {
-                self.switch_config()
-            };
-            match result {
-                Ok(_) => {
-                    let response = "+OK\r\n".to_owned();
-                    self.admin.write_to_client(token, response);
+            return AdminResponse::Bulk(res);
+        }
+        match self.switch_config() {
+            Ok(_) => AdminResponse::Status("OK".to_owned()),
+            Err(err) => AdminResponse::Error(format!("{}", err)),
+        }
+    }
+
+    // Snapshots the counters used for throughput rates. This is a no-op if the last snapshot was under a second ago.
+    fn sample_rates(&mut self) {
+        let now = clock::now();
+        self.stats.sample_rates(now);
+        for pool in self.backendpools.iter_mut() {
+            pool.stats.sample_rates(now);
+        }
+    }
 
+    // Reports this worker's buffered data to the memory budget, every memory::CHECK_INTERVAL_MS.
+    fn measure_memory(&mut self) {
+        let now = clock::now();
+        if !self.memory.is_check_due(now) {
+            return;
+        }
+        let mut used = 0;
+        for pool_memory in self.pool_memory() {
+            used += pool_memory.total_bytes();
+        }
+        self.memory.report(used, now);
+    }
+
+    // Collects the traces of completed requests. They are kept for DEBUG TIMING, and handed to the exporter if there is one.
+    fn collect_traces(&mut self) {
+        let mut traces = Vec::new();
+        for backend in self.backends.iter_mut() {
+            backend.drain_traces(&mut traces);
+        }
+        for &mut (ref mut backend, _) in self.cluster_backends.iter_mut() {
+            traces.append(&mut backend.traces.finished);
+        }
+        if traces.is_empty() {
+            return;
+        }
+        for trace in traces.iter() {
+            if self.recent_traces.len() == RECENT_TRACES {
+                self.recent_traces.pop_front();
+            }
+            self.recent_traces.push_back(trace.clone());
+        }
+        if let Some(ref exporter) = self.trace_exporter {
+            exporter.export(traces);
+        }
+    }
+
+    // Points the backends discovered through sentinel at their master's new address, for each failover reported since the last call.
+    fn follow_failovers(&mut self, completed_clients: &mut VecDeque<ClientTokenValue>) {
+        for pool_index in 0..self.backendpools.len() {
+            let moved = match self.backendpools[pool_index].sentinel {
+                Some(ref sentinel) => sentinel.pending(),
+                None => continue,
+            };
+            for (master_name, master) in moved {
+                for backend in pool_backends_mut(&self.backendpools, &mut self.backends, pool_index).iter_mut() {
+                    if backend.sentinel_master() == Some(&master_name) {
+                        backend.change_host(master, &mut self.clients, completed_clients, &mut self.stats);
+                    }
                 }
+            }
+        }
+    }
+
+    /*
+        Switches the pools with discovery over to the backends they last discovered, if those changed. The switch is
+        the same as SWITCHCONFIG's: clients stay connected, and the pool connects to its new set of backends. A config
+        staged with LOADCONFIG stays staged.
+    */
+    fn follow_discovery(&mut self) {
+        let mut discovered_config: Option<RedFlareProxyConfig> = None;
+        for pool in self.backendpools.iter() {
+            let targets = match pool.discovery.as_ref().and_then(|discovery| discovery.latest()) {
+                Some(targets) => targets,
+                None => continue,
+            };
+            let servers = discovery::discovered_servers(&pool.config.servers, &targets);
+            if servers == pool.config.servers {
+                continue;
+            }
+            let hosts: Vec<String> = targets.iter().map(|target| target.host.to_string()).collect();
+            log_event!(LogLevel::Info, "discovery_update", { pool: pool.name, backends: hosts.len() }, "Switching pool {} to its discovered backends: {}", pool.name, hosts.join(", "));
+            let config = discovered_config.get_or_insert_with(|| self.config.clone());
+            if let Some(pool_config) = config.pools.get_mut(&pool.name) {
+                pool_config.servers = servers;
+            }
+        }
+        if let Some(config) = discovered_config {
+            let staged_config = mem::replace(&mut self.staged_config, Some(config));
+            if let Err(err) = self.switch_config() {
+                error!("Failed to switch to the discovered backends. {}", err);
+            }
+            self.staged_config = staged_config;
+        }
+    }
+
+    // Appends a stats snapshot to the stats_snapshot_file, if one is due.
+    fn write_stats_snapshot(&mut self) {
+        let now = clock::now();
+        let is_due = match self.snapshotter {
+            Some(ref snapshotter) => snapshotter.is_due(now),
+            None => false,
+        };
+        if !is_due {
+            return;
+        }
+        let snapshot = self.stats_snapshot();
+        if let Some(ref mut snapshotter) = self.snapshotter {
+            match snapshotter.write_snapshot(&snapshot, now) {
+                Ok(_) => {}
                 Err(err) => {
-                    let mut response = String::new();
-                    response.push_str("-");
-                    response.push_str(&format!("{}", err));
-                    response.push_str("\r\n");
-                    self.admin.write_to_client(token, response);
+                    error!("Failed to write stats snapshot. Received error: {}", err);
                 }
             }
         }
     }
 
-    fn identify_token(&mut self, token: Token) -> SubType {
-        let num_pools = self.backendpools.len();
-        let num_backends = self.backends.len();
-        let ref value = token.0;
-        if *value == 1 {
-            return SubType::AdminListener;
+    /*
+        Formats the proxy and per-pool stats as a single line of JSON. e.g.:
+        {"timestamp":1500000000,"stats":{"accepted_clients":1,...},"pools":{"pool1":{"requests":1,...}}}
+    */
+    fn stats_snapshot(&self) -> String {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+        let memory = self.pool_memory();
+        let mut pools = Vec::with_capacity(self.backendpools.len());
+        for pool_index in 0..self.backendpools.len() {
+            let pool = &self.backendpools[pool_index];
+            let mut queues = Vec::new();
+            for backend in self.pool_backends(pool_index) {
+                for (host, queue) in backend.queue_stats(&self.cluster_backends) {
+                    queues.push((host, queue.to_json()));
+                }
+            }
+            let queues: Vec<(&str, String)> = queues.iter().map(|(host, queue)| (host.as_str(), queue.clone())).collect();
+            pools.push((pool.name.as_str(), json_object(&[
+                ("accepted_clients", pool.stats.accepted_clients.to_string()),
+                ("requests", pool.stats.requests.to_string()),
+                ("recv_client_bytes", pool.stats.recv_client_bytes.to_string()),
+                ("hedged_requests", pool.stats.hedged_requests.to_string()),
+                ("collapsed_requests", pool.stats.collapsed_requests.to_string()),
+                ("latency_us", self.pool_latency(pool_index).to_json()),
+                ("errors", self.pool_errors(pool_index).to_json()),
+                ("connections", self.pool_connections(pool_index).to_json()),
+                ("memory", memory[pool_index].to_json()),
+                ("queues", json_object(&queues)),
+            ])));
         }
-        if *value > 1 && *value < FIRST_SOCKET_INDEX {
-            return SubType::AdminClient;
+        return json_object(&[
+            ("timestamp", timestamp.to_string()),
+            ("stats", self.stats.to_json()),
+            ("event_loop", self.stats.event_loop.to_json()),
+            ("pools", json_object(&pools)),
+        ]);
+    }
+
+    fn pool_backends(&self, pool_index: PoolIndex) -> &[Backend] {
+        let pool = &self.backendpools[pool_index];
+        return &self.backends[pool.first_backend_index..pool.first_backend_index + pool.num_backends];
+    }
+
+    // Merges the latency of every backend in the pool.
+    fn pool_latency(&self, pool_index: PoolIndex) -> LatencyHistogram {
+        let mut latency = LatencyHistogram::new();
+        for backend in self.pool_backends(pool_index) {
+            latency.merge(&backend.latency(&self.cluster_backends));
         }
-        if *value >= FIRST_SOCKET_INDEX && *value < FIRST_SOCKET_INDEX + num_pools {
-            return SubType::PoolListener;
+        return latency;
+    }
+
+    // Combines the errors returned by the pool itself with the errors of each of its backends.
+    fn pool_errors(&self, pool_index: PoolIndex) -> ErrorStats {
+        let mut errors = self.backendpools[pool_index].stats.errors.clone();
+        for backend in self.pool_backends(pool_index) {
+            errors.merge(&backend.error_stats(&self.cluster_backends));
         }
-        if *value >= FIRST_SOCKET_INDEX + num_pools && *value < FIRST_SOCKET_INDEX + num_pools + num_backends {
-            return SubType::PoolServer;
+        return errors;
+    }
+
+    /*
+        Formats the error counters of each pool, split by cause. e.g.:
+        Errors:
+        pool1: timeouts=2 backend_unavailable=0 connection_failures=1 protocol_errors=0 auth_failures=0 moved_redirects=0 ask_redirects=0 error_responses=0 memory_shed=0 overloaded=0 rate_limited=0
+    */
+    fn format_errors(&self) -> String {
+        let mut output = "Errors:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            output.push_str(&format!("\n{}: {}", self.backendpools[pool_index].name, self.pool_errors(pool_index)));
         }
-        if *value >= FIRST_SOCKET_INDEX + num_pools + num_backends && *value < FIRST_SOCKET_INDEX + num_pools + 2*num_backends {
-            return SubType::Timeout;
+        return output;
+    }
+
+    // Combines the pool's client disconnects with the connection churn of each of its backends.
+    fn pool_connections(&self, pool_index: PoolIndex) -> ConnectionStats {
+        let mut connections = self.backendpools[pool_index].stats.connections.clone();
+        for backend in self.pool_backends(pool_index) {
+            connections.merge(&backend.connection_stats(&self.cluster_backends));
         }
-        if *value >= FIRST_SOCKET_INDEX + num_pools + 2*num_backends && *value < FIRST_SOCKET_INDEX + num_pools + 3*num_backends {
-            return SubType::RequestTimeout;
+        return connections;
+    }
+
+    /*
+        Formats connection churn per pool. e.g.:
+        Connections:
+        pool1: accepted_clients=10 client_disconnects=9 backend_reconnects=1 handshake_failures=0 client_read_pauses=0 shed_connections=0 denied_connections=0 idle_closed=0 connect_timeouts=0 held_requests=0 held_expired=0 quarantined_clients=0 quarantine_refused=0
+        The admin port has a line too when it has allow_clients or deny_clients, e.g. admin: denied_connections=2
+    */
+    fn format_connections(&self) -> String {
+        let mut output = "Connections:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool = &self.backendpools[pool_index];
+            output.push_str(&format!("\n{}: accepted_clients={} {}", pool.name, pool.stats.accepted_clients, self.pool_connections(pool_index)));
+        }
+        if let Some(ref admin) = self.admin {
+            if !admin.config.allow_clients.is_empty() || !admin.config.deny_clients.is_empty() {
+                output.push_str(&format!("\nadmin: denied_connections={}", admin.denied_connections));
+            }
+        }
+        return output;
+    }
+
+    /*
+        Formats the request queue of each backend host. A cluster backend has a line for each of its nodes. e.g.:
+        Queues:
+        pool1 127.0.0.1:6380: depth=3 max_depth=120 concurrency_limit=0
+    */
+    fn format_queues(&self) -> String {
+        let mut output = "Queues:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            for backend in self.pool_backends(pool_index) {
+                for (host, queue) in backend.queue_stats(&self.cluster_backends) {
+                    output.push_str(&format!("\n{} {}: {}", pool_name, host, queue));
+                }
+            }
+        }
+        return output;
+    }
+
+    /*
+        Measures the buffer and queue usage of each pool, from its clients and backends.
+    */
+    fn pool_memory(&self) -> Vec<MemoryStats> {
+        let mut memory = vec![MemoryStats::new(); self.backendpools.len()];
+        for &(ref client, pool_token_value) in self.clients.values() {
+            if let Some(pool_memory) = memory.get_mut(convert_token_to_pool_index(pool_token_value)) {
+                pool_memory.client_read_buffer_bytes += client.buf.len();
+                pool_memory.client_buffered_bytes += client.buffer().len();
+                for response in &client.get_ref().pending_response {
+                    pool_memory.client_pending_response_bytes += response.len();
+                }
+            }
+        }
+        for pool_index in 0..self.backendpools.len() {
+            for backend in self.pool_backends(pool_index) {
+                memory[pool_index].merge(&backend.memory_stats(&self.cluster_backends));
+            }
+        }
+        return memory;
+    }
+
+    /*
+        Formats the bytes held in buffers and queues, in total and per pool, and the buffer pool's usage. e.g.:
+        Memory:
+        total: client_read_buffer_bytes=16384 client_buffered_bytes=0 client_pending_response_bytes=0 ...
+        pool1: client_read_buffer_bytes=16384 client_buffered_bytes=0 client_pending_response_bytes=0 ...
+        buffer_pool: buffer_size=16384 free=2 allocated=4 reused=10
+        budget: used_bytes=32768 limit_bytes=1073741824
+    */
+    fn format_memory(&self) -> String {
+        let memory = self.pool_memory();
+        let mut total = MemoryStats::new();
+        for pool_memory in &memory {
+            total.merge(pool_memory);
+        }
+        let mut output = format!("Memory:\ntotal: {}", total);
+        for (pool, pool_memory) in self.backendpools.iter().zip(memory.iter()) {
+            output.push_str(&format!("\n{}: {}", pool.name, pool_memory));
+        }
+        output.push_str(&format!("\nbuffer_pool: {}", bufferpool::stats()));
+        output.push_str(&format!("\nbudget: used_bytes={} limit_bytes={}", memory::used(), memory::limit()));
+        return output;
+    }
+
+    /*
+        Formats the hottest keys of each pool with hot key detection enabled, with their estimated request counts. e.g.:
+        pool1 user:1234: 5300
+        pool1 user:42: 1200
+    */
+    fn format_hot_keys(&self) -> String {
+        let mut lines = Vec::new();
+        for pool in &self.backendpools {
+            match pool.hotkeys {
+                Some(ref hotkeys) => {
+                    for (key, count) in hotkeys.top_keys() {
+                        lines.push(format!("{} {}: {}", pool.name, String::from_utf8_lossy(&key), count));
+                    }
+                }
+                None => {}
+            }
+        }
+        if lines.is_empty() {
+            return "No hot keys recorded.".to_owned();
         }
-        if *value >= FIRST_CLUSTER_BACKEND_INDEX {
-            return SubType::ClusterServer;
+        return lines.join("\n");
+    }
+
+    /*
+        Formats the read cache of each pool that has one. e.g.:
+        pool1: entries=120 bytes=5230 max_bytes=1048576 hits=300 misses=120 fills=120 evictions=0 expirations=4 invalidations=2 flushes=0 stale_hits=0
+    */
+    fn format_caches(&self) -> String {
+        let mut lines = Vec::new();
+        for pool in &self.backendpools {
+            if let Some(ref cache) = pool.cache {
+                lines.push(format!("{}: {}", pool.name, cache.borrow().format()));
+            }
+        }
+        if lines.is_empty() {
+            return "No pool has a read cache.".to_owned();
         }
-        return SubType::PoolClient;
+        return lines.join("\n");
+    }
+
+    /*
+        Formats the phase timings of the most recently traced requests, oldest first. e.g.:
+        pool1 GET 127.0.0.1:6380: total_us=160 parse_us=12 queue_wait_us=20 backend_rtt_us=110 flush_us=18
+    */
+    /*
+        Formats the slow requests of every backend, newest first. e.g.:
+        pool1 127.0.0.1:6380: time=2018-06-01T12:00:00Z duration_us=15230 command=GET payload="GET user:1"
+    */
+    fn format_slow_requests(&self) -> String {
+        let mut slow_requests = Vec::new();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            for backend in self.pool_backends(pool_index) {
+                for slow_request in backend.slow_requests(&self.cluster_backends) {
+                    slow_requests.push((pool_name, slow_request));
+                }
+            }
+        }
+        if slow_requests.is_empty() {
+            return "No slow requests recorded.".to_owned();
+        }
+        slow_requests.sort_by(|a, b| b.1.start_time.cmp(&a.1.start_time));
+        let lines: Vec<String> = slow_requests.iter().map(|&(pool_name, ref slow_request)| slow_request.format(pool_name)).collect();
+        return lines.join("\n");
+    }
+
+    /*
+        Sends INFO to every backend host of the pool, and returns a table of their key fields. Each host is queried on a
+        new connection, one at a time, so this blocks the event loop until every host has answered or timed out.
+    */
+    fn start_bench(&mut self, pool_name: &str, args: &[&str]) -> String {
+        if let Some(ref bench) = self.bench {
+            if bench.is_running() {
+                return format!("A benchmark is already running for {}", bench.pool);
+            }
+        }
+        let config = match BenchConfig::parse(args) {
+            Ok(config) => config,
+            Err(err) => return err,
+        };
+        let pool = match self.backendpools.iter().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        let mut addr = match pool.listen_socket.as_ref().map(|socket| socket.local_addr()) {
+            Some(Ok(addr)) => addr,
+            _ => return format!("Pool {} isn't listening", pool_name),
+        };
+        // A pool listening on every interface is benchmarked over loopback.
+        if addr.ip().is_unspecified() {
+            addr.set_ip("127.0.0.1".parse().unwrap());
+        }
+        let bench = match Bench::start(pool_name, addr, config.clone()) {
+            Ok(bench) => bench,
+            Err(err) => return err,
+        };
+        info!(target: admin::LOG_TARGET, "Started benchmark of {} at {}: {:?}", pool_name, addr, config);
+        self.bench = Some(bench);
+        return format!(
+            "Started benchmark of {}: {} requests from {} clients, {}% SET, {} byte values",
+            pool_name, config.requests, config.clients, config.set_percent, config.value_size
+        );
+    }
+
+    fn stop_bench(&mut self) -> String {
+        match self.bench {
+            Some(ref bench) if bench.is_running() => {
+                bench.stop();
+                return "OK".to_owned();
+            }
+            _ => return "No benchmark is running.".to_owned(),
+        }
+    }
+
+    fn format_bench(&self) -> String {
+        match self.bench {
+            Some(ref bench) => return bench.format(),
+            None => return "No benchmark has been run.".to_owned(),
+        }
+    }
+
+    fn format_backend_info(&self, pool_name: &str) -> String {
+        let pool_index = match self.backendpools.iter().position(|pool| pool.name == pool_name) {
+            Some(pool_index) => pool_index,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        let mut rows = Vec::new();
+        for backend in self.pool_backends(pool_index) {
+            for (host, auth) in backend.hosts(&self.cluster_backends) {
+                let info = backendinfo::fetch_info(host, auth.as_ref().map(|auth| &auth[..])).map(|info| BackendInfo::from_info(&info));
+                rows.push((host.to_string(), info));
+            }
+        }
+        return backendinfo::format_table(&rows);
+    }
+
+    /*
+        Starts capturing the traffic of a pool's clients to a new file in capture_dir. One in every sample_rate client
+        connections is captured (default 1: all of them), including the ones already connected. max_size accepts K/M/G
+        suffixes, and defaults to capture_max_bytes. Returns the path of the file.
+    */
+    // Lists each pool with a standby, the pool serving its clients, and whether an override pins them there.
+    fn format_failovers(&self) -> String {
+        let mut output = String::new();
+        for pool in self.backendpools.iter() {
+            if let (Some(ref failover), Some(ref standby)) = (pool.failover.as_ref(), pool.config.standby.as_ref()) {
+                let serving = if failover.on_standby() { standby.as_str() } else { pool.name.as_str() };
+                output.push_str(&format!("{}: serving from {}, mode {:?}\n", pool.name, serving, failover.mode()));
+            }
+        }
+        if output.is_empty() {
+            return "No pool has a standby".to_owned();
+        }
+        return output;
+    }
+
+    /*
+        Pins the clients of a pool to it or to its standby with PRIMARY or STANDBY, e.g. to fail back a pool with Manual
+        failback or to drain it for maintenance, and returns to automatic failovers with AUTO.
+    */
+    fn set_failover_mode(&mut self, pool_name: &str, mode: &str) -> String {
+        let mode = match FailoverMode::parse(mode) {
+            Some(mode) => mode,
+            None => return format!("Unknown mode: {}. Expected one of AUTO, PRIMARY, STANDBY", mode),
+        };
+        let pool = match self.backendpools.iter_mut().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        match pool.failover {
+            Some(ref mut failover) => failover.set_mode(mode),
+            None => return format!("Pool {} has no standby", pool_name),
+        }
+        info!(target: admin::LOG_TARGET, "Set the failover mode of {} to {:?}", pool_name, mode);
+        return "OK".to_owned();
+    }
+
+    fn start_capture(&mut self, pool_name: &str, sample_rate: Option<&str>, max_size: Option<&str>) -> String {
+        let sample_rate = match sample_rate.map(|rate| rate.parse::<usize>()) {
+            None => 1,
+            Some(Ok(rate)) if rate > 0 => rate,
+            Some(_) => return format!("Invalid sample rate: {}", sample_rate.unwrap()),
+        };
+        let max_bytes = match max_size {
+            None => self.config.capture_max_bytes as u64,
+            Some(size) => match logging::parse_size(size) {
+                Some(size) => size,
+                None => return format!("Invalid size: {}", size),
+            },
+        };
+        let pool_index = match self.backendpools.iter().position(|pool| pool.name == pool_name) {
+            Some(pool_index) => pool_index,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        if let Some(ref capture) = self.backendpools[pool_index].capture {
+            if capture.borrow().active {
+                return format!("A capture is already running for {}: {}", pool_name, capture.borrow().path);
+            }
+        }
+        let capture = match Capture::start(&self.config.capture_dir, pool_name, sample_rate, max_bytes) {
+            Ok(capture) => Rc::new(RefCell::new(capture)),
+            Err(err) => return format!("Failed to start capture: {}", err),
+        };
+        let pool_token_value = self.backendpools[pool_index].token.0;
+        for (client, client_pool_token_value) in self.clients.values_mut() {
+            if *client_pool_token_value == pool_token_value && capture.borrow_mut().sample_client() {
+                client.get_mut().capture = Some(capture.clone());
+            }
+        }
+        let path = capture.borrow().path.clone();
+        info!(target: admin::LOG_TARGET, "Started capture of {} to {}", pool_name, path);
+        self.backendpools[pool_index].capture = Some(capture);
+        return path;
+    }
+
+    fn stop_capture(&mut self, pool_name: &str) -> String {
+        let pool = match self.backendpools.iter_mut().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        match pool.capture.take() {
+            Some(capture) => {
+                let mut capture = capture.borrow_mut();
+                capture.stop();
+                info!(target: admin::LOG_TARGET, "Stopped capture of {}: {}", pool_name, capture.format());
+                capture.format()
+            }
+            None => format!("No capture is running for {}", pool_name),
+        }
+    }
+
+    // One line per pool with a capture, e.g. pool1: path=/tmp/pool1-20190301T120000Z.capture active=true frames=120 bytes=5230
+    fn format_captures(&self) -> String {
+        let mut lines = Vec::new();
+        for pool in self.backendpools.iter() {
+            if let Some(ref capture) = pool.capture {
+                lines.push(format!("{}: {}", pool.name, capture.borrow().format()));
+            }
+        }
+        if lines.is_empty() {
+            return "No captures running.".to_owned();
+        }
+        return lines.join("\n");
+    }
+
+    // Starts or stops injecting the faults of a pool's chaos config. Requests already held back or dropped still are.
+    fn set_chaos_active(&mut self, pool_name: &str, active: bool) -> String {
+        let pool = match self.backendpools.iter().find(|pool| pool.name == pool_name) {
+            Some(pool) => pool,
+            None => return format!("Unknown pool: {}", pool_name),
+        };
+        match pool.chaos {
+            Some(ref chaos) => chaos.borrow_mut().active = active,
+            None => return format!("Pool {} has no chaos configured", pool_name),
+        }
+        if active {
+            warn!(target: admin::LOG_TARGET, "Started injecting faults into pool {}", pool_name);
+        } else {
+            info!(target: admin::LOG_TARGET, "Stopped injecting faults into pool {}", pool_name);
+        }
+        return "OK".to_owned();
+    }
+
+    // One line per pool with chaos configured, e.g. pool1: active=true delays=12 disconnects=1 drops=3 errors=5
+    fn format_chaos(&self) -> String {
+        let mut lines = Vec::new();
+        for pool in self.backendpools.iter() {
+            if let Some(ref chaos) = pool.chaos {
+                lines.push(format!("{}: {}", pool.name, chaos.borrow().format()));
+            }
+        }
+        if lines.is_empty() {
+            return "No pools have chaos configured.".to_owned();
+        }
+        return lines.join("\n");
+    }
+
+    fn format_timings(&self) -> String {
+        if self.recent_traces.is_empty() {
+            return "No timing samples recorded.".to_owned();
+        }
+        let lines: Vec<String> = self.recent_traces.iter().map(|trace| trace.format_timing()).collect();
+        return lines.join("\n");
+    }
+
+    /*
+        Formats the error counters of each backend. e.g.:
+        Backend errors:
+        pool1 127.0.0.1:6380: timeouts=0 backend_unavailable=0 connection_failures=1 protocol_errors=0 auth_failures=0 moved_redirects=0 ask_redirects=0 error_responses=0 memory_shed=0 overloaded=0 rate_limited=0
+    */
+    fn format_backend_errors(&self) -> String {
+        let mut output = "Backend errors:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            for backend in self.pool_backends(pool_index) {
+                output.push_str(&format!("\n{} {}: {}", pool_name, backend.name(), backend.error_stats(&self.cluster_backends)));
+            }
+        }
+        return output;
+    }
+
+    /*
+        Formats the largest request and response sizes, in bytes, seen by each backend. e.g.:
+        Big values:
+        pool1 127.0.0.1:6380: max_request_bytes=2048 max_response_bytes=1048576
+    */
+    fn format_big_values(&self) -> String {
+        let mut output = "Big values:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            for backend in self.pool_backends(pool_index) {
+                let (max_request_bytes, max_response_bytes) = max_value_sizes(&backend.command_stats(&self.cluster_backends));
+                output.push_str(&format!("\n{} {}: max_request_bytes={} max_response_bytes={}", pool_name, backend.name(), max_request_bytes, max_response_bytes));
+            }
+        }
+        return output;
+    }
+
+    // Combines the pool's request counts with the error and latency stats of each of its backends.
+    fn pool_command_stats(&self, pool_index: PoolIndex) -> BTreeMap<&'static str, CommandStats> {
+        let mut commands = BTreeMap::new();
+        merge_command_stats(&mut commands, &self.backendpools[pool_index].stats.commands);
+        for backend in self.pool_backends(pool_index) {
+            merge_command_stats(&mut commands, &backend.command_stats(&self.cluster_backends));
+        }
+        return commands;
+    }
+
+    /*
+        Formats requests, errors, largest request and response sizes in bytes, and latency in microseconds per command, for each pool. e.g.:
+        Commands:
+        pool1 GET: requests=10 errors=0 max_request_bytes=27 max_response_bytes=9 latency_us: count=10 p50=120 p95=200 p99=250 p999=250
+    */
+    fn format_command_stats(&self) -> String {
+        let mut output = "Commands:".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            for (command, command_stats) in self.pool_command_stats(pool_index) {
+                output.push_str(&format!("\n{} {}: {}", pool_name, command, command_stats));
+            }
+        }
+        return output;
+    }
+
+    /*
+        Formats request latency in microseconds, per pool and per backend. e.g.:
+        Latency (us):
+        pool1: count=10 p50=120 p95=200 p99=250 p999=250
+        pool1 127.0.0.1:6380: count=10 p50=120 p95=200 p99=250 p999=250
+    */
+    fn format_latency(&self) -> String {
+        let mut output = "Latency (us):".to_owned();
+        for pool_index in 0..self.backendpools.len() {
+            let pool_name = &self.backendpools[pool_index].name;
+            output.push_str(&format!("\n{}: {}", pool_name, self.pool_latency(pool_index)));
+            for backend in self.pool_backends(pool_index) {
+                output.push_str(&format!("\n{} {}: {}", pool_name, backend.name(), backend.latency(&self.cluster_backends)));
+            }
+        }
+        return output;
     }
 }
 
 pub fn convert_token_to_pool_index(token_value: PoolTokenValue) -> PoolIndex {
-    return token_value - FIRST_SOCKET_INDEX;
-}
-pub fn convert_token_to_backend_index(token_value: BackendTokenValue, num_pools: usize) -> BackendIndex {
-    return token_value - FIRST_SOCKET_INDEX - num_pools;
-}
-pub fn convert_token_to_timeout_index(token_value: TimeoutTokenValue, num_pools: usize, num_backends: usize) -> usize {
-    return token_value - FIRST_SOCKET_INDEX - num_pools - num_backends;
+    return tokens::index(Token(token_value));
 }
-pub fn convert_token_to_requesttimeout_index(token_value: RequestTimeoutTokenValue, num_pools: usize, num_backends: usize) -> usize {
-    return token_value - FIRST_SOCKET_INDEX - num_pools - 2*num_backends;
+pub fn convert_token_to_backend_index(token_value: BackendTokenValue) -> BackendIndex {
+    return tokens::index(Token(token_value));
 }
 pub fn convert_token_to_cluster_index(token_value: ClusterTokenValue) -> usize {
-    return token_value - FIRST_CLUSTER_BACKEND_INDEX;
+    return tokens::index(Token(token_value));
 }
 
 /*
@@ -727,35 +2516,126 @@ fn handle_client(
     completed_clients: &mut VecDeque<ClientTokenValue>,
     stats: &mut Stats,
     remove_client_if_empty: bool,
+    flush: bool,
 ) {
-    let num_pools = backendpools.len();
-    match clients.get_mut(&token.0) {
+    let (pool_index, keep_client) = match clients.get_mut(&token.0) {
         Some((client, pool_token_value)) => {
-            if client.get_ref().pending_count > 0 {
-                return;
+            if client.get_ref().is_paused() {
+                // Events of a paused client, e.g. a hang up, are handled once a backend resumes it.
+                if remove_client_if_empty {
+                    return;
+                }
+                if let Err(err) = client.get_mut().resume() {
+                    error!("Failed to resume client {:?}: {:?}", token, err);
+                }
             }
-            let pool_index = *pool_token_value - FIRST_SOCKET_INDEX;
-            let start_backend_index = backendpools.get(pool_index).unwrap().first_backend_index - FIRST_SOCKET_INDEX - num_pools;
-            let last_index = start_backend_index + backendpools.get(pool_index).unwrap().num_backends;
-            let backends = match backends.get_mut(start_backend_index..last_index) {
-                Some(b) => b,
-                None => panic!("Unable to get full backends from {:?} to {:?}", start_backend_index, last_index),
-            };
-            if handle_client_readable(&mut backendpools.get_mut(pool_index).unwrap(), client, *token, backends, cluster_backends, completed_clients, stats) || !remove_client_if_empty {
+            if client.get_ref().pending_count > 0 {
                 return;
             }
+            let pool_index = serving_pool_index(backendpools, backends, convert_token_to_pool_index(*pool_token_value));
+            let backends = pool_backends_mut(backendpools, backends, pool_index);
+            let readable = handle_client_readable(backendpools.get_mut(pool_index).unwrap(), client, *token, backends, cluster_backends, completed_clients, stats);
+            (pool_index, readable || !remove_client_if_empty)
+        }
+        None => {
+            debug!("An event occurred for an expired client: {:?}", token);
+            return;
+        }
+    };
+
+    // The client's pipelined requests were buffered per backend, so that each backend gets a single write. With
+    // batch_dispatch, they're written along with the other clients' at the end of the iteration instead.
+    if flush {
+        for backend in pool_backends_mut(backendpools, backends, pool_index).iter_mut() {
+            backend.flush_output(clients, cluster_backends, completed_clients, stats);
         }
-        None => { debug!("An event occurred for an expired client: {:?}", token); }
+    }
+    if keep_client {
+        return;
     }
 
     debug!("Removing client: {:?}", token);
-    clients.remove(&token.0);
+    if let Some((_, pool_token_value)) = clients.remove(&token.0) {
+        if let Some(pool) = backendpools.get_mut(convert_token_to_pool_index(pool_token_value)) {
+            pool.stats.connections.client_disconnects += 1;
+        }
+    }
 }
 
+/*
+    The pool that the clients of a pool are sent to: its standby while it has failed over, otherwise the pool itself.
+    Checked on each read from a client, so that a failover applies from the client's next request.
+*/
+fn serving_pool_index(backendpools: &mut [BackendPool], backends: &[Backend], pool_index: PoolIndex) -> PoolIndex {
+    let standby_index = match backendpools[pool_index].failover {
+        Some(ref failover) => match failover.standby_index {
+            Some(standby_index) => standby_index,
+            None => return pool_index,
+        },
+        None => return pool_index,
+    };
+    let count_available = |pool: &BackendPool| {
+        backends[pool.first_backend_index..pool.first_backend_index + pool.num_backends].iter().filter(|backend| backend.is_available()).count()
+    };
+    let available = count_available(&backendpools[pool_index]);
+    let standby_available = count_available(&backendpools[standby_index]);
+    let pool = &mut backendpools[pool_index];
+    let num_backends = pool.num_backends;
+    let failover = pool.failover.as_mut().unwrap();
+    let was_on_standby = failover.on_standby();
+    let on_standby = failover.update(available, num_backends, standby_available, clock::now());
+    if on_standby && !was_on_standby {
+        log_event!(LogLevel::Warn, "pool_failover", { pool: pool.name }, "Sending the clients of pool {} to its standby", pool.name);
+    } else if !on_standby && was_on_standby {
+        log_event!(LogLevel::Warn, "pool_failback", { pool: pool.name }, "Sending the clients of pool {} back to it from its standby", pool.name);
+    }
+    if on_standby {
+        return standby_index;
+    }
+    return pool_index;
+}
+
+fn pool_backends_mut<'a>(backendpools: &[BackendPool], backends: &'a mut [Backend], pool_index: usize) -> &'a mut [Backend] {
+    let start_backend_index = backendpools.get(pool_index).unwrap().first_backend_index;
+    let last_index = start_backend_index + backendpools.get(pool_index).unwrap().num_backends;
+    match backends.get_mut(start_backend_index..last_index) {
+        Some(b) => b,
+        None => panic!("Unable to get full backends from {:?} to {:?}", start_backend_index, last_index),
+    }
+}
+
+// The host of a backend or cluster backend token, for timers of a single host. None for a pool's redis cluster.
+fn single_backend_mut<'a>(
+    backends: &'a mut [Backend],
+    cluster_backends: &'a mut [(SingleBackend, usize)],
+    token: BackendToken,
+) -> Option<&'a mut SingleBackend> {
+    if tokens::kind(token) == TokenKind::ClusterServer {
+        return cluster_backends.get_mut(convert_token_to_cluster_index(token.0)).map(|(backend, _)| backend);
+    }
+    match backends.get_mut(convert_token_to_backend_index(token.0)) {
+        Some(Backend { single: BackendEnum::Single(backend), .. }) => return Some(backend),
+        _ => return None,
+    }
+}
 
 /*
 Initializes a backend pool, establishes a connection.
 */
+// Loads the filters configured for each pool, by pool name.
+fn load_configured_filters(config: &RedFlareProxyConfig) -> Result<BTreeMap<String, Vec<Box<dyn Filter>>>, ProxyError> {
+    let mut filters = BTreeMap::new();
+    for (pool_name, pool_config) in &config.pools {
+        match filter::from_config(pool_config) {
+            Ok(pool_filters) => {
+                filters.insert(pool_name.clone(), pool_filters);
+            }
+            Err(err) => return Err(ProxyError::FilterLoadFailure(pool_name.clone(), err)),
+        }
+    }
+    return Ok(filters);
+}
+
 fn init_backend_pool(
     backendpools: &mut Vec<BackendPool>,
     backends: &mut Vec<Backend>,
@@ -763,24 +2643,46 @@ fn init_backend_pool(
     pool_config: &BackendPoolConfig,
     enable_advanced_commands: bool,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
-    next_backend_token_value: &mut usize,
-    pool_token_value: usize,
-    poll: &Rc<RefCell<Poll>>,
+    next_backend_index: &mut BackendIndex,
+    pool_index: PoolIndex,
+    registry: &Registry,
+    timers: &Timers,
     num_backends: usize,
+    reuse_port: bool,
 ) -> Result<(), ProxyError> {
-    let pool_token = Token(pool_token_value);
-    let mut pool = backendpool::BackendPool::new(pool_name.clone(), pool_token, pool_config.clone(), enable_advanced_commands, *next_backend_token_value);
+    let pool_token = tokens::token(TokenKind::PoolListener, pool_index);
+    let mut pool = backendpool::BackendPool::new(pool_name.clone(), pool_token, pool_config.clone(), enable_advanced_commands, *next_backend_index);
+    pool.timers = Some(timers.clone());
 
-    let mut backend_token_value = *next_backend_token_value;
+    let mut backend_index = *next_backend_index;
 
-    *next_backend_token_value += pool_config.servers.len();
+    *next_backend_index += pool_config.servers.len();
     
-    try!(pool.connect(&mut poll.borrow_mut()));
+    try!(pool.connect(registry, reuse_port));
 
-    for backend_config in pool_config.servers.clone() {
-        let backend = init_backend(backend_config, pool_config, cluster_backends, pool_token_value, backend_token_value, poll, num_backends, &pool.cached_backend_shards);
+    for mut backend_config in pool_config.servers.clone() {
+        if let Some(ref master_name) = backend_config.sentinel_master {
+            match sentinel::discover_master(master_name, &backend_config.sentinel_hosts) {
+                Ok(master) => backend_config.host = Some(master),
+                Err(err) => return Err(ProxyError::SentinelDiscoveryFailure(master_name.clone(), err)),
+            }
+        }
+        let addresses = match backend_config.hostname {
+            Some(ref hostname) => match happyeyeballs::resolve(hostname) {
+                Ok(addresses) => addresses,
+                Err(err) => return Err(ProxyError::HostnameResolutionFailure(hostname.clone(), err)),
+            },
+            None => Vec::new(),
+        };
+        if let Some(&first) = addresses.first() {
+            backend_config.host = Some(first);
+        }
+        let mut backend = init_backend(backend_config, addresses, pool_config, cluster_backends, pool_token.0, backend_index, registry, timers, num_backends, &pool.cached_backend_shards);
+        if let Some(ref chaos) = pool.chaos {
+            backend.set_chaos(chaos, cluster_backends);
+        }
         backends.push(backend);
-        backend_token_value += 1;
+        backend_index += 1;
     }
 
     backendpools.push(pool);
@@ -789,30 +2691,45 @@ fn init_backend_pool(
 
 fn init_backend(
     backend_config: BackendConfig,
+    addresses: Vec<SocketAddr>,
     pool_config: &BackendPoolConfig,
     cluster_backends: &mut Vec<(SingleBackend, usize)>,
     pool_token_value: usize,
-    backend_token_value: usize,
-    poll_registry: &Rc<RefCell<Poll>>,
+    backend_index: BackendIndex,
+    registry: &Registry,
+    timers: &Timers,
     num_backends: usize,
     cached_backend_shards: &Rc<RefCell<Option<Vec<usize>>>>,
 ) -> Backend {
     // Initialize backends.
-    let backend_token = Token(backend_token_value);
-    let mut next_cluster_token_value = FIRST_CLUSTER_BACKEND_INDEX + cluster_backends.len();
+    let backend_token = tokens::token(TokenKind::PoolServer, backend_index);
+    let mut next_cluster_index = cluster_backends.len();
     let (mut backend, _all_backend_tokens) = Backend::new(
         backend_config,
         backend_token,
         cluster_backends,
-        poll_registry,
-        &mut next_cluster_token_value,
+        registry,
+        timers,
+        &mut next_cluster_index,
         pool_config.timeout,
         pool_config.failure_limit,
-        pool_config.retry_timeout,
+        RetryPolicy::from_config(pool_config),
+        QueueLimits::from_config(pool_config),
+        ConcurrencyLimiter::from_config(pool_config),
+        pool_config.big_value_threshold,
+        SlowLog::from_config(pool_config),
         pool_token_value,
         num_backends,
         cached_backend_shards,
+        pool_config.replica_fallback,
+        pool_config.hedge_delay > 0,
+        pool_config.dual_write,
     );
+    backend.set_addresses(addresses);
+    backend.set_outage_buffer(OutageBuffer::from_config(pool_config));
+    if pool_config.backend_tcp_fastopen {
+        backend.enable_fastopen(cluster_backends);
+    }
     backend.init_connection(cluster_backends);
     return backend;
 }
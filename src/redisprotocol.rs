@@ -2,7 +2,7 @@
 use {init_logging, init_logging_info};
 #[cfg(test)]
 use cluster_backend::Host;
-use memchr::memchr;
+use resp::{self, Args, Limit, Limits, ParseError};
 use std::result::Result;
 
 #[cfg(test)]
@@ -17,6 +17,8 @@ pub enum WriteError {
     NoSocket,
     BufOutOfBounds,
     BackendNotReady,
+    // The backend host is at its adaptive concurrency limit.
+    Overloaded,
     WriteFailure(Option<SocketAddr>, std::io::Error),
 }
 impl fmt::Display for WriteError {
@@ -25,6 +27,7 @@ impl fmt::Display for WriteError {
             WriteError::NoSocket => write!(f, "No TcpStream for this backend"),
             WriteError::BufOutOfBounds => write!(f, "This should be impossible. Somehow send wrote more bytes than the buffer size"),
             WriteError::BackendNotReady => write!(f, "Backend is not available."),
+            WriteError::Overloaded => write!(f, "Backend is at its concurrency limit."),
             WriteError::WriteFailure(ref s, ref e) => write!(f, "Failed to write to stream: {:?}. Received error: {}.", s, e),
         }
     }
@@ -35,6 +38,7 @@ impl error::Error for WriteError {
             WriteError::NoSocket => None,
             WriteError::BufOutOfBounds => None,
             WriteError::BackendNotReady => None,
+            WriteError::Overloaded => None,
             WriteError::WriteFailure(_, ref e) => Some(e),
         }
     }
@@ -43,7 +47,6 @@ impl error::Error for WriteError {
 #[derive(Debug, PartialEq)]
 pub enum RedisError {
     NoBackend,
-    Unknown(Vec<u8>),
     UnsupportedCommand,
     InvalidScript,
     InvalidProtocol,
@@ -52,18 +55,16 @@ pub enum RedisError {
     MissingArgsMget,
     MissingArgsMset,
     WrongArgsMset,
+    // An XREAD or XREADGROUP that doesn't read exactly one stream.
+    InvalidStreams,
+    // A blocking command, e.g. BLPOP or XREAD with BLOCK, which would hold up the backend connection it shares.
+    BlockingCommand,
+    // A request over one of its pool's size limits.
+    TooLarge(Limit),
 }
 impl fmt::Display for RedisError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            RedisError::Unknown(v) => {
-                match std::str::from_utf8(v) {
-                    Ok(s) => write!(f, "RedisError::Unknown {}", s),
-                    Err(_err) => write!(f, "RedisError::Unknown {:?}", v),
-                }
-            }
-            err => write!(f, "{:?}", err),
-        }
+        write!(f, "{:?}", self)
     }
 }
 
@@ -80,6 +81,12 @@ enum KeyPosition {
     MultiInterleaved,
     Unsupported,
     Eval,
+    // The key follows a subcommand, e.g. XGROUP CREATE key group id.
+    Subcommand,
+    // The keys follow STREAMS, after the given number of leading arguments, e.g. XREADGROUP's GROUP group consumer.
+    Streams(usize),
+    // Blocking commands, e.g. BLPOP, which aren't supported.
+    Blocking,
 }
 
 #[test]
@@ -91,12 +98,38 @@ fn test_parsing_redis() {
     let req = b"*5\r\n$4\r\nEVAL\r\n$40\r\nreturn redis.call('set',KEYS[1],ARGV[1])\r\n$1\r\n1\r\n$5\r\nkey10\r\n$7\r\nvalue10";
     let res = extract_key(req);
     assert_eq!(res, Ok(KeyPos::Single(b"key10")));
+    // Functions are sent to the backend of their key, like scripts.
+    let req = b"*4\r\n$8\r\nFCALL_RO\r\n$4\r\nfunc\r\n$1\r\n1\r\n$5\r\nkey10\r\n";
+    assert_eq!(extract_key(req), Ok(KeyPos::Single(b"key10")));
+    let req = b"*5\r\n$5\r\nFCALL\r\n$4\r\nfunc\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\nb\r\n";
+    assert_eq!(extract_key(req), Err(RedisError::InvalidScript));
     let req = b"*4\r\n$4\r\nMGET\r\n$2\r\nab\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n";
     let res = extract_key(req);
     assert_eq!(res, Ok(KeyPos::Multi(vec!(b"ab", b"key2", b"key3"))));
     let req = b"*5\r\n$4\r\nMSET\r\n$2\r\nab\r\n$2\r\ncd\r\n$4\r\nkey2\r\n$0\r\n\r\n";
     let res = extract_key(req);
     assert_eq!(res, Ok(KeyPos::MultiSet(vec!((b"ab", b"cd"), (b"key2", b"")))));
+    // Stream reads are sent to the backend of their stream.
+    let req = b"*6\r\n$5\r\nXREAD\r\n$5\r\nCOUNT\r\n$3\r\n100\r\n$7\r\nstreams\r\n$1\r\ns\r\n$1\r\n$\r\n";
+    assert_eq!(extract_key(req), Ok(KeyPos::Single(b"s")));
+    let req = b"*6\r\n$5\r\nXREAD\r\n$5\r\nblock\r\n$3\r\n100\r\n$7\r\nSTREAMS\r\n$1\r\ns\r\n$1\r\n$\r\n";
+    assert_eq!(extract_key(req), Err(RedisError::BlockingCommand));
+    let req = b"*9\r\n$10\r\nXREADGROUP\r\n$5\r\nGROUP\r\n$1\r\ng\r\n$5\r\nBLOCK\r\n\
+        $5\r\nBLOCK\r\n$1\r\n0\r\n$7\r\nSTREAMS\r\n$1\r\ns\r\n$1\r\n>\r\n";
+    assert_eq!(extract_key(req), Err(RedisError::BlockingCommand));
+    // Other blocking commands are rejected in the same way, even with keys that already have something to pop.
+    assert_eq!(extract_key(b"*3\r\n$5\r\nBLPOP\r\n$1\r\nl\r\n$1\r\n0\r\n"), Err(RedisError::BlockingCommand));
+    assert_eq!(extract_key(b"*3\r\n$8\r\nbzpopmin\r\n$1\r\nz\r\n$1\r\n0\r\n"), Err(RedisError::BlockingCommand));
+    let req = b"*7\r\n$10\r\nXREADGROUP\r\n$5\r\nGROUP\r\n$1\r\ng\r\n$7\r\nSTREAMS\r\n$7\r\nSTREAMS\r\n$1\r\ns\r\n$1\r\n>\r\n";
+    assert_eq!(extract_key(req), Ok(KeyPos::Single(b"s")));
+    let req = b"*7\r\n$5\r\nXREAD\r\n$7\r\nSTREAMS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\n0\r\n$1\r\n0\r\n";
+    assert_eq!(extract_key(req), Err(RedisError::InvalidStreams));
+    assert_eq!(extract_key(b"*3\r\n$5\r\nXREAD\r\n$1\r\ns\r\n$1\r\n0\r\n"), Err(RedisError::InvalidStreams));
+    let req = b"*5\r\n$6\r\nXGROUP\r\n$6\r\nCREATE\r\n$1\r\ns\r\n$1\r\ng\r\n$1\r\n$\r\n";
+    assert_eq!(extract_key(req), Ok(KeyPos::Single(b"s")));
+    // Requests that aren't a command array are rejected, rather than crashing the proxy.
+    assert_eq!(extract_key(b"+OK\r\n"), Err(RedisError::InvalidProtocol));
+    assert_eq!(extract_key(b"*0\r\n"), Err(RedisError::InvalidProtocol));
 }
 
 #[test]
@@ -114,41 +147,26 @@ fn test_parsing_speed() {
     info!("Time spent with extract_key: {:?}", Instant::now() - start);
 }
 
-fn skip_past_eol(bytes: &[u8], index: &mut usize) -> Result<(), RedisError> {
-    let bytes2 = unsafe { bytes.get_unchecked(*index..) };
-    match memchr('\n' as u8, bytes2) {
-        Some(delta) => {
-            *index += delta + 1;
-            return Ok(());
-        }
-        None => {
-            return Err(RedisError::Unknown(bytes.to_vec()));
-        }
-    }
-}
-
-pub fn interpret_num(bytes: &[u8], index: &mut usize) -> Result<isize, RedisError> {
+// Reads the number at index, which ends at a '\r'. Used for CLUSTER SLOTS responses, which were framed already.
+fn interpret_num(bytes: &[u8], index: &mut usize) -> Result<isize, RedisError> {
+    let start = *index;
     let mut negative = false;
-    let mut result = 0;
+    let mut result: isize = 0;
     loop {
-        match unsafe {*bytes.get_unchecked(*index) as char} {
-            '0' => { result = result * 10; }
-            '1' => { result = result * 10 + 1; }
-            '2' => { result = result * 10 + 2; }
-            '3' => { result = result * 10 + 3; }
-            '4' => { result = result * 10 + 4; }
-            '5' => { result = result * 10 + 5; }
-            '6' => { result = result * 10 + 6; }
-            '7' => { result = result * 10 + 7; }
-            '8' => { result = result * 10 + 8; }
-            '9' => { result = result * 10 + 9; }
-            '-' => { negative = true; }
-            '\r' => {
-                if negative {
-                    return Ok(-result);
-                } else {
-                    return Ok(result);
-                }
+        let next = match bytes.get(*index) {
+            Some(next) => *next,
+            None => { return Err(RedisError::IncompleteMessage); }
+        };
+        match next {
+            b'-' if *index == start => { negative = true; }
+            b'\r' if *index > start => {
+                return Ok(if negative { -result } else { result });
+            }
+            _ if next.is_ascii_digit() => {
+                result = match result.checked_mul(10).and_then(|result| result.checked_add((next - b'0') as isize)) {
+                    Some(result) => result,
+                    None => { return Err(RedisError::InvalidProtocol); }
+                };
             }
             _ => {
                 return Err(RedisError::InvalidProtocol);
@@ -191,240 +209,206 @@ fn test_extract_redis_command() {
     assert_eq!(resp, Ok("*3\r\n*3\r\n:10922\r\n:14624\r\n*3\r\n$9\r\n127.0.0.1\r\n:7002\r\n$40\r\ncb0a0a8d38708ce6369a969854e6076e3b3133f5\r\n".as_bytes()));
 }
 
+#[test]
+fn test_extract_incomplete_command() {
+    // Requests cut off anywhere, e.g. at the end of a read, are incomplete rather than invalid.
+    let request = b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n";
+    for len in 1..request.len() {
+        assert_eq!(extract_redis_command(&request[..len]), Err(RedisError::IncompleteMessage), "{}", len);
+    }
+    assert_eq!(extract_redis_command(request), Ok(&request[..]));
+}
+
+// Returns the first whole request or response in bytes. See resp.rs.
 pub fn extract_redis_command(bytes: &[u8]) -> Result<&[u8], RedisError> {
-    let mut index = 0;
-    try!(parse_redis_request(bytes, &mut index));
-    return unsafe { Ok(bytes.get_unchecked(0..index)) };
+    match resp::frame_len(bytes) {
+        Ok(len) => return Ok(&bytes[..len]),
+        Err(err) => return Err(protocol_error(err)),
+    }
+}
+
+// Returns the first whole request in bytes, which must be within the limits.
+pub fn extract_redis_command_within(bytes: &[u8], limits: Limits) -> Result<&[u8], RedisError> {
+    match resp::frame_len_within(bytes, limits) {
+        Ok(len) => return Ok(&bytes[..len]),
+        Err(err) => return Err(protocol_error(err)),
+    }
+}
+
+fn protocol_error(err: ParseError) -> RedisError {
+    match err {
+        ParseError::Incomplete => return RedisError::IncompleteMessage,
+        ParseError::TooLarge(limit) => return RedisError::TooLarge(limit),
+        _ => return RedisError::InvalidProtocol,
+    }
+}
+
+// Takes the next argument of a request, which must have it.
+fn next_arg<'a>(args: &mut Args<'a>) -> Result<&'a [u8], RedisError> {
+    match args.next() {
+        Some(Ok(arg)) => return Ok(arg),
+        Some(Err(err)) => return Err(protocol_error(err)),
+        None => return Err(RedisError::InvalidProtocol),
+    }
 }
 
 /*
-    Iterates through one redis request in bytes, moving the index to the end of the request.
+    Returns the keys of a request, which decide the backend it's sent to. Plain text (inline) commands, and replies
+    sent by a misbehaving client, aren't supported.
 */
-fn parse_redis_request(bytes: &[u8], index: &mut usize) -> Result<(), RedisError> {
-    let next_char = match bytes.get(*index) {
-        Some(c) => *c as char,
-        None => { return Err(RedisError::IncompleteMessage); }
-    };
-    match next_char {
-        '+' =>  {
-            *index += 1;
-            try!(skip_past_eol(bytes, index));
-            return Ok(());
+pub fn extract_key(bytes: &[u8]) -> Result<KeyPos, RedisError> {
+    let mut args = try!(Args::parse(bytes).map_err(protocol_error));
+    let command = try!(next_arg(&mut args));
+    match supported_keys(command) {
+        KeyPosition::Unsupported => { return Err(RedisError::UnsupportedCommand); }
+        KeyPosition::Blocking => { return Err(RedisError::BlockingCommand); }
+        KeyPosition::Next => {
+            return Ok(KeyPos::Single(try!(next_arg(&mut args))));
         }
-        '-' =>  {
-            *index += 1;
-            try!(skip_past_eol(bytes, index));
-            return Ok(());
+        KeyPosition::Eval => {
+            // EVAL script numkeys key [arg ...], and FCALL function numkeys key [arg ...]
+            try!(next_arg(&mut args));
+            if try!(next_arg(&mut args)) != &b"1"[..] {
+                return Err(RedisError::InvalidScript);
+            }
+            return Ok(KeyPos::Single(try!(next_arg(&mut args))));
         }
-        ':' =>  {
-            *index += 1;
-            try!(skip_past_eol(bytes, index));
-            return Ok(());
+        KeyPosition::Subcommand => {
+            try!(next_arg(&mut args));
+            return Ok(KeyPos::Single(try!(next_arg(&mut args))));
         }
-        '$' => {
-            *index += 1;
-            let num = try!(interpret_num(bytes, index));
-            *index += 2;
-            if num < 0 {
-                return Ok(());
+        KeyPosition::Streams(leading) => {
+            // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key id. None of the options' values can be STREAMS.
+            for _ in 0..leading {
+                try!(next_arg(&mut args));
             }
-            *index += num as usize + 2;
-            if *index > bytes.len() {
-                return Err(RedisError::IncompleteMessage);
+            // Blocking reads aren't supported, like the other blocking commands. See supported_keys.
+            loop {
+                match args.next() {
+                    Some(Ok(arg)) if arg.eq_ignore_ascii_case(b"STREAMS") => break,
+                    Some(Ok(arg)) if arg.eq_ignore_ascii_case(b"BLOCK") => return Err(RedisError::BlockingCommand),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(protocol_error(err)),
+                    None => return Err(RedisError::InvalidStreams),
+                }
             }
-            return Ok(());
-        }
-        '*' => {
-            *index += 1;
-            let num = try!(interpret_num(bytes, index));
-            *index += 2;
-            for _ in 0..num {
-                try!(parse_redis_request(bytes, index));
+            // Several streams may be on different backends, so only one can be read at a time.
+            if args.remaining() != 2 {
+                return Err(RedisError::InvalidStreams);
             }
-            return Ok(());
-            
+            return Ok(KeyPos::Single(try!(next_arg(&mut args))));
         }
-        _ => { return Err(RedisError::InvalidProtocol); }
-    }
-}
-
-pub fn extract_key(bytes: &[u8]) -> Result<KeyPos, RedisError> {
-    if bytes[0] == '*' as u8 {
-        // then it is standard redis protcol.
-        let mut index = 0;
-
-        // skip 1
-        try!(skip_past_eol(&bytes, &mut index));
-
-        // verify next byte is '$'
-        if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-            return Err(RedisError::InvalidProtocol);
+        KeyPosition::Multi => {
+            if args.remaining() == 0 {
+                return Err(RedisError::MissingArgsMget);
+            }
+            let mut keys = Vec::new();
+            while args.remaining() > 0 {
+                keys.push(try!(next_arg(&mut args)));
+            }
+            return Ok(KeyPos::Multi(keys));
         }
-        index += 1;
-        let num = try!(interpret_num(bytes, &mut index)) as usize;
-        index += 2;
-
-        // grab the command list.
-        let command = unsafe {
-            bytes.get_unchecked(index..index+num)
-        };
-
-        match supported_keys(command) {
-            KeyPosition::Unsupported => { return Err(RedisError::UnsupportedCommand); }
-            KeyPosition::Next => {
-                index += num + 2;
-
-                if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                    return Err(RedisError::InvalidProtocol);
-                }
-                index += 1;
-                let n = try!(interpret_num(bytes, &mut index));
-                if n < 0 {
-                    return Err(RedisError::InvalidProtocol);
-                }
-                let num = n as usize;
-                index += 2;
-
-                // grab the command list.
-                let key = unsafe {
-                    bytes.get_unchecked(index..index+num)
-                };
-                return Ok(KeyPos::Single(key));
+        KeyPosition::MultiInterleaved => {
+            if args.remaining() == 0 {
+                return Err(RedisError::MissingArgsMset);
             }
-            KeyPosition::Eval => {
-                index += num + 2;
-                if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                    return Err(RedisError::InvalidProtocol);
-                }
-                index += 1;
-                let num = try!(interpret_num(bytes, &mut index)) as usize;
-                index += 2;
-                
-                index += num + 2;
-                if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                    return Err(RedisError::InvalidProtocol);
-                }
-                index += 1;
-                let num = try!(interpret_num(bytes, &mut index)) as usize;
-                index += 2;
-
-                let num_keys = unsafe {
-                    bytes.get_unchecked(index..index+num)
-                };
-                if num_keys != ['1' as u8] {
-                    return Err(RedisError::InvalidScript);
-                }
-
-                index += num + 2;
-                if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                    return Err(RedisError::InvalidProtocol);
-                }
-                index += 1;
-                let num = try!(interpret_num(bytes, &mut index)) as usize;
-                index += 2;
-
-                let key = unsafe {
-                    bytes.get_unchecked(index..index+num)
-                };
-                return Ok(KeyPos::Single(key));
+            if args.remaining() % 2 == 1 {
+                return Err(RedisError::WrongArgsMset);
             }
-            KeyPosition::Multi => {
-                // Go back to the beginning to determine number of keys.
-                let mut temp = 1;
-                let num_keys = try!(interpret_num(bytes, &mut temp)) as usize - 1;
-
-                let mut vec = Vec::new();
-
-                let mut num = num;
-
-                for _ in 0..num_keys {
-                    index += num + 2;
-
-                    if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    index += 1;
-                    let n = try!(interpret_num(bytes, &mut index));
-                    if n < 0 {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    num = n as usize;
-                    index += 2;
-
-                    // grab the command list.
-                    let key = unsafe {
-                        bytes.get_unchecked(index..index+num)
-                    };
-                    vec.push(key);
-                }
-                if vec.len() == 0 {
-                    return Err(RedisError::MissingArgsMget);
-                }
-                return Ok(KeyPos::Multi(vec));
+            let mut pairs = Vec::new();
+            while args.remaining() > 0 {
+                let key = try!(next_arg(&mut args));
+                let value = try!(next_arg(&mut args));
+                pairs.push((key, value));
             }
-            KeyPosition::MultiInterleaved => {
-                // Go back to the beginning to determine number of keys.
-                let mut temp = 1;
-                let num_keys = try!(interpret_num(bytes, &mut temp)) as usize - 1;
-
-                let mut vec = Vec::new();
-
-                let mut num = num;
-
-                if num_keys == 0 {
-                    return Err(RedisError::MissingArgsMset);
-                }
-                if num_keys % 2 == 1 {
-                    return Err(RedisError::WrongArgsMset);
-                }
-
-                for _ in 0..(num_keys)/2 {
-                    index += num + 2;
-
-                    if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    index += 1;
-                    let n = try!(interpret_num(bytes, &mut index));
-                    if n < 0 {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    num = n as usize;
-                    index += 2;
-
-                    // grab the command list.
-                    let key = unsafe {
-                        bytes.get_unchecked(index..index+num)
-                    };
+            return Ok(KeyPos::MultiSet(pairs));
+        }
+    }
+}
 
-                    index += num + 2;
+// Names of the commands the proxy supports, sorted so that they can be binary searched. Used to label per-command stats.
+static COMMAND_NAMES: [&'static str; 123] = [
+    "APPEND", "BITCOUNT", "BITFIELD", "BITPOS", "BLPOP", "BRPOP", "BZPOPMAX", "BZPOPMIN", "DECR", "DECRBY", "DEL",
+    "DUMP", "EVAL", "EXISTS", "EXPIRE", "EXPIREAT", "FCALL", "FCALL_RO", "FUNCTION", "GEOADD", "GEODIST", "GEOHASH",
+    "GEOPOS", "GEORADIUS", "GEORADIUSBYMEMBER", "GET", "GETBIT", "GETRANGE", "GETSET", "HDEL", "HEXISTS", "HGET",
+    "HGETALL", "HINCRBY", "HINCRBYFLOAT", "HKEYS", "HLEN", "HMGET", "HMSET", "HSCAN", "HSET", "HSETNX", "HSTRLEN",
+    "HVALS", "INCR", "INCRBY", "INCRBYFLOAT", "LINDEX", "LINSERT", "LLEN", "LPOP", "LPUSH", "LPUSHX", "LRANGE",
+    "LREM", "LSET", "LTRIM", "MGET", "MSET", "PERSIST", "PEXPIRE", "PEXPIREAT", "PFADD", "PFCOUNT", "PSETEX", "PTTL",
+    "RESTORE", "RPOP", "RPUSH", "RPUSHX", "SADD", "SCARD", "SET", "SETBIT", "SETEX", "SETNX", "SETRANGE", "SISMEMBER",
+    "SMEMBERS", "SORT", "SPOP", "SRANDMEMBER", "SREM", "SSCAN", "STRLEN", "TOUCH", "TTL", "TYPE", "UNLINK", "XACK",
+    "XADD", "XAUTOCLAIM", "XCLAIM", "XDEL", "XGROUP", "XLEN", "XPENDING", "XRANGE", "XREAD", "XREADGROUP",
+    "XREVRANGE", "XTRIM", "ZADD", "ZCARD", "ZCOUNT", "ZINCRBY", "ZLEXCOUNT", "ZPOPMAX", "ZPOPMIN", "ZRANGE",
+    "ZRANGEBYLEX", "ZRANGEBYSCORE", "ZRANK", "ZREM", "ZREMRANGEBYLEX", "ZREMRANGEBYRANK", "ZREMRANGEBYSCORE",
+    "ZREVRANGE", "ZREVRANGEBYLEX", "ZREVRANGEBYSCORE", "ZREVRANK", "ZSCAN", "ZSCORE"
+];
+
+// Used for any command that the proxy doesn't recognize.
+pub const OTHER_COMMAND: &'static str = "OTHER";
 
-                    if '$' as u8 != unsafe { *bytes.get_unchecked(index) } {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    index += 1;
-                    let n = try!(interpret_num(bytes, &mut index));
-                    if n < 0 {
-                        return Err(RedisError::InvalidProtocol);
-                    }
-                    num = n as usize;
-                    index += 2;
+/*
+    Returns the upper-case name of the command in the given redis request, e.g. "GET".
+    Commands that aren't supported by the proxy are returned as OTHER_COMMAND.
+*/
+pub fn command_name(bytes: &[u8]) -> &'static str {
+    let command = match Args::parse(bytes).ok().and_then(|mut args| args.next()) {
+        Some(Ok(command)) if !command.is_empty() => command,
+        _ => return OTHER_COMMAND,
+    };
+    let upper_command = command.to_ascii_uppercase();
+    match COMMAND_NAMES.binary_search_by(|name| name.as_bytes().cmp(&upper_command[..])) {
+        Ok(position) => COMMAND_NAMES[position],
+        Err(_) => OTHER_COMMAND,
+    }
+}
 
-                    // grab the command list.
-                    let value = unsafe {
-                        bytes.get_unchecked(index..index+num)
-                    };
-                    vec.push((key, value));
+#[test]
+fn test_command_name() {
+    assert_eq!(command_name(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n"), "GET");
+    assert_eq!(command_name(b"*2\r\n$7\r\nhgetall\r\n$3\r\nkey\r\n"), "HGETALL");
+    assert_eq!(command_name(b"*1\r\n$4\r\nPING\r\n"), OTHER_COMMAND);
+    assert_eq!(command_name(b"*1\r\n$10\r\nGET"), OTHER_COMMAND);
+    assert_eq!(command_name(b"GET key\r\n"), OTHER_COMMAND);
+    for pair in COMMAND_NAMES.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+}
 
-                }
-                return Ok(KeyPos::MultiSet(vec));
-            }
-        };
-    } else {
-        panic!("Unimplemented support for plain text commands");
+#[test]
+fn test_is_read_only() {
+    assert!(is_read_only("GET"));
+    assert!(is_read_only("ZREVRANGEBYSCORE"));
+    assert!(!is_read_only("SET"));
+    // Reading with a consumer group moves entries into its pending list.
+    assert!(is_read_only("XREAD"));
+    assert!(!is_read_only("XREADGROUP"));
+    assert!(!is_read_only(OTHER_COMMAND));
+    for pair in READ_ONLY_COMMANDS.windows(2) {
+        assert!(pair[0] < pair[1]);
+    }
+    for command in READ_ONLY_COMMANDS.iter() {
+        assert!(COMMAND_NAMES.contains(command));
     }
 }
 
+// Supported commands that never modify data, sorted so that they can be binary searched.
+static READ_ONLY_COMMANDS: [&str; 52] = [
+    "BITCOUNT", "BITPOS", "DUMP", "EXISTS", "FCALL_RO", "GEODIST", "GEOHASH", "GEOPOS", "GET", "GETBIT", "GETRANGE",
+    "HEXISTS", "HGET", "HGETALL", "HKEYS", "HLEN", "HMGET", "HSCAN", "HSTRLEN", "HVALS", "LINDEX", "LLEN", "LRANGE",
+    "MGET", "PFCOUNT", "PTTL", "SCARD", "SISMEMBER", "SMEMBERS", "SRANDMEMBER", "SSCAN", "STRLEN", "TTL", "TYPE",
+    "XLEN", "XPENDING", "XRANGE", "XREAD", "XREVRANGE", "ZCARD", "ZCOUNT", "ZLEXCOUNT", "ZRANGE", "ZRANGEBYLEX",
+    "ZRANGEBYSCORE", "ZRANK", "ZREVRANGE", "ZREVRANGEBYLEX", "ZREVRANGEBYSCORE", "ZREVRANK", "ZSCAN", "ZSCORE"
+];
+
+// Whether the command, as named by command_name, never modifies data, so that a replica can answer it.
+pub fn is_read_only(command: &str) -> bool {
+    return READ_ONLY_COMMANDS.binary_search(&command).is_ok();
+}
+
+/*
+    Where the keys of a supported command are. Blocking commands are recognized, but rejected: the backend connection is
+    shared by the pool's clients, so one would hold up every other client's requests behind it, and one answered after
+    the pool's timeout would answer the next request instead.
+*/
 fn supported_keys(command: &[u8]) -> KeyPosition {
     match command.len() {
         3 => {
@@ -458,6 +442,10 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str4compare(command, 'S', 'R', 'E', 'M') { return KeyPosition::Next; }
             if str4compare(command, 'Z', 'A', 'D', 'D') { return KeyPosition::Next; }
             if str4compare(command, 'Z', 'R', 'E', 'M') { return KeyPosition::Next; }
+            if str4compare(command, 'X', 'A', 'C', 'K') { return KeyPosition::Next; }
+            if str4compare(command, 'X', 'A', 'D', 'D') { return KeyPosition::Next; }
+            if str4compare(command, 'X', 'D', 'E', 'L') { return KeyPosition::Next; }
+            if str4compare(command, 'X', 'L', 'E', 'N') { return KeyPosition::Next; }
             return KeyPosition::Unsupported;
         }
         5 => {
@@ -469,8 +457,8 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str5compare(command, 'H', 'M', 'S', 'E', 'T') { return KeyPosition::Next; }
             if str5compare(command, 'H', 'S', 'C', 'A', 'N') { return KeyPosition::Next; }
             if str5compare(command, 'H', 'V', 'A', 'L', 'S') { return KeyPosition::Next; }
-            if str5compare(command, 'B', 'L', 'P', 'O', 'P') { return KeyPosition::Next; }
-            if str5compare(command, 'B', 'R', 'P', 'O', 'P') { return KeyPosition::Next; }
+            if str5compare(command, 'B', 'L', 'P', 'O', 'P') { return KeyPosition::Blocking; }
+            if str5compare(command, 'B', 'R', 'P', 'O', 'P') { return KeyPosition::Blocking; }
             if str5compare(command, 'L', 'P', 'U', 'S', 'H') { return KeyPosition::Next; }
             if str5compare(command, 'L', 'T', 'R', 'I', 'M') { return KeyPosition::Next; }
             if str5compare(command, 'R', 'P', 'U', 'S', 'H') { return KeyPosition::Next; }
@@ -480,6 +468,9 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str5compare(command, 'Z', 'R', 'A', 'N', 'K') { return KeyPosition::Next; }
             if str5compare(command, 'Z', 'S', 'C', 'A', 'N') { return KeyPosition::Next; }
             if str5compare(command, 'P', 'F', 'A', 'D', 'D') { return KeyPosition::Next; }
+            if str5compare(command, 'X', 'T', 'R', 'I', 'M') { return KeyPosition::Next; }
+            if str5compare(command, 'F', 'C', 'A', 'L', 'L') { return KeyPosition::Eval; }
+            if str5compare(command, 'X', 'R', 'E', 'A', 'D') { return KeyPosition::Streams(0); }
             return KeyPosition::Unsupported;
         }
         6 => {
@@ -505,6 +496,9 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str6compare(command, 'Z', 'S', 'C', 'O', 'R', 'E') { return KeyPosition::Next; }
             if str6compare(command, 'G', 'E', 'O', 'A', 'D', 'D') { return KeyPosition::Next; }
             if str6compare(command, 'G', 'E', 'O', 'P', 'O', 'S') { return KeyPosition::Next; }
+            if str6compare(command, 'X', 'R', 'A', 'N', 'G', 'E') { return KeyPosition::Next; }
+            if str6compare(command, 'X', 'C', 'L', 'A', 'I', 'M') { return KeyPosition::Next; }
+            if str6compare(command, 'X', 'G', 'R', 'O', 'U', 'P') { return KeyPosition::Subcommand; }
             return KeyPosition::Unsupported;
         }
         7 => {
@@ -531,9 +525,11 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str8compare(command, 'G', 'E', 'T', 'R', 'A', 'N', 'G', 'E') { return KeyPosition::Next; }
             if str8compare(command, 'S', 'E', 'T', 'R', 'A', 'N', 'G', 'E') { return KeyPosition::Next; }
             if str8compare(command, 'S', 'M', 'E', 'M', 'B', 'E', 'R', 'S') { return KeyPosition::Next; }
-            if str8compare(command, 'B', 'Z', 'P', 'O', 'P', 'M', 'A', 'X') { return KeyPosition::Next; }
-            if str8compare(command, 'B', 'Z', 'P', 'O', 'P', 'M', 'I', 'N') { return KeyPosition::Next; }
+            if str8compare(command, 'B', 'Z', 'P', 'O', 'P', 'M', 'A', 'X') { return KeyPosition::Blocking; }
+            if str8compare(command, 'B', 'Z', 'P', 'O', 'P', 'M', 'I', 'N') { return KeyPosition::Blocking; }
             if str8compare(command, 'Z', 'R', 'E', 'V', 'R', 'A', 'N', 'K') { return KeyPosition::Next; }
+            if str8compare(command, 'X', 'P', 'E', 'N', 'D', 'I', 'N', 'G') { return KeyPosition::Next; }
+            if str8compare(command, 'F', 'C', 'A', 'L', 'L', '_', 'R', 'O') { return KeyPosition::Eval; }
             return KeyPosition::Unsupported;
         }
         9 => {
@@ -542,9 +538,12 @@ fn supported_keys(command: &[u8]) -> KeyPosition {
             if str9compare(command, 'Z', 'L', 'E', 'X', 'C', 'O', 'U', 'N', 'T') { return KeyPosition::Next; }
             if str9compare(command, 'Z', 'R', 'E', 'V', 'R', 'A', 'N', 'G', 'E') { return KeyPosition::Next; }
             if str9compare(command, 'G', 'E', 'O', 'R', 'A', 'D', 'I', 'U', 'S') { return KeyPosition::Next; }
+            if str9compare(command, 'X', 'R', 'E', 'V', 'R', 'A', 'N', 'G', 'E') { return KeyPosition::Next; }
             return KeyPosition::Unsupported;
         }
         10 => {
+            if str10compare(command, 'X', 'R', 'E', 'A', 'D', 'G', 'R', 'O', 'U', 'P') { return KeyPosition::Streams(3); }
+            if str10compare(command, 'X', 'A', 'U', 'T', 'O', 'C', 'L', 'A', 'I', 'M') { return KeyPosition::Next; }
             return KeyPosition::Unsupported;
         }
         11 => {
@@ -590,9 +589,9 @@ fn str3compare(byte: &[u8], first: char, second: char, third: char) -> bool {
     let second = &(second as u8);
     let third = &(third as u8);
     unsafe {
-        return (byte.get_unchecked(0) == first || &(byte.get_unchecked(0) + 0x20u8) == first) &&
-            (byte.get_unchecked(1) == second || &(byte.get_unchecked(1) + 0x20u8) == second) &&
-            (byte.get_unchecked(2) == third || &(byte.get_unchecked(2) + 0x20u8) == third);
+        return (byte.get_unchecked(0) == first || &(byte.get_unchecked(0).wrapping_add(0x20)) == first) &&
+            (byte.get_unchecked(1) == second || &(byte.get_unchecked(1).wrapping_add(0x20)) == second) &&
+            (byte.get_unchecked(2) == third || &(byte.get_unchecked(2).wrapping_add(0x20)) == third);
     }
 }
 
@@ -602,10 +601,10 @@ fn str4compare(byte: &[u8], first: char, second: char, third: char, fourth: char
     let third = &(third as u8);
     let fourth = &(fourth as u8);
     unsafe {
-        return (byte.get_unchecked(0) == first || &(byte.get_unchecked(0) + 0x20u8) == first) &&
-            (byte.get_unchecked(1) == second || &(byte.get_unchecked(1) + 0x20u8) == second) &&
-            (byte.get_unchecked(2) == third || &(byte.get_unchecked(2) + 0x20u8) == third) &&
-            (byte.get_unchecked(3) == fourth || &(byte.get_unchecked(3) + 0x20u8) == fourth);
+        return (byte.get_unchecked(0) == first || &(byte.get_unchecked(0).wrapping_add(0x20)) == first) &&
+            (byte.get_unchecked(1) == second || &(byte.get_unchecked(1).wrapping_add(0x20)) == second) &&
+            (byte.get_unchecked(2) == third || &(byte.get_unchecked(2).wrapping_add(0x20)) == third) &&
+            (byte.get_unchecked(3) == fourth || &(byte.get_unchecked(3).wrapping_add(0x20)) == fourth);
     }
 }
 
@@ -616,11 +615,11 @@ fn str5compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char) ->
     let c4 = &(c4 as u8);
     let c5 = &(c5 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5);
     }
 }
 
@@ -632,12 +631,12 @@ fn str6compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c6
     let c5 = &(c5 as u8);
     let c6 = &(c6 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6);
     }
 }
 
@@ -650,13 +649,13 @@ fn str7compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c6
     let c6 = &(c6 as u8);
     let c7 = &(c7 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7);
     }
 }
 
@@ -670,14 +669,14 @@ fn str8compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c6
     let c7 = &(c7 as u8);
     let c8 = &(c8 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8);
     }
 }
 
@@ -692,19 +691,18 @@ fn str9compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c6
     let c8 = &(c8 as u8);
     let c9 = &(c9 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9);
     }
 }
 
-#[allow(dead_code)]
 fn str10compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c6: char, c7: char, c8: char, c9: char, c10: char) -> bool {
     let c1 = &(c1 as u8);
     let c2 = &(c2 as u8);
@@ -717,16 +715,16 @@ fn str10compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c9 = &(c9 as u8);
     let c10 = &(c10 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10);
     }
 }
 
@@ -743,17 +741,17 @@ fn str11compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c10 = &(c10 as u8);
     let c11 = &(c11 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11);
     }
 }
 
@@ -771,18 +769,18 @@ fn str12compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c11 = &(c11 as u8);
     let c12 = &(c12 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12);
     }
 }
 
@@ -801,19 +799,19 @@ fn str13compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c12 = &(c12 as u8);
     let c13 = &(c13 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12) &&
-            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12) + 0x20u8) == c13);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12) &&
+            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12).wrapping_add(0x20)) == c13);
     }
 }
 
@@ -833,20 +831,20 @@ fn str14compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c13 = &(c13 as u8);
     let c14 = &(c14 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12) &&
-            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12) + 0x20u8) == c13) &&
-            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13) + 0x20u8) == c14);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12) &&
+            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12).wrapping_add(0x20)) == c13) &&
+            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13).wrapping_add(0x20)) == c14);
     }
 }
 
@@ -867,21 +865,21 @@ fn str15compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c14 = &(c14 as u8);
     let c15 = &(c15 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12) &&
-            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12) + 0x20u8) == c13) &&
-            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13) + 0x20u8) == c14) &&
-            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14) + 0x20u8) == c15);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12) &&
+            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12).wrapping_add(0x20)) == c13) &&
+            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13).wrapping_add(0x20)) == c14) &&
+            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14).wrapping_add(0x20)) == c15);
     }
 }
 
@@ -903,22 +901,22 @@ fn str16compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c15 = &(c15 as u8);
     let c16 = &(c16 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12) &&
-            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12) + 0x20u8) == c13) &&
-            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13) + 0x20u8) == c14) &&
-            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14) + 0x20u8) == c15) &&
-            (byte.get_unchecked(15) == c16 || &(byte.get_unchecked(15) + 0x20u8) == c16);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12) &&
+            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12).wrapping_add(0x20)) == c13) &&
+            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13).wrapping_add(0x20)) == c14) &&
+            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14).wrapping_add(0x20)) == c15) &&
+            (byte.get_unchecked(15) == c16 || &(byte.get_unchecked(15).wrapping_add(0x20)) == c16);
     }
 }
 
@@ -941,26 +939,29 @@ fn str17compare(byte: &[u8], c1: char, c2: char, c3: char, c4: char, c5: char, c
     let c16 = &(c16 as u8);
     let c17 = &(c17 as u8);
     unsafe {
-        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0) + 0x20u8) == c1) &&
-            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1) + 0x20u8) == c2) &&
-            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2) + 0x20u8) == c3) &&
-            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3) + 0x20u8) == c4) &&
-            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4) + 0x20u8) == c5) &&
-            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5) + 0x20u8) == c6) &&
-            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6) + 0x20u8) == c7) &&
-            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7) + 0x20u8) == c8) &&
-            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8) + 0x20u8) == c9) &&
-            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9) + 0x20u8) == c10) &&
-            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10) + 0x20u8) == c11) &&
-            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11) + 0x20u8) == c12) &&
-            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12) + 0x20u8) == c13) &&
-            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13) + 0x20u8) == c14) &&
-            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14) + 0x20u8) == c15) &&
-            (byte.get_unchecked(15) == c16 || &(byte.get_unchecked(15) + 0x20u8) == c16) &&
-            (byte.get_unchecked(16) == c17 || &(byte.get_unchecked(16) + 0x20u8) == c17);
+        return (byte.get_unchecked(0) == c1 || &(byte.get_unchecked(0).wrapping_add(0x20)) == c1) &&
+            (byte.get_unchecked(1) == c2 || &(byte.get_unchecked(1).wrapping_add(0x20)) == c2) &&
+            (byte.get_unchecked(2) == c3 || &(byte.get_unchecked(2).wrapping_add(0x20)) == c3) &&
+            (byte.get_unchecked(3) == c4 || &(byte.get_unchecked(3).wrapping_add(0x20)) == c4) &&
+            (byte.get_unchecked(4) == c5 || &(byte.get_unchecked(4).wrapping_add(0x20)) == c5) &&
+            (byte.get_unchecked(5) == c6 || &(byte.get_unchecked(5).wrapping_add(0x20)) == c6) &&
+            (byte.get_unchecked(6) == c7 || &(byte.get_unchecked(6).wrapping_add(0x20)) == c7) &&
+            (byte.get_unchecked(7) == c8 || &(byte.get_unchecked(7).wrapping_add(0x20)) == c8) &&
+            (byte.get_unchecked(8) == c9 || &(byte.get_unchecked(8).wrapping_add(0x20)) == c9) &&
+            (byte.get_unchecked(9) == c10 || &(byte.get_unchecked(9).wrapping_add(0x20)) == c10) &&
+            (byte.get_unchecked(10) == c11 || &(byte.get_unchecked(10).wrapping_add(0x20)) == c11) &&
+            (byte.get_unchecked(11) == c12 || &(byte.get_unchecked(11).wrapping_add(0x20)) == c12) &&
+            (byte.get_unchecked(12) == c13 || &(byte.get_unchecked(12).wrapping_add(0x20)) == c13) &&
+            (byte.get_unchecked(13) == c14 || &(byte.get_unchecked(13).wrapping_add(0x20)) == c14) &&
+            (byte.get_unchecked(14) == c15 || &(byte.get_unchecked(14).wrapping_add(0x20)) == c15) &&
+            (byte.get_unchecked(15) == c16 || &(byte.get_unchecked(15).wrapping_add(0x20)) == c16) &&
+            (byte.get_unchecked(16) == c17 || &(byte.get_unchecked(16).wrapping_add(0x20)) == c17);
     }
 }
 
+// Number of hash slots in a redis cluster.
+pub const CLUSTER_SLOTS: usize = 16384;
+
 pub fn handle_slotsmap(
     response: &[u8],
     handle_slots: &mut FnMut(String, usize, usize) -> Result<(), RedisError>,
@@ -976,7 +977,7 @@ pub fn handle_slotsmap(
 
     //let mut chars = response.iter();
     let mut index = 0;
-    let mut current_char = response.get(index).unwrap();
+    let mut current_char = try!(byte_at(response, index));
     index += 1;
     if *current_char != '*' as u8 {
         error!("Parse error: expected * at start of response. Found {:?} instead.", *current_char as char);
@@ -986,7 +987,7 @@ pub fn handle_slotsmap(
     index += 2;
 
     for _ in 0..starting_index {
-        current_char = response.get(index).unwrap();
+        current_char = try!(byte_at(response, index));
         index += 1;
         if *current_char != '*' as u8 {
             error!("Parse error: expected * at start of response. Found {:?} instead.", *current_char as char);
@@ -1006,7 +1007,7 @@ pub fn handle_slotsmap(
         let mut identifier = "".to_string();
 
         // Parse starting slot range.
-        current_char = response.get(index).unwrap();
+        current_char = try!(byte_at(response, index));
         index += 1;
         if *current_char != ':' as u8 {
             error!("Parse error: expected : at start of line to mark second level of array. Found {:?} instead.", current_char);
@@ -1020,7 +1021,7 @@ pub fn handle_slotsmap(
         }
 
         // Parse ending slot range.
-        if *response.get(index).unwrap() != ':' as u8 {
+        if *try!(byte_at(response, index)) != ':' as u8 {
             error!("parse error: expected :");
             return Err(RedisError::InvalidProtocol);
         }
@@ -1028,12 +1029,13 @@ pub fn handle_slotsmap(
         //let ending_slot = parse_int(&mut chars, response);
         let ending_slot = try!(interpret_num(response, &mut index));
         index += 2;
-        if ending_slot < 0 {
+        if ending_slot < starting_slot || ending_slot as usize >= CLUSTER_SLOTS {
+            error!("Parse error: invalid slot range {} to {}.", starting_slot, ending_slot);
             return Err(RedisError::InvalidProtocol);
         }
 
         for _ in 0..parsed_resp_length-2 {
-            current_char = response.get(index).unwrap();
+            current_char = try!(byte_at(response, index));
             index += 1;
             if *current_char != '*' as u8 {
                 error!("Parse error: expected * at start of response. Found {:?} instead.", current_char);
@@ -1043,7 +1045,7 @@ pub fn handle_slotsmap(
             index += 2;
             // Can be 2 for older redis versions, and 3 for newer redis versions.
 
-            current_char = response.get(index).unwrap();
+            current_char = try!(byte_at(response, index));
             index += 1;
             if *current_char != '$' as u8 {
                 error!("Parse error: 1st expected $ at start of line to mark second level of array. Found {:?} instead.", current_char);
@@ -1052,12 +1054,12 @@ pub fn handle_slotsmap(
             let parsed_string_length = try!(interpret_num(response, &mut index));
             index += 2;
             for _ in 0..parsed_string_length {
-                hostname.push(*response.get(index).unwrap() as char);
+                hostname.push(*try!(byte_at(response, index)) as char);
                 index += 1;
             }
             try!(expect_eol(response, &mut index));
 
-            current_char = response.get(index).unwrap();
+            current_char = try!(byte_at(response, index));
             index += 1;
             if *current_char != ':' as u8 {
                 error!("Parse error: expected : at start of line to mark second level of array. Found {:?} instead.", current_char);
@@ -1067,7 +1069,7 @@ pub fn handle_slotsmap(
             index += 2;
 
             if parsed_slot_array_length > 2 {
-                current_char = response.get(index).unwrap();
+                current_char = try!(byte_at(response, index));
                 index += 1;
                 if *current_char != '$' as u8 {
                     error!("Parse error: 2nd expected $ at start of line to mark second level of array. Found {:?} instead.", current_char);
@@ -1076,7 +1078,7 @@ pub fn handle_slotsmap(
                 let parsed_string_length = try!(interpret_num(response, &mut index));
                 index += 2;
                 for _ in 0..parsed_string_length {
-                    identifier.push(*response.get(index).unwrap() as char);
+                    identifier.push(*try!(byte_at(response, index)) as char);
                     index += 1;
                 }
                 index += 2;
@@ -1090,15 +1092,26 @@ pub fn handle_slotsmap(
     return Ok(());
 }
 
+// A truncated response is a protocol error, since the backend already sent it as complete.
+fn byte_at(bytes: &[u8], index: usize) -> Result<&u8, RedisError> {
+    match bytes.get(index) {
+        Some(byte) => Ok(byte),
+        None => {
+            error!("Parse error: response ended after {} bytes.", bytes.len());
+            Err(RedisError::InvalidProtocol)
+        }
+    }
+}
+
 fn expect_eol(bytes: &[u8], index: &mut usize) -> Result<(), RedisError> {
     debug!("Expecitng eol: {}", index);
-    let mut next = bytes.get(*index).unwrap();
+    let mut next = try!(byte_at(bytes, *index));
     *index += 1;
     if *next != '\r' as u8 {
         error!("Parse error: expected \\r, found {:?} instead.", *next as char);
         return Err(RedisError::InvalidProtocol);
     }
-    next = bytes.get(*index).unwrap();
+    next = try!(byte_at(bytes, *index));
     *index += 1;
     if *next != '\n' as u8 {
         error!("Parse error: expected \\n, found {:?} instead.", next);
@@ -1153,4 +1166,10 @@ fn test_slotsmap() {
     for i in 10921..16382 {
         assert_eq!(assigned_slots.get(i), Some(&"127.0.0.1:7002".to_owned()))
     }
+
+    // Truncated responses and out of range slots are protocol errors.
+    let mut ignore_slots = |_host: String, _start: usize, _end: usize| -> Result<(), RedisError> { Ok(()) };
+    assert_eq!(handle_slotsmap(&r.as_bytes()[..40], &mut ignore_slots), Err(RedisError::InvalidProtocol));
+    let r = "*1\r\n*3\r\n:0\r\n:16384\r\n*2\r\n$9\r\n127.0.0.1\r\n:7000\r\n";
+    assert_eq!(handle_slotsmap(r.as_bytes(), &mut ignore_slots), Err(RedisError::InvalidProtocol));
 }
@@ -0,0 +1,180 @@
+use mio::{Evented, Events, Poll, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::io::{Error, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::time::Duration;
+
+/*
+    The worker's Poll, shared by everything that registers a socket with it. Sockets are registered through a
+    Registered handle, which owns the registration: it's made with the socket's token and interest, changed through
+    the handle, and deregistered when the handle is dropped. That way a socket can't be left registered after it's
+    replaced, or be closed while another registration with its token is expected.
+
+    Every registration is edge-triggered. Only this module uses mio's registration API, so that a later version of
+    mio only has to be adapted to here.
+*/
+#[derive(Clone)]
+pub struct Registry {
+    poll: Rc<RefCell<Poll>>,
+}
+
+// What a registered socket is polled for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interest {
+    // Nothing, e.g. a client that isn't read from while a backend it sent requests to is full.
+    Paused,
+    Readable,
+    ReadWrite,
+}
+
+impl Interest {
+    fn ready(self) -> Ready {
+        match self {
+            Interest::Paused => Ready::empty(),
+            Interest::Readable => Ready::readable(),
+            Interest::ReadWrite => Ready::readable() | Ready::writable(),
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Result<Registry, Error> {
+        return Ok(Registry {
+            poll: Rc::new(RefCell::new(try!(Poll::new()))),
+        });
+    }
+
+    // Waits for events, for at most the timeout.
+    pub fn poll(&self, events: &mut Events, timeout: Option<Duration>) -> Result<usize, Error> {
+        return self.poll.borrow_mut().poll(events, timeout);
+    }
+
+    /*
+        Registers a handle that lives as long as the Poll, e.g. the signal pipe. Sockets are registered with
+        Registered::new instead.
+    */
+    pub fn register_static<E: Evented + ?Sized>(&self, handle: &E, token: Token, interest: Interest) -> Result<(), Error> {
+        return self.poll.borrow().register(handle, token, interest.ready(), PollOpt::edge());
+    }
+}
+
+// A socket registered with the worker's Poll, until it's dropped.
+pub struct Registered<E: Evented> {
+    handle: E,
+    token: Token,
+    interest: Interest,
+    registry: Registry,
+}
+
+impl<E: Evented> Registered<E> {
+    pub fn new(registry: &Registry, handle: E, token: Token, interest: Interest) -> Result<Registered<E>, Error> {
+        try!(registry.poll.borrow().register(&handle, token, interest.ready(), PollOpt::edge()));
+        return Ok(Registered {
+            handle: handle,
+            token: token,
+            interest: interest,
+            registry: registry.clone(),
+        });
+    }
+
+    pub fn token(&self) -> Token {
+        return self.token;
+    }
+
+    pub fn interest(&self) -> Interest {
+        return self.interest;
+    }
+
+    // Moves the registration to a new token, e.g. once the backends of a pool are renumbered.
+    pub fn set_token(&mut self, token: Token) -> Result<(), Error> {
+        try!(self.reregister(token, self.interest));
+        self.token = token;
+        return Ok(());
+    }
+
+    pub fn set_interest(&mut self, interest: Interest) -> Result<(), Error> {
+        try!(self.reregister(self.token, interest));
+        self.interest = interest;
+        return Ok(());
+    }
+
+    fn reregister(&self, token: Token, interest: Interest) -> Result<(), Error> {
+        return self.registry.poll.borrow().reregister(&self.handle, token, interest.ready(), PollOpt::edge());
+    }
+}
+
+impl<E: Evented> Drop for Registered<E> {
+    fn drop(&mut self) {
+        if let Err(err) = self.registry.poll.borrow().deregister(&self.handle) {
+            debug!("Failed to deregister {:?}: {}", self.token, err);
+        }
+    }
+}
+
+impl<E: Evented> Deref for Registered<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        return &self.handle;
+    }
+}
+
+impl<E: Evented> DerefMut for Registered<E> {
+    fn deref_mut(&mut self) -> &mut E {
+        return &mut self.handle;
+    }
+}
+
+impl<E: Evented + Read> Read for Registered<E> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        return self.handle.read(buf);
+    }
+}
+
+impl<E: Evented + Write> Write for Registered<E> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        return self.handle.write(buf);
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        return self.handle.flush();
+    }
+}
+
+impl<E: Evented + std::fmt::Debug> std::fmt::Debug for Registered<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        return write!(f, "{:?} ({:?})", self.handle, self.token);
+    }
+}
+
+#[test]
+fn test_registered_lifecycle() {
+    use mio::net::{TcpListener, TcpStream};
+
+    let registry = Registry::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = Registered::new(&registry, listener, Token(1), Interest::Readable).unwrap();
+    let _client = TcpStream::connect(&addr).unwrap();
+    let mut events = Events::with_capacity(16);
+    registry.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(events.iter().map(|event| event.token()).collect::<Vec<Token>>(), vec![Token(1)]);
+
+    // Once moved to another token, events arrive for it instead.
+    let (stream, _) = listener.accept().unwrap();
+    let mut stream = Registered::new(&registry, stream, Token(2), Interest::Paused).unwrap();
+    stream.set_token(Token(3)).unwrap();
+    stream.set_interest(Interest::ReadWrite).unwrap();
+    assert_eq!(stream.token(), Token(3));
+    assert_eq!(stream.interest(), Interest::ReadWrite);
+    registry.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(events.iter().map(|event| event.token()).collect::<Vec<Token>>(), vec![Token(3)]);
+
+    // Dropping a handle deregisters it, so no more events arrive for it.
+    drop(stream);
+    drop(listener);
+    let _another = TcpStream::connect(&addr);
+    registry.poll(&mut events, Some(Duration::from_millis(50))).unwrap();
+    assert!(events.is_empty());
+}
@@ -0,0 +1,91 @@
+use std::io::BufRead;
+
+// Identifies a captured client connection. It is the proxy's token for the client.
+pub type ClientId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    // From a client to the proxy.
+    Request,
+    // From the proxy to a client.
+    Response,
+}
+
+/*
+    A raw frame of a capture file.
+*/
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub direction: Direction,
+    // Time since the capture started.
+    pub offset_us: u64,
+    pub client: ClientId,
+    pub data: Vec<u8>,
+}
+
+/*
+    Reads the next frame of a capture file, as written by the proxy's CAPTURE command. Returns None at the end of the
+    file. Each frame is a "<direction> <offset_us> <client> <length>" line, followed by the data and a CRLF. Lines
+    starting with '#' are skipped.
+*/
+pub fn read_frame<R: BufRead>(reader: &mut R) -> Result<Option<Frame>, std::io::Error> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        if !line.starts_with('#') {
+            break;
+        }
+    }
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    if fields.len() != 4 {
+        return Err(invalid(format!("invalid frame header: {:?}", line)));
+    }
+    let direction = match fields[0] {
+        ">" => Direction::Request,
+        "<" => Direction::Response,
+        _ => return Err(invalid(format!("invalid frame direction: {:?}", fields[0]))),
+    };
+    let (offset_us, client, len) = match (fields[1].parse::<u64>(), fields[2].parse::<ClientId>(), fields[3].parse::<usize>()) {
+        (Ok(offset_us), Ok(client), Ok(len)) => (offset_us, client, len),
+        _ => return Err(invalid(format!("invalid frame header: {:?}", line))),
+    };
+    let mut data = vec![0; len + 2];
+    try!(reader.read_exact(&mut data));
+    if &data[len..] != b"\r\n" {
+        return Err(invalid("frame is not terminated by CRLF".to_owned()));
+    }
+    data.truncate(len);
+    Ok(Some(Frame {
+        direction: direction,
+        offset_us: offset_us,
+        client: client,
+        data: data,
+    }))
+}
+
+#[test]
+fn test_read_frame() {
+    let mut reader = &b"# redflare capture pool=pool1 start=2019-03-01T12:00:00Z sample_rate=1\n\
+        > 10 20 21\r\n*2\r\n$3\r\nGET\r\n$2\r\nk1\r\n\r\n\
+        < 250 20 5\r\n$-1\r\n\r\n"[..];
+    assert_eq!(read_frame(&mut reader).unwrap(), Some(Frame {
+        direction: Direction::Request,
+        offset_us: 10,
+        client: 20,
+        data: b"*2\r\n$3\r\nGET\r\n$2\r\nk1\r\n".to_vec(),
+    }));
+    assert_eq!(read_frame(&mut reader).unwrap(), Some(Frame {
+        direction: Direction::Response,
+        offset_us: 250,
+        client: 20,
+        data: b"$-1\r\n".to_vec(),
+    }));
+    assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+    assert!(read_frame(&mut &b"> 1 20 5\r\nabc\r\n"[..]).is_err());
+    assert!(read_frame(&mut &b"? 1 20 3\r\nabc\r\n"[..]).is_err());
+}
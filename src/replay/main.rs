@@ -0,0 +1,92 @@
+extern crate mio;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate clap;
+use clap::{Arg, App};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+mod capture_file;
+mod replayer;
+
+/*
+    Replays a capture file, recorded with the proxy's CAPTURE admin command, against a pool.
+*/
+fn main() {
+    let matches = App::new("RedFlareProxy Replay Tool")
+                    .version("0.1")
+                    .author("Kevin X. <xiaok10003@gmail.com>")
+                    .about("Replays captured traffic against a redis proxy or server")
+                    .arg(Arg::with_name("file")
+                        .short("f")
+                        .long("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Capture file to replay"))
+                    .arg(Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("HOST:PORT")
+                        .default_value("127.0.0.1:1531")
+                        .help("Pool (or redis server) to replay against"))
+                    .arg(Arg::with_name("speed")
+                        .short("s")
+                        .long("speed")
+                        .value_name("SPEED")
+                        .default_value("1")
+                        .help("Multiplies the captured pace, e.g. 2 replays twice as fast. 0 sends requests as fast as possible"))
+                    .arg(Arg::with_name("read_only")
+                        .long("read_only")
+                        .help("Only replays requests that don't modify data"))
+                    .arg(Arg::with_name("drain_timeout")
+                        .long("drain_timeout")
+                        .value_name("SECONDS")
+                        .default_value("5")
+                        .help("How long to wait for outstanding replies after the last request"))
+                    .get_matches();
+
+    let _ = env_logger::init();
+
+    let speed = match matches.value_of("speed").unwrap().parse::<f64>() {
+        Ok(speed) if speed >= 0.0 => speed,
+        _ => exit_with_error(&format!("Invalid speed: {}", matches.value_of("speed").unwrap())),
+    };
+    let drain_timeout = match matches.value_of("drain_timeout").unwrap().parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => exit_with_error(&format!("Invalid drain timeout: {}", matches.value_of("drain_timeout").unwrap())),
+    };
+    let target = matches.value_of("target").unwrap();
+    let target = match target.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => exit_with_error(&format!("Invalid target: {}", target)),
+    };
+    let path = matches.value_of("file").unwrap();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => exit_with_error(&format!("Failed to open {}: {}", path, err)),
+    };
+
+    let options = replayer::ReplayOptions {
+        speed: speed,
+        read_only: matches.is_present("read_only"),
+        drain_timeout: drain_timeout,
+    };
+    let mut replayer = match replayer::Replayer::new(target, options) {
+        Ok(replayer) => replayer,
+        Err(err) => exit_with_error(&format!("Failed to start: {}", err)),
+    };
+    let result = replayer.run(&mut BufReader::new(file));
+    println!("{}", replayer.stats);
+    if let Err(err) = result {
+        exit_with_error(&format!("Replay failed: {}", err));
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
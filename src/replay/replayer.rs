@@ -0,0 +1,379 @@
+use mio::*;
+use mio::tcp::TcpStream;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use capture_file::{read_frame, ClientId, Direction, Frame};
+
+// Like try!, for functions that return an Option.
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(value) => value, None => return None })
+}
+
+// Commands that don't modify data. Only these are sent when replaying read-only.
+static READ_ONLY_COMMANDS: [&'static str; 48] = [
+    "BITCOUNT", "BITPOS", "DUMP", "ECHO", "EXISTS", "GEODIST", "GEOHASH", "GEOPOS", "GET", "GETBIT", "GETRANGE",
+    "HEXISTS", "HGET", "HGETALL", "HKEYS", "HLEN", "HMGET", "HSCAN", "HSTRLEN", "HVALS", "LINDEX", "LLEN", "LRANGE",
+    "MGET", "PFCOUNT", "PING", "PTTL", "SCARD", "SISMEMBER", "SMEMBERS", "SRANDMEMBER", "SSCAN", "STRLEN", "TTL",
+    "TYPE", "ZCARD", "ZCOUNT", "ZLEXCOUNT", "ZRANGE", "ZRANGEBYLEX", "ZRANGEBYSCORE", "ZRANK", "ZREVRANGE",
+    "ZREVRANGEBYLEX", "ZREVRANGEBYSCORE", "ZREVRANK", "ZSCAN", "ZSCORE",
+];
+
+pub struct ReplayOptions {
+    // Multiplies the captured pace. 2.0 replays twice as fast. 0 sends every request as soon as possible.
+    pub speed: f64,
+    // Skips requests that could modify data.
+    pub read_only: bool,
+    // How long to wait for outstanding replies once every request is sent.
+    pub drain_timeout: Duration,
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct ReplayStats {
+    pub requests: usize,
+    pub skipped: usize,
+    pub replies: usize,
+    // Replies that are identical to the captured response.
+    pub matched: usize,
+    pub mismatched: usize,
+    // Requests that never got a reply.
+    pub missing_replies: usize,
+}
+
+impl std::fmt::Display for ReplayStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "requests={} skipped={} replies={} matched={} mismatched={} missing_replies={}",
+            self.requests, self.skipped, self.replies, self.matched, self.mismatched, self.missing_replies
+        )
+    }
+}
+
+/*
+    A connection to the target, replaying the requests of one captured client connection.
+*/
+struct ReplayClient {
+    stream: Option<TcpStream>,
+    // Requests that haven't been written yet.
+    output: Vec<u8>,
+    // Bytes read that don't make up a full reply yet.
+    input: Vec<u8>,
+    // Whether each captured request was sent, in order. Used to pair captured responses with sent requests.
+    sent: VecDeque<bool>,
+    outstanding: usize,
+    // Replies and captured responses that haven't been compared yet.
+    replies: VecDeque<Vec<u8>>,
+    expected: VecDeque<Vec<u8>>,
+}
+
+impl ReplayClient {
+    fn new() -> ReplayClient {
+        ReplayClient {
+            stream: None,
+            output: Vec::new(),
+            input: Vec::new(),
+            sent: VecDeque::new(),
+            outstanding: 0,
+            replies: VecDeque::new(),
+            expected: VecDeque::new(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => return Ok(()),
+        };
+        while !self.output.is_empty() {
+            match stream.write(&self.output) {
+                Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "connection closed")),
+                Ok(len) => {
+                    self.output.drain(..len);
+                }
+                Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        return Ok(());
+    }
+
+    fn read(&mut self, stats: &mut ReplayStats) -> Result<(), std::io::Error> {
+        {
+            let stream = match self.stream {
+                Some(ref mut stream) => stream,
+                None => return Ok(()),
+            };
+            let mut buf = [0; 16384];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")),
+                    Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                    Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        while let Some(len) = reply_len(&self.input, 0) {
+            let reply: Vec<u8> = self.input.drain(..len).collect();
+            stats.replies += 1;
+            self.outstanding = self.outstanding.saturating_sub(1);
+            self.replies.push_back(reply);
+        }
+        self.compare(stats);
+        return Ok(());
+    }
+
+    fn compare(&mut self, stats: &mut ReplayStats) {
+        while !self.replies.is_empty() && !self.expected.is_empty() {
+            let reply = self.replies.pop_front().unwrap();
+            let expected = self.expected.pop_front().unwrap();
+            if reply == expected {
+                stats.matched += 1;
+            } else {
+                stats.mismatched += 1;
+                debug!("Reply {:?} differs from the captured {:?}", String::from_utf8_lossy(&reply), String::from_utf8_lossy(&expected));
+            }
+        }
+    }
+}
+
+/*
+    Replays a capture file against a pool, keeping the captured pacing and connections: each captured client
+    connection is replayed on its own connection, and each request is sent at its captured time (scaled by speed).
+    Replies are compared with the captured responses.
+*/
+pub struct Replayer {
+    target: SocketAddr,
+    options: ReplayOptions,
+    poll: Poll,
+    clients: HashMap<ClientId, ReplayClient>,
+    pub stats: ReplayStats,
+}
+
+impl Replayer {
+    pub fn new(target: SocketAddr, options: ReplayOptions) -> Result<Replayer, std::io::Error> {
+        Ok(Replayer {
+            target: target,
+            options: options,
+            poll: try!(Poll::new()),
+            clients: HashMap::new(),
+            stats: ReplayStats::default(),
+        })
+    }
+
+    pub fn run<R: BufRead>(&mut self, reader: &mut R) -> Result<(), std::io::Error> {
+        let start = Instant::now();
+        let mut events = Events::with_capacity(1024);
+        let mut next_frame = try!(read_frame(reader));
+        let mut drain_deadline = None;
+        loop {
+            let now = Instant::now();
+            let wait = match next_frame {
+                Some(ref frame) => self.time_until_due(frame, start, now),
+                None => {
+                    let outstanding: usize = self.clients.values().map(|client| client.outstanding).sum();
+                    let deadline = *drain_deadline.get_or_insert(now + self.options.drain_timeout);
+                    if outstanding == 0 || now >= deadline {
+                        self.stats.missing_replies = outstanding;
+                        return Ok(());
+                    }
+                    deadline - now
+                }
+            };
+            if wait > Duration::from_secs(0) {
+                try!(self.poll.poll(&mut events, Some(wait)));
+                for event in events.iter() {
+                    try!(self.handle_event(event.token().0));
+                }
+                continue;
+            }
+            if let Some(frame) = next_frame {
+                try!(self.replay_frame(frame));
+            }
+            next_frame = try!(read_frame(reader));
+        }
+    }
+
+    fn time_until_due(&self, frame: &Frame, start: Instant, now: Instant) -> Duration {
+        if frame.direction == Direction::Response || self.options.speed <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let due = start + Duration::from_micros((frame.offset_us as f64 / self.options.speed) as u64);
+        if now >= due {
+            return Duration::from_secs(0);
+        }
+        return due - now;
+    }
+
+    fn replay_frame(&mut self, frame: Frame) -> Result<(), std::io::Error> {
+        let client = self.clients.entry(frame.client).or_insert_with(ReplayClient::new);
+        match frame.direction {
+            Direction::Request => {
+                if self.options.read_only && !is_read_only(&frame.data) {
+                    client.sent.push_back(false);
+                    self.stats.skipped += 1;
+                    return Ok(());
+                }
+                if client.stream.is_none() {
+                    let stream = try!(TcpStream::from_stream(try!(std::net::TcpStream::connect(self.target))));
+                    try!(self.poll.register(&stream, Token(frame.client), Ready::readable() | Ready::writable(), PollOpt::edge()));
+                    client.stream = Some(stream);
+                }
+                client.output.extend_from_slice(&frame.data);
+                client.sent.push_back(true);
+                client.outstanding += 1;
+                self.stats.requests += 1;
+                try!(client.flush());
+            }
+            Direction::Response => {
+                if client.sent.pop_front() == Some(true) {
+                    client.expected.push_back(frame.data);
+                    client.compare(&mut self.stats);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, client_id: ClientId) -> Result<(), std::io::Error> {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            try!(client.flush());
+            try!(client.read(&mut self.stats));
+        }
+        return Ok(());
+    }
+}
+
+// Whether the request's command can't modify data.
+fn is_read_only(request: &[u8]) -> bool {
+    let name = match command_name(request) {
+        Some(name) => name.to_ascii_uppercase(),
+        None => return false,
+    };
+    return READ_ONLY_COMMANDS.binary_search_by(|command| command.as_bytes().cmp(&name[..])).is_ok();
+}
+
+// The command name of a multibulk request.
+fn command_name(request: &[u8]) -> Option<&[u8]> {
+    if request.first() != Some(&b'*') {
+        return None;
+    }
+    let start = try_opt!(line_end(request, 0)) + 2;
+    if request.get(start) != Some(&b'$') {
+        return None;
+    }
+    let end = try_opt!(line_end(request, start));
+    let len = try_opt!(parse_num(&request[start + 1..end])) as usize;
+    return request.get(end + 2..end + 2 + len);
+}
+
+// Position of the next CRLF at or after start.
+fn line_end(buf: &[u8], start: usize) -> Option<usize> {
+    if start >= buf.len() {
+        return None;
+    }
+    return buf[start..].windows(2).position(|window| window == b"\r\n").map(|position| start + position);
+}
+
+fn parse_num(bytes: &[u8]) -> Option<i64> {
+    return std::str::from_utf8(bytes).ok().and_then(|num| num.parse::<i64>().ok());
+}
+
+// Length of the complete reply at the start of buf, or None if it isn't complete yet.
+fn reply_len(buf: &[u8], start: usize) -> Option<usize> {
+    let end = try_opt!(line_end(buf, start));
+    match buf.get(start) {
+        Some(&b'+') | Some(&b'-') | Some(&b':') => Some(end + 2 - start),
+        Some(&b'$') => {
+            let len = try_opt!(parse_num(&buf[start + 1..end]));
+            if len < 0 {
+                return Some(end + 2 - start);
+            }
+            let reply_end = end + 2 + len as usize + 2;
+            if buf.len() < reply_end {
+                return None;
+            }
+            Some(reply_end - start)
+        }
+        Some(&b'*') => {
+            let count = try_opt!(parse_num(&buf[start + 1..end]));
+            let mut index = end + 2;
+            for _ in 0..std::cmp::max(count, 0) {
+                index += try_opt!(reply_len(buf, index));
+            }
+            Some(index - start)
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_reply_len() {
+    assert_eq!(reply_len(b"+OK\r\n", 0), Some(5));
+    assert_eq!(reply_len(b"+OK\r", 0), None);
+    assert_eq!(reply_len(b"$-1\r\n", 0), Some(5));
+    assert_eq!(reply_len(b"$6\r\nvalue1\r\n+OK\r\n", 0), Some(12));
+    assert_eq!(reply_len(b"$6\r\nval", 0), None);
+    assert_eq!(reply_len(b"*2\r\n$2\r\nv1\r\n:5\r\n", 0), Some(16));
+    assert_eq!(reply_len(b"*2\r\n$2\r\nv1\r\n", 0), None);
+    assert_eq!(reply_len(b"*0\r\n", 0), Some(4));
+}
+
+#[test]
+fn test_is_read_only() {
+    let mut sorted = READ_ONLY_COMMANDS.to_vec();
+    sorted.sort();
+    assert_eq!(sorted, READ_ONLY_COMMANDS.to_vec());
+
+    assert!(is_read_only(b"*2\r\n$3\r\nget\r\n$2\r\nk1\r\n"));
+    assert!(is_read_only(b"*3\r\n$4\r\nMGET\r\n$2\r\nk1\r\n$2\r\nk2\r\n"));
+    assert!(!is_read_only(b"*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$1\r\nv\r\n"));
+    assert!(!is_read_only(b"*2\r\n$3\r\nDEL\r\n$2\r\nk1\r\n"));
+    assert!(!is_read_only(b"GET k1\r\n"));
+}
+
+#[test]
+fn test_replay() {
+    use std::net::TcpListener;
+
+    // A target that answers every request with +OK.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            std::thread::spawn(move || {
+                let mut buf = [0; 1024];
+                let mut input = Vec::new();
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(len) => input.extend_from_slice(&buf[..len]),
+                    }
+                    while let Some(len) = reply_len(&input, 0) {
+                        input.drain(..len);
+                        stream.write_all(b"+OK\r\n").unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    let capture = b"# redflare capture pool=pool1 start=2019-03-01T12:00:00Z sample_rate=1\n\
+        > 0 20 28\r\n*3\r\n$3\r\nSET\r\n$2\r\nk1\r\n$1\r\nv\r\n\r\n\
+        > 100 21 21\r\n*2\r\n$3\r\nGET\r\n$2\r\nk1\r\n\r\n\
+        < 200 20 5\r\n+OK\r\n\r\n\
+        < 300 21 7\r\n$1\r\nv\r\n\r\n";
+    let options = ReplayOptions { speed: 0.0, read_only: false, drain_timeout: Duration::from_secs(5) };
+    let mut replayer = Replayer::new(target, options).unwrap();
+    replayer.run(&mut &capture[..]).unwrap();
+    assert_eq!(replayer.stats, ReplayStats { requests: 2, skipped: 0, replies: 2, matched: 1, mismatched: 1, missing_replies: 0 });
+
+    let options = ReplayOptions { speed: 0.0, read_only: true, drain_timeout: Duration::from_secs(5) };
+    let mut replayer = Replayer::new(target, options).unwrap();
+    replayer.run(&mut &capture[..]).unwrap();
+    assert_eq!(replayer.stats, ReplayStats { requests: 1, skipped: 1, replies: 1, matched: 0, mismatched: 1, missing_replies: 0 });
+    assert_eq!(replayer.stats.to_string(), "requests=1 skipped=1 replies=1 matched=0 mismatched=1 missing_replies=0");
+}
@@ -0,0 +1,99 @@
+use backend::SingleBackend;
+use redflareproxy::{convert_token_to_cluster_index, BackendToken, ClientToken};
+use redisprotocol::{command_name, is_read_only, WriteError};
+use stats::Stats;
+use std::time::Instant;
+use trace::RequestTrace;
+
+/*
+    The replicas of a non-cluster backend, which answer its read commands while it's down when the pool has
+    replica_fallback. Writes still fail until the backend is back. With read_from_replicas, they answer its reads
+    while it's up too.
+
+    Like the hosts of a cluster backend, each replica is a SingleBackend in cluster_backends, with a ClusterServer token.
+    Its events and timers are handed to the backend that owns it, which passes them on.
+*/
+pub struct Replicas {
+    tokens: Vec<BackendToken>,
+    // Replica to try first for the next read, so that reads are spread over them.
+    next: usize,
+}
+
+impl Replicas {
+    pub fn new(tokens: Vec<BackendToken>) -> Replicas {
+        Replicas {
+            tokens: tokens,
+            next: 0,
+        }
+    }
+
+    pub fn owns(&self, token: BackendToken) -> bool {
+        return self.tokens.contains(&token);
+    }
+
+    // Whether a replica would answer the request, if the backend is down.
+    pub fn serves(&self, message: &[u8]) -> bool {
+        return !self.tokens.is_empty() && is_read_only(command_name(message));
+    }
+
+    // Whether any of the replicas is available for a read.
+    pub fn any_available(&self, cluster_backends: &[(SingleBackend, usize)]) -> bool {
+        return self.get(cluster_backends).iter().any(|replica| replica.is_available());
+    }
+
+    pub fn get<'a>(&self, cluster_backends: &'a [(SingleBackend, usize)]) -> Vec<&'a SingleBackend> {
+        let mut replicas = Vec::with_capacity(self.tokens.len());
+        for token in self.tokens.iter() {
+            match cluster_backends.get(convert_token_to_cluster_index(token.0)) {
+                Some((replica, _)) => replicas.push(replica),
+                None => error!("Backend is referencing a replica that does not exist: {:?}", token),
+            }
+        }
+        return replicas;
+    }
+
+    pub fn get_mut<'a>(&self, cluster_backends: &'a mut [(SingleBackend, usize)]) -> Vec<&'a mut SingleBackend> {
+        let indices: Vec<usize> = self.tokens.iter().map(|token| convert_token_to_cluster_index(token.0)).collect();
+        return cluster_backends
+            .iter_mut()
+            .enumerate()
+            .filter(|&(index, _)| indices.contains(&index))
+            .map(|(_, (replica, _))| replica)
+            .collect();
+    }
+
+    // Points the replicas' events at the owning backend's new token.
+    pub fn change_owner(&self, cluster_backends: &mut [(SingleBackend, usize)], owner: BackendToken, num_backends: usize) {
+        for token in self.tokens.iter() {
+            if let Some((replica, owner_token_value)) = cluster_backends.get_mut(convert_token_to_cluster_index(token.0)) {
+                replica.num_backends = num_backends;
+                *owner_token_value = owner.0;
+            }
+        }
+    }
+
+    // Sends a read to the next replica that's available. Fails if none are.
+    pub fn write_message(
+        &mut self,
+        message: &[u8],
+        client_token: ClientToken,
+        cluster_backends: &mut [(SingleBackend, usize)],
+        request_id: (Instant, usize),
+        trace: Option<RequestTrace>,
+        timeout: Option<usize>,
+        stats: &mut Stats,
+    ) -> Result<bool, WriteError> {
+        for attempt in 0..self.tokens.len() {
+            let position = (self.next + attempt) % self.tokens.len();
+            let index = convert_token_to_cluster_index(self.tokens[position].0);
+            let replica = match cluster_backends.get_mut(index) {
+                Some((replica, _)) if replica.is_available() => replica,
+                _ => continue,
+            };
+            self.next = position + 1;
+            debug!("Sending a read from {:?} to a replica", client_token);
+            return replica.write_message(message, client_token, request_id, trace, timeout, stats);
+        }
+        return Err(WriteError::BackendNotReady);
+    }
+}
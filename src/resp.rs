@@ -0,0 +1,419 @@
+use memchr::memchr;
+use std::fmt;
+
+/*
+    The RESP framing of requests and responses, without any state of the proxy, so that it can be fuzzed on its own
+    (see fuzz/). Nothing in here panics or reads out of bounds, whatever the input: every length is checked against the
+    input and against the limits below, and arithmetic on lengths is checked.
+
+    Input may arrive in pieces. A frame cut off at any point is Incomplete, which isn't an error: the caller reads more
+    after what it has, and scans again, with a FrameScanner to carry on from where the last scan stopped.
+*/
+
+// The largest bulk string, the same as redis' default proto-max-bulk-len.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// The most elements an array may have.
+pub const MAX_ARRAY_LEN: usize = std::i32::MAX as usize;
+// Longer number lines than this can't be a valid length or integer.
+const MAX_NUMBER_DIGITS: usize = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // The input ends before the frame does.
+    Incomplete,
+    // A frame starting with a byte that isn't a RESP type.
+    UnknownType(u8),
+    // A length or integer that isn't a decimal number.
+    InvalidNumber,
+    // A negative length other than the -1 of a null, or one over the limits.
+    InvalidLength,
+    // A line or bulk string that doesn't end in CRLF.
+    MissingCrlf,
+    // A request that isn't a non-empty array of bulk strings.
+    NotACommand,
+    // A frame over one of the Limits of its scanner, which is rejected as soon as its header shows it.
+    TooLarge(Limit),
+}
+
+// One of the Limits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    FrameLen,
+    ArrayLen,
+    BulkLen,
+}
+
+/*
+    Limits on the frames that a FrameScanner accepts, below the most that RESP allows, e.g. for a pool's requests.
+    Lengths are checked as soon as their header arrives, so a frame over them is rejected before it's buffered.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    // Bytes of the whole frame.
+    pub frame_len: usize,
+    // Elements of each array.
+    pub array_len: usize,
+    // Bytes of each bulk string.
+    pub bulk_len: usize,
+}
+
+// The most that RESP allows.
+pub const NO_LIMITS: Limits = Limits {
+    frame_len: std::usize::MAX,
+    array_len: MAX_ARRAY_LEN,
+    bulk_len: MAX_BULK_LEN,
+};
+
+impl Limits {
+    // Limits of the given sizes, where 0 is the most that RESP allows.
+    pub fn new(frame_len: usize, array_len: usize, bulk_len: usize) -> Limits {
+        let or_max = |limit: usize, max: usize| if limit == 0 { max } else { std::cmp::min(limit, max) };
+        return Limits {
+            frame_len: or_max(frame_len, NO_LIMITS.frame_len),
+            array_len: or_max(array_len, NO_LIMITS.array_len),
+            bulk_len: or_max(bulk_len, NO_LIMITS.bulk_len),
+        };
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Incomplete => write!(f, "Incomplete frame"),
+            ParseError::UnknownType(byte) => write!(f, "Unknown frame type {:?}", byte as char),
+            ParseError::InvalidNumber => write!(f, "Invalid number"),
+            ParseError::InvalidLength => write!(f, "Invalid length"),
+            ParseError::MissingCrlf => write!(f, "Missing CRLF"),
+            ParseError::NotACommand => write!(f, "Not a command array"),
+            ParseError::TooLarge(Limit::FrameLen) => write!(f, "Frame too large"),
+            ParseError::TooLarge(Limit::ArrayLen) => write!(f, "Array too long"),
+            ParseError::TooLarge(Limit::BulkLen) => write!(f, "Bulk string too large"),
+        }
+    }
+}
+
+/*
+    Finds the end of a frame in input that grows between calls. Elements that were complete at the last call aren't
+    scanned again. Once a frame is complete, or on an error, the scanner starts over, for the frame after it.
+*/
+pub struct FrameScanner {
+    // Where the next element starts.
+    index: usize,
+    // Elements left to scan, counting those of the arrays scanned so far. An array only adds to the count, so nested
+    // arrays need neither recursion nor a stack.
+    pending: usize,
+    limits: Limits,
+}
+
+impl FrameScanner {
+    pub fn new() -> FrameScanner {
+        return FrameScanner::with_limits(NO_LIMITS);
+    }
+
+    pub fn with_limits(limits: Limits) -> FrameScanner {
+        FrameScanner {
+            index: 0,
+            pending: 1,
+            limits: limits,
+        }
+    }
+
+    // Returns the length of the frame at the start of bytes, once all of it has arrived.
+    pub fn scan(&mut self, bytes: &[u8]) -> Result<usize, ParseError> {
+        while self.pending > 0 {
+            let (next, elements) = match scan_element(bytes, self.index, &self.limits) {
+                Ok(scanned) => scanned,
+                // Whatever else follows is part of the frame.
+                Err(ParseError::Incomplete) if bytes.len() > self.limits.frame_len => return Err(self.fail(ParseError::TooLarge(Limit::FrameLen))),
+                Err(ParseError::Incomplete) => return Err(ParseError::Incomplete),
+                Err(err) => return Err(self.fail(err)),
+            };
+            if next > self.limits.frame_len {
+                return Err(self.fail(ParseError::TooLarge(Limit::FrameLen)));
+            }
+            self.index = next;
+            self.pending = match (self.pending - 1).checked_add(elements) {
+                Some(pending) => pending,
+                None => return Err(self.fail(ParseError::InvalidLength)),
+            };
+        }
+        let len = self.index;
+        *self = FrameScanner::with_limits(self.limits);
+        return Ok(len);
+    }
+
+    // Starts over, after an error.
+    fn fail(&mut self, err: ParseError) -> ParseError {
+        *self = FrameScanner::with_limits(self.limits);
+        return err;
+    }
+}
+
+// The length of the frame at the start of bytes.
+pub fn frame_len(bytes: &[u8]) -> Result<usize, ParseError> {
+    return FrameScanner::new().scan(bytes);
+}
+
+// The length of the frame at the start of bytes, which must be within the limits.
+pub fn frame_len_within(bytes: &[u8], limits: Limits) -> Result<usize, ParseError> {
+    return FrameScanner::with_limits(limits).scan(bytes);
+}
+
+// Scans the element at index. Returns where the next one starts, and how many elements it contains, if it's an array.
+fn scan_element(bytes: &[u8], index: usize, limits: &Limits) -> Result<(usize, usize), ParseError> {
+    let frame_type = match bytes.get(index) {
+        Some(frame_type) => *frame_type,
+        None => return Err(ParseError::Incomplete),
+    };
+    match frame_type {
+        b'+' | b'-' => {
+            let (_, next) = try!(read_line(bytes, index + 1));
+            return Ok((next, 0));
+        }
+        b':' => {
+            let (_, next) = try!(read_number(bytes, index + 1));
+            return Ok((next, 0));
+        }
+        b'$' => {
+            let (len, start) = try!(read_length(bytes, index + 1, MAX_BULK_LEN));
+            match len {
+                Some(len) if len > limits.bulk_len => return Err(ParseError::TooLarge(Limit::BulkLen)),
+                // Can't overflow, since start is within bytes, and len is at most MAX_BULK_LEN.
+                Some(len) if start + len + 2 > limits.frame_len => return Err(ParseError::TooLarge(Limit::FrameLen)),
+                _ => {}
+            }
+            let (_, next) = try!(read_bulk_body(bytes, len, start));
+            return Ok((next, 0));
+        }
+        b'*' => {
+            let (len, next) = try!(read_length(bytes, index + 1, MAX_ARRAY_LEN));
+            let len = len.unwrap_or(0);
+            if len > limits.array_len {
+                return Err(ParseError::TooLarge(Limit::ArrayLen));
+            }
+            return Ok((next, len));
+        }
+        other => return Err(ParseError::UnknownType(other)),
+    }
+}
+
+// Reads the line starting at index. Returns it without its CRLF, and where the next line starts.
+fn read_line(bytes: &[u8], index: usize) -> Result<(&[u8], usize), ParseError> {
+    let rest = match bytes.get(index..) {
+        Some(rest) => rest,
+        None => return Err(ParseError::Incomplete),
+    };
+    let newline = match memchr(b'\n', rest) {
+        Some(newline) => newline,
+        None => return Err(ParseError::Incomplete),
+    };
+    if newline == 0 || rest[newline - 1] != b'\r' {
+        return Err(ParseError::MissingCrlf);
+    }
+    return Ok((&rest[..newline - 1], index + newline + 1));
+}
+
+// Reads a line holding a decimal integer, e.g. after a ':', or a length.
+fn read_number(bytes: &[u8], index: usize) -> Result<(i64, usize), ParseError> {
+    let (line, next) = match read_line(bytes, index) {
+        // A number line that's already too long to be valid won't become valid with more input.
+        Err(ParseError::Incomplete) if bytes.len().saturating_sub(index) > MAX_NUMBER_DIGITS + 2 => return Err(ParseError::InvalidNumber),
+        result => try!(result),
+    };
+    let (negative, digits) = match line.first() {
+        Some(&b'-') => (true, &line[1..]),
+        _ => (false, line),
+    };
+    if digits.is_empty() || digits.len() > MAX_NUMBER_DIGITS {
+        return Err(ParseError::InvalidNumber);
+    }
+    let mut number: i64 = 0;
+    for digit in digits {
+        if !digit.is_ascii_digit() {
+            return Err(ParseError::InvalidNumber);
+        }
+        number = match number.checked_mul(10).and_then(|number| number.checked_add((digit - b'0') as i64)) {
+            Some(number) => number,
+            None => return Err(ParseError::InvalidNumber),
+        };
+    }
+    return Ok((if negative { -number } else { number }, next));
+}
+
+// Reads the length of a bulk string or array. None for a null, which is -1.
+fn read_length(bytes: &[u8], index: usize, max: usize) -> Result<(Option<usize>, usize), ParseError> {
+    let (len, next) = try!(read_number(bytes, index));
+    if len == -1 {
+        return Ok((None, next));
+    }
+    if len < 0 || len as u64 > max as u64 {
+        return Err(ParseError::InvalidLength);
+    }
+    return Ok((Some(len as usize), next));
+}
+
+// Reads the bulk string starting at index, which must be at its '$'. None for a null bulk string.
+fn read_bulk(bytes: &[u8], index: usize) -> Result<(Option<&[u8]>, usize), ParseError> {
+    let (len, start) = try!(read_length(bytes, index + 1, MAX_BULK_LEN));
+    return read_bulk_body(bytes, len, start);
+}
+
+// Reads the contents of a bulk string of the given length, after its header.
+fn read_bulk_body(bytes: &[u8], len: Option<usize>, start: usize) -> Result<(Option<&[u8]>, usize), ParseError> {
+    let len = match len {
+        Some(len) => len,
+        None => return Ok((None, start)),
+    };
+    // Can't overflow, since start is within bytes, and len is at most MAX_BULK_LEN.
+    let end = start + len;
+    if bytes.len() < end + 2 {
+        return Err(ParseError::Incomplete);
+    }
+    if bytes[end..end + 2] != b"\r\n"[..] {
+        return Err(ParseError::MissingCrlf);
+    }
+    return Ok((Some(&bytes[start..end]), end + 2));
+}
+
+/*
+    The arguments of a request, which is an array of bulk strings, e.g. GET key. The arguments are only parsed as
+    they're taken, so a request needn't be complete for its first arguments to be read.
+*/
+pub struct Args<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    remaining: usize,
+}
+
+impl<'a> Args<'a> {
+    // Reads the array header of the request at the start of bytes.
+    pub fn parse(bytes: &'a [u8]) -> Result<Args<'a>, ParseError> {
+        match bytes.first() {
+            Some(&b'*') => {}
+            Some(_) => return Err(ParseError::NotACommand),
+            None => return Err(ParseError::Incomplete),
+        }
+        let (len, index) = match try!(read_length(bytes, 1, MAX_ARRAY_LEN)) {
+            (Some(len), index) if len > 0 => (len, index),
+            _ => return Err(ParseError::NotACommand),
+        };
+        return Ok(Args {
+            bytes: bytes,
+            index: index,
+            remaining: len,
+        });
+    }
+
+    // The number of arguments not taken yet.
+    pub fn remaining(&self) -> usize {
+        return self.remaining;
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Result<&'a [u8], ParseError>;
+
+    // After an error, there are no more arguments.
+    fn next(&mut self) -> Option<Result<&'a [u8], ParseError>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let result = match self.bytes.get(self.index) {
+            Some(&b'$') => read_bulk(self.bytes, self.index),
+            Some(_) => Err(ParseError::NotACommand),
+            None => Err(ParseError::Incomplete),
+        };
+        match result {
+            Ok((Some(arg), next)) => {
+                self.index = next;
+                return Some(Ok(arg));
+            }
+            Ok((None, _)) => {
+                self.remaining = 0;
+                return Some(Err(ParseError::NotACommand));
+            }
+            Err(err) => {
+                self.remaining = 0;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_frame_len() {
+    assert_eq!(frame_len(b"+OK\r\nrest"), Ok(5));
+    assert_eq!(frame_len(b"$-1\r\n"), Ok(5));
+    assert_eq!(frame_len(b"*-1\r\n"), Ok(5));
+    assert_eq!(frame_len(b"$0\r\n\r\n"), Ok(6));
+    assert_eq!(frame_len(b"*2\r\n*1\r\n:1\r\n$2\r\nab\r\n"), Ok(20));
+    assert_eq!(frame_len(b"*0\r\n"), Ok(4));
+
+    assert_eq!(frame_len(b"?\r\n"), Err(ParseError::UnknownType(b'?')));
+    assert_eq!(frame_len(b"+OK\n"), Err(ParseError::MissingCrlf));
+    assert_eq!(frame_len(b"$2\r\nabc\r\n"), Err(ParseError::MissingCrlf));
+    assert_eq!(frame_len(b"$-2\r\n"), Err(ParseError::InvalidLength));
+    assert_eq!(frame_len(b"$536870913\r\n"), Err(ParseError::InvalidLength));
+    assert_eq!(frame_len(b"*2-\r\n"), Err(ParseError::InvalidNumber));
+    assert_eq!(frame_len(b":99999999999999999999\r\n"), Err(ParseError::InvalidNumber));
+    assert_eq!(frame_len(b"$\r\n"), Err(ParseError::InvalidNumber));
+    assert_eq!(frame_len(b"*1111111111111111111111111"), Err(ParseError::InvalidNumber));
+}
+
+#[test]
+fn test_incremental_scan() {
+    let frame = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n*2\r\n+x\r\n:-5\r\n";
+    for len in 0..frame.len() {
+        assert_eq!(frame_len(&frame[..len]), Err(ParseError::Incomplete), "{}", len);
+    }
+    let mut scanner = FrameScanner::new();
+    for len in 0..frame.len() {
+        assert_eq!(scanner.scan(&frame[..len]), Err(ParseError::Incomplete));
+    }
+    assert_eq!(scanner.scan(frame), Ok(frame.len()));
+    // The scanner starts over for the next frame.
+    assert_eq!(scanner.scan(b"+OK\r\n"), Ok(5));
+
+    // Deeply nested arrays don't recurse.
+    let mut nested = b"*1\r\n".repeat(100000);
+    nested.extend_from_slice(b":1\r\n");
+    assert_eq!(frame_len(&nested), Ok(nested.len()));
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits::new(30, 3, 4);
+    assert_eq!(frame_len_within(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\nabcd\r\n", limits), Ok(30));
+    assert_eq!(frame_len_within(b"*4\r\n", limits), Err(ParseError::TooLarge(Limit::ArrayLen)));
+    // Rejected from the header, before the rest of the bulk string arrives.
+    assert_eq!(frame_len_within(b"*2\r\n$3\r\nGET\r\n$5\r\n", limits), Err(ParseError::TooLarge(Limit::BulkLen)));
+    assert_eq!(frame_len_within(b"*3\r\n$3\r\nSET\r\n$4\r\nabcd\r\n$4\r\n", limits), Err(ParseError::TooLarge(Limit::FrameLen)));
+    assert_eq!(frame_len_within(b"*3\r\n$3\r\nSET\r\n$4\r\nabcd\r\n$1\r\n", limits), Err(ParseError::Incomplete));
+    // Lines have no length header, so they're over once there's more of them than the limit.
+    assert_eq!(frame_len_within(&[b'+'; 31], limits), Err(ParseError::TooLarge(Limit::FrameLen)));
+    assert_eq!(frame_len_within(&[b'+'; 30], limits), Err(ParseError::Incomplete));
+
+    assert_eq!(Limits::new(0, 0, 0), NO_LIMITS);
+    assert_eq!(Limits::new(0, 0, std::usize::MAX).bulk_len, MAX_BULK_LEN);
+}
+
+#[test]
+fn test_args() {
+    let mut args = Args::parse(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$0\r\n\r\n").unwrap();
+    assert_eq!(args.remaining(), 3);
+    assert_eq!(args.next(), Some(Ok(&b"SET"[..])));
+    assert_eq!(args.next(), Some(Ok(&b"a"[..])));
+    assert_eq!(args.next(), Some(Ok(&b""[..])));
+    assert_eq!(args.next(), None);
+
+    assert_eq!(Args::parse(b"*0\r\n").err(), Some(ParseError::NotACommand));
+    assert_eq!(Args::parse(b"+OK\r\n").err(), Some(ParseError::NotACommand));
+    assert_eq!(Args::parse(b"*2\r").err(), Some(ParseError::Incomplete));
+    let mut args = Args::parse(b"*2\r\n:1\r\n$1\r\na\r\n").unwrap();
+    assert_eq!(args.next(), Some(Err(ParseError::NotACommand)));
+    assert_eq!(args.next(), None);
+    let mut args = Args::parse(b"*2\r\n$3\r\nGET\r\n$1\r\n").unwrap();
+    assert_eq!(args.next(), Some(Ok(&b"GET"[..])));
+    assert_eq!(args.next(), Some(Err(ParseError::Incomplete)));
+}
@@ -0,0 +1,85 @@
+use config::{BackendPoolConfig, RetryStrategy};
+use rand::thread_rng;
+use rand::Rng;
+
+/*
+    Determines how long a backend waits before attempting to reconnect after a failure.
+    Each backend keeps its own copy, so that the delay grows independently per backend.
+*/
+#[derive(Clone)]
+pub struct RetryPolicy {
+    strategy: RetryStrategy,
+    // Delay in ms for the first retry.
+    base: usize,
+    // Upper bound in ms for any retry delay.
+    cap: usize,
+    // Delay in ms that was used for the previous retry. 0 means no retry has happened since the last success.
+    current: usize,
+}
+
+impl RetryPolicy {
+    pub fn new(strategy: RetryStrategy, base: usize, cap: usize) -> RetryPolicy {
+        RetryPolicy {
+            strategy: strategy,
+            base: base,
+            cap: if cap < base { base } else { cap },
+            current: 0,
+        }
+    }
+
+    pub fn from_config(config: &BackendPoolConfig) -> RetryPolicy {
+        RetryPolicy::new(config.retry_strategy.clone(), config.retry_timeout, config.max_retry_timeout)
+    }
+
+    // Returns the delay in ms to wait before the next reconnect attempt.
+    pub fn next_delay(&mut self) -> usize {
+        let next = match self.strategy {
+            RetryStrategy::Fixed => self.base,
+            RetryStrategy::Exponential => {
+                if self.current == 0 {
+                    self.base
+                } else {
+                    self.current.saturating_mul(2)
+                }
+            }
+            RetryStrategy::DecorrelatedJitter => {
+                let upper = std::cmp::max(self.current, self.base).saturating_mul(3);
+                if upper <= self.base {
+                    self.base
+                } else {
+                    thread_rng().gen_range(self.base, upper + 1)
+                }
+            }
+        };
+        self.current = std::cmp::min(next, self.cap);
+        self.current
+    }
+
+    // Called once the backend is available again.
+    pub fn reset(&mut self) {
+        self.current = 0;
+    }
+}
+
+#[test]
+fn test_retry_delays() {
+    let mut fixed = RetryPolicy::new(RetryStrategy::Fixed, 100, 1000);
+    assert_eq!(fixed.next_delay(), 100);
+    assert_eq!(fixed.next_delay(), 100);
+
+    let mut exponential = RetryPolicy::new(RetryStrategy::Exponential, 100, 1000);
+    assert_eq!(exponential.next_delay(), 100);
+    assert_eq!(exponential.next_delay(), 200);
+    assert_eq!(exponential.next_delay(), 400);
+    assert_eq!(exponential.next_delay(), 800);
+    assert_eq!(exponential.next_delay(), 1000);
+    assert_eq!(exponential.next_delay(), 1000);
+    exponential.reset();
+    assert_eq!(exponential.next_delay(), 100);
+
+    let mut jitter = RetryPolicy::new(RetryStrategy::DecorrelatedJitter, 100, 1000);
+    for _ in 0..100 {
+        let delay = jitter.next_delay();
+        assert!(delay >= 100 && delay <= 1000);
+    }
+}
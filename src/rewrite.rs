@@ -0,0 +1,201 @@
+use config::RewriteRuleConfig;
+use filter::{encode_command, Filter, FilterAction, FilterContext};
+use resp::Args;
+
+// The commands that max_count applies to, and the index of their first option.
+static COUNT_COMMANDS: [(&str, usize); 6] = [("HSCAN", 3), ("SCAN", 2), ("SSCAN", 3), ("XRANGE", 4), ("XREVRANGE", 4), ("ZSCAN", 3)];
+
+// Options of SET that give the key an expiry, or keep the one it has.
+static SET_EXPIRY_OPTIONS: [&str; 5] = ["EX", "EXAT", "KEEPTTL", "PX", "PXAT"];
+
+struct Rule {
+    command: String,
+    rename: Option<Vec<u8>>,
+    default_ttl: usize,
+    max_count: usize,
+    // The index of the first option of the command, for max_count.
+    options_start: usize,
+}
+
+/*
+    The rewrite_rules of a pool, which rewrite requests declaratively, e.g. to send a deprecated command as its
+    replacement, give the keys of SETs a default expiry, or keep SCANs from asking for too much at once. They run as the
+    first of the pool's filters, before its lua_filter and wasm_filter.
+
+    Requests that no rule's command matches are passed on as they are, without being copied.
+*/
+pub struct RewriteRules {
+    rules: Vec<Rule>,
+}
+
+impl RewriteRules {
+    pub fn from_config(configs: &[RewriteRuleConfig]) -> Result<RewriteRules, String> {
+        let mut rules = Vec::new();
+        for config in configs {
+            let command = config.command.to_ascii_uppercase();
+            if command.is_empty() {
+                return Err("Rewrite rule is missing a command".to_owned());
+            }
+            if config.rename.is_none() && config.default_ttl == 0 && config.max_count == 0 {
+                return Err(format!("Rewrite rule of {} doesn't rewrite anything. Expected rename, default_ttl or max_count", command));
+            }
+            if let Some(ref rename) = config.rename {
+                if rename.is_empty() || rename.contains(char::is_whitespace) {
+                    return Err(format!("Invalid rename of {}: '{}'", command, rename));
+                }
+            }
+            if config.default_ttl > 0 && command != "SET" {
+                return Err(format!("default_ttl only applies to SET, not {}", command));
+            }
+            let options_start = match COUNT_COMMANDS.iter().find(|&&(name, _)| name == command) {
+                Some(&(_, options_start)) => options_start,
+                None if config.max_count > 0 => {
+                    let names: Vec<&str> = COUNT_COMMANDS.iter().map(|&(name, _)| name).collect();
+                    return Err(format!("max_count only applies to {}, not {}", names.join(", "), command));
+                }
+                None => 0,
+            };
+            rules.push(Rule {
+                command: command,
+                rename: config.rename.as_ref().map(|rename| rename.to_ascii_uppercase().into_bytes()),
+                default_ttl: config.default_ttl,
+                max_count: config.max_count,
+                options_start: options_start,
+            });
+        }
+        return Ok(RewriteRules {
+            rules: rules,
+        });
+    }
+
+    // Applies the rules to a request's arguments, in order. Returns whether any of them changed.
+    fn rewrite(&self, args: &mut Vec<Vec<u8>>) -> bool {
+        let mut changed = false;
+        for rule in self.rules.iter() {
+            if !args[0].eq_ignore_ascii_case(rule.command.as_bytes()) {
+                continue;
+            }
+            if rule.default_ttl > 0 {
+                let has_expiry = args.iter().skip(3).any(|arg| SET_EXPIRY_OPTIONS.iter().any(|option| arg.eq_ignore_ascii_case(option.as_bytes())));
+                if !has_expiry {
+                    args.push(b"EX".to_vec());
+                    args.push(rule.default_ttl.to_string().into_bytes());
+                    changed = true;
+                }
+            }
+            if rule.max_count > 0 {
+                changed |= cap_count(args, rule.options_start, rule.max_count);
+            }
+            if let Some(ref rename) = rule.rename {
+                args[0] = rename.clone();
+                changed = true;
+            }
+        }
+        return changed;
+    }
+}
+
+/*
+    Caps the value of the COUNT option, which like the other options of the commands is followed by one value, or adds
+    COUNT if there's none. A COUNT that isn't a number is left for the backend to answer.
+*/
+fn cap_count(args: &mut Vec<Vec<u8>>, options_start: usize, max_count: usize) -> bool {
+    let mut index = options_start;
+    while index + 1 < args.len() {
+        if args[index].eq_ignore_ascii_case(b"COUNT") {
+            let count = String::from_utf8_lossy(&args[index + 1]).parse::<usize>();
+            match count {
+                Ok(count) if count > max_count => {
+                    args[index + 1] = max_count.to_string().into_bytes();
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+        index += 2;
+    }
+    if args.len() < options_start {
+        return false;
+    }
+    args.push(b"COUNT".to_vec());
+    args.push(max_count.to_string().into_bytes());
+    return true;
+}
+
+impl Filter for RewriteRules {
+    fn on_request(&mut self, _context: &mut FilterContext, request: &[u8]) -> FilterAction {
+        let command = match Args::parse(request).ok().and_then(|mut args| args.next()) {
+            Some(Ok(command)) => command,
+            _ => return FilterAction::Continue,
+        };
+        // A rename only applies to the requests of an earlier rule's command, so requests of none are left alone.
+        if !self.rules.iter().any(|rule| command.eq_ignore_ascii_case(rule.command.as_bytes())) {
+            return FilterAction::Continue;
+        }
+        let mut args: Vec<Vec<u8>> = match Args::parse(request) {
+            Ok(args) => match args.map(|arg| arg.map(|arg| arg.to_vec())).collect::<Result<Vec<Vec<u8>>, _>>() {
+                Ok(args) => args,
+                Err(_) => return FilterAction::Continue,
+            },
+            Err(_) => return FilterAction::Continue,
+        };
+        if !self.rewrite(&mut args) {
+            return FilterAction::Continue;
+        }
+        return FilterAction::Rewrite(encode_command(args.iter().map(|arg| &arg[..])));
+    }
+}
+
+#[test]
+fn test_rewrite_rules() {
+    let config = |command: &str, rename: Option<&str>, default_ttl: usize, max_count: usize| RewriteRuleConfig {
+        command: command.to_owned(),
+        rename: rename.map(|rename| rename.to_owned()),
+        default_ttl: default_ttl,
+        max_count: max_count,
+    };
+    let rules = RewriteRules::from_config(&[
+        config("hmset", Some("hset"), 0, 0),
+        config("SET", None, 60, 0),
+        config("SCAN", None, 0, 100),
+        config("XRANGE", None, 0, 10),
+    ]).unwrap();
+    let rewrite = |args: &[&str]| {
+        let mut args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        if !rules.rewrite(&mut args) {
+            return None;
+        }
+        return Some(args.iter().map(|arg| String::from_utf8(arg.clone()).unwrap()).collect::<Vec<String>>().join(" "));
+    };
+    assert_eq!(rewrite(&["HMSET", "h", "f", "v"]), Some("HSET h f v".to_owned()));
+    assert_eq!(rewrite(&["set", "a", "b"]), Some("set a b EX 60".to_owned()));
+    assert_eq!(rewrite(&["SET", "a", "b", "px", "100"]), None);
+    assert_eq!(rewrite(&["SET", "a", "b", "KEEPTTL"]), None);
+    assert_eq!(rewrite(&["SET", "a", "EX"]), Some("SET a EX EX 60".to_owned()));
+    assert_eq!(rewrite(&["SCAN", "0", "MATCH", "count", "COUNT", "1000"]), Some("SCAN 0 MATCH count COUNT 100".to_owned()));
+    assert_eq!(rewrite(&["SCAN", "0", "count", "5"]), None);
+    assert_eq!(rewrite(&["SCAN", "0"]), Some("SCAN 0 COUNT 100".to_owned()));
+    assert_eq!(rewrite(&["XRANGE", "count", "-", "+"]), Some("XRANGE count - + COUNT 10".to_owned()));
+    assert_eq!(rewrite(&["GET", "a"]), None);
+
+    assert!(RewriteRules::from_config(&[config("GET", None, 0, 0)]).is_err());
+    assert!(RewriteRules::from_config(&[config("GET", None, 60, 0)]).is_err());
+    assert!(RewriteRules::from_config(&[config("GET", None, 0, 10)]).is_err());
+    assert!(RewriteRules::from_config(&[config("GET", Some("MY GET"), 0, 0)]).is_err());
+}
+
+#[test]
+fn test_rewrite_filter() {
+    let mut rules = RewriteRules::from_config(&[RewriteRuleConfig {
+        command: "SET".to_owned(),
+        rename: None,
+        default_ttl: 60,
+        max_count: 0,
+    }]).unwrap();
+    let mut context = FilterContext::new("pool1", 1);
+    assert_eq!(
+        rules.on_request(&mut context, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n"),
+        FilterAction::Rewrite(b"*5\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n$2\r\nEX\r\n$2\r\n60\r\n".to_vec())
+    );
+    assert_eq!(rules.on_request(&mut context, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"), FilterAction::Continue);
+}
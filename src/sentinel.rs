@@ -0,0 +1,172 @@
+use config::BackendPoolConfig;
+use log::LogLevel;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use subscription::{Connection, Reply, Subscriptions};
+
+const SWITCH_MASTER_CHANNEL: &[u8] = b"+switch-master";
+
+/*
+    Asks each of the sentinels in turn for the address of the named master, until one answers.
+*/
+pub fn discover_master(master_name: &str, sentinel_hosts: &[SocketAddr]) -> Result<SocketAddr, String> {
+    let mut errors = Vec::new();
+    for &host in sentinel_hosts {
+        match Connection::connect(host).and_then(|mut connection| query_master(&mut connection, master_name)) {
+            Ok(master) => return Ok(master),
+            Err(err) => errors.push(format!("{}: {}", host, err)),
+        }
+    }
+    return Err(errors.join(", "));
+}
+
+fn query_master(connection: &mut Connection, master_name: &str) -> Result<SocketAddr, String> {
+    let reply = try!(connection.command(&[b"SENTINEL", b"get-master-addr-by-name", master_name.as_bytes()]));
+    let mut items = match reply {
+        Reply::Array(Some(items)) => items.into_iter(),
+        Reply::Array(None) => return Err(format!("unknown master {}", master_name)),
+        reply => return Err(format!("invalid reply: {:?}", reply)),
+    };
+    return match (items.next(), items.next()) {
+        (Some(Reply::Bulk(Some(ip))), Some(Reply::Bulk(Some(port)))) => parse_address(&ip, &port),
+        _ => Err("invalid reply".to_owned()),
+    };
+}
+
+fn parse_address(ip: &[u8], port: &[u8]) -> Result<SocketAddr, String> {
+    let ip = String::from_utf8_lossy(ip);
+    let port = String::from_utf8_lossy(port);
+    return match (ip.parse::<IpAddr>(), port.parse::<u16>()) {
+        (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+        _ => Err(format!("invalid address {}:{}", ip, port)),
+    };
+}
+
+/*
+    Follows failovers of the masters that a pool's backends are discovered by, via sentinel_master. Each sentinel host
+    gets a subscription to +switch-master on its own connection, and the master's address is asked for again each
+    time it's (re)established, in case a failover was missed while it was down.
+
+    The new address of each master is handed over to the event loop through a channel, as (master name, address).
+*/
+pub struct SentinelWatcher {
+    receiver: Receiver<(String, SocketAddr)>,
+    // Stops the threads once the watcher is dropped.
+    _subscriptions: Subscriptions,
+}
+
+impl SentinelWatcher {
+    // Returns None when none of the pool's backends use sentinel.
+    pub fn from_config(config: &BackendPoolConfig) -> Option<SentinelWatcher> {
+        let (sender, receiver) = channel();
+        let mut subscriptions = Subscriptions::new();
+        let mut watching = false;
+        for server in config.servers.iter() {
+            let master_name = match server.sentinel_master {
+                Some(ref master_name) => master_name,
+                None => continue,
+            };
+            watching = true;
+            for &host in server.sentinel_hosts.iter() {
+                let sender = sender.clone();
+                let master_name = master_name.clone();
+                subscriptions.spawn(host, move |connection| watch(connection, host, &master_name, &sender));
+            }
+        }
+        if !watching {
+            return None;
+        }
+        return Some(SentinelWatcher {
+            receiver: receiver,
+            _subscriptions: subscriptions,
+        });
+    }
+
+    // Takes the masters that moved since the last call, without waiting.
+    pub fn pending(&self) -> Vec<(String, SocketAddr)> {
+        return self.receiver.try_iter().collect();
+    }
+}
+
+// Subscribes, then passes on the master's new address on each failover until the connection fails. Returns Ok once the receiving end is gone.
+fn watch(connection: &mut Connection, host: SocketAddr, master_name: &str, sender: &Sender<(String, SocketAddr)>) -> Result<(), String> {
+    try!(connection.command(&[b"SUBSCRIBE", SWITCH_MASTER_CHANNEL]));
+    log_event!(LogLevel::Info, "sentinel_subscribed", { sentinel: host }, "Subscribed to failovers of {} from sentinel {}", master_name, host);
+    // Asked over another connection, since a subscribed one only takes (un)subscribe commands.
+    let master = try!(Connection::connect(host).and_then(|mut query| query_master(&mut query, master_name)));
+    if sender.send((master_name.to_owned(), master)).is_err() {
+        return Ok(());
+    }
+    loop {
+        let reply = try!(connection.read_reply());
+        if let Some((name, master)) = parse_switch_master(reply) {
+            if name != master_name {
+                continue;
+            }
+            log_event!(LogLevel::Warn, "sentinel_switch_master", { sentinel: host }, "Sentinel {} reported that {} moved to {}", host, name, master);
+            if sender.send((name, master)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// e.g. ["message", "+switch-master", "mymaster 127.0.0.1 6380 127.0.0.1 6381"], for a master that moved from 6380 to 6381.
+fn parse_switch_master(reply: Reply) -> Option<(String, SocketAddr)> {
+    let mut items = match reply {
+        Reply::Array(Some(items)) => items.into_iter(),
+        _ => return None,
+    };
+    let payload = match (items.next(), items.next(), items.next()) {
+        (Some(Reply::Bulk(Some(ref kind))), Some(Reply::Bulk(Some(ref channel))), Some(Reply::Bulk(Some(payload)))) if kind == b"message" && channel == SWITCH_MASTER_CHANNEL => payload,
+        _ => return None,
+    };
+    let payload = String::from_utf8_lossy(&payload).into_owned();
+    let fields: Vec<&str> = payload.split(' ').collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    return match parse_address(fields[3].as_bytes(), fields[4].as_bytes()) {
+        Ok(master) => Some((fields[0].to_owned(), master)),
+        Err(_) => None,
+    };
+}
+
+#[test]
+fn test_parse_switch_master() {
+    use std::io::Cursor;
+    use subscription::read_reply;
+    let parse = |message: &[u8]| parse_switch_master(read_reply(&mut Cursor::new(message)).unwrap());
+    assert_eq!(
+        parse(b"*3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n$38\r\nmymaster 127.0.0.1 6380 127.0.0.1 6381\r\n"),
+        Some(("mymaster".to_owned(), "127.0.0.1:6381".parse().unwrap()))
+    );
+    assert_eq!(parse(b"*3\r\n$9\r\nsubscribe\r\n$14\r\n+switch-master\r\n:1\r\n"), None);
+    assert_eq!(parse(b"*3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n$8\r\nmymaster\r\n"), None);
+}
+
+#[test]
+fn test_discover_master() {
+    use std::io::{BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap();
+    let sentinel = thread::spawn(move || {
+        for reply in [&b"*2\r\n$9\r\n127.0.0.1\r\n$4\r\n6381\r\n"[..], &b"*-1\r\n"[..]].iter() {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let request = b"*3\r\n$8\r\nSENTINEL\r\n$23\r\nget-master-addr-by-name\r\n$8\r\nmymaster\r\n";
+            let mut received = vec![0; request.len()];
+            reader.read_exact(&mut received).unwrap();
+            assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(request));
+            writer.write_all(reply).unwrap();
+        }
+    });
+    // A sentinel that can't be reached is skipped.
+    let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert_eq!(discover_master("mymaster", &[unreachable, host]), Ok("127.0.0.1:6381".parse().unwrap()));
+    assert!(discover_master("mymaster", &[host]).unwrap_err().contains("unknown master"));
+    sentinel.join().unwrap();
+}
@@ -0,0 +1,124 @@
+use libc;
+use mio::Token;
+use mio::unix::EventedFd;
+use registry::{Interest, Registry};
+use std::mem;
+use std::ptr;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RESTART_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Ends of the pipe written to when shutdown is requested, so that the Poll of every worker wakes up. -1 until created.
+static WAKE_READ_FD: AtomicIsize = AtomicIsize::new(-1);
+static WAKE_WRITE_FD: AtomicIsize = AtomicIsize::new(-1);
+
+/*
+    Handles SIGTERM and SIGINT by requesting a graceful shutdown. A second signal exits immediately, without waiting for
+    in-flight requests. SIGUSR2 requests a hot restart. This should be called after daemonizing, and before the workers
+    are started.
+*/
+pub fn install() -> Result<(), std::io::Error> {
+    try!(create_wake_pipe());
+    for signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGUSR2] {
+        let result = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigaction(*signal, &action, ptr::null_mut())
+        };
+        if result == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    return Ok(());
+}
+
+fn create_wake_pipe() -> Result<(), std::io::Error> {
+    if WAKE_READ_FD.load(Ordering::SeqCst) >= 0 {
+        return Ok(());
+    }
+    let mut fds: [libc::c_int; 2] = [-1, -1];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    for fd in &fds {
+        let result = unsafe {
+            libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK) == -1 || libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1
+        };
+        if result {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    WAKE_READ_FD.store(fds[0] as isize, Ordering::SeqCst);
+    WAKE_WRITE_FD.store(fds[1] as isize, Ordering::SeqCst);
+    return Ok(());
+}
+
+// Only async-signal-safe calls are allowed here.
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if signal == libc::SIGUSR2 {
+        RESTART_REQUESTED.store(true, Ordering::SeqCst);
+        wake();
+        return;
+    }
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+    wake();
+}
+
+// Requests a graceful shutdown of every worker, e.g. for the SHUTDOWN admin command.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+    wake();
+}
+
+pub fn shutdown_requested() -> bool {
+    return SHUTDOWN_REQUESTED.load(Ordering::SeqCst);
+}
+
+// Returns whether a hot restart was requested since the last call. Only worker 0 handles restarts.
+pub fn take_restart_request() -> bool {
+    return RESTART_REQUESTED.swap(false, Ordering::SeqCst);
+}
+
+fn wake() {
+    let fd = WAKE_WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte: u8 = 1;
+        // If the pipe is full, the workers have yet to wake up from an earlier write.
+        unsafe { libc::write(fd as libc::c_int, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+/*
+    Registers the wake up pipe to a worker's Poll, so that it returns once shutdown is requested. The pipe is never
+    read, since the worker shuts down once woken.
+*/
+pub fn register(registry: &Registry, token: Token) -> Result<(), std::io::Error> {
+    let fd = WAKE_READ_FD.load(Ordering::SeqCst);
+    if fd < 0 {
+        return Ok(());
+    }
+    return registry.register_static(&EventedFd(&(fd as RawFd)), token, Interest::Readable);
+}
+
+#[test]
+fn test_request_shutdown_wakes_poll() {
+    use mio::Events;
+    use std::time::Duration;
+
+    create_wake_pipe().unwrap();
+    let registry = Registry::new().unwrap();
+    register(&registry, Token(3)).unwrap();
+    let mut events = Events::with_capacity(16);
+    registry.poll(&mut events, Some(Duration::from_millis(0))).unwrap();
+    assert!(events.is_empty());
+
+    request_shutdown();
+    assert!(shutdown_requested());
+    registry.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(events.iter().next().map(|event| event.token()), Some(Token(3)));
+}
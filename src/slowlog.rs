@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+use hashbrown::HashMap;
+use humantime;
+use config::BackendPoolConfig;
+use redflareproxy::{ClientToken, ClientTokenValue};
+use stats::micros_since;
+
+// Shown in place of arguments that carry a password.
+const REDACTED: &str = "(redacted)";
+
+/*
+    A request that took longer than the slow log threshold, or that timed out.
+*/
+#[derive(Clone)]
+pub struct SlowRequest {
+    // Wall clock time the request was received.
+    pub start_time: SystemTime,
+    pub duration_us: u64,
+    pub backend: SocketAddr,
+    pub command: &'static str,
+    // Truncated copy of the request, with passwords masked. Only captured if slowlog_payload_bytes is set.
+    pub payload: Option<String>,
+    // Error returned to the client instead of a backend response.
+    pub error: Option<&'static str>,
+}
+
+impl SlowRequest {
+    /*
+        Formats the request on a single line. e.g.:
+        pool1 127.0.0.1:6380: time=2018-06-01T12:00:00Z duration_us=15230 command=GET payload="GET user:1"
+    */
+    pub fn format(&self, pool: &str) -> String {
+        let mut output = format!(
+            "{} {}: time={} duration_us={} command={}",
+            pool,
+            self.backend,
+            humantime::format_rfc3339_seconds(self.start_time),
+            self.duration_us,
+            self.command
+        );
+        if let Some(ref payload) = self.payload {
+            output.push_str(&format!(" payload={:?}", payload));
+        }
+        if let Some(error) = self.error {
+            output.push_str(&format!(" error={:?}", error));
+        }
+        return output;
+    }
+}
+
+/*
+    Keeps the most recent slow requests of a backend. Each backend keeps its own copy.
+    When payloads are captured, a snippet of every pending request is kept until its response arrives, since it isn't
+    known to be slow until then.
+*/
+#[derive(Clone)]
+pub struct SlowLog {
+    // In microseconds. 0 disables the slow log.
+    threshold: u64,
+    max_len: usize,
+    payload_bytes: usize,
+    // Payload snippets of pending requests, keyed by client and request id.
+    pending: HashMap<(ClientTokenValue, Instant, usize), String>,
+    pub entries: VecDeque<SlowRequest>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: u64, max_len: usize, payload_bytes: usize) -> SlowLog {
+        SlowLog {
+            threshold: threshold,
+            max_len: max_len,
+            payload_bytes: payload_bytes,
+            pending: HashMap::new(),
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn from_config(config: &BackendPoolConfig) -> SlowLog {
+        SlowLog::new(config.slowlog_threshold as u64, config.slowlog_max_len, config.slowlog_payload_bytes)
+    }
+
+    // Called when a request is written to the backend.
+    pub fn start(&mut self, client_token: ClientToken, request_id: (Instant, usize), message: &[u8]) {
+        if self.threshold == 0 || self.max_len == 0 || self.payload_bytes == 0 {
+            return;
+        }
+        self.pending.insert((client_token.0, request_id.0, request_id.1), sanitize_command(message, self.payload_bytes));
+    }
+
+    /*
+        Called when a request completes, whether with a response or an error. It is kept if it took longer than the
+        threshold.
+    */
+    pub fn finish(
+        &mut self,
+        client_token: ClientToken,
+        request_id: (Instant, usize),
+        start: Instant,
+        backend: SocketAddr,
+        command: &'static str,
+        error: Option<&'static str>,
+    ) {
+        let payload = self.pending.remove(&(client_token.0, request_id.0, request_id.1));
+        let duration_us = micros_since(start);
+        if self.threshold == 0 || self.max_len == 0 || duration_us < self.threshold {
+            return;
+        }
+        if self.entries.len() >= self.max_len {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(SlowRequest {
+            start_time: SystemTime::now() - Duration::from_micros(duration_us),
+            duration_us: duration_us,
+            backend: backend,
+            command: command,
+            payload: payload,
+            error: error,
+        });
+    }
+
+    // Called when a request is dropped without being timed, e.g. when the backend goes down.
+    pub fn discard(&mut self, client_token: ClientToken, request_id: (Instant, usize)) {
+        self.pending.remove(&(client_token.0, request_id.0, request_id.1));
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/*
+    Renders a redis request as its space separated arguments, truncated to max_bytes. Bytes that aren't printable are
+    escaped, and password arguments (e.g. of AUTH) are replaced with "(redacted)".
+*/
+pub fn sanitize_command(message: &[u8], max_bytes: usize) -> String {
+    let args = split_args(message);
+    let mut output = String::new();
+    // Renders until past max_bytes, so that it's known whether anything was cut off.
+    for (index, arg) in args.iter().enumerate() {
+        if output.len() > max_bytes {
+            break;
+        }
+        if index > 0 {
+            output.push(' ');
+        }
+        if is_password(&args, index) {
+            output.push_str(REDACTED);
+            continue;
+        }
+        for &byte in arg.iter() {
+            if output.len() > max_bytes {
+                break;
+            }
+            match byte {
+                b'"' | b'\\' => {
+                    output.push('\\');
+                    output.push(byte as char);
+                }
+                0x20..=0x7e => output.push(byte as char),
+                _ => output.push_str(&format!("\\x{:02x}", byte)),
+            }
+        }
+    }
+    if output.len() > max_bytes {
+        // Everything rendered is ASCII, so this is always a char boundary.
+        output.truncate(max_bytes);
+        output.push_str("...");
+    }
+    return output;
+}
+
+// Splits a multibulk request into its arguments. Anything else is treated as an inline command.
+pub fn split_args(message: &[u8]) -> Vec<&[u8]> {
+    let mut args = Vec::new();
+    if message.first() != Some(&b'*') {
+        let line = message.split(|&b| b == b'\r' || b == b'\n').next().unwrap_or(&[]);
+        return line.split(|&b| b == b' ').filter(|arg| !arg.is_empty()).collect();
+    }
+    let mut index = match find_crlf(message, 0) {
+        Some(end) => end + 2,
+        None => return args,
+    };
+    while message.get(index) == Some(&b'$') {
+        let end = match find_crlf(message, index) {
+            Some(end) => end,
+            None => break,
+        };
+        let len = match std::str::from_utf8(&message[index + 1..end]).ok().and_then(|len| len.parse::<usize>().ok()) {
+            Some(len) => len,
+            None => break,
+        };
+        let start = end + 2;
+        // Keep a partial argument if the request is cut short.
+        let arg_end = std::cmp::min(start + len, message.len());
+        if start > arg_end {
+            break;
+        }
+        args.push(&message[start..arg_end]);
+        index = arg_end + 2;
+    }
+    return args;
+}
+
+fn find_crlf(message: &[u8], from: usize) -> Option<usize> {
+    if from >= message.len() {
+        return None;
+    }
+    return message[from..].windows(2).position(|window| window == b"\r\n").map(|position| from + position);
+}
+
+// Whether the argument at index is a password, for the commands that take one.
+fn is_password(args: &[&[u8]], index: usize) -> bool {
+    let upper = |i: usize| args[i].to_ascii_uppercase();
+    if index == 0 {
+        return false;
+    }
+    match &upper(0)[..] {
+        b"AUTH" => true,
+        // CONFIG SET requirepass <password> / masterauth <password>
+        b"CONFIG" => index == 3 && (upper(2) == b"REQUIREPASS" || upper(2) == b"MASTERAUTH"),
+        // HELLO <version> AUTH <username> <password>, MIGRATE ... AUTH <password> / AUTH2 <username> <password>
+        b"HELLO" | b"MIGRATE" => {
+            let previous = upper(index - 1);
+            let before_previous = if index >= 2 { upper(index - 2) } else { Vec::new() };
+            previous == b"AUTH" || previous == b"AUTH2" || before_previous == b"AUTH2"
+                || (&upper(0)[..] == b"HELLO" && before_previous == b"AUTH")
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_sanitize_command() {
+    assert_eq!(sanitize_command(b"*2\r\n$3\r\nGET\r\n$6\r\nuser:1\r\n", 64), "GET user:1");
+    assert_eq!(sanitize_command(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$10\r\n0123456789\r\n", 10), "SET a 0123...");
+    assert_eq!(sanitize_command(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\n\"\x00\r\n\r\n", 64), "SET a \\\"\\x00\\x0d\\x0a");
+    assert_eq!(sanitize_command(b"PING\r\n", 64), "PING");
+    // A request cut short keeps what was received.
+    assert_eq!(sanitize_command(b"*2\r\n$3\r\nGET\r\n$6\r\nus", 64), "GET us");
+    assert_eq!(sanitize_command(b"*2\r\n$3\r\nGET\r\n$6\r\nuser:1\r\n", 10), "GET user:1");
+
+    assert_eq!(sanitize_command(b"*2\r\n$4\r\nauth\r\n$6\r\nsecret\r\n", 64), "auth (redacted)");
+    assert_eq!(sanitize_command(b"*3\r\n$4\r\nAUTH\r\n$4\r\nuser\r\n$6\r\nsecret\r\n", 64), "AUTH (redacted) (redacted)");
+    assert_eq!(
+        sanitize_command(b"*4\r\n$6\r\nCONFIG\r\n$3\r\nSET\r\n$11\r\nrequirepass\r\n$6\r\nsecret\r\n", 64),
+        "CONFIG SET requirepass (redacted)"
+    );
+    assert_eq!(
+        sanitize_command(b"*5\r\n$5\r\nHELLO\r\n$1\r\n3\r\n$4\r\nAUTH\r\n$4\r\nuser\r\n$6\r\nsecret\r\n", 64),
+        "HELLO 3 AUTH (redacted) (redacted)"
+    );
+}
+
+#[test]
+fn test_slow_log() {
+    use mio::Token;
+    let backend: SocketAddr = "127.0.0.1:6380".parse().unwrap();
+    let mut slowlog = SlowLog::new(10000, 2, 16);
+    let now = Instant::now();
+    let message = b"*2\r\n$3\r\nGET\r\n$6\r\nuser:1\r\n";
+
+    // Fast requests aren't kept.
+    slowlog.start(Token(20), (now, 0), message);
+    slowlog.finish(Token(20), (now, 0), Instant::now(), backend, "GET", None);
+    assert_eq!(slowlog.entries.len(), 0);
+    assert_eq!(slowlog.pending.len(), 0);
+
+    let start = Instant::now() - Duration::from_millis(20);
+    slowlog.start(Token(20), (now, 1), message);
+    slowlog.finish(Token(20), (now, 1), start, backend, "GET", None);
+    slowlog.finish(Token(21), (now, 0), start, backend, "SET", Some("Proxy timed out"));
+    slowlog.finish(Token(22), (now, 0), start, backend, "DEL", None);
+    // Only the most recent max_len requests are kept.
+    assert_eq!(slowlog.entries.len(), 2);
+    assert_eq!(slowlog.entries[0].command, "SET");
+    assert_eq!(slowlog.entries[0].payload, None);
+    assert_eq!(slowlog.entries[1].command, "DEL");
+
+    slowlog.reset();
+    slowlog.start(Token(20), (now, 2), message);
+    slowlog.finish(Token(20), (now, 2), start, backend, "GET", None);
+    let entry = &slowlog.entries[0];
+    assert!(entry.duration_us >= 20000);
+    assert_eq!(entry.payload, Some("GET user:1".to_owned()));
+    let line = entry.format("pool1");
+    assert!(line.starts_with("pool1 127.0.0.1:6380: time="), "{}", line);
+    assert!(line.ends_with(" command=GET payload=\"GET user:1\""), "{}", line);
+
+    slowlog.start(Token(20), (now, 3), message);
+    slowlog.discard(Token(20), (now, 3));
+    assert_eq!(slowlog.pending.len(), 0);
+
+    // Payloads aren't captured unless slowlog_payload_bytes is set.
+    let mut slowlog = SlowLog::new(10000, 2, 0);
+    slowlog.start(Token(20), (now, 0), message);
+    assert_eq!(slowlog.pending.len(), 0);
+}
@@ -0,0 +1,85 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use config::RedFlareProxyConfig;
+
+/*
+    Appends a JSON stats snapshot to a file at a fixed interval, one snapshot per line, so that operators without a
+    metrics stack still have a history of the proxy's stats.
+*/
+pub struct StatsSnapshotter {
+    path: String,
+    interval: Duration,
+    next_snapshot: Instant,
+}
+
+impl StatsSnapshotter {
+    // Returns None when no stats_snapshot_file is configured.
+    pub fn from_config(config: &RedFlareProxyConfig, now: Instant) -> Option<StatsSnapshotter> {
+        match config.stats_snapshot_file {
+            Some(ref path) => {
+                let interval = Duration::from_secs(std::cmp::max(config.stats_snapshot_interval, 1) as u64);
+                Some(StatsSnapshotter {
+                    path: path.clone(),
+                    interval: interval,
+                    next_snapshot: now + interval,
+                })
+            }
+            None => None,
+        }
+    }
+
+    // Time left until the next snapshot is due. Used as the poll timeout, so that an idle proxy still writes snapshots.
+    pub fn time_until_due(&self, now: Instant) -> Duration {
+        if now >= self.next_snapshot {
+            return Duration::from_secs(0);
+        }
+        return self.next_snapshot - now;
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        return now >= self.next_snapshot;
+    }
+
+    /*
+        Appends the snapshot as a single line, and schedules the next one. The next snapshot is scheduled even if the
+        write fails, so that a full disk doesn't turn into a busy loop.
+    */
+    pub fn write_snapshot(&mut self, snapshot: &str, now: Instant) -> Result<(), std::io::Error> {
+        self.next_snapshot = now + self.interval;
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        try!(file.write_all(snapshot.as_bytes()));
+        try!(file.write_all(b"\n"));
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_stats_snapshotter() {
+    use config::load_config;
+    use std::io::Read;
+
+    let path = std::env::temp_dir().join(format!("redflare-snapshot-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = load_config("tests/conf/testconfig1.toml".to_owned(), None).unwrap();
+    assert!(StatsSnapshotter::from_config(&config, Instant::now()).is_none());
+
+    config.stats_snapshot_file = Some(path.to_str().unwrap().to_owned());
+    config.stats_snapshot_interval = 10;
+    let start = Instant::now();
+    let mut snapshotter = StatsSnapshotter::from_config(&config, start).unwrap();
+    assert!(!snapshotter.is_due(start));
+    assert_eq!(snapshotter.time_until_due(start), Duration::from_secs(10));
+
+    let due = start + Duration::from_secs(10);
+    assert!(snapshotter.is_due(due));
+    assert_eq!(snapshotter.time_until_due(due), Duration::from_secs(0));
+    snapshotter.write_snapshot("{\"requests\":1}", due).unwrap();
+    snapshotter.write_snapshot("{\"requests\":2}", due).unwrap();
+    assert!(!snapshotter.is_due(due));
+
+    let mut contents = String::new();
+    std::fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "{\"requests\":1}\n{\"requests\":2}\n");
+    let _ = std::fs::remove_file(&path);
+}
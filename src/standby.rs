@@ -0,0 +1,178 @@
+use config::{BackendPoolConfig, Failback};
+use stats::metric_labels;
+use std::time::{Duration, Instant};
+
+// Set with FAILOVER <pool> <mode>. Auto follows the availability of the backends, the others pin the pool's clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailoverMode {
+    Auto,
+    Primary,
+    Standby,
+}
+
+impl FailoverMode {
+    pub fn parse(mode: &str) -> Option<FailoverMode> {
+        match mode {
+            "AUTO" => Some(FailoverMode::Auto),
+            "PRIMARY" => Some(FailoverMode::Primary),
+            "STANDBY" => Some(FailoverMode::Standby),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Which pool serves the clients of a pool with a standby. They fail over to the standby once none of the pool's own
+    backends are available, as long as one of the standby's is, so that a proxy that is still connecting to both
+    doesn't fail over. With Auto failback, they return once all of the pool's backends have been available for
+    failback_delay, so that a flapping backend doesn't bounce them back and forth.
+
+    Only the pool that clients connected to changes. The standby keeps serving its own clients too.
+*/
+pub struct Failover {
+    // Position of the standby among the pools, resolved from its name whenever the pools are created.
+    pub standby_index: Option<usize>,
+    failback: Failback,
+    failback_delay: Duration,
+    mode: FailoverMode,
+    on_standby: bool,
+    // When all of the pool's backends became available again, while its clients are on the standby.
+    recovered_since: Option<Instant>,
+    failovers: usize,
+    failbacks: usize,
+}
+
+impl Failover {
+    pub fn from_config(config: &BackendPoolConfig) -> Option<Failover> {
+        if config.standby.is_none() {
+            return None;
+        }
+        return Some(Failover::new(config.failback, Duration::from_millis(config.failback_delay as u64)));
+    }
+
+    fn new(failback: Failback, failback_delay: Duration) -> Failover {
+        Failover {
+            standby_index: None,
+            failback: failback,
+            failback_delay: failback_delay,
+            mode: FailoverMode::Auto,
+            on_standby: false,
+            recovered_since: None,
+            failovers: 0,
+            failbacks: 0,
+        }
+    }
+
+    /*
+        Decides where the pool's clients are sent, from how many of the pool's backends are available, out of all of
+        them, and how many of the standby's are. Returns whether they're sent to the standby.
+    */
+    pub fn update(&mut self, available: usize, num_backends: usize, standby_available: usize, now: Instant) -> bool {
+        match self.mode {
+            FailoverMode::Primary => self.switch(false),
+            FailoverMode::Standby => self.switch(true),
+            FailoverMode::Auto if !self.on_standby => {
+                if available == 0 && standby_available > 0 {
+                    self.switch(true);
+                }
+            }
+            FailoverMode::Auto => {
+                if self.failback == Failback::Manual {
+                    return true;
+                }
+                if available < num_backends {
+                    self.recovered_since = None;
+                    return true;
+                }
+                let recovered_since = *self.recovered_since.get_or_insert(now);
+                if now.duration_since(recovered_since) >= self.failback_delay {
+                    self.switch(false);
+                }
+            }
+        }
+        return self.on_standby;
+    }
+
+    fn switch(&mut self, on_standby: bool) {
+        if self.on_standby == on_standby {
+            return;
+        }
+        if on_standby {
+            self.failovers += 1;
+        } else {
+            self.failbacks += 1;
+        }
+        self.on_standby = on_standby;
+        self.recovered_since = None;
+    }
+
+    // Takes effect on the next request of each client. AUTO keeps the clients where they are until the backends change.
+    pub fn set_mode(&mut self, mode: FailoverMode) {
+        self.mode = mode;
+    }
+
+    pub fn on_standby(&self) -> bool {
+        return self.on_standby;
+    }
+
+    pub fn mode(&self) -> FailoverMode {
+        return self.mode;
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.failovers = 0;
+        self.failbacks = 0;
+    }
+
+    pub fn format_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("standby_active", self.on_standby as usize),
+            ("failovers", self.failovers),
+            ("failbacks", self.failbacks),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        return output;
+    }
+}
+
+#[test]
+fn test_failover() {
+    let mut failover = Failover::new(Failback::Auto, Duration::from_millis(100));
+    let now = Instant::now();
+
+    // Nothing to fail over to while the standby is down too, e.g. while connecting.
+    assert!(!failover.update(0, 2, 0, now));
+    assert!(failover.update(0, 2, 1, now));
+
+    // Fails back once all of the backends have stayed up for failback_delay.
+    assert!(failover.update(1, 2, 1, now));
+    assert!(failover.update(2, 2, 1, now));
+    assert!(failover.update(1, 2, 1, now + Duration::from_millis(50)));
+    assert!(failover.update(2, 2, 1, now + Duration::from_millis(100)));
+    assert!(!failover.update(2, 2, 1, now + Duration::from_millis(200)));
+    assert_eq!((failover.failovers, failover.failbacks), (1, 1));
+
+    // Overrides win over the backends.
+    failover.set_mode(FailoverMode::Standby);
+    assert!(failover.update(2, 2, 1, now));
+    failover.set_mode(FailoverMode::Primary);
+    assert!(!failover.update(0, 2, 1, now));
+    failover.set_mode(FailoverMode::Auto);
+    assert!(failover.update(0, 2, 1, now));
+}
+
+#[test]
+fn test_manual_failback() {
+    let mut failover = Failover::new(Failback::Manual, Duration::from_millis(100));
+    let now = Instant::now();
+    assert!(failover.update(0, 1, 1, now));
+    assert!(failover.update(1, 1, 1, now + Duration::from_secs(60)));
+    failover.set_mode(FailoverMode::Primary);
+    assert!(!failover.update(1, 1, 1, now));
+    failover.set_mode(FailoverMode::Auto);
+    assert!(!failover.update(1, 1, 1, now));
+}
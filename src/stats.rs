@@ -1,6 +1,19 @@
-pub struct Stats {
+use clock;
+use config::BackendPoolConfig;
+use std::time::Instant;
+use std::time::Duration;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use hashbrown::HashMap;
+
+/*
+    Counters incremented on the hot path, once per request or read/write. Each worker of the event loop increments its
+    own shard without any synchronization, and the shards are only summed when stats are read.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsShard {
     pub accepted_clients: usize,
-    pub client_connections: usize,
     pub requests: usize,
     pub responses: usize,
     pub send_client_bytes: usize,
@@ -9,41 +22,1317 @@ pub struct Stats {
     pub recv_backend_bytes: usize,
 }
 
+impl StatsShard {
+    pub fn new() -> StatsShard {
+        StatsShard::default()
+    }
+
+    pub fn merge(&mut self, other: &StatsShard) {
+        self.accepted_clients += other.accepted_clients;
+        self.requests += other.requests;
+        self.responses += other.responses;
+        self.send_client_bytes += other.send_client_bytes;
+        self.recv_client_bytes += other.recv_client_bytes;
+        self.send_backend_bytes += other.send_backend_bytes;
+        self.recv_backend_bytes += other.recv_backend_bytes;
+    }
+}
+
+/*
+    The published shards of every worker, so that any worker can report the totals. Each worker copies its own shard
+    into its slot once per event loop iteration, so totals may lag behind the other workers by one iteration.
+*/
+pub struct WorkerShards {
+    shards: Vec<Mutex<StatsShard>>,
+    // Bumped by reset(), so that every worker also clears its own shard.
+    reset_epoch: AtomicUsize,
+}
+
+pub type SharedShards = Arc<WorkerShards>;
+
+impl WorkerShards {
+    pub fn new(workers: usize) -> SharedShards {
+        Arc::new(WorkerShards {
+            shards: (0..workers).map(|_| Mutex::new(StatsShard::new())).collect(),
+            reset_epoch: AtomicUsize::new(0),
+        })
+    }
+}
+
+pub struct Stats {
+    // The current worker's shard. Hot paths increment it through shard().
+    shard: StatsShard,
+    worker: usize,
+    // None when the event loop runs on a single thread.
+    shared: Option<SharedShards>,
+    reset_epoch: usize,
+    pub client_connections: usize,
+    pub event_loop: EventLoopStats,
+
+    rates: RateWindows,
+}
+
 impl Stats {
     pub fn new() -> Stats {
         Stats {
-            accepted_clients: 0,
+            shard: StatsShard::new(),
+            worker: 0,
+            shared: None,
+            reset_epoch: 0,
             client_connections: 0,
+            event_loop: EventLoopStats::new(),
+            rates: RateWindows::new(),
+        }
+    }
+
+    pub fn for_worker(worker: usize, shared: SharedShards) -> Stats {
+        let mut stats = Stats::new();
+        stats.worker = worker;
+        stats.shared = Some(shared);
+        return stats;
+    }
+
+    // The shard of the current worker.
+    #[inline]
+    pub fn shard(&mut self) -> &mut StatsShard {
+        return &mut self.shard;
+    }
+
+    // Copies the current worker's shard to the other workers. Called once per event loop iteration.
+    pub fn publish(&mut self) {
+        let shared = match self.shared {
+            Some(ref shared) => shared,
+            None => return,
+        };
+        let reset_epoch = shared.reset_epoch.load(Ordering::Acquire);
+        if reset_epoch != self.reset_epoch {
+            self.reset_epoch = reset_epoch;
+            self.shard = StatsShard::new();
+        }
+        *shared.shards[self.worker].lock().unwrap() = self.shard.clone();
+    }
+
+    // Sums the counters of every shard.
+    pub fn totals(&self) -> StatsShard {
+        let mut totals = self.shard.clone();
+        if let Some(ref shared) = self.shared {
+            for (worker, shard) in shared.shards.iter().enumerate() {
+                if worker != self.worker {
+                    totals.merge(&shard.lock().unwrap());
+                }
+            }
+        }
+        return totals;
+    }
+
+    fn rate_counters(&self) -> [usize; 4] {
+        let totals = self.totals();
+        [totals.requests, totals.recv_client_bytes, totals.send_client_bytes, totals.accepted_clients]
+    }
+
+    pub fn sample_rates(&mut self, now: Instant) {
+        let counters = self.rate_counters();
+        self.rates.sample(now, &counters);
+    }
+
+    /*
+        Formats the current rates per second, averaged over each window. e.g.:
+        Throughput:
+        requests_per_sec: 1s=10.00 10s=8.50 60s=2.10
+    */
+    pub fn format_rates(&self, now: Instant) -> String {
+        let counters = self.rate_counters();
+        let names = ["requests_per_sec", "recv_client_bytes_per_sec", "send_client_bytes_per_sec", "connections_per_sec"];
+        let mut output = "Throughput:".to_owned();
+        for (index, name) in names.iter().enumerate() {
+            output.push_str(&format!("\n{}:", name));
+            for window in RATE_WINDOWS.iter() {
+                output.push_str(&format!(" {}s={:.2}", window, self.rates.rate(now, index, counters[index], *window)));
+            }
+        }
+        return output;
+    }
+
+    pub fn reset(&mut self) {
+        self.shard = StatsShard::new();
+        if let Some(ref shared) = self.shared {
+            for shard in shared.shards.iter() {
+                *shard.lock().unwrap() = StatsShard::new();
+            }
+            self.reset_epoch = shared.reset_epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        }
+        self.client_connections = 0;
+        self.event_loop.reset();
+        self.rates.clear();
+    }
+
+    pub fn to_json(&self) -> String {
+        let totals = self.totals();
+        return json_counters(&[
+            ("accepted_clients", totals.accepted_clients),
+            ("client_connections", self.client_connections),
+            ("requests", totals.requests),
+            ("responses", totals.responses),
+            ("send_client_bytes", totals.send_client_bytes),
+            ("recv_client_bytes", totals.recv_client_bytes),
+            ("send_backend_bytes", totals.send_backend_bytes),
+            ("recv_backend_bytes", totals.recv_backend_bytes),
+        ]);
+    }
+}
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let totals = self.totals();
+        try!(write!(f, "Stats:\n"));
+        try!(write!(f, "accepted_clients: {}\n", totals.accepted_clients));
+        try!(write!(f, "client_connections: {}\n", self.client_connections));
+        try!(write!(f, "requests: {}\n", totals.requests));
+        try!(write!(f, "responses: {}\n", totals.responses));
+        try!(write!(f, "send_client_bytes: {}\n", totals.send_client_bytes));
+        try!(write!(f, "recv_client_bytes: {}\n", totals.recv_client_bytes));
+        try!(write!(f, "send_backend_bytes: {}\n", totals.send_backend_bytes));
+        write!(f, "recv_backend_bytes: {}", totals.recv_backend_bytes)
+    }
+}
+
+#[test]
+fn test_stats_shards() {
+    let mut stats = Stats::new();
+    stats.shard().requests += 2;
+    stats.publish();
+    assert_eq!(stats.totals().requests, 2);
+
+    let shared = WorkerShards::new(2);
+    let mut stats = Stats::for_worker(0, shared.clone());
+    let mut other = Stats::for_worker(1, shared.clone());
+    stats.shard().requests += 2;
+    stats.shard().recv_client_bytes += 100;
+    other.shard().requests += 3;
+    other.shard().responses += 1;
+    // The other worker's counters are only seen once it publishes them.
+    assert_eq!(stats.totals().requests, 2);
+    other.publish();
+    let totals = stats.totals();
+    assert_eq!(totals.requests, 5);
+    assert_eq!(totals.responses, 1);
+    assert_eq!(totals.recv_client_bytes, 100);
+    assert!(stats.to_string().contains("\nrequests: 5\nresponses: 1\n"));
+
+    // A reset clears every worker's shard, including ones that haven't been published yet.
+    other.shard().requests += 1;
+    stats.reset();
+    assert_eq!(stats.totals(), StatsShard::new());
+    other.publish();
+    assert_eq!(other.totals(), StatsShard::new());
+    assert_eq!(stats.totals(), StatsShard::new());
+}
+/*
+    Counters tracked for a single pool. These are reported with the pool's metric_prefix and metric_labels, so that
+    dashboards can be filtered per pool owner.
+*/
+pub struct PoolStats {
+    pub accepted_clients: usize,
+    pub requests: usize,
+    pub recv_client_bytes: usize,
+    // Reads also sent to a replica, because their backend didn't answer within hedge_delay.
+    pub hedged_requests: usize,
+    // GETs answered with the response to another client's GET of the same key, with collapse_gets.
+    pub collapsed_requests: usize,
+    // Requests, and errors returned by the proxy itself, per command name. Backend errors and latency are tracked by
+    // each backend.
+    pub commands: HashMap<&'static str, CommandStats>,
+    // Clients disconnected while reading their requests. Clients dropped while writing a response are counted by the
+    // backend that was responding.
+    pub connections: ConnectionStats,
+    // Errors returned by the pool itself: unparseable requests, and requests that could not be sent to a backend.
+    pub errors: ErrorStats,
+
+    rates: RateWindows,
+}
+
+impl PoolStats {
+    pub fn new() -> PoolStats {
+        PoolStats {
+            accepted_clients: 0,
             requests: 0,
-            responses: 0,
-            send_client_bytes: 0,
             recv_client_bytes: 0,
-            send_backend_bytes: 0,
-            recv_backend_bytes: 0,
+            hedged_requests: 0,
+            collapsed_requests: 0,
+            commands: HashMap::new(),
+            connections: ConnectionStats::new(),
+            errors: ErrorStats::new(),
+            rates: RateWindows::new(),
         }
     }
 
+    fn rate_counters(&self) -> [usize; 3] {
+        [self.requests, self.recv_client_bytes, self.accepted_clients]
+    }
+
+    pub fn sample_rates(&mut self, now: Instant) {
+        let counters = self.rate_counters();
+        self.rates.sample(now, &counters);
+    }
+
     pub fn reset(&mut self) {
         self.accepted_clients = 0;
-        self.client_connections = 0;
         self.requests = 0;
-        self.responses = 0;
-        self.send_client_bytes = 0;
         self.recv_client_bytes = 0;
-        self.send_backend_bytes = 0;
-        self.recv_backend_bytes = 0;
+        self.hedged_requests = 0;
+        self.collapsed_requests = 0;
+        self.commands.clear();
+        self.connections = ConnectionStats::new();
+        self.errors = ErrorStats::new();
+        self.rates.clear();
+    }
+
+    /*
+        Formats the counters and the pool's latency as one metric per line, e.g.:
+        cache_requests{pool="pool1",team="search"} 10
+        cache_latency_us{pool="pool1",team="search",quantile="0.99"} 250
+        cache_command_requests{pool="pool1",team="search",command="GET"} 8
+    */
+    pub fn format_metrics(
+        &self,
+        pool_name: &str,
+        config: &BackendPoolConfig,
+        latency: &LatencyHistogram,
+        commands: &BTreeMap<&'static str, CommandStats>,
+    ) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("accepted_clients", self.accepted_clients),
+            ("requests", self.requests),
+            ("recv_client_bytes", self.recv_client_bytes),
+            ("hedged_requests", self.hedged_requests),
+            ("collapsed_requests", self.collapsed_requests),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        for &(quantile, percent) in QUANTILES.iter() {
+            output.push_str(&format!("{}latency_us{{{},quantile=\"{}\"}} {}\n", config.metric_prefix, labels, quantile, latency.percentile(percent)));
+        }
+        output.push_str(&format!("{}latency_us_count{{{}}} {}\n", config.metric_prefix, labels, latency.count()));
+        let now = clock::now();
+        let counters = self.rate_counters();
+        let rate_names = ["requests_per_sec", "recv_client_bytes_per_sec", "connections_per_sec"];
+        for (index, name) in rate_names.iter().enumerate() {
+            for window in RATE_WINDOWS.iter() {
+                let rate = self.rates.rate(now, index, counters[index], *window);
+                output.push_str(&format!("{}{}{{{},window=\"{}s\"}} {:.2}\n", config.metric_prefix, name, labels, window, rate));
+            }
+        }
+        for (command, command_stats) in commands {
+            let command_labels = format!("{},command=\"{}\"", labels, command);
+            output.push_str(&format!("{}command_requests{{{}}} {}\n", config.metric_prefix, command_labels, command_stats.requests));
+            output.push_str(&format!("{}command_errors{{{}}} {}\n", config.metric_prefix, command_labels, command_stats.errors));
+            output.push_str(&format!("{}command_max_request_bytes{{{}}} {}\n", config.metric_prefix, command_labels, command_stats.max_request_bytes));
+            output.push_str(&format!("{}command_max_response_bytes{{{}}} {}\n", config.metric_prefix, command_labels, command_stats.max_response_bytes));
+            for &(quantile, percent) in QUANTILES.iter() {
+                output.push_str(&format!("{}command_latency_us{{{},quantile=\"{}\"}} {}\n", config.metric_prefix, command_labels, quantile, command_stats.latency.percentile(percent)));
+            }
+        }
+        return output;
     }
 }
-impl std::fmt::Display for Stats {
+
+// Quantiles reported for each latency histogram, as (label, percent).
+const QUANTILES: [(&'static str, f64); 4] = [("0.5", 50.0), ("0.95", 95.0), ("0.99", 99.0), ("0.999", 99.9)];
+
+// Labels attached to every metric of a pool: the pool name, followed by the pool's configured metric_labels.
+pub fn metric_labels(pool_name: &str, config: &BackendPoolConfig) -> String {
+    let mut labels = format!("pool=\"{}\"", escape_label_value(pool_name));
+    for (name, value) in &config.metric_labels {
+        labels.push_str(&format!(",{}=\"{}\"", name, escape_label_value(value)));
+    }
+    return labels;
+}
+
+// Labels that the proxy sets itself, on some metrics of a pool, so a pool's metric_labels can't use them.
+const RESERVED_LABELS: [&str; 7] = ["backend", "command", "pool", "quantile", "type", "user", "window"];
+
+// Checks the name of one of a pool's metric_labels. Prometheus label names match [a-zA-Z_][a-zA-Z0-9_]*.
+pub fn validate_label_name(name: &str) -> Result<(), String> {
+    let valid = name.chars().enumerate().all(|(index, c)| c == '_' || c.is_ascii_alphabetic() || (index > 0 && c.is_ascii_digit()));
+    if name.is_empty() || !valid {
+        return Err(format!("Invalid metric label name: '{}'. Label names must match [a-zA-Z_][a-zA-Z0-9_]*", name));
+    }
+    if name.starts_with("__") || RESERVED_LABELS.contains(&name) {
+        return Err(format!("Reserved metric label name: '{}'", name));
+    }
+    return Ok(());
+}
+
+#[test]
+fn test_validate_label_name() {
+    assert!(validate_label_name("team").is_ok());
+    assert!(validate_label_name("_service_2").is_ok());
+    assert!(validate_label_name("").is_err());
+    assert!(validate_label_name("2team").is_err());
+    assert!(validate_label_name("team-name").is_err());
+    assert!(validate_label_name("pool").is_err());
+    assert!(validate_label_name("backend").is_err());
+    assert!(validate_label_name("__name__").is_err());
+}
+
+pub fn escape_label_value(value: &str) -> String {
+    return value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n");
+}
+
+// Quotes and escapes a string for a JSON document.
+pub fn json_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    return output;
+}
+
+// Formats the fields as a JSON object. The values must already be formatted as JSON.
+pub fn json_object(fields: &[(&str, String)]) -> String {
+    let mut output = "{".to_owned();
+    for (index, &(name, ref value)) in fields.iter().enumerate() {
+        if index > 0 {
+            output.push(',');
+        }
+        output.push_str(&json_string(name));
+        output.push(':');
+        output.push_str(value);
+    }
+    output.push('}');
+    return output;
+}
+
+// Formats the values as a JSON array. The values must already be formatted as JSON.
+pub fn json_array(values: &[String]) -> String {
+    return format!("[{}]", values.join(","));
+}
+
+pub fn json_counters(fields: &[(&str, usize)]) -> String {
+    let values: Vec<(&str, String)> = fields.iter().map(|&(name, value)| (name, value.to_string())).collect();
+    return json_object(&values);
+}
+
+#[test]
+fn test_json_object() {
+    assert_eq!(json_counters(&[]), "{}");
+    assert_eq!(json_counters(&[("requests", 10), ("responses", 9)]), "{\"requests\":10,\"responses\":9}");
+    assert_eq!(
+        json_object(&[("pool \"1\"\n", json_counters(&[("requests", 1)]))]),
+        "{\"pool \\\"1\\\"\\n\":{\"requests\":1}}"
+    );
+    assert_eq!(json_string("a\tb\\"), "\"a\\u0009b\\\\\"");
+    assert_eq!(json_array(&[]), "[]");
+    assert_eq!(json_array(&["1".to_owned(), json_counters(&[("requests", 1)])]), "[1,{\"requests\":1}]");
+}
+
+// Windows, in seconds, that rates are averaged over.
+const RATE_WINDOWS: [u64; 3] = [1, 10, 60];
+
+/*
+    Keeps once-per-second snapshots of a set of monotonic counters, so that the rate of each counter can be computed
+    over a recent window rather than the lifetime of the proxy.
+    Snapshots are only taken when sample is called, so while the proxy is idle no snapshots are taken. Since the counters
+    don't change while idle, the rates are still correct, aside from attributing any increments to the latest second.
+*/
+struct RateWindows {
+    samples: VecDeque<(Instant, Vec<usize>)>,
+}
+
+impl RateWindows {
+    fn new() -> RateWindows {
+        RateWindows {
+            samples: VecDeque::with_capacity(RATE_WINDOWS[RATE_WINDOWS.len() - 1] as usize + 2),
+        }
+    }
+
+    fn sample(&mut self, now: Instant, counters: &[usize]) {
+        match self.samples.back() {
+            Some(&(last, _)) if now.duration_since(last).as_secs() < 1 => return,
+            _ => {}
+        }
+        self.samples.push_back((now, counters.to_vec()));
+        // Keep one sample older than the largest window, so that the full window can be measured.
+        let max_window = RATE_WINDOWS[RATE_WINDOWS.len() - 1];
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0).as_secs() >= max_window {
+            self.samples.pop_front();
+        }
+    }
+
+    // Returns the per-second rate of the counter at the given index, averaged over the last window_secs seconds.
+    fn rate(&self, now: Instant, index: usize, current: usize, window_secs: u64) -> f64 {
+        // Use the newest sample that is at least window_secs old, or the oldest sample if none are that old yet.
+        let mut baseline = match self.samples.front() {
+            Some(sample) => sample,
+            None => return 0.0,
+        };
+        for sample in self.samples.iter() {
+            if now.duration_since(sample.0).as_secs() >= window_secs {
+                baseline = sample;
+            } else {
+                break;
+            }
+        }
+        let elapsed = now.duration_since(baseline.0);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        if elapsed_secs <= 0.0 {
+            return 0.0;
+        }
+        return current.saturating_sub(baseline.1[index]) as f64 / elapsed_secs;
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[test]
+fn test_rate_windows() {
+    let start = Instant::now();
+    let mut rates = RateWindows::new();
+    assert_eq!(rates.rate(start, 0, 100, 1), 0.0);
+
+    // 10 requests per second for 2 minutes.
+    for second in 0..121 {
+        rates.sample(start + Duration::from_secs(second), &[second as usize * 10]);
+    }
+    // Sampling again within the same second is ignored.
+    rates.sample(start + Duration::from_millis(120500), &[5000]);
+    let now = start + Duration::from_secs(120);
+    assert_eq!(rates.rate(now, 0, 1200, 1), 10.0);
+    assert_eq!(rates.rate(now, 0, 1200, 10), 10.0);
+    assert_eq!(rates.rate(now, 0, 1200, 60), 10.0);
+    assert!(rates.samples.len() <= 62);
+
+    // A burst in the latest second shows up in the short window first.
+    assert_eq!(rates.rate(now, 0, 1300, 1), 110.0);
+    assert_eq!(rates.rate(now, 0, 1300, 10), 20.0);
+
+    rates.clear();
+    assert_eq!(rates.rate(now, 0, 1300, 1), 0.0);
+}
+
+/*
+    Connection churn counters. Tracked by both pools and backends, and merged per pool when reported.
+*/
+#[derive(Clone)]
+pub struct ConnectionStats {
+    pub client_disconnects: usize,
+    // Connections re-established to a backend after losing the previous one.
+    pub backend_reconnects: usize,
+    // Backend connections that failed during AUTH, SELECT or the initial PING.
+    pub handshake_failures: usize,
+    // Times a client stopped being read from because a backend it sent to was full.
+    pub client_read_pauses: usize,
+    // Client connections refused because the proxy was at its memory_limit.
+    pub shed_connections: usize,
+    // Client connections refused because their address wasn't permitted by allow_clients and deny_clients.
+    pub denied_connections: usize,
+    // Clients closed by the sweeper after client_idle_timeout without traffic.
+    pub idle_closed: usize,
+    // Backend connections that were not established within connect_timeout.
+    pub connect_timeouts: usize,
+    // Requests held while their backend reconnected, with outage_buffer_timeout.
+    pub held_requests: usize,
+    // Held requests that failed, because their backend wasn't ready again within outage_buffer_timeout.
+    pub held_expired: usize,
+    // Clients closed for sending quarantine_threshold invalid requests, whose addresses were quarantined.
+    pub quarantined_clients: usize,
+    // Client connections refused because their address was quarantined.
+    pub quarantine_refused: usize,
+}
+
+impl ConnectionStats {
+    pub fn new() -> ConnectionStats {
+        ConnectionStats {
+            client_disconnects: 0,
+            backend_reconnects: 0,
+            handshake_failures: 0,
+            client_read_pauses: 0,
+            shed_connections: 0,
+            denied_connections: 0,
+            idle_closed: 0,
+            connect_timeouts: 0,
+            held_requests: 0,
+            held_expired: 0,
+            quarantined_clients: 0,
+            quarantine_refused: 0,
+        }
+    }
+
+    pub fn merge(&mut self, other: &ConnectionStats) {
+        self.client_disconnects += other.client_disconnects;
+        self.backend_reconnects += other.backend_reconnects;
+        self.handshake_failures += other.handshake_failures;
+        self.client_read_pauses += other.client_read_pauses;
+        self.shed_connections += other.shed_connections;
+        self.denied_connections += other.denied_connections;
+        self.idle_closed += other.idle_closed;
+        self.connect_timeouts += other.connect_timeouts;
+        self.held_requests += other.held_requests;
+        self.held_expired += other.held_expired;
+        self.quarantined_clients += other.quarantined_clients;
+        self.quarantine_refused += other.quarantine_refused;
+    }
+
+    /*
+        Formats the counters of a pool as one metric per line, e.g.:
+        cache_client_disconnects{pool="pool1",team="search"} 10
+    */
+    pub fn format_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("client_disconnects", self.client_disconnects),
+            ("backend_reconnects", self.backend_reconnects),
+            ("handshake_failures", self.handshake_failures),
+            ("client_read_pauses", self.client_read_pauses),
+            ("shed_connections", self.shed_connections),
+            ("denied_connections", self.denied_connections),
+            ("idle_closed", self.idle_closed),
+            ("connect_timeouts", self.connect_timeouts),
+            ("held_requests", self.held_requests),
+            ("held_expired", self.held_expired),
+            ("quarantined_clients", self.quarantined_clients),
+            ("quarantine_refused", self.quarantine_refused),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        return output;
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_counters(&[
+            ("client_disconnects", self.client_disconnects),
+            ("backend_reconnects", self.backend_reconnects),
+            ("handshake_failures", self.handshake_failures),
+            ("client_read_pauses", self.client_read_pauses),
+            ("shed_connections", self.shed_connections),
+            ("denied_connections", self.denied_connections),
+            ("idle_closed", self.idle_closed),
+            ("connect_timeouts", self.connect_timeouts),
+            ("held_requests", self.held_requests),
+            ("held_expired", self.held_expired),
+            ("quarantined_clients", self.quarantined_clients),
+            ("quarantine_refused", self.quarantine_refused),
+        ]);
+    }
+}
+
+impl std::fmt::Display for ConnectionStats {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        try!(write!(f, "Stats:\n"));
-        try!(write!(f, "accepted_clients: {}\n", self.accepted_clients));
-        try!(write!(f, "client_connections: {}\n", self.client_connections));
-        try!(write!(f, "requests: {}\n", self.requests));
-        try!(write!(f, "responses: {}\n", self.responses));
-        try!(write!(f, "send_client_bytes: {}\n", self.send_client_bytes));
-        try!(write!(f, "recv_client_bytes: {}\n", self.recv_client_bytes));
-        try!(write!(f, "send_backend_bytes: {}\n", self.send_backend_bytes));
-        write!(f, "recv_backend_bytes: {}", self.recv_backend_bytes)
-    }
-}
\ No newline at end of file
+        write!(
+            f,
+            "client_disconnects={} backend_reconnects={} handshake_failures={} client_read_pauses={} shed_connections={} \
+             denied_connections={} idle_closed={} connect_timeouts={} held_requests={} held_expired={} \
+             quarantined_clients={} quarantine_refused={}",
+            self.client_disconnects,
+            self.backend_reconnects,
+            self.handshake_failures,
+            self.client_read_pauses,
+            self.shed_connections,
+            self.denied_connections,
+            self.idle_closed,
+            self.connect_timeouts,
+            self.held_requests,
+            self.held_expired,
+            self.quarantined_clients,
+            self.quarantine_refused
+        )
+    }
+}
+
+/*
+    Bytes currently held in buffers and queues. These are gauges, measured when reported rather than counted as traffic
+    flows, so they can be merged per pool and across the proxy.
+*/
+#[derive(Clone)]
+pub struct MemoryStats {
+    // Allocated read buffers of client connections.
+    pub client_read_buffer_bytes: usize,
+    // Bytes read from clients that have not been parsed into requests yet.
+    pub client_buffered_bytes: usize,
+    // Partial multikey responses held until every backend has responded.
+    pub client_pending_response_bytes: usize,
+    // Allocated read buffers of backend connections.
+    pub backend_read_buffer_bytes: usize,
+    // Requests waiting for a backend response.
+    pub queued_requests: usize,
+    // Memory reserved by the backend request queues.
+    pub request_queue_bytes: usize,
+}
+
+impl MemoryStats {
+    pub fn new() -> MemoryStats {
+        MemoryStats {
+            client_read_buffer_bytes: 0,
+            client_buffered_bytes: 0,
+            client_pending_response_bytes: 0,
+            backend_read_buffer_bytes: 0,
+            queued_requests: 0,
+            request_queue_bytes: 0,
+        }
+    }
+
+    // Bytes counted against memory_limit. Buffered client bytes are part of the read buffers, so aren't added again.
+    pub fn total_bytes(&self) -> usize {
+        return self.client_read_buffer_bytes + self.client_pending_response_bytes + self.backend_read_buffer_bytes + self.request_queue_bytes;
+    }
+
+    pub fn merge(&mut self, other: &MemoryStats) {
+        self.client_read_buffer_bytes += other.client_read_buffer_bytes;
+        self.client_buffered_bytes += other.client_buffered_bytes;
+        self.client_pending_response_bytes += other.client_pending_response_bytes;
+        self.backend_read_buffer_bytes += other.backend_read_buffer_bytes;
+        self.queued_requests += other.queued_requests;
+        self.request_queue_bytes += other.request_queue_bytes;
+    }
+
+    /*
+        Formats the gauges of a pool as one metric per line, e.g.:
+        cache_memory_client_read_buffer_bytes{pool="pool1",team="search"} 16384
+    */
+    pub fn format_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let metrics = [
+            ("memory_client_read_buffer_bytes", self.client_read_buffer_bytes),
+            ("memory_client_buffered_bytes", self.client_buffered_bytes),
+            ("memory_client_pending_response_bytes", self.client_pending_response_bytes),
+            ("memory_backend_read_buffer_bytes", self.backend_read_buffer_bytes),
+            ("memory_queued_requests", self.queued_requests),
+            ("memory_request_queue_bytes", self.request_queue_bytes),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        return output;
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_counters(&[
+            ("client_read_buffer_bytes", self.client_read_buffer_bytes),
+            ("client_buffered_bytes", self.client_buffered_bytes),
+            ("client_pending_response_bytes", self.client_pending_response_bytes),
+            ("backend_read_buffer_bytes", self.backend_read_buffer_bytes),
+            ("queued_requests", self.queued_requests),
+            ("request_queue_bytes", self.request_queue_bytes),
+        ]);
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "client_read_buffer_bytes={} client_buffered_bytes={} client_pending_response_bytes={} backend_read_buffer_bytes={} queued_requests={} request_queue_bytes={}",
+            self.client_read_buffer_bytes,
+            self.client_buffered_bytes,
+            self.client_pending_response_bytes,
+            self.backend_read_buffer_bytes,
+            self.queued_requests,
+            self.request_queue_bytes
+        )
+    }
+}
+
+/*
+    Length of a backend host's request queue, and the longest it has been since the stats were reset. Queue growth is
+    the earliest sign of a saturated backend.
+*/
+#[derive(Clone)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub max_depth: usize,
+    // Current adaptive concurrency limit of the host. 0 when the pool has no max_concurrency.
+    pub concurrency_limit: usize,
+}
+
+impl QueueStats {
+    pub fn new(depth: usize, max_depth: usize, concurrency_limit: usize) -> QueueStats {
+        QueueStats {
+            depth: depth,
+            max_depth: max_depth,
+            concurrency_limit: concurrency_limit,
+        }
+    }
+
+    /*
+        Formats the gauges of a backend host as one metric per line, e.g.:
+        cache_backend_queue_depth{pool="pool1",team="search",backend="127.0.0.1:6380"} 3
+    */
+    pub fn format_metrics(&self, pool_name: &str, backend_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = format!("{},backend=\"{}\"", metric_labels(pool_name, config), escape_label_value(backend_name));
+        let metrics = [
+            ("backend_queue_depth", self.depth),
+            ("backend_queue_max_depth", self.max_depth),
+            ("backend_concurrency_limit", self.concurrency_limit),
+        ];
+        let mut output = String::new();
+        for &(name, value) in metrics.iter() {
+            output.push_str(&format!("{}{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        return output;
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_counters(&[
+            ("depth", self.depth),
+            ("max_depth", self.max_depth),
+            ("concurrency_limit", self.concurrency_limit),
+        ]);
+    }
+}
+
+impl std::fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "depth={} max_depth={} concurrency_limit={}", self.depth, self.max_depth, self.concurrency_limit)
+    }
+}
+
+#[test]
+fn test_queue_stats() {
+    let queue = QueueStats::new(3, 120, 64);
+    assert_eq!(queue.to_string(), "depth=3 max_depth=120 concurrency_limit=64");
+    assert_eq!(queue.to_json(), "{\"depth\":3,\"max_depth\":120,\"concurrency_limit\":64}");
+}
+
+/*
+    Error counters split by cause, so that alerts can be routed to the right place. Each backend host keeps its own, so
+    that a misbehaving node stands out, and the pool counts the errors it returns itself. They are merged per pool when
+    reported.
+*/
+#[derive(Clone)]
+pub struct ErrorStats {
+    // Requests that received no response within the pool's timeout.
+    pub timeouts: usize,
+    // Requests that could not be sent to a backend, or whose backend went down before responding.
+    pub backend_unavailable: usize,
+    // Failed connection attempts, and connections dropped or marked down.
+    pub connection_failures: usize,
+    // Requests and responses that could not be parsed as the redis protocol, and requests over their pool's size limits.
+    pub protocol_errors: usize,
+    // AUTH rejected by the backend when connecting.
+    pub auth_failures: usize,
+    // -MOVED and -ASK redirects returned by cluster nodes.
+    pub moved_redirects: usize,
+    pub ask_redirects: usize,
+    // Any other error replies (-ERR ...) returned by the backend.
+    pub error_responses: usize,
+    // Requests rejected because the proxy was at its memory_limit.
+    pub memory_shed: usize,
+    // Requests rejected because the backend host was at its adaptive concurrency limit.
+    pub overloaded: usize,
+    // Requests rejected by client_rate_limit, client_max_inflight or key_rate_limits.
+    pub rate_limited: usize,
+}
+
+impl ErrorStats {
+    pub fn new() -> ErrorStats {
+        ErrorStats {
+            timeouts: 0,
+            backend_unavailable: 0,
+            connection_failures: 0,
+            protocol_errors: 0,
+            auth_failures: 0,
+            moved_redirects: 0,
+            ask_redirects: 0,
+            error_responses: 0,
+            memory_shed: 0,
+            overloaded: 0,
+            rate_limited: 0,
+        }
+    }
+
+    pub fn merge(&mut self, other: &ErrorStats) {
+        self.timeouts += other.timeouts;
+        self.backend_unavailable += other.backend_unavailable;
+        self.connection_failures += other.connection_failures;
+        self.protocol_errors += other.protocol_errors;
+        self.auth_failures += other.auth_failures;
+        self.moved_redirects += other.moved_redirects;
+        self.ask_redirects += other.ask_redirects;
+        self.error_responses += other.error_responses;
+        self.memory_shed += other.memory_shed;
+        self.overloaded += other.overloaded;
+        self.rate_limited += other.rate_limited;
+    }
+
+    // Counts an error reply from a backend under its category.
+    pub fn record_error_response(&mut self, response: &[u8]) {
+        if response.starts_with(b"-MOVED ") {
+            self.moved_redirects += 1;
+        } else if response.starts_with(b"-ASK ") {
+            self.ask_redirects += 1;
+        } else {
+            self.error_responses += 1;
+        }
+    }
+
+    fn categories(&self) -> [(&'static str, usize); 11] {
+        [
+            ("timeouts", self.timeouts),
+            ("backend_unavailable", self.backend_unavailable),
+            ("connection_failures", self.connection_failures),
+            ("protocol_errors", self.protocol_errors),
+            ("auth_failures", self.auth_failures),
+            ("moved_redirects", self.moved_redirects),
+            ("ask_redirects", self.ask_redirects),
+            ("error_responses", self.error_responses),
+            ("memory_shed", self.memory_shed),
+            ("overloaded", self.overloaded),
+            ("rate_limited", self.rate_limited),
+        ]
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_counters(&self.categories());
+    }
+
+    /*
+        Formats the counters of a pool as one metric per line, labelled by type. e.g.:
+        cache_errors{pool="pool1",team="search",type="timeouts"} 2
+    */
+    pub fn format_pool_metrics(&self, pool_name: &str, config: &BackendPoolConfig) -> String {
+        let labels = metric_labels(pool_name, config);
+        let mut output = String::new();
+        for &(name, value) in self.categories().iter() {
+            output.push_str(&format!("{}errors{{{},type=\"{}\"}} {}\n", config.metric_prefix, labels, name, value));
+        }
+        return output;
+    }
+
+    /*
+        Formats the counters and latency of a backend as one metric per line, e.g.:
+        cache_backend_timeouts{pool="pool1",team="search",backend="127.0.0.1:6380"} 2
+    */
+    pub fn format_metrics(&self, pool_name: &str, backend_name: &str, config: &BackendPoolConfig, latency: &LatencyHistogram) -> String {
+        let labels = format!("{},backend=\"{}\"", metric_labels(pool_name, config), escape_label_value(backend_name));
+        let mut output = String::new();
+        for &(name, value) in self.categories().iter() {
+            output.push_str(&format!("{}backend_{}{{{}}} {}\n", config.metric_prefix, name, labels, value));
+        }
+        for &(quantile, percent) in QUANTILES.iter() {
+            output.push_str(&format!("{}backend_latency_us{{{},quantile=\"{}\"}} {}\n", config.metric_prefix, labels, quantile, latency.percentile(percent)));
+        }
+        return output;
+    }
+}
+
+#[test]
+fn test_record_error_response() {
+    let mut errors = ErrorStats::new();
+    errors.record_error_response(b"-MOVED 3999 127.0.0.1:6381\r\n");
+    errors.record_error_response(b"-ASK 3999 127.0.0.1:6381\r\n");
+    errors.record_error_response(b"-ASK 3999 127.0.0.1:6381\r\n");
+    errors.record_error_response(b"-ERR unknown command 'FOO'\r\n");
+    assert_eq!(errors.moved_redirects, 1);
+    assert_eq!(errors.ask_redirects, 2);
+    assert_eq!(errors.error_responses, 1);
+    assert_eq!(
+        errors.to_string(),
+        "timeouts=0 backend_unavailable=0 connection_failures=0 protocol_errors=0 auth_failures=0 moved_redirects=1 ask_redirects=2 error_responses=1 memory_shed=0 overloaded=0 rate_limited=0"
+    );
+}
+
+impl std::fmt::Display for ErrorStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let categories = self.categories();
+        for (index, &(name, value)) in categories.iter().enumerate() {
+            if index > 0 {
+                try!(write!(f, " "));
+            }
+            try!(write!(f, "{}={}", name, value));
+        }
+        return Ok(());
+    }
+}
+
+/*
+    Counters for a single command name. Requests are counted by the pool when read from the client, while errors are
+    counted wherever the error response is produced.
+*/
+#[derive(Clone)]
+pub struct CommandStats {
+    pub requests: usize,
+    pub errors: usize,
+    // Largest request sent to and response received from a backend, in bytes.
+    pub max_request_bytes: usize,
+    pub max_response_bytes: usize,
+    pub latency: LatencyHistogram,
+}
+
+impl CommandStats {
+    pub fn new() -> CommandStats {
+        CommandStats {
+            requests: 0,
+            errors: 0,
+            max_request_bytes: 0,
+            max_response_bytes: 0,
+            latency: LatencyHistogram::new(),
+        }
+    }
+
+    pub fn merge(&mut self, other: &CommandStats) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.max_request_bytes = std::cmp::max(self.max_request_bytes, other.max_request_bytes);
+        self.max_response_bytes = std::cmp::max(self.max_response_bytes, other.max_response_bytes);
+        self.latency.merge(&other.latency);
+    }
+}
+
+impl std::fmt::Display for CommandStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "requests={} errors={} max_request_bytes={} max_response_bytes={} latency_us: {}",
+            self.requests,
+            self.errors,
+            self.max_request_bytes,
+            self.max_response_bytes,
+            self.latency,
+        )
+    }
+}
+
+pub fn command_stats<'a>(commands: &'a mut HashMap<&'static str, CommandStats>, command: &'static str) -> &'a mut CommandStats {
+    return commands.entry(command).or_insert_with(CommandStats::new);
+}
+
+// Returns the largest request and response sizes across all commands, in bytes.
+pub fn max_value_sizes(commands: &HashMap<&'static str, CommandStats>) -> (usize, usize) {
+    let mut max_sizes = (0, 0);
+    for command_stats in commands.values() {
+        max_sizes.0 = std::cmp::max(max_sizes.0, command_stats.max_request_bytes);
+        max_sizes.1 = std::cmp::max(max_sizes.1, command_stats.max_response_bytes);
+    }
+    return max_sizes;
+}
+
+/*
+    Formats the largest request and response sizes of a backend as one metric per line, e.g.:
+    cache_backend_max_request_bytes{pool="pool1",team="search",backend="127.0.0.1:6380"} 2048
+*/
+pub fn format_value_size_metrics(
+    pool_name: &str,
+    backend_name: &str,
+    config: &BackendPoolConfig,
+    commands: &HashMap<&'static str, CommandStats>,
+) -> String {
+    let labels = format!("{},backend=\"{}\"", metric_labels(pool_name, config), escape_label_value(backend_name));
+    let (max_request_bytes, max_response_bytes) = max_value_sizes(commands);
+    return format!(
+        "{}backend_max_request_bytes{{{}}} {}\n{}backend_max_response_bytes{{{}}} {}\n",
+        config.metric_prefix,
+        labels,
+        max_request_bytes,
+        config.metric_prefix,
+        labels,
+        max_response_bytes,
+    );
+}
+
+#[test]
+fn test_max_value_sizes() {
+    let mut commands = HashMap::new();
+    assert_eq!(max_value_sizes(&commands), (0, 0));
+    command_stats(&mut commands, "GET").max_request_bytes = 27;
+    command_stats(&mut commands, "GET").max_response_bytes = 2048;
+    command_stats(&mut commands, "SET").max_request_bytes = 4096;
+    command_stats(&mut commands, "SET").max_response_bytes = 5;
+    assert_eq!(max_value_sizes(&commands), (4096, 2048));
+
+    let mut merged = CommandStats::new();
+    merged.merge(&commands["GET"]);
+    merged.merge(&commands["SET"]);
+    assert_eq!(merged.max_request_bytes, 4096);
+    assert_eq!(merged.max_response_bytes, 2048);
+}
+
+// Merges each command's stats into the given sorted map.
+pub fn merge_command_stats(into: &mut BTreeMap<&'static str, CommandStats>, commands: &HashMap<&'static str, CommandStats>) {
+    for (command, command_stats) in commands {
+        into.entry(*command).or_insert_with(CommandStats::new).merge(command_stats);
+    }
+}
+
+// Each power of two is split into this many linear sub-buckets, which bounds the recording error to ~6%.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = SUB_BUCKET_COUNT * (64 - SUB_BUCKET_BITS as usize + 1);
+
+pub fn micros_since(start: Instant) -> u64 {
+    return duration_micros(clock::since(start));
+}
+
+pub fn duration_micros(duration: Duration) -> u64 {
+    return duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64;
+}
+
+/*
+    A log-linear (HDR-style) histogram of latencies, in microseconds. Recording is constant time, and histograms can be
+    merged, so per-pool latencies are built from the per-backend histograms when reported.
+*/
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; NUM_BUCKETS],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, micros: u64) {
+        self.counts[bucket_index(micros)] += 1;
+        self.total += 1;
+    }
+
+    pub fn record_since(&mut self, start: Instant) {
+        self.record(micros_since(start));
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other_count;
+        }
+        self.total += other.total;
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.total;
+    }
+
+    // Returns the highest latency, in microseconds, at or below which the given percent of recorded values fall.
+    pub fn percentile(&self, percent: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let target = std::cmp::max(1, (percent / 100.0 * self.total as f64).ceil() as u64);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= target {
+                return highest_bucket_value(index);
+            }
+        }
+        return highest_bucket_value(NUM_BUCKETS - 1);
+    }
+
+    pub fn reset(&mut self) {
+        for count in self.counts.iter_mut() {
+            *count = 0;
+        }
+        self.total = 0;
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_object(&[
+            ("count", self.total.to_string()),
+            ("p50", self.percentile(50.0).to_string()),
+            ("p95", self.percentile(95.0).to_string()),
+            ("p99", self.percentile(99.0).to_string()),
+            ("p999", self.percentile(99.9).to_string()),
+        ]);
+    }
+}
+
+impl std::fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "count={} p50={} p95={} p99={} p999={}",
+            self.total,
+            self.percentile(50.0),
+            self.percentile(95.0),
+            self.percentile(99.0),
+            self.percentile(99.9)
+        )
+    }
+}
+
+/*
+    Health of the event loop, which does all of the proxy's work on a single thread. If iterations take long while
+    backend latency is low, the loop itself is the bottleneck.
+*/
+pub struct EventLoopStats {
+    // Time spent handling each wakeup, in microseconds. Time spent blocked in poll is not included.
+    pub iteration_time: LatencyHistogram,
+    // Number of events returned by each wakeup. Small counts fall in exact buckets of the histogram.
+    pub events_per_wakeup: LatencyHistogram,
+    // Time spent writing responses to client sockets in each wakeup, in microseconds.
+    pub client_write_time: LatencyHistogram,
+    // Time spent writing to client sockets so far in the current wakeup.
+    current_client_write_time: Duration,
+    // Iterations that took longer than the watchdog_threshold.
+    pub stalls: usize,
+    // Times the worker stopped accepting clients because it was overloaded.
+    pub accept_pauses: usize,
+    // Client requests sent to a backend by each write to it. Pipelining, and batch_dispatch, raise it.
+    pub requests_per_backend_write: LatencyHistogram,
+}
+
+impl EventLoopStats {
+    pub fn new() -> EventLoopStats {
+        EventLoopStats {
+            iteration_time: LatencyHistogram::new(),
+            events_per_wakeup: LatencyHistogram::new(),
+            client_write_time: LatencyHistogram::new(),
+            current_client_write_time: Duration::from_secs(0),
+            stalls: 0,
+            accept_pauses: 0,
+            requests_per_backend_write: LatencyHistogram::new(),
+        }
+    }
+
+    pub fn record_client_write(&mut self, start: Instant) {
+        self.current_client_write_time += start.elapsed();
+    }
+
+    // Called at the end of each wakeup, with the time poll returned.
+    pub fn record_iteration(&mut self, start: Instant, events: usize) {
+        self.iteration_time.record_since(start);
+        self.events_per_wakeup.record(events as u64);
+        self.client_write_time.record(duration_micros(self.current_client_write_time));
+        self.current_client_write_time = Duration::from_secs(0);
+    }
+
+    pub fn reset(&mut self) {
+        self.iteration_time.reset();
+        self.events_per_wakeup.reset();
+        self.client_write_time.reset();
+        self.stalls = 0;
+        self.accept_pauses = 0;
+        self.requests_per_backend_write.reset();
+    }
+
+    pub fn to_json(&self) -> String {
+        return json_object(&[
+            ("iteration_us", self.iteration_time.to_json()),
+            ("events_per_wakeup", self.events_per_wakeup.to_json()),
+            ("client_write_us", self.client_write_time.to_json()),
+            ("stalls", self.stalls.to_string()),
+            ("accept_pauses", self.accept_pauses.to_string()),
+            ("requests_per_backend_write", self.requests_per_backend_write.to_json()),
+        ]);
+    }
+}
+
+/*
+    e.g.:
+    Event loop:
+    iteration_us: count=120 p50=35 p95=110 p99=300 p999=900
+    events_per_wakeup: count=120 p50=2 p95=8 p99=16 p999=40
+    client_write_us: count=120 p50=10 p95=40 p99=90 p999=200
+    stalls: 0
+    accept_pauses: 0
+    requests_per_backend_write: count=80 p50=1 p95=6 p99=12 p999=30
+*/
+impl std::fmt::Display for EventLoopStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Event loop:\niteration_us: {}\nevents_per_wakeup: {}\nclient_write_us: {}\nstalls: {}\naccept_pauses: {}\nrequests_per_backend_write: {}",
+            self.iteration_time,
+            self.events_per_wakeup,
+            self.client_write_time,
+            self.stalls,
+            self.accept_pauses,
+            self.requests_per_backend_write
+        )
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - SUB_BUCKET_COUNT;
+    return SUB_BUCKET_COUNT * (shift as usize + 1) + sub_bucket;
+}
+
+fn lowest_bucket_value(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64;
+    }
+    let shift = index / SUB_BUCKET_COUNT - 1;
+    let sub_bucket = index % SUB_BUCKET_COUNT;
+    return ((SUB_BUCKET_COUNT + sub_bucket) as u64) << shift;
+}
+
+fn highest_bucket_value(index: usize) -> u64 {
+    if index + 1 >= NUM_BUCKETS {
+        return u64::max_value();
+    }
+    return lowest_bucket_value(index + 1) - 1;
+}
+
+#[test]
+fn test_latency_histogram() {
+    let mut histogram = LatencyHistogram::new();
+    assert_eq!(histogram.percentile(50.0), 0);
+    for value in 1..1001 {
+        histogram.record(value);
+    }
+    assert_eq!(histogram.count(), 1000);
+    // Values are accurate to within a bucket width (1/16th of the value).
+    let p50 = histogram.percentile(50.0);
+    assert!(p50 >= 500 && p50 <= 500 + 500 / 16, "p50 was {}", p50);
+    let p99 = histogram.percentile(99.0);
+    assert!(p99 >= 990 && p99 <= 990 + 990 / 16, "p99 was {}", p99);
+    assert_eq!(histogram.percentile(100.0), histogram.percentile(99.99));
+
+    let mut other = LatencyHistogram::new();
+    other.record(5);
+    other.merge(&histogram);
+    assert_eq!(other.count(), 1001);
+
+    histogram.record(u64::max_value());
+    assert_eq!(histogram.percentile(100.0), u64::max_value());
+    histogram.reset();
+    assert_eq!(histogram.count(), 0);
+
+    for value in 0..100000 {
+        assert!(lowest_bucket_value(bucket_index(value)) <= value);
+        assert!(highest_bucket_value(bucket_index(value)) >= value);
+    }
+}
+
+#[test]
+fn test_event_loop_stats() {
+    let mut event_loop = EventLoopStats::new();
+    let start = Instant::now() - Duration::from_millis(5);
+    event_loop.record_client_write(Instant::now() - Duration::from_millis(2));
+    event_loop.record_client_write(Instant::now() - Duration::from_millis(1));
+    event_loop.record_iteration(start, 3);
+    assert!(event_loop.iteration_time.percentile(100.0) >= 5000);
+    assert_eq!(event_loop.events_per_wakeup.percentile(100.0), 3);
+    // Writes are summed over the wakeup.
+    assert!(event_loop.client_write_time.percentile(100.0) >= 3000);
+
+    // The write time starts over with each wakeup.
+    event_loop.record_iteration(Instant::now(), 0);
+    assert_eq!(event_loop.client_write_time.percentile(50.0), 0);
+    assert_eq!(event_loop.events_per_wakeup.count(), 2);
+
+    event_loop.reset();
+    assert_eq!(event_loop.iteration_time.count(), 0);
+    assert!(event_loop.to_string().starts_with("Event loop:\niteration_us: count=0 "));
+}
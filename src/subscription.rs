@@ -0,0 +1,188 @@
+use log::LogLevel;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How long to wait before resubscribing to a host that dropped the subscription or couldn't be reached.
+const RECONNECT_DELAY_MS: u64 = 1000;
+const CONNECT_TIMEOUT_MS: u64 = 1000;
+
+/*
+    Threads that each keep a subscription up on their own blocking connection, e.g. to a backend or a sentinel, and
+    hand what they receive over to the event loop through a channel. The threads stop once this is dropped.
+*/
+pub struct Subscriptions {
+    // Connection of each thread, shut down to stop it.
+    connections: Vec<Arc<Mutex<Option<TcpStream>>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        Subscriptions {
+            connections: Vec::new(),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /*
+        Runs subscribe on a new connection to the host, and again on another one each time it fails. subscribe returns
+        Ok once the thread should stop, e.g. because the receiving end of its channel is gone.
+    */
+    pub fn spawn<F>(&mut self, host: SocketAddr, mut subscribe: F)
+        where F: FnMut(&mut Connection) -> Result<(), String> + Send + 'static
+    {
+        let connection = Arc::new(Mutex::new(None));
+        self.connections.push(connection.clone());
+        let stopped = self.stopped.clone();
+        thread::spawn(move || {
+            loop {
+                let result = Connection::open(host, &connection, &stopped).and_then(|mut opened| subscribe(&mut opened));
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                match result {
+                    Ok(_) => return,
+                    Err(err) => {
+                        log_event!(LogLevel::Warn, "subscription_lost", { host: host }, "Lost the subscription to {}: {}", host, err);
+                    }
+                }
+                thread::sleep(Duration::from_millis(RECONNECT_DELAY_MS));
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        for connection in self.connections.iter() {
+            if let Ok(connection) = connection.lock() {
+                if let Some(ref stream) = *connection {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+    }
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    // Connects to the host, for commands whose replies are read right away. Reads time out after CONNECT_TIMEOUT_MS.
+    pub fn connect(host: SocketAddr) -> Result<Connection, String> {
+        let stream = try!(TcpStream::connect_timeout(&host, Duration::from_millis(CONNECT_TIMEOUT_MS)).map_err(|err| format!("connect failed: {}", err)));
+        try!(stream.set_read_timeout(Some(Duration::from_millis(CONNECT_TIMEOUT_MS))).map_err(|err| err.to_string()));
+        return Connection::from_stream(stream);
+    }
+
+    // Connects to the host, and stores the connection for Subscriptions to shut down.
+    fn open(host: SocketAddr, connection: &Mutex<Option<TcpStream>>, stopped: &AtomicBool) -> Result<Connection, String> {
+        let stream = try!(TcpStream::connect_timeout(&host, Duration::from_millis(CONNECT_TIMEOUT_MS)).map_err(|err| format!("connect failed: {}", err)));
+        *connection.lock().unwrap() = Some(try!(stream.try_clone().map_err(|err| err.to_string())));
+        // Subscriptions may have been dropped before the connection was stored for it to shut down.
+        if stopped.load(Ordering::SeqCst) {
+            return Err("stopped".to_owned());
+        }
+        return Connection::from_stream(stream);
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<Connection, String> {
+        let writer = try!(stream.try_clone().map_err(|err| err.to_string()));
+        return Ok(Connection {
+            reader: BufReader::new(stream),
+            writer: writer,
+        });
+    }
+
+    // Sends a command and reads its reply. Fails on an error reply.
+    pub fn command(&mut self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        try!(self.writer.write_all(&request).map_err(|err| format!("write failed: {}", err)));
+        return match try!(self.read_reply()) {
+            Reply::Error(err) => Err(format!("{} failed: {}", String::from_utf8_lossy(args[0]), err)),
+            reply => Ok(reply),
+        };
+    }
+
+    pub fn read_reply(&mut self) -> Result<Reply, String> {
+        return read_reply(&mut self.reader);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status,
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, String> {
+    let mut line = Vec::new();
+    match reader.read_until(b'\n', &mut line) {
+        Ok(0) => return Err("connection closed".to_owned()),
+        Ok(_) => {}
+        Err(err) => return Err(format!("read failed: {}", err)),
+    }
+    if line.len() < 3 || !line.ends_with(b"\r\n") {
+        return Err("invalid reply".to_owned());
+    }
+    let value = String::from_utf8_lossy(&line[1..line.len() - 2]).into_owned();
+    let len = || value.parse::<i64>().map_err(|_| format!("invalid reply: {}", value));
+    return match line[0] {
+        b'+' => Ok(Reply::Status),
+        b'-' => Ok(Reply::Error(value.clone())),
+        b':' => Ok(Reply::Integer(try!(len()))),
+        b'$' => {
+            let len = try!(len());
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut bulk = vec![0; len as usize + 2];
+            try!(reader.read_exact(&mut bulk).map_err(|err| format!("read failed: {}", err)));
+            bulk.truncate(len as usize);
+            Ok(Reply::Bulk(Some(bulk)))
+        }
+        b'*' => {
+            let len = try!(len());
+            if len < 0 {
+                return Ok(Reply::Array(None));
+            }
+            let mut items = Vec::new();
+            for _ in 0..len {
+                items.push(try!(read_reply(reader)));
+            }
+            Ok(Reply::Array(Some(items)))
+        }
+        _ => Err(format!("invalid reply: {}", String::from_utf8_lossy(&line))),
+    };
+}
+
+#[test]
+fn test_read_reply() {
+    use std::io::Cursor;
+    let mut reader = Cursor::new(&b"+OK\r\n-ERR no\r\n:7\r\n$-1\r\n*2\r\n$1\r\na\r\n*-1\r\n$3\r\nab"[..]);
+    assert_eq!(read_reply(&mut reader), Ok(Reply::Status));
+    assert_eq!(read_reply(&mut reader), Ok(Reply::Error("ERR no".to_owned())));
+    assert_eq!(read_reply(&mut reader), Ok(Reply::Integer(7)));
+    assert_eq!(read_reply(&mut reader), Ok(Reply::Bulk(None)));
+    assert_eq!(read_reply(&mut reader), Ok(Reply::Array(Some(vec![Reply::Bulk(Some(b"a".to_vec())), Reply::Array(None)]))));
+    // Cut off partway through.
+    assert!(read_reply(&mut reader).is_err());
+}
@@ -0,0 +1,224 @@
+use humantime;
+use libc;
+use log::{LogLevel, LogRecord};
+use log4rs::append::Append;
+use log4rs::encode::Encode;
+use log4rs::encode::writer::simple::SimpleWriter;
+use std::error::Error;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::time::SystemTime;
+
+// Sockets that the local syslog daemon usually listens on, in order of preference.
+const LOCAL_SYSLOG_PATHS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+const APP_NAME: &str = "redflareproxy";
+
+const FACILITIES: [(&str, u8); 20] = [
+    ("kern", 0), ("user", 1), ("mail", 2), ("daemon", 3), ("auth", 4), ("syslog", 5), ("lpr", 6), ("news", 7),
+    ("uucp", 8), ("cron", 9), ("authpriv", 10), ("ftp", 11), ("local0", 16), ("local1", 17), ("local2", 18),
+    ("local3", 19), ("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23),
+];
+const SEVERITIES: [(&str, u8); 8] = [
+    ("emerg", 0), ("alert", 1), ("crit", 2), ("err", 3), ("warning", 4), ("notice", 5), ("info", 6), ("debug", 7),
+];
+
+pub fn parse_facility(name: &str) -> Option<u8> {
+    let name = name.trim().to_lowercase();
+    return FACILITIES.iter().find(|&&(facility, _)| facility == name).map(|&(_, code)| code);
+}
+
+/*
+    Maps each log level to a syslog severity, indexed by LogLevel (ERROR first).
+    Starts from the standard mapping, and applies overrides of the form "ERROR=crit,INFO=notice".
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SeverityMap([u8; 5]);
+
+impl SeverityMap {
+    pub fn parse(overrides: Option<&str>) -> Result<SeverityMap, String> {
+        // err, warning, info, debug, debug
+        let mut severities = [3, 4, 6, 7, 7];
+        let overrides = match overrides {
+            Some(overrides) => overrides,
+            None => return Ok(SeverityMap(severities)),
+        };
+        for entry in overrides.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let level = parts.next().unwrap_or("").trim().to_uppercase();
+            let severity = parts.next().unwrap_or("").trim().to_lowercase();
+            let index = match level.as_str() {
+                "ERROR" => 0,
+                "WARNING" => 1,
+                "INFO" => 2,
+                "DEBUG" => 3,
+                "TRACE" => 4,
+                _ => return Err(entry.to_owned()),
+            };
+            severities[index] = match SEVERITIES.iter().find(|&&(name, _)| name == severity) {
+                Some(&(_, code)) => code,
+                None => return Err(entry.to_owned()),
+            };
+        }
+        return Ok(SeverityMap(severities));
+    }
+
+    fn severity(&self, level: LogLevel) -> u8 {
+        return self.0[level as usize - 1];
+    }
+}
+
+enum Transport {
+    // The local syslog daemon. Messages use the traditional format, and the daemon adds the timestamp and hostname.
+    Local(UnixDatagram),
+    // A remote syslog server, over UDP. Messages use the RFC 5424 format.
+    Remote(UdpSocket, SocketAddr, String),
+}
+
+/*
+    Sends log records to syslog, either through the local daemon's socket or to a remote server over UDP.
+*/
+pub struct SyslogAppender {
+    transport: Transport,
+    facility: u8,
+    severities: SeverityMap,
+    encoder: Box<Encode>,
+}
+
+impl fmt::Debug for SyslogAppender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let destination = match self.transport {
+            Transport::Local(_) => "local".to_owned(),
+            Transport::Remote(_, addr, _) => addr.to_string(),
+        };
+        write!(f, "SyslogAppender {{ destination: {}, facility: {} }}", destination, self.facility)
+    }
+}
+
+impl SyslogAppender {
+    /*
+        Connects to syslog. The address is either "local", for the local syslog daemon, or the host:port of a remote
+        syslog server.
+    */
+    pub fn new(address: &str, facility: u8, severities: SeverityMap, encoder: Box<Encode>) -> Result<SyslogAppender, std::io::Error> {
+        let transport = if address == "local" {
+            let socket = try!(UnixDatagram::unbound());
+            let mut connected = Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no local syslog socket found"));
+            for path in LOCAL_SYSLOG_PATHS.iter() {
+                connected = socket.connect(path);
+                if connected.is_ok() {
+                    break;
+                }
+            }
+            try!(connected);
+            Transport::Local(socket)
+        } else {
+            let addr = match try!(address.to_socket_addrs()).next() {
+                Some(addr) => addr,
+                None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "syslog address did not resolve")),
+            };
+            let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            Transport::Remote(try!(UdpSocket::bind(bind_addr)), addr, hostname())
+        };
+        Ok(SyslogAppender {
+            transport: transport,
+            facility: facility,
+            severities: severities,
+            encoder: encoder,
+        })
+    }
+
+    // The syslog header, up to the start of the message.
+    fn header(&self, level: LogLevel) -> Vec<u8> {
+        let priority = self.facility as usize * 8 + self.severities.severity(level) as usize;
+        match self.transport {
+            Transport::Local(_) => format!("<{}>{}[{}]: ", priority, APP_NAME, std::process::id()).into_bytes(),
+            Transport::Remote(_, _, ref hostname) => format!(
+                "<{}>1 {} {} {} {} - - ",
+                priority,
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                hostname,
+                APP_NAME,
+                std::process::id()
+            ).into_bytes(),
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> Result<usize, std::io::Error> {
+        match self.transport {
+            Transport::Local(ref socket) => socket.send(message),
+            Transport::Remote(ref socket, addr, _) => socket.send_to(message, addr),
+        }
+    }
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &LogRecord) -> Result<(), Box<Error + Sync + Send>> {
+        let mut writer = SimpleWriter(self.header(record.level()));
+        try!(self.encoder.encode(&mut writer, record));
+        let mut message = writer.0;
+        // Each datagram is a single record, so the encoder's trailing newline isn't needed.
+        while message.last() == Some(&b'\n') {
+            message.pop();
+        }
+        try!(self.send(&message));
+        return Ok(());
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return "-".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(name) if !name.is_empty() => name.to_owned(),
+        _ => "-".to_owned(),
+    }
+}
+
+#[test]
+fn test_parse_facility() {
+    assert_eq!(parse_facility("daemon"), Some(3));
+    assert_eq!(parse_facility("LOCAL7"), Some(23));
+    assert_eq!(parse_facility("local8"), None);
+}
+
+#[test]
+fn test_severity_map() {
+    let default = SeverityMap::parse(None).unwrap();
+    assert_eq!(default.severity(LogLevel::Error), 3);
+    assert_eq!(default.severity(LogLevel::Warn), 4);
+    assert_eq!(default.severity(LogLevel::Debug), 7);
+
+    let custom = SeverityMap::parse(Some("ERROR=crit, INFO=notice")).unwrap();
+    assert_eq!(custom.severity(LogLevel::Error), 2);
+    assert_eq!(custom.severity(LogLevel::Info), 5);
+    assert_eq!(custom.severity(LogLevel::Warn), 4);
+
+    assert_eq!(SeverityMap::parse(Some("ERROR=bad")), Err("ERROR=bad".to_owned()));
+    assert_eq!(SeverityMap::parse(Some("FATAL=crit")), Err("FATAL=crit".to_owned()));
+}
+
+#[test]
+fn test_remote_syslog() {
+    use log4rs::encode::pattern::PatternEncoder;
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    let address = server.local_addr().unwrap().to_string();
+    let severities = SeverityMap::parse(Some("WARNING=notice")).unwrap();
+    let appender = SyslogAppender::new(&address, 16, severities, Box::new(PatternEncoder::new("{m}"))).unwrap();
+
+    let mut message = appender.header(LogLevel::Warn);
+    message.extend_from_slice(b"Backend 127.0.0.1:6380 is down");
+    appender.send(&message).unwrap();
+
+    let mut buf = [0; 1024];
+    let len = server.recv(&mut buf).unwrap();
+    let message = String::from_utf8(buf[..len].to_vec()).unwrap();
+    // local0 (16) * 8 + notice (5)
+    assert!(message.starts_with("<133>1 "), "{}", message);
+    assert!(message.ends_with(&format!(" redflareproxy {} - - Backend 127.0.0.1:6380 is down", std::process::id())), "{}", message);
+}
@@ -0,0 +1,178 @@
+use config::RedFlareProxyConfig;
+use handoff;
+
+use libc;
+use std::io::Error;
+use std::mem;
+use std::time::{Duration, Instant};
+
+// Where systemd listens for notifications from a Type=notify service.
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+// Set with WatchdogSec. Keepalives are expected within this many microseconds, from the process WATCHDOG_PID.
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/*
+    Notifications to systemd, for running the proxy as a Type=notify service:
+
+    READY=1 once the listeners are bound and every backend has connected, or once the longest connect_timeout has
+    passed without that, so that units ordered after the proxy don't start while it can't serve yet.
+    WATCHDOG=1 from the event loop, at half of WatchdogSec, so that systemd restarts a worker that's stuck.
+    STOPPING=1 once shutdown begins.
+
+    A hot restart's new process takes over as the main process, with MAINPID, which needs NotifyAccess=all. Socket
+    activation is handled by handoff.rs. The environment variables are left set, so that hot restarts inherit them.
+*/
+pub struct Notifier {
+    // The socket's path, with a leading NUL for an abstract socket.
+    address: Vec<u8>,
+    keepalive_interval: Option<Duration>,
+    next_keepalive: Instant,
+    ready: bool,
+    // When READY=1 is sent, even if some backends haven't connected.
+    ready_deadline: Instant,
+}
+
+impl Notifier {
+    // None unless the process was started by systemd with NOTIFY_SOCKET.
+    pub fn from_env(config: &RedFlareProxyConfig, now: Instant) -> Option<Notifier> {
+        let mut address = match std::env::var(NOTIFY_SOCKET_ENV) {
+            Ok(ref path) if !path.is_empty() => path.clone().into_bytes(),
+            _ => return None,
+        };
+        if address[0] == b'@' {
+            address[0] = 0;
+        }
+        let watchdog_pid = std::env::var(WATCHDOG_PID_ENV).ok().and_then(|pid| pid.parse::<u32>().ok());
+        // The pid of a hot restart's new process only becomes the main pid with its first notification.
+        let watched = watchdog_pid.map_or(true, |pid| pid == std::process::id() || handoff::is_restart());
+        let keepalive_interval = match std::env::var(WATCHDOG_USEC_ENV).ok().and_then(|usec| usec.parse::<u64>().ok()) {
+            Some(usec) if usec > 0 && watched => Some(Duration::from_millis(usec / 2000)),
+            _ => None,
+        };
+        let ready_timeout = config.pools.values().map(|pool| pool.connect_timeout).max().unwrap_or(0);
+        return Some(Notifier {
+            address: address,
+            keepalive_interval: keepalive_interval,
+            next_keepalive: now,
+            ready: false,
+            ready_deadline: now + Duration::from_millis(ready_timeout as u64),
+        });
+    }
+
+    pub fn is_ready(&self) -> bool {
+        return self.ready;
+    }
+
+    /*
+        Sends READY=1 once the backends have connected, or once the deadline passes anyway. Called on each iteration
+        of the event loop until it's sent.
+    */
+    pub fn check_ready(&mut self, connected: bool, now: Instant) {
+        if self.ready || (!connected && now < self.ready_deadline) {
+            return;
+        }
+        self.ready = true;
+        let status = if connected { "STATUS=Serving" } else { "STATUS=Serving, with some backends unavailable" };
+        let state = if handoff::is_restart() {
+            format!("MAINPID={}\nREADY=1\n{}", std::process::id(), status)
+        } else {
+            format!("READY=1\n{}", status)
+        };
+        self.notify(&state);
+    }
+
+    // Sends a watchdog keepalive, if one is due.
+    pub fn keepalive(&mut self, now: Instant) {
+        if let Some(interval) = self.keepalive_interval {
+            if now >= self.next_keepalive {
+                self.notify("WATCHDOG=1");
+                self.next_keepalive = now + interval;
+            }
+        }
+    }
+
+    // How long the event loop may wait before it has to send READY=1 or a keepalive.
+    pub fn time_until_due(&self, now: Instant) -> Option<Duration> {
+        let due = match (self.ready, self.keepalive_interval) {
+            (false, _) => Some(std::cmp::min(self.ready_deadline, self.next_keepalive)),
+            (true, Some(_)) => Some(self.next_keepalive),
+            (true, None) => None,
+        };
+        return due.map(|due| if due > now { due - now } else { Duration::from_millis(0) });
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = send(&self.address, state.as_bytes()) {
+            warn!("Unable to notify systemd of {:?}: {}", state, err);
+        }
+    }
+}
+
+// Sends a datagram to the unix socket at the address.
+fn send(address: &[u8], message: &[u8]) -> Result<(), Error> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    // A path needs room for its terminating NUL, an abstract address doesn't have one.
+    let terminator = if address[0] == 0 { 0 } else { 1 };
+    if address.len() + terminator > addr.sun_path.len() {
+        return Err(Error::new(std::io::ErrorKind::InvalidInput, "NOTIFY_SOCKET is too long"));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (index, byte) in address.iter().enumerate() {
+        addr.sun_path[index] = *byte as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + address.len() + terminator;
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0);
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        let sent = libc::sendto(fd, message.as_ptr() as *const libc::c_void, message.len(), 0, &addr as *const _ as *const libc::sockaddr, len as libc::socklen_t);
+        let result = if sent == -1 { Err(Error::last_os_error()) } else { Ok(()) };
+        libc::close(fd);
+        return result;
+    }
+}
+
+#[test]
+fn test_notify() {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = format!("/tmp/redflare_notify_test_{}", std::process::id());
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let now = Instant::now();
+    let mut notifier = Notifier {
+        address: path.clone().into_bytes(),
+        keepalive_interval: Some(Duration::from_millis(500)),
+        next_keepalive: now,
+        ready: false,
+        ready_deadline: now + Duration::from_millis(100),
+    };
+    let mut buf = [0; 128];
+
+    // Not ready until the backends connect, or the deadline passes.
+    notifier.check_ready(false, now);
+    assert!(!notifier.is_ready());
+    assert_eq!(notifier.time_until_due(now + Duration::from_millis(50)), Some(Duration::from_millis(0)));
+    notifier.keepalive(now);
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"WATCHDOG=1");
+    assert_eq!(notifier.time_until_due(now), Some(Duration::from_millis(100)));
+    notifier.check_ready(false, now + Duration::from_millis(100));
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &b"READY=1\nSTATUS=Serving, with some backends unavailable"[..]);
+
+    // The next keepalive is only due after the interval.
+    notifier.keepalive(now + Duration::from_millis(400));
+    assert_eq!(notifier.time_until_due(now + Duration::from_millis(400)), Some(Duration::from_millis(100)));
+    notifier.stopping();
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"STOPPING=1");
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,300 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+// Slots per level. Each level covers SLOTS times the span of the level below it.
+const SLOT_BITS: u32 = 6;
+const SLOTS: u64 = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+
+/*
+    Identifies a pending deadline, so that it can be cancelled. Ids of expired or cancelled deadlines are stale, and
+    are ignored.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerId {
+    index: usize,
+    generation: usize,
+}
+
+struct Entry<T> {
+    tick: u64,
+    generation: usize,
+    value: Option<T>,
+}
+
+/*
+    A hierarchical timer wheel, holding every pending deadline of an event loop. Deadlines are rounded up to the next
+    tick. Inserting and cancelling a deadline is O(1), and advancing the wheel is O(1) per elapsed tick and expired
+    deadline, regardless of how many deadlines are pending.
+
+    Level 0 has a slot per tick. Each higher level has a slot per full rotation of the level below it, and its slots
+    are cascaded into the lower levels as the wheel reaches them. Deadlines past the top level's span are parked in its
+    furthest slot, and placed again when it is cascaded.
+*/
+pub struct TimerWheel<T> {
+    start: Instant,
+    tick_nanos: u64,
+    // The last tick that has been advanced to.
+    current: u64,
+    // LEVELS * SLOTS lists of (index, generation). Cancelled entries are left in their slot, and skipped once reached.
+    slots: Vec<Vec<(usize, usize)>>,
+    entries: Vec<Entry<T>>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, now: Instant) -> TimerWheel<T> {
+        let tick_nanos = duration_nanos(tick);
+        if tick_nanos == 0 {
+            panic!("TimerWheel tick must be at least a nanosecond.");
+        }
+        let mut slots = Vec::with_capacity(LEVELS * SLOTS as usize);
+        for _ in 0..LEVELS * SLOTS as usize {
+            slots.push(Vec::new());
+        }
+        TimerWheel {
+            start: now,
+            tick_nanos: tick_nanos,
+            current: 0,
+            slots: slots,
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let tick = self.deadline_tick(deadline);
+        let index = match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.tick = tick;
+                entry.value = Some(value);
+                index
+            }
+            None => {
+                self.entries.push(Entry {
+                    tick: tick,
+                    generation: 0,
+                    value: Some(value),
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.len += 1;
+        self.place(index);
+        return TimerId {
+            index: index,
+            generation: self.entries[index].generation,
+        };
+    }
+
+    // Returns the deadline's value, or None if it already expired or was cancelled.
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let value = match self.entries.get_mut(id.index) {
+            Some(ref mut entry) if entry.generation == id.generation => entry.value.take(),
+            _ => None,
+        };
+        if value.is_some() {
+            self.release(id.index);
+        }
+        return value;
+    }
+
+    // The value of a pending deadline, e.g. to retarget it.
+    pub fn get_mut(&mut self, id: TimerId) -> Option<&mut T> {
+        match self.entries.get_mut(id.index) {
+            Some(entry) => {
+                if entry.generation == id.generation {
+                    return entry.value.as_mut();
+                }
+                return None;
+            }
+            None => return None,
+        }
+    }
+
+    /*
+        Advances the wheel to now, moving the values of every deadline that has passed into expired, in order of their
+        deadline's tick.
+    */
+    pub fn advance(&mut self, now: Instant, expired: &mut Vec<T>) {
+        let now_tick = self.elapsed_ticks(now);
+        while self.current < now_tick {
+            // Skip the ticks that have nothing to expire or cascade.
+            match self.next_tick() {
+                Some(next) if next <= now_tick => self.current = next - 1,
+                _ => {
+                    self.current = now_tick;
+                    return;
+                }
+            }
+            self.current += 1;
+            // Cascade the higher levels first, so that their deadlines reach level 0 before it is expired.
+            for level in (1..LEVELS).rev() {
+                if self.current & (level_span(level) - 1) == 0 {
+                    let slot = slot_index(level, self.current);
+                    let cascaded = mem::replace(&mut self.slots[slot], Vec::new());
+                    for (index, generation) in cascaded {
+                        if self.is_pending(index, generation) {
+                            self.place(index);
+                        }
+                    }
+                }
+            }
+            let slot = slot_index(0, self.current);
+            let due = mem::replace(&mut self.slots[slot], Vec::new());
+            for (index, generation) in due {
+                if !self.is_pending(index, generation) {
+                    continue;
+                }
+                if let Some(value) = self.entries[index].value.take() {
+                    expired.push(value);
+                }
+                self.release(index);
+            }
+        }
+    }
+
+    /*
+        Time until the wheel next needs to be advanced, for use as a poll timeout. This may be earlier than the next
+        deadline, when a higher level slot is due to be cascaded.
+    */
+    pub fn time_until_next(&self, now: Instant) -> Option<Duration> {
+        return self.next_tick().map(|tick| {
+            let due = self.start + Duration::from_nanos(tick * self.tick_nanos);
+            if due > now {
+                due - now
+            } else {
+                Duration::from_millis(0)
+            }
+        });
+    }
+
+    // The next tick with a slot to expire or cascade.
+    fn next_tick(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut next: Option<u64> = None;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let block = self.current >> shift;
+            for offset in 1..SLOTS + 1 {
+                let tick = (block + offset) << shift;
+                if !self.slots[slot_index(level, tick)].is_empty() {
+                    if next.map_or(true, |next| tick < next) {
+                        next = Some(tick);
+                    }
+                    break;
+                }
+            }
+        }
+        return next;
+    }
+
+    fn is_pending(&self, index: usize, generation: usize) -> bool {
+        let entry = &self.entries[index];
+        return entry.generation == generation && entry.value.is_some();
+    }
+
+    fn release(&mut self, index: usize) {
+        self.entries[index].generation += 1;
+        self.free.push(index);
+        self.len -= 1;
+    }
+
+    // Files the entry into the slot of the lowest level whose span covers its deadline.
+    fn place(&mut self, index: usize) {
+        let (tick, generation) = {
+            let entry = &self.entries[index];
+            (entry.tick, entry.generation)
+        };
+        // Passed deadlines expire on the next tick.
+        let tick = if tick <= self.current { self.current + 1 } else { tick };
+        let delta = tick - self.current;
+        let mut slot = slot_index(LEVELS - 1, self.current + level_span(LEVELS) - 1);
+        for level in 0..LEVELS {
+            if delta < level_span(level + 1) {
+                slot = slot_index(level, tick);
+                break;
+            }
+        }
+        self.slots[slot].push((index, generation));
+    }
+
+    fn deadline_tick(&self, deadline: Instant) -> u64 {
+        if deadline <= self.start {
+            return 0;
+        }
+        let nanos = duration_nanos(deadline - self.start);
+        return (nanos + self.tick_nanos - 1) / self.tick_nanos;
+    }
+
+    fn elapsed_ticks(&self, now: Instant) -> u64 {
+        if now <= self.start {
+            return 0;
+        }
+        return duration_nanos(now - self.start) / self.tick_nanos;
+    }
+}
+
+// Ticks covered by one slot of the given level. level_span(LEVELS) is the span of the whole wheel.
+fn level_span(level: usize) -> u64 {
+    return 1 << (SLOT_BITS * level as u32);
+}
+
+fn slot_index(level: usize, tick: u64) -> usize {
+    let slot = (tick >> (SLOT_BITS * level as u32)) & (SLOTS - 1);
+    return level * SLOTS as usize + slot as usize;
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    return duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64;
+}
+
+#[test]
+fn test_timer_wheel() {
+    let start = Instant::now();
+    let ms = |millis: u64| start + Duration::from_millis(millis);
+    let mut wheel = TimerWheel::new(Duration::from_millis(1), start);
+    let mut expired = Vec::new();
+
+    // Deadlines on every level, inserted out of order.
+    wheel.insert(ms(100_000), "level 2");
+    wheel.insert(ms(5), "level 0");
+    let cancelled = wheel.insert(ms(70), "cancelled");
+    wheel.insert(ms(70), "level 1");
+    wheel.insert(ms(30_000_000), "past the wheel");
+    assert_eq!(wheel.time_until_next(start), Some(Duration::from_millis(5)));
+
+    assert_eq!(wheel.cancel(cancelled), Some("cancelled"));
+    assert_eq!(wheel.cancel(cancelled), None);
+    // The cancelled entry's slot is reused, without reviving its id.
+    let reused = wheel.insert(ms(10), "reused");
+    assert_eq!(wheel.cancel(cancelled), None);
+    *wheel.get_mut(reused).unwrap() = "retargeted";
+
+    wheel.advance(ms(4), &mut expired);
+    assert!(expired.is_empty());
+    wheel.advance(ms(69), &mut expired);
+    assert_eq!(expired, vec!["level 0", "retargeted"]);
+    wheel.advance(ms(70), &mut expired);
+    assert_eq!(expired, vec!["level 0", "retargeted", "level 1"]);
+
+    expired.clear();
+    wheel.advance(ms(99_999), &mut expired);
+    assert!(expired.is_empty());
+    wheel.advance(ms(100_000), &mut expired);
+    assert_eq!(expired, vec!["level 2"]);
+    wheel.advance(ms(40_000_000), &mut expired);
+    assert_eq!(expired, vec!["level 2", "past the wheel"]);
+    assert_eq!(wheel.time_until_next(ms(40_000_000)), None);
+
+    // Passed deadlines expire on the next tick.
+    wheel.insert(ms(1), "late");
+    assert_eq!(wheel.time_until_next(ms(40_000_000)), Some(Duration::from_millis(1)));
+    wheel.advance(ms(40_000_001), &mut expired);
+    assert_eq!(expired.last(), Some(&"late"));
+}
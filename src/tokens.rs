@@ -0,0 +1,159 @@
+use mio::Token;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// The low bits of a token hold its kind, and the rest hold its index among the tokens of that kind.
+pub const KIND_BITS: u32 = 4;
+const KIND_MASK: usize = (1 << KIND_BITS) - 1;
+
+/*
+    What a token registered to a worker's Poll refers to. The index of PoolListener, PoolServer and ClusterServer
+    tokens is the position in the worker's backendpools, backends and cluster_backends. Client tokens are allocated
+    from a TokenSlab, so that their values are reused instead of growing without bound.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    // Marks the proxy's own requests to a backend, e.g. AUTH. Never registered.
+    Null = 0,
+    AdminListener = 1,
+    AdminClient = 2,
+    // Woken up when shutdown is requested. See signals::register.
+    ShutdownSignal = 3,
+    PoolListener = 4,
+    PoolServer = 5,
+    ClusterServer = 6,
+    PoolClient = 7,
+    // Accepts the connections of transparent proxy mode. See transparent.rs.
+    TransparentListener = 8,
+}
+
+pub fn token(kind: TokenKind, index: usize) -> Token {
+    return Token(index << KIND_BITS | kind as usize);
+}
+
+pub fn kind(token: Token) -> TokenKind {
+    match token.0 & KIND_MASK {
+        1 => TokenKind::AdminListener,
+        2 => TokenKind::AdminClient,
+        3 => TokenKind::ShutdownSignal,
+        4 => TokenKind::PoolListener,
+        5 => TokenKind::PoolServer,
+        6 => TokenKind::ClusterServer,
+        7 => TokenKind::PoolClient,
+        8 => TokenKind::TransparentListener,
+        _ => TokenKind::Null,
+    }
+}
+
+pub fn index(token: Token) -> usize {
+    return token.0 >> KIND_BITS;
+}
+
+/*
+    Allocates the tokens of one kind, reusing the indices of released tokens. A released index is only reused once
+    reclaim is called after the current batch of events is handled, so that a stale event for a closed connection
+    never reaches the connection that took its token.
+*/
+pub struct TokenSlab {
+    kind: TokenKind,
+    // Number of indices handed out so far, including released ones.
+    next: usize,
+    free: Vec<usize>,
+    released: Vec<usize>,
+}
+
+// Shared by a worker with every connection holding one of its tokens, like the Poll.
+pub type SharedTokenSlab = Rc<RefCell<TokenSlab>>;
+
+impl TokenSlab {
+    pub fn new(kind: TokenKind) -> TokenSlab {
+        TokenSlab {
+            kind: kind,
+            next: 0,
+            free: Vec::new(),
+            released: Vec::new(),
+        }
+    }
+
+    pub fn shared(kind: TokenKind) -> SharedTokenSlab {
+        return Rc::new(RefCell::new(TokenSlab::new(kind)));
+    }
+
+    pub fn allocate(&mut self) -> Token {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.next += 1;
+                self.next - 1
+            }
+        };
+        return token(self.kind, index);
+    }
+
+    pub fn release(&mut self, released: Token) {
+        self.released.push(index(released));
+    }
+
+    // Makes the indices released since the last call available again. Called once per event loop iteration.
+    pub fn reclaim(&mut self) {
+        self.free.append(&mut self.released);
+    }
+}
+
+/*
+    A token allocated from a TokenSlab, owned by the connection it is registered for. The token is released once the
+    connection is dropped, wherever it is removed.
+*/
+pub struct TokenSlot {
+    token: Token,
+    slab: SharedTokenSlab,
+}
+
+impl TokenSlot {
+    pub fn allocate(slab: &SharedTokenSlab) -> TokenSlot {
+        let token = slab.borrow_mut().allocate();
+        TokenSlot {
+            token: token,
+            slab: Rc::clone(slab),
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        return self.token;
+    }
+}
+
+impl Drop for TokenSlot {
+    fn drop(&mut self) {
+        self.slab.borrow_mut().release(self.token);
+    }
+}
+
+#[test]
+fn test_token_kinds() {
+    let backend = token(TokenKind::PoolServer, 12);
+    assert_eq!(kind(backend), TokenKind::PoolServer);
+    assert_eq!(index(backend), 12);
+    // Tokens of different kinds never collide, whatever their index.
+    assert!(token(TokenKind::ClusterServer, 12) != backend);
+    assert!(token(TokenKind::PoolClient, 12) != backend);
+    assert_eq!(token(TokenKind::Null, 0), Token(0));
+    assert_eq!(kind(Token(0)), TokenKind::Null);
+}
+
+#[test]
+fn test_token_slab() {
+    let slab = TokenSlab::shared(TokenKind::PoolClient);
+    let first = TokenSlot::allocate(&slab);
+    let second = TokenSlot::allocate(&slab);
+    assert_eq!(kind(first.token()), TokenKind::PoolClient);
+    assert_eq!((index(first.token()), index(second.token())), (0, 1));
+
+    // A dropped slot's token isn't reused until it is reclaimed.
+    let first_token = first.token();
+    drop(first);
+    let third = TokenSlot::allocate(&slab);
+    assert_eq!(index(third.token()), 2);
+    slab.borrow_mut().reclaim();
+    assert_eq!(TokenSlot::allocate(&slab).token(), first_token);
+}
@@ -0,0 +1,520 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hashbrown::HashMap;
+use rand;
+use clock;
+use config::RedFlareProxyConfig;
+use redflareproxy::{ClientToken, ClientTokenValue};
+use stats::{json_array, json_object, json_string};
+
+// Number of trace batches that can be waiting on the exporter thread. Further batches are dropped.
+const EXPORT_QUEUE_SIZE: usize = 64;
+// How long the exporter waits on the collector before giving up on a batch.
+const EXPORT_TIMEOUT_MS: u64 = 1000;
+
+// OTLP span kinds and status codes.
+const SPAN_KIND_SERVER: usize = 2;
+const SPAN_KIND_CLIENT: usize = 3;
+const STATUS_CODE_ERROR: usize = 2;
+
+/*
+    Timings of a single sampled request, as it passes through the proxy:
+    client receive -> shard selection -> backend write -> backend response -> client write.
+    Phases that were never reached (e.g. the backend timed out) are left as None.
+*/
+#[derive(Clone)]
+pub struct RequestTrace {
+    pub trace_id: (u64, u64),
+    pub span_id: u64,
+    pub backend_span_id: u64,
+    pub pool: String,
+    pub command: &'static str,
+    pub backend: Option<SocketAddr>,
+    // Wall clock time the request was received. The phases are measured with Instants, and converted relative to this.
+    pub start_time: SystemTime,
+    pub received: Instant,
+    // Traces are created once the request has been parsed.
+    pub parsed: Instant,
+    pub shard_selected: Option<Instant>,
+    pub backend_write: Option<Instant>,
+    // When the backend started on the request: when the response ahead of it was read, or when it was written if the
+    // backend had nothing else pending.
+    pub backend_dequeued: Option<Instant>,
+    pub backend_response: Option<Instant>,
+    pub client_write: Option<Instant>,
+    // Error returned to the client instead of a backend response.
+    pub error: Option<&'static str>,
+}
+
+impl RequestTrace {
+    pub fn new(pool: &str, command: &'static str, received: Instant) -> RequestTrace {
+        let now = clock::now();
+        RequestTrace {
+            trace_id: (random_id(), random_id()),
+            span_id: random_id(),
+            backend_span_id: random_id(),
+            pool: pool.to_owned(),
+            command: command,
+            backend: None,
+            start_time: SystemTime::now() - (now - received),
+            received: received,
+            parsed: now,
+            shard_selected: None,
+            backend_write: None,
+            backend_dequeued: None,
+            backend_response: None,
+            client_write: None,
+            error: None,
+        }
+    }
+
+    /*
+        Formats the time spent in each phase, in microseconds. Phases that were never reached are shown as "-". e.g.:
+        pool1 GET 127.0.0.1:6380: total_us=160 parse_us=12 queue_wait_us=20 backend_rtt_us=110 flush_us=18
+    */
+    pub fn format_timing(&self) -> String {
+        let backend = match self.backend {
+            Some(backend) => backend.to_string(),
+            None => "-".to_owned(),
+        };
+        let mut output = format!(
+            "{} {} {}: total_us={} parse_us={} queue_wait_us={} backend_rtt_us={} flush_us={}",
+            self.pool,
+            self.command,
+            backend,
+            micros_between(Some(self.received), self.client_write),
+            micros_between(Some(self.received), Some(self.parsed)),
+            micros_between(self.backend_write, self.backend_dequeued),
+            micros_between(self.backend_dequeued, self.backend_response),
+            micros_between(self.backend_response, self.client_write),
+        );
+        if let Some(error) = self.error {
+            output.push_str(&format!(" error=\"{}\"", error));
+        }
+        return output;
+    }
+
+    // Converts a phase's Instant into nanoseconds since the unix epoch.
+    fn unix_nanos(&self, instant: Instant) -> u64 {
+        let time = self.start_time + (instant - self.received);
+        return match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64,
+            Err(_) => 0,
+        };
+    }
+
+    /*
+        Formats the trace as OTLP spans: a server span covering the request from client receive to client write, and a
+        client span for the round trip to the backend, if the request got that far.
+    */
+    fn to_otlp_spans(&self) -> Vec<String> {
+        let trace_id = format!("{:016x}{:016x}", self.trace_id.0, self.trace_id.1);
+        let end = self.client_write.or(self.backend_response).unwrap_or(self.received);
+        let mut attributes = vec![
+            otlp_attribute("db.system", "redis"),
+            otlp_attribute("db.operation", self.command),
+            otlp_attribute("redflare.pool", &self.pool),
+        ];
+        if let Some(backend) = self.backend {
+            attributes.push(otlp_attribute("net.peer.name", &backend.ip().to_string()));
+            attributes.push(otlp_attribute("net.peer.port", &backend.port().to_string()));
+        }
+        let mut events = Vec::new();
+        for &(name, phase) in [
+            ("shard_selected", self.shard_selected),
+            ("backend_write", self.backend_write),
+            ("backend_response", self.backend_response),
+        ].iter() {
+            if let Some(instant) = phase {
+                events.push(json_object(&[
+                    ("timeUnixNano", json_string(&self.unix_nanos(instant).to_string())),
+                    ("name", json_string(name)),
+                ]));
+            }
+        }
+
+        let mut server_span = vec![
+            ("traceId", json_string(&trace_id)),
+            ("spanId", json_string(&format!("{:016x}", self.span_id))),
+            ("name", json_string(self.command)),
+            ("kind", SPAN_KIND_SERVER.to_string()),
+            ("startTimeUnixNano", json_string(&self.unix_nanos(self.received).to_string())),
+            ("endTimeUnixNano", json_string(&self.unix_nanos(end).to_string())),
+            ("attributes", json_array(&attributes)),
+            ("events", json_array(&events)),
+        ];
+        if let Some(error) = self.error {
+            server_span.push(("status", json_object(&[
+                ("code", STATUS_CODE_ERROR.to_string()),
+                ("message", json_string(error)),
+            ])));
+        }
+        let mut spans = vec![json_object(&server_span)];
+
+        if let Some(backend_write) = self.backend_write {
+            let mut backend_span = vec![
+                ("traceId", json_string(&trace_id)),
+                ("spanId", json_string(&format!("{:016x}", self.backend_span_id))),
+                ("parentSpanId", json_string(&format!("{:016x}", self.span_id))),
+                ("name", json_string(&format!("redis {}", self.command))),
+                ("kind", SPAN_KIND_CLIENT.to_string()),
+                ("startTimeUnixNano", json_string(&self.unix_nanos(backend_write).to_string())),
+                ("endTimeUnixNano", json_string(&self.unix_nanos(self.backend_response.unwrap_or(end)).to_string())),
+                ("attributes", json_array(&attributes)),
+            ];
+            if self.backend_response.is_none() {
+                if let Some(error) = self.error {
+                    backend_span.push(("status", json_object(&[
+                        ("code", STATUS_CODE_ERROR.to_string()),
+                        ("message", json_string(error)),
+                    ])));
+                }
+            }
+            spans.push(json_object(&backend_span));
+        }
+        return spans;
+    }
+}
+
+fn micros_between(start: Option<Instant>, end: Option<Instant>) -> String {
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            let duration = end - start;
+            return (duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64).to_string();
+        }
+        _ => return "-".to_owned(),
+    }
+}
+
+// Span and trace ids must not be all zeroes.
+fn random_id() -> u64 {
+    return std::cmp::max(rand::random::<u64>(), 1);
+}
+
+fn otlp_attribute(key: &str, value: &str) -> String {
+    return json_object(&[
+        ("key", json_string(key)),
+        ("value", json_object(&[("stringValue", json_string(value))])),
+    ]);
+}
+
+/*
+    Formats a batch of traces as an OTLP/HTTP JSON export request.
+*/
+pub fn format_otlp_json(traces: &[RequestTrace], service_name: &str) -> String {
+    let mut spans = Vec::with_capacity(2 * traces.len());
+    for trace in traces {
+        spans.extend(trace.to_otlp_spans());
+    }
+    let resource = json_object(&[
+        ("attributes", json_array(&[otlp_attribute("service.name", service_name)])),
+    ]);
+    let scope_spans = json_object(&[
+        ("scope", json_object(&[("name", json_string("redflare"))])),
+        ("spans", json_array(&spans)),
+    ]);
+    let resource_spans = json_object(&[
+        ("resource", resource),
+        ("scopeSpans", json_array(&[scope_spans])),
+    ]);
+    return json_object(&[("resourceSpans", json_array(&[resource_spans]))]);
+}
+
+/*
+    Decides which requests of a pool are traced. One in every sample_rate requests is sampled. 0 disables tracing.
+*/
+pub struct TraceSampler {
+    sample_rate: usize,
+    seen: usize,
+}
+
+impl TraceSampler {
+    pub fn new(sample_rate: usize) -> TraceSampler {
+        TraceSampler {
+            sample_rate: sample_rate,
+            seen: 0,
+        }
+    }
+
+    pub fn sample(&mut self) -> bool {
+        if self.sample_rate == 0 {
+            return false;
+        }
+        self.seen += 1;
+        return self.seen % self.sample_rate == 0;
+    }
+}
+
+/*
+    Traces of the sampled requests sent to a single backend.
+    Traces are keyed the same way as the backend queue: by client, timeout deadline and multikey request id.
+*/
+pub struct BackendTraces {
+    pending: HashMap<(ClientTokenValue, Instant, usize), RequestTrace>,
+    // When the latest response was read, while there were pending traces. Used to measure how long each traced request
+    // waited behind the requests ahead of it.
+    last_response: Option<Instant>,
+    // Completed traces, waiting to be collected by the proxy.
+    pub finished: Vec<RequestTrace>,
+}
+
+impl BackendTraces {
+    pub fn new() -> BackendTraces {
+        BackendTraces {
+            pending: HashMap::new(),
+            last_response: None,
+            finished: Vec::new(),
+        }
+    }
+
+    // Called once the request has been written to the backend.
+    pub fn start(&mut self, client_token: ClientToken, request_id: (Instant, usize), mut trace: RequestTrace, backend: SocketAddr) {
+        trace.backend_write = Some(clock::now());
+        trace.backend = Some(backend);
+        self.pending.insert((client_token.0, request_id.0, request_id.1), trace);
+    }
+
+    // Called when the backend responds. Returns the request's trace, if it was sampled.
+    pub fn take_response(&mut self, client_token: ClientToken, request_id: (Instant, usize)) -> Option<RequestTrace> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let now = clock::now();
+        let previous_response = self.last_response.take();
+        self.last_response = Some(now);
+        let trace = self.pending.remove(&(client_token.0, request_id.0, request_id.1));
+        return trace.map(|mut trace| {
+            trace.backend_dequeued = std::cmp::max(trace.backend_write, previous_response);
+            trace.backend_response = Some(now);
+            trace
+        });
+    }
+
+    // Called once the response has been written to the client.
+    pub fn finish(&mut self, mut trace: RequestTrace) {
+        trace.client_write = Some(clock::now());
+        self.finished.push(trace);
+    }
+
+    // Called when the client was sent an error instead of a backend response.
+    pub fn fail(&mut self, client_token: ClientToken, request_id: (Instant, usize), error: &'static str) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Some(mut trace) = self.pending.remove(&(client_token.0, request_id.0, request_id.1)) {
+            trace.error = Some(error);
+            self.finish(trace);
+        }
+    }
+}
+
+/*
+    Sends finished traces to an OTLP/HTTP collector (e.g. an OpenTelemetry collector listening on port 4318).
+    Exporting happens on a background thread, so that a slow collector never blocks the event loop. If the thread falls
+    behind, batches are dropped rather than queued without bound.
+*/
+pub struct TraceExporter {
+    sender: SyncSender<Vec<RequestTrace>>,
+}
+
+impl TraceExporter {
+    // Returns None when no otlp_endpoint is configured.
+    pub fn from_config(config: &RedFlareProxyConfig) -> Option<TraceExporter> {
+        let endpoint = match config.otlp_endpoint {
+            Some(ref endpoint) => endpoint.clone(),
+            None => return None,
+        };
+        let service_name = config.otlp_service_name.clone();
+        let (sender, receiver) = sync_channel::<Vec<RequestTrace>>(EXPORT_QUEUE_SIZE);
+        // The thread exits once the exporter, and with it the sender, is dropped.
+        thread::spawn(move || {
+            for batch in receiver.iter() {
+                let body = format_otlp_json(&batch, &service_name);
+                match post_traces(&endpoint, &body) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("Failed to export {} traces to {}. Received error: {}", batch.len(), endpoint, err);
+                    }
+                }
+            }
+        });
+        Some(TraceExporter {
+            sender: sender,
+        })
+    }
+
+    pub fn export(&self, traces: Vec<RequestTrace>) {
+        match self.sender.try_send(traces) {
+            Ok(_) => {}
+            Err(TrySendError::Full(traces)) => {
+                warn!("Trace exporter is falling behind. Dropped {} traces.", traces.len());
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Trace exporter thread has stopped.");
+            }
+        }
+    }
+}
+
+/*
+    POSTs an export request to the collector's /v1/traces endpoint.
+    The endpoint is a host:port address.
+*/
+fn post_traces(endpoint: &str, body: &str) -> Result<(), std::io::Error> {
+    let timeout = Duration::from_millis(EXPORT_TIMEOUT_MS);
+    let addr = match try!(endpoint.to_socket_addrs()).next() {
+        Some(addr) => addr,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "endpoint did not resolve to an address")),
+    };
+    let mut stream = try!(TcpStream::connect_timeout(&addr, timeout));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+    let request = format!(
+        "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        endpoint,
+        body.len(),
+        body
+    );
+    try!(stream.write_all(request.as_bytes()));
+
+    let mut response = String::new();
+    try!(stream.read_to_string(&mut response));
+    let status = response.split(' ').nth(1).unwrap_or("");
+    if !status.starts_with('2') {
+        let status_line = response.lines().next().unwrap_or("").to_owned();
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, status_line));
+    }
+    return Ok(());
+}
+
+#[test]
+fn test_trace_sampler() {
+    let mut sampler = TraceSampler::new(3);
+    let sampled: Vec<bool> = (0..6).map(|_| sampler.sample()).collect();
+    assert_eq!(sampled, vec![false, false, true, false, false, true]);
+
+    let mut disabled = TraceSampler::new(0);
+    assert!(!disabled.sample());
+}
+
+#[test]
+fn test_backend_traces() {
+    use mio::Token;
+    let mut traces = BackendTraces::new();
+    let deadline = Instant::now();
+    let backend: SocketAddr = "127.0.0.1:6380".parse().unwrap();
+    assert!(traces.take_response(Token(20), (deadline, 0)).is_none());
+
+    traces.start(Token(20), (deadline, 0), RequestTrace::new("pool1", "GET", Instant::now()), backend);
+    traces.start(Token(21), (deadline, 0), RequestTrace::new("pool1", "SET", Instant::now()), backend);
+    traces.start(Token(22), (deadline, 0), RequestTrace::new("pool1", "INCR", Instant::now()), backend);
+    let trace = traces.take_response(Token(20), (deadline, 0)).unwrap();
+    assert_eq!(trace.command, "GET");
+    assert_eq!(trace.backend, Some(backend));
+    assert!(trace.backend_write.is_some());
+    assert!(trace.backend_response.is_some());
+    // Nothing was ahead of the first request, so it did not wait in the queue.
+    assert_eq!(trace.backend_dequeued, trace.backend_write);
+    traces.finish(trace);
+
+    // A response to a request that wasn't traced. The next request waited in the queue until it was read.
+    assert!(traces.take_response(Token(20), (deadline, 1)).is_none());
+    let previous_response = traces.last_response;
+    let trace = traces.take_response(Token(22), (deadline, 0)).unwrap();
+    assert_eq!(trace.backend_dequeued, previous_response);
+    traces.finish(trace);
+
+    traces.fail(Token(21), (deadline, 0), "Proxy timed out");
+    assert_eq!(traces.finished.len(), 3);
+    assert!(traces.finished[0].client_write.is_some());
+    assert_eq!(traces.finished[2].error, Some("Proxy timed out"));
+    assert!(traces.finished[2].backend_response.is_none());
+}
+
+#[test]
+fn test_format_timing() {
+    let received = Instant::now();
+    let mut trace = RequestTrace::new("pool1", "GET", received);
+    trace.parsed = received + Duration::from_micros(12);
+    trace.backend = Some("127.0.0.1:6380".parse().unwrap());
+    trace.backend_write = Some(received + Duration::from_micros(30));
+    trace.backend_dequeued = Some(received + Duration::from_micros(50));
+    trace.backend_response = Some(received + Duration::from_micros(160));
+    trace.client_write = Some(received + Duration::from_micros(178));
+    assert_eq!(
+        trace.format_timing(),
+        "pool1 GET 127.0.0.1:6380: total_us=178 parse_us=12 queue_wait_us=20 backend_rtt_us=110 flush_us=18"
+    );
+
+    trace.backend_dequeued = None;
+    trace.backend_response = None;
+    trace.error = Some("Proxy timed out");
+    assert_eq!(
+        trace.format_timing(),
+        "pool1 GET 127.0.0.1:6380: total_us=178 parse_us=12 queue_wait_us=- backend_rtt_us=- flush_us=- error=\"Proxy timed out\""
+    );
+}
+
+#[test]
+fn test_format_otlp_json() {
+    let received = Instant::now();
+    let mut trace = RequestTrace::new("pool1", "GET", received);
+    trace.trace_id = (1, 2);
+    trace.span_id = 3;
+    trace.backend_span_id = 4;
+    trace.start_time = UNIX_EPOCH + Duration::from_secs(10);
+    trace.backend = Some("127.0.0.1:6380".parse().unwrap());
+    trace.shard_selected = Some(received + Duration::from_micros(5));
+    trace.backend_write = Some(received + Duration::from_micros(10));
+    trace.backend_response = Some(received + Duration::from_micros(110));
+    trace.client_write = Some(received + Duration::from_micros(120));
+
+    let json = format_otlp_json(&[trace], "redflare");
+    assert!(json.starts_with("{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\",\"value\":{\"stringValue\":\"redflare\"}}]}"));
+    assert!(json.contains("{\"traceId\":\"00000000000000010000000000000002\",\"spanId\":\"0000000000000003\",\"name\":\"GET\",\"kind\":2,\"startTimeUnixNano\":\"10000000000\",\"endTimeUnixNano\":\"10000120000\""));
+    assert!(json.contains("{\"timeUnixNano\":\"10000005000\",\"name\":\"shard_selected\"}"));
+    assert!(json.contains("\"spanId\":\"0000000000000004\",\"parentSpanId\":\"0000000000000003\",\"name\":\"redis GET\",\"kind\":3,\"startTimeUnixNano\":\"10000010000\",\"endTimeUnixNano\":\"10000110000\""));
+    assert!(json.contains("{\"key\":\"redflare.pool\",\"value\":{\"stringValue\":\"pool1\"}}"));
+    assert!(json.contains("{\"key\":\"net.peer.port\",\"value\":{\"stringValue\":\"6380\"}}"));
+    assert!(!json.contains("status"));
+}
+
+#[test]
+fn test_trace_exporter() {
+    use config::load_config;
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut config = load_config("tests/conf/testconfig1.toml".to_owned(), None).unwrap();
+    config.otlp_endpoint = Some(listener.local_addr().unwrap().to_string());
+    let exporter = TraceExporter::from_config(&config).unwrap();
+
+    let mut trace = RequestTrace::new("pool1", "GET", Instant::now());
+    trace.error = Some("Proxy timed out");
+    exporter.export(vec![trace]);
+
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut request = String::new();
+    let mut buf = [0; 4096];
+    loop {
+        let len = stream.read(&mut buf).unwrap();
+        assert!(len > 0);
+        request.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+        if let Some(header_len) = request.find("\r\n\r\n") {
+            let content_length: usize = request.lines()
+                .find(|line| line.starts_with("Content-Length: "))
+                .map(|line| line["Content-Length: ".len()..].parse().unwrap())
+                .unwrap();
+            if request.len() >= header_len + 4 + content_length {
+                break;
+            }
+        }
+    }
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+    assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+    assert!(request.contains("Content-Type: application/json\r\n"));
+    assert!(request.contains("\"status\":{\"code\":2,\"message\":\"Proxy timed out\"}"));
+}
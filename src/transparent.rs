@@ -0,0 +1,144 @@
+use config::{TransparentConfig, TransparentMode};
+use handoff;
+use registry::{Interest, Registered, Registry};
+use worker;
+
+use libc;
+use mio::Token;
+use mio::tcp::{TcpListener, TcpStream};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+
+/*
+    Transparent proxy mode, for intercepting a service's redis connections without a sidecar or a change to its
+    config. iptables redirects the connections to the transparent listener, and each is handed to the pool that has
+    its original destination in original_destinations. e.g. for clients on the proxy's host, leaving out the
+    proxy's own connections to redis by the user it runs as:
+
+    iptables -t nat -A OUTPUT -p tcp -d 10.0.0.5 --dport 6379 -m owner ! --uid-owner redflare -j REDIRECT --to-ports 15379
+
+    With Redirect, the original destination is read from conntrack with SO_ORIGINAL_DST. With Tproxy, the connection
+    keeps its original destination as its local address, and the listener is bound with IP_TRANSPARENT, which needs
+    CAP_NET_ADMIN. Linux only.
+*/
+
+const SO_ORIGINAL_DST: libc::c_int = 80;
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+const IP_TRANSPARENT: libc::c_int = 19;
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+pub struct TransparentListener {
+    pub socket: Registered<TcpListener>,
+    mode: TransparentMode,
+}
+
+impl TransparentListener {
+    /*
+        Binds the listener, unless one was handed off by a hot restart, and registers it with the token. With
+        reuse_port, every worker binds its own.
+    */
+    pub fn bind(config: &TransparentConfig, reuse_port: bool, registry: &Registry, token: Token) -> Result<TransparentListener, Error> {
+        let addr = config.listen;
+        let bound = if let Some(inherited) = handoff::take_listener(&addr) {
+            inherited
+        } else {
+            let mut options = Vec::new();
+            if reuse_port {
+                options.push((libc::SOL_SOCKET, libc::SO_REUSEADDR));
+                options.push((libc::SOL_SOCKET, libc::SO_REUSEPORT));
+            }
+            if config.mode == TransparentMode::Tproxy {
+                options.push(match addr {
+                    SocketAddr::V4(_) => (libc::IPPROTO_IP, IP_TRANSPARENT),
+                    SocketAddr::V6(_) => (libc::IPPROTO_IPV6, IPV6_TRANSPARENT),
+                });
+            }
+            if options.is_empty() {
+                TcpListener::bind(&addr)
+            } else {
+                worker::bind_with_options(&addr, &options)
+            }
+        };
+        return Ok(TransparentListener {
+            socket: try!(Registered::new(registry, try!(bound), token, Interest::Readable)),
+            mode: config.mode,
+        });
+    }
+
+    /*
+        Accepts the next connection, with its client's address and its original destination. None once there are
+        no more to accept. Connections whose original destination can't be read, e.g. ones made to the listener
+        directly, are closed.
+    */
+    pub fn accept(&self) -> Result<Option<(TcpStream, SocketAddr, SocketAddr)>, Error> {
+        loop {
+            let (stream, addr) = match self.socket.accept() {
+                Ok(accepted) => accepted,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            match original_destination(&stream, self.mode) {
+                Ok(destination) => return Ok(Some((stream, addr, destination))),
+                Err(err) => debug!("Closed transparent connection from {}, whose original destination is unknown: {}", addr, err),
+            }
+        }
+    }
+}
+
+pub fn original_destination(stream: &TcpStream, mode: TransparentMode) -> Result<SocketAddr, Error> {
+    let local = try!(stream.local_addr());
+    if mode == TransparentMode::Tproxy {
+        return Ok(local);
+    }
+    let fd = stream.as_raw_fd();
+    match local {
+        SocketAddr::V4(_) => {
+            let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+            let mut len = mem::size_of_val(&raw) as libc::socklen_t;
+            let result = unsafe { libc::getsockopt(fd, libc::IPPROTO_IP, SO_ORIGINAL_DST, &mut raw as *mut _ as *mut libc::c_void, &mut len) };
+            if result == -1 {
+                return Err(Error::last_os_error());
+            }
+            let ip = Ipv4Addr::from(u32::from_be(raw.sin_addr.s_addr));
+            return Ok(SocketAddr::new(IpAddr::V4(ip), u16::from_be(raw.sin_port)));
+        }
+        SocketAddr::V6(_) => {
+            let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            let mut len = mem::size_of_val(&raw) as libc::socklen_t;
+            let result = unsafe { libc::getsockopt(fd, libc::IPPROTO_IPV6, IP6T_SO_ORIGINAL_DST, &mut raw as *mut _ as *mut libc::c_void, &mut len) };
+            if result == -1 {
+                return Err(Error::last_os_error());
+            }
+            let ip = Ipv6Addr::from(raw.sin6_addr.s6_addr);
+            return Ok(SocketAddr::new(IpAddr::V6(ip), u16::from_be(raw.sin6_port)));
+        }
+    }
+}
+
+#[test]
+fn test_tproxy_destination() {
+    use std::thread;
+    use std::time::Duration;
+
+    let config = TransparentConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        mode: TransparentMode::Redirect,
+    };
+    let listener = TransparentListener::bind(&config, false, &Registry::new().unwrap(), Token(1)).unwrap();
+    let listen_addr = listener.socket.local_addr().unwrap();
+    let _client = TcpStream::connect(&listen_addr).unwrap();
+    let mut accepted = None;
+    for _ in 0..50 {
+        match listener.socket.accept() {
+            Ok((stream, _)) => {
+                accepted = Some(stream);
+                break;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    // A TPROXY connection's original destination is the address it was accepted on.
+    assert_eq!(original_destination(&accepted.unwrap(), TransparentMode::Tproxy).unwrap(), listen_addr);
+}
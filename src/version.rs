@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use humantime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set by build.rs.
+pub const GIT_SHA: &str = env!("REDFLARE_GIT_SHA");
+const BUILD_TIME: &str = env!("REDFLARE_BUILD_TIME");
+
+// Build time in RFC 3339, e.g. 2019-03-01T12:00:00Z.
+pub fn build_time() -> String {
+    let seconds = BUILD_TIME.parse::<u64>().unwrap_or(0);
+    return humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(seconds)).to_string();
+}
+
+/*
+    Response to VERSION, on a single line. e.g.:
+    redflareproxy 0.4.0 (git_sha=1a2b3c4 build_time=2019-03-01T12:00:00Z)
+*/
+pub fn format_version() -> String {
+    return format!("redflareproxy {} (git_sha={} build_time={})", VERSION, GIT_SHA, build_time());
+}
+
+/*
+    Response to INFO, in the redis INFO format so that redis clients can parse it. e.g.:
+    # Server
+    version:0.4.0
+    git_sha:1a2b3c4
+    build_time:2019-03-01T12:00:00Z
+    uptime_in_seconds:3600
+*/
+pub fn format_info(start_time: Instant) -> String {
+    return format!(
+        "# Server\nversion:{}\ngit_sha:{}\nbuild_time:{}\nuptime_in_seconds:{}",
+        VERSION,
+        GIT_SHA,
+        build_time(),
+        start_time.elapsed().as_secs()
+    );
+}
+
+#[test]
+fn test_format_info() {
+    let info = format_info(Instant::now() - Duration::from_secs(90));
+    assert!(info.starts_with(&format!("# Server\nversion:{}\ngit_sha:{}\nbuild_time:", VERSION, GIT_SHA)), "{}", info);
+    assert!(info.ends_with("\nuptime_in_seconds:90"), "{}", info);
+    assert!(format_version().starts_with(&format!("redflareproxy {} (git_sha=", VERSION)));
+}
@@ -0,0 +1,249 @@
+use filter::{encode_command, Filter, FilterAction, FilterContext};
+use log::LogLevel;
+use slowlog::split_args;
+use std::fs::File;
+use std::io::Read;
+use wasmi::core::Trap;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+// Version of the ABI below. Plugins report the version they were built for, and are rejected if it doesn't match.
+const ABI_VERSION: i32 = 1;
+// Instructions a plugin may run per request, roughly. A plugin that runs out fails the request.
+const FUEL_PER_REQUEST: u64 = 10_000_000;
+// Largest linear memory a plugin may grow to.
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+// Verdicts returned by redflare_on_request.
+const ALLOW: i32 = 0;
+const DENY: i32 = 1;
+const REWRITE: i32 = 2;
+
+// Sent instead of a response when the plugin fails on the request.
+const PLUGIN_FAILED: &[u8] = b"-ERROR: Filter plugin failed\r\n";
+// Sent for a denied request, when the plugin gives no message.
+const DENIED: &[u8] = b"-ERROR: Denied by filter\r\n";
+
+// Host state of a plugin instance.
+struct PluginState {
+    limits: StoreLimits,
+    // Set by the plugin's call to set_output.
+    output: Option<Vec<u8>>,
+}
+
+/*
+    A filter loaded from a pool's wasm_filter module. Plugins run sandboxed: they only see the requests given to them,
+    can't make system calls, and are limited in memory and in the instructions they run per request. This lets teams
+    ship request policies into a shared proxy without being able to crash or stall it.
+
+    ABI version 1. A module exports:
+        memory
+        redflare_abi_version() -> i32                  Returns 1.
+        redflare_alloc(len: i32) -> i32                Returns where to write an input of len bytes.
+        redflare_on_request(ptr: i32, len: i32) -> i32 Returns 0 to allow, 1 to deny or 2 to rewrite the request.
+    and may import:
+        redflare.set_output(ptr: i32, len: i32)        Gives the error message of a deny, or the rewritten request.
+
+    Requests, and rewritten requests, are encoded as a little-endian u32 count of arguments, followed by each argument
+    as a little-endian u32 length and its bytes. e.g. GET a is 2, 3, "GET", 1, "a". The first argument is the command
+    name and the second, for most commands, the key. A denied request is answered with -<message>. If the plugin
+    traps or runs out of fuel, the request is answered with an error.
+*/
+pub struct WasmFilter {
+    path: String,
+    store: Store<PluginState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_request: TypedFunc<(i32, i32), i32>,
+}
+
+impl WasmFilter {
+    pub fn load(path: &str) -> Result<WasmFilter, String> {
+        let mut wasm = Vec::new();
+        if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut wasm)) {
+            return Err(format!("Unable to read {}: {}", path, err));
+        }
+        return WasmFilter::from_bytes(path, &wasm).map_err(|err| format!("Unable to load {}: {}", path, err));
+    }
+
+    fn from_bytes(path: &str, wasm: &[u8]) -> Result<WasmFilter, String> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = try!(Module::new(&engine, wasm).map_err(|err| err.to_string()));
+        let mut store = Store::new(&engine, PluginState {
+            limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+            output: None,
+        });
+        store.limiter(|state| &mut state.limits);
+        try!(store.add_fuel(FUEL_PER_REQUEST).map_err(|err| err.to_string()));
+
+        let mut linker = Linker::new(&engine);
+        try!(linker.func_wrap("redflare", "set_output", set_output).map_err(|err| err.to_string()));
+        let instance = try!(linker.instantiate(&mut store, &module).and_then(|instance| instance.start(&mut store)).map_err(|err| err.to_string()));
+        let memory = match instance.get_memory(&store, "memory") {
+            Some(memory) => memory,
+            None => return Err("The module doesn't export its memory".to_owned()),
+        };
+        let version = try!(instance.get_typed_func::<(), i32>(&store, "redflare_abi_version").map_err(|err| err.to_string()));
+        match version.call(&mut store, ()) {
+            Ok(ABI_VERSION) => {}
+            Ok(version) => return Err(format!("The module was built for ABI version {}, not {}", version, ABI_VERSION)),
+            Err(err) => return Err(err.to_string()),
+        }
+        return Ok(WasmFilter {
+            path: path.to_owned(),
+            alloc: try!(instance.get_typed_func(&store, "redflare_alloc").map_err(|err| err.to_string())),
+            on_request: try!(instance.get_typed_func(&store, "redflare_on_request").map_err(|err| err.to_string())),
+            store: store,
+            memory: memory,
+        });
+    }
+
+    fn filter_request(&mut self, request: &[u8]) -> Result<FilterAction, String> {
+        let input = encode_args(&split_args(request));
+        // Tops the fuel back up, so that each request gets the same budget.
+        let remaining = try!(self.store.consume_fuel(0).map_err(|err| err.to_string()));
+        try!(self.store.add_fuel(FUEL_PER_REQUEST.saturating_sub(remaining)).map_err(|err| err.to_string()));
+        self.store.data_mut().output = None;
+
+        let ptr = try!(self.alloc.call(&mut self.store, input.len() as i32).map_err(|err| err.to_string()));
+        try!(self.memory.write(&mut self.store, ptr as u32 as usize, &input).map_err(|err| err.to_string()));
+        let verdict = try!(self.on_request.call(&mut self.store, (ptr, input.len() as i32)).map_err(|err| err.to_string()));
+        let output = self.store.data_mut().output.take();
+        return match (verdict, output) {
+            (ALLOW, _) => Ok(FilterAction::Continue),
+            (DENY, Some(message)) => {
+                let mut reply = b"-".to_vec();
+                reply.extend_from_slice(&message);
+                reply.extend_from_slice(b"\r\n");
+                Ok(FilterAction::Reply(reply))
+            }
+            (DENY, None) => Ok(FilterAction::Reply(DENIED.to_vec())),
+            (REWRITE, Some(output)) => match decode_args(&output) {
+                Some(ref args) if !args.is_empty() => Ok(FilterAction::Rewrite(encode_command(args.iter().cloned()))),
+                _ => Err("The rewritten request is invalid".to_owned()),
+            },
+            (REWRITE, None) => Err("The request was rewritten without calling set_output".to_owned()),
+            (verdict, _) => Err(format!("Unknown verdict {}", verdict)),
+        };
+    }
+}
+
+impl Filter for WasmFilter {
+    fn on_request(&mut self, context: &mut FilterContext, request: &[u8]) -> FilterAction {
+        return match self.filter_request(request) {
+            Ok(action) => action,
+            Err(err) => {
+                log_event!(LogLevel::Warn, "wasm_filter_failed", { pool: context.pool, plugin: self.path }, "{} failed for pool {}: {}", self.path, context.pool, err);
+                FilterAction::Reply(PLUGIN_FAILED.to_vec())
+            }
+        };
+    }
+}
+
+// redflare.set_output, imported by plugins.
+fn set_output(mut caller: Caller<PluginState>, ptr: i32, len: i32) -> Result<(), Trap> {
+    let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => memory,
+        None => return Err(Trap::new("The module doesn't export its memory")),
+    };
+    let mut output = vec![0; len as u32 as usize];
+    if memory.read(&caller, ptr as u32 as usize, &mut output).is_err() {
+        return Err(Trap::new("set_output is out of bounds"));
+    }
+    caller.data_mut().output = Some(output);
+    return Ok(());
+}
+
+fn encode_args(args: &[&[u8]]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    for arg in args {
+        encoded.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+        encoded.extend_from_slice(arg);
+    }
+    return encoded;
+}
+
+// Returns None if the arguments aren't encoded as described for WasmFilter.
+fn decode_args(encoded: &[u8]) -> Option<Vec<&[u8]>> {
+    let read_u32 = |index: usize| -> Option<usize> {
+        let bytes = encoded.get(index..index + 4)?;
+        return Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+    };
+    let count = read_u32(0)?;
+    let mut index = 4;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(index)?;
+        index += 4;
+        args.push(encoded.get(index..index + len)?);
+        index += len;
+    }
+    if index != encoded.len() {
+        return None;
+    }
+    return Some(args);
+}
+
+#[test]
+fn test_wasm_filter() {
+    // Encodes a section of a module, with its id and size.
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        assert!(contents.len() < 128);
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        return section;
+    }
+    // GET b, as the plugin's rewrite.
+    let rewrite = encode_args(&[b"GET", b"b"]);
+    // Denies FLUSHALL, rewrites GET to GET b, loops forever on HANG, and allows anything else, by first letter.
+    let on_request: Vec<u8> = [
+        &[0x00][..],                                        // no locals
+        &[0x20, 0x00, 0x2d, 0x00, 0x08, 0x41, 0xc6, 0x00, 0x46, 0x04, 0x40], // if input[8] == 'F'
+        &[0x41, 0x00, 0x41, 0x06, 0x10, 0x00, 0x41, 0x01, 0x0f, 0x0b],       //   set_output(0, 6); return 1
+        &[0x20, 0x00, 0x2d, 0x00, 0x08, 0x41, 0xc7, 0x00, 0x46, 0x04, 0x40], // if input[8] == 'G'
+        &[0x41, 0x10, 0x41, rewrite.len() as u8, 0x10, 0x00, 0x41, 0x02, 0x0f, 0x0b], // set_output(16, len); return 2
+        &[0x20, 0x00, 0x2d, 0x00, 0x08, 0x41, 0xc8, 0x00, 0x46, 0x04, 0x40], // if input[8] == 'H'
+        &[0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b],                             //   loop forever
+        &[0x41, 0x00, 0x0b],                                                // return 0
+    ].concat();
+    let mut code = vec![0x03];
+    for body in [&[0x00, 0x41, 0x01, 0x0b][..], &[0x00, 0x41, 0x80, 0x08, 0x0b][..], &on_request[..]].iter() {
+        code.push(body.len() as u8);
+        code.extend_from_slice(body);
+    }
+    let mut data = vec![0x02, 0x00, 0x41, 0x00, 0x0b, 0x06];
+    data.extend_from_slice(b"Denied");
+    data.extend_from_slice(&[0x00, 0x41, 0x10, 0x0b, rewrite.len() as u8]);
+    data.extend_from_slice(&rewrite);
+    let wasm = [
+        &b"\0asm\x01\0\0\0"[..],
+        // Types: () -> i32, (i32) -> i32, (i32, i32) -> i32, (i32, i32) -> ()
+        &section(1, &[0x04, 0x60, 0x00, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00]),
+        &section(2, &[&[0x01, 0x08][..], b"redflare", &[0x0a], b"set_output", &[0x00, 0x03]].concat()),
+        &section(3, &[0x03, 0x00, 0x01, 0x02]),
+        &section(5, &[0x01, 0x00, 0x01]),
+        &section(7, &[
+            &[0x04, 0x06][..], b"memory", &[0x02, 0x00],
+            &[0x14], b"redflare_abi_version", &[0x00, 0x01],
+            &[0x0e], b"redflare_alloc", &[0x00, 0x02],
+            &[0x13], b"redflare_on_request", &[0x00, 0x03],
+        ].concat()),
+        &section(10, &code),
+        &section(11, &data),
+    ].concat();
+
+    let mut filter = WasmFilter::from_bytes("test.wasm", &wasm).unwrap();
+    let mut context = FilterContext::new("pool1", 1);
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$8\r\nFLUSHALL\r\n"), FilterAction::Reply(b"-Denied\r\n".to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"), FilterAction::Rewrite(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n".to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n"), FilterAction::Continue);
+    // Runs out of fuel, and then gets a fresh budget for the next request.
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$4\r\nHANG\r\n"), FilterAction::Reply(PLUGIN_FAILED.to_vec()));
+    assert_eq!(filter.on_request(&mut context, b"*1\r\n$4\r\nPING\r\n"), FilterAction::Continue);
+
+    assert!(WasmFilter::from_bytes("test.wasm", b"\0asm").is_err());
+    assert_eq!(decode_args(&rewrite), Some(vec![&b"GET"[..], b"b"]));
+    assert_eq!(decode_args(&rewrite[..rewrite.len() - 1]), None);
+}
@@ -0,0 +1,188 @@
+use config::RedFlareProxyConfig;
+use log::LogLevel;
+use mio::Token;
+use tokens;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Shortest time the watchdog sleeps between checks, so that a small threshold doesn't keep it busy.
+const MIN_CHECK_INTERVAL_MS: u64 = 10;
+
+// What the event loop is doing, for the watchdog's report of a stall.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    // Waiting for events. Not a stall, however long it takes.
+    Polling = 0,
+    Events = 1,
+    Timers = 2,
+    Clients = 3,
+    // Stats, traces and memory accounting at the end of an iteration.
+    Housekeeping = 4,
+}
+
+impl Phase {
+    fn from_usize(phase: usize) -> Phase {
+        match phase {
+            1 => Phase::Events,
+            2 => Phase::Timers,
+            3 => Phase::Clients,
+            4 => Phase::Housekeeping,
+            _ => Phase::Polling,
+        }
+    }
+}
+
+// Progress of an event loop, written by the loop and read by its watchdog thread.
+struct Heartbeat {
+    phase: AtomicUsize,
+    // Milliseconds from the watchdog's start to the start of the current iteration, plus 1. 0 while polling.
+    busy_since_ms: AtomicUsize,
+    // Token of the event being handled.
+    token: AtomicUsize,
+    iterations: AtomicUsize,
+    // Stalls detected, not yet added to the worker's stats.
+    stalls: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+/*
+    Detects event loop iterations that take longer than the watchdog_threshold, e.g. because of a blocking DNS resolve
+    or a slow disk write, which would otherwise only show up as latency on every client of the worker. A thread checks
+    the loop's heartbeat and logs what the loop is doing once per stall. The loop's stack can't be captured from
+    another thread, so the report has the phase and the token being handled instead.
+*/
+pub struct Watchdog {
+    heartbeat: Arc<Heartbeat>,
+    start: Instant,
+}
+
+impl Watchdog {
+    // Returns None when the watchdog_threshold is 0.
+    pub fn from_config(config: &RedFlareProxyConfig, worker_id: usize) -> Option<Watchdog> {
+        if config.watchdog_threshold == 0 {
+            return None;
+        }
+        match Watchdog::new(Duration::from_millis(config.watchdog_threshold as u64), worker_id) {
+            Ok(watchdog) => Some(watchdog),
+            Err(err) => {
+                error!("Failed to start the watchdog of worker {}: {}", worker_id, err);
+                None
+            }
+        }
+    }
+
+    pub fn new(threshold: Duration, worker_id: usize) -> Result<Watchdog, std::io::Error> {
+        let watchdog = Watchdog {
+            heartbeat: Arc::new(Heartbeat {
+                phase: AtomicUsize::new(Phase::Polling as usize),
+                busy_since_ms: AtomicUsize::new(0),
+                token: AtomicUsize::new(0),
+                iterations: AtomicUsize::new(0),
+                stalls: AtomicUsize::new(0),
+                stopped: AtomicBool::new(false),
+            }),
+            start: Instant::now(),
+        };
+        let check_interval = std::cmp::max(threshold / 4, Duration::from_millis(MIN_CHECK_INTERVAL_MS));
+        let heartbeat = watchdog.heartbeat.clone();
+        let start = watchdog.start;
+        try!(thread::Builder::new().name(format!("watchdog-{}", worker_id)).spawn(move || {
+            // Start of the iteration last reported, so that each stall is reported once.
+            let mut reported_ms = 0;
+            while !heartbeat.stopped.load(Ordering::SeqCst) {
+                thread::sleep(check_interval);
+                let busy_since_ms = heartbeat.busy_since_ms.load(Ordering::SeqCst);
+                if busy_since_ms == 0 || busy_since_ms == reported_ms {
+                    continue;
+                }
+                let stalled_ms = millis_since(start).saturating_sub(busy_since_ms - 1);
+                if stalled_ms < duration_millis(threshold) {
+                    continue;
+                }
+                reported_ms = busy_since_ms;
+                heartbeat.stalls.fetch_add(1, Ordering::SeqCst);
+                let phase = Phase::from_usize(heartbeat.phase.load(Ordering::SeqCst));
+                let token = Token(heartbeat.token.load(Ordering::SeqCst));
+                let iteration = heartbeat.iterations.load(Ordering::SeqCst);
+                log_event!(
+                    LogLevel::Error,
+                    "event_loop_stall",
+                    {
+                        worker: worker_id,
+                        stalled_ms: stalled_ms,
+                        phase: format!("{:?}", phase),
+                        token: token.0,
+                        iteration: iteration
+                    },
+                    "Event loop of worker {} has been stuck for {}ms. phase={:?} token={:?} token_kind={:?} iteration={}",
+                    worker_id,
+                    stalled_ms,
+                    phase,
+                    token,
+                    tokens::kind(token),
+                    iteration
+                );
+            }
+        }));
+        return Ok(watchdog);
+    }
+
+    // Called when poll returns, to start timing the iteration.
+    pub fn begin_iteration(&self) {
+        self.heartbeat.busy_since_ms.store(millis_since(self.start) + 1, Ordering::SeqCst);
+        self.heartbeat.iterations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn enter(&self, phase: Phase) {
+        self.heartbeat.phase.store(phase as usize, Ordering::SeqCst);
+    }
+
+    pub fn handling(&self, token: Token) {
+        self.heartbeat.token.store(token.0, Ordering::SeqCst);
+    }
+
+    // Called before polling again. Time spent waiting for events isn't a stall.
+    pub fn end_iteration(&self) {
+        self.heartbeat.phase.store(Phase::Polling as usize, Ordering::SeqCst);
+        self.heartbeat.busy_since_ms.store(0, Ordering::SeqCst);
+    }
+
+    // Returns the stalls detected since the last call.
+    pub fn take_stalls(&self) -> usize {
+        return self.heartbeat.stalls.swap(0, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.heartbeat.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+fn millis_since(start: Instant) -> usize {
+    return duration_millis(start.elapsed());
+}
+
+fn duration_millis(duration: Duration) -> usize {
+    return duration.as_secs() as usize * 1000 + duration.subsec_millis() as usize;
+}
+
+#[test]
+fn test_watchdog_detects_stall() {
+    let watchdog = Watchdog::new(Duration::from_millis(20), 0).unwrap();
+
+    // Waiting for events isn't a stall.
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(watchdog.take_stalls(), 0);
+
+    watchdog.begin_iteration();
+    watchdog.enter(Phase::Events);
+    watchdog.handling(Token(7));
+    thread::sleep(Duration::from_millis(100));
+    watchdog.end_iteration();
+    // Reported once, however long it lasts.
+    assert_eq!(watchdog.take_stalls(), 1);
+    assert_eq!(watchdog.take_stalls(), 0);
+}
@@ -0,0 +1,184 @@
+use config::RedFlareProxyConfig;
+use platform;
+use redflareproxy::{ProxyError, RedFlareProxy};
+use stats::SharedShards;
+
+use libc;
+use mio::tcp::TcpListener;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::thread::{self, JoinHandle};
+
+/*
+    Identifies one of the event loop threads, when the proxy runs with more than one worker. Each worker has its own
+    Poll, clients and backend connections. Only worker 0 binds the admin port, and it reports stats summed across the
+    shards of every worker.
+*/
+pub struct WorkerContext {
+    pub id: usize,
+    pub shards: SharedShards,
+}
+
+/*
+    Binds a listener with SO_REUSEPORT, so that every worker can bind the same pool address. The kernel then
+    distributes new connections across the workers' listeners.
+*/
+pub fn bind_reuse_port(addr: &SocketAddr) -> Result<TcpListener, std::io::Error> {
+    return bind_with_options(addr, &[(libc::SOL_SOCKET, libc::SO_REUSEADDR), (libc::SOL_SOCKET, libc::SO_REUSEPORT)]);
+}
+
+// Binds a listener with the given socket options, as (level, option), enabled before it's bound.
+pub fn bind_with_options(addr: &SocketAddr, options: &[(libc::c_int, libc::c_int)]) -> Result<TcpListener, std::io::Error> {
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = try!(platform::tcp_socket(family));
+    // Owns the socket from here, so that it is closed on errors.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    for &(level, option) in options {
+        try!(enable_socket_option(fd, level, option));
+    }
+    let result = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let mut raw: libc::sockaddr_in = unsafe { mem::zeroed() };
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = addr.port().to_be();
+            raw.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            unsafe { libc::bind(fd, &raw as *const _ as *const libc::sockaddr, mem::size_of_val(&raw) as libc::socklen_t) }
+        }
+        SocketAddr::V6(ref addr) => {
+            let mut raw: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = addr.port().to_be();
+            raw.sin6_addr.s6_addr = addr.ip().octets();
+            raw.sin6_flowinfo = addr.flowinfo();
+            raw.sin6_scope_id = addr.scope_id();
+            unsafe { libc::bind(fd, &raw as *const _ as *const libc::sockaddr, mem::size_of_val(&raw) as libc::socklen_t) }
+        }
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, 1024) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return TcpListener::from_std(listener);
+}
+
+fn enable_socket_option(fd: RawFd, level: libc::c_int, option: libc::c_int) -> Result<(), std::io::Error> {
+    let enabled: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(fd, level, option, &enabled as *const _ as *const libc::c_void, mem::size_of_val(&enabled) as libc::socklen_t)
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+/*
+    Pins the calling thread to the CPU of the given worker, from worker_cpus. Linux places memory on the NUMA node of the
+    CPU that first touches it, so a worker that is pinned before allocating its buffers keeps them local.
+*/
+pub fn pin_to_cpu(config: &RedFlareProxyConfig, id: usize) -> Result<(), ProxyError> {
+    if config.worker_cpus.is_empty() {
+        return Ok(());
+    }
+    let cpu = config.worker_cpus[id % config.worker_cpus.len()];
+    if let Err(err) = set_affinity(cpu) {
+        return Err(ProxyError::CpuAffinityFailure(cpu, err));
+    }
+    info!("Pinned worker {} to CPU {}", id, cpu);
+    return Ok(());
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpu: usize) -> Result<(), std::io::Error> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "CPU is beyond the supported CPU set size"));
+    }
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        // 0 is the calling thread.
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_cpu: usize) -> Result<(), std::io::Error> {
+    return Err(std::io::Error::new(std::io::ErrorKind::Other, "CPU affinity is only supported on Linux"));
+}
+
+/*
+    Starts workers 1 to config.workers - 1, each on its own thread. Worker 0 is run by the caller. A worker that fails
+    to start exits the process, since the pool ports would otherwise be served by fewer workers than configured. The
+    workers stop on shutdown, once their in-flight requests are drained, and should be joined before the process exits.
+*/
+pub fn spawn_workers(config: &RedFlareProxyConfig, config_path: &str, profile: Option<String>, shards: &SharedShards) -> Vec<JoinHandle<()>> {
+    let mut handles = Vec::with_capacity(config.workers);
+    for id in 1..config.workers {
+        let config = config.clone();
+        let config_path = config_path.to_owned();
+        let profile = profile.clone();
+        let context = WorkerContext {
+            id: id,
+            shards: shards.clone(),
+        };
+        let spawned = thread::Builder::new().name(format!("worker-{}", id)).spawn(move || {
+            let mut redflareproxy = match RedFlareProxy::new(config, config_path, profile, None, Some(context)) {
+                Ok(redflareproxy) => redflareproxy,
+                Err(err) => {
+                    error!("Failed to start worker {}: {}", id, err);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(err) = redflareproxy.run() {
+                error!("Worker {} stopped: {}", id, err);
+                std::process::exit(1);
+            }
+        });
+        match spawned {
+            Ok(handle) => handles.push(handle),
+            Err(err) => {
+                error!("Failed to spawn worker {}: {}", id, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    return handles;
+}
+
+#[test]
+fn test_bind_reuse_port() {
+    let first = bind_reuse_port(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = first.local_addr().unwrap();
+    // A second worker can bind the same address.
+    let second = bind_reuse_port(&addr).unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_pin_to_cpu() {
+    // On a thread of its own, so that the other tests keep every CPU.
+    thread::spawn(|| {
+        let mut allowed: libc::cpu_set_t = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut allowed) }, 0);
+        let cpu = (0..libc::CPU_SETSIZE as usize).find(|cpu| unsafe { libc::CPU_ISSET(*cpu, &allowed) }).unwrap();
+
+        set_affinity(cpu).unwrap();
+        let mut pinned: libc::cpu_set_t = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut pinned) }, 0);
+        let pinned_cpus: Vec<usize> = (0..libc::CPU_SETSIZE as usize).filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &pinned) }).collect();
+        assert_eq!(pinned_cpus, vec![cpu]);
+
+        assert!(set_affinity(libc::CPU_SETSIZE as usize).is_err());
+    }).join().unwrap();
+}
@@ -1,4 +1,5 @@
 #!/usr/bin/env python
+import os
 import redis
 from test_util import TestUtil
 
@@ -9,4 +10,81 @@ class AdminTests(TestUtil):
 
         r = redis.Redis(port=1530, decode_responses=True)
         response = r.execute_command("INFO")
-        self.assertEqual(response.get('__raw__'), ["DERP"]);
\ No newline at end of file
+        self.assertEqual(sorted(response.keys()), ["build_time", "git_sha", "uptime_in_seconds", "version"])
+        self.assertEqual(response["version"], "0.4.0")
+        self.assertTrue(response["uptime_in_seconds"] >= 0)
+        self.assertTrue(r.execute_command("VERSION").startswith("redflareproxy 0.4.0 (git_sha="))
+
+    def test_log_levels(self):
+        self.start_proxy("tests/conf/timeout1.toml")
+
+        r = redis.Redis(port=1530, decode_responses=True)
+        response = r.execute_command("LOGLEVEL")
+        self.assertTrue("cluster: " in response)
+
+        self.assertEqual(r.execute_command("LOGLEVEL", "cluster", "DEBUG"), "OK")
+        self.assertTrue("cluster: DEBUG" in r.execute_command("LOGLEVEL"))
+        self.assertTrue("cluster = \"DEBUG\"" in r.execute_command("CONFIGINFO"))
+
+        self.assertEqual(r.execute_command("LOGLEVEL", "cluster", "DEFAULT"), "OK")
+        self.assertFalse("cluster: DEBUG" in r.execute_command("LOGLEVEL"))
+
+        self.assertTrue(r.execute_command("LOGLEVEL", "pool", "DEBUG").startswith("Invalid log level"))
+        self.assertTrue(r.execute_command("LOGLEVEL", "cluster", "LOUD").startswith("Invalid log level"))
+
+    def test_audit_log(self):
+        audit_path = "/tmp/redflare-audit.log"
+        if os.path.exists(audit_path):
+            os.remove(audit_path)
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/audit1.toml")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("session:1", "value")
+        r.delete("user:1")
+        r.delete("session:1")
+        try:
+            r.flushall()
+        except redis.exceptions.ResponseError:
+            pass
+
+        with open(audit_path) as f:
+            lines = f.read().splitlines()
+        self.assertEqual(len(lines), 2)
+        self.assertRegexpMatches(lines[0], r'^time=\S+ pool=pool1 client=127.0.0.1:\d+ command="DEL session:1"$')
+        self.assertRegexpMatches(lines[1], r'^time=\S+ pool=pool1 client=127.0.0.1:\d+ command="FLUSHALL"$')
+
+    def test_capture(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertEqual(admin.execute_command("CAPTURE"), "No captures running.")
+        self.assertEqual(admin.execute_command("CAPTURE", "START", "pool2"), "Unknown pool: pool2")
+        path = admin.execute_command("CAPTURE", "START", "pool1")
+        self.assertTrue(path.startswith("/tmp/pool1-") and path.endswith(".capture"))
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("key1", "value1")
+        r.get("key1")
+
+        status = admin.execute_command("CAPTURE", "STOP", "pool1")
+        self.assertEqual(status, "path=%s active=false frames=4 bytes=%d" % (path, os.path.getsize(path)))
+        self.assertEqual(admin.execute_command("CAPTURE", "STOP", "pool1"), "No capture is running for pool1")
+        with open(path) as f:
+            contents = f.read()
+        os.remove(path)
+        self.assertTrue(contents.startswith("# redflare capture pool=pool1 "))
+        self.assertTrue("\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\r\n" in contents)
+        self.assertTrue("\r\n$6\r\nvalue1\r\n\r\n" in contents)
+
+    def test_backend_info(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+
+        admin = redis.Redis(port=1530, socket_timeout=5, decode_responses=True)
+        self.assertEqual(admin.execute_command("BACKENDINFO", "pool2"), "Unknown pool: pool2")
+        lines = admin.execute_command("BACKENDINFO", "pool1").split("\n")
+        self.assertEqual(len(lines), 2)
+        self.assertEqual(lines[0].split(), ["host", "role", "used_memory", "connected_clients", "repl_lag"])
+        self.assertEqual(lines[1].split()[:2], ["127.0.0.1:6380", "master"])
@@ -1,10 +1,48 @@
 #!/usr/bin/env python
 import redis
 import socket
+import time
 from test_util import TestUtil
 
 class CommandTests(TestUtil):
 
+    def test_pipelined_requests(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.populate_redis_key(1531, "key1", "value1")
+
+        # Pipelined requests are sent to the backend together, and their responses are written back together.
+        s1 = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        s1.settimeout(1)
+        s1.connect(("0.0.0.0", 1531))
+        s1.send(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$2\r\nv2\r\n*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n")
+        resp = s1.recv(1024)
+        self.assertEquals(resp, "$6\r\nvalue1\r\n+OK\r\n$2\r\nv2\r\n")
+        s1.close()
+
+    def test_large_pipeline(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.populate_redis_key(1531, "key1", "value1")
+
+        # A pipeline much larger than the client's read buffer is answered in full, with requests split across reads,
+        # while another client sharing the backend is still served in between.
+        s1 = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        s1.settimeout(5)
+        s1.connect(("0.0.0.0", 1531))
+        s2 = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        s2.settimeout(1)
+        s2.connect(("0.0.0.0", 1531))
+        s1.sendall(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n" * 20000)
+        s2.send(b"*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n")
+        self.assertEquals(s2.recv(1024), "$6\r\nvalue1\r\n")
+        resp = ""
+        while len(resp) < len("$6\r\nvalue1\r\n") * 20000:
+            resp += s1.recv(65536)
+        self.assertEquals(resp, "$6\r\nvalue1\r\n" * 20000)
+        s1.close()
+        s2.close()
+
     def test_multikey_commands(self):
         self.start_redis_server(6381)
         self.start_redis_server(6382)
@@ -162,6 +200,42 @@ class CommandTests(TestUtil):
         #self.assertEquals(r.execute_command("SCRIPT FLUSH key10"), 1)
         #self.assertEquals(r.execute_command("SCRIPT KILL key10"), 1)
 
+    def test_function_commands(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_redis_server(6383)
+        self.start_redis_server(6384)
+        self.start_proxy("tests/conf/multishard1.toml")
+
+        r = redis.Redis(port=1533, socket_timeout=1)
+
+        # Libraries are loaded on every backend, so functions can be called on keys of any of them.
+        library = "#!lua name=mylib\nredis.register_function('myget', function(keys, args) return redis.call('GET', keys[1]) end)"
+        self.assertEquals(r.execute_command("FUNCTION", "LOAD", library), 'mylib')
+        for index in range(10):
+            r.set("key" + str(index), "value" + str(index))
+            self.assertEquals(r.execute_command("FCALL_RO", "myget", 1, "key" + str(index)), "value" + str(index))
+
+        # Each library is listed once, though every backend has it.
+        libraries = r.execute_command("FUNCTION", "LIST")
+        self.assertEquals(len(libraries), 1)
+        self.assertEquals(libraries[0][1], 'mylib')
+
+        # An error of any backend is returned.
+        try:
+            r.execute_command("FUNCTION", "LOAD", library)
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "ERR Library 'mylib' already exists")
+
+        self.assertEquals(r.execute_command("FUNCTION", "DELETE", "mylib"), 'OK')
+        self.assertEquals(r.execute_command("FUNCTION", "LIST"), [])
+        try:
+            r.execute_command("FCALL", "myget", 1, "key1")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "ERR Function not found")
+
     def test_redis_commands(self):
         self.start_redis_server(6381)
         self.start_redis_server(6382)
@@ -241,8 +315,10 @@ class CommandTests(TestUtil):
 
         # Test lists commands
         r.lpush('key6', 'value1', 'value2');
-        self.assertEquals(r.execute_command("BLPOP key6 1"), ['key6', 'value2'])
-        self.assertEquals(r.execute_command("BRPOP key6 1"), ['key6', 'value1'])
+        self.assert_blocking_rejected(r, "BLPOP key6 1")
+        self.assert_blocking_rejected(r, "BRPOP key6 1")
+        self.assertEquals(r.execute_command("LPOP key6"), 'value2')
+        self.assertEquals(r.execute_command("RPOP key6"), 'value1')
         #self.assertEquals(r.execute_command("BRPOPLPUSH key6"), 1)
         self.assertEquals(r.execute_command("LINDEX key6 0"), None)
         self.assertEquals(r.execute_command("LINSERT key6 BEFORE a b"), 0)
@@ -278,8 +354,9 @@ class CommandTests(TestUtil):
 
         # Test sorted set commands
         r.zadd('key8', value1=1, value2=2)
-        self.assertEquals(r.execute_command("BZPOPMAX key8 1"), ['key8', 'value2', '2'])
-        self.assertEquals(r.execute_command("BZPOPMIN key8 1"), ['key8', 'value1', '1'])
+        self.assert_blocking_rejected(r, "BZPOPMAX key8 1")
+        self.assert_blocking_rejected(r, "BZPOPMIN key8 1")
+        self.assertEquals(r.execute_command("ZREM key8 value1 value2"), 2)
         self.assertEquals(r.execute_command("ZADD key8 3 value3"), 1)
         self.assertEquals(r.execute_command("ZCARD key8"), 1)
         self.assertEquals(r.execute_command("ZCOUNT key8 0 1"), 0)
@@ -327,7 +404,31 @@ class CommandTests(TestUtil):
         # No support for server commands
 
         # Test streams commands
-        # Not implemented yet.
+        self.assertEquals(r.execute_command("XADD key20 1-1 field1 value1"), '1-1')
+        self.assertEquals(r.execute_command("XADD key20 2-1 field2 value2"), '2-1')
+        self.assertEquals(r.execute_command("XLEN key20"), 2)
+        self.assertEquals(r.execute_command("XRANGE key20 - + COUNT 1"), [['1-1', ['field1', 'value1']]])
+        self.assertEquals(r.execute_command("XREVRANGE key20 + - COUNT 1"), [['2-1', ['field2', 'value2']]])
+        self.assertEquals(r.execute_command("XREAD COUNT 1 STREAMS key20 1-1"), [['key20', [['2-1', ['field2', 'value2']]]]])
+        self.assertEquals(r.execute_command("XGROUP CREATE key20 group1 0"), 'OK')
+        self.assertEquals(r.execute_command("XREADGROUP GROUP group1 consumer1 COUNT 1 STREAMS key20 >"), [['key20', [['1-1', ['field1', 'value1']]]]])
+        self.assertEquals(r.execute_command("XPENDING key20 group1"), [1, '1-1', '1-1', [['consumer1', '1']]])
+        self.assertEquals(r.execute_command("XACK key20 group1 1-1"), 1)
+        self.assertEquals(r.execute_command("XDEL key20 1-1"), 1)
+        self.assertEquals(r.execute_command("XTRIM key20 MAXLEN 0"), 1)
+        # Blocking reads are rejected, rather than holding up the backend connection past the pool's timeout, after
+        # which their response would answer the next request.
+        self.assert_blocking_rejected(r, "XREAD BLOCK 1000 STREAMS key20 $")
+        self.assert_blocking_rejected(r, "XREADGROUP GROUP group1 consumer1 BLOCK 1000 STREAMS key20 >")
+        time.sleep(0.1)
+        self.assertEquals(r.execute_command("XADD key20 3-1 field3 value3"), '3-1')
+        self.assertEquals(r.execute_command("XREAD STREAMS key20 0"), [['key20', [['3-1', ['field3', 'value3']]]]])
+        # Streams may be on different backends, so only one is read at a time.
+        try:
+            r.execute_command("XREAD STREAMS key20 key21 0 0")
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "ERROR: XREAD and XREADGROUP must read 1 stream")
 
         # No support for transactions commands
 
@@ -338,4 +439,10 @@ class CommandTests(TestUtil):
         # ping
         # quit
         # select
-        # swapdb
\ No newline at end of file
+        # swapdb
+    def assert_blocking_rejected(self, r, command):
+        try:
+            r.execute_command(command)
+            self.fail("Expected response error did not occur")
+        except redis.ResponseError, e:
+            self.assertEquals(str(e), "ERROR: Blocking commands are not supported")
@@ -1,6 +1,10 @@
 #!/usr/bin/env python
+import json
 import redis
 import time
+import os
+import socket
+import shutil
 from test_util import TestUtil
 
 class ConfigTests(TestUtil):
@@ -31,6 +35,54 @@ class ConfigTests(TestUtil):
         self.assertEquals(proxy_proc.poll(), 1)
 
 
+    def test_daemonize(self):
+        self.start_redis_server(6380)
+        pid_file = "tests/tmp/redflareproxy.pid"
+        proxy_proc = self.start_proxy("tests/conf/testconfig1.toml", extra_args=["--daemonize", "--pid_file={}".format(pid_file)])
+
+        # The foreground process exits once the proxy has forked into the background.
+        proxy_proc.wait()
+        self.assertEquals(proxy_proc.returncode, 0)
+        TestUtil.verify_redis_connection(1531)
+
+        with open(pid_file) as f:
+            pid = int(f.read())
+        os.kill(pid, 0)
+
+        # The pid file is removed on shutdown.
+        r = redis.Redis(port=1530)
+        r.execute_command("SHUTDOWN")
+        time.sleep(0.2)
+        self.assertFalse(os.path.exists(pid_file))
+
+    def test_json_log_format(self):
+        self.start_redis_server(6380)
+        log_file = "tests/tmp/redflareproxy.json.log"
+        self.start_proxy("tests/conf/testconfig1.toml", extra_args=["--log_format", "JSON", "--log_file", log_file])
+        TestUtil.verify_redis_connection(1531)
+
+        with open(log_file) as f:
+            records = [json.loads(line) for line in f]
+        events = [record["mdc"] for record in records if "event" in record["mdc"]]
+        connected = [event for event in events if event["event"] == "client_connected"]
+        self.assertTrue(len(connected) > 0)
+        self.assertEqual(connected[0]["pool"], "pool1")
+        responses = [event for event in events if event["event"] == "backend_response"]
+        self.assertEqual(responses[0]["backend"], "127.0.0.1:6380")
+        self.assertTrue(int(responses[0]["latency_us"]) > 0)
+
+    def test_event_loop_options(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/eventloop1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Spinning, and handling one event per iteration, still serves every client.
+        clients = [redis.Redis(port=1531, socket_timeout=1) for _ in range(5)]
+        for index, client in enumerate(clients):
+            client.set("eventloop%d" % index, index)
+        for index, client in enumerate(clients):
+            self.assertEqual(client.get("eventloop%d" % index), str(index))
+
     def test_switch_config(self):
         self.start_redis_server(6380)
         self.start_redis_server(6381)
@@ -92,4 +144,86 @@ class ConfigTests(TestUtil):
 
         r = redis.Redis(port=1540)
         response = r.execute_command("LOADCONFIG tests/conf/swapconfig3.toml")
-        self.assertTrue(response)
\ No newline at end of file
+        self.assertTrue(response)
+    def test_repeated_switch_config(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        pid = TestUtil.find_proxy_pid("tests/conf/testconfig1.toml")
+        r = redis.Redis(port=1530)
+        fd_counts = []
+        for _ in range(5):
+            # A client of the pool whose listener is removed by the switch.
+            orphan = socket.create_connection(("127.0.0.1", 1531))
+            orphan.settimeout(1)
+            TestUtil.verify_redis_connection(1531)
+
+            self.assertTrue(r.execute_command("LOADCONFIG tests/conf/swapconfig2.toml"))
+            self.assertEqual(r.execute_command("SWITCHCONFIG"), "OK")
+            TestUtil.verify_redis_connection(1532)
+            self.assertEqual(orphan.recv(1), "")
+            orphan.close()
+
+            self.assertTrue(r.execute_command("LOADCONFIG tests/conf/testconfig1.toml"))
+            self.assertEqual(r.execute_command("SWITCHCONFIG"), "OK")
+            TestUtil.verify_redis_connection(1531)
+            fd_counts.append(len(os.listdir("/proc/{}/fd".format(pid))))
+
+        # The replaced listeners, clients and backend connections are all closed.
+        self.assertEqual(len(set(fd_counts)), 1, fd_counts)
+
+    def test_rewrite_config(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        rewrite_path = "tests/log/rewrite_config.toml"
+        shutil.copyfile("tests/conf/timeout1.toml", rewrite_path)
+        self.start_proxy(rewrite_path)
+
+        r = redis.Redis(port=1530)
+        response = r.execute_command("LOADCONFIG tests/conf/swapconfig3.toml")
+        self.assertTrue(response)
+        try:
+            r.execute_command("SWITCHCONFIG")
+        except redis.ConnectionError, e:
+            pass
+        time.sleep(0.2)
+
+        r = redis.Redis(port=1530)
+        # Only the file the proxy was started with can be written.
+        other_path = "tests/log/rewrite_config_other.toml"
+        self.assertEqual(
+            r.execute_command("REWRITECONFIG " + other_path),
+            "ERROR: REWRITECONFIG takes no arguments. It writes to the config file the proxy was started with."
+        )
+        self.assertFalse(os.path.exists(other_path))
+        response = r.execute_command("REWRITECONFIG")
+        self.assertEqual(response, "OK")
+        r.execute_command("SHUTDOWN")
+        time.sleep(0.2)
+
+        # A proxy started from the rewritten file should use the switched config.
+        self.start_proxy(rewrite_path, "rewritten")
+        TestUtil.populate_redis_key(1532, "key1")
+        self.assert_redis_key(6381, "key1")
+
+    def test_config_profile(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/profiles1.toml", extra_args=["--profile", "replica"])
+
+        # The replica profile swaps the pool's backend to 6381.
+        TestUtil.populate_redis_key(1531, "key1")
+        self.assert_redis_key(6381, "key1")
+
+    def test_rewrite_config_profile(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        config_path = "tests/log/rewrite_profiles.toml"
+        shutil.copyfile("tests/conf/profiles1.toml", config_path)
+        self.start_proxy(config_path, extra_args=["--profile", "replica"])
+
+        # Rewriting would flatten the replica profile into the file and drop the others, so it's refused.
+        r = redis.Redis(port=1530)
+        response = r.execute_command("REWRITECONFIG")
+        self.assertTrue(response.startswith("Unable to rewrite config file"), response)
+        with open(config_path) as rewritten, open("tests/conf/profiles1.toml") as original:
+            self.assertEqual(rewritten.read(), original.read())
@@ -7,6 +7,9 @@ import sys
 import time
 import unittest
 import socket
+import json
+import threading
+import BaseHTTPServer
 from test_util import TestUtil
 from timeout_tests import TimeoutTests
 from cluster_tests import ClusterTests
@@ -15,11 +18,12 @@ from config_tests import ConfigTests
 from sharding_tests import ShardingTests
 from command_tests import CommandTests
 from stats_tests import StatsTests
+from sentinel_tests import SentinelTests
 
 class TestRedFlareProxy(TestUtil):
 
     def test_single_backend_no_timeout(self):
-        self.start_redis_server(6380)
+        self.start_backend(6380)
         self.start_proxy("tests/conf/testconfig1.toml")
 
         TestUtil.verify_redis_connection(1531)
@@ -35,10 +39,10 @@ class TestRedFlareProxy(TestUtil):
 
 # Test successful, multiple (4) backends, no timeout. verify that the sharding is correct.
     def test_multiple_backend_no_timeout(self):
-        self.start_redis_server(6381)
-        self.start_redis_server(6382)
-        self.start_redis_server(6383)
-        self.start_redis_server(6384)
+        self.start_backend(6381)
+        self.start_backend(6382)
+        self.start_backend(6383)
+        self.start_backend(6384)
         self.start_proxy("tests/conf/multishard1.toml")
 
         TestUtil.verify_redis_connection(1533)
@@ -50,10 +54,10 @@ class TestRedFlareProxy(TestUtil):
 
     def test_multiple_backend_with_timeout(self):
         # TODO: Set delay at 1 at first, then verify timeout when delay set to 101.
-        self.start_redis_server(6381)
-        self.start_redis_server(6382)
-        self.start_redis_server(6380)
-        self.start_redis_server(6384)
+        self.start_backend(6381)
+        self.start_backend(6382)
+        self.start_backend(6380)
+        self.start_backend(6384)
         self.start_delayer(6383, 6380, 110)
         self.start_proxy("tests/conf/multishard2.toml")
 
@@ -63,10 +67,10 @@ class TestRedFlareProxy(TestUtil):
         TestUtil.verify_redis_error(1533, "ERROR: Not connected")
 
     def test_hashtags(self):
-        self.start_redis_server(6381)
-        self.start_redis_server(6382)
-        self.start_redis_server(6383)
-        self.start_redis_server(6384)
+        self.start_backend(6381)
+        self.start_backend(6382)
+        self.start_backend(6383)
+        self.start_backend(6384)
         ports = [6381, 6382, 6383, 6384]
         self.start_proxy("tests/conf/multishardtags1.toml")
         TestUtil.verify_redis_connection(1533)
@@ -149,6 +153,20 @@ class TestRedFlareProxy(TestUtil):
         time.sleep(1)
         TestUtil.verify_redis_connection(1531)
 
+    def test_acl_user_auth(self):
+        # Requires Redis 6, for ACL users.
+        self.start_redis_server(6380, config_path="tests/conf/redis-acl.conf")
+        self.start_proxy("tests/conf/auth3.toml")
+
+        TestUtil.verify_redis_connection(1531)
+        TestUtil.verify_redis_error(1532, "ERROR: Not connected")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        errors = r.execute_command("STATS").split("\nErrors:\n")[1].split("\nBackend errors:")[0].split("\n")
+        self.assertTrue(" auth_failures=0 " in errors[0])
+        # Each reconnect is rejected again.
+        self.assertNotEqual(errors[1].split(" auth_failures=")[1].split(" ")[0], "0")
+
     def test_db(self):
         self.start_redis_server(6380)
         self.start_redis_server(6382)
@@ -226,7 +244,7 @@ class TestRedFlareProxy(TestUtil):
         self.assertEquals(response, 3)
 
     def test_invalid_client_requests(self):
-        self.start_redis_server(6380)
+        self.start_backend(6380)
         self.start_proxy("tests/conf/testconfig1.toml")
         s1 = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
         s1.settimeout(1)
@@ -236,6 +254,597 @@ class TestRedFlareProxy(TestUtil):
         s1.close()
         self.assertEquals(resp, "-ERROR: Invalid redis protocol\r\n")
 
+    def test_large_values_pipelined(self):
+        self.start_backend(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.verify_redis_connection(1531)
+        large = "x" * 8000
+        redis.Redis(port=6380).set("small", "1")
+        redis.Redis(port=6380).set("large", large)
+
+        # A large response is written straight away instead of being buffered, after the responses buffered before it.
+        s1 = socket.create_connection(("127.0.0.1", 1531))
+        s1.settimeout(1)
+        get = lambda key: "*2\r\n$3\r\nGET\r\n$%d\r\n%s\r\n" % (len(key), key)
+        s1.sendall(get("small") + get("large") + get("small"))
+        expected = "$1\r\n1\r\n$8000\r\n" + large + "\r\n$1\r\n1\r\n"
+        response = ""
+        while len(response) < len(expected):
+            response += s1.recv(16384)
+        self.assertEquals(response, expected)
+
+    def test_replica_fallback(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381, config_path="tests/conf/redis-replica6381.conf")
+        self.start_proxy("tests/conf/replica1.toml")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        time.sleep(0.1)
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.1)
+        # Reads are answered by the replica while the master is down, and writes fail.
+        self.assertEquals(r.get("a"), "1")
+        self.assertRaises(redis.ResponseError, r.set, "a", "2")
+
+    def test_read_your_writes(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/readyourwrites1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # The replica isn't replicating, so which one answered a read shows from its value.
+        redis.Redis(port=6381).set("a", "replica")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(r.get("a"), "replica")
+        # The client's reads of a key it just wrote go to the primary, while other clients still read it from the replica.
+        r.set("b", "1")
+        self.assertEquals(r.get("b"), "1")
+        self.assertEquals(r.mget("a", "b"), ["replica", "1"])
+        self.assertEquals(redis.Redis(port=1531, socket_timeout=1).get("b"), None)
+        # Until read_your_writes has passed.
+        time.sleep(0.6)
+        self.assertEquals(r.get("b"), None)
+
+    def test_hedged_reads(self):
+        self.start_redis_server(6381)
+        self.start_redis_server(6382)
+        self.start_delayer(6380, 6382, 50)
+        self.start_proxy("tests/conf/hedge1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        redis.Redis(port=6382).set("a", "master")
+        redis.Redis(port=6381).set("a", "replica")
+        # The delayed master doesn't answer within hedge_delay, so the replica's response is returned.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(r.get("a"), "replica")
+
+    def test_collapsed_gets(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 200)
+        self.start_proxy("tests/conf/collapse1.toml")
+        TestUtil.verify_redis_connection(1531)
+        redis.Redis(port=6381).set("hot", "value")
+
+        first = socket.create_connection(("127.0.0.1", 1531))
+        second = socket.create_connection(("127.0.0.1", 1531))
+        first.settimeout(1)
+        second.settimeout(1)
+        first.sendall(b"*2\r\n$3\r\nGET\r\n$3\r\nhot\r\n")
+        time.sleep(0.1)
+        start = time.time()
+        second.sendall(b"*2\r\n$3\r\nGET\r\n$3\r\nhot\r\n")
+        # The second GET joins the first, so it's answered before the backend's delay has passed for it.
+        self.assertEquals(second.recv(1024), b"$5\r\nvalue\r\n")
+        self.assertTrue(time.time() - start < 0.19)
+        self.assertEquals(first.recv(1024), b"$5\r\nvalue\r\n")
+
+        # Once answered, the next GET of the key is sent to the backend again.
+        redis.Redis(port=6381).set("hot", "other")
+        self.assertEquals(redis.Redis(port=1531, socket_timeout=1).get("hot"), "other")
+
+    def test_collapsed_gets_pipelined(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 200)
+        self.start_proxy("tests/conf/collapse1.toml")
+        TestUtil.verify_redis_connection(1531)
+        redis.Redis(port=6381).set("hot", "value")
+
+        first = socket.create_connection(("127.0.0.1", 1531))
+        second = socket.create_connection(("127.0.0.1", 1531))
+        first.settimeout(1)
+        second.settimeout(1)
+        first.sendall(b"*2\r\n$3\r\nGET\r\n$3\r\nhot\r\n")
+        time.sleep(0.1)
+        # A GET pipelined behind the client's SET doesn't join the first client's GET, which would answer it before the
+        # SET is, but is sent after the SET.
+        second.sendall(b"*3\r\n$3\r\nSET\r\n$3\r\nhot\r\n$3\r\nnew\r\n*2\r\n$3\r\nGET\r\n$3\r\nhot\r\n")
+        expected = b"+OK\r\n$3\r\nnew\r\n"
+        response = b""
+        while len(response) < len(expected):
+            response += second.recv(1024)
+        self.assertEquals(response, expected)
+        self.assertEquals(first.recv(1024), b"$5\r\nvalue\r\n")
+
+    def test_dual_write(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/dualwrite1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        time.sleep(0.1)
+        # The write reaches both backends, and reads are only answered by the primary.
+        self.assertEquals(redis.Redis(port=6380).get("a"), "1")
+        self.assertEquals(redis.Redis(port=6381).get("a"), "1")
+        redis.Redis(port=6381).set("a", "2")
+        self.assertEquals(r.get("a"), "1")
+
+    def test_canary(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/canary1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # The canary gets a copy of every request, but clients only see the real backend's responses.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        time.sleep(0.1)
+        self.assertEquals(redis.Redis(port=6381).get("a"), "1")
+        redis.Redis(port=6381).set("a", "2")
+        self.assertEquals(r.get("a"), "1")
+
+    def test_standby_failover(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        self.start_proxy("tests/conf/standby1.toml")
+        TestUtil.verify_redis_connection(1531)
+        redis.Redis(port=6380).set("a", "primary")
+        redis.Redis(port=6381).set("a", "standby")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(r.get("a"), "primary")
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.1)
+        # Once none of its backends are available, the pool's clients are sent to the standby.
+        self.assertEquals(r.get("a"), "standby")
+
+        # Clients go back once the primary has been up for failback_delay.
+        self.start_redis_server(6380)
+        redis.Redis(port=6380).set("a", "primary")
+        time.sleep(0.2)
+        r.get("a")
+        time.sleep(0.3)
+        self.assertEquals(r.get("a"), "primary")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertEquals(admin.execute_command("FAILOVER", "pool1", "STANDBY"), "OK")
+        self.assertEquals(r.get("a"), "standby")
+        self.assertEquals(admin.execute_command("FAILOVER"), "pool1: serving from pool2, mode Standby\n")
+        self.assertEquals(admin.execute_command("FAILOVER", "pool2", "AUTO"), "Pool pool2 has no standby")
+        self.assertEquals(admin.execute_command("FAILOVER", "pool1", "AUTO"), "OK")
+        time.sleep(0.3)
+        r.get("a")
+        time.sleep(0.3)
+        self.assertEquals(r.get("a"), "primary")
+
+    def test_chaos(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/chaos1.toml")
+        TestUtil.verify_redis_connection(1531)
+        redis.Redis(port=6380).set("a", "1")
+
+        # Faults are only injected once chaos mode is started.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEquals(r.get("a"), "1")
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertEquals(admin.execute_command("CHAOS", "START", "pool1"), "OK")
+        self.assertRaises(redis.ResponseError, r.get, "a")
+        self.assertEquals(admin.execute_command("CHAOS"), "pool1: active=true delays=0 disconnects=0 drops=0 errors=1")
+        self.assertEquals(admin.execute_command("CHAOS", "STOP", "pool1"), "OK")
+        self.assertEquals(r.get("a"), "1")
+        self.assertEquals(admin.execute_command("CHAOS", "START", "pool2"), "Unknown pool: pool2")
+
+    def test_bench(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/timeout1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertEquals(admin.execute_command("BENCH"), "No benchmark has been run.")
+        self.assertEquals(admin.execute_command("BENCH", "START", "pool1", "1000", "10", "50", "16"),
+                          "Started benchmark of pool1: 1000 requests from 10 clients, 50% SET, 16 byte values")
+        for _ in range(50):
+            if " done " in admin.execute_command("BENCH"):
+                break
+            time.sleep(0.1)
+        report = admin.execute_command("BENCH")
+        self.assertTrue(report.startswith("pool1 done requests=1000/1000 errors=0 "), report)
+        self.assertTrue(" p99_us=" in report, report)
+        self.assertEquals(admin.execute_command("BENCH", "STOP"), "No benchmark is running.")
+        self.assertEquals(admin.execute_command("BENCH", "START", "pool2"), "Unknown pool: pool2")
+        self.assertTrue(redis.Redis(port=6380).dbsize() > 0)
+
+    def test_mock_backend(self):
+        self.start_mock_server(6380)
+        self.start_proxy("tests/conf/timeout1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        self.assertEquals(r.get("a"), "1")
+        script = redis.Redis(port=6380, socket_timeout=1)
+        script.execute_command("MOCK", "REPLY", "GET", "-ERR boom\r\n")
+        TestUtil.verify_redis_error(1531, "boom", key="a")
+        script.execute_command("MOCK", "UNREPLY", "GET")
+
+        # A backend that stops answering times out.
+        script.execute_command("MOCK", "FAIL", "HANG")
+        TestUtil.verify_redis_error(1531, "Proxy timed out", key="a")
+        script.execute_command("MOCK", "FAIL", "NONE")
+        self.assertEquals(r.get("a"), "1")
+
+    def test_client_rate_limit(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/ratelimit1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Each connection gets its own limit.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        r.get("a")
+        self.assertRaisesRegexp(redis.ResponseError, "rate limit exceeded", r.get, "a")
+        self.assertEquals(redis.Redis(port=1531, socket_timeout=1).get("a"), "1")
+        time.sleep(0.5)
+        self.assertEquals(r.get("a"), "1")
+
+        # With Delay, requests over the limits are answered late instead of rejected.
+        s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        s.settimeout(3)
+        s.connect(("0.0.0.0", 1532))
+        start = time.time()
+        s.send(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n" * 12)
+        expected = b"$1\r\n1\r\n" * 12
+        response = b""
+        while len(response) < len(expected):
+            response += s.recv(1024)
+        s.close()
+        self.assertEquals(response, expected)
+        self.assertTrue(time.time() - start >= 0.15)
+
+    def test_key_rate_limit(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/keyratelimit1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # The limit is shared by every client, and only applies to writes of matching keys.
+        redis.Redis(port=1531, socket_timeout=1).incr("counter:a")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.incr("counter:b")
+        self.assertRaisesRegexp(redis.ResponseError, "rate limit exceeded", r.incr, "counter:a")
+        self.assertRaisesRegexp(redis.ResponseError, "rate limit exceeded", r.execute_command, "MSET", "counter:a", "1", "other", "1")
+        self.assertEquals(r.get("counter:a"), "1")
+        self.assertEquals(r.incr("other"), 1)
+        time.sleep(0.5)
+        self.assertEquals(r.incr("counter:a"), 2)
+
+    def test_client_network_acl(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/netacl1.toml")
+        time.sleep(0.1)
+
+        # Only 10.0.0.0/8 may connect to pool1, so the connection is closed before any reply.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertRaises(redis.ConnectionError, r.get, "a")
+        r = redis.Redis(port=1532, socket_timeout=1)
+        r.set("a", "1")
+        self.assertEquals(r.get("a"), "1")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        connections = admin.execute_command("STATS").split("\nConnections:\n")[1].split("\nBig values:")[0].split("\n")
+        self.assertTrue(" denied_connections=1 " in connections[0])
+        self.assertTrue(" denied_connections=0 " in connections[1])
+        self.assertEquals(connections[2], "admin: denied_connections=0")
+
+    def test_quarantine(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/quarantine1.toml")
+        time.sleep(0.1)
+
+        # The client is closed after its second invalid request.
+        conn = socket.create_connection(("127.0.0.1", 1531))
+        conn.settimeout(1)
+        conn.sendall("GARBAGE\r\n")
+        self.assertEquals(conn.recv(1024), "-ERROR: Invalid redis protocol\r\n")
+        conn.sendall("GARBAGE\r\n")
+        self.assertEquals(conn.recv(1024), "-ERROR: Invalid redis protocol\r\n")
+        self.assertEquals(conn.recv(1024), "")
+        conn.close()
+
+        # Its address is refused until quarantine_duration has passed.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertRaises(redis.ConnectionError, r.get, "a")
+        time.sleep(1)
+        self.assertEquals(r.get("a"), None)
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        connections = admin.execute_command("STATS").split("\nConnections:\n")[1].split("\n")[0]
+        self.assertTrue(connections.endswith(" quarantined_clients=1 quarantine_refused=1"), connections)
+
+    def test_maintenance_windows(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/maintenance1.toml")
+        time.sleep(0.1)
+
+        # Both pools' windows last all day. Requests to pool1 are answered with its message.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertRaisesRegexp(redis.ResponseError, "ERROR: Back soon", r.get, "a")
+        # New clients of pool2 are refused.
+        conn = socket.create_connection(("127.0.0.1", 1532))
+        conn.settimeout(1)
+        self.assertEquals(conn.recv(1024), "-ERROR: Pool is under maintenance\r\n")
+        self.assertEquals(conn.recv(1024), "")
+        conn.close()
+
+    def test_proxy_protocol(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/proxyprotocol1.toml")
+        time.sleep(0.1)
+
+        # The client address in the header is checked against allow_clients, rather than the connection's.
+        conn = socket.create_connection(("127.0.0.1", 1531))
+        conn.sendall("PROXY TCP4 10.1.2.3 127.0.0.1 51234 1531\r\n")
+        time.sleep(0.05)
+        conn.sendall("*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+        self.assertEquals(conn.recv(1024), "+OK\r\n")
+        conn.close()
+
+        conn = socket.create_connection(("127.0.0.1", 1531))
+        conn.sendall("PROXY TCP4 192.168.1.1 127.0.0.1 51234 1531\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n")
+        self.assertEquals(conn.recv(1024), "")
+
+        # Clients that don't go through the load balancer are closed.
+        conn = socket.create_connection(("127.0.0.1", 1531))
+        conn.sendall("*2\r\n$3\r\nGET\r\n$1\r\na\r\n")
+        self.assertEquals(conn.recv(1024), "")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        connections = r.execute_command("STATS").split("\nConnections:\n")[1].split("\n")[0]
+        self.assertTrue(" denied_connections=1 " in connections)
+
+    def test_consul_discovery(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381)
+        TestUtil.populate_redis_key(6381, "key1")
+        instances = [6381]
+
+        class ConsulHandler(BaseHTTPServer.BaseHTTPRequestHandler):
+            def do_GET(self):
+                body = json.dumps([{"Node": {"Address": "127.0.0.1"}, "Service": {"Address": "", "Port": port}} for port in instances])
+                self.send_response(200)
+                self.send_header("Content-Type", "application/json")
+                self.end_headers()
+                self.wfile.write(body)
+
+            def log_message(self, *args):
+                pass
+
+        consul = BaseHTTPServer.HTTPServer(("127.0.0.1", 8500), ConsulHandler)
+        consul_thread = threading.Thread(target=consul.serve_forever)
+        consul_thread.daemon = True
+        consul_thread.start()
+        try:
+            self.start_proxy("tests/conf/discovery1.toml")
+            # Discovered backends are switched to within a second.
+            time.sleep(1.5)
+            self.assert_redis_key(1531, "key1")
+            r = redis.Redis(port=1530, socket_timeout=1)
+            self.assertTrue("127.0.0.1:6381" in r.execute_command("CONFIGINFO"))
+
+            instances[0] = 6380
+            time.sleep(1.5)
+            self.assertEquals(redis.Redis(port=1531, socket_timeout=1).get("key1"), None)
+
+            # A lookup that finds no backends keeps the current ones.
+            del instances[:]
+            time.sleep(1.5)
+            self.assertTrue("127.0.0.1:6380" in r.execute_command("CONFIGINFO"))
+        finally:
+            consul.shutdown()
+            consul.server_close()
+
+    def test_user_quotas(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/quota1.toml")
+        time.sleep(0.1)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertRaisesRegexp(redis.ResponseError, "NOAUTH", r.get, "a")
+        self.assertRaisesRegexp(redis.ResponseError, "WRONGPASS", r.execute_command, "AUTH", "password2")
+        self.assertEquals(r.execute_command("AUTH", "password1"), "OK")
+        for _ in range(5):
+            r.get("a")
+
+        # The quota is shared by every connection of the user.
+        r1 = redis.Redis(port=1531, socket_timeout=1)
+        r1.execute_command("AUTH", "search", "password2")
+        r2 = redis.Redis(port=1531, socket_timeout=1)
+        r2.execute_command("AUTH", "search", "password2")
+        r1.get("a")
+        r2.get("a")
+        self.assertRaisesRegexp(redis.ResponseError, "quota exceeded", r1.get, "a")
+        time.sleep(0.6)
+        r2.get("a")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        stats = admin.execute_command("POOLSTATS")
+        self.assertIn('user_requests{pool="pool1",user="search"} 3', stats)
+        self.assertIn('user_quota_exceeded{pool="pool1",user="search"} 1', stats)
+
+    def test_user_permissions(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/acl1.toml")
+        time.sleep(0.1)
+
+        # A user without rules may run anything.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.execute_command("AUTH", "password1")
+        r.set("session:1", "a")
+        r.delete("session:1")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.execute_command("AUTH", "reader", "password2")
+        self.assertTrue(r.set("cache:1", "a"))
+        self.assertEquals(r.get("cache:1"), "a")
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* command", r.incr, "cache:2")
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* command", r.ttl, "cache:1")
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* keys", r.get, "session:1")
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* keys", r.mget, "cache:1", "session:1")
+        self.assertEquals(r.mget("cache:1", "cache:2"), ["a", None])
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertIn('user_denied{pool="pool1",user="reader"} 4', admin.execute_command("POOLSTATS"))
+
+    def test_user_namespaces(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/namespace1.toml")
+        time.sleep(0.1)
+
+        team1 = redis.Redis(port=1531, socket_timeout=1)
+        team1.execute_command("AUTH", "team1", "password2")
+        team2 = redis.Redis(port=1531, socket_timeout=1)
+        team2.execute_command("AUTH", "team2", "password3")
+        admin = redis.Redis(port=1531, socket_timeout=1)
+        admin.execute_command("AUTH", "admin", "password1")
+
+        self.assertTrue(team1.set("cache:1", "a"))
+        self.assertTrue(team2.set("cache:1", "b"))
+        self.assertEquals(team1.get("cache:1"), "a")
+        self.assertEquals(team2.mget("cache:1", "cache:2"), ["b", None])
+        self.assertEquals(admin.get("team1:cache:1"), "a")
+        self.assertEquals(admin.get("team2:cache:1"), "b")
+        self.assertEquals(admin.get("cache:1"), None)
+
+        # Key rules apply to the keys as the user sends them.
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* keys", team2.get, "session:1")
+        # Scripts could reach the keys of other namespaces.
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* command", team1.eval, "return redis.call('get', 'team2:cache:1')", 1, "cache:1")
+        # Replies that name their keys would show the namespace's prefix.
+        self.assertEquals(team1.execute_command("XADD", "stream:1", "1-1", "field", "value"), "1-1")
+        self.assertEquals(admin.execute_command("XLEN", "team1:stream:1"), 1)
+        self.assertRaisesRegexp(redis.ResponseError, "NOPERM .* command", team1.execute_command, "XREAD", "STREAMS", "stream:1", "0")
+        self.assertRaisesRegexp(
+            redis.ResponseError, "NOPERM .* command", team1.execute_command, "XREADGROUP", "GROUP", "g", "c", "STREAMS", "stream:1", ">"
+        )
+        # Users without a namespace read them as usual.
+        self.assertEquals(admin.execute_command("XREAD", "STREAMS", "team1:stream:1", "0"), [["team1:stream:1", [["1-1", ["field", "value"]]]]])
+
+    def test_rewrite_rules(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/rewrite1.toml")
+        time.sleep(0.1)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        backend = redis.Redis(port=6380, socket_timeout=1)
+        self.assertTrue(r.set("a", "1"))
+        self.assertTrue(0 < backend.ttl("a") <= 100)
+        self.assertTrue(r.set("b", "1", ex=1000))
+        self.assertTrue(100 < backend.ttl("b") <= 1000)
+
+        # HSET answers with the number of fields added, rather than HMSET's OK.
+        self.assertEquals(r.execute_command("HMSET", "h", "f1", "v1", "f2", "v2"), 2)
+        self.assertEquals(backend.hgetall("h"), {"f1": "v1", "f2": "v2"})
+
+    def test_request_size_limits(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/sizelimits1.toml")
+        time.sleep(0.1)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertTrue(r.set("a", "x" * 100))
+        self.assertEquals(r.mget(*["a"] * 9), ["x" * 100] * 9)
+
+        def rejected(request, error):
+            s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+            s.settimeout(1)
+            s.connect(("127.0.0.1", 1531))
+            s.sendall(request)
+            self.assertEquals(s.recv(1000), error)
+            # The rest of the request can't be told apart from the next one, so the client is closed.
+            self.assertEquals(s.recv(1000), "")
+            s.close()
+
+        # Only the headers are sent, which is enough to reject them.
+        rejected("*2\r\n$3\r\nGET\r\n$101\r\n", "-ERROR: Argument larger than max_bulk_length\r\n")
+        rejected("*11\r\n", "-ERROR: Request has more arguments than max_multibulk_length\r\n")
+        rejected("*10\r\n$3\r\nSET\r\n" + ("$100\r\n" + "x" * 100 + "\r\n") * 4 + "$100\r\n", "-ERROR: Request larger than max_request_size\r\n")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertIn('protocol_errors=3', admin.execute_command("STATS"))
+
+    def test_error_responses(self):
+        self.start_redis_server(6381)
+        self.start_delayer(incoming_port=6380, outgoing_port=6381, delay=1, admin_port=6382)
+        self.start_proxy("tests/conf/errorresponses1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        conn_to_delayer = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        conn_to_delayer.connect(("0.0.0.0", 6382))
+        conn_to_delayer.sendall("SETDELAY 400")
+
+        TestUtil.verify_redis_error(1531, "TIMEOUT Request timed out, safe to retry")
+        # With codes, the errors that aren't configured get a code of their own.
+        TestUtil.verify_redis_error(1531, "PROXY_UNAVAILABLE Backend unavailable")
+
+        # Errors from redis are left alone.
+        conn_to_delayer.sendall("SETDELAY 1")
+        time.sleep(1.5)
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        self.assertRaisesRegexp(redis.ResponseError, "^value is not an integer", r.execute_command, "INCRBY", "a", "x")
+
+    def test_backend_hostname(self):
+        # localhost usually resolves to both ::1 and 127.0.0.1. Whichever the redis server doesn't listen on is
+        # refused, and the proxy connects to the other one right away.
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/hostname1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        self.assertEqual(r.get("a"), "1")
+
+    def test_tcp_fastopen(self):
+        # Whether or not the kernel allows TCP Fast Open, the proxy and its backend connections work the same.
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/fastopen1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        self.assertEqual(r.get("a"), "1")
+
+        # Reconnecting to the restarted backend can use the cookie of the first connection.
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.5)
+        self.start_redis_server(6380)
+        time.sleep(1.5)
+        r.set("a", "2")
+        self.assertEqual(r.get("a"), "2")
+
+    def test_transparent_unrouted(self):
+        # Redirecting connections needs iptables, so this only checks that connections made to the transparent
+        # listener directly, which have no original destination of a pool, are closed.
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/transparent1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        s.settimeout(1)
+        s.connect(("127.0.0.1", 1540))
+        self.assertEquals(s.recv(1024), "")
+
     def test_client_reclamation(self):
         # This tests that client tokens are reclaimed properly.
         # When a client connects, it gets assigned a vec index for the client. When it disconnects, it gets
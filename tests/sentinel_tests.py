@@ -0,0 +1,33 @@
+#!/usr/bin/env python
+import redis
+import time
+from test_util import TestUtil
+
+class SentinelTests(TestUtil):
+
+    def test_sentinel_failover(self):
+        self.start_redis_server(6380)
+        self.start_redis_server(6381, config_path="tests/conf/redis-replica6381.conf")
+        self.start_redis_sentinel(26379)
+        self.start_proxy("tests/conf/sentinel1.toml")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.set("a", "1")
+        self.assert_redis_key(6380, "a", "1")
+
+        sentinel = redis.Redis(port=26379)
+        sentinel.execute_command("SENTINEL FAILOVER mymaster")
+        attempts_remaining = 50
+        while sentinel.execute_command("SENTINEL get-master-addr-by-name mymaster")[1] != "6381":
+            attempts_remaining = attempts_remaining - 1
+            self.assertTrue(attempts_remaining > 0)
+            time.sleep(0.2)
+
+        # The proxy follows the +switch-master event to the promoted replica.
+        time.sleep(0.5)
+        try:
+            r.set("b", "1")
+        except redis.ResponseError:
+            # The first request may arrive while the new master is being connected to.
+            r.set("b", "1")
+        self.assert_redis_key(6381, "b", "1")
@@ -1,4 +1,6 @@
 #!/usr/bin/env python
+import os
+import json
 import time
 import socket
 import redis
@@ -15,7 +17,7 @@ class StatsTests(TestUtil):
         r = redis.Redis(port=1530, socket_timeout=1)
         response = r.execute_command("STATS")
         self.assertEqual(
-            response,
+            response.split("\nLatency (us):")[0],
             """Stats:
 accepted_clients: 1
 client_connections: 0
@@ -31,7 +33,7 @@ recv_backend_bytes: 12"""
         TestUtil.populate_redis_key(1531, "key2")
         response = r.execute_command("STATS")
         self.assertEqual(
-            response,
+            response.split("\nLatency (us):")[0],
             """Stats:
 accepted_clients: 2
 client_connections: 0
@@ -43,3 +45,434 @@ send_backend_bytes: 67
 recv_backend_bytes: 17"""
         );
 
+        # Each request goes through the 50ms delayer.
+        latency_lines = response.split("\nLatency (us):\n")[1].split("\nErrors:")[0].split("\n")
+        self.assertEqual(len(latency_lines), 2)
+        self.assertTrue(latency_lines[0].startswith("pool1: count=2 p50="))
+        self.assertTrue(latency_lines[1].startswith("pool1 127.0.0.1:6380: count=2 p50="))
+        p50 = int(latency_lines[0].split("p50=")[1].split(" ")[0])
+        self.assertTrue(p50 >= 50000)
+
+        self.assertEqual(
+            response.split("\nErrors:\n")[1].split("\nBackend errors:")[0],
+            "pool1: timeouts=0 backend_unavailable=0 connection_failures=0 protocol_errors=0 auth_failures=0 moved_redirects=0 ask_redirects=0 error_responses=0 memory_shed=0 overloaded=0 rate_limited=0"
+        );
+        self.assertEqual(
+            response.split("\nBackend errors:\n")[1].split("\nConnections:")[0],
+            "pool1 127.0.0.1:6380: timeouts=0 backend_unavailable=0 connection_failures=0 protocol_errors=0 auth_failures=0 moved_redirects=0 ask_redirects=0 error_responses=0 memory_shed=0 overloaded=0 rate_limited=0"
+        );
+
+        self.assertEqual(
+            response.split("\nConnections:\n")[1].split("\nBig values:")[0],
+            "pool1: accepted_clients=2 client_disconnects=2 backend_reconnects=0 handshake_failures=0 client_read_pauses=0 shed_connections=0 denied_connections=0 idle_closed=0 connect_timeouts=0 held_requests=0 held_expired=0 quarantined_clients=0 quarantine_refused=0"
+        );
+
+        self.assertEqual(
+            response.split("\nBig values:\n")[1].split("\nCommands:")[0],
+            "pool1 127.0.0.1:6380: max_request_bytes=34 max_response_bytes=5"
+        );
+
+        command_lines = response.split("\nCommands:\n")[1].split("\nThroughput:")[0].split("\n")
+        self.assertEqual(len(command_lines), 2)
+        self.assertTrue(command_lines[0].startswith("pool1 GET: requests=1 errors=0 max_request_bytes=27 max_response_bytes=5 latency_us: count=1 p50="))
+        self.assertTrue(command_lines[1].startswith("pool1 SET: requests=1 errors=0 max_request_bytes=34 max_response_bytes=5 latency_us: count=1 p50="))
+
+        throughput_lines = response.split("\nThroughput:\n")[1].split("\nMemory:")[0].split("\n")
+        self.assertEqual(len(throughput_lines), 4)
+        self.assertTrue(throughput_lines[0].startswith("requests_per_sec: 1s="))
+        self.assertTrue(throughput_lines[3].startswith("connections_per_sec: 1s="))
+
+
+    def test_pool_stats_labels(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/poolmetrics1.toml")
+
+        TestUtil.verify_redis_connection(1531)
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("POOLSTATS")
+        lines = response.split("\n")
+        self.assertEqual(
+            lines[:3],
+            ['cache_accepted_clients{pool="pool1",service="indexer",team="search"} 1',
+             'cache_requests{pool="pool1",service="indexer",team="search"} 1',
+             'cache_recv_client_bytes{pool="pool1",service="indexer",team="search"} 27']
+        );
+        self.assertTrue(lines[3].startswith('cache_latency_us{pool="pool1",service="indexer",team="search",quantile="0.5"} '))
+        self.assertTrue(lines[6].startswith('cache_latency_us{pool="pool1",service="indexer",team="search",quantile="0.999"} '))
+        self.assertEqual(lines[7], 'cache_latency_us_count{pool="pool1",service="indexer",team="search"} 1')
+        self.assertTrue(lines[8].startswith('cache_requests_per_sec{pool="pool1",service="indexer",team="search",window="1s"} '))
+        self.assertTrue('cache_command_requests{pool="pool1",service="indexer",team="search",command="GET"} 1' in lines)
+        self.assertTrue('cache_command_errors{pool="pool1",service="indexer",team="search",command="GET"} 0' in lines)
+        self.assertTrue('cache_command_max_request_bytes{pool="pool1",service="indexer",team="search",command="GET"} 27' in lines)
+        self.assertTrue('cache_backend_max_response_bytes{pool="pool1",service="indexer",team="search",backend="127.0.0.1:6380"} 5' in lines)
+
+    def test_backend_error_stats(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 2, 6382)
+        self.start_proxy("tests/conf/timeout1.toml")
+
+        TestUtil.verify_redis_connection(1531)
+
+        # Set a delay longer than the pool timeout of 100ms.
+        conn_to_delayer = socket.socket(socket.AF_INET)
+        conn_to_delayer.connect(("0.0.0.0", 6382))
+        conn_to_delayer.sendall("SETDELAY 110")
+        TestUtil.verify_redis_error(1531, "Proxy timed out")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS")
+        self.assertEqual(
+            response.split("\nBackend errors:\n")[1].split("\nConnections:")[0],
+            "pool1 127.0.0.1:6380: timeouts=1 backend_unavailable=0 connection_failures=0 protocol_errors=0 auth_failures=0 moved_redirects=0 ask_redirects=0 error_responses=0 memory_shed=0 overloaded=0 rate_limited=0"
+        );
+        response = r.execute_command("POOLSTATS")
+        self.assertTrue('backend_timeouts{pool="pool1",backend="127.0.0.1:6380"} 1' in response.split("\n"))
+        self.assertTrue('errors{pool="pool1",type="timeouts"} 1' in response.split("\n"))
+
+    def test_hot_keys(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/hotkeys1.toml")
+
+        r = redis.Redis(port=1531, socket_timeout=1)
+        for _ in range(5):
+            r.get("hot")
+        for _ in range(3):
+            r.get("warm")
+        r.get("cold")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        response = admin.execute_command("HOTKEYS")
+        self.assertEqual(response, "pool1 hot: 5\npool1 warm: 3")
+
+        admin.execute_command("RESETSTATS")
+        response = admin.execute_command("HOTKEYS")
+        self.assertEqual(response, "No hot keys recorded.")
+
+    def test_read_cache(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/readcache1.toml")
+
+        backend = redis.Redis(port=6380, socket_timeout=1)
+        backend.set("a", "1")
+        backend.set("b", "2")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEqual(r.get("a"), "1")
+        # Served from the cache, which doesn't see writes made directly to the backend until it expires.
+        backend.set("a", "3")
+        self.assertEqual(r.get("a"), "1")
+        self.assertEqual(r.mget("a", "b"), ["1", "2"])
+        self.assertEqual(r.mget("a", "b"), ["1", "2"])
+        # Writes through the proxy invalidate the cached key.
+        r.set("a", "4")
+        self.assertEqual(r.get("a"), "4")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        response = admin.execute_command("CACHE")
+        self.assertEqual(response, "pool1: entries=2 bytes=16 max_bytes=1048576 hits=4 misses=3 fills=3 evictions=0 expirations=0 invalidations=1 flushes=0 stale_hits=0")
+
+        admin.execute_command("RESETSTATS")
+        response = admin.execute_command("CACHE")
+        self.assertEqual(response, "pool1: entries=2 bytes=16 max_bytes=1048576 hits=0 misses=0 fills=0 evictions=0 expirations=0 invalidations=0 flushes=0 stale_hits=0")
+
+    def test_serve_stale(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/servestale1.toml")
+
+        redis.Redis(port=6380, socket_timeout=1).set("a", "1")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEqual(r.get("a"), "1")
+        time.sleep(0.2)
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.1)
+        # The expired response is served while the backend is down, but keys that weren't cached still fail.
+        self.assertEqual(r.get("a"), "1")
+        self.assertEqual(r.mget("a"), ["1"])
+        self.assertRaisesRegexp(redis.ResponseError, "Not connected", r.get, "b")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        self.assertTrue(admin.execute_command("CACHE").endswith(" stale_hits=2"))
+
+    def test_read_cache_invalidation(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/readcache2.toml")
+
+        backend = redis.Redis(port=6380, socket_timeout=1)
+        backend.set("a", "1")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        self.assertEqual(r.get("a"), "1")
+        self.assertEqual(r.get("a"), "1")
+        # The backend reports the write through CLIENT TRACKING, which evicts the cached key.
+        backend.set("a", "2")
+        time.sleep(0.1)
+        self.assertEqual(r.get("a"), "2")
+
+    def test_hot_key_cache(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/hotkeys2.toml")
+
+        backend = redis.Redis(port=6380, socket_timeout=1)
+        backend.set("hot", "1")
+        backend.set("cold", "1")
+        r = redis.Redis(port=1531, socket_timeout=1)
+        for _ in range(5):
+            r.get("hot")
+        # Only hot is in the top list, so only it is served from the cache until hotkey_cache_ttl passes.
+        backend.set("hot", "2")
+        self.assertEqual(r.get("hot"), "1")
+        r.get("cold")
+        backend.set("cold", "2")
+        self.assertEqual(r.get("cold"), "2")
+        time.sleep(1.1)
+        self.assertEqual(r.get("hot"), "2")
+
+    def test_debug_timing(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/tracing1.toml")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        response = admin.execute_command("DEBUG", "TIMING")
+        self.assertEqual(response, "No timing samples recorded.")
+
+        # One in every 2 requests is sampled.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        for _ in range(4):
+            r.get("key1")
+
+        lines = admin.execute_command("DEBUG", "TIMING").split("\n")
+        self.assertEqual(len(lines), 2)
+        for line in lines:
+            self.assertRegexpMatches(line, r"^pool1 GET 127.0.0.1:6380: total_us=\d+ parse_us=\d+ queue_wait_us=\d+ backend_rtt_us=\d+ flush_us=\d+$")
+
+        admin.execute_command("RESETSTATS")
+        response = admin.execute_command("DEBUG", "TIMING")
+        self.assertEqual(response, "No timing samples recorded.")
+
+    def test_slowlog(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/slowlog1.toml")
+
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        response = admin.execute_command("SLOWLOG")
+        self.assertEqual(response, "No slow requests recorded.")
+
+        # Every request is over the 1us threshold, but only the last 2 are kept.
+        r = redis.Redis(port=1531, socket_timeout=1)
+        r.get("key1")
+        r.set("key2", "value2")
+        r.set("key3", "a much longer value")
+
+        lines = admin.execute_command("SLOWLOG").split("\n")
+        self.assertEqual(len(lines), 2)
+        self.assertRegexpMatches(lines[0], r'^pool1 127.0.0.1:6380: time=\S+ duration_us=\d+ command=SET payload="SET key3 a much ..."$')
+        self.assertRegexpMatches(lines[1], r'^pool1 127.0.0.1:6380: time=\S+ duration_us=\d+ command=SET payload="SET key2 value2"$')
+
+        self.assertEqual(admin.execute_command("SLOWLOG", "RESET"), "OK")
+        response = admin.execute_command("SLOWLOG")
+        self.assertEqual(response, "No slow requests recorded.")
+
+    def test_event_loop_stats(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.populate_redis_key(1531, "key1")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        event_loop = r.execute_command("STATS").split("\nEvent loop:\n")[1].split("\n")
+        self.assertEqual(len(event_loop), 6)
+        self.assertRegexpMatches(event_loop[0], r"^iteration_us: count=[1-9]\d* p50=\d+ p95=\d+ p99=\d+ p999=\d+$")
+        self.assertRegexpMatches(event_loop[1], r"^events_per_wakeup: count=[1-9]\d* p50=\d+")
+        self.assertRegexpMatches(event_loop[2], r"^client_write_us: count=[1-9]\d* p50=\d+")
+        self.assertEqual(event_loop[3], "stalls: 0")
+        self.assertEqual(event_loop[4], "accept_pauses: 0")
+        self.assertRegexpMatches(event_loop[5], r"^requests_per_backend_write: count=[1-9]\d* p50=\d+")
+
+    def test_batch_dispatch(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/batchdispatch1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Pipelines from several clients read in the same iteration are written to the backend together, and each
+        # client still gets its responses in order.
+        clients = [socket.create_connection(("127.0.0.1", 1531)) for _ in range(5)]
+        for index, client in enumerate(clients):
+            client.settimeout(1)
+            client.sendall("".join(["*3\r\n$3\r\nSET\r\n$6\r\nbatch%d\r\n$2\r\n%02d\r\n" % (index, n) for n in range(10)]))
+        for client in clients:
+            responses = ""
+            while len(responses) < 50:
+                responses += client.recv(1024)
+            self.assertEqual(responses, "+OK\r\n" * 10)
+        for index in range(5):
+            self.assertEqual(redis.Redis(port=6380).get("batch%d" % index), "09")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        event_loop = r.execute_command("STATS").split("\nEvent loop:\n")[1].split("\n")
+        largest = int(event_loop[5].split(" p999=")[1])
+        self.assertTrue(largest >= 10, event_loop[5])
+
+    def test_event_loop_stall(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Loading a config from a fifo blocks the event loop until something writes to it, for longer than the default
+        # watchdog_threshold.
+        fifo = "/tmp/redflare_stall_fifo"
+        if os.path.exists(fifo):
+            os.remove(fifo)
+        os.mkfifo(fifo)
+        admin = socket.socket(socket.AF_INET)
+        admin.connect(("0.0.0.0", 1530))
+        admin.sendall("*2\r\n$10\r\nLOADCONFIG\r\n$%d\r\n%s\r\n" % (len(fifo), fifo))
+        time.sleep(1.5)
+        with open(fifo, "w") as f:
+            f.write("workers = 1\n")
+        os.remove(fifo)
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        event_loop = r.execute_command("STATS").split("\nEvent loop:\n")[1].split("\n")
+        self.assertEqual(event_loop[3], "stalls: 1")
+
+    def test_overload_accept_pause(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/overload1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Loading a config from a fifo blocks the event loop for longer than overload_latency. A client that connects
+        # meanwhile waits in the listen backlog, and is accepted once the worker has caught up.
+        fifo = "/tmp/redflare_overload_fifo"
+        if os.path.exists(fifo):
+            os.remove(fifo)
+        os.mkfifo(fifo)
+        admin = socket.socket(socket.AF_INET)
+        admin.connect(("0.0.0.0", 1530))
+        admin.sendall("*2\r\n$10\r\nLOADCONFIG\r\n$%d\r\n%s\r\n" % (len(fifo), fifo))
+        time.sleep(0.2)
+        client = socket.create_connection(("127.0.0.1", 1531))
+        client.settimeout(1)
+        client.sendall("*2\r\n$3\r\nGET\r\n$1\r\na\r\n")
+        time.sleep(0.5)
+        with open(fifo, "w") as f:
+            f.write("workers = 1\n")
+        os.remove(fifo)
+        self.assertEqual(client.recv(1024), "$-1\r\n")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        event_loop = r.execute_command("STATS").split("\nEvent loop:\n")[1].split("\n")
+        self.assertEqual(event_loop[4], "accept_pauses: 1")
+
+    def test_queue_stats(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/testconfig1.toml")
+        TestUtil.populate_redis_key(1531, "key1")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        queues = r.execute_command("STATS").split("\nQueues:\n")[1].split("\nEvent loop:\n")[0].split("\n")
+        self.assertEqual(len(queues), 1)
+        self.assertRegexpMatches(queues[0], r"^pool1 127.0.0.1:6380: depth=0 max_depth=[1-9]\d* concurrency_limit=0$")
+
+    def test_memory_stats(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/poolmetrics1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Hold a client connection open, so that its read buffer is counted.
+        client = socket.socket(socket.AF_INET)
+        client.connect(("0.0.0.0", 1531))
+        time.sleep(0.1)
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS")
+        memory_lines = response.split("\nMemory:\n")[1].split("\nQueues:")[0].split("\n")
+        self.assertEqual(len(memory_lines), 4)
+        self.assertTrue(memory_lines[0].startswith("total: client_read_buffer_bytes=16384 client_buffered_bytes=0 client_pending_response_bytes=0 backend_read_buffer_bytes=16384 queued_requests=0 "))
+        self.assertTrue(memory_lines[1].startswith("pool1: client_read_buffer_bytes=16384 "))
+        # The backend connection and the two clients each took a buffer. The first client's was returned on close, and
+        # reused by the second.
+        self.assertEqual(memory_lines[2], "buffer_pool: buffer_size=16384 free=0 allocated=2 reused=1")
+        self.assertTrue(memory_lines[3].startswith("budget: used_bytes="))
+        self.assertTrue(memory_lines[3].endswith(" limit_bytes=0"))
+
+        response = r.execute_command("POOLSTATS")
+        lines = response.split("\n")
+        self.assertTrue('cache_memory_client_read_buffer_bytes{pool="pool1",service="indexer",team="search"} 16384' in lines)
+        self.assertTrue('cache_memory_queued_requests{pool="pool1",service="indexer",team="search"} 0' in lines)
+        client.close()
+
+    def test_memory_limit(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/memorylimit1.toml")
+        time.sleep(0.2)
+
+        # The backend connection's buffers alone are over the limit, so clients are refused with an error.
+        TestUtil.verify_redis_error(1531, "ERROR: Proxy memory limit reached")
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS")
+        connections = response.split("\nConnections:\n")[1].split("\n")[0]
+        self.assertTrue(connections.endswith(" shed_connections=1 denied_connections=0 idle_closed=0 connect_timeouts=0 held_requests=0 held_expired=0 quarantined_clients=0 quarantine_refused=0"))
+        budget = response.split("\nMemory:\n")[1].split("\nQueues:")[0].split("\n")[3]
+        self.assertTrue(budget.endswith(" limit_bytes=1024"))
+
+    def test_connection_churn(self):
+        # The backend requires a different password than the one in the config, so every handshake is rejected.
+        self.start_redis_server(6380, password="password1")
+        self.start_proxy("tests/conf/auth2.toml")
+
+        TestUtil.verify_redis_error(1531, "ERROR: Not connected")
+        r = redis.Redis(port=1530, socket_timeout=1)
+        response = r.execute_command("STATS")
+        self.assertEqual(
+            response.split("\nConnections:\n")[1].split("\nBig values:")[0],
+            "pool1: accepted_clients=1 client_disconnects=1 backend_reconnects=0 handshake_failures=1 client_read_pauses=0 shed_connections=0 denied_connections=0 idle_closed=0 connect_timeouts=0 held_requests=0 held_expired=0 quarantined_clients=0 quarantine_refused=0"
+        );
+        self.assertTrue(response.split("\nErrors:\n")[1].startswith("pool1: timeouts=0 backend_unavailable=1 connection_failures=0 protocol_errors=0 auth_failures=1 "))
+        response = r.execute_command("POOLSTATS")
+        self.assertTrue('handshake_failures{pool="pool1"} 1' in response.split("\n"))
+        self.assertTrue('errors{pool="pool1",type="auth_failures"} 1' in response.split("\n"))
+
+    def test_stats_snapshot_file(self):
+        snapshot_path = "/tmp/redflare-stats-snapshot.json"
+        if os.path.exists(snapshot_path):
+            os.remove(snapshot_path)
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/snapshot1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Snapshots are written every second, even while the proxy is idle.
+        time.sleep(2.5)
+        with open(snapshot_path) as f:
+            snapshots = [json.loads(line) for line in f.readlines()]
+        self.assertTrue(len(snapshots) >= 2)
+        self.assertTrue(snapshots[1]["timestamp"] > snapshots[0]["timestamp"])
+        self.assertEqual(snapshots[-1]["stats"]["requests"], 1)
+        self.assertEqual(snapshots[-1]["pools"]["pool1"]["requests"], 1)
+        self.assertEqual(snapshots[-1]["pools"]["pool1"]["latency_us"]["count"], 1)
+        self.assertEqual(snapshots[-1]["pools"]["pool1"]["errors"]["timeouts"], 0)
+
+    def test_workers(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/workers1.toml")
+
+        # Connections are spread across the workers, and each gets its own backend connection.
+        for i in range(20):
+            r = redis.Redis(port=1531, socket_timeout=1)
+            r.set("key%d" % i, "value")
+            self.assertEqual(r.get("key%d" % i), "value")
+            r.connection_pool.disconnect()
+
+        # Workers publish their counters once per event loop iteration.
+        time.sleep(0.1)
+        admin = redis.Redis(port=1530, socket_timeout=1)
+        stats = admin.execute_command("STATS").split("\n")
+        self.assertTrue("accepted_clients: 20" in stats)
+        self.assertTrue("requests: 40" in stats)
+        self.assertTrue("responses: 40" in stats)
+        self.assertEqual(
+            admin.execute_command("SWITCHCONFIG"),
+            "ERROR: SWITCHCONFIG is not supported with multiple workers. Restart the proxy instead."
+        )
+        # Per-pool stats, and changes to a pool's workers, would only cover worker 0.
+        for command in ["POOLSTATS", "HOTKEYS", "CACHE", "SLOWLOG", "CAPTURE", "DEBUG TIMING", "LOGLEVEL backend DEBUG"]:
+            self.assertEqual(
+                admin.execute_command(command),
+                "ERROR: %s is not supported with multiple workers, since it only covers worker 0." % command.split()[0]
+            )
@@ -8,6 +8,8 @@ import time
 import unittest
 import socket
 import re
+import shutil
+from distutils.spawn import find_executable
 
 class TestUtil(unittest.TestCase):
     subprocesses = []
@@ -61,6 +63,21 @@ class TestUtil(unittest.TestCase):
         if call(["cargo", "build"] + args) != 0:
             raise AssertionError('Failed to compile RedFlareProxy with cargo')
 
+    @staticmethod
+    def find_proxy_pid(config_path):
+        # The proxy itself, rather than the cargo process that started it.
+        for pid in os.listdir("/proc"):
+            if not pid.isdigit():
+                continue
+            try:
+                with open("/proc/{}/cmdline".format(pid)) as f:
+                    args = f.read().split("\0")
+            except IOError:
+                continue
+            if args[0].endswith("redflareproxy") and "-c{}".format(config_path) in args:
+                return int(pid)
+        raise AssertionError('No proxy running with config: {}'.format(config_path))
+
     @staticmethod
     def kill_redis_server(port):
         try:
@@ -146,7 +163,23 @@ class TestUtil(unittest.TestCase):
     def assert_redis_key(self, port, key="test_key", data="value"):
         self.assertTrue(TestUtil.expect_redis_key(port, key, data))
 
+    @staticmethod
+    def use_mock_backend():
+        return os.environ.get('REDFLARE_TEST_BACKEND') == 'mock' or not find_executable("redis-server")
+
+    # Tests that need more than redflare-mock serves are skipped, rather than run against it, without redis-server.
+    def require_redis_server(self):
+        if not find_executable("redis-server"):
+            self.skipTest("Needs redis-server, which isn't installed")
+
+    # Starts a backend for tests that only use the commands redflare-mock serves, e.g. GET and SET.
+    def start_backend(self, port, password=None):
+        if TestUtil.use_mock_backend():
+            return self.start_mock_server(port, password)
+        return self.start_redis_server(port, password)
+
     def start_redis_server(self, port, password=None, config_path=None):
+        self.require_redis_server()
         FNULL = open(os.devnull, 'w')
         command = ["redis-server"]
         if config_path:
@@ -169,7 +202,28 @@ class TestUtil(unittest.TestCase):
                 time.sleep(0.1)
         raise AssertionError('Redis server did not start at port: {}'.format(port))
 
+    # Starts redflare-mock, the in-memory stand-in for redis-server. See src/mock/README.md.
+    def start_mock_server(self, port, password=None):
+        log_out = open("tests/log/{}.mock{}.stdout".format(self._testMethodName, port), 'w')
+        command = ["cargo", "run", "--bin", "redflare-mock", "--", "--port", str(port)]
+        if password:
+            command = command + ["--requirepass", str(password)]
+        process = subprocess.Popen(command, stdout=log_out, stderr=subprocess.STDOUT)
+        self.subprocesses.append(process)
+
+        r = redis.Redis(port=port, password=password)
+        attempts_remaining = 20
+        while attempts_remaining:
+            try:
+                r.ping()
+                return process
+            except redis.ConnectionError:
+                attempts_remaining = attempts_remaining - 1
+                time.sleep(0.1)
+        raise AssertionError('Mock backend did not start at port: {}'.format(port))
+
     def start_redis_cluster_server(self, port):
+        self.require_redis_server()
         FNULL = open(os.devnull, 'w')
         process = subprocess.Popen(["redis-server", "tests/conf/redis-cluster{}.conf".format(port)], stdout=FNULL, stderr=subprocess.STDOUT)
 
@@ -198,12 +252,31 @@ class TestUtil(unittest.TestCase):
                 raise AssertionError('Redis cluster server {} failed to add slot {}. Stopping test.'.format(ports[port_index], i))
         time.sleep(1.0);
 
-    def start_proxy(self, config_path, tag=""):
+    def start_redis_sentinel(self, port):
+        # Sentinel rewrites its config file, so it runs from a copy.
+        config_path = "tests/log/sentinel{}.conf".format(port)
+        shutil.copyfile("tests/conf/sentinel{}.conf".format(port), config_path)
+        FNULL = open(os.devnull, 'w')
+        process = subprocess.Popen(["redis-server", config_path, "--sentinel"], stdout=FNULL, stderr=subprocess.STDOUT)
+        self.subprocesses.append(process)
+
+        r = redis.Redis(port=port)
+        attempts_remaining = 4
+        while attempts_remaining:
+            try:
+                r.ping()
+                return process
+            except redis.ConnectionError:
+                attempts_remaining = attempts_remaining - 1
+                time.sleep(0.1)
+        raise AssertionError('Redis sentinel did not start at port: {}'.format(port))
+
+    def start_proxy(self, config_path, tag="", extra_args=[]):
         log_file = "tests/log/{}{}.stdout".format(self._testMethodName, tag)
         log_out = open(log_file, 'w')
         args = ["-c{}".format(
             config_path),
-            "-l DEBUG"]
+            "-l DEBUG"] + extra_args
         env = os.environ.copy()
         env['RUST_BACKTRACE'] = '1'
         process = subprocess.Popen(["cargo", "run", "--bin", "redflareproxy", "--"] + args, stdout=log_out, stderr=subprocess.STDOUT, env=env)
@@ -1,4 +1,7 @@
 #!/usr/bin/env python
+import os
+import redis
+import signal
 import time
 import socket
 from test_util import TestUtil
@@ -49,6 +52,136 @@ class TimeoutTests(TestUtil):
         TestUtil.verify_redis_connection(1531)
         TestUtil.verify_redis_connection(1531)
         
+    def test_graceful_shutdown(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 300)
+        pid_file = "tests/tmp/redflareproxy_shutdown.pid"
+        self.start_proxy("tests/conf/shutdown1.toml", extra_args=["--pid_file={}".format(pid_file)])
+        TestUtil.verify_redis_connection(1531)
+        with open(pid_file) as f:
+            pid = int(f.read())
+
+        # Send a request, and shut down while it is waiting on the delayed backend.
+        conn = socket.socket(socket.AF_INET)
+        conn.connect(("0.0.0.0", 1531))
+        conn.sendall("*3\r\n$3\r\nSET\r\n$8\r\nshutdown\r\n$1\r\n1\r\n")
+        time.sleep(0.1)
+        os.kill(pid, signal.SIGTERM)
+        time.sleep(0.05)
+
+        # New connections are refused, but the in-flight request is still answered.
+        self.assertRaises(socket.error, socket.create_connection, ("0.0.0.0", 1531))
+        self.assertEquals(conn.recv(1024), "+OK\r\n")
+
+        # The proxy exits once the request is drained, and removes its pid file.
+        time.sleep(0.5)
+        self.assertEquals(conn.recv(1024), "")
+        self.assertFalse(os.path.exists(pid_file))
+
+    def test_hot_restart(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 300)
+        pid_file = "tests/tmp/redflareproxy_restart.pid"
+        self.start_proxy("tests/conf/shutdown1.toml", extra_args=["--pid_file={}".format(pid_file)])
+        TestUtil.verify_redis_connection(1531)
+        with open(pid_file) as f:
+            old_pid = int(f.read())
+
+        # Send a request, and restart while it is waiting on the delayed backend.
+        conn = socket.socket(socket.AF_INET)
+        conn.connect(("0.0.0.0", 1531))
+        conn.sendall("*3\r\n$3\r\nSET\r\n$7\r\nrestart\r\n$1\r\n1\r\n")
+        time.sleep(0.1)
+        os.kill(old_pid, signal.SIGUSR2)
+
+        # New connections are accepted throughout the restart, and the in-flight request is answered by the old process.
+        for _ in range(10):
+            TestUtil.verify_redis_connection(1531)
+            time.sleep(0.05)
+        self.assertEquals(conn.recv(1024), "+OK\r\n")
+
+        # The new process rewrites the pid file, and the old process exits once drained.
+        time.sleep(0.5)
+        with open(pid_file) as f:
+            new_pid = int(f.read())
+        self.assertNotEqual(new_pid, old_pid)
+        self.assertEquals(conn.recv(1024), "")
+        TestUtil.verify_redis_connection(1531)
+
+        # The new process isn't a child of this test, so it is shut down here.
+        os.kill(new_pid, signal.SIGTERM)
+        time.sleep(0.5)
+        self.assertFalse(os.path.exists(pid_file))
+
+    def test_backend_backpressure(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 50)
+        self.start_proxy("tests/conf/backpressure1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Pipeline more requests than the backend's queue limit. The client is paused and resumed as the backend
+        # catches up, and every request is still answered in order.
+        conn = socket.socket(socket.AF_INET)
+        conn.connect(("0.0.0.0", 1531))
+        conn.settimeout(5)
+        conn.sendall("*3\r\n$3\r\nSET\r\n$12\r\nbackpressure\r\n$1\r\n1\r\n" * 10)
+        response = ""
+        while response.count("\r\n") < 10:
+            response += conn.recv(1024)
+        self.assertEquals(response, "+OK\r\n" * 10)
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        connections = r.execute_command("STATS").split("\nConnections:\n")[1].split("\n")[0]
+        self.assertNotEqual(connections.split("client_read_pauses=")[1].split(" ")[0], "0")
+
+    def test_concurrency_limit(self):
+        self.start_redis_server(6381)
+        self.start_delayer(6380, 6381, 50)
+        self.start_proxy("tests/conf/concurrency1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # Pipeline more requests than max_concurrency. The requests past the limit are rejected right away instead of
+        # waiting on the backend.
+        conn = socket.socket(socket.AF_INET)
+        conn.connect(("0.0.0.0", 1531))
+        conn.settimeout(5)
+        conn.sendall("*3\r\n$3\r\nSET\r\n$11\r\nconcurrency\r\n$1\r\n1\r\n" * 5)
+        response = ""
+        while response.count("\r\n") < 5:
+            response += conn.recv(1024)
+        self.assertEquals(response.count("+OK\r\n"), 2)
+        self.assertEquals(response.count("-ERROR: Backend overloaded\r\n"), 3)
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        stats = r.execute_command("STATS")
+        self.assertIn("overloaded=3", stats)
+        self.assertIn("concurrency_limit=2", stats)
+
+    def test_idle_clients_closed(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/idle1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        idle = socket.socket(socket.AF_INET)
+        idle.connect(("0.0.0.0", 1531))
+        idle.settimeout(5)
+        admin = socket.socket(socket.AF_INET)
+        admin.connect(("0.0.0.0", 1530))
+        admin.settimeout(5)
+        active = redis.Redis(port=1531, socket_timeout=1)
+        for i in range(3):
+            active.set("idle", "1")
+            time.sleep(1)
+
+        # Clients are closed once they go client_idle_timeout without traffic, and admin clients after idle_timeout.
+        self.assertEquals(idle.recv(1024), "")
+        self.assertEquals(admin.recv(1024), "")
+        self.assertEquals(active.get("idle"), "1")
+
+        r = redis.Redis(port=1530, socket_timeout=1)
+        connections = r.execute_command("STATS").split("\nConnections:\n")[1].split("\n")[0]
+        self.assertNotEqual(connections.split("idle_closed=")[1].split(" ")[0], "0")
+
 # test a backend responding with just a partial response and then failing to ever respond.
     def test_partial_response_timeout(self):
         # Test having a broken pipe.
@@ -197,3 +330,74 @@ class TimeoutTests(TestUtil):
         self.assert_redis_key(6380, "key5")
         TestUtil.populate_redis_key(1531, "key6")
         self.assert_redis_key(6386, "key6")
+
+    def test_exponential_retry_backoff(self):
+        # The backend starts out unavailable, so the proxy keeps retrying with a growing delay, capped at 400ms.
+        self.start_proxy("tests/conf/retrybackoff1.toml")
+        TestUtil.verify_redis_error(1531, "ERROR: Not connected")
+        time.sleep(1)
+
+        self.start_redis_server(6380)
+        time.sleep(0.5)
+        TestUtil.verify_redis_connection(1531)
+
+    def test_client_deadlines(self):
+        self.start_redis_server(6381)
+        delayer = self.start_delayer(6380, 6381, 100)
+        self.start_proxy("tests/conf/deadline1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        client = socket.create_connection(("127.0.0.1", 1531))
+        client.settimeout(2)
+        client.sendall(b"*2\r\n$8\r\nDEADLINE\r\n$1\r\n0\r\n")
+        self.assertEqual(client.recv(1024), b"-ERR DEADLINE must be a positive number of milliseconds\r\n")
+
+        # A request with a DEADLINE shorter than the backend's delay fails without waiting for the pool's timeout.
+        client.sendall(b"*2\r\n$8\r\nDEADLINE\r\n$2\r\n50\r\n")
+        self.assertEqual(client.recv(1024), b"+OK\r\n")
+        start = time.time()
+        client.sendall(b"*2\r\n$3\r\nGET\r\n$12\r\ndeadline_key\r\n")
+        self.assertEqual(client.recv(1024), b"-ERR Proxy timed out\r\n")
+        self.assertTrue(time.time() - start < 0.09)
+
+        # The DEADLINE only applies to the next request. The backend's late response to the one that timed out is
+        # discarded, rather than answering this one.
+        client.sendall(b"*3\r\n$3\r\nSET\r\n$12\r\ndeadline_key\r\n$5\r\nvalue\r\n")
+        self.assertEqual(client.recv(1024), b"+OK\r\n")
+        self.assert_redis_key(6381, "deadline_key")
+
+        # A longer DEADLINE lets a request wait past the pool's timeout.
+        delayer.sendall("SETDELAY 400")
+        time.sleep(0.2)
+        client.sendall(b"*2\r\n$8\r\nDEADLINE\r\n$4\r\n1000\r\n")
+        self.assertEqual(client.recv(1024), b"+OK\r\n")
+        client.sendall(b"*2\r\n$3\r\nGET\r\n$12\r\ndeadline_key\r\n")
+        self.assertEqual(client.recv(1024), b"$5\r\nvalue\r\n")
+
+    def test_outage_buffer(self):
+        self.start_redis_server(6380)
+        self.start_proxy("tests/conf/outagebuffer1.toml")
+        TestUtil.verify_redis_connection(1531)
+
+        # A request sent while the backend restarts is held until it's back, instead of failing.
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.2)
+        client = socket.create_connection(("127.0.0.1", 1531))
+        client.settimeout(4)
+        client.sendall(b"*3\r\n$3\r\nSET\r\n$8\r\nheld_key\r\n$5\r\nvalue\r\n")
+        time.sleep(0.5)
+        self.start_redis_server(6380)
+        self.assertEqual(client.recv(1024), b"+OK\r\n")
+        self.assert_redis_key(6380, "held_key")
+
+        # Once the backend has been down for the whole outage_buffer_timeout, the request fails as before.
+        TestUtil.kill_redis_server(6380)
+        time.sleep(0.2)
+        start = time.time()
+        client.sendall(b"*2\r\n$3\r\nGET\r\n$8\r\nheld_key\r\n")
+        self.assertEqual(client.recv(1024), b"-ERROR: Not connected\r\n")
+        self.assertTrue(time.time() - start >= 2.9)
+
+        r = redis.Redis(port=1530)
+        connections = r.execute_command("STATS").split("\nConnections:\n")[1].split("\n")[0]
+        self.assertTrue(" held_requests=2 held_expired=1 " in connections, connections)